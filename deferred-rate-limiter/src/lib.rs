@@ -1,4 +1,6 @@
 //#![warn(missing_docs)]
+mod token_bucket;
+
 use log::error;
 use quick_cache_ttl::CacheWithTTL;
 use redis_rate_limiter::{RedisRateLimitResult, RedisRateLimiter};
@@ -10,6 +12,8 @@ use std::sync::{atomic::AtomicU64, Arc};
 use tokio::sync::Mutex;
 use tokio::time::{Duration, Instant};
 
+pub use token_bucket::DeferredTokenBucket;
+
 /// A local cache that sits in front of a RedisRateLimiter
 /// Generic accross the key so it is simple to use with IPs or user keys
 pub struct DeferredRateLimiter<K>
@@ -61,6 +65,37 @@ where
         }
     }
 
+    /// the max requests per period for a key that has no limit of its own
+    pub fn default_max_requests_per_period(&self) -> u64 {
+        self.default_max_requests_per_period
+            .unwrap_or(self.rrl.max_requests_per_period)
+    }
+
+    /// seconds since the epoch when the current period ends and counts reset
+    pub fn period_end_as_secs(&self) -> f32 {
+        let now = self.rrl.now_as_secs();
+
+        self.rrl.period_end_as_secs(now)
+    }
+
+    /// how many requests the key has made in the current period. this does not count as a request.
+    /// if redis is unavailable, the local count is used instead
+    pub async fn peek(&self, key: K) -> u64 {
+        let redis_key = format!("{}:{}", self.prefix, key);
+
+        match self.rrl.peek_label(&redis_key).await {
+            Ok(count) => count,
+            Err(err) => {
+                error!("unable to peek rate limits. key={:?} err={:?}", key, err);
+
+                self.local_cache
+                    .get(&key)
+                    .map(|x| x.load(Ordering::Acquire))
+                    .unwrap_or_default()
+            }
+        }
+    }
+
     /// if setting max_per_period, be sure to keep the period the same for all requests to this label
     /// TODO: max_per_period being None means two things. some places it means unlimited, but here it means to use the default. make an enum
     pub async fn throttle(
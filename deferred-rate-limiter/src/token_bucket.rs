@@ -0,0 +1,435 @@
+use log::error;
+use quick_cache_ttl::CacheWithTTL;
+use redis_rate_limiter::{RedisTokenBucket, TokenBucket, TokenBucketResult};
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+/// A bucket as this server last saw it
+#[derive(Debug)]
+struct LocalTokens {
+    tokens: f64,
+    at: Instant,
+}
+
+impl LocalTokens {
+    /// the tokens the bucket would have now
+    fn refilled(&self, bucket: &TokenBucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.at).as_secs_f64();
+
+        (self.tokens + elapsed * bucket.per_second).min(bucket.capacity as f64)
+    }
+}
+
+type LocalBucket = Arc<Mutex<LocalTokens>>;
+
+/// A local estimate of token buckets that sits in front of a RedisTokenBucket.
+/// Requests that leave plenty of tokens in every bucket are allowed right away and redis is updated in the background.
+/// Redis is only waited on for a label's first request and when a bucket is close to empty.
+///
+/// Other servers take from the same buckets, so the local estimate can only have more tokens than redis does.
+/// That makes it safe to reject without asking redis. Every reply from redis resets the estimate.
+pub struct DeferredTokenBucket {
+    local_cache: CacheWithTTL<String, LocalBucket>,
+    rtb: RedisTokenBucket,
+}
+
+impl DeferredTokenBucket {
+    pub async fn new(cache_size: usize, rtb: RedisTokenBucket) -> Self {
+        // the estimate drifts a little with every request that another server handles.
+        // expiring them makes the next request check with redis
+        // TODO: ttl from config
+        let local_cache = CacheWithTTL::new(
+            "deferred token buckets",
+            cache_size,
+            Duration::from_secs(60),
+        )
+        .await;
+
+        Self { local_cache, rtb }
+    }
+
+    /// Save what redis said about each bucket
+    fn reset_local(&self, buckets: &[TokenBucket], tokens: &[u64], now: Instant) {
+        for (bucket, tokens) in buckets.iter().zip(tokens) {
+            let x = LocalTokens {
+                tokens: *tokens as f64,
+                at: now,
+            };
+
+            match self.local_cache.get(&bucket.label) {
+                Some(local) => *local.lock().unwrap() = x,
+                None => {
+                    let _ = self
+                        .local_cache
+                        .try_insert(bucket.label.clone(), Arc::new(Mutex::new(x)));
+                }
+            }
+        }
+    }
+
+    /// Take `count` tokens from every bucket, or from none of them.
+    /// If redis is down, the local estimates are all we have
+    pub async fn throttle(
+        &self,
+        buckets: &[TokenBucket],
+        count: u64,
+    ) -> anyhow::Result<TokenBucketResult> {
+        if buckets.is_empty() {
+            return Ok(TokenBucketResult::Allowed(u64::MAX));
+        }
+
+        if buckets
+            .iter()
+            .any(|x| count > x.capacity || x.per_second <= 0.0)
+        {
+            return Ok(TokenBucketResult::RetryNever);
+        }
+
+        let now = Instant::now();
+
+        let locals: Option<Vec<LocalBucket>> = buckets
+            .iter()
+            .map(|x| self.local_cache.get(&x.label))
+            .collect();
+
+        let locals = match locals {
+            Some(x) => x,
+            None => {
+                // a label that we haven't seen recently. redis has to decide
+                return match self.rtb.throttle_labels(buckets, count).await {
+                    Ok((result, tokens)) => {
+                        self.reset_local(buckets, &tokens, now);
+
+                        Ok(result)
+                    }
+                    Err(err) => {
+                        // if we get a redis error, just let the user through.
+                        // if users are sticky on a server, local caches will work well enough
+                        error!(
+                            "unable to throttle token buckets! starting them full. err={:?}",
+                            err
+                        );
+
+                        let full: Vec<_> = buckets.iter().map(|x| x.capacity - count).collect();
+
+                        self.reset_local(buckets, &full, now);
+
+                        Ok(TokenBucketResult::Allowed(
+                            full.into_iter().min().unwrap_or_default(),
+                        ))
+                    }
+                };
+            }
+        };
+
+        // always lock the buckets in the same order so that two requests for the same buckets can't deadlock
+        let mut order: Vec<usize> = (0..buckets.len()).collect();
+        order.sort_by(|a, b| buckets[*a].label.cmp(&buckets[*b].label));
+
+        let local_result = {
+            let mut guards: Vec<_> = order
+                .iter()
+                .map(|i| (*i, locals[*i].lock().unwrap()))
+                .collect();
+
+            let mut wait_on_redis = false;
+            let mut wait = Duration::ZERO;
+            let mut emptiest = f64::MAX;
+
+            for (i, guard) in guards.iter() {
+                let bucket = &buckets[*i];
+
+                let tokens = guard.refilled(bucket, now);
+
+                if tokens < count as f64 {
+                    wait = wait.max(Duration::from_secs_f64(
+                        (count as f64 - tokens) / bucket.per_second,
+                    ));
+                } else {
+                    // if close to empty, wait for redis
+                    // TODO: how close should we allow? depends on max expected concurent requests from one user
+                    let headroom = (bucket.capacity as f64 * 0.01).max(1.0);
+
+                    if tokens - (count as f64) < headroom {
+                        wait_on_redis = true;
+                    }
+                }
+
+                emptiest = emptiest.min(tokens - count as f64);
+            }
+
+            if !wait.is_zero() {
+                // this server alone has already used the tokens. redis can only have fewer
+                Some(TokenBucketResult::RetryAt(now + wait, 0))
+            } else if wait_on_redis {
+                None
+            } else {
+                // every bucket has plenty of room. take from the estimates now and tell redis in the background
+                for (i, guard) in guards.iter_mut() {
+                    let bucket = &buckets[*i];
+
+                    guard.tokens = guard.refilled(bucket, now) - count as f64;
+                    guard.at = now;
+                }
+
+                Some(TokenBucketResult::Allowed(emptiest.floor() as u64))
+            }
+        };
+
+        match local_result {
+            Some(TokenBucketResult::Allowed(x)) => {
+                let rtb = self.rtb.clone();
+                let buckets = buckets.to_vec();
+
+                // TODO: send an error here somewhere
+                tokio::spawn(async move {
+                    match rtb.throttle_labels(&buckets, count).await {
+                        Ok((_, tokens)) => {
+                            let now = Instant::now();
+
+                            for (local, tokens) in locals.iter().zip(tokens) {
+                                *local.lock().unwrap() = LocalTokens {
+                                    tokens: tokens as f64,
+                                    at: now,
+                                };
+                            }
+                        }
+                        Err(err) => {
+                            // don't let redis errors block our users!
+                            error!(
+                                "unable to throttle token buckets, but local cache is available. err={:?}",
+                                err
+                            );
+                        }
+                    }
+                });
+
+                Ok(TokenBucketResult::Allowed(x))
+            }
+            Some(x) => Ok(x),
+            None => match self.rtb.throttle_labels(buckets, count).await {
+                Ok((result, tokens)) => {
+                    self.reset_local(buckets, &tokens, Instant::now());
+
+                    Ok(result)
+                }
+                Err(err) => {
+                    error!(
+                        "unable to throttle token buckets. using the local cache. err={:?}",
+                        err
+                    );
+
+                    // there were enough tokens locally, so take them
+                    let mut emptiest = u64::MAX;
+
+                    for (bucket, local) in buckets.iter().zip(locals.iter()) {
+                        let mut local = local.lock().unwrap();
+
+                        local.tokens = (local.refilled(bucket, now) - count as f64).max(0.0);
+                        local.at = now;
+
+                        emptiest = emptiest.min(local.tokens as u64);
+                    }
+
+                    Ok(TokenBucketResult::Allowed(emptiest))
+                }
+            },
+        }
+    }
+
+    /// Like `peek`, but only this server's estimates are used and redis is never waited on.
+    /// None if there are no buckets or any of them hasn't been used here recently
+    pub fn peek_local(&self, buckets: &[TokenBucket]) -> Option<u64> {
+        let now = Instant::now();
+
+        let tokens: Option<Vec<u64>> = buckets
+            .iter()
+            .map(|bucket| {
+                self.local_cache
+                    .get(&bucket.label)
+                    .map(|local| local.lock().unwrap().refilled(bucket, now) as u64)
+            })
+            .collect();
+
+        tokens?.into_iter().min()
+    }
+
+    /// How many whole tokens are in the emptiest bucket. This does not take any and does not write anything
+    pub async fn peek(&self, buckets: &[TokenBucket]) -> anyhow::Result<u64> {
+        let tokens = match self.rtb.peek_labels(buckets).await {
+            Ok(x) => x,
+            Err(err) => {
+                error!(
+                    "unable to peek token buckets. using the local cache. err={:?}",
+                    err
+                );
+
+                let now = Instant::now();
+
+                buckets
+                    .iter()
+                    .map(|bucket| match self.local_cache.get(&bucket.label) {
+                        Some(local) => local.lock().unwrap().refilled(bucket, now) as u64,
+                        None => bucket.capacity,
+                    })
+                    .collect()
+            }
+        };
+
+        Ok(tokens.into_iter().min().unwrap_or(u64::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis_rate_limiter::{DeadpoolRuntime, RedisConfig};
+
+    /// nothing listens on port 1, so every redis call fails and only the local estimates are used
+    async fn unreachable_redis() -> DeferredTokenBucket {
+        let pool = RedisConfig::from_url("redis://127.0.0.1:1")
+            .builder()
+            .unwrap()
+            .runtime(DeadpoolRuntime::Tokio1)
+            .build()
+            .unwrap();
+
+        let rtb = RedisTokenBucket::new("test", "frontend", pool);
+
+        DeferredTokenBucket::new(100, rtb).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_without_redis() {
+        let x = unreachable_redis().await;
+
+        let bucket = TokenBucket::new("user:1".to_string(), 3, 1.0);
+
+        // the first request starts a full bucket
+        assert_eq!(
+            x.throttle(&[bucket.clone()], 1).await.unwrap(),
+            TokenBucketResult::Allowed(2)
+        );
+        assert_eq!(
+            x.throttle(&[bucket.clone()], 1).await.unwrap(),
+            TokenBucketResult::Allowed(1)
+        );
+        assert_eq!(
+            x.throttle(&[bucket.clone()], 1).await.unwrap(),
+            TokenBucketResult::Allowed(0)
+        );
+
+        let now = Instant::now();
+
+        match x.throttle(&[bucket.clone()], 1).await.unwrap() {
+            TokenBucketResult::RetryAt(retry_at, 0) => {
+                assert_eq!(retry_at, now + Duration::from_secs(1))
+            }
+            other => panic!("{:?}", other),
+        }
+
+        // rejections don't take a token, so one second refills enough for one more
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        assert!(matches!(
+            x.throttle(&[bucket.clone()], 1).await.unwrap(),
+            TokenBucketResult::Allowed(_)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_takes_from_all_or_none() {
+        let x = unreachable_redis().await;
+
+        let key = TokenBucket::new("key:1".to_string(), 10, 1.0);
+        let user = TokenBucket::new("user:1".to_string(), 2, 1.0);
+
+        let both = [key.clone(), user.clone()];
+
+        assert!(matches!(
+            x.throttle(&both, 1).await.unwrap(),
+            TokenBucketResult::Allowed(1)
+        ));
+        assert!(matches!(
+            x.throttle(&both, 1).await.unwrap(),
+            TokenBucketResult::Allowed(0)
+        ));
+
+        // the user's bucket is empty. the key's bucket must not pay for the rejected request
+        assert!(matches!(
+            x.throttle(&both, 1).await.unwrap(),
+            TokenBucketResult::RetryAt(..)
+        ));
+        assert!(matches!(
+            x.throttle(&both, 1).await.unwrap(),
+            TokenBucketResult::RetryAt(..)
+        ));
+
+        assert_eq!(x.peek(&[key]).await.unwrap(), 8);
+        assert_eq!(x.peek(&[user]).await.unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peek_does_not_take() {
+        let x = unreachable_redis().await;
+
+        let bucket = TokenBucket::new("key:2".to_string(), 5, 1.0);
+
+        // unknown buckets are full
+        assert_eq!(x.peek(&[bucket.clone()]).await.unwrap(), 5);
+
+        x.throttle(&[bucket.clone()], 2).await.unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(x.peek(&[bucket.clone()]).await.unwrap(), 3);
+        }
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        assert_eq!(x.peek(&[bucket]).await.unwrap(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peek_local() {
+        let x = unreachable_redis().await;
+
+        let key = TokenBucket::new("key:5".to_string(), 5, 1.0);
+        let user = TokenBucket::new("user:5".to_string(), 3, 1.0);
+
+        // nothing is known until a bucket is used here
+        assert_eq!(x.peek_local(&[key.clone()]), None);
+        assert_eq!(x.peek_local(&[]), None);
+
+        x.throttle(&[key.clone()], 2).await.unwrap();
+
+        assert_eq!(x.peek_local(&[key.clone()]), Some(3));
+        assert_eq!(x.peek_local(&[key.clone(), user.clone()]), None);
+
+        x.throttle(&[key.clone(), user.clone()], 3).await.unwrap();
+
+        assert_eq!(x.peek_local(&[key.clone(), user.clone()]), Some(0));
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+
+        assert_eq!(x.peek_local(&[key, user]), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_impossible_requests() {
+        let x = unreachable_redis().await;
+
+        let bucket = TokenBucket::new("key:3".to_string(), 5, 1.0);
+
+        assert_eq!(
+            x.throttle(&[bucket], 6).await.unwrap(),
+            TokenBucketResult::RetryNever
+        );
+
+        let stopped = TokenBucket::new("key:4".to_string(), 5, 0.0);
+
+        assert_eq!(
+            x.throttle(&[stopped], 1).await.unwrap(),
+            TokenBucketResult::RetryNever
+        );
+    }
+}
@@ -3,20 +3,33 @@
 pub mod prelude;
 
 pub mod admin;
+pub mod admin_audit;
 pub mod admin_increase_balance_receipt;
 pub mod admin_trail;
 pub mod balance;
+pub mod canary_hit;
+pub mod idempotency_key;
 pub mod increase_on_chain_balance_receipt;
 pub mod login;
+pub mod org;
+pub mod org_invite;
+pub mod org_member;
 pub mod pending_login;
 pub mod referee;
 pub mod referrer;
+pub mod refresh_token;
 pub mod revert_log;
 pub mod rpc_accounting;
 pub mod rpc_accounting_v2;
 pub mod rpc_key;
+pub mod rpc_key_pool;
 pub mod sea_orm_active_enums;
 pub mod secondary_user;
 pub mod serialization;
+pub mod stat_flush;
+pub mod status_snapshot;
+pub mod tx_journal;
+pub mod tx_screening;
 pub mod user;
 pub mod user_tier;
+pub mod user_tier_change;
@@ -1,18 +1,31 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.7
 
 pub use super::admin::Entity as Admin;
+pub use super::admin_audit::Entity as AdminAudit;
 pub use super::admin_increase_balance_receipt::Entity as AdminIncreaseBalanceReceipt;
 pub use super::admin_trail::Entity as AdminTrail;
 pub use super::balance::Entity as Balance;
+pub use super::canary_hit::Entity as CanaryHit;
+pub use super::idempotency_key::Entity as IdempotencyKey;
 pub use super::increase_on_chain_balance_receipt::Entity as IncreaseOnChainBalanceReceipt;
 pub use super::login::Entity as Login;
+pub use super::org::Entity as Org;
+pub use super::org_invite::Entity as OrgInvite;
+pub use super::org_member::Entity as OrgMember;
 pub use super::pending_login::Entity as PendingLogin;
 pub use super::referee::Entity as Referee;
 pub use super::referrer::Entity as Referrer;
+pub use super::refresh_token::Entity as RefreshToken;
 pub use super::revert_log::Entity as RevertLog;
 pub use super::rpc_accounting::Entity as RpcAccounting;
 pub use super::rpc_accounting_v2::Entity as RpcAccountingV2;
 pub use super::rpc_key::Entity as RpcKey;
+pub use super::rpc_key_pool::Entity as RpcKeyPool;
 pub use super::secondary_user::Entity as SecondaryUser;
+pub use super::stat_flush::Entity as StatFlush;
+pub use super::status_snapshot::Entity as StatusSnapshot;
+pub use super::tx_journal::Entity as TxJournal;
+pub use super::tx_screening::Entity as TxScreening;
 pub use super::user::Entity as User;
 pub use super::user_tier::Entity as UserTier;
+pub use super::user_tier_change::Entity as UserTierChange;
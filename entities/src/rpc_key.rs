@@ -11,9 +11,13 @@ pub struct Model {
     #[sea_orm(primary_key)]
     pub id: u64,
     pub user_id: u64,
+    /// only set on keys from before secrets were hashed. see `secret_key_hash`
     #[sea_orm(unique)]
-    #[serde(serialize_with = "serialization::uuid_as_ulid")]
-    pub secret_key: Uuid,
+    #[serde(serialize_with = "serialization::option_uuid_as_ulid")]
+    pub secret_key: Option<Uuid>,
+    /// keyed hash of the secret. the secret itself is only shown once when the key is created
+    #[sea_orm(unique)]
+    pub secret_key_hash: Option<String>,
     pub description: Option<String>,
     pub private_txs: bool,
     pub active: bool,
@@ -28,18 +32,82 @@ pub struct Model {
     pub log_revert_chance: f64,
     // TODO: rename this with a migration
     pub log_level: TrackingLevel,
+    pub rpc_key_pool_id: Option<u64>,
+    pub payload_sampling_opt_out: bool,
+    pub bypass_cache: bool,
+    /// never used by the owner. any request with this key means it leaked
+    pub canary: bool,
+    /// when a canary key is used, deactivate the owner's other keys too
+    pub canary_freeze_siblings: bool,
+    /// org keys are billed to the org's balance instead of the user's
+    pub org_id: Option<u64>,
+    /// free-form label for grouping keys in billing exports
+    pub project_tag: Option<String>,
+    /// the org member that created an org key. org keys are all owned by the org's owner
+    pub created_by: Option<u64>,
+    /// comma separated "method=limit" pairs. these can only lower the tier's limits
+    #[sea_orm(column_type = "Text", nullable)]
+    pub method_requests_per_second: Option<String>,
+    /// https endpoint that gets signed copies of this key's request metadata
+    #[sea_orm(column_type = "Text", nullable)]
+    pub shadow_url: Option<String>,
+    /// signs the requests to `shadow_url`. encrypted if `row_encryption_keyfile` is set. only shown once when it is made
+    #[sea_orm(column_type = "Text", nullable)]
+    #[serde(skip_serializing)]
+    pub shadow_secret: Option<String>,
+    /// set on the row that holds a rotated-away secret. requests with that secret are counted as this key
+    pub superseded_by: Option<u64>,
+    /// a rotated-away secret stops working at this time
+    pub expires_at: Option<DateTimeUtc>,
+    /// can only lower the tier's burst_capacity
+    pub burst_capacity: Option<u64>,
+    /// can only lower the tier's sustained_requests_per_period
+    pub sustained_requests_per_period: Option<u64>,
+    /// comma separated chain ids that this key can be used on. null is only the proxy's own chain
+    #[sea_orm(column_type = "Text", nullable)]
+    pub allowed_chains: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
+    #[sea_orm(has_many = "super::canary_hit::Entity")]
+    CanaryHit,
     #[sea_orm(has_many = "super::revert_log::Entity")]
     RevertLog,
+    #[sea_orm(
+        belongs_to = "super::org::Entity",
+        from = "Column::OrgId",
+        to = "super::org::Column::Id",
+        on_update = "NoAction",
+        on_delete = "NoAction"
+    )]
+    Org,
     #[sea_orm(has_many = "super::rpc_accounting::Entity")]
     RpcAccounting,
     #[sea_orm(has_many = "super::rpc_accounting_v2::Entity")]
     RpcAccountingV2,
+    #[sea_orm(
+        belongs_to = "super::rpc_key_pool::Entity",
+        from = "Column::RpcKeyPoolId",
+        to = "super::rpc_key_pool::Column::Id",
+        on_update = "NoAction",
+        on_delete = "NoAction"
+    )]
+    RpcKeyPool,
+    #[sea_orm(
+        belongs_to = "Entity",
+        from = "Column::SupersededBy",
+        to = "Column::Id",
+        on_update = "NoAction",
+        on_delete = "NoAction"
+    )]
+    SelfRef,
     #[sea_orm(has_many = "super::secondary_user::Entity")]
     SecondaryUser,
+    #[sea_orm(has_many = "super::tx_journal::Entity")]
+    TxJournal,
+    #[sea_orm(has_many = "super::tx_screening::Entity")]
+    TxScreening,
     #[sea_orm(
         belongs_to = "super::user::Entity",
         from = "Column::UserId",
@@ -50,6 +118,18 @@ pub enum Relation {
     User,
 }
 
+impl Related<super::canary_hit::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::CanaryHit.def()
+    }
+}
+
+impl Related<super::org::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Org.def()
+    }
+}
+
 impl Related<super::revert_log::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::RevertLog.def()
@@ -68,12 +148,30 @@ impl Related<super::rpc_accounting_v2::Entity> for Entity {
     }
 }
 
+impl Related<super::rpc_key_pool::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::RpcKeyPool.def()
+    }
+}
+
 impl Related<super::secondary_user::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::SecondaryUser.def()
     }
 }
 
+impl Related<super::tx_journal::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::TxJournal.def()
+    }
+}
+
+impl Related<super::tx_screening::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::TxScreening.def()
+    }
+}
+
 impl Related<super::user::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::User.def()
@@ -41,3 +41,55 @@ pub enum Role {
     #[sea_orm(string_value = "collaborator")]
     Collaborator,
 }
+
+/// What a member can do in an org. Only the owner can manage members
+#[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "org_role")]
+#[serde(rename_all = "snake_case")]
+pub enum OrgRole {
+    #[sea_orm(string_value = "owner")]
+    Owner,
+    /// can add funds and see spend
+    #[sea_orm(string_value = "billing_admin")]
+    BillingAdmin,
+    /// can manage the org's keys
+    #[sea_orm(string_value = "developer")]
+    Developer,
+    #[sea_orm(string_value = "viewer")]
+    Viewer,
+}
+
+/// What happened to a screened transaction
+#[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "screening_decision")]
+#[serde(rename_all = "snake_case")]
+pub enum ScreeningDecision {
+    /// nothing matched
+    #[sea_orm(string_value = "allowed")]
+    Allowed,
+    /// matched and sent anyway
+    #[sea_orm(string_value = "logged")]
+    Logged,
+    /// matched, sent anyway, and alerted on
+    #[sea_orm(string_value = "flagged")]
+    Flagged,
+    /// matched and not sent
+    #[sea_orm(string_value = "blocked")]
+    Blocked,
+}
+
+/// How far a journaled transaction got
+#[derive(Debug, Clone, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "tx_journal_status")]
+#[serde(rename_all = "snake_case")]
+pub enum TxJournalStatus {
+    /// saved but not known to be broadcast. recovery finishes these
+    #[sea_orm(string_value = "pending")]
+    Pending,
+    /// a backend accepted it
+    #[sea_orm(string_value = "broadcast")]
+    Broadcast,
+    /// every backend rejected it or recovery gave up
+    #[sea_orm(string_value = "failed")]
+    Failed,
+}
@@ -13,6 +13,7 @@ pub struct Model {
     pub description: Option<String>,
     pub rpc_secret_key_id: u64,
     pub role: Role,
+    pub stats_access: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -28,3 +28,12 @@ where
     // TODO: to_string shouldn't be needed, but i'm still seeing Uuid length
     x.to_string().serialize(s)
 }
+
+pub fn option_uuid_as_ulid<S>(x: &Option<Uuid>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let x = x.map(|x| Ulid::from(x.as_u128()).to_string());
+
+    x.serialize(s)
+}
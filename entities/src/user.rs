@@ -13,6 +13,8 @@ pub struct Model {
     #[serde(serialize_with = "serialization::vec_as_address")]
     pub address: Vec<u8>,
     pub description: Option<String>,
+    /// encrypted if `row_encryption_keyfile` is set
+    #[sea_orm(column_type = "Text", nullable)]
     pub email: Option<String>,
     pub user_tier_id: u64,
 }
@@ -23,6 +25,8 @@ pub enum Relation {
     Login,
     #[sea_orm(has_many = "super::rpc_key::Entity")]
     RpcKey,
+    #[sea_orm(has_many = "super::rpc_key_pool::Entity")]
+    RpcKeyPool,
     #[sea_orm(has_many = "super::secondary_user::Entity")]
     SecondaryUser,
     #[sea_orm(
@@ -47,6 +51,12 @@ impl Related<super::rpc_key::Entity> for Entity {
     }
 }
 
+impl Related<super::rpc_key_pool::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::RpcKeyPool.def()
+    }
+}
+
 impl Related<super::secondary_user::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::SecondaryUser.def()
@@ -12,6 +12,22 @@ pub struct Model {
     pub max_requests_per_period: Option<u64>,
     pub max_concurrent_requests: Option<u32>,
     pub downgrade_tier_id: Option<u64>,
+    pub max_queue_millis: Option<u64>,
+    pub max_batch_size: Option<u64>,
+    #[sea_orm(column_type = "Decimal(Some((20, 10)))", nullable)]
+    pub batch_overhead_cost: Option<Decimal>,
+    pub max_stats_range_seconds: Option<u64>,
+    /// comma separated "method=limit" pairs like "eth_getLogs=10, eth_call=500"
+    #[sea_orm(column_type = "Text", nullable)]
+    pub method_requests_per_second: Option<String>,
+    /// bytes each key can send and receive per month. null is unlimited
+    pub max_monthly_bytes: Option<u64>,
+    /// requests that can be made at once after a quiet spell. null is `max_requests_per_period`
+    pub burst_capacity: Option<u64>,
+    /// requests per rate limit period that refill the burst. null is `max_requests_per_period`
+    pub sustained_requests_per_period: Option<u64>,
+    /// the largest response that any one request can get. null only uses the proxy's per-method limits
+    pub max_response_bytes: Option<u64>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
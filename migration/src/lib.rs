@@ -28,6 +28,34 @@ mod m20230422_172555_premium_downgrade_logic;
 mod m20230511_161214_remove_columns_statsv2_origin_and_method;
 mod m20230512_220213_allow_null_rpc_key_id_in_stats_v2;
 mod m20230514_114803_admin_add_credits;
+mod m20230605_152843_rpc_key_pools;
+mod m20230607_221917_rate_limit_queue;
+mod m20230608_153302_subuser_stats_access;
+mod m20230612_094155_payload_sampling_opt_out;
+mod m20230613_171522_rpc_key_bypass_cache;
+mod m20230614_120312_tier_batch_limits;
+mod m20230615_101807_tier_stats_limits;
+mod m20230616_110512_status_snapshots;
+mod m20230617_091544_idempotency_keys;
+mod m20230618_140328_encrypted_user_email;
+mod m20230619_083412_rpc_key_secret_hash;
+mod m20230620_101233_canary_keys;
+mod m20230621_093154_orgs;
+mod m20230622_141907_rpc_key_project_tags;
+mod m20230623_110402_method_rate_limits;
+mod m20230624_151024_tx_screening;
+mod m20230625_094612_stat_flushes;
+mod m20230626_103217_user_tier_changes;
+mod m20230627_142318_tx_journal;
+mod m20230628_091544_request_shadowing;
+mod m20230629_102455_bandwidth_quotas;
+mod m20230630_114207_refresh_tokens;
+mod m20230701_093012_rpc_key_rotation;
+mod m20230702_105311_admin_audit;
+mod m20230703_091822_token_bucket_limits;
+mod m20230704_102133_rpc_key_allowed_chains;
+mod m20230705_083214_tier_response_limits;
+mod m20230706_091204_rpc_key_pool_secret_hash;
 
 pub struct Migrator;
 
@@ -63,6 +91,34 @@ impl MigratorTrait for Migrator {
             Box::new(m20230511_161214_remove_columns_statsv2_origin_and_method::Migration),
             Box::new(m20230512_220213_allow_null_rpc_key_id_in_stats_v2::Migration),
             Box::new(m20230514_114803_admin_add_credits::Migration),
+            Box::new(m20230605_152843_rpc_key_pools::Migration),
+            Box::new(m20230607_221917_rate_limit_queue::Migration),
+            Box::new(m20230608_153302_subuser_stats_access::Migration),
+            Box::new(m20230612_094155_payload_sampling_opt_out::Migration),
+            Box::new(m20230613_171522_rpc_key_bypass_cache::Migration),
+            Box::new(m20230614_120312_tier_batch_limits::Migration),
+            Box::new(m20230615_101807_tier_stats_limits::Migration),
+            Box::new(m20230616_110512_status_snapshots::Migration),
+            Box::new(m20230617_091544_idempotency_keys::Migration),
+            Box::new(m20230618_140328_encrypted_user_email::Migration),
+            Box::new(m20230619_083412_rpc_key_secret_hash::Migration),
+            Box::new(m20230620_101233_canary_keys::Migration),
+            Box::new(m20230621_093154_orgs::Migration),
+            Box::new(m20230622_141907_rpc_key_project_tags::Migration),
+            Box::new(m20230623_110402_method_rate_limits::Migration),
+            Box::new(m20230624_151024_tx_screening::Migration),
+            Box::new(m20230625_094612_stat_flushes::Migration),
+            Box::new(m20230626_103217_user_tier_changes::Migration),
+            Box::new(m20230627_142318_tx_journal::Migration),
+            Box::new(m20230628_091544_request_shadowing::Migration),
+            Box::new(m20230629_102455_bandwidth_quotas::Migration),
+            Box::new(m20230630_114207_refresh_tokens::Migration),
+            Box::new(m20230701_093012_rpc_key_rotation::Migration),
+            Box::new(m20230702_105311_admin_audit::Migration),
+            Box::new(m20230703_091822_token_bucket_limits::Migration),
+            Box::new(m20230704_102133_rpc_key_allowed_chains::Migration),
+            Box::new(m20230705_083214_tier_response_limits::Migration),
+            Box::new(m20230706_091204_rpc_key_pool_secret_hash::Migration),
         ]
     }
 }
@@ -0,0 +1,103 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(RpcKeyPool::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(RpcKeyPool::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(RpcKeyPool::UserId).big_unsigned().not_null())
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(RpcKeyPool::Table, RpcKeyPool::UserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .col(
+                        ColumnDef::new(RpcKeyPool::SecretKey)
+                            .uuid()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(ColumnDef::new(RpcKeyPool::Description).string())
+                    .col(
+                        ColumnDef::new(RpcKeyPool::Active)
+                            .boolean()
+                            .not_null()
+                            .default(true),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // keys can be in at most one pool
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .add_column(ColumnDef::new(RpcKey::RpcKeyPoolId).big_unsigned())
+                    .add_foreign_key(
+                        TableForeignKey::new()
+                            .name("fk-rpc_key-rpc_key_pool_id")
+                            .from_tbl(RpcKey::Table)
+                            .from_col(RpcKey::RpcKeyPoolId)
+                            .to_tbl(RpcKeyPool::Table)
+                            .to_col(RpcKeyPool::Id),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .drop_foreign_key(Alias::new("fk-rpc_key-rpc_key_pool_id"))
+                    .drop_column(RpcKey::RpcKeyPoolId)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(RpcKeyPool::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    RpcKeyPoolId,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RpcKeyPool {
+    Table,
+    Id,
+    UserId,
+    SecretKey,
+    Description,
+    Active,
+}
@@ -0,0 +1,42 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // owners and admins can always see stats. this lets the key's owner show them to collaborators too
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(SecondaryUser::Table)
+                    .add_column(
+                        ColumnDef::new(SecondaryUser::StatsAccess)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(SecondaryUser::Table)
+                    .drop_column(SecondaryUser::StatsAccess)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum SecondaryUser {
+    Table,
+    StatsAccess,
+}
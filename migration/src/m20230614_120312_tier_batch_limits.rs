@@ -0,0 +1,40 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // null max_batch_size allows any size batch. null batch_overhead_cost means batches cost the same as their requests
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(UserTier::Table)
+                    .add_column(ColumnDef::new(UserTier::MaxBatchSize).big_unsigned())
+                    .add_column(ColumnDef::new(UserTier::BatchOverheadCost).decimal_len(20, 10))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(UserTier::Table)
+                    .drop_column(UserTier::MaxBatchSize)
+                    .drop_column(UserTier::BatchOverheadCost)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum UserTier {
+    Table,
+    MaxBatchSize,
+    BatchOverheadCost,
+}
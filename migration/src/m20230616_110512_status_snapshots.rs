@@ -0,0 +1,75 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(StatusSnapshot::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(StatusSnapshot::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(StatusSnapshot::ContentHash)
+                            .string_len(66)
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(
+                        ColumnDef::new(StatusSnapshot::ChainId)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(StatusSnapshot::CreatedBy).big_unsigned())
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(StatusSnapshot::Table, StatusSnapshot::CreatedBy)
+                            .to(User::Table, User::Id),
+                    )
+                    .col(
+                        ColumnDef::new(StatusSnapshot::CreatedAt)
+                            .timestamp()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    // text and not json so that the bytes (and their hash) never change
+                    .col(ColumnDef::new(StatusSnapshot::Document).text().not_null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(StatusSnapshot::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum StatusSnapshot {
+    Table,
+    Id,
+    ContentHash,
+    ChainId,
+    CreatedBy,
+    CreatedAt,
+    Document,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
@@ -0,0 +1,88 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(IdempotencyKey::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(IdempotencyKey::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(IdempotencyKey::UserId)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(IdempotencyKey::Table, IdempotencyKey::UserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .col(
+                        ColumnDef::new(IdempotencyKey::IdempotencyKey)
+                            .string_len(255)
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(IdempotencyKey::Endpoint).string().not_null())
+                    .col(
+                        ColumnDef::new(IdempotencyKey::RequestHash)
+                            .string_len(66)
+                            .not_null(),
+                    )
+                    // null until the first request finishes
+                    .col(ColumnDef::new(IdempotencyKey::StatusCode).small_unsigned())
+                    .col(ColumnDef::new(IdempotencyKey::Response).text())
+                    .col(
+                        ColumnDef::new(IdempotencyKey::CreatedAt)
+                            .timestamp()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .index(
+                        sea_query::Index::create()
+                            .col(IdempotencyKey::UserId)
+                            .col(IdempotencyKey::IdempotencyKey)
+                            .unique(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(IdempotencyKey::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum IdempotencyKey {
+    Table,
+    Id,
+    UserId,
+    IdempotencyKey,
+    Endpoint,
+    RequestHash,
+    StatusCode,
+    Response,
+    CreatedAt,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
@@ -0,0 +1,47 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    // keyed hash of the secret. new keys only save this
+                    .add_column(
+                        ColumnDef::new(RpcKey::SecretKeyHash)
+                            .string_len(64)
+                            .null()
+                            .unique_key(),
+                    )
+                    // old keys keep their plaintext until `hash_rpc_keys` runs
+                    .modify_column(ColumnDef::new(RpcKey::SecretKey).uuid().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // keys that were only saved as a hash can not be recovered. this will fail if there are any
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .modify_column(ColumnDef::new(RpcKey::SecretKey).uuid().not_null())
+                    .drop_column(RpcKey::SecretKeyHash)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    SecretKey,
+    SecretKeyHash,
+}
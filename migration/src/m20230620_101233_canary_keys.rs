@@ -0,0 +1,116 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // canary keys are never used by their owner. any request with one means the key leaked
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .add_column(
+                        ColumnDef::new(RpcKey::Canary)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .add_column(
+                        ColumnDef::new(RpcKey::CanaryFreezeSiblings)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(CanaryHit::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(CanaryHit::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(CanaryHit::RpcKeyId)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(CanaryHit::Table, CanaryHit::RpcKeyId)
+                            .to(RpcKey::Table, RpcKey::Id),
+                    )
+                    .col(ColumnDef::new(CanaryHit::Ip).string().not_null())
+                    .col(ColumnDef::new(CanaryHit::Origin).text())
+                    .col(ColumnDef::new(CanaryHit::Referer).text())
+                    .col(ColumnDef::new(CanaryHit::UserAgent).text())
+                    .col(
+                        ColumnDef::new(CanaryHit::SiblingsFrozen)
+                            .unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .col(
+                        ColumnDef::new(CanaryHit::Timestamp)
+                            .timestamp()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .index(
+                        sea_query::Index::create()
+                            .col(CanaryHit::RpcKeyId)
+                            .col(CanaryHit::Timestamp),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(CanaryHit::Table).to_owned())
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .drop_column(RpcKey::Canary)
+                    .drop_column(RpcKey::CanaryFreezeSiblings)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    Id,
+    Canary,
+    CanaryFreezeSiblings,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum CanaryHit {
+    Table,
+    Id,
+    RpcKeyId,
+    Ip,
+    Origin,
+    Referer,
+    UserAgent,
+    SiblingsFrozen,
+    Timestamp,
+}
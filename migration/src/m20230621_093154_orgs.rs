@@ -0,0 +1,243 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // orgs have their own balance. members move funds into it from their own balance
+        manager
+            .create_table(
+                Table::create()
+                    .table(Org::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Org::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Org::Name).string().not_null())
+                    .col(ColumnDef::new(Org::OwnerId).big_unsigned().not_null())
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(Org::Table, Org::OwnerId)
+                            .to(User::Table, User::Id),
+                    )
+                    .col(
+                        ColumnDef::new(Org::AvailableBalance)
+                            .decimal_len(20, 10)
+                            .not_null()
+                            .default(0.0),
+                    )
+                    .col(
+                        ColumnDef::new(Org::UsedBalance)
+                            .decimal_len(20, 10)
+                            .not_null()
+                            .default(0.0),
+                    )
+                    .col(
+                        ColumnDef::new(Org::CreatedAt)
+                            .timestamp()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(OrgMember::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(OrgMember::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(OrgMember::OrgId).big_unsigned().not_null())
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(OrgMember::Table, OrgMember::OrgId)
+                            .to(Org::Table, Org::Id),
+                    )
+                    .col(ColumnDef::new(OrgMember::UserId).big_unsigned().not_null())
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(OrgMember::Table, OrgMember::UserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .col(
+                        ColumnDef::new(OrgMember::Role)
+                            .enumeration(
+                                Alias::new("org_role"),
+                                [
+                                    Alias::new("owner"),
+                                    Alias::new("billing_admin"),
+                                    Alias::new("developer"),
+                                    Alias::new("viewer"),
+                                ],
+                            )
+                            .not_null(),
+                    )
+                    .index(
+                        sea_query::Index::create()
+                            .col(OrgMember::OrgId)
+                            .col(OrgMember::UserId)
+                            .unique(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // invites are by address so that users who haven't logged in yet can be invited
+        manager
+            .create_table(
+                Table::create()
+                    .table(OrgInvite::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(OrgInvite::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(OrgInvite::OrgId).big_unsigned().not_null())
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(OrgInvite::Table, OrgInvite::OrgId)
+                            .to(Org::Table, Org::Id),
+                    )
+                    .col(ColumnDef::new(OrgInvite::Address).binary_len(20).not_null())
+                    .col(
+                        ColumnDef::new(OrgInvite::Role)
+                            .enumeration(
+                                Alias::new("org_role"),
+                                [
+                                    Alias::new("owner"),
+                                    Alias::new("billing_admin"),
+                                    Alias::new("developer"),
+                                    Alias::new("viewer"),
+                                ],
+                            )
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(OrgInvite::InvitedBy)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(OrgInvite::Table, OrgInvite::InvitedBy)
+                            .to(User::Table, User::Id),
+                    )
+                    .col(ColumnDef::new(OrgInvite::ExpiresAt).timestamp().not_null())
+                    .index(sea_query::Index::create().col(OrgInvite::Address))
+                    .index(
+                        sea_query::Index::create()
+                            .col(OrgInvite::OrgId)
+                            .col(OrgInvite::Address)
+                            .unique(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // org keys are owned by the org's owner and billed to the org
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .add_column(ColumnDef::new(RpcKey::OrgId).big_unsigned().null())
+                    .add_foreign_key(
+                        TableForeignKey::new()
+                            .name("fk-rpc_key-org_id")
+                            .from_tbl(RpcKey::Table)
+                            .from_col(RpcKey::OrgId)
+                            .to_tbl(Org::Table)
+                            .to_col(Org::Id),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .drop_foreign_key(Alias::new("fk-rpc_key-org_id"))
+                    .drop_column(RpcKey::OrgId)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(OrgInvite::Table).to_owned())
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(OrgMember::Table).to_owned())
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(Org::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Org {
+    Table,
+    Id,
+    Name,
+    OwnerId,
+    AvailableBalance,
+    UsedBalance,
+    CreatedAt,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum OrgMember {
+    Table,
+    Id,
+    OrgId,
+    UserId,
+    Role,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum OrgInvite {
+    Table,
+    Id,
+    OrgId,
+    Address,
+    Role,
+    InvitedBy,
+    ExpiresAt,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    OrgId,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
@@ -0,0 +1,56 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // org keys all belong to the org's owner. created_by keeps track of the member that made the key for billing exports
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .add_column(ColumnDef::new(RpcKey::ProjectTag).string_len(64).null())
+                    .add_column(ColumnDef::new(RpcKey::CreatedBy).big_unsigned().null())
+                    .add_foreign_key(
+                        TableForeignKey::new()
+                            .name("fk-rpc_key-created_by")
+                            .from_tbl(RpcKey::Table)
+                            .from_col(RpcKey::CreatedBy)
+                            .to_tbl(User::Table)
+                            .to_col(User::Id),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .drop_foreign_key(Alias::new("fk-rpc_key-created_by"))
+                    .drop_column(RpcKey::ProjectTag)
+                    .drop_column(RpcKey::CreatedBy)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    ProjectTag,
+    CreatedBy,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
@@ -0,0 +1,102 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // an audit record for every transaction that was screened. clean transactions get a row too
+        manager
+            .create_table(
+                Table::create()
+                    .table(TxScreening::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(TxScreening::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(TxScreening::ChainId)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(TxScreening::TxHash)
+                            .binary_len(32)
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(TxScreening::FromAddress).binary_len(20))
+                    .col(ColumnDef::new(TxScreening::ToAddress).binary_len(20))
+                    .col(ColumnDef::new(TxScreening::MatchedAddress).binary_len(20))
+                    .col(ColumnDef::new(TxScreening::MatchSource).string())
+                    .col(
+                        ColumnDef::new(TxScreening::Decision)
+                            .enumeration(
+                                Alias::new("screening_decision"),
+                                [
+                                    Alias::new("allowed"),
+                                    Alias::new("logged"),
+                                    Alias::new("flagged"),
+                                    Alias::new("blocked"),
+                                ],
+                            )
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(TxScreening::RpcKeyId).big_unsigned())
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(TxScreening::Table, TxScreening::RpcKeyId)
+                            .to(RpcKey::Table, RpcKey::Id),
+                    )
+                    .col(ColumnDef::new(TxScreening::Ip).string().not_null())
+                    .col(
+                        ColumnDef::new(TxScreening::Timestamp)
+                            .timestamp()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .index(sea_query::Index::create().col(TxScreening::TxHash))
+                    .index(
+                        sea_query::Index::create()
+                            .col(TxScreening::Decision)
+                            .col(TxScreening::Timestamp),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(TxScreening::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum TxScreening {
+    Table,
+    Id,
+    ChainId,
+    TxHash,
+    FromAddress,
+    ToAddress,
+    MatchedAddress,
+    MatchSource,
+    Decision,
+    RpcKeyId,
+    Ip,
+    Timestamp,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    Id,
+}
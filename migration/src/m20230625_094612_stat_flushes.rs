@@ -0,0 +1,56 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // one row for every accounting entry the stat buffer saved. a retried save finds its row and is skipped
+        manager
+            .create_table(
+                Table::create()
+                    .table(StatFlush::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(StatFlush::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(StatFlush::FlushId)
+                            .string_len(66)
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(ColumnDef::new(StatFlush::ChainId).big_unsigned().not_null())
+                    .col(
+                        ColumnDef::new(StatFlush::CreatedAt)
+                            .timestamp()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .index(sea_query::Index::create().col(StatFlush::CreatedAt))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(StatFlush::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum StatFlush {
+    Table,
+    Id,
+    FlushId,
+    ChainId,
+    CreatedAt,
+}
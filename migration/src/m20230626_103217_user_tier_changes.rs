@@ -0,0 +1,102 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // one row for every user moved by a tier rebalance. rolling back a rebalance moves them back
+        manager
+            .create_table(
+                Table::create()
+                    .table(UserTierChange::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(UserTierChange::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(UserTierChange::RebalanceId)
+                            .string_len(26)
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(UserTierChange::UserId)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(UserTierChange::Table, UserTierChange::UserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .col(
+                        ColumnDef::new(UserTierChange::OldTierId)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(UserTierChange::Table, UserTierChange::OldTierId)
+                            .to(UserTier::Table, UserTier::Id),
+                    )
+                    .col(
+                        ColumnDef::new(UserTierChange::NewTierId)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(UserTierChange::Table, UserTierChange::NewTierId)
+                            .to(UserTier::Table, UserTier::Id),
+                    )
+                    .col(
+                        ColumnDef::new(UserTierChange::ChangedAt)
+                            .timestamp()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .col(ColumnDef::new(UserTierChange::RolledBackAt).timestamp())
+                    .index(sea_query::Index::create().col(UserTierChange::RebalanceId))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(UserTierChange::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum UserTier {
+    Table,
+    Id,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum UserTierChange {
+    Table,
+    Id,
+    RebalanceId,
+    UserId,
+    OldTierId,
+    NewTierId,
+    ChangedAt,
+    RolledBackAt,
+}
@@ -0,0 +1,111 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // raw transactions are saved here before they are broadcast so that a crash can not lose them
+        manager
+            .create_table(
+                Table::create()
+                    .table(TxJournal::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(TxJournal::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(TxJournal::ChainId).big_unsigned().not_null())
+                    .col(ColumnDef::new(TxJournal::TxHash).binary_len(32).not_null())
+                    // hex like the client sent it. TEXT is too small for transactions with lots of calldata
+                    .col(
+                        ColumnDef::new(TxJournal::RawTx)
+                            .custom(Alias::new("MEDIUMTEXT"))
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(TxJournal::Status)
+                            .enumeration(
+                                Alias::new("tx_journal_status"),
+                                [
+                                    Alias::new("pending"),
+                                    Alias::new("broadcast"),
+                                    Alias::new("failed"),
+                                ],
+                            )
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(TxJournal::Attempts)
+                            .unsigned()
+                            .not_null()
+                            .default(1),
+                    )
+                    .col(ColumnDef::new(TxJournal::LastError).text())
+                    .col(ColumnDef::new(TxJournal::RpcKeyId).big_unsigned())
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(TxJournal::Table, TxJournal::RpcKeyId)
+                            .to(RpcKey::Table, RpcKey::Id),
+                    )
+                    .col(
+                        ColumnDef::new(TxJournal::CreatedAt)
+                            .timestamp()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .col(
+                        ColumnDef::new(TxJournal::UpdatedAt)
+                            .timestamp()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .index(
+                        sea_query::Index::create()
+                            .unique()
+                            .col(TxJournal::ChainId)
+                            .col(TxJournal::TxHash),
+                    )
+                    .index(
+                        sea_query::Index::create()
+                            .col(TxJournal::Status)
+                            .col(TxJournal::UpdatedAt),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(TxJournal::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum TxJournal {
+    Table,
+    Id,
+    ChainId,
+    TxHash,
+    RawTx,
+    Status,
+    Attempts,
+    LastError,
+    RpcKeyId,
+    CreatedAt,
+    UpdatedAt,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    Id,
+}
@@ -0,0 +1,50 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // rotating a key moves its old secret to a new row that points at the key and works until expires_at
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .add_column(ColumnDef::new(RpcKey::SupersededBy).big_unsigned().null())
+                    .add_column(ColumnDef::new(RpcKey::ExpiresAt).timestamp().null())
+                    .add_foreign_key(
+                        TableForeignKey::new()
+                            .name("fk-rpc_key-superseded_by")
+                            .from_tbl(RpcKey::Table)
+                            .from_col(RpcKey::SupersededBy)
+                            .to_tbl(RpcKey::Table)
+                            .to_col(RpcKey::Id),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .drop_foreign_key(Alias::new("fk-rpc_key-superseded_by"))
+                    .drop_column(RpcKey::SupersededBy)
+                    .drop_column(RpcKey::ExpiresAt)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    Id,
+    SupersededBy,
+    ExpiresAt,
+}
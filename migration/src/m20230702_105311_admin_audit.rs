@@ -0,0 +1,92 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // one row for every change an admin makes. old_value and new_value are json
+        manager
+            .create_table(
+                Table::create()
+                    .table(AdminAudit::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AdminAudit::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(AdminAudit::ActorId)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(AdminAudit::Table, AdminAudit::ActorId)
+                            .to(User::Table, User::Id),
+                    )
+                    .col(ColumnDef::new(AdminAudit::Ip).string_len(45).not_null())
+                    .col(ColumnDef::new(AdminAudit::Action).string_len(64).not_null())
+                    .col(ColumnDef::new(AdminAudit::TargetUserId).big_unsigned())
+                    .foreign_key(
+                        sea_query::ForeignKey::create()
+                            .from(AdminAudit::Table, AdminAudit::TargetUserId)
+                            .to(User::Table, User::Id),
+                    )
+                    .col(ColumnDef::new(AdminAudit::Target).string())
+                    .col(ColumnDef::new(AdminAudit::OldValue).text())
+                    .col(ColumnDef::new(AdminAudit::NewValue).text())
+                    .col(
+                        ColumnDef::new(AdminAudit::CreatedAt)
+                            .timestamp()
+                            .not_null()
+                            .extra("DEFAULT CURRENT_TIMESTAMP".to_string()),
+                    )
+                    .index(sea_query::Index::create().col(AdminAudit::CreatedAt))
+                    .index(
+                        sea_query::Index::create()
+                            .col(AdminAudit::TargetUserId)
+                            .col(AdminAudit::CreatedAt),
+                    )
+                    .index(
+                        sea_query::Index::create()
+                            .col(AdminAudit::Action)
+                            .col(AdminAudit::CreatedAt),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AdminAudit::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum User {
+    Table,
+    Id,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum AdminAudit {
+    Table,
+    Id,
+    ActorId,
+    Ip,
+    Action,
+    TargetUserId,
+    Target,
+    OldValue,
+    NewValue,
+    CreatedAt,
+}
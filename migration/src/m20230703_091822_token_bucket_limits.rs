@@ -0,0 +1,69 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // null falls back to max_requests_per_period
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(UserTier::Table)
+                    .add_column(ColumnDef::new(UserTier::BurstCapacity).big_unsigned())
+                    .add_column(ColumnDef::new(UserTier::SustainedRequestsPerPeriod).big_unsigned())
+                    .to_owned(),
+            )
+            .await?;
+
+        // null uses the tier's limits. keys can only lower them
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .add_column(ColumnDef::new(RpcKey::BurstCapacity).big_unsigned())
+                    .add_column(ColumnDef::new(RpcKey::SustainedRequestsPerPeriod).big_unsigned())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKey::Table)
+                    .drop_column(RpcKey::BurstCapacity)
+                    .drop_column(RpcKey::SustainedRequestsPerPeriod)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(UserTier::Table)
+                    .drop_column(UserTier::BurstCapacity)
+                    .drop_column(UserTier::SustainedRequestsPerPeriod)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum UserTier {
+    Table,
+    BurstCapacity,
+    SustainedRequestsPerPeriod,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RpcKey {
+    Table,
+    BurstCapacity,
+    SustainedRequestsPerPeriod,
+}
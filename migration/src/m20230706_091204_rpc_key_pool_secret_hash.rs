@@ -0,0 +1,48 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKeyPool::Table)
+                    // keyed hash of the secret. new pools only save this
+                    .add_column(
+                        ColumnDef::new(RpcKeyPool::SecretKeyHash)
+                            .string_len(64)
+                            .null()
+                            .unique_key(),
+                    )
+                    // the hash key is in rpc_key_hash_keyfile, not the database.
+                    // old pools keep their plaintext until `hash_rpc_keys` (or the proxy's startup) hashes them
+                    .modify_column(ColumnDef::new(RpcKeyPool::SecretKey).uuid().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // pools that were only saved as a hash can not be recovered. this will fail if there are any
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(RpcKeyPool::Table)
+                    .modify_column(ColumnDef::new(RpcKeyPool::SecretKey).uuid().not_null())
+                    .drop_column(RpcKeyPool::SecretKeyHash)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RpcKeyPool {
+    Table,
+    SecretKey,
+    SecretKeyHash,
+}
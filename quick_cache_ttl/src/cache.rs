@@ -97,6 +97,12 @@ impl<
         Self(inner)
     }
 
+    /// Keep track of keys so that `remove_matching` works.
+    /// Only call this for caches that need it. It costs a key clone and a lock on every insert
+    pub fn purgeable(self) -> Self {
+        Self(self.0.purgeable())
+    }
+
     #[inline]
     pub fn get(&self, key: &Key) -> Option<Val> {
         self.0.get(key, &())
@@ -137,4 +143,19 @@ impl<
     pub fn remove(&self, key: &Key) -> bool {
         self.0.remove(key, &())
     }
+
+    /// Remove every entry that `f` returns true for. Returns how many were removed.
+    /// The cache must have been made `purgeable`
+    #[inline]
+    pub fn remove_matching<F>(&self, f: F) -> usize
+    where
+        F: Fn(&Key, &Val) -> bool,
+    {
+        self.0.remove_matching(|key, _, val| f(key, val))
+    }
+
+    #[inline]
+    pub fn tracked_keys(&self) -> usize {
+        self.0.tracked_keys()
+    }
 }
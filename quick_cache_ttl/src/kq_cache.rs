@@ -1,18 +1,101 @@
-use log::{log_enabled, trace};
+use log::{log_enabled, trace, warn};
 use quick_cache::sync::KQCache;
 use quick_cache::{PlaceholderGuard, Weighter};
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
 use std::convert::Infallible;
 use std::fmt::Debug;
 use std::future::Future;
-use std::hash::{BuildHasher, Hash};
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::num::NonZeroU32;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::task::JoinHandle;
 use tokio::time::{sleep_until, Instant};
 
+const LIVE_KEY_SHARDS: usize = 16;
+
+/// the smallest number of keys a shard holds before it checks for evicted keys
+const MIN_SHARD_LIMIT: usize = 8;
+
+struct LiveKeyShard<Key, Qey> {
+    keys: HashSet<(Key, Qey)>,
+    /// prune when `keys` is longer than this
+    limit: usize,
+}
+
+/// The keys of a purgeable cache. quick_cache can't iterate its entries, so this is how `remove_matching` finds them.
+/// Only caches that called `purgeable` have this. Everything else skips the bookkeeping.
+///
+/// quick_cache doesn't tell us when it evicts for capacity or ttl.
+/// Instead, a shard that grows past its limit drops every key that is no longer in the cache.
+/// That keeps the keys close to what the cache actually holds without scanning on every insert.
+struct LiveKeys<Key, Qey> {
+    hasher: RandomState,
+    shards: Box<[Mutex<LiveKeyShard<Key, Qey>>]>,
+}
+
+impl<Key: Eq + Hash, Qey: Eq + Hash> LiveKeys<Key, Qey> {
+    fn new(estimated_items_capacity: usize) -> Self {
+        // twice the expected share so that a full cache doesn't prune on every insert
+        let limit = (estimated_items_capacity / LIVE_KEY_SHARDS * 2).max(MIN_SHARD_LIMIT);
+
+        let shards = (0..LIVE_KEY_SHARDS)
+            .map(|_| {
+                Mutex::new(LiveKeyShard {
+                    keys: HashSet::new(),
+                    limit,
+                })
+            })
+            .collect();
+
+        Self {
+            hasher: RandomState::new(),
+            shards,
+        }
+    }
+
+    fn shard(&self, key: &Key, qey: &Qey) -> &Mutex<LiveKeyShard<Key, Qey>> {
+        let mut hasher = self.hasher.build_hasher();
+        key.hash(&mut hasher);
+        qey.hash(&mut hasher);
+
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    fn insert<Val: Clone, We: Weighter<Key, Qey, Val>, B: BuildHasher>(
+        &self,
+        cache: &KQCache<Key, Qey, Val, We, B>,
+        key: Key,
+        qey: Qey,
+    ) {
+        let mut shard = self.shard(&key, &qey).lock().unwrap();
+
+        shard.keys.insert((key, qey));
+
+        if shard.keys.len() > shard.limit {
+            // drop the keys that were evicted or expired
+            shard
+                .keys
+                .retain(|(key, qey)| cache.peek(key, qey).is_some());
+
+            // if the cache really does hold this many, give the shard room so we don't scan again on the next insert
+            shard.limit = shard.limit.max(shard.keys.len() * 2);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().keys.len())
+            .sum()
+    }
+}
+
 pub struct KQCacheWithTTL<Key, Qey, Val, We, B> {
     cache: Arc<KQCache<Key, Qey, Val, We, B>>,
+    estimated_items_capacity: usize,
+    live_keys: Option<LiveKeys<Key, Qey>>,
     max_item_weight: NonZeroU32,
     name: &'static str,
     ttl: Duration,
@@ -30,7 +113,9 @@ struct KQCacheWithTTLTask<Key, Qey, Val, We, B> {
 
 pub struct PlaceholderGuardWithTTL<'a, Key, Qey, Val, We, B> {
     name: &'a str,
+    cache: &'a KQCache<Key, Qey, Val, We, B>,
     inner: PlaceholderGuard<'a, Key, Qey, Val, We, B>,
+    live_keys: Option<&'a LiveKeys<Key, Qey>>,
     key: Key,
     qey: Qey,
     ttl: Duration,
@@ -75,6 +160,8 @@ impl<
 
         Self {
             cache,
+            estimated_items_capacity,
+            live_keys: None,
             max_item_weight,
             name,
             task_handle,
@@ -84,6 +171,13 @@ impl<
         }
     }
 
+    /// Keep track of keys so that `remove_matching` works.
+    /// Only call this for caches that need it. It costs a key clone and a lock on every insert
+    pub fn purgeable(mut self) -> Self {
+        self.live_keys = Some(LiveKeys::new(self.estimated_items_capacity));
+        self
+    }
+
     #[inline]
     pub fn get(&self, key: &Key, qey: &Qey) -> Option<Val> {
         self.cache.get(key, qey)
@@ -109,7 +203,8 @@ impl<
     where
         Fut: Future<Output = Result<Val, E>>,
     {
-        self.cache
+        let x = self
+            .cache
             .get_or_insert_async(key, qey, async move {
                 let x = f.await;
 
@@ -129,7 +224,13 @@ impl<
 
                 x
             })
-            .await
+            .await;
+
+        if let (Some(live_keys), Ok(_)) = (&self.live_keys, &x) {
+            live_keys.insert(&self.cache, key.clone(), qey.clone());
+        }
+
+        x
     }
 
     #[inline]
@@ -142,7 +243,9 @@ impl<
             Ok(x) => Ok(x),
             Err(inner) => Err(PlaceholderGuardWithTTL {
                 name: self.name,
+                cache: &self.cache,
                 inner,
+                live_keys: self.live_keys.as_ref(),
                 key,
                 qey,
                 ttl: self.ttl,
@@ -175,6 +278,10 @@ impl<
                 expire_at.duration_since(Instant::now()).as_secs_f32()
             );
 
+            if let Some(live_keys) = &self.live_keys {
+                live_keys.insert(&self.cache, key.clone(), qey.clone());
+            }
+
             self.tx.send((expire_at, key, qey)).unwrap();
 
             Ok(())
@@ -197,6 +304,50 @@ impl<
     pub fn remove(&self, key: &Key, qey: &Qey) -> bool {
         self.cache.remove(key, qey)
     }
+
+    /// Remove every entry that `f` returns true for. Returns how many were removed.
+    /// This has to look at every live key, so it is for rare things like an admin purging bad data. Not the hot path.
+    /// Only one shard of keys is locked at a time, so inserts into the other shards keep going
+    pub fn remove_matching<F>(&self, f: F) -> usize
+    where
+        F: Fn(&Key, &Qey, &Val) -> bool,
+    {
+        let live_keys = if let Some(x) = &self.live_keys {
+            x
+        } else {
+            warn!("{} is not purgeable. nothing removed", self.name);
+            return 0;
+        };
+
+        let mut removed = 0;
+
+        for shard in live_keys.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+
+            shard
+                .keys
+                .retain(|(key, qey)| match self.cache.peek(key, qey) {
+                    Some(val) if f(key, qey, &val) => {
+                        if self.cache.remove(key, qey) {
+                            removed += 1;
+                        }
+                        false
+                    }
+                    Some(_) => true,
+                    // evicted or expired
+                    None => false,
+                });
+        }
+
+        trace!("removed {} matching entries from {}", removed, self.name);
+
+        removed
+    }
+
+    /// how many keys `remove_matching` would have to check
+    pub fn tracked_keys(&self) -> usize {
+        self.live_keys.as_ref().map(|x| x.len()).unwrap_or_default()
+    }
 }
 
 impl<
@@ -265,6 +416,10 @@ impl<
             );
         }
 
+        if let Some(live_keys) = self.live_keys {
+            live_keys.insert(self.cache, self.key.clone(), self.qey.clone());
+        }
+
         self.tx.send((expire_at, self.key, self.qey)).unwrap();
     }
 }
@@ -52,6 +52,75 @@ mod tests {
         assert!(x.get(&0).is_none());
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_remove_matching() {
+        let x = CacheWithTTL::<u32, u32>::new("test", 10, Duration::from_secs(2))
+            .await
+            .purgeable();
+
+        for i in 0..5 {
+            x.try_insert(i, i * 10).unwrap();
+        }
+
+        assert_eq!(x.remove_matching(|k, v| *k >= 3 || *v == 0), 3);
+
+        assert!(x.get(&0).is_none());
+        assert!(x.get(&1).is_some());
+        assert!(x.get(&2).is_some());
+        assert!(x.get(&3).is_none());
+
+        // removed entries are not counted twice
+        assert_eq!(x.remove_matching(|k, _| *k >= 3), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_remove_matching_not_purgeable() {
+        let x = CacheWithTTL::<u32, u32>::new("test", 10, Duration::from_secs(2)).await;
+
+        x.try_insert(0, 0).unwrap();
+
+        assert_eq!(x.tracked_keys(), 0);
+        assert_eq!(x.remove_matching(|_, _| true), 0);
+        assert!(x.get(&0).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_evicted_keys_are_not_tracked() {
+        let x = CacheWithTTL::<u32, u32>::new("test", 10, Duration::from_secs(600))
+            .await
+            .purgeable();
+
+        for i in 0..1_000 {
+            x.try_insert(i, i).unwrap();
+        }
+
+        // every shard prunes the keys that were evicted for capacity once it passes its limit
+        assert!(x.tracked_keys() < 200, "{}", x.tracked_keys());
+
+        // the newest keys are still in the cache and can still be purged
+        assert!(x.get(&999).is_some());
+        assert_eq!(x.remove_matching(|k, _| *k == 999), 1);
+        assert!(x.get(&999).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_keys_are_not_tracked() {
+        let x = CacheWithTTL::<u32, u32>::new("test", 10, Duration::from_secs(2))
+            .await
+            .purgeable();
+
+        for i in 0..8 {
+            x.try_insert(i, i).unwrap();
+        }
+
+        time::advance(Duration::from_secs(3)).await;
+        yield_now().await;
+
+        // expired entries are skipped and dropped
+        assert_eq!(x.remove_matching(|_, _| true), 0);
+        assert_eq!(x.tracked_keys(), 0);
+    }
+
     // #[tokio::test(start_paused = true)]
     // async fn test_overweight() {
     //     todo!("wip");
@@ -1,4 +1,6 @@
 //#![warn(missing_docs)]
+mod token_bucket;
+
 use anyhow::Context;
 use std::ops::Add;
 use tokio::time::{Duration, Instant};
@@ -8,6 +10,7 @@ pub use deadpool_redis::{
     Config as RedisConfig, Connection as RedisConnection, Manager as RedisManager,
     Pool as RedisPool, Runtime as DeadpoolRuntime,
 };
+pub use token_bucket::{RedisTokenBucket, TokenBucket, TokenBucketResult};
 
 #[derive(Clone)]
 pub struct RedisRateLimiter {
@@ -60,6 +63,34 @@ impl RedisRateLimiter {
         Instant::now().add(Duration::from_secs_f32(seconds_left_in_period))
     }
 
+    /// seconds since the epoch when the current period ends
+    pub fn period_end_as_secs(&self, now_as_secs: f32) -> f32 {
+        now_as_secs - (now_as_secs % self.period) + self.period
+    }
+
+    /// how many requests the label has made in the current period. unlike `throttle_label`, this does not count as a request
+    pub async fn peek_label(&self, label: &str) -> anyhow::Result<u64> {
+        let now = self.now_as_secs();
+
+        let period_id = self.period_id(now);
+
+        let throttle_key = format!("{}:{}:{}", self.key_prefix, label, period_id);
+
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .context("get redis connection for rate limits")?;
+
+        let count: Option<u64> = redis::cmd("GET")
+            .arg(&throttle_key)
+            .query_async(&mut *conn)
+            .await
+            .context("cannot get rate limit")?;
+
+        Ok(count.unwrap_or_default())
+    }
+
     /// label might be an ip address or a rpc_key id.
     /// if setting max_per_period, be sure to keep the period the same for all requests to this label
     pub async fn throttle_label(
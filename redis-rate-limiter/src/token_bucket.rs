@@ -0,0 +1,238 @@
+use super::{redis, RedisPool};
+use anyhow::Context;
+use tokio::time::{Duration, Instant};
+
+/// Refill every bucket for the time since it was last used and then take `count` tokens from all of them if they all have enough.
+/// If any bucket is short, nothing is taken from any of them. Redis runs the whole script at once, so the buckets can't be left half debited.
+/// Redis's clock is used so that every proxy agrees on how much has refilled.
+///
+/// KEYS are the buckets. ARGV is {count, expire_ms, capacity_1, per_second_1, capacity_2, per_second_2, ...}
+/// Returns {allowed, milliseconds until every bucket has enough tokens, whole tokens left in bucket 1, whole tokens left in bucket 2, ...}
+const THROTTLE_SCRIPT: &str = r#"
+local count = tonumber(ARGV[1])
+
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+local tokens = {}
+local allowed = 1
+local wait_ms = 0
+
+for i, key in ipairs(KEYS) do
+    local capacity = tonumber(ARGV[i * 2 + 1])
+    local per_ms = tonumber(ARGV[i * 2 + 2]) / 1000
+
+    local state = redis.call('HMGET', key, 'tokens', 'at')
+    local x = tonumber(state[1]) or capacity
+    local at = tonumber(state[2]) or now
+
+    x = math.min(capacity, x + math.max(0, now - at) * per_ms)
+
+    if x < count then
+        allowed = 0
+        wait_ms = math.max(wait_ms, math.ceil((count - x) / per_ms))
+    end
+
+    tokens[i] = x
+end
+
+local result = {allowed, wait_ms}
+
+for i, key in ipairs(KEYS) do
+    if allowed == 1 then
+        tokens[i] = tokens[i] - count
+    end
+
+    redis.call('HSET', key, 'tokens', tostring(tokens[i]), 'at', tostring(now))
+    redis.call('PEXPIRE', key, ARGV[2])
+
+    result[i + 2] = math.floor(tokens[i])
+end
+
+return result
+"#;
+
+/// Like THROTTLE_SCRIPT, but only reads. ARGV is {capacity_1, per_second_1, capacity_2, per_second_2, ...}
+/// Returns the whole tokens in each bucket
+const PEEK_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+local result = {}
+
+for i, key in ipairs(KEYS) do
+    local capacity = tonumber(ARGV[i * 2 - 1])
+    local per_ms = tonumber(ARGV[i * 2]) / 1000
+
+    local state = redis.call('HMGET', key, 'tokens', 'at')
+    local x = tonumber(state[1]) or capacity
+    local at = tonumber(state[2]) or now
+
+    result[i] = math.floor(math.min(capacity, x + math.max(0, now - at) * per_ms))
+end
+
+return result
+"#;
+
+/// One bucket. keep `capacity` and `per_second` the same for every request to a label
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenBucket {
+    /// label might be a user id or an rpc key id
+    pub label: String,
+    pub capacity: u64,
+    pub per_second: f64,
+}
+
+impl TokenBucket {
+    pub fn new(label: String, capacity: u64, per_second: f64) -> Self {
+        Self {
+            label,
+            capacity,
+            per_second,
+        }
+    }
+
+    /// a bucket that is left alone this long is full again. there's no need to keep it
+    pub fn refill_duration(&self) -> Duration {
+        Duration::from_secs_f64(self.capacity as f64 / self.per_second)
+    }
+}
+
+/// A token bucket for every label. Buckets hold up to `capacity` tokens and refill at `per_second`.
+/// Unlike `RedisRateLimiter`'s fixed windows, a label that was quiet can burst and then has to slow down to the refill rate
+#[derive(Clone)]
+pub struct RedisTokenBucket {
+    key_prefix: String,
+    pool: RedisPool,
+    /// these are sent with EVALSHA. the script's text is only sent again if redis forgot it
+    throttle_script: redis::Script,
+    peek_script: redis::Script,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenBucketResult {
+    /// whole tokens left in the emptiest bucket
+    Allowed(u64),
+    /// every bucket will have enough tokens at this time. the inner value is the whole tokens left in the emptiest bucket
+    RetryAt(Instant, u64),
+    RetryNever,
+}
+
+impl RedisTokenBucket {
+    pub fn new(app: &str, label: &str, pool: RedisPool) -> Self {
+        let key_prefix = format!("{}:rtb:{}", app, label);
+
+        Self {
+            key_prefix,
+            pool,
+            throttle_script: redis::Script::new(THROTTLE_SCRIPT),
+            peek_script: redis::Script::new(PEEK_SCRIPT),
+        }
+    }
+
+    /// Take `count` tokens from every bucket, or from none of them.
+    /// Also returns the whole tokens left in each bucket, in the same order as `buckets`
+    pub async fn throttle_labels(
+        &self,
+        buckets: &[TokenBucket],
+        count: u64,
+    ) -> anyhow::Result<(TokenBucketResult, Vec<u64>)> {
+        if buckets
+            .iter()
+            .any(|x| count > x.capacity || x.per_second <= 0.0)
+        {
+            return Ok((TokenBucketResult::RetryNever, vec![0; buckets.len()]));
+        }
+
+        let expire_ms = buckets
+            .iter()
+            .map(|x| x.refill_duration())
+            .max()
+            .unwrap_or_default()
+            .as_millis() as u64
+            + 1_000;
+
+        let mut invocation = self.throttle_script.prepare_invoke();
+
+        for bucket in buckets {
+            invocation.key(format!("{}:{}", self.key_prefix, bucket.label));
+        }
+
+        invocation.arg(count).arg(expire_ms);
+
+        for bucket in buckets {
+            invocation.arg(bucket.capacity).arg(bucket.per_second);
+        }
+
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .context("get redis connection for token buckets")?;
+
+        let x: Vec<u64> = invocation
+            .invoke_async(&mut *conn)
+            .await
+            .context("cannot take from token buckets")?;
+
+        let (allowed, wait_ms, tokens) = match x.as_slice() {
+            [allowed, wait_ms, tokens @ ..] if tokens.len() == buckets.len() => {
+                (*allowed == 1, *wait_ms, tokens.to_vec())
+            }
+            _ => return Err(anyhow::anyhow!("unexpected token bucket reply: {:?}", x)),
+        };
+
+        let emptiest = tokens.iter().copied().min().unwrap_or_default();
+
+        let result = if allowed {
+            TokenBucketResult::Allowed(emptiest)
+        } else {
+            TokenBucketResult::RetryAt(Instant::now() + Duration::from_millis(wait_ms), emptiest)
+        };
+
+        Ok((result, tokens))
+    }
+
+    /// a single bucket. see `throttle_labels`
+    pub async fn throttle_label(
+        &self,
+        label: &str,
+        capacity: u64,
+        per_second: f64,
+        count: u64,
+    ) -> anyhow::Result<TokenBucketResult> {
+        let bucket = TokenBucket::new(label.to_string(), capacity, per_second);
+
+        self.throttle_labels(&[bucket], count).await.map(|(x, _)| x)
+    }
+
+    /// How many whole tokens each bucket has, in the same order as `buckets`.
+    /// Unlike `throttle_labels`, this does not take any and does not write anything
+    pub async fn peek_labels(&self, buckets: &[TokenBucket]) -> anyhow::Result<Vec<u64>> {
+        if buckets.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut invocation = self.peek_script.prepare_invoke();
+
+        for bucket in buckets {
+            invocation
+                .key(format!("{}:{}", self.key_prefix, bucket.label))
+                .arg(bucket.capacity)
+                .arg(bucket.per_second);
+        }
+
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .context("get redis connection for token buckets")?;
+
+        let x: Vec<u64> = invocation
+            .invoke_async(&mut *conn)
+            .await
+            .context("cannot peek token buckets")?;
+
+        Ok(x)
+    }
+}
@@ -1,6 +1,7 @@
 use crate::app::Web3ProxyApp;
+use crate::frontend::admin_audit::AdminAction;
 use crate::frontend::errors::{Web3ProxyError, Web3ProxyResponse};
-use crate::http_params::get_user_id_from_params;
+use crate::http_params::{get_user_id_from_params, AdminModifyRoleParams};
 use anyhow::Context;
 use axum::response::IntoResponse;
 use axum::{
@@ -8,12 +9,13 @@ use axum::{
     Json, TypedHeader,
 };
 use entities::{admin, login, user, user_tier};
-use ethers::prelude::Address;
 use hashbrown::HashMap;
 use log::{debug, info};
 use migration::sea_orm::{
     self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter,
 };
+use serde_json::json;
+use std::net::IpAddr;
 
 // TODO: Add some logic to check if the operating user is an admin
 // If he is, return true
@@ -22,26 +24,20 @@ use migration::sea_orm::{
 
 pub async fn query_admin_modify_usertier<'a>(
     app: &'a Web3ProxyApp,
+    ip: IpAddr,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
-    params: &'a HashMap<String, String>,
+    params: &'a AdminModifyRoleParams,
 ) -> Web3ProxyResponse {
     // Quickly return if any of the input tokens are bad
     let user_address: Vec<u8> = params
-        .get("user_address")
-        .ok_or_else(|| {
-            Web3ProxyError::BadRequest("Unable to find user_address key in request".to_string())
-        })?
-        .parse::<Address>()
-        .map_err(|_| {
-            Web3ProxyError::BadRequest("Unable to parse user_address as an Address".to_string())
-        })?
+        .user_address
+        .ok_or(Web3ProxyError::MissingParam("user_address"))?
         .to_fixed_bytes()
         .into();
-    let user_tier_title = params.get("user_tier_title").ok_or_else(|| {
-        Web3ProxyError::BadRequest(
-            "Unable to get the user_tier_title key from the request".to_string(),
-        )
-    })?;
+    let user_tier_title = params
+        .user_tier_title
+        .as_ref()
+        .ok_or(Web3ProxyError::MissingParam("user_tier_title"))?;
 
     // Prepare output body
     let mut response_body = HashMap::new();
@@ -64,8 +60,14 @@ pub async fn query_admin_modify_usertier<'a>(
     // Try to get the user who is calling from redis (if existent) / else from the database
     // TODO: Make a single query, where you retrieve the user, and directly from it the secondary user (otherwise we do two jumpy, which is unnecessary)
     // get the user id first. if it is 0, we should use a cache on the app
-    let caller_id =
-        get_user_id_from_params(&mut redis_conn, &db_conn, &db_replica, bearer, params).await?;
+    let caller_id = get_user_id_from_params(
+        &mut redis_conn,
+        &db_conn,
+        &db_replica,
+        bearer,
+        params.user_id,
+    )
+    .await?;
 
     debug!("Caller id is: {:?}", caller_id);
 
@@ -83,9 +85,7 @@ pub async fn query_admin_modify_usertier<'a>(
         .filter(user::Column::Address.eq(user_address))
         .one(&db_conn)
         .await?
-        .ok_or(Web3ProxyError::BadRequest(
-            "No user with this id found".to_string(),
-        ))?;
+        .ok_or(Web3ProxyError::UserNotFound)?;
     // Return early if the target user_tier_id is the same as the original user_tier_id
     response_body.insert(
         "user_tier_title",
@@ -97,19 +97,30 @@ pub async fn query_admin_modify_usertier<'a>(
         .filter(user_tier::Column::Title.eq(user_tier_title.clone()))
         .one(&db_conn)
         .await?
-        .ok_or(Web3ProxyError::BadRequest(
-            "User Tier name was not found".to_string(),
-        ))?;
+        .ok_or(Web3ProxyError::UserTierNotFound)?;
 
     if user.user_tier_id == new_user_tier.id {
         info!("user already has that tier");
     } else {
+        let user_id = user.id;
+        let old_user_tier_id = user.user_tier_id;
+
         let mut user = user.clone().into_active_model();
 
         user.user_tier_id = sea_orm::Set(new_user_tier.id);
 
         user.save(&db_conn).await?;
 
+        AdminAction::new("modify_role")
+            .target_user(user_id)
+            .old_value(json!({ "user_tier_id": old_user_tier_id }))
+            .new_value(json!({
+                "user_tier_id": new_user_tier.id,
+                "user_tier_title": new_user_tier.title,
+            }))
+            .save(&db_conn, caller_id, ip)
+            .await?;
+
         info!("user's tier changed");
     }
 
@@ -0,0 +1,316 @@
+//! Cut keys off when their balance runs out. Stats already take credits from the `balance` table, but only when the stat buffer saves them.
+//!
+//! Every user (or org) that a key on a `balance_cutoff.tiers` tier spends from has its balance in memory.
+//! Each billable request takes its credits from it as soon as its stat is built, so a key is cut off without waiting for the stat buffer.
+//! Every `balance_cutoff.reconcile_seconds` the balances are loaded from the database again. That picks up top ups and other proxies' spending.
+//! Credits spent here that the stat buffer hasn't saved yet are taken out again.
+//!
+//! Requests are rejected once the balance is at `-grace`. The database never goes below 0, so the grace is only given once.
+//! It starts again after the balance is topped up. Like the other limits, requests are allowed if the database is down.
+use super::Web3ProxyApp;
+use crate::frontend::authorization::Authorization;
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
+use entities::{balance, org};
+use hashbrown::HashMap;
+use log::{debug, error, warn};
+use migration::sea_orm::prelude::Decimal;
+use migration::sea_orm::{ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter};
+use num_traits::ToPrimitive;
+use parking_lot::RwLock;
+use std::sync::atomic::{self, AtomicBool, AtomicI64};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{interval, MissedTickBehavior};
+
+/// balances are kept in billionths of a credit so that they can be atomics
+const NANOS_PER_CREDIT: i64 = 1_000_000_000;
+
+fn to_nanos(credits: Decimal) -> i64 {
+    credits
+        .checked_mul(Decimal::from(NANOS_PER_CREDIT))
+        .and_then(|x| x.round().to_i64())
+        .unwrap_or(if credits.is_sign_negative() {
+            i64::MIN
+        } else {
+            i64::MAX
+        })
+}
+
+fn from_nanos(nanos: i64) -> Decimal {
+    Decimal::new(nanos, 9)
+}
+
+/// Whose balance a key spends. Org keys spend the org's balance
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum BalanceOwner {
+    User(u64),
+    Org(u64),
+}
+
+#[derive(Debug, Default)]
+pub struct UserBalance {
+    /// the database's balance minus the credits spent here since. nano credits
+    remaining: AtomicI64,
+    /// credits spent here that the stat buffer hasn't saved yet. nano credits
+    unsaved: AtomicI64,
+    /// checked since the last reconcile. balances that weren't are forgotten
+    used: AtomicBool,
+}
+
+impl UserBalance {
+    fn new(available: Decimal) -> Self {
+        Self {
+            remaining: AtomicI64::new(to_nanos(available)),
+            unsaved: AtomicI64::new(0),
+            used: AtomicBool::new(true),
+        }
+    }
+
+    pub fn remaining(&self) -> Decimal {
+        from_nanos(self.remaining.load(atomic::Ordering::Acquire))
+    }
+
+    /// Take a finished request's credits
+    pub fn spend(&self, credits: Decimal) {
+        if credits <= Decimal::ZERO {
+            return;
+        }
+
+        let x = to_nanos(credits);
+
+        self.unsaved.fetch_add(x, atomic::Ordering::AcqRel);
+        self.remaining.fetch_sub(x, atomic::Ordering::AcqRel);
+    }
+
+    /// The stat buffer saved these credits (or gave up on them). The database has all it will ever have of them
+    pub fn settle(&self, credits: Decimal) {
+        if credits <= Decimal::ZERO {
+            return;
+        }
+
+        self.unsaved
+            .fetch_sub(to_nanos(credits), atomic::Ordering::AcqRel);
+    }
+
+    /// Replace the balance with the database's.
+    /// The database stops at 0, so an empty balance can only go down. Otherwise the grace would be given again every time
+    fn reconcile(&self, available: Decimal) {
+        let x = to_nanos(available).saturating_sub(self.unsaved.load(atomic::Ordering::Acquire));
+
+        if available > Decimal::ZERO {
+            self.remaining.store(x, atomic::Ordering::Release);
+        } else {
+            self.remaining.fetch_min(x, atomic::Ordering::AcqRel);
+        }
+    }
+
+    /// true if the balance and the grace are used up
+    fn exhausted(&self, grace: Decimal) -> bool {
+        self.remaining.load(atomic::Ordering::Acquire) <= to_nanos(grace).saturating_neg()
+    }
+}
+
+/// The balances of everyone who has recently used a key that is cut off at 0
+pub type Balances = RwLock<HashMap<BalanceOwner, Arc<UserBalance>>>;
+
+/// The available balances of the owners that have a row. Owners without a row have nothing
+async fn load_available(
+    db_conn: &DatabaseConnection,
+    owners: &[BalanceOwner],
+) -> Result<HashMap<BalanceOwner, Decimal>, DbErr> {
+    let mut user_ids = vec![];
+    let mut org_ids = vec![];
+
+    for owner in owners {
+        match owner {
+            BalanceOwner::User(x) => user_ids.push(*x),
+            BalanceOwner::Org(x) => org_ids.push(*x),
+        }
+    }
+
+    let mut available = HashMap::with_capacity(owners.len());
+
+    if !user_ids.is_empty() {
+        for x in balance::Entity::find()
+            .filter(balance::Column::UserId.is_in(user_ids))
+            .all(db_conn)
+            .await?
+        {
+            available.insert(BalanceOwner::User(x.user_id), x.available_balance);
+        }
+    }
+
+    if !org_ids.is_empty() {
+        for x in org::Entity::find()
+            .filter(org::Column::Id.is_in(org_ids))
+            .all(db_conn)
+            .await?
+        {
+            available.insert(BalanceOwner::Org(x.id), x.available_balance);
+        }
+    }
+
+    Ok(available)
+}
+
+impl Web3ProxyApp {
+    /// Error if the key's balance and the grace are used up
+    pub async fn check_balance(&self, authorization: &Authorization) -> Web3ProxyResult<()> {
+        let owner = match authorization.checks.balance_owner {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let balance = match self.load_balance(owner).await {
+            Ok(x) => x,
+            Err(err) => {
+                error!(
+                    "unable to load balance. allowing {:?}. err={:?}",
+                    owner, err
+                );
+
+                return Ok(());
+            }
+        };
+
+        balance.used.store(true, atomic::Ordering::Release);
+
+        let grace = self.config.balance_cutoff.grace;
+
+        if balance.exhausted(grace) {
+            return Err(Web3ProxyError::BalanceExhausted {
+                balance: balance.remaining(),
+                grace,
+                hint: self.backoff_hint(None, None),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The in-memory balance that the key's requests are taken from. None if the key is not cut off at 0
+    pub fn user_balance(&self, authorization: &Authorization) -> Option<Arc<UserBalance>> {
+        let owner = authorization.checks.balance_owner?;
+
+        self.balances.read().get(&owner).cloned()
+    }
+
+    async fn load_balance(&self, owner: BalanceOwner) -> Web3ProxyResult<Arc<UserBalance>> {
+        if let Some(x) = self.balances.read().get(&owner) {
+            return Ok(x.clone());
+        }
+
+        let db_conn = self.db_conn().web3_context("balances need a db")?;
+
+        let available = load_available(&db_conn, &[owner])
+            .await?
+            .remove(&owner)
+            .unwrap_or_default();
+
+        // another request might have loaded it while this one waited on the database
+        let x = self
+            .balances
+            .write()
+            .entry(owner)
+            .or_insert_with(|| Arc::new(UserBalance::new(available)))
+            .clone();
+
+        Ok(x)
+    }
+
+    /// Load the balances from the database every `balance_cutoff.reconcile_seconds`.
+    /// Balances that weren't checked since the last time are forgotten
+    pub(super) async fn reconcile_balances(self: Arc<Self>) -> Web3ProxyResult<()> {
+        let config = &self.config.balance_cutoff;
+
+        if !config.enabled {
+            return Ok(());
+        }
+
+        let mut interval = interval(Duration::from_secs(config.reconcile_seconds.max(1)));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let db_conn = match self.db_conn() {
+                Some(x) => x,
+                None => {
+                    debug!("no database. balances are not cut off");
+                    return Ok(());
+                }
+            };
+
+            // anything with unsaved credits is kept so that they aren't forgotten
+            self.balances.write().retain(|_, x| {
+                x.used.swap(false, atomic::Ordering::AcqRel)
+                    || x.unsaved.load(atomic::Ordering::Acquire) > 0
+            });
+
+            let balances: Vec<_> = self
+                .balances
+                .read()
+                .iter()
+                .map(|(k, v)| (*k, v.clone()))
+                .collect();
+
+            if balances.is_empty() {
+                continue;
+            }
+
+            let owners: Vec<_> = balances.iter().map(|(k, _)| *k).collect();
+
+            let available = match load_available(&db_conn, &owners).await {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!("reconciling balances failed: {:?}", err);
+                    continue;
+                }
+            };
+
+            for (owner, balance) in balances {
+                balance.reconcile(available.get(&owner).copied().unwrap_or_default());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_balance() {
+        let x = UserBalance::new(Decimal::new(5, 0));
+
+        x.spend(Decimal::new(3, 0));
+        assert_eq!(x.remaining(), Decimal::new(2, 0));
+        assert!(!x.exhausted(Decimal::ZERO));
+
+        // another proxy spent 1. the 3 from here haven't been saved yet
+        x.reconcile(Decimal::new(4, 0));
+        assert_eq!(x.remaining(), Decimal::new(1, 0));
+
+        // the stat buffer saved them
+        x.settle(Decimal::new(3, 0));
+        x.reconcile(Decimal::new(1, 0));
+        assert_eq!(x.remaining(), Decimal::new(1, 0));
+
+        // into the grace
+        x.spend(Decimal::new(15, 1));
+        assert_eq!(x.remaining(), Decimal::new(-5, 1));
+        assert!(!x.exhausted(Decimal::new(1, 0)));
+        assert!(x.exhausted(Decimal::new(5, 1)));
+
+        // the database stops at 0. the grace isn't given again
+        x.settle(Decimal::new(15, 1));
+        x.reconcile(Decimal::ZERO);
+        assert_eq!(x.remaining(), Decimal::new(-5, 1));
+
+        // a top up starts over
+        x.reconcile(Decimal::new(10, 0));
+        assert_eq!(x.remaining(), Decimal::new(10, 0));
+
+        assert_eq!(to_nanos(Decimal::new(18, 6)), 18_000);
+    }
+}
@@ -0,0 +1,155 @@
+//! Monthly byte quotas. Stats already count every request's and response's bytes. This enforces the tier's `max_monthly_bytes` with them.
+//!
+//! A key's bytes for the month are loaded from `rpc_accounting_v2` and cached for a minute.
+//! Requests and responses add to the cached total as they happen, so a key is cut off without waiting for its stats to be saved.
+//! Quotas reset at midnight UTC on the first day of every month.
+use super::Web3ProxyApp;
+use crate::frontend::authorization::Authorization;
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
+use chrono::{DateTime, Datelike, Months, Utc};
+use entities::rpc_accounting_v2;
+use log::error;
+use migration::sea_orm::prelude::Decimal;
+use migration::sea_orm::{
+    self, ColumnTrait, EntityTrait, FromQueryResult, QueryFilter, QuerySelect,
+};
+use num_traits::ToPrimitive;
+use std::num::NonZeroU64;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::Arc;
+
+/// midnight UTC on the first day of the month
+fn month_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    let x = now
+        .date_naive()
+        .with_day(1)
+        .expect("every month has a first day")
+        .and_hms_opt(0, 0, 0)
+        .expect("midnight is valid");
+
+    DateTime::<Utc>::from_utc(x, Utc)
+}
+
+/// when the current month's quotas reset
+fn next_month_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    month_start(now)
+        .checked_add_months(Months::new(1))
+        .expect("next month is in range")
+}
+
+impl Web3ProxyApp {
+    /// Error if the key has used up its tier's bytes for the month.
+    /// Like the rate limits, this fails open if the database is down
+    pub async fn check_bandwidth_quota(
+        &self,
+        authorization: &Authorization,
+    ) -> Web3ProxyResult<()> {
+        let max_bytes = match authorization.checks.max_monthly_bytes {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let rpc_key_id = match authorization.checks.rpc_secret_key_id {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let used = match self
+            .bandwidth_usage
+            .try_get_or_insert_async(&rpc_key_id, self.load_bandwidth_used(rpc_key_id))
+            .await
+        {
+            Ok(x) => x,
+            Err(err) => {
+                error!(
+                    "unable to load bandwidth used. allowing key {}. err={:?}",
+                    rpc_key_id, err
+                );
+
+                return Ok(());
+            }
+        };
+
+        let used_bytes = used.load(atomic::Ordering::Acquire);
+
+        if used_bytes >= max_bytes {
+            return Err(Web3ProxyError::BandwidthExceeded {
+                used_bytes,
+                max_bytes,
+                resets_at: next_month_start(Utc::now()).timestamp(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The cached total for a key with a monthly quota. The key's requests and responses add to it
+    pub fn bandwidth_used(&self, authorization: &Authorization) -> Option<Arc<AtomicU64>> {
+        authorization.checks.max_monthly_bytes?;
+
+        self.bandwidth_usage
+            .get(&authorization.checks.rpc_secret_key_id?)
+    }
+
+    /// Sum the key's saved stats for this month. Stats that haven't been saved yet are counted once the cache expires
+    async fn load_bandwidth_used(&self, rpc_key_id: NonZeroU64) -> Web3ProxyResult<Arc<AtomicU64>> {
+        let db_replica = self
+            .db_replica()
+            .web3_context("bandwidth quotas need a db")?;
+
+        #[derive(Debug, FromQueryResult)]
+        struct BytesUsed {
+            sum_request_bytes: Option<Decimal>,
+            sum_response_bytes: Option<Decimal>,
+        }
+
+        let x = rpc_accounting_v2::Entity::find()
+            .select_only()
+            .column_as(
+                rpc_accounting_v2::Column::SumRequestBytes.sum(),
+                "sum_request_bytes",
+            )
+            .column_as(
+                rpc_accounting_v2::Column::SumResponseBytes.sum(),
+                "sum_response_bytes",
+            )
+            .filter(rpc_accounting_v2::Column::RpcKeyId.eq(rpc_key_id.get()))
+            .filter(rpc_accounting_v2::Column::PeriodDatetime.gte(month_start(Utc::now())))
+            .into_model::<BytesUsed>()
+            .one(db_replica.conn())
+            .await?;
+
+        let used_bytes = x
+            .map(|x| {
+                let request_bytes = x.sum_request_bytes.and_then(|x| x.to_u64());
+                let response_bytes = x.sum_response_bytes.and_then(|x| x.to_u64());
+
+                request_bytes
+                    .unwrap_or_default()
+                    .saturating_add(response_bytes.unwrap_or_default())
+            })
+            .unwrap_or_default();
+
+        Ok(Arc::new(AtomicU64::new(used_bytes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_month_boundaries() {
+        let now = Utc.with_ymd_and_hms(2023, 12, 31, 23, 59, 59).unwrap();
+
+        assert_eq!(
+            month_start(now),
+            Utc.with_ymd_and_hms(2023, 12, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            next_month_start(now),
+            Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()
+        );
+    }
+}
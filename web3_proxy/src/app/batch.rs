@@ -0,0 +1,54 @@
+//! Limits for JSON-RPC batches.
+//! A batch is split up and every request in it is routed on its own. These decide how big a batch can be and how much of it runs at once.
+use super::Web3ProxyApp;
+use crate::frontend::authorization::{Authorization, AuthorizationType};
+
+/// The lowest of the limits. None if there are none
+fn lowest<T: Ord + Copy>(limits: &[Option<T>]) -> Option<T> {
+    limits.iter().flatten().min().copied()
+}
+
+impl Web3ProxyApp {
+    /// The largest batch that this authorization can send. None is unlimited
+    pub(crate) fn max_batch_size(&self, authorization: &Authorization) -> Option<u64> {
+        lowest(&[
+            self.config.max_batch_size,
+            authorization.checks.max_batch_size,
+        ])
+    }
+
+    /// How many requests of a batch of `num_requests` to send at once.
+    /// A batch only holds one of the key's (or ip's) concurrency permits, so it is also capped at that limit
+    pub(crate) fn batch_parallelism(
+        &self,
+        authorization: &Authorization,
+        num_requests: usize,
+    ) -> usize {
+        let concurrency_limit = if authorization.authorization_type == AuthorizationType::Internal {
+            None
+        } else if authorization.checks.rpc_secret_key_id.is_some() {
+            authorization
+                .checks
+                .max_concurrent_requests
+                .map(|x| x as usize)
+        } else {
+            self.config.public_max_concurrent_requests
+        };
+
+        lowest(&[self.config.batch_max_parallel, concurrency_limit])
+            .unwrap_or(num_requests)
+            .clamp(1, num_requests.max(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::lowest;
+
+    #[test]
+    fn test_lowest() {
+        assert_eq!(lowest::<u64>(&[None, None]), None);
+        assert_eq!(lowest(&[Some(100u64), None]), Some(100));
+        assert_eq!(lowest(&[Some(100u64), Some(10)]), Some(10));
+    }
+}
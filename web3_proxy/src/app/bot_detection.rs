@@ -0,0 +1,335 @@
+//! Tag keys and IPs whose traffic looks like an MEV bot.
+//!
+//! Two patterns are tagged: racing new blocks with a burst of simulations (like `eth_call`) right after every head,
+//! and sending lots of bundles. Tags only go into the timeseries stats. Nothing is blocked.
+use super::Web3ProxyApp;
+use crate::config::BotDetectionConfig;
+use crate::frontend::authorization::Authorization;
+use crate::frontend::errors::Web3ProxyResult;
+use hashbrown::HashMap;
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::fmt;
+use std::net::IpAddr;
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{Duration, Instant};
+
+/// Methods that simulate transactions against the newest state
+const SIMULATION_METHODS: &[&str] = &[
+    "debug_traceCall",
+    "eth_call",
+    "eth_callBundle",
+    "eth_createAccessList",
+    "eth_estimateGas",
+];
+
+const BUNDLE_METHODS: &[&str] = &[
+    "eth_callBundle",
+    "eth_sendBundle",
+    "eth_sendPrivateTransaction",
+    "mev_sendBundle",
+];
+
+/// Clients that have not sent anything in this long are forgotten (unless they are still tagged)
+const IDLE_SECONDS: u64 = 300;
+
+/// Keys are tracked by key. Anonymous traffic is tracked by ip
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BotClient {
+    RpcKey(NonZeroU64),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for BotClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RpcKey(x) => write!(f, "rpc key {}", x),
+            Self::Ip(x) => write!(f, "ip {}", x),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BotReason {
+    /// bursts of simulations right after new heads
+    HeadRacing,
+    /// lots of bundles
+    Bundles,
+}
+
+/// A client that is tagged right now. Returned by `GET /admin/bots`
+#[derive(Debug, Serialize)]
+pub struct TaggedBot {
+    pub client: BotClient,
+    pub reason: BotReason,
+    /// seconds until the tag expires. it is extended every time the client matches again
+    pub expires_in: u64,
+}
+
+#[derive(Debug)]
+struct ClientActivity {
+    last_seen: Instant,
+    /// the block that `head_calls` are for
+    head_num: u64,
+    head_calls: u32,
+    /// the last block that got at least `head_calls` simulations
+    last_raced_block: u64,
+    /// how many blocks in a row were raced
+    raced_blocks: u32,
+    bundle_window_start: Instant,
+    bundles: u32,
+    tagged: Option<(BotReason, Instant)>,
+}
+
+impl ClientActivity {
+    fn new(now: Instant) -> Self {
+        Self {
+            last_seen: now,
+            head_num: 0,
+            head_calls: 0,
+            last_raced_block: 0,
+            raced_blocks: 0,
+            bundle_window_start: now,
+            bundles: 0,
+            tagged: None,
+        }
+    }
+
+    /// Count one request. `head_age` is how long ago the current head was seen.
+    /// Returns the reason if the client is tagged
+    fn observe(
+        &mut self,
+        config: &BotDetectionConfig,
+        method: &str,
+        head_num: u64,
+        head_age: Duration,
+        now: Instant,
+    ) -> Option<BotReason> {
+        self.last_seen = now;
+
+        let mut matched = None;
+
+        if SIMULATION_METHODS.contains(&method)
+            && head_age <= Duration::from_millis(config.head_window_ms)
+        {
+            if self.head_num != head_num {
+                self.head_num = head_num;
+                self.head_calls = 0;
+            }
+
+            self.head_calls += 1;
+
+            // only count the block once
+            if self.head_calls == config.head_calls.max(1) {
+                if self.last_raced_block + 1 == head_num {
+                    self.raced_blocks += 1;
+                } else {
+                    self.raced_blocks = 1;
+                }
+                self.last_raced_block = head_num;
+
+                if self.raced_blocks >= config.racing_blocks.max(1) {
+                    matched = Some(BotReason::HeadRacing);
+                }
+            }
+        }
+
+        if BUNDLE_METHODS.contains(&method) {
+            if now.duration_since(self.bundle_window_start) > Duration::from_secs(60) {
+                self.bundle_window_start = now;
+                self.bundles = 0;
+            }
+
+            self.bundles += 1;
+
+            if self.bundles >= config.bundles_per_minute.max(1) {
+                matched = Some(BotReason::Bundles);
+            }
+        }
+
+        if let Some(reason) = matched {
+            self.tagged = Some((reason, now + Duration::from_secs(config.tag_seconds)));
+        }
+
+        match self.tagged {
+            Some((reason, until)) if until > now => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BotDetector {
+    /// the newest head's number and when it was seen
+    head: Mutex<Option<(u64, Instant)>>,
+    clients: Mutex<HashMap<BotClient, ClientActivity>>,
+}
+
+impl BotDetector {
+    fn set_head(&self, head_num: u64) {
+        *self.head.lock() = Some((head_num, Instant::now()));
+    }
+
+    fn forget_idle(&self, now: Instant) {
+        let idle = Duration::from_secs(IDLE_SECONDS);
+
+        self.clients.lock().retain(|_, x| {
+            now.duration_since(x.last_seen) < idle || x.tagged.map(|x| x.1 > now).unwrap_or(false)
+        });
+    }
+}
+
+impl Web3ProxyApp {
+    /// Count the request and return true if its key or ip is tagged as a probable bot
+    pub fn observe_probable_bot(&self, authorization: &Authorization, method: &str) -> bool {
+        let config = &self.config.bot_detection;
+
+        if !config.enabled || authorization.is_internal() {
+            return false;
+        }
+
+        let client = match authorization.checks.rpc_secret_key_id {
+            Some(x) => BotClient::RpcKey(x),
+            None => BotClient::Ip(authorization.ip),
+        };
+
+        let now = Instant::now();
+
+        let (head_num, head_age) = match *self.bot_detector.head.lock() {
+            Some((num, seen_at)) => (num, now.duration_since(seen_at)),
+            None => (0, Duration::MAX),
+        };
+
+        let mut clients = self.bot_detector.clients.lock();
+
+        let activity = clients
+            .entry(client)
+            .or_insert_with(|| ClientActivity::new(now));
+
+        let was_tagged = activity.tagged.map(|x| x.1 > now).unwrap_or(false);
+
+        let reason = activity.observe(config, method, head_num, head_age, now);
+
+        if let (false, Some(reason)) = (was_tagged, reason) {
+            info!("{} tagged as a probable bot: {:?}", client, reason);
+        }
+
+        reason.is_some()
+    }
+
+    /// The clients that are tagged right now
+    pub fn tagged_bots(&self) -> Vec<TaggedBot> {
+        let now = Instant::now();
+
+        self.bot_detector
+            .clients
+            .lock()
+            .iter()
+            .filter_map(|(client, x)| {
+                let (reason, until) = x.tagged?;
+
+                (until > now).then(|| TaggedBot {
+                    client: *client,
+                    reason,
+                    expires_in: until.duration_since(now).as_secs(),
+                })
+            })
+            .collect()
+    }
+
+    /// Keep track of when each head arrived so that simulations can be timed against it
+    pub(super) async fn watch_heads_for_bots(self: Arc<Self>) -> Web3ProxyResult<()> {
+        if !self.config.bot_detection.enabled {
+            return Ok(());
+        }
+
+        let mut head_events = self.head_events();
+
+        loop {
+            match head_events.recv().await {
+                Ok(event) => {
+                    if let Some(head) = event.head_block() {
+                        self.bot_detector.set_head(head.number().as_u64());
+
+                        self.bot_detector.forget_idle(Instant::now());
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("bot detection missed {} head events", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_observe() {
+        let config = BotDetectionConfig {
+            enabled: true,
+            head_window_ms: 500,
+            head_calls: 2,
+            racing_blocks: 2,
+            bundles_per_minute: 3,
+            tag_seconds: 60,
+        };
+
+        let now = Instant::now();
+        let fresh = Duration::from_millis(100);
+
+        let mut x = ClientActivity::new(now);
+
+        // block 10 is raced. one block is not enough
+        assert_eq!(x.observe(&config, "eth_call", 10, fresh, now), None);
+        assert_eq!(x.observe(&config, "eth_call", 10, fresh, now), None);
+
+        // calls long after the head do not count
+        let stale = Duration::from_secs(5);
+        assert_eq!(x.observe(&config, "eth_call", 10, stale, now), None);
+
+        // block 11 is raced too
+        assert_eq!(x.observe(&config, "eth_call", 11, fresh, now), None);
+        assert_eq!(
+            x.observe(&config, "eth_call", 11, fresh, now),
+            Some(BotReason::HeadRacing)
+        );
+
+        // the tag sticks for any method until it expires
+        assert_eq!(
+            x.observe(&config, "eth_blockNumber", 11, stale, now),
+            Some(BotReason::HeadRacing)
+        );
+        let later = now + Duration::from_secs(61);
+        assert_eq!(
+            x.observe(&config, "eth_blockNumber", 11, stale, later),
+            None
+        );
+
+        // skipping a block starts the count over
+        let mut x = ClientActivity::new(now);
+        for head_num in [20, 22] {
+            x.observe(&config, "eth_call", head_num, fresh, now);
+            assert_eq!(x.observe(&config, "eth_call", head_num, fresh, now), None);
+        }
+
+        // bundles
+        let mut x = ClientActivity::new(now);
+        assert_eq!(x.observe(&config, "eth_sendBundle", 0, stale, now), None);
+        assert_eq!(x.observe(&config, "eth_sendBundle", 0, stale, now), None);
+        assert_eq!(
+            x.observe(&config, "eth_sendBundle", 0, stale, now),
+            Some(BotReason::Bundles)
+        );
+    }
+}
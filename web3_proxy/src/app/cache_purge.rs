@@ -0,0 +1,340 @@
+//! Evict bad data from the caches without restarting the proxy.
+//! Useful after an upstream served bad blocks or bad responses.
+//!
+//! Responses for blocks that a reorg removed from the chain are evicted as soon as the reorg is seen on the head event bus.
+use super::Web3ProxyApp;
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::response_cache::{CachedResponseInfo, JsonRpcResponseCacheKey};
+use crate::rpcs::blockchain::Web3ProxyBlock;
+use crate::rpcs::head_events::HeadEvent;
+use ethers::types::{H256, U64};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+
+/// How far back from the new head a reorg is checked for. Deeper reorgs are left for the cache's ttl
+const REORG_CHECK_DEPTH: usize = 128;
+
+/// Which caches to purge
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CachePurgeTarget {
+    #[default]
+    All,
+    /// the jsonrpc response cache. this includes `eth_getLogs` responses
+    Responses,
+    /// the blocks cached by hash and by number
+    Blocks,
+}
+
+/// Select the cache entries to purge. Every selector that is set must match.
+/// An empty selector purges everything
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CachePurgeSelector {
+    pub cache: CachePurgeTarget,
+    /// if this is not the proxy's chain, nothing is purged.
+    /// this lets a script send the same purge to every proxy
+    pub chain_id: Option<u64>,
+    /// a method name. ending in "*" matches any method with that prefix (like "eth_get*")
+    pub method: Option<String>,
+    /// the first block to purge
+    pub from_block: Option<u64>,
+    /// the last block to purge
+    pub to_block: Option<u64>,
+    /// the JSON-encoded params of one cached request. use with `method` to purge a single response
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CachePurgeResult {
+    pub chain_id: u64,
+    pub responses_purged: usize,
+    pub blocks_purged: usize,
+}
+
+impl CachePurgeSelector {
+    fn method_matches(&self, method: &str) -> bool {
+        match self.method.as_deref() {
+            None => true,
+            Some(x) => match x.strip_suffix('*') {
+                Some(prefix) => method.starts_with(prefix),
+                None => method == x,
+            },
+        }
+    }
+
+    fn has_block_range(&self) -> bool {
+        self.from_block.is_some() || self.to_block.is_some()
+    }
+
+    /// true if `from..=to` overlaps the selected blocks
+    fn blocks_overlap(&self, from: U64, to: U64) -> bool {
+        self.from_block.map_or(true, |x| to >= U64::from(x))
+            && self.to_block.map_or(true, |x| from <= U64::from(x))
+    }
+
+    fn response_matches(
+        &self,
+        info: &CachedResponseInfo,
+        params: Option<&serde_json::Value>,
+    ) -> bool {
+        if !self.method_matches(&info.method) {
+            return false;
+        }
+
+        if self.key.is_some() && info.params.as_ref() != params {
+            return false;
+        }
+
+        if self.has_block_range() {
+            let from = info.from_block;
+            let to = info.to_block;
+
+            // responses that do not depend on a block are not in any range
+            match (from.or(to), to.or(from)) {
+                (Some(from), Some(to)) => self.blocks_overlap(from, to),
+                _ => false,
+            }
+        } else {
+            true
+        }
+    }
+}
+
+/// True if the response is for a block that is no longer part of the chain.
+/// `chain` is the heaviest chain's hashes by number. Heights that aren't in it can't be checked and are kept
+fn response_orphaned(
+    key: &JsonRpcResponseCacheKey,
+    head_num: U64,
+    chain: &HashMap<U64, H256>,
+) -> bool {
+    [key.from_block.as_ref(), key.to_block.as_ref()]
+        .into_iter()
+        .flatten()
+        .any(|block| match (block.number, block.hash) {
+            (Some(num), Some(hash)) => {
+                num > head_num || chain.get(&num).map_or(false, |x| *x != hash)
+            }
+            _ => false,
+        })
+}
+
+impl Web3ProxyApp {
+    /// Remove responses for blocks that are not on the chain that ends at `head`
+    fn purge_orphaned_responses(&self, head: &Web3ProxyBlock) -> usize {
+        let chain = self.balanced_rpcs.cached_chain(head, REORG_CHECK_DEPTH);
+
+        self.jsonrpc_response_cache
+            .remove_matching(|key, _| response_orphaned(key, *head.number(), &chain))
+    }
+
+    /// Drop responses for blocks that a reorg removed. Their keys have the old hashes, so they would sit in the cache until their ttl
+    pub(super) async fn invalidate_reorged_responses(self: Arc<Self>) -> Web3ProxyResult<()> {
+        let mut head_events = self.head_events();
+
+        loop {
+            match head_events.recv().await {
+                Ok(HeadEvent::Reorg { new, .. }) => {
+                    let removed = self.purge_orphaned_responses(&new);
+
+                    debug!("reorg removed {} cached responses", removed);
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    // one of the skipped events might have been a reorg. check against the current head instead
+                    warn!("response cache missed {} head events", skipped);
+
+                    let head = self.watch_consensus_head_receiver.borrow().clone();
+
+                    if let Some(head) = head {
+                        self.purge_orphaned_responses(&head);
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove everything matching the selector from the response and block caches.
+    /// This includes the responses and blocks that are shared with other replicas in redis
+    pub async fn purge_caches(
+        &self,
+        selector: &CachePurgeSelector,
+    ) -> Web3ProxyResult<CachePurgeResult> {
+        let chain_id = self.config.chain_id;
+
+        let mut result = CachePurgeResult {
+            chain_id,
+            ..Default::default()
+        };
+
+        if selector.chain_id.map_or(false, |x| x != chain_id) {
+            return Ok(result);
+        }
+
+        if let (Some(from), Some(to)) = (selector.from_block, selector.to_block) {
+            if from > to {
+                return Err(Web3ProxyError::InvalidParam(
+                    "from_block",
+                    "must not be after to_block".into(),
+                ));
+            }
+        }
+
+        let params = selector
+            .key
+            .as_deref()
+            .map(serde_json::from_str::<serde_json::Value>)
+            .transpose()
+            .map_err(|err| Web3ProxyError::InvalidParam("key", format!("{}", err).into()))?;
+
+        if matches!(
+            selector.cache,
+            CachePurgeTarget::All | CachePurgeTarget::Responses
+        ) {
+            result.responses_purged = self
+                .response_cache_backend
+                .remove_matching(&|info| selector.response_matches(info, params.as_ref()))
+                .await?;
+        }
+
+        // blocks are not cached by method, so a selector for specific requests leaves them alone
+        let selects_requests = selector.method.is_some() || selector.key.is_some();
+
+        if !selects_requests
+            && matches!(
+                selector.cache,
+                CachePurgeTarget::All | CachePurgeTarget::Blocks
+            )
+        {
+            result.blocks_purged = self
+                .balanced_rpcs
+                .purge_blocks(
+                    selector.from_block.map(U64::from),
+                    selector.to_block.map(U64::from),
+                )
+                .await?;
+        }
+
+        info!("purged caches. {:?} -> {:?}", selector, result);
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Block, TxHash};
+    use serde_json::json;
+
+    fn cached_info(method: &str, from: Option<u64>, to: Option<u64>) -> CachedResponseInfo {
+        CachedResponseInfo {
+            method: method.to_string(),
+            params: Some(json!(["0x1", false])),
+            from_block: from.map(U64::from),
+            to_block: to.map(U64::from),
+        }
+    }
+
+    #[test]
+    fn test_response_orphaned() {
+        let block = |num: u64, hash: u64| {
+            Arc::new(Block::<TxHash> {
+                number: Some(num.into()),
+                hash: Some(H256::from_low_u64_be(hash)),
+                ..Default::default()
+            })
+        };
+
+        let key = |from: Option<(u64, u64)>, to: Option<(u64, u64)>| JsonRpcResponseCacheKey {
+            from_block: from.map(|(num, hash)| block(num, hash)),
+            to_block: to.map(|(num, hash)| block(num, hash)),
+            method: "eth_getLogs".to_string(),
+            params: None,
+            cache_errors: false,
+            cache_partition: None,
+        };
+
+        // the new chain is 8 -> 9 -> 10 with hashes 80, 91, 101
+        let chain: HashMap<U64, H256> = [(8, 80), (9, 91), (10, 101)]
+            .into_iter()
+            .map(|(num, hash)| (U64::from(num), H256::from_low_u64_be(hash)))
+            .collect();
+
+        let head_num = U64::from(10);
+
+        assert!(!response_orphaned(&key(None, None), head_num, &chain));
+        assert!(!response_orphaned(
+            &key(Some((8, 80)), Some((10, 101))),
+            head_num,
+            &chain
+        ));
+
+        // blocks from the old chain
+        assert!(response_orphaned(
+            &key(Some((9, 90)), None),
+            head_num,
+            &chain
+        ));
+        assert!(response_orphaned(
+            &key(Some((8, 80)), Some((10, 100))),
+            head_num,
+            &chain
+        ));
+
+        // the old chain was longer
+        assert!(response_orphaned(
+            &key(None, Some((11, 110))),
+            head_num,
+            &chain
+        ));
+
+        // too old to check
+        assert!(!response_orphaned(
+            &key(Some((2, 20)), None),
+            head_num,
+            &chain
+        ));
+    }
+
+    #[test]
+    fn test_purge_selectors() {
+        let by_method = CachePurgeSelector {
+            method: Some("eth_get*".to_string()),
+            ..Default::default()
+        };
+        assert!(by_method.response_matches(&cached_info("eth_getLogs", Some(1), Some(2)), None));
+        assert!(!by_method.response_matches(&cached_info("eth_call", Some(1), None), None));
+
+        let by_range = CachePurgeSelector {
+            from_block: Some(10),
+            to_block: Some(20),
+            ..Default::default()
+        };
+        assert!(by_range.response_matches(&cached_info("eth_getLogs", Some(5), Some(10)), None));
+        assert!(by_range.response_matches(&cached_info("eth_call", None, Some(15)), None));
+        assert!(!by_range.response_matches(&cached_info("eth_getLogs", Some(21), Some(30)), None));
+        assert!(!by_range.response_matches(&cached_info("eth_chainId", None, None), None));
+
+        let by_key = CachePurgeSelector {
+            method: Some("eth_getBlockByNumber".to_string()),
+            key: Some(r#"["0x1", false]"#.to_string()),
+            ..Default::default()
+        };
+        let params = json!(["0x1", false]);
+        assert!(by_key.response_matches(
+            &cached_info("eth_getBlockByNumber", Some(1), None),
+            Some(&params)
+        ));
+        assert!(!by_key.response_matches(
+            &cached_info("eth_getBlockByNumber", Some(1), None),
+            Some(&json!(["0x2", false]))
+        ));
+    }
+}
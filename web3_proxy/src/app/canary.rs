@@ -0,0 +1,177 @@
+//! Honeypot keys. A key owner marks a key as a canary and then leaves it somewhere it could leak (CI logs, a public repo).
+//! The owner never uses it, so any request with it means someone found it.
+//!
+//! Requests with a canary key are rejected like an unknown key so that the caller doesn't learn anything.
+//! The hit is logged as an error, saved for the owner, and optionally POSTed to `canary_webhook_url`.
+use super::Web3ProxyApp;
+use crate::frontend::authorization::Authorization;
+use axum::headers::{Header, Referer};
+use chrono::Utc;
+use entities::{canary_hit, rpc_key};
+use log::{error, trace, warn};
+use migration::sea_orm::{self, ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter};
+use migration::Expr;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::num::NonZeroU64;
+use std::time::Duration;
+
+#[derive(Debug, Serialize)]
+pub struct CanaryAlert {
+    pub chain_id: u64,
+    pub rpc_key_id: NonZeroU64,
+    pub user_id: u64,
+    pub ip: IpAddr,
+    pub origin: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub siblings_frozen: u64,
+    /// unix epoch seconds
+    pub timestamp: i64,
+}
+
+impl Web3ProxyApp {
+    /// Alert on a request that used a canary key. Errors are logged instead of returned.
+    /// The caller should still reject the request
+    pub(crate) async fn canary_tripped(&self, authorization: &Authorization) {
+        let rpc_key_id = match authorization.checks.rpc_secret_key_id {
+            Some(x) => x,
+            None => return,
+        };
+
+        if self
+            .canary_alerts
+            .try_insert((rpc_key_id, authorization.ip), ())
+            .is_err()
+        {
+            trace!(
+                "canary key {} already alerted for {}",
+                rpc_key_id,
+                authorization.ip
+            );
+            return;
+        }
+
+        let user_id = authorization.checks.user_id;
+
+        let siblings_frozen = if authorization.checks.canary_freeze_siblings {
+            match self.freeze_sibling_keys(user_id, rpc_key_id).await {
+                Ok(x) => x,
+                Err(err) => {
+                    error!("failed freezing keys for user {}: {:?}", user_id, err);
+                    0
+                }
+            }
+        } else {
+            0
+        };
+
+        let alert = CanaryAlert {
+            chain_id: self.config.chain_id,
+            rpc_key_id,
+            user_id,
+            ip: authorization.ip,
+            origin: authorization.origin.as_ref().map(|x| x.to_string()),
+            referer: authorization.referer.as_ref().and_then(referer_to_string),
+            user_agent: authorization
+                .user_agent
+                .as_ref()
+                .map(|x| x.as_str().to_string()),
+            siblings_frozen,
+            timestamp: Utc::now().timestamp(),
+        };
+
+        // error logs are sent to sentry
+        error!("canary rpc key used! {:?}", alert);
+
+        if let Err(err) = self.save_canary_hit(&alert).await {
+            warn!("failed saving canary hit: {:?}", err);
+        }
+
+        if let Err(err) = self.send_canary_webhook(&alert).await {
+            warn!("failed sending canary webhook: {:?}", err);
+        }
+    }
+
+    /// Deactivate the user's other keys. Returns how many were deactivated.
+    /// Other proxies keep their cached keys until the caches expire
+    async fn freeze_sibling_keys(
+        &self,
+        user_id: u64,
+        rpc_key_id: NonZeroU64,
+    ) -> anyhow::Result<u64> {
+        let db_conn = match self.db_conn() {
+            Some(x) => x,
+            None => return Ok(0),
+        };
+
+        let frozen = rpc_key::Entity::update_many()
+            .col_expr(rpc_key::Column::Active, Expr::value(false))
+            .filter(rpc_key::Column::UserId.eq(user_id))
+            .filter(rpc_key::Column::Id.ne(rpc_key_id.get()))
+            .filter(rpc_key::Column::Active.eq(true))
+            .exec(&db_conn)
+            .await?
+            .rows_affected;
+
+        // the canary key is removed too. it will be loaded again on its next use
+        self.rpc_secret_key_cache
+            .remove_matching(|_, x| x.user_id == user_id);
+        self.rpc_key_id_cache
+            .remove_matching(|_, x| x.user_id == user_id);
+
+        Ok(frozen)
+    }
+
+    async fn save_canary_hit(&self, alert: &CanaryAlert) -> anyhow::Result<()> {
+        let db_conn = match self.db_conn() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let hit = canary_hit::ActiveModel {
+            rpc_key_id: sea_orm::Set(alert.rpc_key_id.get()),
+            ip: sea_orm::Set(alert.ip.to_string()),
+            origin: sea_orm::Set(alert.origin.clone()),
+            referer: sea_orm::Set(alert.referer.clone()),
+            user_agent: sea_orm::Set(alert.user_agent.clone()),
+            siblings_frozen: sea_orm::Set(alert.siblings_frozen.try_into().unwrap_or(u32::MAX)),
+            ..Default::default()
+        };
+
+        hit.save(&db_conn).await?;
+
+        Ok(())
+    }
+
+    async fn send_canary_webhook(&self, alert: &CanaryAlert) -> anyhow::Result<()> {
+        let (url, http_client) = match (
+            self.config.canary_webhook_url.as_ref(),
+            self.http_client.as_ref(),
+        ) {
+            (Some(url), Some(http_client)) => (url, http_client),
+            _ => return Ok(()),
+        };
+
+        http_client
+            .post(url)
+            .timeout(Duration::from_secs(10))
+            .json(alert)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+/// Referer doesn't implement Display
+fn referer_to_string(referer: &Referer) -> Option<String> {
+    let mut values = vec![];
+    referer.encode(&mut values);
+
+    values
+        .first()
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_string())
+}
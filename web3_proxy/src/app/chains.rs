@@ -0,0 +1,584 @@
+//! More chains from the same proxy. `/v1/:chain_id/:rpc_key` sends requests to that chain's own `balanced_rpcs`.
+//!
+//! Keys, rate limits, and concurrency limits are shared with the proxy's own chain. Stats are saved with the request's chain id.
+//! These chains only get plain proxying: no response cache, no archive routing, no websockets, and no private relays.
+//! Only the `eth`, `net`, and `web3` namespaces are sent to their backends.
+//!
+//! Keys only work on the proxy's own chain unless their `allowed_chains` says otherwise.
+//! That way a leaked mainnet key can't run up costs on every other chain.
+use super::{AuthorizationChecks, TaskSupervisor, Web3ProxyApp};
+use crate::config::TopConfig;
+use crate::frontend::authorization::{
+    Authorization, RequestMetadata, RequestOrMethod, ResponseOrBytes,
+};
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::jsonrpc::{
+    JsonRpcErrorData, JsonRpcForwardedResponse, JsonRpcForwardedResponseEnum, JsonRpcRequest,
+    JsonRpcRequestEnum,
+};
+use crate::response_cache::JsonRpcResponseData;
+use crate::rpcs::blockchain::Web3ProxyBlock;
+use crate::rpcs::many::Web3Rpcs;
+use crate::rpcs::one::Web3Rpc;
+use crate::rpcs::transactions::TxStatus;
+use crate::worker_pool::WorkerPool;
+use anyhow::Context;
+use axum::http::StatusCode;
+use ethers::types::{TxHash, U64};
+use futures::stream::{self, StreamExt};
+use hashbrown::HashMap;
+use log::{info, Level};
+use migration::sea_orm::DatabaseConnection;
+use quick_cache_ttl::CacheWithTTL;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// methods that need an account on the backend or filters that the proxy can't track across servers
+const BLOCKED_METHODS: [&str; 11] = [
+    "eth_accounts",
+    "eth_getFilterChanges",
+    "eth_getFilterLogs",
+    "eth_newBlockFilter",
+    "eth_newFilter",
+    "eth_newPendingTransactionFilter",
+    "eth_sendTransaction",
+    "eth_sign",
+    "eth_signTransaction",
+    "eth_submitHashrate",
+    "eth_submitWork",
+];
+
+/// One of the `chains` in the config
+pub struct ExtraChain {
+    pub chain_id: u64,
+    pub rpcs: Arc<Web3Rpcs>,
+    /// the rpcs can't send their head block if nothing is watching it
+    _head_block_receiver: watch::Receiver<Option<Web3ProxyBlock>>,
+}
+
+fn method_allowed(method: &str) -> bool {
+    let namespace = method.split('_').next().unwrap_or_default();
+
+    matches!(namespace, "eth" | "net" | "web3") && !BLOCKED_METHODS.contains(&method)
+}
+
+/// Parse comma separated chain ids like "1, 137". Empty is no chains
+pub fn parse_allowed_chains(x: &str) -> anyhow::Result<Vec<u64>> {
+    let mut chains = x
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            x.parse()
+                .with_context(|| format!("{} is not a chain id", x))
+        })
+        .collect::<anyhow::Result<Vec<u64>>>()?;
+
+    chains.sort_unstable();
+    chains.dedup();
+
+    Ok(chains)
+}
+
+/// Start (but don't connect) every chain in the config. Their backends are connected by `apply_top_config`
+pub(super) async fn spawn_extra_chains(
+    top_config: &TopConfig,
+    db_conn: Option<DatabaseConnection>,
+    pending_transactions: Arc<CacheWithTTL<TxHash, TxStatus>>,
+    tasks: &TaskSupervisor,
+) -> anyhow::Result<HashMap<u64, ExtraChain>> {
+    let pool_max_wait = Duration::from_millis(top_config.app.pool_max_wait_ms);
+
+    let mut chains = HashMap::new();
+
+    for (name, chain_config) in top_config.chains.iter() {
+        if chain_config.chain_id == top_config.app.chain_id {
+            return Err(anyhow::anyhow!(
+                "chain {} has the same chain_id as the app",
+                name
+            ));
+        }
+
+        let (watch_consensus_head_sender, head_block_receiver) = watch::channel(None);
+
+        let (rpcs, handle, _) = Web3Rpcs::spawn(
+            WorkerPool::new("backend", top_config.app.backend_pool_size, pool_max_wait),
+            db_conn.clone(),
+            vec![],
+            Default::default(),
+            top_config.app.backend_health.clone(),
+            // hedging is tuned for the app's chain
+            Default::default(),
+            top_config.app.max_block_age,
+            top_config.app.max_block_lag,
+            chain_config.min_synced_rpcs,
+            chain_config.min_sum_soft_limit,
+            format!("{} rpcs", name),
+            pending_transactions.clone(),
+            None,
+            vec![],
+            None,
+            Some(watch_consensus_head_sender),
+        )
+        .await
+        .with_context(|| format!("spawning {} rpcs", name))?;
+
+        tasks.adopt(format!("{} rpcs", name), &[], handle, None);
+
+        info!(
+            "serving chain {} ({}) at /v1/{}",
+            name, chain_config.chain_id, chain_config.chain_id
+        );
+
+        let x = ExtraChain {
+            chain_id: chain_config.chain_id,
+            rpcs,
+            _head_block_receiver: head_block_receiver,
+        };
+
+        if chains.insert(chain_config.chain_id, x).is_some() {
+            return Err(anyhow::anyhow!(
+                "chain_id {} is configured more than once",
+                chain_config.chain_id
+            ));
+        }
+    }
+
+    Ok(chains)
+}
+
+impl Web3ProxyApp {
+    /// Every chain id this proxy serves. The app's own chain is first
+    pub fn served_chain_ids(&self) -> Vec<u64> {
+        let mut chain_ids: Vec<_> = self.chains.keys().copied().collect();
+
+        chain_ids.sort_unstable();
+        chain_ids.insert(0, self.config.chain_id);
+
+        chain_ids
+    }
+
+    /// Error if the request's key can't be used on this chain. Requests without a key are limited by ip instead
+    pub fn check_allowed_chain(
+        &self,
+        authorization_checks: &AuthorizationChecks,
+        chain_id: u64,
+    ) -> Web3ProxyResult<()> {
+        if authorization_checks.rpc_secret_key_id.is_none() {
+            return Ok(());
+        }
+
+        let allowed = match authorization_checks.allowed_chains.as_ref() {
+            Some(x) => x.contains(&chain_id),
+            None => chain_id == self.config.chain_id,
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(Web3ProxyError::ChainNotAllowed(chain_id))
+        }
+    }
+
+    /// Send a request (or a batch) to one of the `chains`
+    pub async fn proxy_chain_rpc(
+        self: &Arc<Self>,
+        chain_id: u64,
+        authorization: Arc<Authorization>,
+        request: JsonRpcRequestEnum,
+    ) -> Web3ProxyResult<(
+        StatusCode,
+        Option<JsonRpcForwardedResponseEnum>,
+        Vec<Arc<Web3Rpc>>,
+    )> {
+        let chain = self.chains.get(&chain_id).ok_or(Web3ProxyError::NotFound)?;
+
+        // held until the whole request (or batch) is done
+        let _permit = self.request_pool.acquire(&authorization).await?;
+
+        let is_notification = request.is_notification();
+
+        let response = match request {
+            JsonRpcRequestEnum::Single(mut request) => {
+                let (status_code, response, rpcs) = self
+                    .proxy_chain_request(chain, &authorization, &mut request)
+                    .await;
+
+                let response = if is_notification {
+                    None
+                } else {
+                    Some(JsonRpcForwardedResponseEnum::Single(response))
+                };
+
+                (status_code, response, rpcs)
+            }
+            JsonRpcRequestEnum::Batch(mut requests) => {
+                if let Some(max_batch_size) = self.max_batch_size(&authorization) {
+                    if requests.len() as u64 > max_batch_size {
+                        return Err(Web3ProxyError::BatchTooLarge {
+                            num_requests: requests.len(),
+                            max_batch_size,
+                        });
+                    }
+                }
+
+                let parallelism = self.batch_parallelism(&authorization, requests.len());
+
+                let futures: Vec<_> = requests
+                    .iter_mut()
+                    .map(|request| self.proxy_chain_request(chain, &authorization, request))
+                    .collect();
+
+                // buffered keeps the responses in the same order as the requests
+                let responses: Vec<_> = stream::iter(futures).buffered(parallelism).collect().await;
+
+                let mut collected = vec![];
+                let mut collected_rpcs: Vec<Arc<Web3Rpc>> = vec![];
+
+                for (request, (_, response, rpcs)) in requests.iter().zip(responses) {
+                    if !request.notification {
+                        collected.push(response);
+                    }
+
+                    for rpc in rpcs {
+                        if !collected_rpcs.iter().any(|x| x.name == rpc.name) {
+                            collected_rpcs.push(rpc);
+                        }
+                    }
+                }
+
+                let response = if is_notification {
+                    None
+                } else {
+                    Some(JsonRpcForwardedResponseEnum::Batch(collected))
+                };
+
+                (StatusCode::OK, response, collected_rpcs)
+            }
+        };
+
+        Ok(response)
+    }
+
+    async fn proxy_chain_request(
+        self: &Arc<Self>,
+        chain: &ExtraChain,
+        authorization: &Arc<Authorization>,
+        request: &mut JsonRpcRequest,
+    ) -> (StatusCode, JsonRpcForwardedResponse, Vec<Arc<Web3Rpc>>) {
+        let head_block_num = chain.rpcs.head_block_num();
+
+        let request_metadata = RequestMetadata::new_for_chain(
+            self,
+            Some(chain.chain_id),
+            authorization.clone(),
+            RequestOrMethod::Request(request),
+            head_block_num.as_ref(),
+        )
+        .await;
+
+        let (status_code, response_data): (_, JsonRpcResponseData) = match self
+            ._proxy_chain_request(chain, authorization, request, &request_metadata)
+            .await
+        {
+            Ok(x) => (StatusCode::OK, x),
+            Err(err) => err.into_response_parts(),
+        };
+
+        let response =
+            JsonRpcForwardedResponse::from_response_data(response_data, Default::default())
+                .normalize(request);
+
+        request_metadata.add_response(ResponseOrBytes::Response(&response));
+
+        let rpcs = request_metadata.backend_rpcs_used();
+
+        (status_code, response, rpcs)
+    }
+
+    async fn _proxy_chain_request(
+        self: &Arc<Self>,
+        chain: &ExtraChain,
+        authorization: &Arc<Authorization>,
+        request: &mut JsonRpcRequest,
+        request_metadata: &Arc<RequestMetadata>,
+    ) -> Web3ProxyResult<JsonRpcResponseData> {
+        // the script can rewrite the request, so this runs before anything else looks at the method
+        self.check_request_policy(authorization, request, request_metadata)
+            .await?;
+
+        if !method_allowed(&request.method) {
+            return Ok(JsonRpcErrorData::from(format!(
+                "the method {} does not exist/is not available",
+                request.method
+            ))
+            .into());
+        }
+
+        let x: JsonRpcResponseData = match request.method.as_str() {
+            "eth_chainId" => json!(U64::from(chain.chain_id)).into(),
+            "net_version" => json!(chain.chain_id.to_string()).into(),
+            "eth_sendRawTransaction" => {
+                // there are no private relays for these chains. send to a few public rpcs like `try_send_protected` does
+                chain
+                    .rpcs
+                    .try_send_all_synced_connections(
+                        authorization,
+                        request,
+                        Some(request_metadata.clone()),
+                        None,
+                        None,
+                        Level::Trace,
+                        Some(4),
+                        true,
+                    )
+                    .await?
+            }
+            method => {
+                let coalesce_key = self.coalesce_key(
+                    chain.chain_id,
+                    authorization,
+                    method,
+                    request.params.as_ref(),
+                    None,
+                    chain.rpcs.head_block_hash(),
+                );
+
+                self.coalesce(
+                    coalesce_key,
+                    chain.rpcs.try_proxy_connection(
+                        authorization,
+                        request,
+                        Some(request_metadata),
+                        None,
+                        None,
+                    ),
+                )
+                .await?
+            }
+        };
+
+        Ok(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{method_allowed, parse_allowed_chains};
+    use crate::app::{AuthorizationChecks, Web3ProxyApp};
+    use crate::frontend::authorization::{Authorization, RpcSecretKey};
+    use crate::frontend::errors::Web3ProxyError;
+    use crate::jsonrpc::{JsonRpcForwardedResponseEnum, JsonRpcRequestEnum};
+    use crate::test_utils::spawn_test_app_with_config;
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use std::net::SocketAddr;
+    use std::num::NonZeroU64;
+    use std::sync::Arc;
+
+    /// an app on chain 1 that also serves chain 137. neither chain has any backends
+    async fn spawn_app() -> Arc<Web3ProxyApp> {
+        spawn_test_app_with_config(
+            r#"
+            [app]
+            chain_id = 1
+
+            [balanced_rpcs]
+
+            [chains.polygon]
+            chain_id = 137
+
+            [chains.polygon.balanced_rpcs]
+            "#,
+        )
+        .await
+    }
+
+    fn request(method: &str) -> JsonRpcRequestEnum {
+        serde_json::from_value(json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": []}))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_proxy_chain_rpc() {
+        let app = spawn_app().await;
+
+        let authorization = Arc::new(Authorization::internal(None).unwrap());
+
+        assert_eq!(app.served_chain_ids(), vec![1, 137]);
+
+        // the chain id is answered without a backend
+        let (_, response, _) = app
+            .proxy_chain_rpc(137, authorization.clone(), request("eth_chainId"))
+            .await
+            .unwrap();
+
+        let response = serde_json::to_value(response.unwrap()).unwrap();
+
+        assert_eq!(response["result"], json!("0x89"));
+
+        let (_, response, _) = app
+            .proxy_chain_rpc(137, authorization.clone(), request("eth_sendTransaction"))
+            .await
+            .unwrap();
+
+        match response {
+            Some(JsonRpcForwardedResponseEnum::Single(x)) => assert!(x.error.is_some()),
+            other => panic!("{:?}", other),
+        }
+
+        assert!(matches!(
+            app.proxy_chain_rpc(5, authorization, request("eth_chainId"))
+                .await,
+            Err(Web3ProxyError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_check_allowed_chain() {
+        let app = spawn_app().await;
+
+        // requests without a key are limited by ip instead
+        let anonymous = AuthorizationChecks::default();
+
+        assert!(app.check_allowed_chain(&anonymous, 137).is_ok());
+
+        // keys only work on the app's own chain unless they say otherwise
+        let key = AuthorizationChecks {
+            rpc_secret_key_id: NonZeroU64::new(1),
+            ..Default::default()
+        };
+
+        assert!(app.check_allowed_chain(&key, 1).is_ok());
+        assert!(matches!(
+            app.check_allowed_chain(&key, 137),
+            Err(Web3ProxyError::ChainNotAllowed(137))
+        ));
+
+        let polygon_key = AuthorizationChecks {
+            rpc_secret_key_id: NonZeroU64::new(2),
+            allowed_chains: Some(vec![137]),
+            ..Default::default()
+        };
+
+        assert!(app.check_allowed_chain(&polygon_key, 137).is_ok());
+        assert!(app.check_allowed_chain(&polygon_key, 1).is_err());
+    }
+
+    #[test]
+    fn test_method_allowed() {
+        assert!(method_allowed("eth_call"));
+        assert!(method_allowed("net_version"));
+        assert!(method_allowed("web3_clientVersion"));
+
+        assert!(!method_allowed("eth_sendTransaction"));
+        assert!(!method_allowed("eth_newFilter"));
+        assert!(!method_allowed("debug_traceTransaction"));
+        assert!(!method_allowed("personal_sign"));
+        assert!(!method_allowed("admin_peers"));
+    }
+
+    #[test]
+    fn test_parse_allowed_chains() {
+        assert_eq!(parse_allowed_chains("137, 1,137").unwrap(), vec![1, 137]);
+        assert!(parse_allowed_chains("").unwrap().is_empty());
+        assert!(parse_allowed_chains("1, mainnet").is_err());
+    }
+
+    /// the frontend on a random local port
+    async fn serve(app: Arc<Web3ProxyApp>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let router = crate::frontend::router(app).await;
+
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.into_make_service_with_connect_info::<SocketAddr>()),
+        );
+
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_chain_route() {
+        let app = spawn_app().await;
+
+        // keys are cached so that no database is needed
+        let default_key = RpcSecretKey::new();
+        let polygon_key = RpcSecretKey::new();
+
+        for (i, (rpc_secret_key, allowed_chains)) in
+            [(default_key, None), (polygon_key, Some(vec![5, 137]))]
+                .into_iter()
+                .enumerate()
+        {
+            let checks = AuthorizationChecks {
+                user_id: 1,
+                rpc_secret_key: Some(rpc_secret_key),
+                rpc_secret_key_id: NonZeroU64::new(i as u64 + 1),
+                allowed_chains,
+                ..Default::default()
+            };
+
+            app.rpc_secret_key_cache
+                .try_insert(rpc_secret_key, checks)
+                .unwrap();
+        }
+
+        let url = serve(app).await;
+
+        let client = reqwest::Client::new();
+
+        let chain_id = |path: String| {
+            let client = client.clone();
+            let url = url.clone();
+
+            async move {
+                let body =
+                    json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []});
+
+                let response = client
+                    .post(format!("{}{}", url, path))
+                    .json(&body)
+                    .send()
+                    .await
+                    .unwrap();
+
+                let status = response.status();
+
+                let response: serde_json::Value = response.json().await.unwrap();
+
+                (status, response)
+            }
+        };
+
+        let (status, response) = chain_id(format!("/v1/137/{}", polygon_key)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["result"], json!("0x89"));
+
+        // the app's own chain
+        let (status, response) = chain_id(format!("/v1/1/{}", default_key)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["result"], json!("0x1"));
+
+        // keys without allowed_chains only work on the app's own chain
+        let (status, response) = chain_id(format!("/v1/137/{}", default_key)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(response["id"], json!(1));
+        assert!(response["error"].is_object());
+
+        let (status, _) = chain_id(format!("/v1/1/{}", polygon_key)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // an allowed chain that this proxy doesn't serve
+        let (status, _) = chain_id(format!("/v1/5/{}", polygon_key)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = chain_id("/v1/137/not-a-key".to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
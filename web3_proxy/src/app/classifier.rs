@@ -0,0 +1,351 @@
+//! Request classifier scripts. Operators can reject, rewrite, price, and route requests with a small Rhai script instead of forking the proxy.
+//!
+//! The script's `classify(request)` is called for every request (and every request in a batch) before anything else looks at it.
+//! It runs in a sandbox. There are no files, modules, or `eval`. A call that runs too many operations, runs too long, or builds too large a value is stopped.
+//! See `config/example_classifier.rhai` for what the script is given and what it can return.
+use super::Web3ProxyApp;
+use crate::config::ClassifierConfig;
+use crate::frontend::authorization::{Authorization, RequestMetadata};
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::jsonrpc::JsonRpcRequest;
+use anyhow::Context;
+use log::{debug, error, info, warn};
+use migration::sea_orm::prelude::Decimal;
+use num_traits::FromPrimitive;
+use rhai::{Dynamic, Engine, Scope, AST};
+use serde::Deserialize;
+use serde_json::json;
+use std::cell::Cell;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const ENTRYPOINT: &str = "classify";
+
+/// how many operations run between checks of the clock
+const OPERATIONS_PER_CLOCK_CHECK: u64 = 128;
+
+thread_local! {
+    /// When the call that is running on this thread has to stop. Calls are synchronous, so there is only ever one per thread
+    static DEADLINE: Cell<Option<Instant>> = Cell::new(None);
+}
+
+/// What the script decided about a request that it did not reject
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Classification {
+    /// added to the request's credits
+    pub credits: Option<Decimal>,
+    /// capabilities that a server needs to be sent this request. on top of any `routing_rules`
+    pub require: Vec<String>,
+}
+
+/// The map that a script returns. Every field is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScriptOutput {
+    #[serde(default)]
+    reject: Option<String>,
+    #[serde(default)]
+    method: Option<String>,
+    #[serde(default)]
+    params: Option<serde_json::Value>,
+    #[serde(default)]
+    credits: Option<f64>,
+    #[serde(default)]
+    require: Vec<String>,
+}
+
+pub struct Classifier {
+    engine: Engine,
+    ast: AST,
+    max_run: Duration,
+    fail_closed: bool,
+}
+
+impl Classifier {
+    /// Compile the configured script. None if there is no script
+    pub fn new(config: &ClassifierConfig) -> anyhow::Result<Option<Self>> {
+        let script_path = match config.script_path.as_ref() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let script = std::fs::read_to_string(script_path)
+            .with_context(|| format!("reading {}", script_path))?;
+
+        let x =
+            Self::compile(config, &script).with_context(|| format!("compiling {}", script_path))?;
+
+        Ok(Some(x))
+    }
+
+    fn compile(config: &ClassifierConfig, script: &str) -> anyhow::Result<Self> {
+        let mut engine = Engine::new();
+
+        engine
+            .set_max_operations(config.max_operations)
+            .set_max_string_size(config.max_string_bytes)
+            .set_max_array_size(config.max_collection_len)
+            .set_max_map_size(config.max_collection_len)
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .set_max_modules(0)
+            .disable_symbol("eval")
+            .on_progress(|operations| {
+                if operations % OPERATIONS_PER_CLOCK_CHECK != 0 {
+                    return None;
+                }
+
+                let timed_out = DEADLINE.with(|x| x.get().map_or(false, |x| Instant::now() > x));
+
+                // any value stops the script
+                timed_out.then(|| Dynamic::from("time limit"))
+            })
+            .on_print(|x| debug!("classifier: {}", x))
+            .on_debug(|x, _, pos| debug!("classifier {}: {}", pos, x));
+
+        let ast = engine.compile(script)?;
+
+        if !ast
+            .iter_functions()
+            .any(|x| x.name == ENTRYPOINT && x.params.len() == 1)
+        {
+            return Err(anyhow::anyhow!(
+                "the script needs a {}(request) function",
+                ENTRYPOINT
+            ));
+        }
+
+        Ok(Self {
+            engine,
+            ast,
+            max_run: Duration::from_millis(config.max_run_ms),
+            fail_closed: config.fail_closed,
+        })
+    }
+
+    /// Call the script. None if the request should be proxied unchanged
+    fn run(&self, input: serde_json::Value) -> anyhow::Result<Option<ScriptOutput>> {
+        let input = rhai::serde::to_dynamic(input)?;
+
+        DEADLINE.with(|x| x.set(Some(Instant::now() + self.max_run)));
+
+        let output =
+            self.engine
+                .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, ENTRYPOINT, (input,));
+
+        DEADLINE.with(|x| x.set(None));
+
+        let output = output?;
+
+        if output.is_unit() {
+            return Ok(None);
+        }
+
+        let output = rhai::serde::from_dynamic(&output)?;
+
+        Ok(Some(output))
+    }
+}
+
+impl Web3ProxyApp {
+    /// Run the classifier script on a request. The script can change the request's method and params.
+    /// Errors if the script rejected the request (or failed and `fail_closed` is set)
+    pub(crate) fn classify_request(
+        &self,
+        authorization: &Authorization,
+        request: &mut JsonRpcRequest,
+    ) -> Web3ProxyResult<Option<Classification>> {
+        let classifier = match self.classifier.read().clone() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        // 0 for anonymous requests. scripts can't tell None from a missing field
+        let rpc_key_id = authorization
+            .checks
+            .rpc_secret_key_id
+            .map(|x| x.get())
+            .unwrap_or_default();
+
+        let input = json!({
+            "method": request.method,
+            "params": request.params,
+            "chain_id": self.config.chain_id,
+            "user_id": authorization.checks.user_id,
+            "rpc_key_id": rpc_key_id,
+            "ip": authorization.ip.to_string(),
+            "origin": authorization.origin.as_ref().map(|x| x.to_string()),
+        });
+
+        let output = match classifier.run(input) {
+            Ok(Some(x)) => x,
+            Ok(None) => return Ok(None),
+            Err(err) if classifier.fail_closed => {
+                warn!(
+                    "classifier failed on {}. rejecting it. err={:?}",
+                    request.method, err
+                );
+
+                return Err(Web3ProxyError::RequestRejected(
+                    "the request could not be classified".to_string(),
+                ));
+            }
+            Err(err) => {
+                warn!(
+                    "classifier failed on {}. proxying it unchanged. err={:?}",
+                    request.method, err
+                );
+
+                return Ok(None);
+            }
+        };
+
+        if let Some(reason) = output.reject {
+            return Err(Web3ProxyError::RequestRejected(reason));
+        }
+
+        if let Some(method) = output.method {
+            request.method = method;
+        }
+
+        if let Some(params) = output.params {
+            request.params = Some(params);
+        }
+
+        let credits = match output.credits {
+            Some(x) if x.is_finite() && x >= 0.0 => Decimal::from_f64(x),
+            Some(x) => {
+                warn!("classifier returned invalid credits: {}", x);
+                None
+            }
+            None => None,
+        };
+
+        Ok(Some(Classification {
+            credits,
+            require: output.require,
+        }))
+    }
+
+    /// The checks that every request goes through before it is sent anywhere, whichever path it came in on.
+    /// The classifier runs first because it can change the method that the other checks look at
+    pub(crate) async fn check_request_policy(
+        &self,
+        authorization: &Authorization,
+        request: &mut JsonRpcRequest,
+        request_metadata: &RequestMetadata,
+    ) -> Web3ProxyResult<()> {
+        if let Some(x) = self.classify_request(authorization, request)? {
+            let _ = request_metadata.classification.set(x);
+        }
+
+        self.check_read_only(&request.method)?;
+
+        if let Some(x) = self.max_response_bytes(authorization, &request.method) {
+            let _ = request_metadata.max_response_bytes.set(x);
+        }
+
+        self.check_method_rate_limit(authorization, &request.method)
+            .await
+    }
+
+    /// Compile the script again. The old script is kept if the new one has an error
+    pub(super) fn reload_classifier(&self, config: &ClassifierConfig) {
+        match Classifier::new(config) {
+            Ok(x) => {
+                if let Some(script_path) = config.script_path.as_ref() {
+                    info!("classifier loaded from {}", script_path);
+                }
+
+                *self.classifier.write() = x.map(Arc::new);
+            }
+            Err(err) => {
+                error!(
+                    "unable to reload the classifier. keeping the old one. err={:?}",
+                    err
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"
+        fn classify(request) {
+            if request.method == "eth_getLogs" {
+                return #{ credits: 2.5, require: ["archive"] };
+            }
+
+            if request.rpc_key_id == 0 {
+                return #{ reject: "keys only" };
+            }
+
+            if request.method == "forever" {
+                loop {}
+            }
+
+            ()
+        }
+    "#;
+
+    #[test]
+    fn test_run() {
+        let classifier = Classifier::compile(&Default::default(), SCRIPT).unwrap();
+
+        let x = classifier
+            .run(json!({"method": "eth_getLogs", "rpc_key_id": 0}))
+            .unwrap()
+            .unwrap();
+        assert_eq!(x.credits, Some(2.5));
+        assert_eq!(x.require, vec!["archive".to_string()]);
+
+        let x = classifier
+            .run(json!({"method": "eth_call", "rpc_key_id": 0}))
+            .unwrap()
+            .unwrap();
+        assert_eq!(x.reject.as_deref(), Some("keys only"));
+
+        let x = classifier
+            .run(json!({"method": "eth_call", "rpc_key_id": 1}))
+            .unwrap();
+        assert!(x.is_none());
+
+        // the operation limit stops scripts that never finish
+        assert!(classifier
+            .run(json!({"method": "forever", "rpc_key_id": 1}))
+            .is_err());
+
+        assert!(Classifier::compile(&Default::default(), "fn other(x) { () }").is_err());
+    }
+
+    #[test]
+    fn test_time_limit() {
+        let config = ClassifierConfig {
+            max_operations: 0,
+            max_run_ms: 5,
+            ..Default::default()
+        };
+
+        let classifier = Classifier::compile(&config, SCRIPT).unwrap();
+
+        // 0 is no operation limit. the clock still stops it
+        let start = Instant::now();
+
+        assert!(classifier
+            .run(json!({"method": "forever", "rpc_key_id": 1}))
+            .is_err());
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // the deadline is only for the call that set it
+        DEADLINE.with(|x| assert_eq!(x.get(), None));
+
+        assert!(classifier
+            .run(json!({"method": "eth_call", "rpc_key_id": 1}))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,263 @@
+//! Identical requests that arrive while the first of them is still waiting on a backend share its response instead of all going to the backends.
+//! Requests are identical if they have the same chain, method, params, and blocks. Requests that don't name a block are tied to the head block.
+//! Only successful responses are shared. If the first request errors or its client goes away, the others send their own requests.
+use super::Web3ProxyApp;
+use crate::frontend::authorization::Authorization;
+use crate::frontend::errors::Web3ProxyResult;
+use crate::response_cache::{JsonRpcResponseCacheKey, JsonRpcResponseData};
+use ethers::types::H256;
+use hashbrown::hash_map::Entry;
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::future::Future;
+use std::num::NonZeroU64;
+use std::sync::atomic::{self, AtomicU64};
+use tokio::sync::watch;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct CoalesceKey {
+    chain_id: u64,
+    method: String,
+    /// params are stored as a string because `serde_json::Value` does not implement `Hash`
+    params: String,
+    from_block: H256,
+    to_block: Option<H256>,
+    /// tiers in `partitioned_cache_tiers` don't share responses with other tiers
+    cache_partition: Option<NonZeroU64>,
+}
+
+impl CoalesceKey {
+    /// `from_block` is the block the response is for. The head block if the request doesn't name one
+    pub fn new(
+        chain_id: u64,
+        method: &str,
+        params: Option<&serde_json::Value>,
+        from_block: H256,
+        to_block: Option<H256>,
+        cache_partition: Option<NonZeroU64>,
+    ) -> Self {
+        Self {
+            chain_id,
+            method: method.to_string(),
+            params: params.map(|x| x.to_string()).unwrap_or_default(),
+            from_block,
+            to_block,
+            cache_partition,
+        }
+    }
+}
+
+/// The requests that are waiting on a backend. The value is sent when the first request gets its response
+pub type InFlightRequests =
+    Mutex<HashMap<CoalesceKey, watch::Receiver<Option<JsonRpcResponseData>>>>;
+
+/// Counts since the app started. Exposed in the prometheus metrics and `/status`
+#[derive(Debug, Default, Serialize)]
+pub struct CoalescedRequests {
+    /// requests that used another request's response instead of going to a backend
+    pub requests: AtomicU64,
+    /// the size of those responses
+    pub response_bytes: AtomicU64,
+    /// requests that waited on another request that failed and then sent their own
+    pub fallbacks: AtomicU64,
+}
+
+/// The first request does the fetch. The rest wait for its response
+enum Role {
+    First(watch::Sender<Option<JsonRpcResponseData>>),
+    Waiting(watch::Receiver<Option<JsonRpcResponseData>>),
+}
+
+/// Removes the key even if the first request is dropped. Requests waiting on it see the sender close
+struct InFlightGuard<'a> {
+    in_flight: &'a InFlightRequests,
+    key: CoalesceKey,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.in_flight.lock().remove(&self.key);
+    }
+}
+
+/// Run `fetch`, or wait for an identical request that is already running and use its response
+pub async fn coalesce<F>(
+    in_flight: &InFlightRequests,
+    counts: &CoalescedRequests,
+    key: CoalesceKey,
+    fetch: F,
+) -> Web3ProxyResult<JsonRpcResponseData>
+where
+    F: Future<Output = Web3ProxyResult<JsonRpcResponseData>>,
+{
+    let role = match in_flight.lock().entry(key.clone()) {
+        Entry::Occupied(x) => Role::Waiting(x.get().clone()),
+        Entry::Vacant(x) => {
+            let (tx, rx) = watch::channel(None);
+
+            x.insert(rx);
+
+            Role::First(tx)
+        }
+    };
+
+    let mut rx = match role {
+        Role::First(tx) => return run_first(in_flight, key, tx, fetch).await,
+        Role::Waiting(rx) => rx,
+    };
+
+    // an error means the sender is gone. it might have sent the response right before that, so check the value either way
+    let _ = rx.changed().await;
+
+    let shared = rx.borrow().clone();
+
+    match shared {
+        Some(response_data) => {
+            counts.requests.fetch_add(1, atomic::Ordering::Relaxed);
+            counts.response_bytes.fetch_add(
+                response_data.num_bytes().get() as u64,
+                atomic::Ordering::Relaxed,
+            );
+
+            Ok(response_data)
+        }
+        None => {
+            counts.fallbacks.fetch_add(1, atomic::Ordering::Relaxed);
+
+            fetch.await
+        }
+    }
+}
+
+async fn run_first<F>(
+    in_flight: &InFlightRequests,
+    key: CoalesceKey,
+    tx: watch::Sender<Option<JsonRpcResponseData>>,
+    fetch: F,
+) -> Web3ProxyResult<JsonRpcResponseData>
+where
+    F: Future<Output = Web3ProxyResult<JsonRpcResponseData>>,
+{
+    let _guard = InFlightGuard { in_flight, key };
+
+    let response_data = fetch.await?;
+
+    let _ = tx.send(Some(response_data.clone()));
+
+    Ok(response_data)
+}
+
+impl Web3ProxyApp {
+    /// The coalescing key for a request. `cache_key` has the blocks that the request needs.
+    /// None if coalescing is off or the request doesn't name a block and there is no head block
+    pub(super) fn coalesce_key(
+        &self,
+        chain_id: u64,
+        authorization: &Authorization,
+        method: &str,
+        params: Option<&serde_json::Value>,
+        cache_key: Option<&JsonRpcResponseCacheKey>,
+        head_block_hash: Option<H256>,
+    ) -> Option<CoalesceKey> {
+        if !self.config.coalesce_requests {
+            return None;
+        }
+
+        let (from_block, to_block) = match cache_key.and_then(|x| x.from_block.as_ref()) {
+            Some(from_block) => (
+                from_block.hash?,
+                cache_key
+                    .and_then(|x| x.to_block.as_ref())
+                    .and_then(|x| x.hash),
+            ),
+            None => (head_block_hash?, None),
+        };
+
+        Some(CoalesceKey::new(
+            chain_id,
+            method,
+            params,
+            from_block,
+            to_block,
+            authorization.checks.cache_partition,
+        ))
+    }
+
+    /// Run `fetch` unless an identical request is already waiting on a backend. None always runs `fetch`
+    pub(super) async fn coalesce<F>(
+        &self,
+        key: Option<CoalesceKey>,
+        fetch: F,
+    ) -> Web3ProxyResult<JsonRpcResponseData>
+    where
+        F: Future<Output = Web3ProxyResult<JsonRpcResponseData>>,
+    {
+        match key {
+            Some(key) => {
+                coalesce(
+                    &self.in_flight_requests,
+                    &self.coalesced_requests,
+                    key,
+                    fetch,
+                )
+                .await
+            }
+            None => fetch.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::errors::Web3ProxyError;
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_coalesce() {
+        let in_flight = InFlightRequests::default();
+        let counts = CoalescedRequests::default();
+
+        let key = CoalesceKey::new(
+            1,
+            "eth_getBlockByNumber",
+            Some(&json!(["latest", false])),
+            H256::zero(),
+            None,
+            None,
+        );
+
+        let first = coalesce(&in_flight, &counts, key.clone(), async {
+            sleep(Duration::from_millis(100)).await;
+            Ok(json!("0x1").into())
+        });
+
+        // this would error if the second request sent its own
+        let second = coalesce(&in_flight, &counts, key.clone(), async {
+            Err(Web3ProxyError::NoServersSynced)
+        });
+
+        let (first, second) = futures::join!(first, second);
+
+        assert_eq!(first.unwrap().num_bytes(), second.unwrap().num_bytes());
+        assert_eq!(counts.requests.load(atomic::Ordering::Relaxed), 1);
+        assert!(in_flight.lock().is_empty());
+
+        // errors are not shared
+        let first = coalesce(&in_flight, &counts, key.clone(), async {
+            sleep(Duration::from_millis(100)).await;
+            Err(Web3ProxyError::NoServersSynced)
+        });
+
+        let second = coalesce(&in_flight, &counts, key, async { Ok(json!("0x2").into()) });
+
+        let (first, second) = futures::join!(first, second);
+
+        assert!(first.is_err());
+        assert!(second.is_ok());
+        assert_eq!(counts.fallbacks.load(atomic::Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,29 @@
+//! Graceful shutdown. On SIGTERM (or ctrl-c) the proxy drains instead of dropping everything:
+//! `/health` and `/ready` fail so that load balancers stop sending traffic, websockets get a close frame,
+//! and the frontend stops accepting connections while in-flight requests get `shutdown_drain_seconds` to finish.
+//! The stat buffers are flushed after the frontend is done so that the requests it finished are still counted.
+use super::Web3ProxyApp;
+use log::info;
+use tokio::sync::watch;
+
+impl Web3ProxyApp {
+    /// Stop taking new work. Calling this more than once does nothing
+    pub fn start_draining(&self) {
+        let started = self
+            .draining
+            .send_if_modified(|x| !std::mem::replace(x, true));
+
+        if started {
+            info!("draining");
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Changes to true once when the proxy starts draining
+    pub fn draining_receiver(&self) -> watch::Receiver<bool> {
+        self.draining.subscribe()
+    }
+}
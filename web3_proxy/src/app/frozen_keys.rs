@@ -0,0 +1,163 @@
+//! Admins can freeze a key that leaked. It stops working on every proxy within seconds instead of when the auth caches expire.
+//!
+//! Changes are sent on a broadcast channel. Every proxy publishes them to redis and copies the ones from redis onto its own channel.
+//! Listeners drop the key from the auth caches and close its websockets.
+use super::Web3ProxyApp;
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use anyhow::Context;
+use entities::rpc_key;
+use futures::StreamExt;
+use log::{debug, info, warn};
+use migration::sea_orm::{self, ActiveModelTrait, EntityTrait, IntoActiveModel};
+use redis_rate_limiter::redis;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::sleep;
+
+const FROZEN_KEYS_CHANNEL: &str = "web3_proxy:rpc_key_frozen";
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct RpcKeyFrozen {
+    pub rpc_key_id: u64,
+    /// false if the key was unfrozen or its secret was rotated. the auth caches forget the key either way
+    pub frozen: bool,
+}
+
+impl Web3ProxyApp {
+    /// Changes to frozen keys from every proxy
+    pub fn frozen_rpc_keys(&self) -> broadcast::Receiver<RpcKeyFrozen> {
+        self.rpc_key_frozen_sender.subscribe()
+    }
+
+    /// Turn a key off (or back on) and tell every proxy about it
+    pub async fn set_rpc_key_frozen(
+        &self,
+        rpc_key_id: u64,
+        frozen: bool,
+    ) -> Web3ProxyResult<rpc_key::Model> {
+        let db_conn = self.db_conn().context("freezing keys needs a db")?;
+
+        let key = rpc_key::Entity::find_by_id(rpc_key_id)
+            .one(&db_conn)
+            .await?
+            .ok_or(Web3ProxyError::RpcKeyNotFound)?;
+
+        let mut key = key.into_active_model();
+
+        key.active = sea_orm::Set(!frozen);
+
+        let key = key.update(&db_conn).await?;
+
+        let change = RpcKeyFrozen { rpc_key_id, frozen };
+
+        self.publish_rpc_key_change(change).await?;
+
+        info!("rpc key frozen: {:?}", change);
+
+        Ok(key)
+    }
+
+    /// Tell every proxy (including this one) to forget a key's cached auth checks
+    pub(crate) async fn publish_rpc_key_change(&self, change: RpcKeyFrozen) -> Web3ProxyResult<()> {
+        // this proxy does not wait for redis
+        let _ = self.rpc_key_frozen_sender.send(change);
+
+        match self.redis_conn().await {
+            Ok(Some(mut redis_conn)) => {
+                if let Err(err) = redis::cmd("PUBLISH")
+                    .arg(FROZEN_KEYS_CHANNEL)
+                    .arg(serde_json::to_string(&change)?)
+                    .query_async::<_, ()>(&mut redis_conn)
+                    .await
+                {
+                    warn!("unable to tell other proxies about {:?}: {:?}", change, err);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => warn!("unable to tell other proxies about {:?}: {:?}", change, err),
+        }
+
+        Ok(())
+    }
+
+    /// Drop changed keys from the auth caches. Their next request loads them from the database again
+    pub(super) async fn watch_frozen_keys(self: Arc<Self>) -> Web3ProxyResult<()> {
+        if let Some(redis_url) = self.config.volatile_redis_url.clone() {
+            tokio::spawn(subscribe_frozen_keys(
+                redis_url,
+                self.rpc_key_frozen_sender.clone(),
+            ));
+        }
+
+        let mut receiver = self.frozen_rpc_keys();
+
+        loop {
+            let change = match receiver.recv().await {
+                Ok(x) => x,
+                Err(RecvError::Lagged(_)) => {
+                    // some changes were missed. the only safe thing to do is forget every key
+                    warn!("missed rpc key changes. clearing the auth caches");
+                    self.rpc_secret_key_cache.remove_matching(|_, _| true);
+                    self.rpc_key_id_cache.remove_matching(|_, _| true);
+                    continue;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            };
+
+            debug!("forgetting rpc key {}", change.rpc_key_id);
+
+            self.rpc_secret_key_cache.remove_matching(|_, x| {
+                x.rpc_secret_key_id.map(|x| x.get()) == Some(change.rpc_key_id)
+            });
+            self.rpc_key_id_cache
+                .remove_matching(|x, _| x.get() == change.rpc_key_id);
+        }
+    }
+}
+
+/// Copy changes published by any proxy onto this proxy's channel. Reconnects if redis goes away
+async fn subscribe_frozen_keys(redis_url: String, sender: broadcast::Sender<RpcKeyFrozen>) {
+    loop {
+        if let Err(err) = _subscribe_frozen_keys(&redis_url, &sender).await {
+            warn!("frozen key subscription failed: {:?}", err);
+        }
+
+        if sender.receiver_count() == 0 {
+            return;
+        }
+
+        sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn _subscribe_frozen_keys(
+    redis_url: &str,
+    sender: &broadcast::Sender<RpcKeyFrozen>,
+) -> anyhow::Result<()> {
+    let mut pubsub = redis::Client::open(redis_url)?
+        .get_async_connection()
+        .await?
+        .into_pubsub();
+
+    pubsub.subscribe(FROZEN_KEYS_CHANNEL).await?;
+
+    let mut messages = pubsub.on_message();
+
+    while let Some(msg) = messages.next().await {
+        let payload: String = msg.get_payload()?;
+
+        match serde_json::from_str(&payload) {
+            Ok(change) => {
+                if sender.send(change).is_err() {
+                    // nothing is listening. the app is shutting down
+                    return Ok(());
+                }
+            }
+            Err(err) => warn!("invalid frozen key message {:?}: {:?}", payload, err),
+        }
+    }
+
+    Ok(())
+}
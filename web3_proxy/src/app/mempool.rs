@@ -0,0 +1,173 @@
+//! Mempool sizes for every chain this proxy serves.
+//! Backends with the `mempool_stats.capability` are asked for `txpool_status` on a schedule.
+//! `/v1/:chain/mempool` only reads the saved counts, so users polling it never reach a backend's txpool api.
+use super::Web3ProxyApp;
+use crate::frontend::authorization::Authorization;
+use crate::frontend::errors::Web3ProxyResult;
+use crate::rpcs::one::Web3Rpc;
+use crate::rpcs::request::RequestErrorHandler;
+use chrono::Utc;
+use ethers::types::U64;
+use futures::future::join_all;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::iter;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{interval, timeout, MissedTickBehavior};
+
+/// geth's `txpool_status`
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+struct TxpoolStatus {
+    pending: U64,
+    queued: U64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BackendMempool {
+    pub name: String,
+    pub pending: u64,
+    pub queued: u64,
+}
+
+/// The last counts that any backend on a chain answered with
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MempoolStats {
+    pub chain_id: u64,
+    /// the largest pool of any backend. each node only has the transactions that reached it
+    pub pending: u64,
+    pub queued: u64,
+    pub backends: Vec<BackendMempool>,
+    /// unix timestamp of the poll that these counts are from
+    pub updated_at: i64,
+}
+
+/// None if no backend answered
+fn aggregate(
+    chain_id: u64,
+    mut backends: Vec<BackendMempool>,
+    updated_at: i64,
+) -> Option<MempoolStats> {
+    if backends.is_empty() {
+        return None;
+    }
+
+    backends.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+    let pending = backends.iter().map(|x| x.pending).max().unwrap_or_default();
+    let queued = backends.iter().map(|x| x.queued).max().unwrap_or_default();
+
+    Some(MempoolStats {
+        chain_id,
+        pending,
+        queued,
+        backends,
+        updated_at,
+    })
+}
+
+/// Ask every backend at once. Backends that error or are slow are left out
+async fn poll_txpools(
+    rpcs: Vec<Arc<Web3Rpc>>,
+    authorization: &Arc<Authorization>,
+) -> Vec<BackendMempool> {
+    let futures = rpcs.into_iter().map(|rpc| async move {
+        let f = rpc.request::<_, TxpoolStatus>(
+            "txpool_status",
+            &None::<()>,
+            RequestErrorHandler::DebugLevel,
+            authorization.clone(),
+        );
+
+        match timeout(Duration::from_secs(5), f).await {
+            Ok(Ok(x)) => Some(BackendMempool {
+                name: rpc.name.clone(),
+                pending: x.pending.as_u64(),
+                queued: x.queued.as_u64(),
+            }),
+            Ok(Err(err)) => {
+                debug!("txpool_status on {} failed: {:?}", rpc, err);
+                None
+            }
+            Err(_) => {
+                debug!("txpool_status on {} timed out", rpc);
+                None
+            }
+        }
+    });
+
+    join_all(futures).await.into_iter().flatten().collect()
+}
+
+impl Web3ProxyApp {
+    /// The saved counts for the chain. None if the chain is not served or no backend has answered yet
+    pub fn mempool_stats(&self, chain_id: u64) -> Option<MempoolStats> {
+        self.mempool_stats.read().get(&chain_id).cloned()
+    }
+
+    /// Poll every chain's capable backends every `mempool_stats.poll_seconds`.
+    /// A chain keeps its old counts if none of its backends answer. `updated_at` shows how old they are
+    pub(super) async fn watch_mempools(self: Arc<Self>) -> Web3ProxyResult<()> {
+        let config = &self.config.mempool_stats;
+
+        if !config.enabled {
+            return Ok(());
+        }
+
+        let authorization = Arc::new(Authorization::internal(self.db_conn())?);
+
+        let mut interval = interval(Duration::from_secs(config.poll_seconds.max(1)));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let chains = iter::once((self.config.chain_id, &self.balanced_rpcs))
+                .chain(self.chains.values().map(|x| (x.chain_id, &x.rpcs)));
+
+            for (chain_id, rpcs) in chains {
+                let rpcs = rpcs.rpcs_with_capability(&config.capability);
+
+                if rpcs.is_empty() {
+                    continue;
+                }
+
+                let backends = poll_txpools(rpcs, &authorization).await;
+
+                if let Some(x) = aggregate(chain_id, backends, Utc::now().timestamp()) {
+                    self.mempool_stats.write().insert(chain_id, x);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate() {
+        assert_eq!(aggregate(1, vec![], 100), None);
+
+        let backends = vec![
+            BackendMempool {
+                name: "b".to_string(),
+                pending: 10,
+                queued: 7,
+            },
+            BackendMempool {
+                name: "a".to_string(),
+                pending: 12,
+                queued: 3,
+            },
+        ];
+
+        let x = aggregate(1, backends, 100).unwrap();
+
+        assert_eq!(x.pending, 12);
+        assert_eq!(x.queued, 7);
+        assert_eq!(x.backends[0].name, "a");
+        assert_eq!(x.updated_at, 100);
+    }
+}
@@ -0,0 +1,143 @@
+//! Per-method rate limits. These are on top of the key's (or ip's) limit for all requests.
+//! Expensive methods like `eth_getLogs` can get a much lower limit than cheap ones like `eth_call`.
+//!
+//! Tiers and keys have comma separated "method=limit" pairs. A key's limits can only lower its tier's limits.
+//! Anonymous requests use `public_method_requests_per_second`.
+use super::Web3ProxyApp;
+use crate::frontend::authorization::{Authorization, AuthorizationType};
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use anyhow::Context;
+use hashbrown::HashMap;
+use log::error;
+use redis_rate_limiter::RedisRateLimitResult;
+
+/// Parse comma separated "method=limit" pairs like "eth_getLogs=10, eth_call=500"
+pub fn parse_method_requests_per_second(x: &str) -> anyhow::Result<HashMap<String, u64>> {
+    x.split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            let (method, limit) = x
+                .split_once('=')
+                .with_context(|| format!("{} is not method=limit", x))?;
+
+            let method = method.trim();
+
+            if method.is_empty() {
+                anyhow::bail!("{} has no method", x);
+            }
+
+            let limit = limit
+                .trim()
+                .parse()
+                .with_context(|| format!("{} is not a number of requests", limit))?;
+
+            Ok((method.to_string(), limit))
+        })
+        .collect()
+}
+
+/// Combine the tier's and the key's limits. The lower limit wins
+pub fn merge_method_requests_per_second(
+    mut tier: HashMap<String, u64>,
+    key: HashMap<String, u64>,
+) -> HashMap<String, u64> {
+    for (method, limit) in key {
+        tier.entry(method)
+            .and_modify(|x| *x = (*x).min(limit))
+            .or_insert(limit);
+    }
+
+    tier
+}
+
+impl Web3ProxyApp {
+    /// Error if the key (or ip) has used up its limit for this method.
+    /// Like the other rate limits, this needs redis and fails open if redis is down
+    pub async fn check_method_rate_limit(
+        &self,
+        authorization: &Authorization,
+        method: &str,
+    ) -> Web3ProxyResult<()> {
+        if authorization.authorization_type == AuthorizationType::Internal {
+            return Ok(());
+        }
+
+        let rate_limiter = match self.frontend_method_rate_limiter.as_ref() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let (label, limit) = match authorization.checks.rpc_secret_key_id {
+            Some(rpc_key_id) => (
+                format!("key:{}:{}", rpc_key_id, method),
+                authorization.checks.method_requests_per_second.get(method),
+            ),
+            None => (
+                format!("ip:{}:{}", authorization.ip, method),
+                self.config.public_method_requests_per_second.get(method),
+            ),
+        };
+
+        let limit = match limit {
+            Some(x) => *x,
+            None => return Ok(()),
+        };
+
+        match rate_limiter.throttle_label(&label, Some(limit), 1).await {
+            Ok(RedisRateLimitResult::Allowed(_)) => Ok(()),
+            Ok(RedisRateLimitResult::RetryAt(retry_at, _)) => {
+                Err(Web3ProxyError::MethodRateLimited(
+                    method.to_string(),
+                    Some(retry_at),
+                    self.backoff_hint(Some(limit), Some(1)),
+                ))
+            }
+            Ok(RedisRateLimitResult::RetryNever) => Err(Web3ProxyError::MethodRateLimited(
+                method.to_string(),
+                None,
+                self.backoff_hint(Some(limit), Some(1)),
+            )),
+            Err(err) => {
+                error!(
+                    "method rate limiter is unhappy. allowing request. err={:?}",
+                    err
+                );
+
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_method_requests_per_second() {
+        let x = parse_method_requests_per_second("eth_getLogs=10, eth_call = 500,").unwrap();
+
+        assert_eq!(x.len(), 2);
+        assert_eq!(x["eth_getLogs"], 10);
+        assert_eq!(x["eth_call"], 500);
+
+        assert!(parse_method_requests_per_second("").unwrap().is_empty());
+        assert!(parse_method_requests_per_second("eth_getLogs").is_err());
+        assert!(parse_method_requests_per_second("eth_getLogs=ten").is_err());
+        assert!(parse_method_requests_per_second("=10").is_err());
+    }
+
+    #[test]
+    fn test_merge_method_requests_per_second() {
+        let tier = parse_method_requests_per_second("eth_getLogs=10, eth_call=500").unwrap();
+        let key =
+            parse_method_requests_per_second("eth_getLogs=50, eth_call=5, trace_block=1").unwrap();
+
+        let x = merge_method_requests_per_second(tier, key);
+
+        assert_eq!(x["eth_getLogs"], 10);
+        assert_eq!(x["eth_call"], 5);
+        assert_eq!(x["trace_block"], 1);
+    }
+}
@@ -1,43 +1,108 @@
 // TODO: this file is way too big now. move things into other modules
+mod balances;
+mod bandwidth_quotas;
+mod batch;
+mod bot_detection;
+mod cache_purge;
+mod canary;
+mod chains;
+mod classifier;
+mod coalescing;
+mod draining;
+mod frozen_keys;
+mod mempool;
+mod method_rate_limits;
+mod negative_cache;
+mod node_info;
+mod pruning;
+mod read_only;
+mod receipts;
+mod sandbox;
+mod screening;
+mod status_snapshots;
+mod streaming;
+mod tasks;
+mod tier_rebalance;
+mod token_buckets;
+mod tx_journal;
+mod tx_status;
+mod warmup;
 mod ws;
 
+pub use balances::{BalanceOwner, UserBalance};
+pub use bot_detection::{BotClient, BotDetector, BotReason, TaggedBot};
+pub use cache_purge::{CachePurgeResult, CachePurgeSelector, CachePurgeTarget};
+pub use canary::CanaryAlert;
+pub use chains::{parse_allowed_chains, ExtraChain};
+pub use classifier::{Classification, Classifier};
+pub use coalescing::CoalescedRequests;
+pub use frozen_keys::RpcKeyFrozen;
+pub use mempool::{BackendMempool, MempoolStats};
+pub use method_rate_limits::{merge_method_requests_per_second, parse_method_requests_per_second};
+pub use pruning::PrunedRows;
+pub use read_only::{ReadOnlyReason, ReadOnlyStatus, ReadOnlySwitch, STATE_CHANGING_METHODS};
+pub use status_snapshots::{Anomaly, RecentAnomalies};
+pub use streaming::{take_utf8, StreamedResponse};
+pub use tasks::{RestartPolicy, TaskReady, TaskState, TaskStatus, TaskSupervisor};
+pub use tier_rebalance::{
+    TierMapping, TierRebalance, TierRebalanceResult, TierRebalanceRollback, UserTierMove,
+};
+pub use token_buckets::{
+    key_token_bucket, tier_token_bucket, QueueResult, QueueTurn, RateLimitQueue, TokenBucketLimit,
+};
+pub use tx_status::{TrackedTx, TxLifecycle, TxTracker};
+pub use warmup::{Warmup, WarmupPhase};
+pub use ws::{WsSession, WsSessionCache};
+
 use crate::block_number::{block_needed, BlockNeeded};
-use crate::config::{AppConfig, TopConfig};
+use crate::config::{AppConfig, ResponseCacheBackend, TopConfig};
+use crate::frontend::allowed_ips::AllowedIps;
 use crate::frontend::authorization::{
-    Authorization, RequestMetadata, RequestOrMethod, ResponseOrBytes, RpcSecretKey,
+    Authorization, RequestMetadata, RequestOrMethod, ResponseOrBytes, RpcKeyHasher,
+    RpcKeyPoolMembers, RpcSecretKey,
 };
 use crate::frontend::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
 use crate::frontend::rpc_proxy_ws::ProxyMode;
+use crate::frontend::subscriptions::SubscriptionManager;
 use crate::jsonrpc::{
     JsonRpcErrorData, JsonRpcForwardedResponse, JsonRpcForwardedResponseEnum, JsonRpcRequest,
     JsonRpcRequestEnum,
 };
 use crate::response_cache::{
-    JsonRpcResponseCache, JsonRpcResponseCacheKey, JsonRpcResponseData, JsonRpcResponseWeigher,
+    CacheBackend, JsonRpcResponseCache, JsonRpcResponseCacheKey, JsonRpcResponseData,
+    JsonRpcResponseWeigher, MemoryCacheBackend, NegativeResponseCache, RedisCacheBackend,
+    TieredCacheBackend,
 };
+use crate::row_encryption::RowCipher;
+use crate::rpcs::beacon::BeaconRpcs;
 use crate::rpcs::blockchain::Web3ProxyBlock;
 use crate::rpcs::consensus::ConsensusWeb3Rpcs;
+use crate::rpcs::head_events::HeadEvent;
+use crate::rpcs::ipfs::IpfsGateways;
 use crate::rpcs::many::Web3Rpcs;
 use crate::rpcs::one::Web3Rpc;
+use crate::rpcs::rollup::rollup_method_needs_archive;
 use crate::rpcs::transactions::TxStatus;
-use crate::stats::{AppStat, StatBuffer};
+use crate::semaphore_registry::{SemaphoreRegistry, SemaphoreRegistryStatus};
+use crate::stats::request_shadow::{ShadowEvent, ShadowTarget};
+use crate::stats::{AppStat, StatBuffer, StatFreshness, BATCH_STAT_METHOD};
 use crate::user_token::UserBearerToken;
+use crate::worker_pool::{WorkerPool, WorkerPoolStatus};
 use anyhow::Context;
 use axum::headers::{Origin, Referer, UserAgent};
 use axum::http::StatusCode;
 use chrono::Utc;
-use deferred_rate_limiter::DeferredRateLimiter;
+use deferred_rate_limiter::{DeferredRateLimiter, DeferredTokenBucket};
 use derive_more::From;
-use entities::sea_orm_active_enums::TrackingLevel;
+use entities::sea_orm_active_enums::{TrackingLevel, TxJournalStatus};
 use entities::user;
 use ethers::core::utils::keccak256;
 use ethers::prelude::{Address, Bytes, Transaction, TxHash, H256, U64};
-use ethers::types::U256;
+use ethers::types::{Chain, U256};
 use ethers::utils::rlp::{Decodable, Rlp};
-use futures::future::join_all;
-use futures::stream::{FuturesUnordered, StreamExt};
+use futures::future::FutureExt;
+use futures::stream::{self, FuturesUnordered, StreamExt};
 use hashbrown::{HashMap, HashSet};
-use ipnet::IpNet;
 use log::{debug, error, info, trace, warn, Level};
 use migration::sea_orm::prelude::Decimal;
 use migration::sea_orm::{
@@ -45,21 +110,27 @@ use migration::sea_orm::{
 };
 use migration::sea_query::table::ColumnDef;
 use migration::{Alias, DbErr, Migrator, MigratorTrait, Table};
+use parking_lot::{Mutex, RwLock};
 use quick_cache_ttl::{Cache, CacheWithTTL};
+use rate_counter::RateCounter;
 use redis_rate_limiter::redis::AsyncCommands;
-use redis_rate_limiter::{redis, DeadpoolRuntime, RedisConfig, RedisPool, RedisRateLimiter};
+use redis_rate_limiter::{
+    redis, DeadpoolRuntime, RedisConfig, RedisPool, RedisRateLimiter, RedisTokenBucket,
+};
 use serde::Serialize;
 use serde_json::json;
 use std::borrow::Cow;
 use std::fmt;
 use std::net::IpAddr;
 use std::num::{NonZeroU32, NonZeroU64};
+use std::path::Path;
 use std::str::FromStr;
-use std::sync::{atomic, Arc};
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::{broadcast, watch, Semaphore};
+use tokio::sync::{broadcast, watch};
 use tokio::task::JoinHandle;
-use tokio::time::{sleep, timeout};
+use tokio::time::{interval, sleep, timeout, MissedTickBehavior};
 
 // TODO: make this customizable?
 // TODO: include GIT_REF in here. i had trouble getting https://docs.rs/vergen/latest/vergen/ to work with a workspace. also .git is in .dockerignore
@@ -70,9 +141,17 @@ pub static APP_USER_AGENT: &str = concat!(
     env!("CARGO_PKG_VERSION")
 );
 
+/// http requests to backends and gateways give up after this long
+/// TODO: timeouts from config. defaults are hopefully good
+pub const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const HTTP_REQUEST_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
 // aggregate across 1 week
 pub const BILLING_PERIOD_SECONDS: i64 = 60 * 60 * 24 * 7;
 
+/// the frontend and login rate limiters count requests over this many seconds
+pub const RATE_LIMIT_PERIOD_SECONDS: u64 = 60;
+
 pub type Web3ProxyJoinHandle<T> = JoinHandle<Web3ProxyResult<T>>;
 
 /// TODO: move this
@@ -89,8 +168,25 @@ pub struct AuthorizationChecks {
     pub rpc_secret_key_id: Option<NonZeroU64>,
     /// if None, allow unlimited queries. inherited from the user_tier
     pub max_requests_per_period: Option<u64>,
+    /// the user's token bucket. if None, allow unlimited queries. inherited from the user_tier
+    pub token_bucket: Option<TokenBucketLimit>,
+    /// Some if the key has a smaller bucket than its user
+    pub key_token_bucket: Option<TokenBucketLimit>,
+    /// if None, allow unlimited bytes. inherited from the user_tier
+    pub max_monthly_bytes: Option<u64>,
     // if None, allow unlimited concurrent requests. inherited from the user_tier
     pub max_concurrent_requests: Option<u32>,
+    /// if None, allow batches of any size. inherited from the user_tier
+    pub max_batch_size: Option<u64>,
+    /// if None, only the config's per-method limits apply. inherited from the user_tier
+    pub max_response_bytes: Option<u64>,
+    /// charged once per batch on top of the batch's requests. inherited from the user_tier
+    pub batch_overhead_cost: Option<Decimal>,
+    /// requests per second for specific methods. the lower of the user_tier's and the key's limits
+    pub method_requests_per_second: Arc<HashMap<String, u64>>,
+    /// if None, rate limited requests are rejected immediately. inherited from the user_tier
+    /// if Some, rate limited requests wait up to this many milliseconds for the limit to reset
+    pub max_queue_millis: Option<u64>,
     /// if None, allow any Origin
     pub allowed_origins: Option<Vec<Origin>>,
     /// if None, allow any Referer
@@ -98,7 +194,9 @@ pub struct AuthorizationChecks {
     /// if None, allow any UserAgent
     pub allowed_user_agents: Option<Vec<UserAgent>>,
     /// if None, allow any IP Address
-    pub allowed_ips: Option<Vec<IpNet>>,
+    pub allowed_ips: Option<AllowedIps>,
+    /// if None, only allow the app's own chain
+    pub allowed_chains: Option<Vec<u64>>,
     /// how detailed any rpc account entries should be
     pub tracking_level: TrackingLevel,
     /// Chance to save reverting eth_call, eth_estimateGas, and eth_sendRawTransaction to the database.
@@ -110,6 +208,24 @@ pub struct AuthorizationChecks {
     pub private_txs: bool,
     pub proxy_mode: ProxyMode,
     pub balance: Option<Decimal>,
+    /// whose balance the key spends. None unless the key's tier is in `balance_cutoff.tiers`
+    pub balance_owner: Option<BalanceOwner>,
+    /// database id of the key pool that picked this key (if any)
+    pub rpc_key_pool_id: Option<NonZeroU64>,
+    /// if true, responses are never read from or saved to the response caches
+    pub bypass_cache: bool,
+    /// the user_tier's id if the tier is in `partitioned_cache_tiers`. None shares the default cache partition
+    pub cache_partition: Option<NonZeroU64>,
+    /// if true, this key's requests are never picked for payload sampling
+    pub payload_sampling_opt_out: bool,
+    /// if true, the owner never uses this key. any request with it is alerted on and rejected
+    pub canary: bool,
+    /// if true, using this canary key deactivates the owner's other keys
+    pub canary_freeze_siblings: bool,
+    /// Some if the key's request metadata is sent to its own endpoint. See `request_shadowing`
+    pub request_shadow: Option<Arc<ShadowTarget>>,
+    /// Some if the secret was rotated away. It stops working at this unix timestamp even if these checks are still cached
+    pub secret_expires_at: Option<i64>,
 }
 
 /// Simple wrapper so that we can keep track of read only connections.
@@ -128,6 +244,21 @@ impl DatabaseReplica {
 /// Cache data from the database about rpc keys
 pub type RpcSecretKeyCache = Arc<CacheWithTTL<RpcSecretKey, AuthorizationChecks>>;
 
+/// Cache data from the database about rpc keys that were looked up by id instead of by secret
+pub type RpcKeyIdCache = Arc<CacheWithTTL<NonZeroU64, AuthorizationChecks>>;
+
+/// Cache data from the database about rpc key pools. Keyed by the pool secret's hash so the secret isn't kept in memory
+pub type RpcKeyPoolCache = Arc<CacheWithTTL<String, Arc<RpcKeyPoolMembers>>>;
+
+/// Canary keys that were recently alerted on, by ip. Keeps a leaked key in a retry loop from flooding the alerts
+pub type CanaryAlertCache = Arc<CacheWithTTL<(NonZeroU64, IpAddr), ()>>;
+
+/// The sanctions screening api's recent answers. true if the address matched
+pub type ScreeningCache = Arc<CacheWithTTL<Address, bool>>;
+
+/// Bytes that keys with a monthly quota have sent and received this month. See `bandwidth_quotas`
+pub type BandwidthUsageCache = Arc<CacheWithTTL<NonZeroU64, Arc<AtomicU64>>>;
+
 /// The application
 // TODO: i'm sure this is more arcs than necessary, but spawning futures makes references hard
 pub struct Web3ProxyApp {
@@ -135,6 +266,12 @@ pub struct Web3ProxyApp {
     pub balanced_rpcs: Arc<Web3Rpcs>,
     /// Send 4337 Abstraction Bundler requests to one of these servers
     pub bundler_4337_rpcs: Option<Arc<Web3Rpcs>>,
+    /// more chains served at `/v1/:chain_id/:rpc_key`
+    pub chains: HashMap<u64, ExtraChain>,
+    /// Send consensus layer REST requests to one of these servers
+    pub beacon_rpcs: Option<Arc<BeaconRpcs>>,
+    /// Send IPFS content requests to one of these gateways
+    pub ipfs_gateways: Option<Arc<IpfsGateways>>,
     pub http_client: Option<reqwest::Client>,
     /// application config
     /// TODO: this will need a large refactor to handle reloads while running. maybe use a watch::Receiver?
@@ -142,8 +279,12 @@ pub struct Web3ProxyApp {
     /// Send private requests (like eth_sendRawTransaction) to all these servers
     /// TODO: include another type so that we can use private miner relays that do not use JSONRPC requests
     pub private_rpcs: Option<Arc<Web3Rpcs>>,
-    /// track JSONRPC responses
-    pub jsonrpc_response_cache: JsonRpcResponseCache,
+    /// track JSONRPC responses in this replica's memory
+    pub jsonrpc_response_cache: Arc<JsonRpcResponseCache>,
+    /// where `_proxy_cached_request` looks for and saves responses. might be shared with other replicas
+    pub response_cache_backend: Arc<dyn CacheBackend>,
+    /// lookups by hash that found nothing at the current head
+    pub negative_response_cache: NegativeResponseCache,
     /// rpc clients that subscribe to newHeads use this channel
     /// don't drop this or the sender will stop working
     /// TODO: broadcast channel instead?
@@ -161,8 +302,12 @@ pub struct Web3ProxyApp {
     pub pending_transactions: Arc<CacheWithTTL<TxHash, TxStatus>>,
     /// rate limit anonymous users
     pub frontend_ip_rate_limiter: Option<DeferredRateLimiter<IpAddr>>,
-    /// rate limit authenticated users
-    pub frontend_registered_user_rate_limiter: Option<DeferredRateLimiter<u64>>,
+    /// rate limit authenticated users and their keys. see `token_buckets`
+    pub frontend_token_buckets: Option<DeferredTokenBucket>,
+    /// requests from tiers with `max_queue_millis` that are waiting for their key's bucket to refill
+    pub rate_limit_queue: RateLimitQueue,
+    /// rate limit expensive methods. per key or ip and per method
+    pub frontend_method_rate_limiter: Option<RedisRateLimiter>,
     /// Optional time series database for making pretty graphs that load quickly
     pub influxdb_client: Option<influxdb2::Client>,
     /// rate limit the login endpoint
@@ -174,15 +319,69 @@ pub struct Web3ProxyApp {
     /// cache authenticated users so that we don't have to query the database on the hot path
     // TODO: should the key be our RpcSecretKey class instead of Ulid?
     pub rpc_secret_key_cache: RpcSecretKeyCache,
+    /// cache keys that are used by id (key pools and presigned urls). their secrets are not saved
+    pub rpc_key_id_cache: RpcKeyIdCache,
+    /// keys that admins froze or unfroze on any proxy. see `frozen_rpc_keys`
+    rpc_key_frozen_sender: broadcast::Sender<RpcKeyFrozen>,
+    /// recent traffic from every key and ip. see `observe_probable_bot`
+    bot_detector: BotDetector,
+    /// transactions sent through this proxy and what happened to them
+    tx_tracker: TxTracker,
+    /// secrets are only saved as a hash
+    pub rpc_key_hasher: RpcKeyHasher,
+    /// cache the members of key pools so that we don't have to query the database on the hot path
+    pub rpc_key_pool_cache: RpcKeyPoolCache,
+    /// recent canary key alerts
+    pub canary_alerts: CanaryAlertCache,
+    /// recent answers from the sanctions screening api
+    pub screening_cache: ScreeningCache,
+    /// running totals for monthly byte quotas
+    pub bandwidth_usage: BandwidthUsageCache,
+    /// balances of keys that are cut off when they run out. see `balances`
+    balances: balances::Balances,
     /// concurrent/parallel RPC request limits for authenticated users
-    pub user_semaphores: Cache<NonZeroU64, Arc<Semaphore>>,
+    pub user_semaphores: SemaphoreRegistry<NonZeroU64>,
+    /// recently rate limited requests for authenticated users. used to help them debug their throttling
+    pub user_rate_limit_rejections: Cache<NonZeroU64, Arc<Mutex<RateCounter>>>,
     /// concurrent/parallel request limits for anonymous users
-    pub ip_semaphores: Cache<IpAddr, Arc<Semaphore>>,
+    pub ip_semaphores: SemaphoreRegistry<IpAddr>,
     /// concurrent/parallel application request limits for authenticated users
-    pub bearer_token_semaphores: Cache<UserBearerToken, Arc<Semaphore>>,
+    pub bearer_token_semaphores: SemaphoreRegistry<UserBearerToken>,
+    /// limits how many requests this chain handles at once
+    pub request_pool: WorkerPool,
     pub kafka_producer: Option<rdkafka::producer::FutureProducer>,
+    /// `/ready` is unhealthy until this finishes
+    pub warmup: Warmup,
+    /// subscriptions of closed websockets that can still be resumed
+    pub ws_sessions: WsSessionCache,
+    /// upstream work shared by every websocket subscription
+    pub subscriptions: SubscriptionManager,
     /// channel for sending stats in a background task
     pub stat_sender: Option<flume::Sender<AppStat>>,
+    /// how far behind the stats in influx are. None if stats are not saved
+    pub stat_freshness: Option<Arc<StatFreshness>>,
+    /// channel for sending keys' request metadata to their shadow urls. None if shadowing is off
+    pub request_shadow_sender: Option<flume::Sender<ShadowEvent>>,
+    /// reorgs and other surprises. included in status snapshots
+    pub recent_anomalies: RecentAnomalies,
+    /// rows deleted by the background pruning
+    pub pruned_rows: PrunedRows,
+    /// starts and stops the background tasks. see `GET /admin/tasks`
+    pub tasks: TaskSupervisor,
+    /// requests that used another identical request's response. see `coalesce`
+    pub coalesced_requests: CoalescedRequests,
+    /// requests that identical requests can wait on
+    in_flight_requests: coalescing::InFlightRequests,
+    /// emergency switches that reject state-changing methods
+    pub read_only: RwLock<ReadOnlyStatus>,
+    /// the operator's request classifier script. None if `classifier.script_path` is not set
+    pub classifier: RwLock<Option<Arc<Classifier>>>,
+    /// the last `txpool_status` counts for each chain. see `watch_mempools`
+    mempool_stats: RwLock<HashMap<u64, MempoolStats>>,
+    /// true once shutdown has started. see `start_draining`
+    draining: watch::Sender<bool>,
+    /// encrypts sensitive columns. None if `row_encryption_keyfile` is not set
+    pub row_cipher: Option<RowCipher>,
 }
 
 /// flatten a JoinError into an anyhow error
@@ -308,6 +507,28 @@ pub async fn get_migrated_db(
     Ok(db_conn)
 }
 
+/// Config keys ending in "*" match any method with that prefix (like "trace_*")
+pub fn method_matches(pattern: &str, method: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => method.starts_with(prefix),
+        None => pattern == method,
+    }
+}
+
+/// The smallest of the limits whose pattern matches the method and the user tier's limit
+pub fn lowest_max_response_bytes(
+    method_limits: &HashMap<String, u64>,
+    tier_limit: Option<u64>,
+    method: &str,
+) -> Option<u64> {
+    method_limits
+        .iter()
+        .filter(|(k, _)| method_matches(k, method))
+        .map(|(_, v)| *v)
+        .chain(tier_limit)
+        .min()
+}
+
 /// starting an app creates many tasks
 #[derive(From)]
 pub struct Web3ProxyAppSpawn {
@@ -330,7 +551,8 @@ impl Web3ProxyApp {
         num_workers: usize,
         shutdown_sender: broadcast::Sender<()>,
     ) -> anyhow::Result<Web3ProxyAppSpawn> {
-        let stat_buffer_shutdown_receiver = shutdown_sender.subscribe();
+        // the task supervisor stops the stat buffer after everything else
+        let (stat_buffer_shutdown_sender, stat_buffer_shutdown_receiver) = broadcast::channel(1);
         let mut background_shutdown_receiver = shutdown_sender.subscribe();
 
         // safety checks on the config
@@ -357,10 +579,21 @@ impl Web3ProxyApp {
             );
         }
 
+        // fail early. saving sensitive columns without their encryption would be worse than not starting
+        let row_cipher = top_config
+            .app
+            .row_encryption_keyfile
+            .as_ref()
+            .map(|path| RowCipher::from_keyfile(Path::new(path)))
+            .transpose()?;
+
         // these futures are key parts of the app. if they stop running, the app has encountered an irrecoverable error
         // TODO: this is a small enough group, that a vec with try_join_all is probably fine
         let app_handles: FuturesUnordered<Web3ProxyJoinHandle<()>> = FuturesUnordered::new();
 
+        // background tasks are added to this and started once the app exists
+        let tasks = TaskSupervisor::default();
+
         // we must wait for these to end on their own (and they need to subscribe to shutdown_sender)
         let important_background_handles: FuturesUnordered<Web3ProxyJoinHandle<()>> =
             FuturesUnordered::new();
@@ -507,10 +740,69 @@ impl Web3ProxyApp {
         // if there is no database of users, there will be no keys and so this will be empty
         // TODO: max_capacity from config
         // TODO: ttl from config
-        let rpc_secret_key_cache = CacheWithTTL::arc_with_capacity(
-            "rpc_secret_key_cache",
+        // purgeable so that freezing keys and changing tiers can drop a user's entries
+        let rpc_secret_key_cache = Arc::new(
+            CacheWithTTL::new("rpc_secret_key_cache", 10_000, Duration::from_secs(600))
+                .await
+                .purgeable(),
+        );
+
+        // TODO: max_capacity from config
+        // TODO: ttl from config
+        let rpc_key_id_cache = Arc::new(
+            CacheWithTTL::new("rpc_key_id_cache", 1_000, Duration::from_secs(600))
+                .await
+                .purgeable(),
+        );
+
+        let rpc_key_hasher = RpcKeyHasher::from_config(&top_config.app)?;
+
+        if let Some(db_conn) = db_conn.as_ref() {
+            // lookups only check the hash. keys saved before hashing existed need one now
+            let hashed = rpc_key_hasher
+                .hash_plaintext_keys(db_conn, 1_000, false)
+                .await?;
+
+            if hashed > 0 {
+                info!("hashed {} plaintext rpc keys and key pools", hashed);
+            }
+        }
+
+        // TODO: max_capacity from config
+        // TODO: ttl from config
+        let rpc_key_pool_cache =
+            CacheWithTTL::arc_with_capacity("rpc_key_pool_cache", 1_000, Duration::from_secs(600))
+                .await;
+
+        // TODO: max_capacity from config
+        let canary_alerts = CacheWithTTL::arc_with_capacity(
+            "canary_alerts",
+            1_000,
+            Duration::from_secs(top_config.app.canary_alert_dedupe_seconds.max(1)),
+        )
+        .await;
+
+        // TODO: max_capacity from config
+        let screening_cache = CacheWithTTL::arc_with_capacity(
+            "screening_cache",
             10_000,
-            Duration::from_secs(600),
+            Duration::from_secs(top_config.app.screening.api_cache_seconds.max(1)),
+        )
+        .await;
+
+        // the totals are reloaded from the database when they expire
+        // TODO: max_capacity from config
+        // TODO: ttl from config
+        let bandwidth_usage =
+            CacheWithTTL::arc_with_capacity("bandwidth_usage", 10_000, Duration::from_secs(60))
+                .await;
+
+        // closed websockets are only kept for the grace window
+        // TODO: max_capacity from config
+        let ws_sessions = CacheWithTTL::arc_with_capacity(
+            "ws_sessions",
+            10_000,
+            Duration::from_secs(top_config.app.ws_resume_grace_seconds.max(1)),
         )
         .await;
 
@@ -518,6 +810,7 @@ impl Web3ProxyApp {
         // we do this in a channel so we don't slow down our response to the users
         // stats can be saved in mysql, influxdb, both, or none
         let mut stat_sender = None;
+        let mut stat_freshness = None;
         if let Some(influxdb_bucket) = top_config.app.influxdb_bucket.clone() {
             if let Some(spawned_stat_buffer) = StatBuffer::try_spawn(
                 BILLING_PERIOD_SECONDS,
@@ -528,12 +821,20 @@ impl Web3ProxyApp {
                 influxdb_client.clone(),
                 Some(rpc_secret_key_cache.clone()),
                 stat_buffer_shutdown_receiver,
+                top_config.app.stat_buffer_capacity,
                 1,
             )? {
                 // since the database entries are used for accounting, we want to be sure everything is saved before exiting
-                important_background_handles.push(spawned_stat_buffer.background_handle);
+                // it is added first so that it is stopped last
+                tasks.adopt(
+                    "stat buffer".to_string(),
+                    &[],
+                    spawned_stat_buffer.background_handle,
+                    Some(stat_buffer_shutdown_sender),
+                );
 
                 stat_sender = Some(spawned_stat_buffer.stat_sender);
+                stat_freshness = Some(spawned_stat_buffer.freshness);
             }
         }
 
@@ -541,13 +842,22 @@ impl Web3ProxyApp {
             info!("stats will not be collected");
         }
 
+        let (request_shadow_sender, request_shadow_receiver) =
+            if top_config.app.request_shadowing.enabled {
+                let (sender, receiver) =
+                    flume::bounded(top_config.app.request_shadowing.queue_size.max(1));
+
+                (Some(sender), Some(receiver))
+            } else {
+                (None, None)
+            };
+
         // make a http shared client
         // TODO: can we configure the connection pool? should we?
-        // TODO: timeouts from config. defaults are hopefully good
         let http_client = Some(
             reqwest::ClientBuilder::new()
-                .connect_timeout(Duration::from_secs(5))
-                .timeout(Duration::from_secs(5 * 60))
+                .connect_timeout(HTTP_CONNECT_TIMEOUT)
+                .timeout(HTTP_REQUEST_TIMEOUT)
                 .user_agent(APP_USER_AGENT)
                 .build()?,
         );
@@ -555,7 +865,11 @@ impl Web3ProxyApp {
         // create rate limiters
         // these are optional. they require redis
         let mut frontend_ip_rate_limiter = None;
-        let mut frontend_registered_user_rate_limiter = None;
+        let mut frontend_token_buckets = None;
+        // TODO: take cache_size from config
+        let rate_limit_queue =
+            RateLimitQueue::new(20_000, top_config.app.rate_limit_queue_max_depth);
+        let mut frontend_method_rate_limiter = None;
         let mut login_rate_limiter = None;
 
         if let Some(ref redis_pool) = vredis_pool {
@@ -565,26 +879,43 @@ impl Web3ProxyApp {
                     &format!("web3_proxy:{}", top_config.app.chain_id),
                     "frontend",
                     public_requests_per_period,
-                    60.0,
+                    RATE_LIMIT_PERIOD_SECONDS as f32,
                     redis_pool.clone(),
                 );
 
-                // these two rate limiters can share the base limiter
-                // these are deferred rate limiters because we don't want redis network requests on the hot path
+                // this is a deferred rate limiter because we don't want redis network requests on the hot path
                 // TODO: take cache_size from config
-                frontend_ip_rate_limiter = Some(
-                    DeferredRateLimiter::<IpAddr>::new(20_000, "ip", rpc_rrl.clone(), None).await,
-                );
-                frontend_registered_user_rate_limiter =
-                    Some(DeferredRateLimiter::<u64>::new(10_000, "key", rpc_rrl, None).await);
+                frontend_ip_rate_limiter =
+                    Some(DeferredRateLimiter::<IpAddr>::new(20_000, "ip", rpc_rrl, None).await);
             }
 
+            // the buckets' sizes always come from the tier or key
+            let rtb = RedisTokenBucket::new(
+                &format!("web3_proxy:{}", top_config.app.chain_id),
+                "frontend",
+                redis_pool.clone(),
+            );
+
+            // this is deferred too because we don't want redis network requests on the hot path
+            // TODO: take cache_size from config
+            frontend_token_buckets = Some(DeferredTokenBucket::new(20_000, rtb).await);
+
+            // most methods have no limit of their own, so this checks redis directly instead of keeping a local cache
+            // the limits always come from the tier, key, or config. the default max is never used
+            frontend_method_rate_limiter = Some(RedisRateLimiter::new(
+                &format!("web3_proxy:{}", top_config.app.chain_id),
+                "method",
+                0,
+                1.0,
+                redis_pool.clone(),
+            ));
+
             // login rate limiter
             login_rate_limiter = Some(RedisRateLimiter::new(
                 "web3_proxy",
                 "login",
                 top_config.app.login_rate_limit_per_period,
-                60.0,
+                RATE_LIMIT_PERIOD_SECONDS as f32,
                 redis_pool.clone(),
             ));
         }
@@ -614,7 +945,6 @@ impl Web3ProxyApp {
 
         // responses can be very different in sizes, so this is a cache with a max capacity and a weigher
         // TODO: we should emit stats to calculate a more accurate expected cache size
-        // TODO: do we actually want a TTL on this?
         // TODO: configurable max item weight instead of using ~0.1%
         // TODO: resize the cache automatically
         let response_cache = JsonRpcResponseCache::new_with_weights(
@@ -623,9 +953,57 @@ impl Web3ProxyApp {
             NonZeroU32::try_from((top_config.app.response_cache_max_bytes / 1024) as u32).unwrap(),
             top_config.app.response_cache_max_bytes,
             JsonRpcResponseWeigher,
-            Duration::from_secs(3600),
+            Duration::from_secs(top_config.app.response_cache_ttl_seconds.max(1)),
         )
-        .await;
+        .await
+        // for the admin cache purge
+        .purgeable();
+
+        let response_cache = Arc::new(response_cache);
+
+        let redis_cache_backend = vredis_pool.clone().map(|redis_pool| RedisCacheBackend {
+            chain_id: top_config.app.chain_id,
+            redis_pool,
+            ttl_ms: top_config.app.response_cache_ttl_seconds.max(1) * 1000,
+        });
+
+        // blocks by hash are shared through redis whenever responses are
+        let shared_blocks = match top_config.app.response_cache_backend {
+            ResponseCacheBackend::Memory => None,
+            ResponseCacheBackend::Redis | ResponseCacheBackend::Tiered => {
+                redis_cache_backend.clone()
+            }
+        };
+
+        let response_cache_backend: Arc<dyn CacheBackend> = match (
+            top_config.app.response_cache_backend,
+            redis_cache_backend,
+        ) {
+            (ResponseCacheBackend::Memory, _) => {
+                Arc::new(MemoryCacheBackend(response_cache.clone()))
+            }
+            (ResponseCacheBackend::Redis, Some(redis)) => Arc::new(redis),
+            (ResponseCacheBackend::Tiered, Some(redis)) => Arc::new(TieredCacheBackend {
+                memory: response_cache.clone(),
+                redis,
+            }),
+            (x, None) => {
+                warn!(
+                    "response_cache_backend {:?} needs volatile_redis_url. caching responses in memory",
+                    x
+                );
+                Arc::new(MemoryCacheBackend(response_cache.clone()))
+            }
+        };
+
+        // entries are only used at the head they were seen at. the ttl just makes sure they are cleaned up
+        let negative_response_cache = NegativeResponseCache::new(
+            "negative_response_cache",
+            10_000,
+            Duration::from_secs(top_config.app.negative_cache_ttl_seconds.max(1)),
+        )
+        .await
+        .purgeable();
 
         // TODO: how should we handle hitting this max?
         let max_users = 20_000;
@@ -633,12 +1011,20 @@ impl Web3ProxyApp {
         // create semaphores for concurrent connection limits
         // TODO: how can we implement time til idle?
         // TODO: what should tti be for semaphores?
-        let bearer_token_semaphores = Cache::new(max_users);
-        let ip_semaphores = Cache::new(max_users);
-        let user_semaphores = Cache::new(max_users);
+        let bearer_token_semaphores = SemaphoreRegistry::new("bearer_token", max_users);
+        let ip_semaphores = SemaphoreRegistry::new("ip", max_users);
+        let user_semaphores = SemaphoreRegistry::new("user", max_users);
+        let user_rate_limit_rejections = Cache::new(max_users);
+
+        let pool_max_wait = Duration::from_millis(top_config.app.pool_max_wait_ms);
 
         let (balanced_rpcs, balanced_handle, consensus_connections_watcher) = Web3Rpcs::spawn(
+            WorkerPool::new("backend", top_config.app.backend_pool_size, pool_max_wait),
             db_conn.clone(),
+            top_config.app.failover_groups.clone(),
+            top_config.app.head_quorum.clone(),
+            top_config.app.backend_health.clone(),
+            top_config.app.hedging.clone(),
             top_config.app.max_block_age,
             top_config.app.max_block_lag,
             top_config.app.min_synced_rpcs,
@@ -646,12 +1032,14 @@ impl Web3ProxyApp {
             "balanced rpcs".to_string(),
             pending_transactions.clone(),
             Some(pending_tx_sender.clone()),
+            top_config.app.routing_rules.clone(),
+            shared_blocks,
             Some(watch_consensus_head_sender),
         )
         .await
         .context("spawning balanced rpcs")?;
 
-        app_handles.push(balanced_handle);
+        tasks.adopt("balanced rpcs".to_string(), &[], balanced_handle, None);
 
         // prepare a Web3Rpcs to hold all our private connections
         // only some chains have this, so this is optional
@@ -663,7 +1051,15 @@ impl Web3ProxyApp {
             // TODO: Merge
             // let (private_rpcs, private_rpcs_handle) = Web3Rpcs::spawn(
             let (private_rpcs, private_handle, _) = Web3Rpcs::spawn(
+                // only the balanced rpcs are budgeted. these only get a few kinds of requests
+                Default::default(),
                 db_conn.clone(),
+                vec![],
+                // private rpcs don't get consensus heads, so they need no quorum and are never scored
+                Default::default(),
+                Default::default(),
+                // transactions are never hedged
+                Default::default(),
                 // private rpcs don't get subscriptions, so no need for max_block_age or max_block_lag
                 None,
                 None,
@@ -673,6 +1069,9 @@ impl Web3ProxyApp {
                 pending_transactions.clone(),
                 // TODO: subscribe to pending transactions on the private rpcs? they seem to have low rate limits, but they should have
                 None,
+                // every private rpc can send transactions
+                vec![],
+                None,
                 // subscribing to new heads here won't work well. if they are fast, they might be ahead of balanced_rpcs
                 // they also often have low rate limits
                 // however, they are well connected to miners/validators. so maybe using them as a safety check would be good
@@ -682,7 +1081,7 @@ impl Web3ProxyApp {
             .await
             .context("spawning private_rpcs")?;
 
-            app_handles.push(private_handle);
+            tasks.adopt("private rpcs".to_string(), &[], private_handle, None);
 
             Some(private_rpcs)
         };
@@ -695,7 +1094,13 @@ impl Web3ProxyApp {
         } else {
             // TODO: do something with the spawn handle
             let (bundler_4337_rpcs, bundler_4337_rpcs_handle, _) = Web3Rpcs::spawn(
+                // only the balanced rpcs are budgeted. these only get a few kinds of requests
+                Default::default(),
                 db_conn.clone(),
+                vec![],
+                Default::default(),
+                Default::default(),
+                Default::default(),
                 // bundler_4337_rpcs don't get subscriptions, so no need for max_block_age or max_block_lag
                 None,
                 None,
@@ -704,16 +1109,62 @@ impl Web3ProxyApp {
                 "eip4337 rpcs".to_string(),
                 pending_transactions.clone(),
                 None,
+                vec![],
+                None,
                 None,
             )
             .await
             .context("spawning bundler_4337_rpcs")?;
 
-            app_handles.push(bundler_4337_rpcs_handle);
+            tasks.adopt(
+                "bundler_4337 rpcs".to_string(),
+                &[],
+                bundler_4337_rpcs_handle,
+                None,
+            );
 
             Some(bundler_4337_rpcs)
         };
 
+        let chains = chains::spawn_extra_chains(
+            &top_config,
+            db_conn.clone(),
+            pending_transactions.clone(),
+            &tasks,
+        )
+        .await
+        .context("spawning chains")?;
+
+        // beacon nodes are plain http servers. they don't need anything spawned
+        let beacon_rpcs = match (top_config.beacon_rpcs.clone(), http_client.clone()) {
+            (Some(beacon_rpc_configs), Some(http_client)) => {
+                BeaconRpcs::spawn(
+                    beacon_rpc_configs,
+                    http_client,
+                    top_config.app.beacon_cache_ttl_seconds,
+                )
+                .await
+            }
+            _ => None,
+        };
+
+        let ipfs_gateways = match (top_config.ipfs_gateways.clone(), http_client.clone()) {
+            (Some(ipfs_gateway_configs), Some(http_client)) => {
+                IpfsGateways::spawn(
+                    ipfs_gateway_configs,
+                    http_client,
+                    top_config.app.ipfs_max_response_bytes,
+                    top_config.app.ipfs_cache_max_bytes,
+                )
+                .await
+            }
+            _ => None,
+        };
+
+        let classifier = Classifier::new(&top_config.app.classifier)
+            .context("loading the classifier script")?
+            .map(Arc::new);
+
         let hostname = hostname::get()
             .ok()
             .and_then(|x| x.to_str().map(|x| x.to_string()));
@@ -722,15 +1173,22 @@ impl Web3ProxyApp {
             config: top_config.app.clone(),
             balanced_rpcs,
             bundler_4337_rpcs,
+            chains,
+            beacon_rpcs,
+            ipfs_gateways,
             http_client,
             kafka_producer,
             private_rpcs,
             jsonrpc_response_cache: response_cache,
+            response_cache_backend,
+            negative_response_cache,
             watch_consensus_head_receiver,
             pending_tx_sender,
             pending_transactions,
             frontend_ip_rate_limiter,
-            frontend_registered_user_rate_limiter,
+            frontend_token_buckets,
+            rate_limit_queue,
+            frontend_method_rate_limiter,
             login_rate_limiter,
             db_conn,
             db_replica,
@@ -738,10 +1196,41 @@ impl Web3ProxyApp {
             hostname,
             vredis_pool,
             rpc_secret_key_cache,
+            rpc_key_id_cache,
+            rpc_key_hasher,
+            rpc_key_pool_cache,
+            canary_alerts,
+            screening_cache,
+            bandwidth_usage,
+            balances: Default::default(),
             bearer_token_semaphores,
             ip_semaphores,
+            request_pool: WorkerPool::new(
+                "request",
+                top_config.app.request_pool_size,
+                pool_max_wait,
+            ),
             user_semaphores,
+            user_rate_limit_rejections,
             stat_sender,
+            stat_freshness,
+            request_shadow_sender,
+            warmup: Default::default(),
+            ws_sessions,
+            subscriptions: Default::default(),
+            recent_anomalies: Default::default(),
+            pruned_rows: Default::default(),
+            tasks,
+            coalesced_requests: Default::default(),
+            in_flight_requests: Default::default(),
+            read_only: Default::default(),
+            classifier: RwLock::new(classifier),
+            mempool_stats: Default::default(),
+            draining: watch::channel(false).0,
+            rpc_key_frozen_sender: broadcast::channel(256).0,
+            bot_detector: Default::default(),
+            tx_tracker: Default::default(),
+            row_cipher,
         };
 
         let app = Arc::new(app);
@@ -749,30 +1238,107 @@ impl Web3ProxyApp {
         // watch for config changes
         // TODO: initial config reload should be from this channel. not from the call to spawn
 
-        let (new_top_config_sender, mut new_top_config_receiver) = watch::channel(top_config);
+        let (new_top_config_sender, new_top_config_receiver) = watch::channel(top_config);
 
-        {
-            let app = app.clone();
-            let config_handle = tokio::spawn(async move {
-                loop {
-                    let new_top_config = new_top_config_receiver.borrow_and_update().to_owned();
+        // ready once the first config is applied
+        app.tasks.add_with_ready(
+            "config",
+            &["balanced rpcs"],
+            RestartPolicy::OnPanic,
+            move |app, ready| {
+                app.watch_top_config(new_top_config_receiver.clone(), ready)
+                    .boxed()
+            },
+        );
 
-                    if let Err(err) = app.apply_top_config(new_top_config).await {
-                        error!("unable to apply config! {:?}", err);
-                    };
+        // connecting to the backends happens in the config handle. warmup waits for that and more
+        app.tasks
+            .add("warmup", &["config"], RestartPolicy::Never, |app| {
+                app.warmup().boxed()
+            });
 
-                    new_top_config_receiver
-                        .changed()
-                        .await
-                        .context("failed awaiting top_config change")?;
+        app.tasks.add(
+            "negative cache",
+            &["balanced rpcs"],
+            RestartPolicy::OnPanic,
+            |app| app.invalidate_negative_cache().boxed(),
+        );
 
-                    info!("config changed");
-                }
+        app.tasks.add(
+            "reorged responses",
+            &["balanced rpcs"],
+            RestartPolicy::OnPanic,
+            |app| app.invalidate_reorged_responses().boxed(),
+        );
+
+        app.tasks.add(
+            "anomalies",
+            &["balanced rpcs"],
+            RestartPolicy::OnPanic,
+            |app| app.watch_anomalies().boxed(),
+        );
+
+        app.tasks
+            .add("pruning", &[], RestartPolicy::OnPanic, |app| {
+                app.prune_stale_rows().boxed()
+            });
+
+        app.tasks
+            .add("read only", &[], RestartPolicy::OnPanic, |app| {
+                app.watch_read_only().boxed()
+            });
+
+        app.tasks
+            .add("frozen keys", &[], RestartPolicy::OnPanic, |app| {
+                app.watch_frozen_keys().boxed()
+            });
+
+        app.tasks.add(
+            "bot detection",
+            &["balanced rpcs"],
+            RestartPolicy::OnPanic,
+            |app| app.watch_heads_for_bots().boxed(),
+        );
+
+        app.tasks
+            .add("tx journal", &["config"], RestartPolicy::OnPanic, |app| {
+                app.watch_tx_journal().boxed()
+            });
+
+        app.tasks.add(
+            "tx status",
+            &["balanced rpcs"],
+            RestartPolicy::OnPanic,
+            |app| app.watch_tx_status().boxed(),
+        );
+
+        app.tasks
+            .add("mempools", &["config"], RestartPolicy::OnPanic, |app| {
+                app.watch_mempools().boxed()
             });
 
-            app_handles.push(config_handle);
+        app.tasks
+            .add("balances", &[], RestartPolicy::OnPanic, |app| {
+                app.reconcile_balances().boxed()
+            });
+
+        app.tasks
+            .add("leaked permits", &[], RestartPolicy::OnPanic, |app| {
+                app.release_leaked_permits().boxed()
+            });
+
+        if let Some(receiver) = request_shadow_receiver {
+            // the receiver can be cloned, so this one can restart
+            app.tasks
+                .add("request shadows", &[], RestartPolicy::OnPanic, move |app| {
+                    app.watch_request_shadows(receiver.clone()).boxed()
+                });
         }
 
+        app.tasks
+            .start(&app, &app_handles)
+            .context("starting background tasks")?;
+
         if important_background_handles.is_empty() {
             info!("no important background handles");
 
@@ -795,9 +1361,37 @@ impl Web3ProxyApp {
             .into())
     }
 
+    /// Apply the current config and then every change to it
+    async fn watch_top_config(
+        self: Arc<Self>,
+        mut new_top_config_receiver: watch::Receiver<TopConfig>,
+        ready: TaskReady,
+    ) -> Web3ProxyResult<()> {
+        loop {
+            let new_top_config = new_top_config_receiver.borrow_and_update().to_owned();
+
+            if let Err(err) = self.apply_top_config(new_top_config).await {
+                error!("unable to apply config! {:?}", err);
+            };
+
+            // the tasks that need the backends can start even if the config had errors. they would have to wait for a fixed config otherwise
+            ready.ready();
+
+            new_top_config_receiver
+                .changed()
+                .await
+                .context("failed awaiting top_config change")?;
+
+            info!("config changed");
+        }
+    }
+
     pub async fn apply_top_config(&self, new_top_config: TopConfig) -> Web3ProxyResult<()> {
         // TODO: also update self.config from new_top_config.app
 
+        // a broken script is logged and the old one is kept. it shouldn't stop the backends from updating
+        self.reload_classifier(&new_top_config.app.classifier);
+
         // connect to the backends
         self.balanced_rpcs
             .apply_server_configs(self, new_top_config.balanced_rpcs)
@@ -828,6 +1422,26 @@ impl Web3ProxyApp {
             }
         }
 
+        for (name, chain_config) in new_top_config.chains {
+            match self.chains.get(&chain_config.chain_id) {
+                Some(chain) => {
+                    chain
+                        .rpcs
+                        .apply_chain_server_configs(
+                            self,
+                            chain_config.chain_id,
+                            chain_config.min_synced_rpcs,
+                            chain_config.balanced_rpcs,
+                        )
+                        .await
+                        .with_context(|| format!("updating {} rpcs", name))?;
+                }
+                None => {
+                    warn!("chain {} is new. restart to serve it", name);
+                }
+            }
+        }
+
         Ok(())
     }
 
@@ -835,6 +1449,83 @@ impl Web3ProxyApp {
         self.watch_consensus_head_receiver.clone()
     }
 
+    /// Every change to the balanced rpcs' consensus head. Use this instead of polling `head_block_receiver`
+    pub fn head_events(&self) -> broadcast::Receiver<HeadEvent> {
+        self.balanced_rpcs.subscribe_head_events()
+    }
+
+    /// The configured chain_name or the well known name for the chain_id
+    pub fn chain_name(&self) -> Option<String> {
+        self.config.chain_name.clone().or_else(|| {
+            Chain::try_from(self.config.chain_id)
+                .ok()
+                .map(|x| x.to_string())
+        })
+    }
+
+    /// How full this chain's pools are. A saturated pool means this chain is shedding load
+    pub fn pool_status(&self) -> HashMap<&'static str, WorkerPoolStatus> {
+        let mut pools = HashMap::new();
+
+        pools.insert("request", self.request_pool.status());
+        pools.insert("backend", self.balanced_rpcs.backend_pool.status());
+
+        if let Some(stat_sender) = self.stat_sender.as_ref() {
+            // the stat buffer is a channel instead of a semaphore, but it fills up the same way
+            let size = stat_sender.capacity().unwrap_or_default();
+            let in_use = stat_sender.len();
+
+            pools.insert(
+                "stat_buffer",
+                WorkerPoolStatus {
+                    size,
+                    in_use,
+                    saturation: if size == 0 {
+                        0.0
+                    } else {
+                        in_use as f64 / size as f64
+                    },
+                    ..Default::default()
+                },
+            );
+        }
+
+        pools
+    }
+
+    /// Concurrent request permits that are in use and how many were leaked
+    pub fn semaphore_status(&self) -> HashMap<&'static str, SemaphoreRegistryStatus> {
+        let mut registries = HashMap::new();
+
+        registries.insert("bearer_token", self.bearer_token_semaphores.status());
+        registries.insert("ip", self.ip_semaphores.status());
+        registries.insert("user", self.user_semaphores.status());
+
+        registries
+    }
+
+    /// Give back concurrent request permits that have been held longer than `permit_leak_seconds`
+    async fn release_leaked_permits(self: Arc<Self>) -> Web3ProxyResult<()> {
+        if self.config.permit_leak_seconds == 0 {
+            info!("leaked permits are never released");
+            return Ok(());
+        }
+
+        let max_held = Duration::from_secs(self.config.permit_leak_seconds);
+
+        // checking more often than this would only release permits a little sooner
+        let mut interval = interval((max_held / 10).max(Duration::from_secs(1)));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            self.bearer_token_semaphores.release_leaked(max_held);
+            self.ip_semaphores.release_leaked(max_held);
+            self.user_semaphores.release_leaked(max_held);
+        }
+    }
+
     pub async fn prometheus_metrics(&self) -> String {
         let globals = HashMap::new();
         // TODO: what globals? should this be the hostname or what?
@@ -992,34 +1683,119 @@ impl Web3ProxyApp {
         // "user_cache_count": app.rpc_secret_key_cache.entry_count(),
         // "user_cache_size": app.rpc_secret_key_cache.weighted_size(),
 
+        // 1.0 means the backend is not throttled. lower numbers mean it has been sending us rate limit errors
+        let backend_throttle_factors: HashMap<String, f64> = self
+            .balanced_rpcs
+            .by_name
+            .load()
+            .iter()
+            .map(|(name, rpc)| (name.clone(), rpc.throttle_factor()))
+            .collect();
+
+        // how long startup spent in each phase of warmup
+        let warmup_phase_seconds: HashMap<String, f64> = self
+            .warmup
+            .phase_seconds()
+            .into_iter()
+            .map(|(phase, seconds)| (format!("{:?}", phase), seconds))
+            .collect();
+
+        // 1.0 means the pool is full and requests are waiting or being shed
+        let pool_saturation: HashMap<&'static str, f64> = self
+            .pool_status()
+            .into_iter()
+            .map(|(name, status)| (name, status.saturation))
+            .collect();
+
+        // seconds since the newest moment that every stat is in influx. -1 if stats are off or nothing is saved yet
+        let stat_lag_seconds = self
+            .stat_freshness
+            .as_ref()
+            .and_then(|x| x.lag_seconds())
+            .unwrap_or(-1.0);
+
+        // how long the last tsdb save spent in each step of the stat pipeline
+        let stat_pipeline_ms: HashMap<&'static str, u64> = self
+            .stat_freshness
+            .as_ref()
+            .map(|x| x.steps_ms().into_iter().collect())
+            .unwrap_or_default();
+
+        let semaphore_status = self.semaphore_status();
+
+        let semaphore_permits_in_use: HashMap<&'static str, usize> = semaphore_status
+            .iter()
+            .map(|(name, status)| (*name, status.in_use))
+            .collect();
+
+        // a permit that was held past `permit_leak_seconds` means a request is stuck somewhere
+        let semaphore_permits_force_released: HashMap<&'static str, u64> = semaphore_status
+            .iter()
+            .map(|(name, status)| (*name, status.force_released))
+            .collect();
+
         #[derive(Serialize)]
-        struct CombinedMetrics {
+        struct CombinedMetrics<'a> {
+            backend_throttle_factors: HashMap<String, f64>,
+            coalesced_requests: &'a CoalescedRequests,
+            pool_saturation: HashMap<&'static str, f64>,
+            pruned_rows: &'a PrunedRows,
             recent_ip_counts: RecentCounts,
             recent_user_id_counts: RecentCounts,
             recent_tx_counts: RecentCounts,
+            semaphore_permits_force_released: HashMap<&'static str, u64>,
+            semaphore_permits_in_use: HashMap<&'static str, usize>,
+            stat_lag_seconds: f64,
+            stat_pipeline_ms: HashMap<&'static str, u64>,
             user_count: UserCount,
+            warmup_phase_seconds: HashMap<String, f64>,
         }
 
         let metrics = CombinedMetrics {
+            backend_throttle_factors,
+            coalesced_requests: &self.coalesced_requests,
+            pool_saturation,
+            pruned_rows: &self.pruned_rows,
             recent_ip_counts,
             recent_user_id_counts,
             recent_tx_counts,
+            semaphore_permits_force_released,
+            semaphore_permits_in_use,
+            stat_lag_seconds,
+            stat_pipeline_ms,
             user_count,
+            warmup_phase_seconds,
         };
 
         // TODO: i don't like this library. it doesn't include HELP or TYPE lines and so our prometheus server fails to parse it
-        serde_prometheus::to_string(&metrics, Some("web3_proxy"), globals)
+        let namespace = self
+            .config
+            .metric_namespace
+            .as_deref()
+            .unwrap_or("web3_proxy");
+
+        serde_prometheus::to_string(&metrics, Some(namespace), globals)
             .expect("prometheus metrics should always serialize")
     }
 
     /// send the request or batch of requests to the approriate RPCs
+    /// notifications (requests without an id) are still processed, but they do not get a response
     pub async fn proxy_web3_rpc(
         self: &Arc<Self>,
         authorization: Arc<Authorization>,
         request: JsonRpcRequestEnum,
-    ) -> Web3ProxyResult<(StatusCode, JsonRpcForwardedResponseEnum, Vec<Arc<Web3Rpc>>)> {
+    ) -> Web3ProxyResult<(
+        StatusCode,
+        Option<JsonRpcForwardedResponseEnum>,
+        Vec<Arc<Web3Rpc>>,
+    )> {
         // trace!(?request, "proxy_web3_rpc");
 
+        // held until the whole request (or batch) is done
+        let _permit = self.request_pool.acquire(&authorization).await?;
+
+        let is_notification = request.is_notification();
+
         // TODO: use streams and buffers so we don't overwhelm our server
         let response = match request {
             JsonRpcRequestEnum::Single(mut request) => {
@@ -1027,23 +1803,58 @@ impl Web3ProxyApp {
                     .proxy_cached_request(&authorization, &mut request, None)
                     .await;
 
-                (
-                    status_code,
-                    JsonRpcForwardedResponseEnum::Single(response),
-                    rpcs,
-                )
+                let response = if is_notification {
+                    // the side effects (and stats) are done. the client does not want a response
+                    None
+                } else {
+                    Some(JsonRpcForwardedResponseEnum::Single(response))
+                };
+
+                (status_code, response, rpcs)
             }
             JsonRpcRequestEnum::Batch(requests) => {
+                // one error for the whole batch. none of it is sent to the backends
+                if let Some(max_batch_size) = self.max_batch_size(&authorization) {
+                    if requests.len() as u64 > max_batch_size {
+                        return Err(Web3ProxyError::BatchTooLarge {
+                            num_requests: requests.len(),
+                            max_batch_size,
+                        });
+                    }
+                }
+
+                // the batch's own stat carries the tier's per-batch cost
+                let batch_metadata = if authorization.checks.batch_overhead_cost.is_some() {
+                    Some(
+                        RequestMetadata::new(
+                            self,
+                            authorization.clone(),
+                            RequestOrMethod::Method(BATCH_STAT_METHOD, 0),
+                            None,
+                        )
+                        .await,
+                    )
+                } else {
+                    None
+                };
+
                 let (responses, rpcs) = self
                     .proxy_web3_rpc_requests(&authorization, requests)
                     .await?;
 
+                if let Some(batch_metadata) = batch_metadata {
+                    batch_metadata.add_response(0u64);
+                }
+
+                // if every request in the batch was a notification, the spec says to respond with nothing at all
+                let response = if is_notification {
+                    None
+                } else {
+                    Some(JsonRpcForwardedResponseEnum::Batch(responses))
+                };
+
                 // TODO: real status code
-                (
-                    StatusCode::OK,
-                    JsonRpcForwardedResponseEnum::Batch(responses),
-                    rpcs,
-                )
+                (StatusCode::OK, response, rpcs)
             }
         };
 
@@ -1051,6 +1862,7 @@ impl Web3ProxyApp {
     }
 
     /// cut up the request and send to potentually different servers
+    /// responses for notifications are left out of the returned Vec
     /// TODO: make sure this isn't a problem
     async fn proxy_web3_rpc_requests(
         self: &Arc<Self>,
@@ -1062,29 +1874,38 @@ impl Web3ProxyApp {
 
         // get the head block now so that any requests that need it all use the same block
         // TODO: this still has an edge condition if there is a reorg in the middle of the request!!!
-        let head_block_num = self
-            .balanced_rpcs
-            .head_block_num()
-            .ok_or(Web3ProxyError::NoServersSynced)?;
-
-        let responses = join_all(
-            requests
-                .iter_mut()
-                .map(|request| {
-                    self.proxy_cached_request(authorization, request, Some(head_block_num))
-                })
-                .collect::<Vec<_>>(),
-        )
-        .await;
+        let head_block_num = match self.sandbox_head_block_num() {
+            Some(x) => x,
+            None => self
+                .balanced_rpcs
+                .head_block_num()
+                .ok_or(Web3ProxyError::NoServersSynced)?,
+        };
+
+        let notifications: Vec<bool> = requests.iter().map(|x| x.notification).collect();
+
+        let parallelism = self.batch_parallelism(authorization, num_requests);
+
+        let futures: Vec<_> = requests
+            .iter_mut()
+            .map(|request| self.proxy_cached_request(authorization, request, Some(head_block_num)))
+            .collect();
+
+        // buffered keeps the responses in the same order as the requests
+        let responses: Vec<_> = stream::iter(futures).buffered(parallelism).collect().await;
 
         let mut collected: Vec<JsonRpcForwardedResponse> = Vec::with_capacity(num_requests);
         let mut collected_rpc_names: HashSet<String> = HashSet::new();
         let mut collected_rpcs: Vec<Arc<Web3Rpc>> = vec![];
-        for response in responses {
+        for (response, notification) in responses.into_iter().zip(notifications) {
             // TODO: any way to attach the tried rpcs to the error? it is likely helpful
             let (status_code, response, rpcs) = response;
 
-            collected.push(response);
+            // notifications are still sent to the backends, but their responses are dropped
+            if !notification {
+                collected.push(response);
+            }
+
             collected_rpcs.extend(rpcs.into_iter().filter(|x| {
                 if collected_rpc_names.contains(&x.name) {
                     false
@@ -1109,6 +1930,29 @@ impl Web3ProxyApp {
         self.db_replica.clone()
     }
 
+    /// Encrypt a sensitive value before saving it. Unchanged if row encryption is not configured
+    pub fn seal_row(&self, x: String) -> Web3ProxyResult<String> {
+        match self.row_cipher.as_ref() {
+            Some(row_cipher) => Ok(row_cipher.encrypt(&x)?),
+            None => Ok(x),
+        }
+    }
+
+    /// Decrypt a value saved with `seal_row`
+    pub fn unseal_row(&self, x: String) -> Web3ProxyResult<String> {
+        match self.row_cipher.as_ref() {
+            Some(row_cipher) => Ok(row_cipher.decrypt(&x)?),
+            None => Ok(x),
+        }
+    }
+
+    /// Decrypt the user's sensitive columns so that they can be shown to them
+    pub fn unseal_user(&self, mut user: user::Model) -> Web3ProxyResult<user::Model> {
+        user.email = user.email.map(|x| self.unseal_row(x)).transpose()?;
+
+        Ok(user)
+    }
+
     pub async fn redis_conn(&self) -> anyhow::Result<Option<redis_rate_limiter::RedisConnection>> {
         match self.vredis_pool.as_ref() {
             // TODO: don't do an error. return None
@@ -1186,11 +2030,6 @@ impl Web3ProxyApp {
         // TODO: move this code to another module so that its easy to turn this trace logging on in dev
         trace!("Received request: {:?}", request);
 
-        // save the id so we can attach it to the response
-        // TODO: we don't always need to clone this. if we come from the cache, we can just take from the request
-        // TODO: store on the request_metadata?
-        let response_id = request.id.clone();
-
         let request_metadata = RequestMetadata::new(
             self,
             authorization.clone(),
@@ -1207,7 +2046,10 @@ impl Web3ProxyApp {
             Err(err) => err.into_response_parts(),
         };
 
-        let response = JsonRpcForwardedResponse::from_response_data(response_data, response_id);
+        // the response data might have come from the cache. make sure it has this request's id and version
+        let response =
+            JsonRpcForwardedResponse::from_response_data(response_data, Default::default())
+                .normalize(request);
 
         // TODO: this serializes twice :/
         request_metadata.add_response(ResponseOrBytes::Response(&response));
@@ -1226,9 +2068,17 @@ impl Web3ProxyApp {
         head_block_num: Option<U64>,
         request_metadata: &Arc<RequestMetadata>,
     ) -> Web3ProxyResult<JsonRpcResponseData> {
+        // the script can rewrite the request, so this runs before anything else looks at the method
+        self.check_request_policy(authorization, request, request_metadata)
+            .await?;
+
         // TODO: don't clone?
         let request_method = request.method.clone();
 
+        if self.config.sandbox.enabled {
+            return self.sandbox_response(request);
+        }
+
         // TODO: serve net_version without querying the backend
         let response_data: JsonRpcResponseData = match request_method.as_ref() {
             // lots of commands are blocked
@@ -1402,7 +2252,14 @@ impl Web3ProxyApp {
                     response_data
                 }
             }
-            "eth_getTransactionReceipt" | "eth_getTransactionByHash" => {
+            method @ ("eth_getTransactionReceipt" | "eth_getTransactionByHash") => {
+                let negative_cache_key =
+                    self.negative_cache_key(authorization, method, request.params.as_ref());
+
+                if let Some(response_data) = self.cached_not_found(negative_cache_key.as_ref()) {
+                    return Ok(response_data);
+                }
+
                 // try to get the transaction without specifying a min_block_height
                 // TODO: timeout
                 let mut response_data = self
@@ -1436,6 +2293,9 @@ impl Web3ProxyApp {
                     }
                 }
 
+                // full and archive nodes both said "null". it won't exist until at least the next block
+                self.cache_not_found(negative_cache_key, &response_data);
+
                 response_data
             }
             // TODO: eth_gasPrice that does awesome magic to predict the future
@@ -1444,18 +2304,38 @@ impl Web3ProxyApp {
             // TODO: eth_sendBundle (flashbots/eden command)
             // broadcast transactions to all private rpcs at once
             "eth_sendRawTransaction" => {
-                // TODO: decode the transaction
-
                 // TODO: error if the chain_id is incorrect
 
+                self.screen_raw_transaction(authorization, request).await?;
+
+                let journal_id = self.journal_raw_transaction(authorization, request).await?;
+
+                let tracked_tx = self.track_raw_transaction(request);
+
                 // TODO: timeout
-                let mut response_data = self
+                let mut response_data = match self
                     .try_send_protected(
                         authorization,
                         request,
                         request_metadata.clone(),
                     )
-                    .await?;
+                    .await
+                {
+                    Ok(x) => x,
+                    Err(err) => {
+                        // the backends might not have seen it. recovery will check
+                        self.finish_tx_journal(
+                            journal_id,
+                            TxJournalStatus::Pending,
+                            Some(format!("{:?}", err)),
+                        )
+                        .await;
+
+                        self.track_broadcast(tracked_tx, 0, Some(format!("{:?}", err)));
+
+                        return Err(err);
+                    }
+                };
 
                 // sometimes we get an error that the transaction is already known by our nodes,
                 // that's not really an error. Return the hash like a successful response would.
@@ -1473,20 +2353,20 @@ impl Web3ProxyApp {
                         let params = params
                             .as_array()
                             .ok_or_else(|| {
-                                Web3ProxyError::BadRequest(
-                                    "Unable to get array from params".to_string(),
+                                Web3ProxyError::InvalidRpcParams(
+                                    "params must be an array".into(),
                                 )
                             })?
                             .get(0)
                             .ok_or_else(|| {
-                                Web3ProxyError::BadRequest(
-                                    "Unable to get item 0 from params".to_string(),
+                                Web3ProxyError::InvalidRpcParams(
+                                    "params must have an item 0".into(),
                                 )
                             })?
                             .as_str()
                             .ok_or_else(|| {
-                                Web3ProxyError::BadRequest(
-                                    "Unable to get string from params item 0".to_string(),
+                                Web3ProxyError::InvalidRpcParams(
+                                    "params item 0 must be a string".into(),
                                 )
                             })?;
 
@@ -1506,6 +2386,31 @@ impl Web3ProxyApp {
                     }
                 }
 
+                let num_backends = request_metadata.backend_requests.lock().len();
+
+                match &response_data {
+                    JsonRpcResponseData::Result { .. } => {
+                        self.finish_tx_journal(journal_id, TxJournalStatus::Broadcast, None)
+                            .await;
+
+                        self.track_broadcast(tracked_tx, num_backends, None);
+                    }
+                    JsonRpcResponseData::Error { value, .. } => {
+                        self.finish_tx_journal(
+                            journal_id,
+                            TxJournalStatus::Failed,
+                            Some(value.message.to_string()),
+                        )
+                        .await;
+
+                        self.track_broadcast(
+                            tracked_tx,
+                            num_backends,
+                            Some(value.message.to_string()),
+                        );
+                    }
+                }
+
                 // emit transaction count stats
                 // TODO: use this cache to avoid sending duplicate transactions?
                 if let Some(ref salt) = self.config.public_recent_ips_salt {
@@ -1544,13 +2449,17 @@ impl Web3ProxyApp {
                     }
                 }
 
+                if let JsonRpcResponseData::Result { value, .. } = &response_data {
+                    if let Ok(tx_hash) = serde_json::from_str::<TxHash>(value.get()) {
+                        self.forget_not_found_transaction(tx_hash);
+                    }
+                }
+
                 response_data
             }
             "eth_syncing" => {
                 // no stats on this. its cheap
-                // TODO: return a real response if all backends are syncing or if no servers in sync
-                // TODO: const
-                JsonRpcResponseData::from(serde_json::Value::Bool(false))
+                JsonRpcResponseData::from(self.eth_syncing())
             }
             "eth_subscribe" => JsonRpcErrorData {
                 message: Cow::Borrowed(
@@ -1574,7 +2483,7 @@ impl Web3ProxyApp {
                 JsonRpcResponseData::from(json!(U64::from(self.balanced_rpcs.num_synced_rpcs())))
             ,
             "web3_clientVersion" => 
-                JsonRpcResponseData::from(serde_json::Value::String(APP_USER_AGENT.to_string()))
+                JsonRpcResponseData::from(serde_json::Value::String(self.web3_client_version()))
             ,
             "web3_sha3" => {
                 // returns Keccak-256 (not the standardized SHA3-256) of the given data.
@@ -1586,14 +2495,14 @@ impl Web3ProxyApp {
                             || !params.get(0).map(|x| x.is_string()).unwrap_or(false)
                         {
                             // TODO: what error code?
-                            // TODO: use Web3ProxyError::BadRequest
+                            // TODO: use Web3ProxyError::InvalidRpcParams
                             JsonRpcErrorData {
                                 message: Cow::Borrowed("Invalid request"),
                                 code: -32600,
                                 data: None
                             }.into()
                         } else {
-                            // TODO: BadRequest instead of web3_context
+                            // TODO: InvalidRpcParams instead of web3_context
                             let param = Bytes::from_str(
                                 params[0]
                                     .as_str()
@@ -1602,8 +2511,8 @@ impl Web3ProxyApp {
                             )
                             .map_err(|x| {
                                 trace!("bad request: {:?}", x);
-                                Web3ProxyError::BadRequest(
-                                    "param 0 could not be read as H256".to_string(),
+                                Web3ProxyError::InvalidRpcParams(
+                                    "param 0 could not be read as H256".into(),
                                 )
                             })?;
 
@@ -1614,7 +2523,7 @@ impl Web3ProxyApp {
                     }
                     _ => {
                         // TODO: this needs the correct error code in the response
-                        // TODO: Web3ProxyError::BadRequest instead?
+                        // TODO: Web3ProxyError::InvalidRpcParams instead?
                         JsonRpcErrorData {
                             message: Cow::Borrowed("invalid request"),
                             code: StatusCode::BAD_REQUEST.as_u16().into(),
@@ -1661,6 +2570,7 @@ impl Web3ProxyApp {
                         method: method.to_string(),
                         params: request.params.clone(),
                         cache_errors: false,
+                        cache_partition: authorization.checks.cache_partition,
                     }),
                     BlockNeeded::CacheNever => None,
                     BlockNeeded::Cache {
@@ -1691,6 +2601,7 @@ impl Web3ProxyApp {
                             // TODO: hash here?
                             params: request.params.clone(),
                             cache_errors,
+                            cache_partition: authorization.checks.cache_partition,
                         })
                     }
                     BlockNeeded::CacheRange {
@@ -1732,10 +2643,32 @@ impl Web3ProxyApp {
                             method: method.to_string(),
                             params: request.params.clone(),
                             cache_errors,
+                            cache_partition: authorization.checks.cache_partition,
                         })
                     }
                 };
 
+                // identical requests share one trip to the backends even if they can't be cached
+                let coalesce_key = self.coalesce_key(
+                    self.config.chain_id,
+                    authorization,
+                    method,
+                    request.params.as_ref(),
+                    cache_key.as_ref(),
+                    self.balanced_rpcs.head_block_hash(),
+                );
+
+                // keys that require fresh data never use the response cache
+                let cache_key = cache_key.filter(|_| !authorization.checks.bypass_cache);
+
+                // the request params are final now that block_needed is done with them
+                let negative_cache_key =
+                    self.negative_cache_key(authorization, method, request.params.as_ref());
+
+                if let Some(response_data) = self.cached_not_found(negative_cache_key.as_ref()) {
+                    return Ok(response_data);
+                }
+
                 let authorization = authorization.clone();
 
                 // TODO: different timeouts for different user tiers
@@ -1745,50 +2678,141 @@ impl Web3ProxyApp {
                     let from_block_num = cache_key.from_block.as_ref().map(|x| x.number.unwrap());
                     let to_block_num = cache_key.to_block.as_ref().map(|x| x.number.unwrap());
 
-                    match self
-                        .jsonrpc_response_cache
-                        .get_value_or_guard_async(cache_key).await
-                    {
-                        Ok(x) => x,
-                        Err(x) => {
-                            let response_data = timeout(
-                                duration,
-                                self.balanced_rpcs
-                                    .try_proxy_connection(
+                    let fetch = async {
+                        let response_data = self
+                            .coalesce(coalesce_key, async {
+                                let response_data = timeout(
+                                    duration,
+                                    self.balanced_rpcs.try_proxy_connection(
                                         &authorization,
                                         request,
                                         Some(request_metadata),
                                         from_block_num.as_ref(),
                                         to_block_num.as_ref(),
-                                    )
+                                    ),
                                 )
                                 .await??;
 
-                            // TODO: convert the Box<RawValue> to an Arc<RawValue>
-                            x.insert(response_data.clone());
+                                let response_data = self
+                                    .emulate_block_receipts_if_needed(
+                                        &authorization,
+                                        request,
+                                        response_data,
+                                        head_block_num,
+                                        request_metadata,
+                                    )
+                                    .await?;
+
+                                // too large responses are not cached
+                                self.check_response_size(method, &response_data, request_metadata)?;
 
-                            response_data
+                                Ok(response_data)
+                            })
+                            .await?;
+
+                        let not_found = negative_cache_key.is_some()
+                            && matches!(
+                                &response_data,
+                                JsonRpcResponseData::Result { value, .. } if value.get() == "null"
+                            );
+
+                        if not_found {
+                            // "null" is only true until the next block. keep it out of the response cache
+                            self.cache_not_found(negative_cache_key, &response_data);
                         }
-                    }
+
+                        Ok::<_, Web3ProxyError>((response_data, !not_found))
+                    };
+
+                    self.response_cache_backend
+                        .get_or_fetch(cache_key, fetch.boxed())
+                        .await?
                 } else {
-                    timeout(
-                        duration,
-                        self.balanced_rpcs
-                        .try_proxy_connection(
-                            &authorization,
-                            request,
-                            Some(request_metadata),
-                            None,
-                            None,
+                    // uncached methods do not say what block they need. some rollup methods always need old blocks
+                    let min_block_needed = if rollup_method_needs_archive(method) {
+                        request_metadata
+                            .archive_request
+                            .store(true, atomic::Ordering::Release);
+
+                        Some(U64::one())
+                    } else {
+                        None
+                    };
+
+                    self.coalesce(coalesce_key, async {
+                        let response_data = timeout(
+                            duration,
+                            self.balanced_rpcs.try_proxy_connection(
+                                &authorization,
+                                request,
+                                Some(request_metadata),
+                                min_block_needed.as_ref(),
+                                None,
+                            ),
                         )
-                    )
-                    .await??
+                        .await??;
+
+                        let response_data = self
+                            .emulate_block_receipts_if_needed(
+                                &authorization,
+                                request,
+                                response_data,
+                                head_block_num,
+                                request_metadata,
+                            )
+                            .await?;
+
+                        self.check_response_size(method, &response_data, request_metadata)?;
+
+                        Ok(response_data)
+                    })
+                    .await?
                 }
             }
         };
 
         Ok(response_data)
     }
+
+    /// The smallest of the max_response_bytes that match the method and the user tier's max_response_bytes
+    pub(crate) fn max_response_bytes(
+        &self,
+        authorization: &Authorization,
+        method: &str,
+    ) -> Option<u64> {
+        lowest_max_response_bytes(
+            &self.config.max_response_bytes,
+            authorization.checks.max_response_bytes,
+            method,
+        )
+    }
+
+    /// Error if the response is larger than the request's max_response_bytes.
+    /// Backends that can be read a chunk at a time are already cut off while the body is read. This catches everything else
+    fn check_response_size(
+        &self,
+        method: &str,
+        response_data: &JsonRpcResponseData,
+        request_metadata: &RequestMetadata,
+    ) -> Web3ProxyResult<()> {
+        if let Some(max_bytes) = request_metadata.max_response_bytes.get().copied() {
+            let num_bytes = response_data.num_bytes().get() as u64;
+
+            if num_bytes > max_bytes {
+                request_metadata
+                    .response_too_large
+                    .store(true, atomic::Ordering::Release);
+
+                return Err(Web3ProxyError::ResponseTooLarge {
+                    method: method.to_string(),
+                    num_bytes,
+                    max_bytes,
+                });
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl fmt::Debug for Web3ProxyApp {
@@ -1797,3 +2821,44 @@ impl fmt::Debug for Web3ProxyApp {
         f.debug_struct("Web3ProxyApp").finish_non_exhaustive()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lowest_max_response_bytes() {
+        let method_limits = HashMap::from([
+            ("eth_getLogs".to_string(), 1_000),
+            ("debug_*".to_string(), 5_000),
+            ("debug_traceTransaction".to_string(), 2_000),
+        ]);
+
+        assert_eq!(
+            lowest_max_response_bytes(&method_limits, None, "eth_call"),
+            None
+        );
+        assert_eq!(
+            lowest_max_response_bytes(&method_limits, None, "debug_traceTransaction"),
+            Some(2_000)
+        );
+        assert_eq!(
+            lowest_max_response_bytes(&method_limits, None, "debug_traceCall"),
+            Some(5_000)
+        );
+
+        // the tier applies to every method
+        assert_eq!(
+            lowest_max_response_bytes(&method_limits, Some(3_000), "eth_call"),
+            Some(3_000)
+        );
+        assert_eq!(
+            lowest_max_response_bytes(&method_limits, Some(3_000), "eth_getLogs"),
+            Some(1_000)
+        );
+        assert_eq!(
+            lowest_max_response_bytes(&method_limits, Some(3_000), "debug_traceCall"),
+            Some(3_000)
+        );
+    }
+}
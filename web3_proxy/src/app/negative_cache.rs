@@ -0,0 +1,116 @@
+//! Cache "null" answers to lookups by hash so that clients polling for something that does not exist yet don't hit the backends every time.
+//! Entries are tied to the head block they were seen at. A new head (or a reorg) means they are never used again.
+use super::Web3ProxyApp;
+use crate::frontend::authorization::Authorization;
+use crate::frontend::errors::Web3ProxyResult;
+use crate::response_cache::{JsonRpcResponseData, NegativeCacheKey};
+use crate::rpcs::head_events::HeadEvent;
+use ethers::types::TxHash;
+use log::{debug, trace, warn};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+
+/// lookups by hash. once the backends agree on a head block, "null" means the thing does not exist as of that head.
+const NEGATIVE_CACHE_METHODS: &[&str] = &[
+    "eth_getBlockByHash",
+    "eth_getBlockTransactionCountByHash",
+    "eth_getTransactionByBlockHashAndIndex",
+    "eth_getTransactionByHash",
+    "eth_getTransactionReceipt",
+    "eth_getUncleByBlockHashAndIndex",
+    "eth_getUncleCountByBlockHash",
+];
+
+/// methods that will find a transaction as soon as it is sent to us
+const TRANSACTION_METHODS: &[&str] = &["eth_getTransactionByHash", "eth_getTransactionReceipt"];
+
+impl Web3ProxyApp {
+    /// The negative cache key for a request.
+    /// None if the method is not cacheable, the negative cache is disabled, or the key bypasses caches
+    pub(super) fn negative_cache_key(
+        &self,
+        authorization: &Authorization,
+        method: &str,
+        params: Option<&serde_json::Value>,
+    ) -> Option<NegativeCacheKey> {
+        if self.config.negative_cache_ttl_seconds == 0
+            || authorization.checks.bypass_cache
+            || !NEGATIVE_CACHE_METHODS.contains(&method)
+        {
+            return None;
+        }
+
+        let head_block_hash = *self.watch_consensus_head_receiver.borrow().as_ref()?.hash();
+
+        Some(NegativeCacheKey {
+            head_block_hash,
+            method: method.to_string(),
+            params: params.map(|x| x.to_string()).unwrap_or_default(),
+        })
+    }
+
+    /// A "null" response if this lookup already found nothing at the current head
+    pub(super) fn cached_not_found(
+        &self,
+        key: Option<&NegativeCacheKey>,
+    ) -> Option<JsonRpcResponseData> {
+        let key = key?;
+
+        self.negative_response_cache.get(key)?;
+
+        trace!("negative cache hit for {:?}", key);
+
+        Some(JsonRpcResponseData::from(serde_json::Value::Null))
+    }
+
+    /// Remember the response if it was "null"
+    pub(super) fn cache_not_found(
+        &self,
+        key: Option<NegativeCacheKey>,
+        response_data: &JsonRpcResponseData,
+    ) {
+        if let (Some(key), JsonRpcResponseData::Result { value, .. }) = (key, response_data) {
+            if value.get() == "null" {
+                let _ = self.negative_response_cache.try_insert(key, ());
+            }
+        }
+    }
+
+    /// A transaction was just sent through us. Anything polling for it should see it right away
+    pub(super) fn forget_not_found_transaction(&self, tx_hash: TxHash) {
+        let params = json!([tx_hash]).to_string();
+
+        self.negative_response_cache.remove_matching(|key, _| {
+            key.params == params && TRANSACTION_METHODS.contains(&key.method.as_str())
+        });
+    }
+
+    /// Drop every entry from a head that is no longer part of the chain.
+    /// They can't be used anymore anyways, but reorgs can leave a lot of them behind until their ttl
+    pub(super) async fn invalidate_negative_cache(self: Arc<Self>) -> Web3ProxyResult<()> {
+        let mut head_events = self.head_events();
+
+        loop {
+            match head_events.recv().await {
+                Ok(HeadEvent::Reorg { new, .. }) => {
+                    let removed = self
+                        .negative_response_cache
+                        .remove_matching(|key, _| key.head_block_hash != *new.hash());
+
+                    debug!("reorg removed {} negative cache entries", removed);
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    // one of the skipped events might have been a reorg
+                    warn!("negative cache missed {} head events. clearing it", skipped);
+
+                    self.negative_response_cache.remove_matching(|_, _| true);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+
+        Ok(())
+    }
+}
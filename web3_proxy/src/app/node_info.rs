@@ -0,0 +1,117 @@
+//! `eth_syncing` and `web3_clientVersion` answered by the proxy.
+//! Asking one random backend would give a different answer every time, so the backends' states are combined instead.
+use super::{Web3ProxyApp, APP_USER_AGENT};
+use ethers::types::U64;
+use itertools::Itertools;
+use serde_json::json;
+
+/// geth's `eth_syncing` object if every backend is syncing. false if any of them is not
+fn aggregate_syncing(states: &[(Option<U64>, bool)]) -> serde_json::Value {
+    if states.iter().any(|(_, syncing)| !syncing) {
+        return false.into();
+    }
+
+    let heads = states.iter().filter_map(|(x, _)| *x);
+
+    let (starting_block, highest_block) = heads
+        .minmax()
+        .into_option()
+        .unwrap_or((U64::zero(), U64::zero()));
+
+    // the proxy serves from the best backend, so that is the current block
+    json!({
+        "startingBlock": starting_block,
+        "currentBlock": highest_block,
+        "highestBlock": highest_block,
+    })
+}
+
+/// "brand (Geth x2, erigon x1)". Clients are named by the part of their version before the first "/"
+fn client_version_summary(brand: &str, versions: &[String]) -> String {
+    let clients = versions
+        .iter()
+        .map(|x| x.split('/').next().unwrap_or_default())
+        .filter(|x| !x.is_empty())
+        .counts();
+
+    if clients.is_empty() {
+        return brand.to_string();
+    }
+
+    let clients = clients
+        .into_iter()
+        .sorted()
+        .map(|(name, count)| format!("{} x{}", name, count))
+        .join(", ");
+
+    format!("{} ({})", brand, clients)
+}
+
+impl Web3ProxyApp {
+    pub(crate) fn eth_syncing(&self) -> serde_json::Value {
+        let config = &self.config.node_info;
+
+        if !config.aggregate_syncing {
+            return false.into();
+        }
+
+        aggregate_syncing(
+            &self
+                .balanced_rpcs
+                .sync_states(config.syncing_max_head_age_seconds),
+        )
+    }
+
+    pub(crate) fn web3_client_version(&self) -> String {
+        let config = &self.config.node_info;
+
+        let brand = config.client_version.as_deref().unwrap_or(APP_USER_AGENT);
+
+        if !config.client_version_backends {
+            return brand.to_string();
+        }
+
+        client_version_summary(brand, &self.balanced_rpcs.client_versions())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate_syncing() {
+        assert_eq!(
+            aggregate_syncing(&[(Some(10.into()), true), (Some(20.into()), false)]),
+            json!(false)
+        );
+
+        assert_eq!(
+            aggregate_syncing(&[
+                (Some(10.into()), true),
+                (None, true),
+                (Some(20.into()), true)
+            ]),
+            json!({
+                "startingBlock": "0xa",
+                "currentBlock": "0x14",
+                "highestBlock": "0x14",
+            })
+        );
+    }
+
+    #[test]
+    fn test_client_version_summary() {
+        let versions = vec![
+            "Geth/v1.12.0-stable/linux-amd64/go1.20.5".to_string(),
+            "erigon/2.48.0/linux-amd64/go1.20.5".to_string(),
+            "Geth/v1.11.6-stable/linux-amd64/go1.20.3".to_string(),
+        ];
+
+        assert_eq!(
+            client_version_summary("llamanodes", &versions),
+            "llamanodes (Geth x2, erigon x1)"
+        );
+        assert_eq!(client_version_summary("llamanodes", &[]), "llamanodes");
+    }
+}
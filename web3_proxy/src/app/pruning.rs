@@ -0,0 +1,179 @@
+//! Delete database rows that will never be used again so that tables don't grow forever.
+//! Rows are deleted in small batches so that a big backlog doesn't lock a table for long
+use super::Web3ProxyApp;
+use crate::frontend::errors::Web3ProxyResult;
+use chrono::Utc;
+use entities::{idempotency_key, login, org_invite, pending_login, revert_log, stat_flush};
+use log::{debug, info, warn};
+use migration::sea_orm::{
+    ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QuerySelect,
+};
+use serde::Serialize;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{interval, MissedTickBehavior};
+
+/// Counts of rows deleted since the app started. Exposed in the prometheus metrics
+#[derive(Debug, Default, Serialize)]
+pub struct PrunedRows {
+    pub idempotency_key: AtomicU64,
+    pub login: AtomicU64,
+    pub org_invite: AtomicU64,
+    pub pending_login: AtomicU64,
+    pub revert_log: AtomicU64,
+    pub stat_flush: AtomicU64,
+}
+
+/// Select a batch of ids and delete them. Returns the total number of rows deleted.
+/// Keeps going until a batch comes back short
+macro_rules! prune_table {
+    ($db_conn:expr, $batch_size:expr, $entity:ident, $filter:expr) => {{
+        let mut total: u64 = 0;
+
+        loop {
+            let ids: Vec<u64> = $entity::Entity::find()
+                .select_only()
+                .column($entity::Column::Id)
+                .filter($filter)
+                .limit($batch_size)
+                .into_tuple()
+                .all($db_conn)
+                .await?;
+
+            if ids.is_empty() {
+                break;
+            }
+
+            let num_ids = ids.len() as u64;
+
+            let deleted = $entity::Entity::delete_many()
+                .filter($entity::Column::Id.is_in(ids))
+                .exec($db_conn)
+                .await?
+                .rows_affected;
+
+            total += deleted;
+
+            if num_ids < $batch_size {
+                break;
+            }
+
+            // give other queries a turn
+            tokio::task::yield_now().await;
+        }
+
+        total
+    }};
+}
+
+impl Web3ProxyApp {
+    /// Prune stale rows every `prune_interval_seconds`
+    pub(super) async fn prune_stale_rows(self: Arc<Self>) -> Web3ProxyResult<()> {
+        if self.config.prune_interval_seconds == 0 {
+            info!("pruning is disabled");
+            return Ok(());
+        }
+
+        let mut interval = interval(Duration::from_secs(self.config.prune_interval_seconds));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let db_conn = match self.db_conn() {
+                Some(x) => x,
+                None => {
+                    debug!("no database. nothing to prune");
+                    return Ok(());
+                }
+            };
+
+            if let Err(err) = self.prune_once(&db_conn).await {
+                warn!("pruning stale rows failed: {:?}", err);
+            }
+        }
+    }
+
+    /// One pass over every table that gets pruned
+    pub async fn prune_once(&self, db_conn: &DatabaseConnection) -> Result<(), DbErr> {
+        let batch_size = self.config.prune_batch_size.max(1);
+
+        let now = Utc::now();
+
+        // nonces that were never used to log in
+        let deleted = prune_table!(
+            db_conn,
+            batch_size,
+            pending_login,
+            pending_login::Column::ExpiresAt.lt(now)
+        );
+        record_pruned(&self.pruned_rows.pending_login, "pending_login", deleted);
+
+        // bearer tokens that can't be used anymore
+        let deleted = prune_table!(db_conn, batch_size, login, login::Column::ExpiresAt.lt(now));
+        record_pruned(&self.pruned_rows.login, "login", deleted);
+
+        // invites that can't be accepted anymore
+        let deleted = prune_table!(
+            db_conn,
+            batch_size,
+            org_invite,
+            org_invite::Column::ExpiresAt.lt(now)
+        );
+        record_pruned(&self.pruned_rows.org_invite, "org_invite", deleted);
+
+        if self.config.revert_log_max_age_days > 0 {
+            let cutoff = now - chrono::Duration::days(self.config.revert_log_max_age_days as i64);
+
+            let deleted = prune_table!(
+                db_conn,
+                batch_size,
+                revert_log,
+                revert_log::Column::Timestamp.lt(cutoff)
+            );
+            record_pruned(&self.pruned_rows.revert_log, "revert_log", deleted);
+        }
+
+        if self.config.idempotency_key_max_age_days > 0 {
+            let cutoff =
+                now - chrono::Duration::days(self.config.idempotency_key_max_age_days as i64);
+
+            let deleted = prune_table!(
+                db_conn,
+                batch_size,
+                idempotency_key,
+                idempotency_key::Column::CreatedAt.lt(cutoff)
+            );
+            record_pruned(
+                &self.pruned_rows.idempotency_key,
+                "idempotency_key",
+                deleted,
+            );
+        }
+
+        if self.config.stat_flush_max_age_days > 0 {
+            let cutoff = now - chrono::Duration::days(self.config.stat_flush_max_age_days as i64);
+
+            let deleted = prune_table!(
+                db_conn,
+                batch_size,
+                stat_flush,
+                stat_flush::Column::CreatedAt.lt(cutoff)
+            );
+            record_pruned(&self.pruned_rows.stat_flush, "stat_flush", deleted);
+        }
+
+        // TODO: rpc keys are only ever deactivated, never soft-deleted, so there is nothing with a grace period to prune yet
+
+        Ok(())
+    }
+}
+
+fn record_pruned(counter: &AtomicU64, table: &str, deleted: u64) {
+    if deleted > 0 {
+        info!("pruned {} rows from {}", deleted, table);
+
+        counter.fetch_add(deleted, atomic::Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,199 @@
+//! Emergency read-only mode. State-changing methods are rejected while reads are still served.
+//! Useful during upstream mempool incidents or when a chain is halted.
+//!
+//! Switches are saved in redis so that every proxy sees them. The global switch applies to every chain.
+//! A chain's switch only applies to the proxies serving that chain.
+use super::Web3ProxyApp;
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use chrono::Utc;
+use log::{debug, info, warn};
+use redis_rate_limiter::redis;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{interval, MissedTickBehavior};
+
+/// Methods that change chain state. Methods that are always blocked (like `eth_sendTransaction`) are not listed
+pub const STATE_CHANGING_METHODS: &[&str] = &[
+    "eth_cancelPrivateTransaction",
+    "eth_sendBundle",
+    "eth_sendPrivateTransaction",
+    "eth_sendRawTransaction",
+    "eth_sendUserOperation",
+];
+
+const GLOBAL_REDIS_KEY: &str = "read_only:global";
+
+fn chain_redis_key(chain_id: u64) -> String {
+    format!("read_only:chain:{}", chain_id)
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct ReadOnlyReason {
+    pub reason: String,
+    /// unix epoch seconds
+    pub since: i64,
+}
+
+/// The switches that apply to this proxy
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ReadOnlyStatus {
+    pub global: Option<ReadOnlyReason>,
+    /// the switch for this proxy's chain
+    pub chain: Option<ReadOnlyReason>,
+}
+
+impl ReadOnlyStatus {
+    /// The chain's reason is more specific, so it wins
+    pub fn active(&self) -> Option<&ReadOnlyReason> {
+        self.chain.as_ref().or(self.global.as_ref())
+    }
+}
+
+/// Turn read-only mode on or off
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReadOnlySwitch {
+    pub enabled: bool,
+    /// None is the global switch. Other chains' switches are saved for the proxies that serve them
+    pub chain_id: Option<u64>,
+    /// shown to users in the error
+    pub reason: Option<String>,
+}
+
+impl Web3ProxyApp {
+    pub fn read_only_status(&self) -> ReadOnlyStatus {
+        self.read_only.read().clone()
+    }
+
+    /// Error if the method changes state and read-only mode is on
+    pub fn check_read_only(&self, method: &str) -> Web3ProxyResult<()> {
+        if !STATE_CHANGING_METHODS.contains(&method) {
+            return Ok(());
+        }
+
+        match self.read_only.read().active() {
+            None => Ok(()),
+            Some(x) => Err(Web3ProxyError::ReadOnly(x.reason.clone())),
+        }
+    }
+
+    /// Save the switch to redis (if configured) and apply it here if it is for this chain
+    pub async fn set_read_only(&self, switch: &ReadOnlySwitch) -> Web3ProxyResult<ReadOnlyStatus> {
+        let reason = if switch.enabled {
+            Some(ReadOnlyReason {
+                reason: switch
+                    .reason
+                    .clone()
+                    .unwrap_or_else(|| "emergency maintenance".to_string()),
+                since: Utc::now().timestamp(),
+            })
+        } else {
+            None
+        };
+
+        let redis_key = match switch.chain_id {
+            None => GLOBAL_REDIS_KEY.to_string(),
+            Some(chain_id) => chain_redis_key(chain_id),
+        };
+
+        if let Some(mut redis_conn) = self.redis_conn().await? {
+            match reason.as_ref() {
+                Some(x) => {
+                    redis::cmd("SET")
+                        .arg(&redis_key)
+                        .arg(serde_json::to_string(x)?)
+                        .query_async::<_, ()>(&mut redis_conn)
+                        .await?
+                }
+                None => {
+                    redis::cmd("DEL")
+                        .arg(&redis_key)
+                        .query_async::<_, ()>(&mut redis_conn)
+                        .await?
+                }
+            }
+        }
+
+        {
+            let mut status = self.read_only.write();
+
+            match switch.chain_id {
+                None => status.global = reason,
+                Some(chain_id) if chain_id == self.config.chain_id => status.chain = reason,
+                Some(_) => {}
+            }
+        }
+
+        info!("read-only switch changed: {:?}", switch);
+
+        Ok(self.read_only_status())
+    }
+
+    /// Copy the switches from redis every `read_only_poll_seconds` so that every proxy agrees
+    pub(super) async fn watch_read_only(self: Arc<Self>) -> Web3ProxyResult<()> {
+        if self.vredis_pool.is_none() || self.config.read_only_poll_seconds == 0 {
+            debug!("read-only switches are only set locally");
+            return Ok(());
+        }
+
+        let chain_key = chain_redis_key(self.config.chain_id);
+
+        let mut interval = interval(Duration::from_secs(self.config.read_only_poll_seconds));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let mut redis_conn = match self.redis_conn().await {
+                Ok(Some(x)) => x,
+                Ok(None) => return Ok(()),
+                Err(err) => {
+                    // keep the switches we have. failing open here could let transactions through during an incident
+                    warn!("unable to check read-only switches: {:?}", err);
+                    continue;
+                }
+            };
+
+            let (global, chain): (Option<String>, Option<String>) = match redis::pipe()
+                .get(GLOBAL_REDIS_KEY)
+                .get(&chain_key)
+                .query_async(&mut redis_conn)
+                .await
+            {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!("unable to check read-only switches: {:?}", err);
+                    continue;
+                }
+            };
+
+            let new_status = ReadOnlyStatus {
+                global: global.and_then(|x| parse_reason(&x)),
+                chain: chain.and_then(|x| parse_reason(&x)),
+            };
+
+            let mut status = self.read_only.write();
+
+            if *status != new_status {
+                info!("read-only switches are now {:?}", new_status);
+
+                *status = new_status;
+            }
+        }
+    }
+}
+
+fn parse_reason(x: &str) -> Option<ReadOnlyReason> {
+    match serde_json::from_str(x) {
+        Ok(x) => Some(x),
+        Err(err) => {
+            // something was saved, so it was meant to be on
+            warn!("invalid read-only switch in redis: {:?}", err);
+
+            Some(ReadOnlyReason {
+                reason: "emergency maintenance".to_string(),
+                since: Utc::now().timestamp(),
+            })
+        }
+    }
+}
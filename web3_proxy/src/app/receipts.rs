@@ -0,0 +1,176 @@
+//! Serve transaction receipts in bulk, even from backends that do not support `eth_getBlockReceipts`.
+use super::Web3ProxyApp;
+use crate::block_number::block_num_to_U64;
+use crate::frontend::authorization::{Authorization, RequestMetadata};
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::jsonrpc::{JsonRpcId, JsonRpcRequest};
+use crate::response_cache::{JsonRpcResponseCacheKey, JsonRpcResponseData};
+use crate::rpcs::blockchain::ArcBlock;
+use ethers::prelude::{BlockNumber, H256, U64};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use log::trace;
+use serde_json::json;
+use serde_json::value::RawValue;
+use std::sync::Arc;
+
+/// how many `eth_getTransactionReceipt` requests to have in flight at once while emulating `eth_getBlockReceipts`
+/// TODO: put this in the config?
+const EMULATED_RECEIPTS_CONCURRENCY: usize = 10;
+
+impl Web3ProxyApp {
+    /// If the backend rejected `eth_getBlockReceipts` because it does not know the method, build the response ourselves.
+    /// Any other response is returned unchanged.
+    pub(super) async fn emulate_block_receipts_if_needed(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        request: &JsonRpcRequest,
+        response_data: JsonRpcResponseData,
+        head_block_num: U64,
+        request_metadata: &Arc<RequestMetadata>,
+    ) -> Web3ProxyResult<JsonRpcResponseData> {
+        if request.method != "eth_getBlockReceipts" {
+            return Ok(response_data);
+        }
+
+        let method_not_found = match &response_data {
+            JsonRpcResponseData::Error { value, .. } => {
+                value.code == -32601
+                    || value.message.contains("does not exist")
+                    || value.message.contains("not supported")
+            }
+            JsonRpcResponseData::Result { .. } => false,
+        };
+
+        if !method_not_found {
+            return Ok(response_data);
+        }
+
+        trace!("backend does not support eth_getBlockReceipts. emulating it");
+
+        self.emulate_block_receipts(
+            authorization,
+            request.params.as_ref(),
+            head_block_num,
+            request_metadata,
+        )
+        .await
+    }
+
+    /// Build an `eth_getBlockReceipts` response out of an `eth_getTransactionReceipt` for every transaction in the block.
+    async fn emulate_block_receipts(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        params: Option<&serde_json::Value>,
+        head_block_num: U64,
+        request_metadata: &Arc<RequestMetadata>,
+    ) -> Web3ProxyResult<JsonRpcResponseData> {
+        let block_param = params.and_then(|x| x.get(0)).ok_or_else(|| {
+            Web3ProxyError::InvalidRpcParams("eth_getBlockReceipts requires a block".into())
+        })?;
+
+        // the param is either a block hash or a block number/tag
+        let block_hash = match block_param.as_str() {
+            Some(x) if x.len() == 66 => x.parse::<H256>().map_err(|_| {
+                Web3ProxyError::InvalidRpcParams("param 0 could not be read as H256".into())
+            })?,
+            _ => {
+                let block_num: BlockNumber =
+                    serde_json::from_value(block_param.clone()).map_err(|_| {
+                        Web3ProxyError::InvalidRpcParams(
+                            "param 0 could not be read as a block number".into(),
+                        )
+                    })?;
+
+                let (block_num, _) = block_num_to_U64(block_num, head_block_num);
+
+                self.balanced_rpcs
+                    .block_hash(authorization, &block_num)
+                    .await?
+                    .0
+            }
+        };
+
+        let block = self
+            .balanced_rpcs
+            .block(authorization, &block_hash, None)
+            .await?
+            .block;
+
+        let receipts: Vec<Box<RawValue>> = stream::iter(block.transactions.iter().copied())
+            .map(|tx_hash| {
+                self.block_transaction_receipt(authorization, &block, tx_hash, request_metadata)
+            })
+            .buffered(EMULATED_RECEIPTS_CONCURRENCY)
+            .try_collect()
+            .await?;
+
+        let receipts = serde_json::to_value(receipts)?;
+
+        Ok(receipts.into())
+    }
+
+    /// Get a transaction's receipt from the response cache or a server that has the block.
+    async fn block_transaction_receipt(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        block: &ArcBlock,
+        tx_hash: H256,
+        request_metadata: &Arc<RequestMetadata>,
+    ) -> Web3ProxyResult<Box<RawValue>> {
+        let method = "eth_getTransactionReceipt";
+        let params = json!([tx_hash]);
+
+        // keying the cache on the block keeps receipts from reorged blocks from being served
+        let cache_key = JsonRpcResponseCacheKey {
+            from_block: Some(block.clone()),
+            to_block: None,
+            method: method.to_string(),
+            params: Some(params.clone()),
+            cache_errors: false,
+            cache_partition: authorization.checks.cache_partition,
+        };
+
+        // keys that require fresh data skip the cache. they get no guard to insert with
+        let cached = if authorization.checks.bypass_cache {
+            Err(None)
+        } else {
+            self.jsonrpc_response_cache
+                .get_value_or_guard_async(cache_key)
+                .await
+                .map_err(Some)
+        };
+
+        let response_data = match cached {
+            Ok(x) => x,
+            Err(guard) => {
+                let request =
+                    JsonRpcRequest::new(JsonRpcId::Number(1), method.to_string(), Some(params))?;
+
+                let response_data = self
+                    .balanced_rpcs
+                    .try_proxy_connection(
+                        authorization,
+                        &request,
+                        Some(request_metadata),
+                        block.number.as_ref(),
+                        None,
+                    )
+                    .await?;
+
+                if let (Some(guard), JsonRpcResponseData::Result { .. }) = (guard, &response_data) {
+                    guard.insert(response_data.clone());
+                }
+
+                response_data
+            }
+        };
+
+        match response_data {
+            JsonRpcResponseData::Result { value, .. } => Ok(value),
+            JsonRpcResponseData::Error { value, .. } => Err(Web3ProxyError::BadResponse(format!(
+                "unable to get receipt for {:?}: {}",
+                tx_hash, value.message
+            ))),
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! A fake chain for integrating against the proxy in CI without any backends.
+//!
+//! Requests still go through auth, rate limits, and stats. Only the responses are synthetic.
+//! Block `n` is made every `block_seconds` after `genesis_timestamp` and its hash only depends on the chain id and `n`, so every sandbox proxy agrees.
+//! Every address has `faucet_ether`. Transactions are accepted but never mined. Subscriptions are not simulated.
+use super::Web3ProxyApp;
+use crate::config::SandboxConfig;
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::jsonrpc::{JsonRpcErrorData, JsonRpcRequest};
+use crate::response_cache::JsonRpcResponseData;
+use chrono::Utc;
+use ethers::types::{Block, BlockNumber, Bloom, Bytes, TxHash, H256, H64, U256, U64};
+use ethers::utils::keccak256;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::str::FromStr;
+
+/// how far back `eth_getBlockByHash` looks for a matching hash
+const HASH_LOOKBACK: u64 = 1_024;
+
+/// `finalized` and `safe` are this far behind the head
+const FINALIZED_DEPTH: u64 = 32;
+
+const GAS_PRICE_WEI: u64 = 1_000_000_000;
+
+fn block_hash(chain_id: u64, number: u64) -> H256 {
+    H256(keccak256(format!(
+        "web3_proxy sandbox {} {}",
+        chain_id, number
+    )))
+}
+
+/// The newest block at a unix timestamp. Block 0 is at the genesis timestamp
+fn head_block_num(config: &SandboxConfig, now: u64) -> u64 {
+    now.saturating_sub(config.genesis_timestamp) / config.block_seconds.max(1)
+}
+
+fn block(chain_id: u64, config: &SandboxConfig, number: u64) -> Block<TxHash> {
+    Block {
+        hash: Some(block_hash(chain_id, number)),
+        parent_hash: number
+            .checked_sub(1)
+            .map(|x| block_hash(chain_id, x))
+            .unwrap_or_default(),
+        number: Some(U64::from(number)),
+        timestamp: U256::from(config.genesis_timestamp + number * config.block_seconds.max(1)),
+        gas_limit: U256::from(30_000_000),
+        base_fee_per_gas: Some(U256::from(GAS_PRICE_WEI)),
+        logs_bloom: Some(Bloom::zero()),
+        nonce: Some(H64::zero()),
+        mix_hash: Some(H256::zero()),
+        total_difficulty: Some(U256::zero()),
+        size: Some(U256::from(512)),
+        ..Default::default()
+    }
+}
+
+impl Web3ProxyApp {
+    /// The sandbox's head block. None if the sandbox is off
+    pub fn sandbox_head_block_num(&self) -> Option<U64> {
+        let config = &self.config.sandbox;
+
+        config
+            .enabled
+            .then(|| U64::from(head_block_num(config, Utc::now().timestamp() as u64)))
+    }
+
+    /// Answer a request the way a quiet chain would
+    pub(super) fn sandbox_response(
+        &self,
+        request: &JsonRpcRequest,
+    ) -> Web3ProxyResult<JsonRpcResponseData> {
+        let config = &self.config.sandbox;
+        let chain_id = self.config.chain_id;
+
+        let head_num = head_block_num(config, Utc::now().timestamp() as u64);
+
+        let param = |i: usize| request.params.as_ref().and_then(|x| x.get(i));
+
+        let block_json = |number: u64| -> Web3ProxyResult<Value> {
+            if number > head_num {
+                return Ok(Value::Null);
+            }
+
+            Ok(serde_json::to_value(block(chain_id, config, number))?)
+        };
+
+        let x = match request.method.as_str() {
+            "eth_blockNumber" => json!(U64::from(head_num)),
+            "eth_chainId" => json!(U64::from(chain_id)),
+            "net_version" => json!(chain_id.to_string()),
+            "net_listening" => json!(true),
+            "net_peerCount" => json!(U64::zero()),
+            "web3_clientVersion" => {
+                json!(format!("{} (sandbox)", self.web3_client_version()))
+            }
+            "eth_syncing" => json!(false),
+            "eth_getBlockByNumber" => {
+                let tag: BlockNumber = param(0)
+                    .map(|x| serde_json::from_value(x.clone()))
+                    .transpose()
+                    .map_err(|_| {
+                        Web3ProxyError::InvalidParam("params", "invalid block number".into())
+                    })?
+                    .unwrap_or_default();
+
+                let number = match tag {
+                    BlockNumber::Earliest => 0,
+                    BlockNumber::Finalized | BlockNumber::Safe => {
+                        head_num.saturating_sub(FINALIZED_DEPTH)
+                    }
+                    BlockNumber::Latest | BlockNumber::Pending => head_num,
+                    BlockNumber::Number(x) => x.as_u64(),
+                };
+
+                block_json(number)?
+            }
+            "eth_getBlockByHash" => {
+                let hash = param(0)
+                    .and_then(|x| x.as_str())
+                    .and_then(|x| H256::from_str(x).ok())
+                    .ok_or_else(|| Web3ProxyError::InvalidParam("params", "invalid hash".into()))?;
+
+                match (head_num.saturating_sub(HASH_LOOKBACK)..=head_num)
+                    .rev()
+                    .find(|x| block_hash(chain_id, *x) == hash)
+                {
+                    Some(number) => block_json(number)?,
+                    None => Value::Null,
+                }
+            }
+            "eth_getBalance" => json!(U256::from(config.faucet_ether) * U256::exp10(18)),
+            "eth_getTransactionCount" => json!(U64::zero()),
+            "eth_gasPrice" => json!(U256::from(GAS_PRICE_WEI)),
+            "eth_maxPriorityFeePerGas" => json!(U256::zero()),
+            "eth_estimateGas" => json!(U64::from(21_000)),
+            "eth_call" | "eth_getCode" => json!("0x"),
+            "eth_getStorageAt" => json!(H256::zero()),
+            "eth_getLogs" => json!([]),
+            "eth_getTransactionByHash" | "eth_getTransactionReceipt" => Value::Null,
+            "eth_sendRawTransaction" => {
+                let raw_tx = param(0)
+                    .and_then(|x| x.as_str())
+                    .and_then(|x| Bytes::from_str(x).ok())
+                    .ok_or_else(|| {
+                        Web3ProxyError::InvalidParam("params", "invalid raw transaction".into())
+                    })?;
+
+                json!(H256(keccak256(raw_tx)))
+            }
+            _ => {
+                return Ok(JsonRpcErrorData {
+                    message: Cow::Owned(format!(
+                        "{} is not available in the sandbox",
+                        request.method
+                    )),
+                    code: -32601,
+                    data: None,
+                }
+                .into())
+            }
+        };
+
+        Ok(x.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks() {
+        let config = SandboxConfig {
+            enabled: true,
+            block_seconds: 12,
+            genesis_timestamp: 1_000,
+            faucet_ether: 1_000,
+        };
+
+        assert_eq!(head_block_num(&config, 0), 0);
+        assert_eq!(head_block_num(&config, 1_011), 0);
+        assert_eq!(head_block_num(&config, 1_012), 1);
+
+        let first = block(1337, &config, 0);
+        let second = block(1337, &config, 1);
+
+        assert_eq!(first.parent_hash, H256::zero());
+        assert_eq!(second.parent_hash, first.hash.unwrap());
+        assert_eq!(second.timestamp, U256::from(1_012));
+
+        // the same block is always the same
+        assert_eq!(block(1337, &config, 1).hash, second.hash);
+        assert_ne!(block(1, &config, 1).hash, second.hash);
+    }
+}
@@ -0,0 +1,224 @@
+//! Sanctions screening for `eth_sendRawTransaction`.
+//! The sender and recipient are checked against `screening.denylist` and then against `screening.api_url`.
+//!
+//! Depending on `screening.mode`, a match is only saved, saved and alerted on, or rejected.
+//! Every screened transaction (matched or not) gets a row in `tx_screening` for auditors.
+use super::Web3ProxyApp;
+use crate::config::ScreeningMode;
+use crate::frontend::authorization::Authorization;
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::jsonrpc::JsonRpcRequest;
+use anyhow::Context;
+use entities::sea_orm_active_enums::ScreeningDecision;
+use entities::tx_screening;
+use ethers::types::{Address, Bytes, Transaction};
+use ethers::utils::rlp::{Decodable, Rlp};
+use log::{error, trace, warn};
+use migration::sea_orm::{self, ActiveModelTrait};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Serialize)]
+struct ScreeningApiRequest<'a> {
+    chain_id: u64,
+    addresses: &'a [Address],
+}
+
+#[derive(Deserialize)]
+struct ScreeningApiResponse {
+    #[serde(default)]
+    matches: Vec<Address>,
+}
+
+/// The first of the addresses that is on the denylist
+fn denylist_match(denylist: &[Address], addresses: &[Address]) -> Option<Address> {
+    addresses.iter().find(|x| denylist.contains(x)).copied()
+}
+
+impl Web3ProxyApp {
+    /// Screen a raw transaction before it is sent.
+    /// Only errors if the transaction matched and `screening.mode` is "block".
+    /// Transactions that can't be decoded are left for the backends to reject
+    pub(crate) async fn screen_raw_transaction(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        request: &JsonRpcRequest,
+    ) -> Web3ProxyResult<()> {
+        let config = &self.config.screening;
+
+        if config.mode == ScreeningMode::Off {
+            return Ok(());
+        }
+
+        let tx = match request
+            .params
+            .as_ref()
+            .and_then(|x| x.get(0))
+            .and_then(|x| x.as_str())
+            .and_then(|x| Bytes::from_str(x).ok())
+            .and_then(|x| Transaction::decode(&Rlp::new(x.as_ref())).ok())
+        {
+            Some(x) => x,
+            None => {
+                trace!("unable to decode transaction for screening");
+                return Ok(());
+            }
+        };
+
+        let from = tx.recover_from().ok();
+
+        let addresses: Vec<Address> = from.into_iter().chain(tx.to).collect();
+
+        let mut match_source = None;
+
+        let mut matched = denylist_match(&config.denylist, &addresses);
+
+        if matched.is_some() {
+            match_source = Some("denylist");
+        } else if let Some(api_url) = config.api_url.as_ref() {
+            match self.screening_api_match(api_url, &addresses).await {
+                Ok(Some(x)) => {
+                    matched = Some(x);
+                    match_source = Some("api");
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    warn!("screening api failed for {:?}: {:?}", tx.hash, err);
+                    match_source = Some("error");
+                }
+            }
+        }
+
+        let is_match = matched.is_some() || (match_source == Some("error") && config.fail_closed);
+
+        let decision = match (is_match, config.mode) {
+            (false, _) | (_, ScreeningMode::Off) => ScreeningDecision::Allowed,
+            (true, ScreeningMode::Log) => ScreeningDecision::Logged,
+            (true, ScreeningMode::Flag) => ScreeningDecision::Flagged,
+            (true, ScreeningMode::Block) => ScreeningDecision::Blocked,
+        };
+
+        if decision == ScreeningDecision::Flagged {
+            // error logs are sent to sentry
+            error!(
+                "screened transaction flagged! tx={:?} matched={:?} source={:?} key={:?} ip={}",
+                tx.hash,
+                matched,
+                match_source,
+                authorization.checks.rpc_secret_key_id,
+                authorization.ip
+            );
+        }
+
+        // save the audit record in the background so that clean transactions aren't slowed down by the database
+        let record = tx_screening::ActiveModel {
+            chain_id: sea_orm::Set(self.config.chain_id),
+            tx_hash: sea_orm::Set(tx.hash.as_bytes().to_vec()),
+            from_address: sea_orm::Set(from.map(|x| x.as_bytes().to_vec())),
+            to_address: sea_orm::Set(tx.to.map(|x| x.as_bytes().to_vec())),
+            matched_address: sea_orm::Set(matched.map(|x| x.as_bytes().to_vec())),
+            match_source: sea_orm::Set(match_source.map(|x| x.to_string())),
+            decision: sea_orm::Set(decision.clone()),
+            rpc_key_id: sea_orm::Set(authorization.checks.rpc_secret_key_id.map(|x| x.get())),
+            ip: sea_orm::Set(authorization.ip.to_string()),
+            ..Default::default()
+        };
+
+        let app = self.clone();
+        let tx_hash = tx.hash;
+
+        tokio::spawn(async move {
+            if let Err(err) = app.save_tx_screening(record).await {
+                // a missing audit record is a compliance problem. make sure someone sees it
+                error!("failed saving screening of {:?}: {:?}", tx_hash, err);
+            }
+        });
+
+        if decision == ScreeningDecision::Blocked {
+            return Err(Web3ProxyError::TransactionScreened(tx_hash));
+        }
+
+        Ok(())
+    }
+
+    /// Ask the screening api about the addresses. Answers are cached for `screening.api_cache_seconds`
+    async fn screening_api_match(
+        &self,
+        api_url: &str,
+        addresses: &[Address],
+    ) -> anyhow::Result<Option<Address>> {
+        let mut unknown = vec![];
+
+        for address in addresses {
+            match self.screening_cache.get(address) {
+                Some(true) => return Ok(Some(*address)),
+                Some(false) => {}
+                None => unknown.push(*address),
+            }
+        }
+
+        if unknown.is_empty() {
+            return Ok(None);
+        }
+
+        let http_client = self
+            .http_client
+            .as_ref()
+            .context("screening api needs an http client")?;
+
+        let response: ScreeningApiResponse = http_client
+            .post(api_url)
+            .timeout(Duration::from_millis(self.config.screening.api_timeout_ms))
+            .json(&ScreeningApiRequest {
+                chain_id: self.config.chain_id,
+                addresses: &unknown,
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        for address in unknown.iter() {
+            let _ = self
+                .screening_cache
+                .try_insert(*address, response.matches.contains(address));
+        }
+
+        Ok(unknown.into_iter().find(|x| response.matches.contains(x)))
+    }
+
+    async fn save_tx_screening(&self, record: tx_screening::ActiveModel) -> anyhow::Result<()> {
+        let db_conn = match self.db_conn() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        record.save(&db_conn).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_denylist_match() {
+        let sanctioned = Address::from_low_u64_be(1);
+        let sender = Address::from_low_u64_be(2);
+        let recipient = Address::from_low_u64_be(3);
+
+        let denylist = vec![sanctioned];
+
+        assert_eq!(denylist_match(&denylist, &[sender, recipient]), None);
+        assert_eq!(
+            denylist_match(&denylist, &[sender, sanctioned]),
+            Some(sanctioned)
+        );
+        assert_eq!(denylist_match(&[], &[sanctioned]), None);
+    }
+}
@@ -0,0 +1,192 @@
+//! Freeze the proxy's health into a document that support can link users to.
+//! Snapshots are saved under the keccak256 of their json, so a link always shows exactly the state it was taken at.
+use super::Web3ProxyApp;
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
+use crate::rpcs::head_events::HeadEvent;
+use chrono::Utc;
+use entities::status_snapshot;
+use ethers::types::H256;
+use ethers::utils::keccak256;
+use log::info;
+use migration::sea_orm::{self, ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter};
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+
+/// how many anomalies are kept for snapshots. older ones are dropped
+/// TODO: put this in the config?
+const MAX_RECENT_ANOMALIES: usize = 100;
+
+/// Something unusual that support might want to know about when looking at a complaint
+#[derive(Clone, Debug, Serialize)]
+pub struct Anomaly {
+    pub timestamp: i64,
+    pub kind: &'static str,
+    pub description: String,
+}
+
+pub type RecentAnomalies = Mutex<VecDeque<Anomaly>>;
+
+impl Web3ProxyApp {
+    pub fn record_anomaly(&self, kind: &'static str, description: String) {
+        info!("anomaly {}: {}", kind, description);
+
+        let mut recent_anomalies = self.recent_anomalies.lock();
+
+        if recent_anomalies.len() >= MAX_RECENT_ANOMALIES {
+            recent_anomalies.pop_front();
+        }
+
+        recent_anomalies.push_back(Anomaly {
+            timestamp: Utc::now().timestamp(),
+            kind,
+            description,
+        });
+    }
+
+    /// Record reorgs and changes in whether backups are needed
+    pub(super) async fn watch_anomalies(self: Arc<Self>) -> Web3ProxyResult<()> {
+        let mut head_events = self.head_events();
+
+        let mut backups_needed = false;
+
+        loop {
+            match head_events.recv().await {
+                Ok(HeadEvent::Reorg { old, new }) => {
+                    self.record_anomaly(
+                        "reorg",
+                        format!(
+                            "head reorged from {} {:?} to {} {:?}",
+                            old.number(),
+                            old.hash(),
+                            new.number(),
+                            new.hash()
+                        ),
+                    );
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    self.record_anomaly(
+                        "head_events_lagged",
+                        format!("missed {} head events", skipped),
+                    );
+                }
+                Err(RecvError::Closed) => break,
+            }
+
+            let new_backups_needed = self
+                .balanced_rpcs
+                .watch_consensus_rpcs_sender
+                .borrow()
+                .as_ref()
+                .map(|x| x.backups_needed)
+                .unwrap_or_default();
+
+            if new_backups_needed != backups_needed {
+                backups_needed = new_backups_needed;
+
+                if backups_needed {
+                    self.record_anomaly("backups_needed", "backup rpcs are in use".to_string());
+                } else {
+                    self.record_anomaly(
+                        "backups_not_needed",
+                        "backup rpcs are no longer needed".to_string(),
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The current state of the backends. Keys are sorted so the same state always has the same bytes
+    pub fn status_snapshot_document(&self) -> serde_json::Value {
+        let head_block = self
+            .watch_consensus_head_receiver
+            .borrow()
+            .as_ref()
+            .map(|x| json!({"number": x.number(), "hash": x.hash()}));
+
+        let backups_needed = self
+            .balanced_rpcs
+            .watch_consensus_rpcs_sender
+            .borrow()
+            .as_ref()
+            .map(|x| x.backups_needed);
+
+        let recent_anomalies: Vec<_> = self.recent_anomalies.lock().iter().cloned().collect();
+
+        json!({
+            "created_at": Utc::now().timestamp(),
+            "version": super::APP_USER_AGENT,
+            "chain_id": self.config.chain_id,
+            "hostname": self.hostname,
+            "synced": self.balanced_rpcs.synced(),
+            "head_block": head_block,
+            "backups_needed": backups_needed,
+            "balanced_rpcs": self.balanced_rpcs.health_snapshot(),
+            "private_rpcs": self.private_rpcs.as_ref().map(|x| x.health_snapshot()),
+            "pools": self.pool_status(),
+            "recent_anomalies": recent_anomalies,
+        })
+    }
+
+    /// Save the current state. Returns the saved row
+    pub async fn create_status_snapshot(
+        &self,
+        created_by: Option<u64>,
+    ) -> Web3ProxyResult<status_snapshot::Model> {
+        let db_conn = self
+            .db_conn()
+            .web3_context("status snapshots need a database")?;
+
+        let document = self.status_snapshot_document().to_string();
+
+        let content_hash = format!("{:?}", H256::from(keccak256(document.as_bytes())));
+
+        // the same bytes are the same snapshot
+        if let Some(existing) = status_snapshot::Entity::find()
+            .filter(status_snapshot::Column::ContentHash.eq(&content_hash))
+            .one(&db_conn)
+            .await?
+        {
+            return Ok(existing);
+        }
+
+        let snapshot = status_snapshot::ActiveModel {
+            content_hash: sea_orm::Set(content_hash),
+            chain_id: sea_orm::Set(self.config.chain_id),
+            created_by: sea_orm::Set(created_by),
+            document: sea_orm::Set(document),
+            ..Default::default()
+        };
+
+        let snapshot = snapshot
+            .insert(&db_conn)
+            .await
+            .web3_context("saving status snapshot")?;
+
+        Ok(snapshot)
+    }
+
+    /// Load a snapshot by its content hash
+    pub async fn status_snapshot(
+        &self,
+        content_hash: &str,
+    ) -> Web3ProxyResult<status_snapshot::Model> {
+        let db_replica = self
+            .db_replica()
+            .web3_context("status snapshots need a database")?;
+
+        let snapshot = status_snapshot::Entity::find()
+            .filter(status_snapshot::Column::ContentHash.eq(content_hash.to_lowercase()))
+            .one(db_replica.conn())
+            .await?
+            .ok_or(Web3ProxyError::NotFound)?;
+
+        Ok(snapshot)
+    }
+}
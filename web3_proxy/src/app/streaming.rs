@@ -0,0 +1,195 @@
+//! Stream large responses (like `eth_getLogs` over a wide range) from http backends straight to http clients.
+//! The normal path holds the whole response in memory at least twice. Streaming holds one chunk at a time.
+//!
+//! Only single requests for methods in `stream_response_methods` are streamed. Batches use the normal path.
+//! Websockets only stream after `w3p_streamChunks`. Then the body is sent as `w3p_chunk` notifications.
+use super::{method_matches, Web3ProxyApp};
+use crate::block_number::{block_needed, BlockNeeded};
+use crate::frontend::authorization::{
+    Authorization, RequestMetadata, RequestOrMethod, ResponseOrBytes,
+};
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::jsonrpc::{JsonRpcForwardedResponse, JsonRpcRequest};
+use crate::rpcs::one::Web3Rpc;
+use crate::rpcs::stream::StreamingResponse;
+use ethers::types::U64;
+use http::StatusCode;
+use std::sync::atomic;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+
+pub enum StreamedResponse {
+    Stream(StreamingResponse),
+    /// something went wrong before the backend started sending a body
+    Error(StatusCode, JsonRpcForwardedResponse),
+}
+
+impl Web3ProxyApp {
+    /// Returns None if the request should use the normal path.
+    /// That is decided before any rate limits are checked so that nothing is counted twice
+    pub async fn proxy_web3_rpc_streaming(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        request: &mut JsonRpcRequest,
+    ) -> Option<(StreamedResponse, Vec<Arc<Web3Rpc>>)> {
+        if request.notification
+            || !self
+                .config
+                .stream_response_methods
+                .iter()
+                .any(|x| method_matches(x, &request.method))
+            || !self.balanced_rpcs.can_stream()
+        {
+            return None;
+        }
+
+        let request_metadata = RequestMetadata::new(
+            self,
+            authorization.clone(),
+            RequestOrMethod::Request(request),
+            None,
+        )
+        .await;
+
+        let response = match self
+            ._proxy_web3_rpc_streaming(authorization, request, &request_metadata)
+            .await
+        {
+            Ok(x) => StreamedResponse::Stream(x),
+            Err(err) => {
+                let (status_code, response_data) = err.into_response_parts();
+
+                let response =
+                    JsonRpcForwardedResponse::from_response_data(response_data, Default::default())
+                        .normalize(request);
+
+                request_metadata.add_response(ResponseOrBytes::Response(&response));
+
+                StreamedResponse::Error(status_code, response)
+            }
+        };
+
+        let rpcs = request_metadata.backend_rpcs_used();
+
+        Some((response, rpcs))
+    }
+
+    async fn _proxy_web3_rpc_streaming(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        request: &mut JsonRpcRequest,
+        request_metadata: &Arc<RequestMetadata>,
+    ) -> Web3ProxyResult<StreamingResponse> {
+        // held until the body is done
+        let permit = self.request_pool.acquire(authorization).await?;
+
+        // the same classifier, read only, and method rate limit checks as the normal path
+        self.check_request_policy(authorization, request, request_metadata)
+            .await?;
+
+        let method = request.method.clone();
+
+        if method.starts_with("admin_") {
+            return Err(Web3ProxyError::AccessDenied);
+        }
+
+        let head_block_num = self
+            .balanced_rpcs
+            .head_block_num()
+            .ok_or(Web3ProxyError::NoServersSynced)?;
+
+        // this might modify the request params
+        let (min_block_needed, max_block_needed): (Option<U64>, Option<U64>) = match block_needed(
+            authorization,
+            &method,
+            request.params.as_mut(),
+            head_block_num,
+            &self.balanced_rpcs,
+        )
+        .await?
+        {
+            BlockNeeded::CacheSuccessForever | BlockNeeded::CacheNever => (None, None),
+            BlockNeeded::Cache { block_num, .. } => (Some(block_num), None),
+            BlockNeeded::CacheRange {
+                from_block_num,
+                to_block_num,
+                ..
+            } => (Some(from_block_num), Some(to_block_num)),
+        };
+
+        // same as the normal path so that streamed requests are billed the same
+        if let Some(min_block_needed) = min_block_needed {
+            let block_depth = head_block_num.saturating_sub(min_block_needed).as_u64();
+
+            if block_depth < self.config.archive_depth {
+                request_metadata
+                    .archive_request
+                    .store(true, atomic::Ordering::Release);
+            }
+        }
+
+        // set by check_request_policy
+        let max_bytes = request_metadata.max_response_bytes.get().copied();
+
+        // TODO: different timeouts for different user tiers
+        // this only covers the time until the backend responds. the body has its own timeouts
+        let duration = Duration::from_secs(240);
+
+        let mut response = timeout(
+            duration,
+            self.balanced_rpcs.try_stream_best_connection(
+                authorization,
+                request,
+                request_metadata,
+                min_block_needed.as_ref(),
+                max_block_needed.as_ref(),
+                max_bytes,
+            ),
+        )
+        .await??;
+
+        response.hold(permit);
+
+        response.set_timeouts(
+            Duration::from_secs(self.config.stream_idle_timeout_seconds),
+            Duration::from_secs(self.config.stream_timeout_seconds),
+        );
+
+        Ok(response)
+    }
+}
+
+/// Take the longest valid UTF-8 prefix out of `buf`. A character split across two chunks stays in `buf` until the rest of it arrives
+pub fn take_utf8(buf: &mut Vec<u8>) -> String {
+    let valid = match std::str::from_utf8(buf) {
+        Ok(_) => buf.len(),
+        Err(err) => err.valid_up_to(),
+    };
+
+    let rest = buf.split_off(valid);
+
+    String::from_utf8(std::mem::replace(buf, rest)).expect("only valid utf-8 was taken")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::take_utf8;
+
+    #[test]
+    fn test_take_utf8() {
+        let snowman = "☃".as_bytes();
+
+        let mut buf = b"{\"result\":\"".to_vec();
+        buf.extend_from_slice(&snowman[..1]);
+
+        assert_eq!(take_utf8(&mut buf), "{\"result\":\"");
+        assert_eq!(buf, &snowman[..1]);
+
+        buf.extend_from_slice(&snowman[1..]);
+        buf.extend_from_slice(b"\"}");
+
+        assert_eq!(take_utf8(&mut buf), "☃\"}");
+        assert!(buf.is_empty());
+    }
+}
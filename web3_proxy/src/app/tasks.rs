@@ -0,0 +1,686 @@
+//! Background tasks are started in the order of their dependencies and stopped in reverse, so a task never runs without the tasks it needs.
+//! A task only starts once every task it depends on is ready. Most tasks are ready as soon as they start. Tasks added with `add_with_ready` say when.
+//! A task that panics can be restarted, but only `task_max_restarts` times in `task_restart_window_seconds`. After that it fails and the app exits.
+//! `GET /admin/tasks` shows every task's status.
+use super::{Web3ProxyApp, Web3ProxyJoinHandle};
+use crate::frontend::errors::Web3ProxyResult;
+use chrono::Utc;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use log::{info, warn};
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinError;
+use tokio::time::{sleep, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    Never,
+    /// panics restart the task. errors and normal exits do not
+    OnPanic,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    /// not started yet
+    Pending,
+    Running,
+    /// panicked and waiting to be started again
+    Restarting,
+    /// returned without an error. some tasks (like warmup) are supposed to
+    Exited,
+    /// returned an error, or panicked more often than it can be restarted
+    Failed,
+    /// stopped during shutdown
+    Stopped,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskStatus {
+    pub name: String,
+    pub depends_on: Vec<String>,
+    pub restart: RestartPolicy,
+    pub state: TaskState,
+    /// restarts since the app started
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    /// unix timestamp of the last start
+    pub started_at: Option<i64>,
+    /// tasks that depend on this one start once it is ready
+    pub ready: bool,
+}
+
+/// Given to tasks added with `add_with_ready`. Tasks that depend on this one start after `ready` is called
+#[derive(Clone)]
+pub struct TaskReady {
+    sender: Arc<watch::Sender<bool>>,
+    status: Arc<RwLock<TaskStatus>>,
+}
+
+impl TaskReady {
+    /// Calling this again (like after a restart) does nothing
+    pub fn ready(&self) {
+        if !self.sender.send_replace(true) {
+            let mut status = self.status.write();
+
+            status.ready = true;
+
+            info!("task {} is ready", status.name);
+        }
+    }
+}
+
+type TaskFn =
+    Box<dyn FnMut(Arc<Web3ProxyApp>, TaskReady) -> BoxFuture<'static, Web3ProxyResult<()>> + Send>;
+
+enum TaskRun {
+    /// started by the supervisor. restarted if its policy allows
+    Spawn(TaskFn),
+    /// spawned before the app existed, like the backends' head watchers. never restarted
+    Handle(Web3ProxyJoinHandle<()>),
+}
+
+struct Task {
+    status: Arc<RwLock<TaskStatus>>,
+    run: TaskRun,
+    /// tasks that save things before exiting get this signal and are waited for. the rest are aborted
+    shutdown: Option<broadcast::Sender<()>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct RestartLimits {
+    max_restarts: u32,
+    window: Duration,
+}
+
+/// Starts and stops the app's background tasks. See the module docs
+#[derive(Default)]
+pub struct TaskSupervisor {
+    /// in the order they were added
+    pending: Mutex<Vec<Task>>,
+    /// in start order. sending `true` stops the task. the sender closes once the task is done. None once `stop` has it
+    started: Mutex<Vec<(Arc<RwLock<TaskStatus>>, Option<watch::Sender<bool>>)>>,
+}
+
+impl TaskSupervisor {
+    fn push(
+        &self,
+        name: String,
+        depends_on: &[&str],
+        restart: RestartPolicy,
+        run: TaskRun,
+        shutdown: Option<broadcast::Sender<()>>,
+    ) {
+        let status = TaskStatus {
+            name,
+            depends_on: depends_on.iter().map(|x| x.to_string()).collect(),
+            restart,
+            state: TaskState::Pending,
+            restarts: 0,
+            last_error: None,
+            started_at: None,
+            ready: false,
+        };
+
+        self.pending.lock().push(Task {
+            status: Arc::new(RwLock::new(status)),
+            run,
+            shutdown,
+        });
+    }
+
+    /// Add a task for `start` to spawn. `f` is called again for every restart.
+    /// The task is ready as soon as it starts
+    pub fn add<F>(&self, name: &str, depends_on: &[&str], restart: RestartPolicy, mut f: F)
+    where
+        F: FnMut(Arc<Web3ProxyApp>) -> BoxFuture<'static, Web3ProxyResult<()>> + Send + 'static,
+    {
+        self.add_with_ready(name, depends_on, restart, move |app, ready| {
+            ready.ready();
+
+            f(app)
+        });
+    }
+
+    /// Like `add`, but tasks that depend on this one wait until it calls `TaskReady::ready`
+    pub fn add_with_ready<F>(&self, name: &str, depends_on: &[&str], restart: RestartPolicy, f: F)
+    where
+        F: FnMut(Arc<Web3ProxyApp>, TaskReady) -> BoxFuture<'static, Web3ProxyResult<()>>
+            + Send
+            + 'static,
+    {
+        self.push(
+            name.to_string(),
+            depends_on,
+            restart,
+            TaskRun::Spawn(Box::new(f)),
+            None,
+        );
+    }
+
+    /// Supervise a task that is already running. It can't be restarted and it is ready right away.
+    /// If `shutdown` is set, stopping sends on it and waits for the task to finish instead of aborting it
+    pub fn adopt(
+        &self,
+        name: String,
+        depends_on: &[&str],
+        handle: Web3ProxyJoinHandle<()>,
+        shutdown: Option<broadcast::Sender<()>>,
+    ) {
+        self.push(
+            name,
+            depends_on,
+            RestartPolicy::Never,
+            TaskRun::Handle(handle),
+            shutdown,
+        );
+    }
+
+    /// Start every pending task in dependency order. Each waits for its dependencies to be ready before it is spawned.
+    /// The supervising handles are pushed to `handles`. They only error if their task failed
+    pub(super) fn start(
+        &self,
+        app: &Arc<Web3ProxyApp>,
+        handles: &FuturesUnordered<Web3ProxyJoinHandle<()>>,
+    ) -> anyhow::Result<()> {
+        let pending = std::mem::take(&mut *self.pending.lock());
+
+        let deps: Vec<_> = pending
+            .iter()
+            .map(|x| {
+                let x = x.status.read();
+                (x.name.clone(), x.depends_on.clone())
+            })
+            .collect();
+
+        let order = start_order(&deps)?;
+
+        let limits = RestartLimits {
+            max_restarts: app.config.task_max_restarts,
+            window: Duration::from_secs(app.config.task_restart_window_seconds),
+        };
+
+        let (ready_senders, ready_receivers): (Vec<_>, Vec<_>) =
+            deps.iter().map(|_| watch::channel(false)).unzip();
+
+        let mut ready_senders: Vec<_> = ready_senders.into_iter().map(Some).collect();
+
+        let mut tasks: Vec<_> = pending.into_iter().map(Some).collect();
+
+        let mut started = self.started.lock();
+
+        for i in order {
+            let task = tasks[i].take().expect("start_order never repeats a task");
+
+            let ready = TaskReady {
+                sender: Arc::new(ready_senders[i].take().expect("one sender per task")),
+                status: task.status.clone(),
+            };
+
+            // start_order already checked that every dependency exists
+            let wait_for: Vec<_> = deps[i]
+                .1
+                .iter()
+                .filter_map(|dep| {
+                    let j = deps.iter().position(|(x, _)| x == dep)?;
+
+                    Some((dep.clone(), ready_receivers[j].clone()))
+                })
+                .collect();
+
+            let (stop_sender, stop_receiver) = watch::channel(false);
+
+            started.push((task.status.clone(), Some(stop_sender)));
+
+            handles.push(tokio::spawn(supervise(
+                app.clone(),
+                task,
+                ready,
+                wait_for,
+                stop_receiver,
+                limits,
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Stop every task in reverse start order. Each task is done before the tasks it depends on are stopped
+    pub async fn stop(&self) {
+        let stops: Vec<_> = self
+            .started
+            .lock()
+            .iter_mut()
+            .rev()
+            .filter_map(|(status, x)| Some((status.clone(), x.take()?)))
+            .collect();
+
+        for (status, stop_sender) in stops {
+            let name = status.read().name.clone();
+
+            // the task might already be done. that is fine
+            let _ = stop_sender.send(true);
+
+            stop_sender.closed().await;
+
+            info!("stopped {}", name);
+        }
+    }
+
+    /// Every task. Started tasks are in start order
+    pub fn statuses(&self) -> Vec<TaskStatus> {
+        let started = self.started.lock();
+        let pending = self.pending.lock();
+
+        started
+            .iter()
+            .map(|(x, _)| x.read().clone())
+            .chain(pending.iter().map(|x| x.status.read().clone()))
+            .collect()
+    }
+}
+
+/// The order to start tasks in. A task's dependencies are always before it.
+/// Ties keep the order the tasks were added in, so tasks added first are stopped last
+fn start_order(tasks: &[(String, Vec<String>)]) -> anyhow::Result<Vec<usize>> {
+    for (name, depends_on) in tasks.iter() {
+        for dep in depends_on.iter() {
+            if !tasks.iter().any(|(x, _)| x == dep) {
+                return Err(anyhow::anyhow!(
+                    "task {} depends on unknown task {}",
+                    name,
+                    dep
+                ));
+            }
+        }
+    }
+
+    let mut order: Vec<usize> = Vec::with_capacity(tasks.len());
+
+    while order.len() < tasks.len() {
+        let next = tasks.iter().enumerate().position(|(i, (_, depends_on))| {
+            !order.contains(&i)
+                && depends_on
+                    .iter()
+                    .all(|dep| order.iter().any(|j| &tasks[*j].0 == dep))
+        });
+
+        match next {
+            Some(i) => order.push(i),
+            None => {
+                let stuck: Vec<_> = tasks
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| !order.contains(i))
+                    .map(|(_, (name, _))| name.as_str())
+                    .collect();
+
+                return Err(anyhow::anyhow!("tasks depend on each other: {:?}", stuck));
+            }
+        }
+    }
+
+    Ok(order)
+}
+
+/// Forget restarts older than the window. True if there is room for one more
+fn allow_restart(restarts: &mut VecDeque<Instant>, now: Instant, limits: RestartLimits) -> bool {
+    while let Some(x) = restarts.front() {
+        if now.duration_since(*x) < limits.window {
+            break;
+        }
+
+        restarts.pop_front();
+    }
+
+    (restarts.len() as u32) < limits.max_restarts
+}
+
+fn panic_message(err: JoinError) -> String {
+    match err.try_into_panic() {
+        Ok(payload) => {
+            if let Some(x) = payload.downcast_ref::<&str>() {
+                x.to_string()
+            } else if let Some(x) = payload.downcast_ref::<String>() {
+                x.clone()
+            } else {
+                "panicked".to_string()
+            }
+        }
+        Err(err) => err.to_string(),
+    }
+}
+
+/// Run one task until it exits, fails, or is stopped. It isn't started until every task in `wait_for` is ready
+async fn supervise(
+    app: Arc<Web3ProxyApp>,
+    task: Task,
+    ready: TaskReady,
+    wait_for: Vec<(String, watch::Receiver<bool>)>,
+    mut stop: watch::Receiver<bool>,
+    limits: RestartLimits,
+) -> Web3ProxyResult<()> {
+    let Task {
+        status,
+        run,
+        shutdown,
+    } = task;
+
+    let restart = status.read().restart;
+    let name = status.read().name.clone();
+
+    for (dep, mut dep_ready) in wait_for {
+        tokio::select! {
+            x = dep_ready.wait_for(|x| *x) => {
+                if x.is_err() {
+                    let message = format!("{} stopped before it was ready", dep);
+
+                    let mut status = status.write();
+                    status.state = TaskState::Failed;
+                    status.last_error = Some(message.clone());
+
+                    return Err(anyhow::anyhow!("task {} failed: {}", name, message).into());
+                }
+            }
+            _ = stop.changed() => {
+                status.write().state = TaskState::Stopped;
+
+                return Ok(());
+            }
+        }
+    }
+
+    let (mut f, mut handle) = match run {
+        TaskRun::Spawn(mut f) => {
+            let handle = tokio::spawn(f(app.clone(), ready.clone()));
+
+            (Some(f), handle)
+        }
+        TaskRun::Handle(handle) => {
+            ready.ready();
+
+            (None, handle)
+        }
+    };
+
+    let mut recent_restarts = VecDeque::new();
+
+    loop {
+        {
+            let mut status = status.write();
+            status.state = TaskState::Running;
+            status.started_at = Some(Utc::now().timestamp());
+        }
+
+        let result = tokio::select! {
+            x = &mut handle => x,
+            _ = stop.changed() => {
+                match shutdown.as_ref() {
+                    Some(shutdown) => {
+                        // no receivers means the task is already exiting
+                        let _ = shutdown.send(());
+                    }
+                    None => handle.abort(),
+                }
+
+                let x = (&mut handle).await;
+
+                let mut status = status.write();
+
+                return match x {
+                    Ok(Err(err)) => {
+                        status.state = TaskState::Failed;
+                        status.last_error = Some(format!("{:?}", err));
+
+                        Err(err)
+                    }
+                    Err(err) if err.is_panic() => {
+                        let message = panic_message(err);
+
+                        status.state = TaskState::Failed;
+                        status.last_error = Some(message.clone());
+
+                        Err(anyhow::anyhow!("task {} failed: {}", name, message).into())
+                    }
+                    // aborted or finished
+                    _ => {
+                        status.state = TaskState::Stopped;
+
+                        Ok(())
+                    }
+                };
+            }
+        };
+
+        match result {
+            Ok(Ok(())) => {
+                info!("task {} exited", name);
+
+                status.write().state = TaskState::Exited;
+
+                return Ok(());
+            }
+            Ok(Err(err)) => {
+                let mut status = status.write();
+                status.state = TaskState::Failed;
+                status.last_error = Some(format!("{:?}", err));
+
+                return Err(err);
+            }
+            Err(err) => {
+                let is_panic = err.is_panic();
+                let message = panic_message(err);
+
+                let restartable = is_panic && restart == RestartPolicy::OnPanic && f.is_some();
+
+                if !restartable || !allow_restart(&mut recent_restarts, Instant::now(), limits) {
+                    let mut status = status.write();
+                    status.state = TaskState::Failed;
+                    status.last_error = Some(message.clone());
+
+                    return Err(anyhow::anyhow!("task {} failed: {}", name, message).into());
+                }
+
+                recent_restarts.push_back(Instant::now());
+
+                // 1, 2, 4, ... seconds. capped so a task that recovers isn't gone for long
+                let delay = Duration::from_secs(1 << (recent_restarts.len() - 1).min(5));
+
+                warn!(
+                    "task {} panicked: {}. restarting in {:?}",
+                    name, message, delay
+                );
+
+                {
+                    let mut status = status.write();
+                    status.state = TaskState::Restarting;
+                    status.restarts += 1;
+                    status.last_error = Some(message);
+                }
+
+                tokio::select! {
+                    _ = sleep(delay) => {}
+                    _ = stop.changed() => {
+                        status.write().state = TaskState::Stopped;
+
+                        return Ok(());
+                    }
+                }
+
+                let f = f.as_mut().expect("only spawned tasks restart");
+
+                handle = tokio::spawn(f(app.clone(), ready.clone()));
+            }
+        }
+    }
+}
+
+impl Web3ProxyApp {
+    /// True if no task has failed
+    pub fn tasks_healthy(&self) -> bool {
+        self.tasks
+            .statuses()
+            .iter()
+            .all(|x| x.state != TaskState::Failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::spawn_test_app;
+    use futures::FutureExt;
+
+    fn state(tasks: &TaskSupervisor, name: &str) -> (TaskState, bool) {
+        let x = tasks
+            .statuses()
+            .into_iter()
+            .find(|x| x.name == name)
+            .unwrap();
+
+        (x.state, x.ready)
+    }
+
+    #[tokio::test]
+    async fn test_dependents_wait_for_ready() {
+        let app = spawn_test_app().await;
+
+        let tasks = TaskSupervisor::default();
+
+        let started = Arc::new(Mutex::new(vec![]));
+        let (go_sender, go_receiver) = watch::channel(false);
+
+        {
+            let started = started.clone();
+
+            tasks.add_with_ready("db", &[], RestartPolicy::Never, move |_, ready| {
+                let started = started.clone();
+                let mut go_receiver = go_receiver.clone();
+
+                async move {
+                    started.lock().push("db");
+
+                    go_receiver.wait_for(|x| *x).await.unwrap();
+
+                    ready.ready();
+
+                    futures::future::pending::<()>().await;
+
+                    Ok(())
+                }
+                .boxed()
+            });
+        }
+
+        {
+            let started = started.clone();
+
+            tasks.add("cache", &["db"], RestartPolicy::Never, move |_| {
+                let started = started.clone();
+
+                async move {
+                    started.lock().push("cache");
+
+                    futures::future::pending::<()>().await;
+
+                    Ok(())
+                }
+                .boxed()
+            });
+        }
+
+        let handles = FuturesUnordered::new();
+
+        tasks.start(&app, &handles).unwrap();
+
+        sleep(Duration::from_millis(100)).await;
+
+        // db is running, but it isn't ready. cache has to wait
+        assert_eq!(*started.lock(), vec!["db"]);
+        assert_eq!(state(&tasks, "db"), (TaskState::Running, false));
+        assert_eq!(state(&tasks, "cache"), (TaskState::Pending, false));
+
+        go_sender.send_replace(true);
+
+        sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(*started.lock(), vec!["db", "cache"]);
+        assert_eq!(state(&tasks, "db"), (TaskState::Running, true));
+        assert_eq!(state(&tasks, "cache"), (TaskState::Running, true));
+
+        tasks.stop().await;
+
+        assert_eq!(state(&tasks, "db").0, TaskState::Stopped);
+        assert_eq!(state(&tasks, "cache").0, TaskState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn test_dependency_exits_before_ready() {
+        let app = spawn_test_app().await;
+
+        let tasks = TaskSupervisor::default();
+
+        tasks.add_with_ready("db", &[], RestartPolicy::Never, |_, _| {
+            async move { Ok(()) }.boxed()
+        });
+
+        tasks.add("cache", &["db"], RestartPolicy::Never, |_| {
+            async move { Ok(()) }.boxed()
+        });
+
+        let handles = FuturesUnordered::new();
+
+        tasks.start(&app, &handles).unwrap();
+
+        sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(state(&tasks, "db"), (TaskState::Exited, false));
+        assert_eq!(state(&tasks, "cache"), (TaskState::Failed, false));
+    }
+
+    #[test]
+    fn test_start_order() {
+        let task = |name: &str, deps: &[&str]| {
+            (
+                name.to_string(),
+                deps.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+            )
+        };
+
+        let tasks = vec![
+            task("stat buffer", &[]),
+            task("warmup", &["config"]),
+            task("config", &["balanced rpcs"]),
+            task("balanced rpcs", &[]),
+            task("pruning", &[]),
+        ];
+
+        // dependencies first. otherwise the order they were added
+        assert_eq!(start_order(&tasks).unwrap(), vec![0, 3, 2, 1, 4]);
+
+        assert!(start_order(&[task("a", &["b"])]).is_err());
+        assert!(start_order(&[task("a", &["b"]), task("b", &["a"])]).is_err());
+
+        let limits = RestartLimits {
+            max_restarts: 2,
+            window: Duration::from_secs(60),
+        };
+
+        let now = Instant::now();
+        let mut restarts = VecDeque::from(vec![now, now]);
+
+        assert!(!allow_restart(&mut restarts, now, limits));
+        assert!(allow_restart(
+            &mut restarts,
+            now + Duration::from_secs(61),
+            limits
+        ));
+        assert!(restarts.is_empty());
+    }
+}
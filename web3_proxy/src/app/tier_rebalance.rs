@@ -0,0 +1,366 @@
+//! Move users between tiers in bulk after pricing changes.
+//! Every user that moves gets a `user_tier_change` row so that the whole rebalance can be rolled back.
+//! Limits come from the user's tier, so the auth caches for those users are cleared and their next request gets the new limits.
+use super::Web3ProxyApp;
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use anyhow::Context;
+use chrono::Utc;
+use entities::{user, user_tier, user_tier_change};
+use ethers::types::Address;
+use hashbrown::{HashMap, HashSet};
+use log::{info, warn};
+use migration::sea_orm::{self, ColumnTrait, EntityTrait, QueryFilter, TransactionTrait};
+use migration::Expr;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use ulid::Ulid;
+
+/// The JSON body of `POST /admin/tiers/rebalance`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TierRebalance {
+    /// old tier title -> new tier title. a user only moves once even if their new tier is also a key here
+    pub tier_map: BTreeMap<String, String>,
+    /// only show who would move
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// The JSON body of `POST /admin/tiers/rebalance/:rebalance_id/rollback`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TierRebalanceRollback {
+    /// only show who would move back
+    pub dry_run: bool,
+}
+
+/// One old tier and the new tier its users move to. Both tiers' limits are included so the change can be reviewed
+#[derive(Debug, Serialize)]
+pub struct TierMapping {
+    pub from: user_tier::Model,
+    pub to: user_tier::Model,
+    pub users: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct UserTierMove {
+    pub user_id: u64,
+    pub address: Address,
+    pub old_tier: String,
+    pub new_tier: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TierRebalanceResult {
+    pub rebalance_id: String,
+    pub dry_run: bool,
+    pub rolled_back: bool,
+    pub mappings: Vec<TierMapping>,
+    pub users: Vec<UserTierMove>,
+    /// only for rollbacks. users that changed tiers again after the rebalance are left alone
+    pub skipped: usize,
+}
+
+/// What is POSTed to `tier_change_webhook_url`
+#[derive(Debug, Serialize)]
+struct TierChangeNotice<'a> {
+    chain_id: u64,
+    rebalance_id: &'a str,
+    rolled_back: bool,
+    users: &'a [UserTierMove],
+}
+
+impl Web3ProxyApp {
+    /// Move every user on a tier in `tier_map` to its new tier
+    pub async fn rebalance_tiers(
+        &self,
+        rebalance: &TierRebalance,
+    ) -> Web3ProxyResult<TierRebalanceResult> {
+        if rebalance.tier_map.is_empty() {
+            return Err(Web3ProxyError::InvalidParam(
+                "tier_map",
+                "at least one tier is required".into(),
+            ));
+        }
+
+        let db_conn = self.db_conn().context("rebalancing tiers needs a db")?;
+
+        let tiers: HashMap<String, user_tier::Model> = user_tier::Entity::find()
+            .all(&db_conn)
+            .await?
+            .into_iter()
+            .map(|x| (x.title.clone(), x))
+            .collect();
+
+        let tier = |title: &str| {
+            tiers.get(title).cloned().ok_or_else(|| {
+                Web3ProxyError::InvalidParam("tier_map", format!("unknown tier {}", title).into())
+            })
+        };
+
+        let mut mappings = vec![];
+        for (from, to) in rebalance.tier_map.iter() {
+            if from == to {
+                return Err(Web3ProxyError::InvalidParam(
+                    "tier_map",
+                    format!("{} is mapped to itself", from).into(),
+                ));
+            }
+
+            mappings.push(TierMapping {
+                from: tier(from)?,
+                to: tier(to)?,
+                users: 0,
+            });
+        }
+
+        let users = user::Entity::find()
+            .filter(user::Column::UserTierId.is_in(mappings.iter().map(|x| x.from.id)))
+            .all(&db_conn)
+            .await?;
+
+        let mut moves = vec![];
+        let mut changes = vec![];
+        for user in users {
+            let mapping = mappings
+                .iter_mut()
+                .find(|x| x.from.id == user.user_tier_id)
+                .expect("users were only loaded for these tiers");
+
+            mapping.users += 1;
+
+            moves.push(UserTierMove {
+                user_id: user.id,
+                address: Address::from_slice(&user.address),
+                old_tier: mapping.from.title.clone(),
+                new_tier: mapping.to.title.clone(),
+            });
+
+            changes.push((user.id, mapping.from.id, mapping.to.id));
+        }
+
+        let result = TierRebalanceResult {
+            rebalance_id: Ulid::new().to_string(),
+            dry_run: rebalance.dry_run,
+            rolled_back: false,
+            mappings,
+            users: moves,
+            skipped: 0,
+        };
+
+        if rebalance.dry_run || changes.is_empty() {
+            return Ok(result);
+        }
+
+        let txn = db_conn.begin().await?;
+
+        for mapping in result.mappings.iter() {
+            let user_ids = changes
+                .iter()
+                .filter(|(_, from, _)| *from == mapping.from.id)
+                .map(|(user_id, _, _)| *user_id);
+
+            // users that changed tiers since they were loaded are not moved
+            user::Entity::update_many()
+                .col_expr(user::Column::UserTierId, Expr::value(mapping.to.id))
+                .filter(user::Column::Id.is_in(user_ids))
+                .filter(user::Column::UserTierId.eq(mapping.from.id))
+                .exec(&txn)
+                .await?;
+        }
+
+        user_tier_change::Entity::insert_many(changes.iter().map(|(user_id, from, to)| {
+            user_tier_change::ActiveModel {
+                rebalance_id: sea_orm::Set(result.rebalance_id.clone()),
+                user_id: sea_orm::Set(*user_id),
+                old_tier_id: sea_orm::Set(*from),
+                new_tier_id: sea_orm::Set(*to),
+                ..Default::default()
+            }
+        }))
+        .exec(&txn)
+        .await?;
+
+        txn.commit().await?;
+
+        info!(
+            "tier rebalance {} moved {} users",
+            result.rebalance_id,
+            result.users.len()
+        );
+
+        self.after_tier_changes(&result).await;
+
+        Ok(result)
+    }
+
+    /// Move the users from a rebalance back to their old tiers
+    pub async fn rollback_tier_rebalance(
+        &self,
+        rebalance_id: &str,
+        dry_run: bool,
+    ) -> Web3ProxyResult<TierRebalanceResult> {
+        let db_conn = self.db_conn().context("rebalancing tiers needs a db")?;
+
+        let changes = user_tier_change::Entity::find()
+            .filter(user_tier_change::Column::RebalanceId.eq(rebalance_id))
+            .filter(user_tier_change::Column::RolledBackAt.is_null())
+            .all(&db_conn)
+            .await?;
+
+        if changes.is_empty() {
+            return Err(Web3ProxyError::NotFound);
+        }
+
+        let tiers: HashMap<u64, user_tier::Model> = user_tier::Entity::find()
+            .all(&db_conn)
+            .await?
+            .into_iter()
+            .map(|x| (x.id, x))
+            .collect();
+
+        let users: HashMap<u64, user::Model> = user::Entity::find()
+            .filter(user::Column::Id.is_in(changes.iter().map(|x| x.user_id)))
+            .all(&db_conn)
+            .await?
+            .into_iter()
+            .map(|x| (x.id, x))
+            .collect();
+
+        let mut mappings: Vec<TierMapping> = vec![];
+        let mut moves = vec![];
+        let mut to_roll_back = vec![];
+        let mut skipped = 0;
+
+        for change in changes {
+            let user = match users.get(&change.user_id) {
+                Some(x) if x.user_tier_id == change.new_tier_id => x,
+                _ => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+
+            let (from, to) = match (
+                tiers.get(&change.new_tier_id),
+                tiers.get(&change.old_tier_id),
+            ) {
+                (Some(from), Some(to)) => (from, to),
+                _ => {
+                    warn!("tier for {:?} no longer exists", change);
+                    skipped += 1;
+                    continue;
+                }
+            };
+
+            match mappings
+                .iter_mut()
+                .find(|x| x.from.id == from.id && x.to.id == to.id)
+            {
+                Some(x) => x.users += 1,
+                None => mappings.push(TierMapping {
+                    from: from.clone(),
+                    to: to.clone(),
+                    users: 1,
+                }),
+            }
+
+            moves.push(UserTierMove {
+                user_id: user.id,
+                address: Address::from_slice(&user.address),
+                old_tier: from.title.clone(),
+                new_tier: to.title.clone(),
+            });
+
+            to_roll_back.push(change);
+        }
+
+        let result = TierRebalanceResult {
+            rebalance_id: rebalance_id.to_string(),
+            dry_run,
+            rolled_back: true,
+            mappings,
+            users: moves,
+            skipped,
+        };
+
+        if dry_run {
+            return Ok(result);
+        }
+
+        let txn = db_conn.begin().await?;
+
+        for change in to_roll_back.iter() {
+            user::Entity::update_many()
+                .col_expr(user::Column::UserTierId, Expr::value(change.old_tier_id))
+                .filter(user::Column::Id.eq(change.user_id))
+                .filter(user::Column::UserTierId.eq(change.new_tier_id))
+                .exec(&txn)
+                .await?;
+        }
+
+        // skipped users are marked too. rolling back again should not move them later
+        user_tier_change::Entity::update_many()
+            .col_expr(
+                user_tier_change::Column::RolledBackAt,
+                Expr::value(Utc::now()),
+            )
+            .filter(user_tier_change::Column::RebalanceId.eq(rebalance_id))
+            .filter(user_tier_change::Column::RolledBackAt.is_null())
+            .exec(&txn)
+            .await?;
+
+        txn.commit().await?;
+
+        info!(
+            "tier rebalance {} rolled back for {} users",
+            rebalance_id,
+            result.users.len()
+        );
+
+        self.after_tier_changes(&result).await;
+
+        Ok(result)
+    }
+
+    /// Clear the moved users' cached limits and tell them about the change
+    async fn after_tier_changes(&self, result: &TierRebalanceResult) {
+        let user_ids: HashSet<u64> = result.users.iter().map(|x| x.user_id).collect();
+
+        self.rpc_secret_key_cache
+            .remove_matching(|_, x| user_ids.contains(&x.user_id));
+        self.rpc_key_id_cache
+            .remove_matching(|_, x| user_ids.contains(&x.user_id));
+
+        if let Err(err) = self.send_tier_change_webhook(result).await {
+            warn!("failed sending tier change webhook: {:?}", err);
+        }
+    }
+
+    async fn send_tier_change_webhook(&self, result: &TierRebalanceResult) -> anyhow::Result<()> {
+        let (url, http_client) = match (
+            self.config.tier_change_webhook_url.as_ref(),
+            self.http_client.as_ref(),
+        ) {
+            (Some(url), Some(http_client)) => (url, http_client),
+            _ => return Ok(()),
+        };
+
+        let notice = TierChangeNotice {
+            chain_id: self.config.chain_id,
+            rebalance_id: &result.rebalance_id,
+            rolled_back: result.rolled_back,
+            users: &result.users,
+        };
+
+        http_client
+            .post(url)
+            .timeout(Duration::from_secs(30))
+            .json(&notice)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,450 @@
+//! Rate limits for keys. Every user has a token bucket that holds `burst_capacity` requests and refills at `sustained_requests_per_period`.
+//! A user that was quiet can send a burst all at once, but then has to slow down to the sustained rate.
+//!
+//! Tiers set the user's bucket. Missing values fall back to the tier's `max_requests_per_period`.
+//! A key can also have its own smaller bucket. Its requests have to fit in both buckets, so a key can only lower its tier's limits.
+//! The buckets are in redis so that every proxy shares them. A local estimate sits in front of redis so that most requests don't wait on it.
+//! Like the other rate limits, requests are allowed if redis is down.
+//!
+//! Tiers with `max_queue_millis` wait in a `RateLimitQueue` instead of getting an error. The buckets are only asked once per request.
+use super::{AuthorizationChecks, Web3ProxyApp, RATE_LIMIT_PERIOD_SECONDS};
+use crate::frontend::errors::BackoffHint;
+use parking_lot::Mutex;
+use quick_cache_ttl::Cache;
+use redis_rate_limiter::{TokenBucket, TokenBucketResult};
+use std::convert::Infallible;
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenBucketLimit {
+    /// the most requests that can be made at once
+    pub burst: u64,
+    /// requests per `RATE_LIMIT_PERIOD_SECONDS`
+    pub sustained: u64,
+}
+
+impl TokenBucketLimit {
+    pub fn per_second(&self) -> f64 {
+        self.sustained as f64 / RATE_LIMIT_PERIOD_SECONDS as f64
+    }
+
+    /// How long the bucket takes to refill one request
+    pub fn refill_interval(&self) -> Duration {
+        if self.sustained == 0 {
+            Duration::MAX
+        } else {
+            Duration::from_secs_f64(1.0 / self.per_second())
+        }
+    }
+}
+
+/// The user's bucket from their tier. None is unlimited
+pub fn tier_token_bucket(
+    max_requests_per_period: Option<u64>,
+    burst_capacity: Option<u64>,
+    sustained_requests_per_period: Option<u64>,
+) -> Option<TokenBucketLimit> {
+    let sustained = sustained_requests_per_period.or(max_requests_per_period)?;
+
+    // a whole period's requests at once is what the old fixed windows allowed
+    let burst = burst_capacity.unwrap_or(sustained);
+
+    Some(TokenBucketLimit { burst, sustained })
+}
+
+/// The key's own bucket. None if the key doesn't lower its tier's limits
+pub fn key_token_bucket(
+    tier: Option<TokenBucketLimit>,
+    burst_capacity: Option<u64>,
+    sustained_requests_per_period: Option<u64>,
+) -> Option<TokenBucketLimit> {
+    let x = match tier {
+        Some(tier) => TokenBucketLimit {
+            burst: burst_capacity.map_or(tier.burst, |x| x.min(tier.burst)),
+            sustained: sustained_requests_per_period
+                .map_or(tier.sustained, |x| x.min(tier.sustained)),
+        },
+        None => {
+            let sustained = sustained_requests_per_period.or(burst_capacity)?;
+
+            TokenBucketLimit {
+                burst: burst_capacity.unwrap_or(sustained),
+                sustained,
+            }
+        }
+    };
+
+    if Some(x) == tier {
+        None
+    } else {
+        Some(x)
+    }
+}
+
+#[derive(Debug, Default)]
+struct KeyQueue {
+    /// requests that are in line and haven't run yet
+    waiting: usize,
+    /// when the next request to get in line may run
+    next_turn: Option<Instant>,
+}
+
+/// A request's place in a key's line. It leaves the line when this is dropped
+#[derive(Debug)]
+pub struct QueueTurn {
+    /// when the request may run
+    pub at: Instant,
+    queue: Arc<Mutex<KeyQueue>>,
+}
+
+impl Drop for QueueTurn {
+    fn drop(&mut self) {
+        self.queue.lock().waiting -= 1;
+    }
+}
+
+#[derive(Debug)]
+pub enum QueueResult {
+    Turn(QueueTurn),
+    /// the request's turn would come after its wait budget
+    TooLate,
+    /// `max_depth` requests are already waiting for this key
+    Full,
+}
+
+/// Requests that are waiting for their key's bucket to refill.
+/// Each request in line gets its own turn, spaced by the bucket's refill rate, so that they don't all run as soon as it refills.
+/// Turns are only kept on this server, so requests in line don't take from the buckets.
+pub struct RateLimitQueue {
+    keys: Cache<NonZeroU64, Arc<Mutex<KeyQueue>>>,
+    max_depth: usize,
+}
+
+impl RateLimitQueue {
+    pub fn new(capacity: usize, max_depth: usize) -> Self {
+        Self {
+            keys: Cache::new(capacity),
+            max_depth,
+        }
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Get in line for the key. The first turn is at `retry_at` and each turn after it is `interval` later.
+    /// Nothing is taken from the line unless a `QueueTurn` is returned
+    pub async fn enqueue(
+        &self,
+        rpc_key_id: NonZeroU64,
+        retry_at: Instant,
+        interval: Duration,
+        deadline: Instant,
+    ) -> QueueResult {
+        let queue = self
+            .keys
+            .get_or_insert_async::<Infallible>(&rpc_key_id, async move { Ok(Default::default()) })
+            .await
+            .expect("infallible");
+
+        let mut x = queue.lock();
+
+        if x.waiting >= self.max_depth {
+            return QueueResult::Full;
+        }
+
+        let at = match x.next_turn {
+            Some(next_turn) => next_turn.max(retry_at),
+            None => retry_at,
+        };
+
+        if at > deadline {
+            return QueueResult::TooLate;
+        }
+
+        x.waiting += 1;
+        x.next_turn = at.checked_add(interval);
+
+        drop(x);
+
+        QueueResult::Turn(QueueTurn { at, queue })
+    }
+}
+
+impl Web3ProxyApp {
+    /// How long apart requests waiting for this key's buckets run. The key's own bucket is the one that matters if it has one
+    pub(crate) fn queue_interval(checks: &AuthorizationChecks) -> Duration {
+        checks
+            .key_token_bucket
+            .or(checks.token_bucket)
+            .map(|x| x.refill_interval())
+            .unwrap_or_default()
+    }
+
+    /// The key's bucket and its user's bucket. Empty if neither has a limit
+    fn token_buckets(checks: &AuthorizationChecks) -> Vec<TokenBucket> {
+        // the key's bucket is usually the smaller one
+        let key_bucket =
+            checks
+                .key_token_bucket
+                .zip(checks.rpc_secret_key_id)
+                .map(|(limit, rpc_key_id)| {
+                    TokenBucket::new(
+                        format!("key:{}", rpc_key_id),
+                        limit.burst,
+                        limit.per_second(),
+                    )
+                });
+
+        let user_bucket = checks.token_bucket.map(|limit| {
+            TokenBucket::new(
+                format!("user:{}", checks.user_id),
+                limit.burst,
+                limit.per_second(),
+            )
+        });
+
+        key_bucket.into_iter().chain(user_bucket).collect()
+    }
+
+    /// Take a token from the key's bucket and from its user's bucket. If either is empty, neither is taken from.
+    /// None if there is no redis or no limit. Errors are from redis
+    pub(crate) async fn throttle_token_buckets(
+        &self,
+        checks: &AuthorizationChecks,
+    ) -> anyhow::Result<Option<TokenBucketResult>> {
+        let token_buckets = match self.frontend_token_buckets.as_ref() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let buckets = Self::token_buckets(checks);
+
+        if buckets.is_empty() {
+            return Ok(None);
+        }
+
+        token_buckets.throttle(&buckets, 1).await.map(Some)
+    }
+
+    /// The requests left in the key's smallest bucket. None if there is no redis or no limit
+    pub(crate) async fn peek_token_buckets(
+        &self,
+        checks: &AuthorizationChecks,
+    ) -> anyhow::Result<Option<u64>> {
+        let token_buckets = match self.frontend_token_buckets.as_ref() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let buckets = Self::token_buckets(checks);
+
+        if buckets.is_empty() {
+            return Ok(None);
+        }
+
+        token_buckets.peek(&buckets).await.map(Some)
+    }
+
+    /// Like `peek_token_buckets`, but only this server's estimates are used. This never waits on redis.
+    /// None if there is no limit or the key's buckets haven't been used here recently
+    pub(crate) fn peek_local_token_buckets(&self, checks: &AuthorizationChecks) -> Option<u64> {
+        let token_buckets = self.frontend_token_buckets.as_ref()?;
+
+        token_buckets.peek_local(&Self::token_buckets(checks))
+    }
+
+    /// What a client needs to back off from its key's buckets. The key's own bucket is the one that matters if it has one
+    pub fn token_bucket_hint(&self, checks: &AuthorizationChecks) -> BackoffHint {
+        let limit = checks.key_token_bucket.or(checks.token_bucket);
+
+        let mut hint =
+            self.backoff_hint(limit.map(|x| x.sustained), Some(RATE_LIMIT_PERIOD_SECONDS));
+
+        hint.burst = limit.map(|x| x.burst);
+
+        hint
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_buckets_for_checks() {
+        let mut checks = AuthorizationChecks {
+            user_id: 1,
+            rpc_secret_key_id: Some(2.try_into().unwrap()),
+            ..Default::default()
+        };
+
+        assert!(Web3ProxyApp::token_buckets(&checks).is_empty());
+
+        checks.token_bucket = tier_token_bucket(Some(600), Some(50), None);
+
+        assert_eq!(
+            Web3ProxyApp::token_buckets(&checks),
+            vec![TokenBucket::new("user:1".to_string(), 50, 10.0)]
+        );
+
+        checks.key_token_bucket = key_token_bucket(checks.token_bucket, Some(5), Some(60));
+
+        // the key's bucket comes first
+        assert_eq!(
+            Web3ProxyApp::token_buckets(&checks),
+            vec![
+                TokenBucket::new("key:2".to_string(), 5, 1.0),
+                TokenBucket::new("user:1".to_string(), 50, 10.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_token_bucket_limits() {
+        assert_eq!(tier_token_bucket(None, Some(10), None), None);
+
+        // old tiers only have max_requests_per_period
+        let tier = tier_token_bucket(Some(600), None, None).unwrap();
+        assert_eq!(
+            tier,
+            TokenBucketLimit {
+                burst: 600,
+                sustained: 600
+            }
+        );
+        assert_eq!(tier.per_second(), 10.0);
+
+        let tier = tier_token_bucket(Some(600), Some(50), Some(120));
+        assert_eq!(
+            tier,
+            Some(TokenBucketLimit {
+                burst: 50,
+                sustained: 120
+            })
+        );
+
+        // keys can only lower their tier's limits
+        assert_eq!(key_token_bucket(tier, None, None), None);
+        assert_eq!(key_token_bucket(tier, Some(100), None), None);
+        assert_eq!(
+            key_token_bucket(tier, Some(100), Some(60)),
+            Some(TokenBucketLimit {
+                burst: 50,
+                sustained: 60
+            })
+        );
+
+        // unlimited tiers
+        assert_eq!(key_token_bucket(None, None, None), None);
+        assert_eq!(
+            key_token_bucket(None, Some(5), None),
+            Some(TokenBucketLimit {
+                burst: 5,
+                sustained: 5
+            })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_queue() {
+        let queue = RateLimitQueue::new(100, 2);
+
+        let rpc_key_id = NonZeroU64::new(1).unwrap();
+        let other_key_id = NonZeroU64::new(2).unwrap();
+
+        let now = Instant::now();
+        let retry_at = now + Duration::from_millis(100);
+        let interval = Duration::from_millis(50);
+        let deadline = now + Duration::from_secs(1);
+
+        let first = match queue
+            .enqueue(rpc_key_id, retry_at, interval, deadline)
+            .await
+        {
+            QueueResult::Turn(x) => x,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(first.at, retry_at);
+
+        // the second request waits a refill longer instead of running at the same time as the first
+        let second = match queue
+            .enqueue(rpc_key_id, retry_at, interval, deadline)
+            .await
+        {
+            QueueResult::Turn(x) => x,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(second.at, retry_at + interval);
+
+        assert!(matches!(
+            queue
+                .enqueue(rpc_key_id, retry_at, interval, deadline)
+                .await,
+            QueueResult::Full
+        ));
+
+        // other keys have their own line
+        assert!(matches!(
+            queue
+                .enqueue(other_key_id, retry_at, interval, deadline)
+                .await,
+            QueueResult::Turn(_)
+        ));
+
+        // leaving the line makes room
+        drop(first);
+
+        let third = match queue
+            .enqueue(rpc_key_id, retry_at, interval, deadline)
+            .await
+        {
+            QueueResult::Turn(x) => x,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(third.at, retry_at + interval * 2);
+
+        drop(second);
+
+        // turns past the budget are rejected without taking a place in line
+        let short_deadline = retry_at + interval;
+
+        assert!(matches!(
+            queue
+                .enqueue(rpc_key_id, retry_at, interval, short_deadline)
+                .await,
+            QueueResult::TooLate
+        ));
+
+        assert!(matches!(
+            queue
+                .enqueue(rpc_key_id, retry_at, interval, deadline)
+                .await,
+            QueueResult::Turn(_)
+        ));
+    }
+
+    #[test]
+    fn test_queue_interval() {
+        let mut checks = AuthorizationChecks::default();
+
+        assert_eq!(Web3ProxyApp::queue_interval(&checks), Duration::ZERO);
+
+        checks.token_bucket = tier_token_bucket(Some(600), None, None);
+
+        assert_eq!(
+            Web3ProxyApp::queue_interval(&checks),
+            Duration::from_millis(100)
+        );
+
+        // the key's own bucket refills slower
+        checks.key_token_bucket = key_token_bucket(checks.token_bucket, None, Some(60));
+
+        assert_eq!(
+            Web3ProxyApp::queue_interval(&checks),
+            Duration::from_secs(1)
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! A durable journal for `eth_sendRawTransaction`.
+//!
+//! The raw transaction is saved before it is broadcast, and its row is updated with what the backends said.
+//! Rows that stay pending (because a proxy crashed or lost the backends mid-broadcast) are picked up by `watch_tx_journal` on any proxy.
+//! If a backend already knows the transaction it is marked as broadcast. Otherwise it is sent again.
+use super::Web3ProxyApp;
+use crate::frontend::authorization::Authorization;
+use crate::frontend::errors::Web3ProxyResult;
+use crate::jsonrpc::{JsonRpcForwardedResponseEnum, JsonRpcId, JsonRpcRequest, JsonRpcRequestEnum};
+use anyhow::Context;
+use chrono::Utc;
+use entities::sea_orm_active_enums::TxJournalStatus;
+use entities::tx_journal;
+use ethers::types::{Bytes, H256};
+use ethers::utils::keccak256;
+use log::{error, info, warn};
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter, QueryOrder,
+    QuerySelect,
+};
+use serde_json::json;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::time::{interval_at, Duration, Instant, MissedTickBehavior};
+
+/// the most pending transactions that one recovery checks
+const RECOVERY_BATCH: u64 = 100;
+
+impl Web3ProxyApp {
+    /// Save a raw transaction before it is broadcast. Sending the same transaction again reuses its row.
+    /// None if journaling is off or the params are not a raw transaction (the backends will reject those)
+    pub(crate) async fn journal_raw_transaction(
+        &self,
+        authorization: &Authorization,
+        request: &JsonRpcRequest,
+    ) -> Web3ProxyResult<Option<u64>> {
+        if !self.config.tx_journal.enabled {
+            return Ok(None);
+        }
+
+        let raw_tx = match request
+            .params
+            .as_ref()
+            .and_then(|x| x.get(0))
+            .and_then(|x| x.as_str())
+        {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let tx_hash = match Bytes::from_str(raw_tx) {
+            Ok(x) => keccak256(&x),
+            Err(_) => return Ok(None),
+        };
+
+        let db_conn = self
+            .db_conn()
+            .context("journaling transactions needs a db")?;
+
+        let existing = tx_journal::Entity::find()
+            .filter(tx_journal::Column::ChainId.eq(self.config.chain_id))
+            .filter(tx_journal::Column::TxHash.eq(tx_hash.to_vec()))
+            .one(&db_conn)
+            .await?;
+
+        let row = match existing {
+            Some(x) => {
+                let attempts = x.attempts + 1;
+
+                let mut x = x.into_active_model();
+
+                x.status = sea_orm::Set(TxJournalStatus::Pending);
+                x.attempts = sea_orm::Set(attempts);
+                x.updated_at = sea_orm::Set(Utc::now());
+
+                x.update(&db_conn).await?
+            }
+            None => {
+                let x = tx_journal::ActiveModel {
+                    chain_id: sea_orm::Set(self.config.chain_id),
+                    tx_hash: sea_orm::Set(tx_hash.to_vec()),
+                    raw_tx: sea_orm::Set(raw_tx.to_string()),
+                    status: sea_orm::Set(TxJournalStatus::Pending),
+                    attempts: sea_orm::Set(1),
+                    rpc_key_id: sea_orm::Set(
+                        authorization.checks.rpc_secret_key_id.map(|x| x.get()),
+                    ),
+                    ..Default::default()
+                };
+
+                x.insert(&db_conn).await?
+            }
+        };
+
+        Ok(Some(row.id))
+    }
+
+    /// Save what the backends said about a journaled transaction.
+    /// If this fails, the row stays pending and recovery checks it later
+    pub(crate) async fn finish_tx_journal(
+        &self,
+        journal_id: Option<u64>,
+        status: TxJournalStatus,
+        last_error: Option<String>,
+    ) {
+        let journal_id = match journal_id {
+            Some(x) => x,
+            None => return,
+        };
+
+        if let Err(err) = self
+            ._finish_tx_journal(journal_id, status, last_error)
+            .await
+        {
+            error!("unable to update tx journal {}: {:?}", journal_id, err);
+        }
+    }
+
+    async fn _finish_tx_journal(
+        &self,
+        journal_id: u64,
+        status: TxJournalStatus,
+        last_error: Option<String>,
+    ) -> Web3ProxyResult<()> {
+        let db_conn = self
+            .db_conn()
+            .context("journaling transactions needs a db")?;
+
+        let x = tx_journal::ActiveModel {
+            id: sea_orm::Unchanged(journal_id),
+            status: sea_orm::Set(status),
+            last_error: sea_orm::Set(last_error),
+            updated_at: sea_orm::Set(Utc::now()),
+            ..Default::default()
+        };
+
+        x.update(&db_conn).await?;
+
+        Ok(())
+    }
+
+    /// The journal row for a transaction on this proxy's chain
+    pub async fn tx_journal_entry(
+        &self,
+        tx_hash: H256,
+    ) -> Web3ProxyResult<Option<tx_journal::Model>> {
+        let db_replica = self
+            .db_replica()
+            .context("journaling transactions needs a db")?;
+
+        let x = tx_journal::Entity::find()
+            .filter(tx_journal::Column::ChainId.eq(self.config.chain_id))
+            .filter(tx_journal::Column::TxHash.eq(tx_hash.as_bytes().to_vec()))
+            .one(db_replica.conn())
+            .await?;
+
+        Ok(x)
+    }
+
+    /// Finish broadcasts that were interrupted. Runs on every proxy
+    pub(super) async fn watch_tx_journal(self: Arc<Self>) -> Web3ProxyResult<()> {
+        let config = &self.config.tx_journal;
+
+        if !config.enabled {
+            return Ok(());
+        }
+
+        let period = Duration::from_secs(config.recovery_seconds.max(1));
+
+        // after a restart, the backends need time to connect
+        let mut interval = interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = self.recover_tx_journal().await {
+                warn!("tx journal recovery failed: {:?}", err);
+            }
+        }
+    }
+
+    async fn recover_tx_journal(self: &Arc<Self>) -> Web3ProxyResult<()> {
+        let config = &self.config.tx_journal;
+
+        let db_conn = self
+            .db_conn()
+            .context("journaling transactions needs a db")?;
+
+        // newer rows might still be in flight
+        let stale = Utc::now() - chrono::Duration::seconds(config.recovery_seconds as i64);
+
+        let pending = tx_journal::Entity::find()
+            .filter(tx_journal::Column::ChainId.eq(self.config.chain_id))
+            .filter(tx_journal::Column::Status.eq(TxJournalStatus::Pending))
+            .filter(tx_journal::Column::UpdatedAt.lt(stale))
+            .order_by_asc(tx_journal::Column::Id)
+            .limit(RECOVERY_BATCH)
+            .all(&db_conn)
+            .await?;
+
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        info!("recovering {} pending transactions", pending.len());
+
+        let authorization = Arc::new(Authorization::internal(self.db_conn())?);
+
+        for row in pending {
+            let tx_hash = H256::from_slice(&row.tx_hash);
+
+            match self.transaction_is_known(&authorization, tx_hash).await {
+                Ok(true) => {
+                    self.finish_tx_journal(Some(row.id), TxJournalStatus::Broadcast, None)
+                        .await;
+                    continue;
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    warn!("unable to look up pending tx {:?}: {:?}", tx_hash, err);
+                    continue;
+                }
+            }
+
+            if row.attempts >= config.max_attempts {
+                warn!(
+                    "giving up on pending tx {:?} after {} attempts",
+                    tx_hash, row.attempts
+                );
+
+                self.finish_tx_journal(
+                    Some(row.id),
+                    TxJournalStatus::Failed,
+                    Some(format!("gave up after {} attempts", row.attempts)),
+                )
+                .await;
+
+                continue;
+            }
+
+            // the normal request path updates the row with what the backends said
+            let request = JsonRpcRequest::new(
+                JsonRpcId::Number(row.id),
+                "eth_sendRawTransaction".to_string(),
+                Some(json!([row.raw_tx])),
+            )?;
+
+            if let Err(err) = self
+                .proxy_web3_rpc(authorization.clone(), JsonRpcRequestEnum::Single(request))
+                .await
+            {
+                warn!("unable to resend pending tx {:?}: {:?}", tx_hash, err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// True if a backend has the transaction in its mempool or a block
+    async fn transaction_is_known(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        tx_hash: H256,
+    ) -> Web3ProxyResult<bool> {
+        let request = JsonRpcRequest::new(
+            JsonRpcId::Number(0),
+            "eth_getTransactionByHash".to_string(),
+            Some(json!([tx_hash])),
+        )?;
+
+        let (_, response, _) = self
+            .proxy_web3_rpc(authorization.clone(), JsonRpcRequestEnum::Single(request))
+            .await?;
+
+        let response = match response {
+            Some(JsonRpcForwardedResponseEnum::Single(x)) => x,
+            _ => return Ok(false),
+        };
+
+        if let Some(err) = response.error {
+            return Err(anyhow::anyhow!("eth_getTransactionByHash failed: {}", err.message).into());
+        }
+
+        Ok(response.result.map(|x| x.get() != "null").unwrap_or(false))
+    }
+}
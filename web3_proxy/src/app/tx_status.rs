@@ -0,0 +1,466 @@
+//! Follow transactions sent with `eth_sendRawTransaction` after they are broadcast.
+//!
+//! Pending transactions from the backends' subscriptions mark a transaction as seen in the mempool, and every new head marks the ones that it includes as mined.
+//! Transactions that make no progress for `check_after_blocks` are looked up on the backends.
+//! If no backend has one, it was replaced when its sender's nonce has been used and dropped otherwise.
+//! Each proxy only knows about the transactions that it was sent. `GET /v1/:chain/tx_status/:tx_hash` falls back to the journal for the others.
+use super::Web3ProxyApp;
+use crate::frontend::authorization::Authorization;
+use crate::frontend::errors::Web3ProxyResult;
+use crate::jsonrpc::{JsonRpcForwardedResponseEnum, JsonRpcId, JsonRpcRequest, JsonRpcRequestEnum};
+use crate::rpcs::blockchain::Web3ProxyBlock;
+use crate::rpcs::head_events::HeadEvent;
+use crate::rpcs::transactions::TxStatus;
+use chrono::{DateTime, Utc};
+use ethers::types::{Address, Bytes, Transaction, TxHash, H256, U256, U64};
+use ethers::utils::rlp::{Decodable, Rlp};
+use hashbrown::HashMap;
+use log::{debug, trace, warn};
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::json;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{Duration, Instant};
+
+/// the most transactions that are looked up on the backends after one head
+const MAX_LOOKUPS_PER_HEAD: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxLifecycle {
+    /// the proxy has it, but no backend has accepted it yet
+    Received,
+    /// at least one backend accepted it
+    Broadcast,
+    /// the backends rejected it
+    Rejected,
+    /// a backend has it in its mempool
+    Mempool,
+    Mined,
+    /// no backend has it and its nonce is unused. sending it again might work
+    Dropped,
+    /// another transaction from the same sender used its nonce
+    Replaced,
+}
+
+/// What `GET /v1/:chain/tx_status/:tx_hash` returns for a transaction this proxy is following
+#[derive(Clone, Debug, Serialize)]
+pub struct TrackedTx {
+    pub tx_hash: TxHash,
+    pub status: TxLifecycle,
+    pub from: Option<Address>,
+    pub nonce: U256,
+    /// how many backends it was sent to. sending it again adds to this
+    pub backends: usize,
+    pub seen_in_mempool: bool,
+    pub block_number: Option<U64>,
+    pub block_hash: Option<H256>,
+    pub last_error: Option<String>,
+    pub received_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// the head when the status last changed or the backends were last asked
+    #[serde(skip)]
+    checked_block: u64,
+    #[serde(skip)]
+    last_change: Instant,
+}
+
+impl TrackedTx {
+    fn new(tx: &Transaction, head_num: u64, now: Instant) -> Self {
+        Self {
+            tx_hash: tx.hash,
+            status: TxLifecycle::Received,
+            from: tx.recover_from().ok(),
+            nonce: tx.nonce,
+            backends: 0,
+            seen_in_mempool: false,
+            block_number: None,
+            block_hash: None,
+            last_error: None,
+            received_at: Utc::now(),
+            updated_at: Utc::now(),
+            checked_block: head_num,
+            last_change: now,
+        }
+    }
+
+    fn set_status(&mut self, status: TxLifecycle, head_num: u64, now: Instant) {
+        if status != TxLifecycle::Mined {
+            self.block_number = None;
+            self.block_hash = None;
+        }
+
+        self.status = status;
+        self.checked_block = head_num;
+        self.last_change = now;
+        self.updated_at = Utc::now();
+    }
+
+    fn mined(&mut self, block: &Web3ProxyBlock, now: Instant) {
+        let head_num = block.number().as_u64();
+
+        self.set_status(TxLifecycle::Mined, head_num, now);
+        self.block_number = Some(*block.number());
+        self.block_hash = Some(*block.hash());
+    }
+
+    /// Mempool sightings only move a transaction forward. They are too late for mined transactions
+    fn seen_in_mempool(&mut self, head_num: u64, now: Instant) {
+        self.seen_in_mempool = true;
+
+        if matches!(
+            self.status,
+            TxLifecycle::Received | TxLifecycle::Broadcast | TxLifecycle::Dropped
+        ) {
+            self.set_status(TxLifecycle::Mempool, head_num, now);
+        }
+    }
+
+    /// True if this has not changed in a while and could still be mined
+    fn needs_lookup(&self, head_num: u64, check_after_blocks: u64) -> bool {
+        matches!(
+            self.status,
+            TxLifecycle::Broadcast | TxLifecycle::Mempool | TxLifecycle::Dropped
+        ) && head_num >= self.checked_block + check_after_blocks.max(1)
+    }
+}
+
+/// The transactions this proxy is following
+#[derive(Debug, Default)]
+pub struct TxTracker {
+    txs: Mutex<HashMap<TxHash, TrackedTx>>,
+}
+
+impl TxTracker {
+    /// Follow a new transaction. When full, the one that changed least recently is forgotten
+    fn received(&self, tx: &Transaction, head_num: u64, max_tracked: usize) {
+        let now = Instant::now();
+
+        let mut txs = self.txs.lock();
+
+        if let Some(x) = txs.get_mut(&tx.hash) {
+            // sent again. a mined transaction is already done
+            if x.status != TxLifecycle::Mined {
+                x.last_error = None;
+                x.set_status(TxLifecycle::Received, head_num, now);
+            }
+            return;
+        }
+
+        if txs.len() >= max_tracked.max(1) {
+            let oldest = txs
+                .values()
+                .min_by_key(|x| x.last_change)
+                .map(|x| x.tx_hash);
+
+            if let Some(oldest) = oldest {
+                txs.remove(&oldest);
+            }
+        }
+
+        txs.insert(tx.hash, TrackedTx::new(tx, head_num, now));
+    }
+
+    fn on_head(&self, event: &HeadEvent, keep: Duration) {
+        let head = match event.head_block() {
+            Some(x) => x,
+            None => return,
+        };
+
+        let head_num = head.number().as_u64();
+        let now = Instant::now();
+
+        let mut txs = self.txs.lock();
+
+        txs.retain(|_, x| now.duration_since(x.last_change) < keep);
+
+        if let HeadEvent::Reorg { .. } = event {
+            // the blocks after the new head are gone. their transactions might be mined again
+            for x in txs.values_mut() {
+                if x.status == TxLifecycle::Mined
+                    && x.block_number.map(|x| x >= *head.number()) == Some(true)
+                {
+                    x.set_status(TxLifecycle::Broadcast, head_num, now);
+                }
+            }
+        }
+
+        for tx_hash in head.block.transactions.iter() {
+            if let Some(x) = txs.get_mut(tx_hash) {
+                x.mined(head, now);
+            }
+        }
+    }
+
+    pub fn get(&self, tx_hash: &TxHash) -> Option<TrackedTx> {
+        self.txs.lock().get(tx_hash).cloned()
+    }
+}
+
+impl Web3ProxyApp {
+    /// Start following a raw transaction. Transactions that can't be decoded are left for the backends to reject
+    pub(crate) fn track_raw_transaction(&self, request: &JsonRpcRequest) -> Option<TxHash> {
+        let config = &self.config.tx_status;
+
+        if !config.enabled {
+            return None;
+        }
+
+        let tx = request
+            .params
+            .as_ref()
+            .and_then(|x| x.get(0))
+            .and_then(|x| x.as_str())
+            .and_then(|x| Bytes::from_str(x).ok())
+            .and_then(|x| Transaction::decode(&Rlp::new(x.as_ref())).ok())?;
+
+        self.tx_tracker
+            .received(&tx, self.head_block_num_u64(), config.max_tracked);
+
+        Some(tx.hash)
+    }
+
+    /// Save what the backends said about a transaction
+    pub(crate) fn track_broadcast(
+        &self,
+        tx_hash: Option<TxHash>,
+        backends: usize,
+        error: Option<String>,
+    ) {
+        let tx_hash = match tx_hash {
+            Some(x) if self.config.tx_status.enabled => x,
+            _ => return,
+        };
+
+        let head_num = self.head_block_num_u64();
+        let now = Instant::now();
+
+        if let Some(x) = self.tx_tracker.txs.lock().get_mut(&tx_hash) {
+            x.backends += backends;
+
+            // the head might have mined it already
+            if x.status != TxLifecycle::Received {
+                return;
+            }
+
+            match error {
+                None => x.set_status(TxLifecycle::Broadcast, head_num, now),
+                Some(err) if backends == 0 => {
+                    // nothing was sent. the status stays received
+                    x.last_error = Some(err);
+                }
+                Some(err) => {
+                    x.last_error = Some(err);
+                    x.set_status(TxLifecycle::Rejected, head_num, now);
+                }
+            }
+        }
+    }
+
+    /// What this proxy knows about a transaction that it was sent
+    pub fn tracked_transaction(&self, tx_hash: &TxHash) -> Option<TrackedTx> {
+        self.tx_tracker.get(tx_hash)
+    }
+
+    fn head_block_num_u64(&self) -> u64 {
+        self.watch_consensus_head_receiver
+            .borrow()
+            .as_ref()
+            .map(|x| x.number().as_u64())
+            .unwrap_or_default()
+    }
+
+    /// Follow tracked transactions with the head and pending transaction watchers
+    pub(super) async fn watch_tx_status(self: Arc<Self>) -> Web3ProxyResult<()> {
+        let config = &self.config.tx_status;
+
+        if !config.enabled {
+            return Ok(());
+        }
+
+        let keep = Duration::from_secs(config.keep_seconds);
+
+        let mut head_events = self.head_events();
+        let mut pending_txs = self.pending_tx_sender.subscribe();
+
+        loop {
+            tokio::select! {
+                x = head_events.recv() => match x {
+                    Ok(event) => {
+                        self.tx_tracker.on_head(&event, keep);
+
+                        if let Some(head) = event.head_block() {
+                            let head_num = head.number().as_u64();
+
+                            if let Err(err) = self.look_up_stuck_transactions(head_num).await {
+                                warn!("unable to look up stuck transactions: {:?}", err);
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("tx status missed {} head events", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                x = pending_txs.recv() => match x {
+                    Ok(TxStatus::Pending(tx)) => {
+                        let head_num = self.head_block_num_u64();
+
+                        if let Some(x) = self.tx_tracker.txs.lock().get_mut(&tx.hash) {
+                            x.seen_in_mempool(head_num, Instant::now());
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        trace!("tx status missed {} pending transactions", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ask the backends about transactions that have not changed in `check_after_blocks`
+    async fn look_up_stuck_transactions(self: &Arc<Self>, head_num: u64) -> Web3ProxyResult<()> {
+        let check_after_blocks = self.config.tx_status.check_after_blocks;
+
+        let mut stuck: Vec<_> = self
+            .tx_tracker
+            .txs
+            .lock()
+            .values()
+            .filter(|x| x.needs_lookup(head_num, check_after_blocks))
+            .map(|x| (x.checked_block, x.tx_hash, x.from, x.nonce))
+            .collect();
+
+        if stuck.is_empty() {
+            return Ok(());
+        }
+
+        // the ones that were checked longest ago go first
+        stuck.sort_unstable();
+        stuck.truncate(MAX_LOOKUPS_PER_HEAD);
+
+        let authorization = Arc::new(Authorization::internal(self.db_conn())?);
+
+        for (_, tx_hash, from, nonce) in stuck {
+            let tx: Option<Transaction> = self
+                .internal_call(&authorization, "eth_getTransactionByHash", json!([tx_hash]))
+                .await?;
+
+            let mut block = None;
+
+            let status = match tx {
+                Some(x) if x.block_number.is_some() => {
+                    block = x.block_number.zip(x.block_hash);
+                    TxLifecycle::Mined
+                }
+                Some(_) => TxLifecycle::Mempool,
+                None => match from {
+                    Some(from) => {
+                        let next_nonce: U256 = self
+                            .internal_call(
+                                &authorization,
+                                "eth_getTransactionCount",
+                                json!([from, "latest"]),
+                            )
+                            .await?;
+
+                        if next_nonce > nonce {
+                            TxLifecycle::Replaced
+                        } else {
+                            TxLifecycle::Dropped
+                        }
+                    }
+                    None => TxLifecycle::Dropped,
+                },
+            };
+
+            debug!("looked up stuck tx {:?}: {:?}", tx_hash, status);
+
+            let now = Instant::now();
+
+            if let Some(x) = self.tx_tracker.txs.lock().get_mut(&tx_hash) {
+                if x.status == status {
+                    x.checked_block = head_num;
+                } else {
+                    x.set_status(status, head_num, now);
+
+                    if let Some((number, hash)) = block {
+                        x.block_number = Some(number);
+                        x.block_hash = Some(hash);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn internal_call<R: DeserializeOwned>(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Web3ProxyResult<R> {
+        let request = JsonRpcRequest::new(JsonRpcId::Number(0), method.to_string(), Some(params))?;
+
+        let (_, response, _) = self
+            .proxy_web3_rpc(authorization.clone(), JsonRpcRequestEnum::Single(request))
+            .await?;
+
+        let response = match response {
+            Some(JsonRpcForwardedResponseEnum::Single(x)) => x,
+            _ => return Err(anyhow::anyhow!("{} got no response", method).into()),
+        };
+
+        if let Some(err) = response.error {
+            return Err(anyhow::anyhow!("{} failed: {}", method, err.message).into());
+        }
+
+        let result = response
+            .result
+            .ok_or_else(|| anyhow::anyhow!("{} has no result", method))?;
+
+        Ok(serde_json::from_str(result.get())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lifecycle() {
+        let now = Instant::now();
+
+        let tx = Transaction {
+            hash: TxHash::repeat_byte(1),
+            ..Default::default()
+        };
+
+        let mut x = TrackedTx::new(&tx, 100, now);
+        assert_eq!(x.status, TxLifecycle::Received);
+        assert!(!x.needs_lookup(200, 3));
+
+        x.set_status(TxLifecycle::Broadcast, 100, now);
+        assert!(!x.needs_lookup(102, 3));
+        assert!(x.needs_lookup(103, 3));
+
+        x.seen_in_mempool(101, now);
+        assert_eq!(x.status, TxLifecycle::Mempool);
+        assert!(x.seen_in_mempool);
+
+        x.set_status(TxLifecycle::Mined, 102, now);
+        assert!(!x.needs_lookup(200, 3));
+
+        // a late mempool sighting doesn't undo mining
+        x.seen_in_mempool(103, now);
+        assert_eq!(x.status, TxLifecycle::Mined);
+    }
+}
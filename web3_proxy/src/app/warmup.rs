@@ -0,0 +1,236 @@
+//! Get everything connected and primed before `/ready` tells the load balancer to send us traffic.
+use super::Web3ProxyApp;
+use crate::frontend::authorization::Authorization;
+use crate::frontend::errors::Web3ProxyResult;
+use crate::jsonrpc::{JsonRpcId, JsonRpcRequest, JsonRpcRequestEnum};
+use hashbrown::HashMap;
+use log::{debug, info, trace, warn};
+use migration::sea_orm::ConnectionTrait;
+use parking_lot::Mutex;
+use redis_rate_limiter::redis;
+use serde::Serialize;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+/// how long to wait before checking a phase again
+const WARMUP_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The phases of warmup. They run in this order
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmupPhase {
+    /// waiting for enough backends to report a head block
+    Backends,
+    /// waiting for the backends to agree on a head block
+    HeadConsensus,
+    /// sending the `warmup_methods` through the response cache
+    Caches,
+    /// checking that the database and redis respond
+    Storage,
+    Ready,
+}
+
+/// Where warmup is and how long each phase took
+pub struct Warmup {
+    phase: watch::Sender<WarmupPhase>,
+    phase_durations: Mutex<HashMap<WarmupPhase, Duration>>,
+    /// set if `max_warmup_seconds` passed before every phase finished
+    timed_out: AtomicBool,
+}
+
+impl Default for Warmup {
+    fn default() -> Self {
+        let (phase, _) = watch::channel(WarmupPhase::Backends);
+
+        Self {
+            phase,
+            phase_durations: Default::default(),
+            timed_out: false.into(),
+        }
+    }
+}
+
+impl Warmup {
+    pub fn phase(&self) -> WarmupPhase {
+        *self.phase.borrow()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.phase() == WarmupPhase::Ready
+    }
+
+    /// Wait until warmup is finished
+    pub fn subscribe(&self) -> watch::Receiver<WarmupPhase> {
+        self.phase.subscribe()
+    }
+
+    /// seconds spent in each finished phase. used for metrics
+    pub fn phase_seconds(&self) -> HashMap<WarmupPhase, f64> {
+        self.phase_durations
+            .lock()
+            .iter()
+            .map(|(k, v)| (*k, v.as_secs_f64()))
+            .collect()
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.timed_out.load(atomic::Ordering::Relaxed)
+    }
+
+    fn finish_phase(&self, phase: WarmupPhase, started: Instant, next: WarmupPhase) {
+        let elapsed = started.elapsed();
+
+        debug!("warmup phase {:?} took {:?}", phase, elapsed);
+
+        self.phase_durations.lock().insert(phase, elapsed);
+
+        self.phase.send_replace(next);
+    }
+}
+
+impl Serialize for Warmup {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        let mut state = serializer.serialize_struct("Warmup", 3)?;
+
+        state.serialize_field("phase", &self.phase())?;
+        state.serialize_field("phase_seconds", &self.phase_seconds())?;
+        state.serialize_field("timed_out", &self.timed_out())?;
+
+        state.end()
+    }
+}
+
+impl Web3ProxyApp {
+    /// Run every warmup phase and then mark the app as ready.
+    /// If warmup takes longer than `max_warmup_seconds`, the app is marked ready anyway so that a single slow dependency can't keep it out of service forever.
+    pub(super) async fn warmup(self: Arc<Self>) -> Web3ProxyResult<()> {
+        let max_warmup = Duration::from_secs(self.config.max_warmup_seconds);
+
+        let started = Instant::now();
+
+        if timeout(max_warmup, self.clone().warmup_phases())
+            .await
+            .is_err()
+        {
+            warn!(
+                "warmup did not finish within {}s. stuck in {:?}. accepting traffic anyway",
+                self.config.max_warmup_seconds,
+                self.warmup.phase()
+            );
+
+            self.warmup.timed_out.store(true, atomic::Ordering::Relaxed);
+
+            self.warmup.phase.send_replace(WarmupPhase::Ready);
+        }
+
+        info!("warmup finished in {:?}", started.elapsed());
+
+        Ok(())
+    }
+
+    async fn warmup_phases(self: Arc<Self>) {
+        // the sandbox has no backends to wait for
+        let sandbox = self.config.sandbox.enabled;
+
+        // backends
+        let started = Instant::now();
+        let min_head_rpcs = self.balanced_rpcs.min_head_rpcs().max(1);
+        loop {
+            let num_with_heads = self
+                .balanced_rpcs
+                .by_name
+                .load()
+                .values()
+                .filter(|x| x.head_block_num().is_some())
+                .count();
+
+            if sandbox || num_with_heads >= min_head_rpcs {
+                break;
+            }
+
+            trace!(
+                "waiting for backends. {}/{} have a head block",
+                num_with_heads,
+                min_head_rpcs
+            );
+
+            sleep(WARMUP_RETRY_INTERVAL).await;
+        }
+        self.warmup
+            .finish_phase(WarmupPhase::Backends, started, WarmupPhase::HeadConsensus);
+
+        // head consensus
+        let started = Instant::now();
+        let mut head_block_receiver = self.head_block_receiver();
+        while !sandbox && head_block_receiver.borrow_and_update().is_none() {
+            if head_block_receiver.changed().await.is_err() {
+                // the sender is gone. the app is shutting down
+                return;
+            }
+        }
+        self.warmup
+            .finish_phase(WarmupPhase::HeadConsensus, started, WarmupPhase::Caches);
+
+        // caches
+        let started = Instant::now();
+        if let Err(err) = self.warmup_caches().await {
+            // a cold cache is slower, but it still works
+            warn!("unable to prime the response cache: {:?}", err);
+        }
+        self.warmup
+            .finish_phase(WarmupPhase::Caches, started, WarmupPhase::Storage);
+
+        // storage
+        let started = Instant::now();
+        while let Err(err) = self.check_storage().await {
+            warn!("waiting for storage: {:?}", err);
+
+            sleep(WARMUP_RETRY_INTERVAL).await;
+        }
+        self.warmup
+            .finish_phase(WarmupPhase::Storage, started, WarmupPhase::Ready);
+    }
+
+    /// Send the hot methods through the normal request path so their responses are cached
+    async fn warmup_caches(self: &Arc<Self>) -> Web3ProxyResult<()> {
+        let authorization = Arc::new(Authorization::internal(self.db_conn())?);
+
+        for (i, method) in self.config.warmup_methods.iter().enumerate() {
+            let request = JsonRpcRequest::new(
+                JsonRpcId::Number(i as u64),
+                method.clone(),
+                Some(serde_json::Value::Array(vec![])),
+            )?;
+
+            let (_, response, _) = self
+                .proxy_web3_rpc(authorization.clone(), JsonRpcRequestEnum::Single(request))
+                .await?;
+
+            trace!("warmed {}: {:?}", method, response);
+        }
+
+        Ok(())
+    }
+
+    /// Make sure the database and redis (if configured) answer queries
+    async fn check_storage(&self) -> Web3ProxyResult<()> {
+        if let Some(db_conn) = self.db_conn() {
+            db_conn.execute_unprepared("SELECT 1").await?;
+        }
+
+        if let Some(mut redis_conn) = self.redis_conn().await? {
+            redis::cmd("PING")
+                .query_async::<_, String>(&mut redis_conn)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
@@ -3,27 +3,295 @@
 use super::Web3ProxyApp;
 use crate::frontend::authorization::{Authorization, RequestMetadata, RequestOrMethod};
 use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::frontend::subscriptions::LogsFilter;
 use crate::jsonrpc::JsonRpcForwardedResponse;
 use crate::jsonrpc::JsonRpcRequest;
 use crate::response_cache::JsonRpcResponseData;
+use crate::rpcs::blockchain::Web3ProxyBlock;
+use crate::rpcs::head_events::HeadEvent;
 use crate::rpcs::transactions::TxStatus;
 use axum::extract::ws::Message;
 use ethers::types::U64;
 use futures::future::AbortHandle;
 use futures::future::Abortable;
 use futures::stream::StreamExt;
-use log::trace;
+use hashbrown::HashMap;
+use log::{trace, warn};
+use parking_lot::Mutex;
+use quick_cache_ttl::CacheWithTTL;
+use serde::Deserialize;
 use serde_json::json;
-use std::sync::atomic::{self, AtomicUsize};
+use std::net::IpAddr;
+use std::num::NonZeroU64;
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize};
 use std::sync::Arc;
-use tokio_stream::wrappers::{BroadcastStream, WatchStream};
+use tokio_stream::wrappers::BroadcastStream;
+use ulid::Ulid;
+
+/// most missed heads sent to a resumed newHeads subscription. older heads are skipped the same as for a lagged subscriber
+const MAX_BACKFILL_HEADS: u64 = 128;
+
+pub type WsSessionCache = Arc<CacheWithTTL<Ulid, Arc<WsSession>>>;
+
+#[derive(Debug)]
+struct WsSessionSubscription {
+    params: serde_json::Value,
+    /// the number of the last head sent on a newHeads subscription. 0 if none have been sent
+    last_head: Arc<AtomicU64>,
+}
+
+/// The subscriptions on a websocket.
+/// Kept for `ws_resume_grace_seconds` after the socket closes so that the client can reconnect and pick up where it left off
+#[derive(Debug)]
+pub struct WsSession {
+    pub token: Ulid,
+    /// only the same key (or the same ip for anonymous users) can resume a session
+    rpc_secret_key_id: Option<NonZeroU64>,
+    ip: IpAddr,
+    subscriptions: Mutex<HashMap<U64, WsSessionSubscription>>,
+    /// send streamed responses as `w3p_chunk` notifications. turned on with `w3p_streamChunks`
+    stream_chunks: AtomicBool,
+}
+
+impl WsSession {
+    pub fn new(authorization: &Authorization) -> Self {
+        Self {
+            token: Ulid::new(),
+            rpc_secret_key_id: authorization.checks.rpc_secret_key_id,
+            ip: authorization.ip,
+            subscriptions: Default::default(),
+            stream_chunks: Default::default(),
+        }
+    }
+
+    fn can_resume(&self, authorization: &Authorization) -> bool {
+        match (
+            self.rpc_secret_key_id,
+            authorization.checks.rpc_secret_key_id,
+        ) {
+            (Some(a), Some(b)) => a == b,
+            (None, None) => self.ip == authorization.ip,
+            _ => false,
+        }
+    }
+
+    /// the subscriptions to start again on a resumed socket. sorted by id
+    pub fn subscriptions(&self) -> Vec<(U64, serde_json::Value)> {
+        let mut x: Vec<_> = self
+            .subscriptions
+            .lock()
+            .iter()
+            .map(|(k, v)| (*k, v.params.clone()))
+            .collect();
+
+        x.sort_by_key(|(k, _)| *k);
+
+        x
+    }
+
+    pub fn stream_chunks(&self) -> bool {
+        self.stream_chunks.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_stream_chunks(&self, enabled: bool) {
+        self.stream_chunks.store(enabled, atomic::Ordering::Relaxed);
+    }
+
+    pub fn forget_subscription(&self, subscription_id: &U64) {
+        self.subscriptions.lock().remove(subscription_id);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscriptions.lock().is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.subscriptions.lock().len()
+    }
+
+    /// Remember a subscription so that it can be resumed.
+    /// Returns the counter for the last head sent on it. New subscriptions start at 0
+    fn register(&self, subscription_id: U64, params: &serde_json::Value) -> Arc<AtomicU64> {
+        self.subscriptions
+            .lock()
+            .entry(subscription_id)
+            .or_insert_with(|| WsSessionSubscription {
+                params: params.clone(),
+                last_head: Default::default(),
+            })
+            .last_head
+            .clone()
+    }
+}
+
+/// How much of each header a newHeads subscription sends.
+/// Chosen with the options object in `["newHeads", {"mode": "compact"}]`
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NewHeadsMode {
+    /// the whole header. the same as geth
+    #[default]
+    Full,
+    /// only number, hash, parentHash, timestamp, and baseFeePerGas
+    Compact,
+    /// the whole header first. after that, a JSON merge patch (RFC 7396) against the previous header
+    Delta,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct NewHeadsOptions {
+    mode: NewHeadsMode,
+}
+
+impl NewHeadsMode {
+    /// The mode for `eth_subscribe` params. None if they are not for newHeads
+    fn from_params(params: Option<&serde_json::Value>) -> Web3ProxyResult<Option<Self>> {
+        let params = match params.and_then(|x| x.as_array()) {
+            Some(x) if x.first().and_then(|x| x.as_str()) == Some("newHeads") => x,
+            _ => return Ok(None),
+        };
+
+        match params.get(1) {
+            None if params.len() == 1 => Ok(Some(Self::Full)),
+            Some(options) if params.len() == 2 => {
+                let options: NewHeadsOptions =
+                    serde_json::from_value(options.clone()).map_err(|err| {
+                        Web3ProxyError::InvalidRpcParams(
+                            format!("invalid newHeads options. {}", err).into(),
+                        )
+                    })?;
+
+                Ok(Some(options.mode))
+            }
+            _ => Err(Web3ProxyError::InvalidRpcParams(
+                "newHeads takes at most one options object".into(),
+            )),
+        }
+    }
+}
+
+/// Turns heads into the results of a newHeads subscription
+struct NewHeadsEncoder {
+    mode: NewHeadsMode,
+    /// the last header sent in delta mode
+    previous: Option<serde_json::Value>,
+}
+
+impl NewHeadsEncoder {
+    fn new(mode: NewHeadsMode) -> Self {
+        Self {
+            mode,
+            previous: None,
+        }
+    }
+
+    fn encode(&mut self, head: &Web3ProxyBlock) -> serde_json::Value {
+        match self.mode {
+            NewHeadsMode::Full => json!(head.block),
+            NewHeadsMode::Compact => json!({
+                "number": head.block.number,
+                "hash": head.block.hash,
+                "parentHash": head.block.parent_hash,
+                "timestamp": head.block.timestamp,
+                "baseFeePerGas": head.block.base_fee_per_gas,
+            }),
+            NewHeadsMode::Delta => {
+                let header = json!(head.block);
+
+                let result = match self.previous.as_ref() {
+                    Some(previous) => merge_patch(previous, &header),
+                    None => header.clone(),
+                };
+
+                self.previous = Some(header);
+
+                result
+            }
+        }
+    }
+}
+
+/// A JSON merge patch (RFC 7396) that turns `old` into `new`. Only top level fields are diffed
+fn merge_patch(old: &serde_json::Value, new: &serde_json::Value) -> serde_json::Value {
+    let (old, new) = match (old.as_object(), new.as_object()) {
+        (Some(old), Some(new)) => (old, new),
+        _ => return new.clone(),
+    };
+
+    let mut patch = serde_json::Map::new();
+
+    for (k, v) in new.iter() {
+        if old.get(k) != Some(v) {
+            patch.insert(k.clone(), v.clone());
+        }
+    }
+
+    // null removes a field
+    for k in old.keys() {
+        if !new.contains_key(k) {
+            patch.insert(k.clone(), serde_json::Value::Null);
+        }
+    }
+
+    patch.into()
+}
+
+/// Send one head to a newHeads subscription. Returns false if the client is gone
+async fn send_new_head(
+    app: &Arc<Web3ProxyApp>,
+    authorization: &Arc<Authorization>,
+    subscription_id: U64,
+    new_head: &Web3ProxyBlock,
+    encoder: &mut NewHeadsEncoder,
+    response_sender: &flume::Sender<Message>,
+) -> bool {
+    let subscription_request_metadata = RequestMetadata::new(
+        app,
+        authorization.clone(),
+        RequestOrMethod::Method("eth_subscribe(newHeads)", 0),
+        Some(new_head.number()),
+    )
+    .await;
+
+    // TODO: make a struct for this? using our JsonRpcForwardedResponse won't work because it needs an id
+    let response_json = json!({
+        "jsonrpc": "2.0",
+        "method":"eth_subscription",
+        "params": {
+            "subscription": subscription_id,
+            // TODO: option to include full transaction objects instead of just the hashes?
+            "result": encoder.encode(new_head),
+        },
+    });
+
+    let response_str =
+        serde_json::to_string(&response_json).expect("this should always be valid json");
+
+    // we could use JsonRpcForwardedResponseEnum::num_bytes() here, but since we already have the string, this is easier
+    let response_bytes = response_str.len();
+
+    // TODO: do clients support binary messages?
+    // TODO: can we check a content type header?
+    let response_msg = Message::Text(response_str);
+
+    if response_sender.send_async(response_msg).await.is_err() {
+        // TODO: increment error_response? i don't think so. i think this will happen once every time a client disconnects.
+        return false;
+    };
+
+    subscription_request_metadata.add_response(response_bytes);
+
+    true
+}
 
 impl Web3ProxyApp {
     pub async fn eth_subscribe<'a>(
         self: &'a Arc<Self>,
         authorization: Arc<Authorization>,
-        jsonrpc_request: JsonRpcRequest,
+        mut jsonrpc_request: JsonRpcRequest,
         subscription_count: &'a AtomicUsize,
+        session: &'a WsSession,
         // TODO: taking a sender for Message instead of the exact json we are planning to send feels wrong, but its easier for now
         response_sender: flume::Sender<Message>,
     ) -> Web3ProxyResult<(AbortHandle, JsonRpcForwardedResponse)> {
@@ -35,79 +303,159 @@ impl Web3ProxyApp {
         )
         .await;
 
-        let (subscription_abort_handle, subscription_registration) = AbortHandle::new_pair();
+        let max_subscriptions = self.config.max_subscriptions_per_socket;
+
+        if max_subscriptions > 0 && session.len() >= max_subscriptions {
+            return Err(Web3ProxyError::TooManySubscriptions(max_subscriptions));
+        }
+
+        // the connection's rate limit was already checked. this is the classifier and the per-method limit from the user's tier
+        self.check_request_policy(&authorization, &mut jsonrpc_request, &request_metadata)
+            .await?;
 
         // TODO: this only needs to be unique per connection. we don't need it globably unique
-        // TODO: have a max number of subscriptions per key/ip. have a global max number of subscriptions? how should this be calculated?
+        // TODO: have a global max number of subscriptions? how should this be calculated?
         let subscription_id = subscription_count.fetch_add(1, atomic::Ordering::SeqCst);
         let subscription_id = U64::from(subscription_id as u64);
 
         // save the id so we can use it in the response
         let id = jsonrpc_request.id.clone();
 
+        let subscription_abort_handle = self.spawn_subscription(
+            authorization,
+            session,
+            subscription_id,
+            jsonrpc_request.params.as_ref(),
+            response_sender,
+            false,
+        )?;
+
+        // TODO: do something with subscription_join_handle?
+
+        let response_data = JsonRpcResponseData::from(json!(subscription_id));
+
+        let response = JsonRpcForwardedResponse::from_response_data(response_data, id)
+            .normalize(&jsonrpc_request);
+
+        // TODO: this serializes twice
+        request_metadata.add_response(&response);
+
+        // TODO: make a `SubscriptonHandle(AbortHandle, JoinHandle)` struct?
+        Ok((subscription_abort_handle, response))
+    }
+
+    /// Start a subscription from a closed socket again with the same id.
+    /// newHeads subscriptions first get the heads that were missed while the client was away
+    pub fn resume_subscription(
+        self: &Arc<Self>,
+        authorization: Arc<Authorization>,
+        session: &WsSession,
+        subscription_id: U64,
+        params: &serde_json::Value,
+        response_sender: flume::Sender<Message>,
+    ) -> Web3ProxyResult<AbortHandle> {
+        self.spawn_subscription(
+            authorization,
+            session,
+            subscription_id,
+            Some(params),
+            response_sender,
+            true,
+        )
+    }
+
+    fn spawn_subscription(
+        self: &Arc<Self>,
+        authorization: Arc<Authorization>,
+        session: &WsSession,
+        subscription_id: U64,
+        params: Option<&serde_json::Value>,
+        response_sender: flume::Sender<Message>,
+        resumed: bool,
+    ) -> Web3ProxyResult<AbortHandle> {
+        let (subscription_abort_handle, subscription_registration) = AbortHandle::new_pair();
+
         // TODO: calling json! on every request is probably not fast. but we can only match against
         // TODO: i think we need a stricter EthSubscribeRequest type that JsonRpcRequest can turn into
-        match jsonrpc_request.params.as_ref() {
-            Some(x) if x == &json!(["newHeads"]) => {
-                let head_block_receiver = self.watch_consensus_head_receiver.clone();
+        let new_heads_mode = NewHeadsMode::from_params(params)?;
+
+        let mut logs_filter = LogsFilter::from_params(params)?;
+
+        match (params, new_heads_mode) {
+            (Some(x), Some(mode)) => {
+                // subscribe before looking for missed heads so that nothing is skipped in between
+                let head_events = self.head_events();
                 let app = self.clone();
 
+                let last_head = session.register(subscription_id, x);
+
+                let missed_heads = if resumed {
+                    self.missed_heads(last_head.load(atomic::Ordering::Acquire))
+                } else {
+                    vec![]
+                };
+
                 trace!("newHeads subscription {:?}", subscription_id);
                 tokio::spawn(async move {
-                    let mut head_block_receiver = Abortable::new(
-                        WatchStream::new(head_block_receiver),
+                    let mut head_events = Abortable::new(
+                        BroadcastStream::new(head_events),
                         subscription_registration,
                     );
 
-                    while let Some(new_head) = head_block_receiver.next().await {
-                        let new_head = if let Some(new_head) = new_head {
-                            new_head
-                        } else {
-                            continue;
-                        };
+                    let mut encoder = NewHeadsEncoder::new(mode);
 
-                        let subscription_request_metadata = RequestMetadata::new(
+                    let mut backfilled_to = U64::zero();
+
+                    for new_head in missed_heads.iter() {
+                        if !send_new_head(
                             &app,
-                            authorization.clone(),
-                            RequestOrMethod::Method("eth_subscribe(newHeads)", 0),
-                            Some(new_head.number()),
+                            &authorization,
+                            subscription_id,
+                            new_head,
+                            &mut encoder,
+                            &response_sender,
                         )
-                        .await;
+                        .await
+                        {
+                            return;
+                        }
 
-                        // TODO: make a struct for this? using our JsonRpcForwardedResponse won't work because it needs an id
-                        let response_json = json!({
-                            "jsonrpc": "2.0",
-                            "method":"eth_subscription",
-                            "params": {
-                                "subscription": subscription_id,
-                                // TODO: option to include full transaction objects instead of just the hashes?
-                                "result": new_head.block,
-                            },
-                        });
-
-                        let response_str = serde_json::to_string(&response_json)
-                            .expect("this should always be valid json");
-
-                        // we could use JsonRpcForwardedResponseEnum::num_bytes() here, but since we already have the string, this is easier
-                        let response_bytes = response_str.len();
+                        backfilled_to = *new_head.number();
+                        last_head.store(backfilled_to.as_u64(), atomic::Ordering::Release);
+                    }
 
-                        // TODO: do clients support binary messages?
-                        // TODO: can we check a content type header?
-                        let response_msg = Message::Text(response_str);
+                    while let Some(head_event) = head_events.next().await {
+                        // a lagged subscriber skips to the latest head the same as geth does
+                        let new_head = match head_event {
+                            // backfilled heads are not sent twice
+                            Ok(HeadEvent::NewHead(x)) if x.number() <= &backfilled_to => continue,
+                            Ok(HeadEvent::NewHead(x)) => x,
+                            Ok(HeadEvent::Reorg { new, .. }) => new,
+                            Ok(HeadEvent::Finalized(_)) | Err(_) => continue,
+                        };
 
-                        if response_sender.send_async(response_msg).await.is_err() {
-                            // TODO: increment error_response? i don't think so. i think this will happen once every time a client disconnects.
+                        if !send_new_head(
+                            &app,
+                            &authorization,
+                            subscription_id,
+                            &new_head,
+                            &mut encoder,
+                            &response_sender,
+                        )
+                        .await
+                        {
                             // TODO: cancel this subscription earlier? select on head_block_receiver.next() and an abort handle?
                             break;
-                        };
+                        }
 
-                        subscription_request_metadata.add_response(response_bytes);
+                        // heads still queued for the socket when it closes are lost. a resumed subscription backfills from here
+                        last_head.store(new_head.number().as_u64(), atomic::Ordering::Release);
                     }
 
                     trace!("closed newHeads subscription {:?}", subscription_id);
                 });
             }
-            Some(x) if x == &json!(["newPendingTransactions"]) => {
+            (Some(x), None) if x == &json!(["newPendingTransactions"]) => {
                 let pending_tx_receiver = self.pending_tx_sender.subscribe();
                 let app = self.clone();
 
@@ -171,7 +519,7 @@ impl Web3ProxyApp {
                     );
                 });
             }
-            Some(x) if x == &json!(["newPendingFullTransactions"]) => {
+            (Some(x), None) if x == &json!(["newPendingFullTransactions"]) => {
                 // TODO: too much copy/pasta with newPendingTransactions
                 let pending_tx_receiver = self.pending_tx_sender.subscribe();
                 let app = self.clone();
@@ -234,7 +582,7 @@ impl Web3ProxyApp {
                     );
                 });
             }
-            Some(x) if x == &json!(["newPendingRawTransactions"]) => {
+            (Some(x), None) if x == &json!(["newPendingRawTransactions"]) => {
                 // TODO: too much copy/pasta with newPendingTransactions
                 let pending_tx_receiver = self.pending_tx_sender.subscribe();
                 let app = self.clone();
@@ -300,19 +648,172 @@ impl Web3ProxyApp {
                     );
                 });
             }
+            (Some(_), None) if logs_filter.is_some() => {
+                let logs_filter = logs_filter.take().expect("checked above");
+
+                // every logs subscription shares one upstream request per block
+                let logs_receiver = self.subscriptions.subscribe_logs(self);
+                let app = self.clone();
+
+                let mut logs_receiver = Abortable::new(
+                    BroadcastStream::new(logs_receiver),
+                    subscription_registration,
+                );
+
+                trace!("logs subscription {:?}", subscription_id);
+                tokio::spawn(async move {
+                    while let Some(logs) = logs_receiver.next().await {
+                        let logs = match logs {
+                            Ok(x) => x,
+                            Err(err) => {
+                                // geth closes a lagged logs subscription. we keep going but the skipped logs are lost
+                                warn!("logs subscription {:?} lagged: {:?}", subscription_id, err);
+                                continue;
+                            }
+                        };
+
+                        for log in logs.iter().filter(|x| logs_filter.matches(x)) {
+                            let subscription_request_metadata = RequestMetadata::new(
+                                &app,
+                                authorization.clone(),
+                                RequestOrMethod::Method("eth_subscribe(logs)", 0),
+                                log.block_number.as_ref(),
+                            )
+                            .await;
+
+                            // TODO: make a struct for this? using our JsonRpcForwardedResponse won't work because it needs an id
+                            let response_json = json!({
+                                "jsonrpc": "2.0",
+                                "method": "eth_subscription",
+                                "params": {
+                                    "subscription": subscription_id,
+                                    "result": log,
+                                },
+                            });
+
+                            let response_str = serde_json::to_string(&response_json)
+                                .expect("this should always be valid json");
+
+                            let response_bytes = response_str.len();
+
+                            let response_msg = Message::Text(response_str);
+
+                            if response_sender.send_async(response_msg).await.is_err() {
+                                trace!("closed logs subscription {:?}", subscription_id);
+                                return;
+                            };
+
+                            subscription_request_metadata.add_response(response_bytes);
+                        }
+                    }
+
+                    trace!("closed logs subscription {:?}", subscription_id);
+                });
+            }
             _ => return Err(Web3ProxyError::NotImplemented),
         }
 
-        // TODO: do something with subscription_join_handle?
+        if let Some(params) = params {
+            session.register(subscription_id, params);
+        }
 
-        let response_data = JsonRpcResponseData::from(json!(subscription_id));
+        Ok(subscription_abort_handle)
+    }
 
-        let response = JsonRpcForwardedResponse::from_response_data(response_data, id);
+    /// Cached heads after `last_head` up to the current head. Empty if no heads were sent yet
+    fn missed_heads(&self, last_head: u64) -> Vec<Web3ProxyBlock> {
+        if last_head == 0 {
+            return vec![];
+        }
 
-        // TODO: this serializes twice
-        request_metadata.add_response(&response);
+        let head_num = match self.watch_consensus_head_receiver.borrow().as_ref() {
+            Some(x) => x.number().as_u64(),
+            None => return vec![],
+        };
 
-        // TODO: make a `SubscriptonHandle(AbortHandle, JoinHandle)` struct?
-        Ok((subscription_abort_handle, response))
+        let first = (last_head + 1).max(head_num.saturating_sub(MAX_BACKFILL_HEADS - 1));
+
+        (first..=head_num)
+            .filter_map(|num| self.balanced_rpcs.cached_block_by_number(&num.into()))
+            .collect()
+    }
+
+    /// A new session for a socket, or the closed session that `resume` is the token for.
+    /// A closed session can only be resumed once
+    pub fn open_ws_session(
+        &self,
+        authorization: &Authorization,
+        resume: Option<&str>,
+    ) -> Web3ProxyResult<Arc<WsSession>> {
+        let token = match resume {
+            None => return Ok(Arc::new(WsSession::new(authorization))),
+            Some(x) => x,
+        };
+
+        let expired =
+            || Web3ProxyError::InvalidParam("resume", "unknown or expired resume token".into());
+
+        let token: Ulid = token.parse().map_err(|_| expired())?;
+
+        let session = self.ws_sessions.get(&token).ok_or_else(expired)?;
+
+        if !session.can_resume(authorization) {
+            return Err(expired());
+        }
+
+        // this fails if another socket already resumed the session
+        if !self.ws_sessions.remove(&token) {
+            return Err(expired());
+        }
+
+        trace!("resuming websocket session {}", token);
+
+        Ok(session)
+    }
+
+    /// Keep a closed socket's subscriptions for `ws_resume_grace_seconds`
+    pub fn close_ws_session(&self, session: Arc<WsSession>) {
+        if self.config.ws_resume_grace_seconds == 0 || session.is_empty() {
+            return;
+        }
+
+        let token = session.token;
+
+        if self.ws_sessions.try_insert(token, session).is_err() {
+            warn!("unable to save websocket session {}", token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_heads_mode() {
+        assert_eq!(
+            NewHeadsMode::from_params(Some(&json!(["newHeads"]))).unwrap(),
+            Some(NewHeadsMode::Full)
+        );
+        assert_eq!(
+            NewHeadsMode::from_params(Some(&json!(["newHeads", {"mode": "delta"}]))).unwrap(),
+            Some(NewHeadsMode::Delta)
+        );
+        assert_eq!(
+            NewHeadsMode::from_params(Some(&json!(["newPendingTransactions"]))).unwrap(),
+            None
+        );
+        assert!(NewHeadsMode::from_params(Some(&json!(["newHeads", {"mode": "tiny"}]))).is_err());
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let old = json!({"number": "0x1", "miner": "0xaa", "extraData": "0x"});
+        let new = json!({"number": "0x2", "miner": "0xaa", "baseFeePerGas": "0x7"});
+
+        assert_eq!(
+            merge_patch(&old, &new),
+            json!({"number": "0x2", "baseFeePerGas": "0x7", "extraData": null})
+        );
     }
 }
@@ -0,0 +1,249 @@
+use anyhow::Context;
+use argh::FromArgs;
+use ethers::types::Bytes;
+use log::{info, warn};
+use migration::sea_orm::DatabaseConnection;
+use migration::{Migrator, MigratorTrait};
+use redis_rate_limiter::{redis, DeadpoolRuntime, RedisConfig, RedisConnection};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, create_dir_all};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use web3_proxy::config::TopConfig;
+
+/// Bump this when the layout of a backup changes
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+pub const CONFIG_FILE: &str = "config.toml";
+pub const DATABASE_FILE: &str = "database.sql";
+pub const REDIS_FILE: &str = "redis.json";
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BackupManifest {
+    pub format_version: u32,
+    /// the version of web3_proxy that made the backup
+    pub web3_proxy_version: String,
+    pub chain_id: u64,
+    /// unix epoch seconds
+    pub created_at: i64,
+    /// the newest migration applied to the database. None if the database was skipped
+    pub migration: Option<String>,
+    pub database: bool,
+    pub redis: bool,
+}
+
+/// A redis key saved with DUMP so that RESTORE can recreate it exactly
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RedisEntry {
+    pub key: String,
+    /// milliseconds left when the backup was made. 0 never expires
+    pub ttl_ms: u64,
+    pub dump: Bytes,
+}
+
+/// The redis keys that are worth keeping: read-only switches, revoked presigned urls, and the rate limiters' counters
+pub fn redis_patterns(chain_id: u64) -> Vec<String> {
+    vec![
+        "read_only:*".to_string(),
+        "presigned_url_revoked:*".to_string(),
+        "web3_proxy:rrl:*".to_string(),
+        format!("web3_proxy:{}:rrl:*", chain_id),
+    ]
+}
+
+pub async fn redis_conn(top_config: &TopConfig) -> anyhow::Result<Option<RedisConnection>> {
+    match top_config.app.volatile_redis_url.as_ref() {
+        Some(redis_url) => {
+            let redis_pool = RedisConfig::from_url(redis_url)
+                .builder()?
+                .max_size(1)
+                .runtime(DeadpoolRuntime::Tokio1)
+                .build()?;
+
+            Ok(Some(redis_pool.get().await?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The connection arguments for the mysql command line tools. The password goes in MYSQL_PWD so it stays out of `ps`
+pub fn mysql_command(program: &str, db_url: &str) -> anyhow::Result<(Command, String)> {
+    let db_url = url::Url::parse(db_url).context("parsing db_url")?;
+
+    let db_name = db_url.path().trim_start_matches('/').to_string();
+
+    anyhow::ensure!(!db_name.is_empty(), "db_url needs a database name");
+
+    let mut cmd = Command::new(program);
+
+    cmd.arg(format!(
+        "--host={}",
+        db_url.host_str().unwrap_or("127.0.0.1")
+    ))
+    .arg(format!("--port={}", db_url.port().unwrap_or(3306)));
+
+    if !db_url.username().is_empty() {
+        cmd.arg(format!("--user={}", db_url.username()));
+    }
+
+    if let Some(password) = db_url.password() {
+        cmd.env("MYSQL_PWD", password);
+    }
+
+    Ok((cmd, db_name))
+}
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// Save the database, config, and redis state of this deployment. Restore it with `restore`.
+#[argh(subcommand, name = "backup")]
+pub struct BackupSubCommand {
+    /// where to write the backup. a new directory named after the current time is created in here
+    #[argh(positional, default = "\"./data/backups\".to_string()")]
+    output_dir: String,
+
+    /// do not dump the database
+    #[argh(switch)]
+    skip_db: bool,
+
+    /// do not save redis keys
+    #[argh(switch)]
+    skip_redis: bool,
+
+    /// the mysqldump binary
+    #[argh(option, default = "\"mysqldump\".to_string()")]
+    mysqldump: String,
+}
+
+impl BackupSubCommand {
+    pub async fn main(
+        self,
+        top_config: TopConfig,
+        top_config_path: PathBuf,
+        db_url: Option<String>,
+        db_conn: Option<&DatabaseConnection>,
+    ) -> anyhow::Result<()> {
+        let now = chrono::Utc::now().timestamp();
+
+        let backup_dir = Path::new(&self.output_dir).join(now.to_string());
+
+        create_dir_all(&backup_dir)?;
+
+        // the config and the database dump both have secrets in them. keep backups somewhere safe
+        fs::copy(&top_config_path, backup_dir.join(CONFIG_FILE)).context("copying the config")?;
+
+        let mut migration = None;
+        let mut database = false;
+
+        if !self.skip_db {
+            let db_url = db_url.context("'--config' (with a db) or '--db-url' is required to back up the database. use --skip-db to skip it")?;
+            let db_conn = db_conn.context("a db connection is required")?;
+
+            migration = Migrator::get_migration_models(db_conn)
+                .await?
+                .pop()
+                .map(|x| x.version);
+
+            let (mut cmd, db_name) = mysql_command(&self.mysqldump, &db_url)?;
+
+            // --single-transaction gives a consistent snapshot without locking the tables
+            let status = cmd
+                .arg("--single-transaction")
+                .arg("--routines")
+                .arg("--no-create-db")
+                .arg(format!(
+                    "--result-file={}",
+                    backup_dir.join(DATABASE_FILE).display()
+                ))
+                .arg(db_name)
+                .status()
+                .await
+                .context("running mysqldump")?;
+
+            anyhow::ensure!(status.success(), "mysqldump failed: {}", status);
+
+            database = true;
+
+            info!("dumped the database. migration={:?}", migration);
+        }
+
+        let mut redis = false;
+
+        if !self.skip_redis {
+            match redis_conn(&top_config).await? {
+                Some(mut redis_conn) => {
+                    let mut entries = vec![];
+
+                    for pattern in redis_patterns(top_config.app.chain_id) {
+                        let mut cursor = 0u64;
+
+                        loop {
+                            let (next_cursor, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                                .arg(cursor)
+                                .arg("MATCH")
+                                .arg(&pattern)
+                                .arg("COUNT")
+                                .arg(1000)
+                                .query_async(&mut redis_conn)
+                                .await?;
+
+                            for key in keys {
+                                let (ttl_ms, dump): (i64, Option<Vec<u8>>) = redis::pipe()
+                                    .cmd("PTTL")
+                                    .arg(&key)
+                                    .cmd("DUMP")
+                                    .arg(&key)
+                                    .query_async(&mut redis_conn)
+                                    .await?;
+
+                                // the key expired while we were scanning
+                                let Some(dump) = dump else { continue };
+
+                                entries.push(RedisEntry {
+                                    key,
+                                    ttl_ms: ttl_ms.max(0) as u64,
+                                    dump: dump.into(),
+                                });
+                            }
+
+                            if next_cursor == 0 {
+                                break;
+                            }
+                            cursor = next_cursor;
+                        }
+                    }
+
+                    info!("saved {} redis keys", entries.len());
+
+                    fs::write(
+                        backup_dir.join(REDIS_FILE),
+                        serde_json::to_string_pretty(&entries)?,
+                    )?;
+
+                    redis = true;
+                }
+                None => warn!("no volatile_redis_url. skipping redis"),
+            }
+        }
+
+        // the manifest is written last. a backup without one did not finish
+        let manifest = BackupManifest {
+            format_version: BACKUP_FORMAT_VERSION,
+            web3_proxy_version: env!("CARGO_PKG_VERSION").to_string(),
+            chain_id: top_config.app.chain_id,
+            created_at: now,
+            migration,
+            database,
+            redis,
+        };
+
+        fs::write(
+            backup_dir.join(MANIFEST_FILE),
+            serde_json::to_string_pretty(&manifest)?,
+        )?;
+
+        info!("saved backup to {}", backup_dir.display());
+
+        Ok(())
+    }
+}
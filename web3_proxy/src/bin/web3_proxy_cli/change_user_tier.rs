@@ -6,6 +6,7 @@ use migration::sea_orm::{
     self, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
     QueryFilter,
 };
+use web3_proxy::app::parse_method_requests_per_second;
 
 /// change a user's tier.
 #[derive(FromArgs, PartialEq, Eq, Debug)]
@@ -22,6 +23,26 @@ pub struct ChangeUserTierSubCommand {
     /// the amount of concurret requests to allow from a single user
     #[argh(option)]
     max_concurrent_requests: Option<u32>,
+
+    /// comma separated "method=limit" pairs like "eth_getLogs=10, eth_call=500". an empty string removes them
+    #[argh(option)]
+    method_requests_per_second: Option<String>,
+
+    /// bytes each key can send and receive per month. 0 removes the quota
+    #[argh(option)]
+    max_monthly_bytes: Option<u64>,
+
+    /// requests a user can make at once after a quiet spell. 0 uses max_requests_per_period
+    #[argh(option)]
+    burst_capacity: Option<u64>,
+
+    /// requests per rate limit period that refill the burst. 0 uses max_requests_per_period
+    #[argh(option)]
+    sustained_requests_per_period: Option<u64>,
+
+    /// the largest response that any one request can get. 0 only uses the proxy's per-method limits
+    #[argh(option)]
+    max_response_bytes: Option<u64>,
 }
 
 impl ChangeUserTierSubCommand {
@@ -58,6 +79,94 @@ impl ChangeUserTierSubCommand {
             }
         }
 
+        if let Some(method_requests_per_second) = self.method_requests_per_second {
+            // check that it parses before saving it
+            let limits = parse_method_requests_per_second(&method_requests_per_second)?;
+
+            let method_requests_per_second = if limits.is_empty() {
+                None
+            } else {
+                Some(method_requests_per_second)
+            };
+
+            if user_tier.method_requests_per_second
+                == sea_orm::Set(method_requests_per_second.clone())
+            {
+                info!("method_requests_per_second already has this value");
+            } else {
+                user_tier.method_requests_per_second = sea_orm::Set(method_requests_per_second);
+
+                info!("changed method_requests_per_second")
+            }
+        }
+
+        if let Some(max_monthly_bytes) = self.max_monthly_bytes {
+            let max_monthly_bytes = if max_monthly_bytes == 0 {
+                None
+            } else {
+                Some(max_monthly_bytes)
+            };
+
+            if user_tier.max_monthly_bytes == sea_orm::Set(max_monthly_bytes) {
+                info!("max_monthly_bytes already has this value");
+            } else {
+                user_tier.max_monthly_bytes = sea_orm::Set(max_monthly_bytes);
+
+                info!("changed max_monthly_bytes")
+            }
+        }
+
+        if let Some(burst_capacity) = self.burst_capacity {
+            let burst_capacity = if burst_capacity == 0 {
+                None
+            } else {
+                Some(burst_capacity)
+            };
+
+            if user_tier.burst_capacity == sea_orm::Set(burst_capacity) {
+                info!("burst_capacity already has this value");
+            } else {
+                user_tier.burst_capacity = sea_orm::Set(burst_capacity);
+
+                info!("changed burst_capacity")
+            }
+        }
+
+        if let Some(sustained_requests_per_period) = self.sustained_requests_per_period {
+            let sustained_requests_per_period = if sustained_requests_per_period == 0 {
+                None
+            } else {
+                Some(sustained_requests_per_period)
+            };
+
+            if user_tier.sustained_requests_per_period
+                == sea_orm::Set(sustained_requests_per_period)
+            {
+                info!("sustained_requests_per_period already has this value");
+            } else {
+                user_tier.sustained_requests_per_period =
+                    sea_orm::Set(sustained_requests_per_period);
+
+                info!("changed sustained_requests_per_period")
+            }
+        }
+
+        if let Some(max_response_bytes) = self.max_response_bytes {
+            let max_response_bytes = if max_response_bytes == 0 {
+                None
+            } else {
+                Some(max_response_bytes)
+            };
+
+            if user_tier.max_response_bytes == sea_orm::Set(max_response_bytes) {
+                info!("max_response_bytes already has this value");
+            } else {
+                user_tier.max_response_bytes = sea_orm::Set(max_response_bytes);
+
+                info!("changed max_response_bytes")
+            }
+        }
+
         let user_tier = user_tier.save(db_conn).await?;
 
         debug!("new user_tier: {:#?}", user_tier);
@@ -6,8 +6,7 @@ use migration::sea_orm::{
     self, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
     QueryFilter,
 };
-use uuid::Uuid;
-use web3_proxy::frontend::authorization::RpcSecretKey;
+use web3_proxy::frontend::authorization::{RpcKeyHasher, RpcSecretKey};
 
 /// change a user's tier.
 #[derive(FromArgs, PartialEq, Eq, Debug)]
@@ -25,9 +24,11 @@ pub struct ChangeUserTierByKeySubCommand {
 impl ChangeUserTierByKeySubCommand {
     // TODO: don't expose the RpcSecretKeys at all. Better to take a user/key id. this is definitely most convenient
 
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
-        let rpc_secret_key: Uuid = self.rpc_secret_key.into();
-
+    pub async fn main(
+        self,
+        db_conn: &DatabaseConnection,
+        rpc_key_hasher: &RpcKeyHasher,
+    ) -> anyhow::Result<()> {
         let user_tier = user_tier::Entity::find()
             .filter(user_tier::Column::Title.eq(self.user_tier_title))
             .one(db_conn)
@@ -39,7 +40,7 @@ impl ChangeUserTierByKeySubCommand {
         // use the rpc secret key to get the user
         let user = user::Entity::find()
             .inner_join(rpc_key::Entity)
-            .filter(rpc_key::Column::SecretKey.eq(rpc_secret_key))
+            .filter(rpc_key_hasher.condition(self.rpc_secret_key))
             .one(db_conn)
             .await?
             .context("No user found with that key")?;
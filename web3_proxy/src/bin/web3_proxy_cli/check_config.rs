@@ -18,13 +18,20 @@ impl CheckConfigSubCommand {
 
         info!("Loading config @ {}", self.path);
         let top_config: String = fs::read_to_string(self.path)?;
-        let top_config: TopConfig = toml::from_str(&top_config)?;
+        let top_config = TopConfig::from_toml(&top_config)?;
+
+        if let Some(profile) = top_config.app.profile.as_ref() {
+            info!("using config profile {}", profile);
+        }
 
         // TODO: pretty print
         info!("config: {:#?}", top_config);
 
         if top_config.app.db_url.is_none() {
             warn!("app.db_url is not set! Some features disabled")
+        } else if top_config.app.rpc_key_hash_keyfile.is_none() {
+            num_errors += 1;
+            error!("app.rpc_key_hash_keyfile must be set when app.db_url is set")
         }
 
         match top_config.app.public_requests_per_period {
@@ -6,7 +6,7 @@ use log::info;
 use migration::sea_orm::{self, ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter};
 use ulid::Ulid;
 use uuid::Uuid;
-use web3_proxy::frontend::authorization::RpcSecretKey;
+use web3_proxy::frontend::authorization::{RpcKeyHasher, RpcSecretKey};
 
 #[derive(FromArgs, PartialEq, Debug, Eq)]
 /// Create a new user and api key
@@ -29,7 +29,11 @@ pub struct CreateKeySubCommand {
 }
 
 impl CreateKeySubCommand {
-    pub async fn main(self, db: &sea_orm::DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(
+        self,
+        db: &sea_orm::DatabaseConnection,
+        rpc_key_hasher: &RpcKeyHasher,
+    ) -> anyhow::Result<()> {
         // TODO: would be nice to use the fixed array instead of a Vec in the entities
         // take a simple String. If it starts with 0x, parse as address. otherwise convert ascii to hex
         let address: Vec<u8> = if self.address.starts_with("0x") {
@@ -62,13 +66,15 @@ impl CreateKeySubCommand {
         // create a key for the new user
         let uk = rpc_key::ActiveModel {
             user_id: sea_orm::Set(u.id),
-            secret_key: sea_orm::Set(rpc_secret_key.into()),
+            secret_key: sea_orm::Set(None),
+            secret_key_hash: sea_orm::Set(Some(rpc_key_hasher.hash(rpc_secret_key))),
             description: sea_orm::Set(self.description),
             ..Default::default()
         };
 
         let _uk = uk.save(db).await.context("Failed saving new user key")?;
 
+        // only the hash was saved. this is the only time the key is shown
         info!("user key as ULID: {}", Ulid::from(rpc_secret_key));
         info!("user key as UUID: {}", Uuid::from(rpc_secret_key));
 
@@ -6,7 +6,8 @@ use log::info;
 use migration::sea_orm::{self, ActiveModelTrait, TransactionTrait};
 use ulid::Ulid;
 use uuid::Uuid;
-use web3_proxy::frontend::authorization::RpcSecretKey;
+use web3_proxy::frontend::authorization::{RpcKeyHasher, RpcSecretKey};
+use web3_proxy::row_encryption::RowCipher;
 
 #[derive(FromArgs, PartialEq, Debug, Eq)]
 /// Create a new user and api key
@@ -33,7 +34,12 @@ pub struct CreateUserSubCommand {
 }
 
 impl CreateUserSubCommand {
-    pub async fn main(self, db: &sea_orm::DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(
+        self,
+        db: &sea_orm::DatabaseConnection,
+        row_cipher: Option<&RowCipher>,
+        rpc_key_hasher: &RpcKeyHasher,
+    ) -> anyhow::Result<()> {
         let txn = db.begin().await?;
 
         // TODO: would be nice to use the fixed array instead of a Vec in the entities
@@ -53,10 +59,15 @@ impl CreateUserSubCommand {
             bytes.try_into().expect("Bytes can always be a Vec<u8>")
         };
 
+        let email = match (self.email, row_cipher) {
+            (Some(email), Some(row_cipher)) => Some(row_cipher.encrypt(&email)?),
+            (email, _) => email,
+        };
+
         // TODO: get existing or create a new one
         let u = user::ActiveModel {
             address: sea_orm::Set(address),
-            email: sea_orm::Set(self.email),
+            email: sea_orm::Set(email),
             ..Default::default()
         };
 
@@ -73,7 +84,8 @@ impl CreateUserSubCommand {
         // create a key for the new user
         let uk = rpc_key::ActiveModel {
             user_id: u.id,
-            secret_key: sea_orm::Set(rpc_secret_key.into()),
+            secret_key: sea_orm::Set(None),
+            secret_key_hash: sea_orm::Set(Some(rpc_key_hasher.hash(rpc_secret_key))),
             description: sea_orm::Set(self.description),
             ..Default::default()
         };
@@ -83,6 +95,7 @@ impl CreateUserSubCommand {
 
         txn.commit().await?;
 
+        // only the hash was saved. this is the only time the key is shown
         info!("user key as ULID: {}", Ulid::from(rpc_secret_key));
         info!("user key as UUID: {}", Uuid::from(rpc_secret_key));
 
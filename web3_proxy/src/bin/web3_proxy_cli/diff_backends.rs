@@ -0,0 +1,672 @@
+//! Replay a sample of recent read requests against the current backends and a candidate set (like nodes on a new client version).
+//! Every request is sent to both sets at the same time and their responses are compared field by field.
+//!
+//! The corpus is the most recent payload samples from kafka (see `payload_samples_per_million`) or a file with one sample per line.
+//! Requests that change state, subscriptions, filters, and samples with redacted params are skipped.
+//! "latest" and "pending" are pinned to a block that both sets have so that a new head block doesn't show up as a mismatch.
+use anyhow::Context;
+use argh::FromArgs;
+use futures::stream::{self, StreamExt};
+use log::{info, warn};
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::{ClientConfig, Message, Offset, TopicPartitionList};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use web3_proxy::app::STATE_CHANGING_METHODS;
+use web3_proxy::config::TopConfig;
+use web3_proxy::stats::payload_sample::REDACTED;
+
+/// methods with answers that change every block or that differ between node versions on purpose
+const SKIPPED_METHODS: &[&str] = &[
+    "eth_blockNumber",
+    "eth_gasPrice",
+    "eth_getFilterChanges",
+    "eth_getFilterLogs",
+    "eth_maxPriorityFeePerGas",
+    "eth_newBlockFilter",
+    "eth_newFilter",
+    "eth_newPendingTransactionFilter",
+    "eth_subscribe",
+    "eth_syncing",
+    "eth_uninstallFilter",
+    "eth_unsubscribe",
+    "net_peerCount",
+    "web3_clientVersion",
+];
+
+/// only this many field mismatches are kept for each example
+const MAX_FIELD_MISMATCHES: usize = 10;
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// Replay sampled requests against two sets of backends and report the differences.
+#[argh(subcommand, name = "diff_backends")]
+pub struct DiffBackendsSubCommand {
+    #[argh(option)]
+    /// a backend that is in use now. can be given more than once
+    current: Vec<String>,
+
+    #[argh(option)]
+    /// a backend to compare against the current ones. can be given more than once
+    candidate: Vec<String>,
+
+    #[argh(option)]
+    /// a file with one payload sample per line. if not set, the most recent samples are read from kafka
+    corpus: Option<PathBuf>,
+
+    #[argh(option, default = "1000")]
+    /// how many samples to read
+    samples: usize,
+
+    #[argh(option)]
+    /// the block to pin "latest" and "pending" to. defaults to a few blocks behind the lowest head of all the backends
+    block: Option<u64>,
+
+    #[argh(option, default = "16")]
+    /// how many requests to have in flight at once
+    parallelism: usize,
+
+    #[argh(option)]
+    /// save the full report as json to this file
+    report: Option<PathBuf>,
+
+    #[argh(option, default = "50")]
+    /// the most mismatched requests to put in the report
+    max_examples: usize,
+
+    #[argh(option, default = "0")]
+    /// exit with an error if more requests (in parts per thousand) than this do not match
+    max_mismatch_permille: u64,
+
+    #[argh(option)]
+    /// exit with an error if the candidates' p99 latency is more than this percent higher than the current backends'
+    max_latency_increase_percent: Option<u64>,
+}
+
+/// The parts of a payload sample that are replayed
+#[derive(Debug, Deserialize)]
+struct Sample {
+    #[serde(default)]
+    chain_id: Option<u64>,
+    method: String,
+    #[serde(default)]
+    params: serde_json::Value,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct FieldMismatch {
+    /// like `result.transactions[3].gas`
+    path: String,
+    current: serde_json::Value,
+    candidate: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+struct Example {
+    method: String,
+    params: serde_json::Value,
+    mismatches: Vec<FieldMismatch>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Latency {
+    current_p50_ms: u64,
+    current_p99_ms: u64,
+    candidate_p50_ms: u64,
+    candidate_p99_ms: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct MethodReport {
+    requests: usize,
+    mismatched: usize,
+    /// one of the backends could not be reached or did not return json
+    failed: usize,
+    latency: Latency,
+}
+
+#[derive(Debug, Serialize)]
+struct DiffReport {
+    current: Vec<String>,
+    candidate: Vec<String>,
+    pinned_block: u64,
+    requests: usize,
+    mismatched: usize,
+    failed: usize,
+    latency: Latency,
+    methods: BTreeMap<String, MethodReport>,
+    examples: Vec<Example>,
+}
+
+/// What happened to one replayed request
+struct Replayed {
+    method: String,
+    params: serde_json::Value,
+    current_ms: u64,
+    candidate_ms: u64,
+    outcome: anyhow::Result<Vec<FieldMismatch>>,
+}
+
+impl DiffBackendsSubCommand {
+    pub async fn main(self, top_config: Option<TopConfig>) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.current.is_empty() && !self.candidate.is_empty(),
+            "at least one --current and one --candidate backend are needed"
+        );
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(60))
+            .build()?;
+
+        let chain_id = top_config.as_ref().map(|x| x.app.chain_id);
+
+        let samples = if let Some(corpus) = self.corpus.as_ref() {
+            let corpus = std::fs::read_to_string(corpus)
+                .with_context(|| format!("reading {}", corpus.display()))?;
+
+            corpus
+                .lines()
+                .filter(|x| !x.trim().is_empty())
+                .map(|x| serde_json::from_str(x).context("parsing a sample"))
+                .take(self.samples)
+                .collect::<anyhow::Result<Vec<Sample>>>()?
+        } else {
+            let top_config =
+                top_config.context("--config is required to read samples from kafka")?;
+
+            kafka_samples(&top_config, self.samples).await?
+        };
+
+        let num_samples = samples.len();
+
+        let samples: Vec<Sample> = samples
+            .into_iter()
+            .filter(|x| chain_id.is_none() || x.chain_id.is_none() || x.chain_id == chain_id)
+            .filter(replayable)
+            .collect();
+
+        info!(
+            "replaying {} of {} samples. the rest change state or can't be compared",
+            samples.len(),
+            num_samples
+        );
+
+        anyhow::ensure!(!samples.is_empty(), "no samples to replay");
+
+        let pinned_block = match self.block {
+            Some(x) => x,
+            None => {
+                let mut lowest = u64::MAX;
+
+                for rpc in self.current.iter().chain(self.candidate.iter()) {
+                    let x = block_number(&client, rpc)
+                        .await
+                        .with_context(|| format!("getting the head block of {}", rpc))?;
+
+                    lowest = lowest.min(x);
+                }
+
+                // a few blocks back so that a reorg doesn't change the answers part way through
+                lowest.saturating_sub(2)
+            }
+        };
+
+        info!("pinning latest to block {}", pinned_block);
+
+        let replayed: Vec<Replayed> = stream::iter(samples.into_iter().enumerate())
+            .map(|(i, sample)| {
+                let current = &self.current[i % self.current.len()];
+                let candidate = &self.candidate[i % self.candidate.len()];
+
+                replay(&client, current, candidate, sample, pinned_block)
+            })
+            .buffer_unordered(self.parallelism.max(1))
+            .collect()
+            .await;
+
+        let report = self.build_report(pinned_block, replayed);
+
+        for (method, x) in report.methods.iter() {
+            info!(
+                "{}: {} requests. {} mismatched. {} failed. p99 {}ms -> {}ms",
+                method,
+                x.requests,
+                x.mismatched,
+                x.failed,
+                x.latency.current_p99_ms,
+                x.latency.candidate_p99_ms
+            );
+        }
+
+        info!(
+            "{} requests. {} mismatched. {} failed. p50 {}ms -> {}ms. p99 {}ms -> {}ms",
+            report.requests,
+            report.mismatched,
+            report.failed,
+            report.latency.current_p50_ms,
+            report.latency.candidate_p50_ms,
+            report.latency.current_p99_ms,
+            report.latency.candidate_p99_ms
+        );
+
+        if let Some(path) = self.report.as_ref() {
+            std::fs::write(path, serde_json::to_string_pretty(&report)?)
+                .with_context(|| format!("writing {}", path.display()))?;
+
+            info!("saved the report to {}", path.display());
+        }
+
+        let bad = (report.mismatched + report.failed) as u64;
+
+        anyhow::ensure!(
+            bad * 1000 <= report.requests as u64 * self.max_mismatch_permille,
+            "{} of {} requests did not match",
+            bad,
+            report.requests
+        );
+
+        if let Some(max_increase) = self.max_latency_increase_percent {
+            let allowed = report.latency.current_p99_ms * (100 + max_increase) / 100;
+
+            anyhow::ensure!(
+                report.latency.candidate_p99_ms <= allowed,
+                "candidate p99 of {}ms is more than {}% over {}ms",
+                report.latency.candidate_p99_ms,
+                max_increase,
+                report.latency.current_p99_ms
+            );
+        }
+
+        Ok(())
+    }
+
+    fn build_report(&self, pinned_block: u64, replayed: Vec<Replayed>) -> DiffReport {
+        let mut methods: BTreeMap<String, MethodReport> = BTreeMap::new();
+        let mut latencies: BTreeMap<String, (Vec<u64>, Vec<u64>)> = BTreeMap::new();
+        let mut examples = vec![];
+
+        let requests = replayed.len();
+        let mut mismatched = 0;
+        let mut failed = 0;
+
+        for x in replayed {
+            let method_report = methods.entry(x.method.clone()).or_default();
+
+            method_report.requests += 1;
+
+            let mismatches = match x.outcome {
+                Ok(mismatches) => mismatches,
+                Err(err) => {
+                    warn!("{} failed: {:#}", x.method, err);
+
+                    method_report.failed += 1;
+                    failed += 1;
+                    continue;
+                }
+            };
+
+            let method_latencies = latencies.entry(x.method.clone()).or_default();
+            method_latencies.0.push(x.current_ms);
+            method_latencies.1.push(x.candidate_ms);
+
+            if mismatches.is_empty() {
+                continue;
+            }
+
+            method_report.mismatched += 1;
+            mismatched += 1;
+
+            if examples.len() < self.max_examples {
+                examples.push(Example {
+                    method: x.method,
+                    params: x.params,
+                    mismatches,
+                });
+            }
+        }
+
+        let mut all_current = vec![];
+        let mut all_candidate = vec![];
+
+        for (method, (current, candidate)) in latencies {
+            all_current.extend_from_slice(&current);
+            all_candidate.extend_from_slice(&candidate);
+
+            if let Some(x) = methods.get_mut(&method) {
+                x.latency = latency(current, candidate);
+            }
+        }
+
+        DiffReport {
+            current: self.current.clone(),
+            candidate: self.candidate.clone(),
+            pinned_block,
+            requests,
+            mismatched,
+            failed,
+            latency: latency(all_current, all_candidate),
+            methods,
+            examples,
+        }
+    }
+}
+
+/// Read about `num` of the newest samples from every partition of the payload sample topic
+async fn kafka_samples(top_config: &TopConfig, num: usize) -> anyhow::Result<Vec<Sample>> {
+    let kafka_brokers = top_config
+        .app
+        .kafka_urls
+        .as_ref()
+        .context("top_config.app.kafka_urls is required")?;
+
+    let topic = top_config.app.payload_sample_topic.as_str();
+
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", kafka_brokers)
+        .set("enable.partition.eof", "false")
+        .set("security.protocol", &top_config.app.kafka_protocol)
+        .set("session.timeout.ms", "6000")
+        .set("enable.auto.commit", "false")
+        .create()
+        .context("kafka consumer creation failed")?;
+
+    let timeout = Duration::from_secs(30);
+
+    let metadata = consumer
+        .fetch_metadata(Some(topic), timeout)
+        .context("fetching kafka metadata")?;
+
+    let partitions: Vec<i32> = metadata
+        .topics()
+        .iter()
+        .filter(|x| x.name() == topic)
+        .flat_map(|x| x.partitions().iter().map(|x| x.id()))
+        .collect();
+
+    anyhow::ensure!(!partitions.is_empty(), "topic {} has no partitions", topic);
+
+    let per_partition = (num / partitions.len() + 1) as i64;
+
+    let mut assignment = TopicPartitionList::new();
+    let mut remaining = 0;
+
+    for partition in partitions {
+        let (low, high) = consumer
+            .fetch_watermarks(topic, partition, timeout)
+            .with_context(|| format!("fetching watermarks for partition {}", partition))?;
+
+        let start = low.max(high - per_partition);
+
+        if start < high {
+            assignment.add_partition_offset(topic, partition, Offset::Offset(start))?;
+            remaining += (high - start) as usize;
+        }
+    }
+
+    consumer.assign(&assignment)?;
+
+    let mut samples = vec![];
+
+    while remaining > 0 && samples.len() < num {
+        let msg = match tokio::time::timeout(timeout, consumer.recv()).await {
+            Ok(x) => x?,
+            Err(_) => {
+                warn!(
+                    "timed out waiting for kafka. {} samples were read",
+                    samples.len()
+                );
+                break;
+            }
+        };
+
+        remaining -= 1;
+
+        match msg.payload().map(serde_json::from_slice::<Sample>) {
+            Some(Ok(x)) => samples.push(x),
+            Some(Err(err)) => warn!("unable to parse sample at {}: {}", msg.offset(), err),
+            None => {}
+        }
+    }
+
+    info!("read {} samples from {}", samples.len(), topic);
+
+    Ok(samples)
+}
+
+/// Replaying the sample should get the same answer from any healthy node
+fn replayable(sample: &Sample) -> bool {
+    let method = sample.method.as_str();
+
+    !STATE_CHANGING_METHODS.contains(&method)
+        && !SKIPPED_METHODS.contains(&method)
+        && !has_redacted(&sample.params)
+}
+
+fn has_redacted(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::String(x) => x == REDACTED,
+        serde_json::Value::Array(x) => x.iter().any(has_redacted),
+        serde_json::Value::Object(x) => x.values().any(has_redacted),
+        _ => false,
+    }
+}
+
+/// Replace the "latest" and "pending" block tags with a block number
+fn pin_block_tags(value: &mut serde_json::Value, block: u64) {
+    match value {
+        serde_json::Value::String(x) if x == "latest" || x == "pending" => {
+            *value = json!(format!("{:#x}", block));
+        }
+        serde_json::Value::Array(x) => x.iter_mut().for_each(|x| pin_block_tags(x, block)),
+        serde_json::Value::Object(x) => x.values_mut().for_each(|x| pin_block_tags(x, block)),
+        _ => {}
+    }
+}
+
+/// Every field that is different between `current` and `candidate`. Missing fields are compared as null
+fn diff_json(
+    path: &str,
+    current: &serde_json::Value,
+    candidate: &serde_json::Value,
+    mismatches: &mut Vec<FieldMismatch>,
+) {
+    use serde_json::Value;
+
+    match (current, candidate) {
+        (Value::Object(a), Value::Object(b)) => {
+            let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+            keys.sort_unstable();
+            keys.dedup();
+
+            for key in keys {
+                let child = if path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+
+                diff_json(
+                    &child,
+                    a.get(key).unwrap_or(&Value::Null),
+                    b.get(key).unwrap_or(&Value::Null),
+                    mismatches,
+                );
+            }
+        }
+        (Value::Array(a), Value::Array(b)) if a.len() == b.len() => {
+            for (i, (a, b)) in a.iter().zip(b.iter()).enumerate() {
+                diff_json(&format!("{}[{}]", path, i), a, b, mismatches);
+            }
+        }
+        (a, b) => {
+            if a != b {
+                mismatches.push(FieldMismatch {
+                    path: path.to_string(),
+                    current: a.clone(),
+                    candidate: b.clone(),
+                });
+            }
+        }
+    }
+}
+
+/// The parts of a response that should be the same on every node.
+/// Error messages are different between clients, so only the code is kept
+fn comparable(response: &serde_json::Value) -> serde_json::Value {
+    match response.get("error") {
+        Some(error) => json!({ "error": { "code": error.get("code") } }),
+        None => json!({ "result": response.get("result") }),
+    }
+}
+
+async fn send(
+    client: &reqwest::Client,
+    rpc: &str,
+    request: &serde_json::Value,
+) -> anyhow::Result<(serde_json::Value, u64)> {
+    let start = Instant::now();
+
+    let response: serde_json::Value = client
+        .post(rpc)
+        .json(request)
+        .send()
+        .await?
+        .json()
+        .await
+        .with_context(|| format!("parsing the response from {}", rpc))?;
+
+    Ok((response, start.elapsed().as_millis() as u64))
+}
+
+async fn block_number(client: &reqwest::Client, rpc: &str) -> anyhow::Result<u64> {
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+
+    let (response, _) = send(client, rpc, &request).await?;
+
+    let x = response
+        .get("result")
+        .and_then(|x| x.as_str())
+        .with_context(|| format!("unexpected response: {}", response))?;
+
+    u64::from_str_radix(x.trim_start_matches("0x"), 16).context("parsing the block number")
+}
+
+async fn replay(
+    client: &reqwest::Client,
+    current: &str,
+    candidate: &str,
+    sample: Sample,
+    pinned_block: u64,
+) -> Replayed {
+    let mut params = if sample.params.is_null() {
+        json!([])
+    } else {
+        sample.params
+    };
+
+    pin_block_tags(&mut params, pinned_block);
+
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": sample.method,
+        "params": params,
+    });
+
+    // at the same time so that both sets see the same chain
+    let (a, b) = tokio::join!(
+        send(client, current, &request),
+        send(client, candidate, &request)
+    );
+
+    let mut current_ms = 0;
+    let mut candidate_ms = 0;
+
+    let outcome = match (a, b) {
+        (Ok((a, a_ms)), Ok((b, b_ms))) => {
+            current_ms = a_ms;
+            candidate_ms = b_ms;
+
+            let mut mismatches = vec![];
+
+            diff_json("", &comparable(&a), &comparable(&b), &mut mismatches);
+
+            mismatches.truncate(MAX_FIELD_MISMATCHES);
+
+            Ok(mismatches)
+        }
+        (Err(err), _) => Err(err.context(format!("current backend {}", current))),
+        (_, Err(err)) => Err(err.context(format!("candidate backend {}", candidate))),
+    };
+
+    Replayed {
+        method: sample.method,
+        params,
+        current_ms,
+        candidate_ms,
+        outcome,
+    }
+}
+
+fn latency(mut current: Vec<u64>, mut candidate: Vec<u64>) -> Latency {
+    current.sort_unstable();
+    candidate.sort_unstable();
+
+    Latency {
+        current_p50_ms: percentile(&current, 50),
+        current_p99_ms: percentile(&current, 99),
+        candidate_p50_ms: percentile(&candidate, 50),
+        candidate_p99_ms: percentile(&candidate, 99),
+    }
+}
+
+/// `sorted` must be sorted. 0 if it is empty
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+
+    let i = (sorted.len() * p / 100).min(sorted.len() - 1);
+
+    sorted[i]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_json() {
+        let current = json!({
+            "result": {"number": "0x10", "logs": [{"data": "0x"}, {"data": "0x01"}], "extra": null}
+        });
+        let candidate = json!({
+            "result": {"number": "0x10", "logs": [{"data": "0x"}, {"data": "0x02"}], "added": 1}
+        });
+
+        let mut mismatches = vec![];
+        diff_json("", &current, &candidate, &mut mismatches);
+
+        assert_eq!(
+            mismatches,
+            vec![
+                FieldMismatch {
+                    path: "result.added".to_string(),
+                    current: json!(null),
+                    candidate: json!(1),
+                },
+                FieldMismatch {
+                    path: "result.logs[1].data".to_string(),
+                    current: json!("0x01"),
+                    candidate: json!("0x02"),
+                },
+            ]
+        );
+
+        let mut params = json!([{"to": "0x01", "data": "0x"}, "latest"]);
+        pin_block_tags(&mut params, 255);
+        assert_eq!(params, json!([{"to": "0x01", "data": "0x"}, "0xff"]));
+    }
+}
@@ -0,0 +1,39 @@
+//! Replace plaintext rpc secret keys and key pool secrets with their keyed hash.
+//! The proxy also does this at startup because lookups only check the hash. Running it again is safe
+use argh::FromArgs;
+use log::info;
+use migration::sea_orm::DatabaseConnection;
+use web3_proxy::frontend::authorization::RpcKeyHasher;
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// Hash every plaintext rpc secret key and key pool secret with rpc_key_hash_keyfile and forget the plaintext.
+#[argh(subcommand, name = "hash_rpc_keys")]
+pub struct HashRpcKeysSubCommand {
+    #[argh(option, default = "1000")]
+    /// how many rows to load at once
+    batch_size: u64,
+
+    #[argh(switch)]
+    /// count the keys that need hashing without saving anything
+    dry_run: bool,
+}
+
+impl HashRpcKeysSubCommand {
+    pub async fn main(
+        self,
+        db_conn: &DatabaseConnection,
+        rpc_key_hasher: &RpcKeyHasher,
+    ) -> anyhow::Result<()> {
+        let hashed = rpc_key_hasher
+            .hash_plaintext_keys(db_conn, self.batch_size, self.dry_run)
+            .await?;
+
+        if self.dry_run {
+            info!("{} rpc keys and key pools need hashing", hashed);
+        } else {
+            info!("hashed {} rpc keys and key pools", hashed);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+//! Import usage history exported from another rpc provider's dashboard into influx.
+//! The points are tagged with "imported" so they can be told apart from stats that the proxy saved itself.
+use anyhow::Context;
+use argh::FromArgs;
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use futures::stream;
+use hashbrown::HashMap;
+use influxdb2::api::write::TimestampPrecision;
+use influxdb2::models::DataPoint;
+use log::{info, warn};
+use std::fs;
+use std::path::Path;
+use web3_proxy::config::TopConfig;
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// Import usage history from an Alchemy or Infura CSV export.
+#[argh(subcommand, name = "import_stats")]
+pub struct ImportStatsSubCommand {
+    #[argh(positional)]
+    /// which provider made the export: "alchemy" or "infura". picks the default column names
+    provider: String,
+
+    #[argh(positional)]
+    /// path to the CSV export
+    input_file: String,
+
+    #[argh(option)]
+    /// the rpc key to attribute the requests to. without this, only the global stats are imported
+    rpc_secret_key_id: Option<u64>,
+
+    #[argh(option)]
+    /// column with the date or unix timestamp
+    timestamp_column: Option<String>,
+
+    #[argh(option)]
+    /// column with the rpc method
+    method_column: Option<String>,
+
+    #[argh(option)]
+    /// column with the number of requests
+    count_column: Option<String>,
+
+    #[argh(switch)]
+    /// parse the file and log what would be saved without writing anything
+    dry_run: bool,
+}
+
+/// requests per (timestamp, method)
+type ImportedCounts = HashMap<(i64, Option<String>), u64>;
+
+impl ImportStatsSubCommand {
+    pub async fn main(self, top_config: TopConfig) -> anyhow::Result<()> {
+        // the header names of the providers' usage exports
+        let (timestamp_column, method_column, count_column) = match self.provider.as_str() {
+            "alchemy" => ("date", "method", "requests"),
+            "infura" => ("timestamp", "method", "count"),
+            x => return Err(anyhow::anyhow!("unknown provider: {}", x)),
+        };
+
+        let timestamp_column = self
+            .timestamp_column
+            .clone()
+            .unwrap_or_else(|| timestamp_column.to_string());
+        let method_column = self
+            .method_column
+            .clone()
+            .unwrap_or_else(|| method_column.to_string());
+        let count_column = self
+            .count_column
+            .clone()
+            .unwrap_or_else(|| count_column.to_string());
+
+        let input_file = Path::new(&self.input_file);
+
+        let csv = fs::read_to_string(input_file)
+            .context(format!("reading {}", input_file.to_string_lossy()))?;
+
+        let counts = parse_usage_csv(&csv, &timestamp_column, &method_column, &count_column)?;
+
+        let total: u64 = counts.values().sum();
+
+        info!(
+            "parsed {} requests in {} buckets from {}",
+            total,
+            counts.len(),
+            input_file.to_string_lossy()
+        );
+
+        let chain_id = top_config.app.chain_id;
+
+        let mut points = vec![];
+
+        // global stats do not track methods. sum them up so points with the same tags do not overwrite each other
+        let mut global_counts: HashMap<i64, u64> = HashMap::new();
+        for ((timestamp, _), count) in counts.iter() {
+            *global_counts.entry(*timestamp).or_default() += count;
+        }
+
+        for (timestamp, count) in global_counts.into_iter() {
+            points.push(self.build_point("global_proxy", chain_id, timestamp, None, count)?);
+        }
+
+        if self.rpc_secret_key_id.is_some() {
+            for ((timestamp, method), count) in counts.into_iter() {
+                points.push(self.build_point(
+                    "opt_in_proxy",
+                    chain_id,
+                    timestamp,
+                    method,
+                    count,
+                )?);
+            }
+        }
+
+        if self.dry_run {
+            info!("dry run. not saving {} points", points.len());
+            return Ok(());
+        }
+
+        let influxdb_host = top_config
+            .app
+            .influxdb_host
+            .context("influxdb_host needed to import stats")?;
+        let influxdb_org = top_config
+            .app
+            .influxdb_org
+            .context("influxdb_org needed to import stats")?;
+        let influxdb_token = top_config
+            .app
+            .influxdb_token
+            .context("influxdb_token needed to import stats")?;
+        let influxdb_bucket = top_config
+            .app
+            .influxdb_bucket
+            .context("influxdb_bucket needed to import stats")?;
+
+        let influxdb_client = influxdb2::Client::new(influxdb_host, influxdb_org, influxdb_token);
+
+        let num_points = points.len();
+
+        // TODO: put max_batch_size in config?
+        let max_batch_size = 100;
+
+        while !points.is_empty() {
+            let batch_size = points.len().min(max_batch_size);
+
+            let batch: Vec<_> = points.drain(..batch_size).collect();
+
+            influxdb_client
+                .write_with_precision(
+                    &influxdb_bucket,
+                    stream::iter(batch),
+                    TimestampPrecision::Seconds,
+                )
+                .await
+                .context("saving imported stats")?;
+        }
+
+        info!("saved {} imported points", num_points);
+
+        Ok(())
+    }
+
+    fn build_point(
+        &self,
+        measurement: &str,
+        chain_id: u64,
+        timestamp: i64,
+        method: Option<String>,
+        count: u64,
+    ) -> anyhow::Result<DataPoint> {
+        let mut builder = DataPoint::builder(measurement)
+            .tag("chain_id", chain_id.to_string())
+            .tag("imported", self.provider.clone());
+
+        if measurement == "opt_in_proxy" {
+            if let Some(rpc_secret_key_id) = self.rpc_secret_key_id {
+                builder = builder.tag("rpc_secret_key_id", rpc_secret_key_id.to_string());
+            }
+        }
+
+        if let Some(method) = method {
+            builder = builder.tag("method", method);
+        }
+
+        // the other provider served these requests. we only know how many there were
+        let point = builder
+            .tag("archive_needed", "false")
+            .tag("error_response", "false")
+            .field("frontend_requests", count as i64)
+            .field("backend_requests", 0i64)
+            .field("no_servers", 0i64)
+            .field("cache_misses", count as i64)
+            .field("cache_hits", 0i64)
+            .field("sum_request_bytes", 0i64)
+            .field("sum_response_millis", 0i64)
+            .field("sum_response_bytes", 0i64)
+            .field("sum_credits_used", 0f64)
+            .field("balance", 0f64)
+            .timestamp(timestamp)
+            .build()?;
+
+        Ok(point)
+    }
+}
+
+/// Sum the request counts in a CSV export by timestamp and method.
+/// Header names are matched case insensitively. The method column is optional.
+fn parse_usage_csv(
+    csv: &str,
+    timestamp_column: &str,
+    method_column: &str,
+    count_column: &str,
+) -> anyhow::Result<ImportedCounts> {
+    let mut lines = csv.lines().filter(|x| !x.trim().is_empty());
+
+    let header = split_csv_line(lines.next().context("empty csv")?);
+
+    let find_column = |name: &str| {
+        header
+            .iter()
+            .position(|x| x.trim().eq_ignore_ascii_case(name))
+    };
+
+    let timestamp_index =
+        find_column(timestamp_column).context(format!("no {} column in csv", timestamp_column))?;
+    let count_index =
+        find_column(count_column).context(format!("no {} column in csv", count_column))?;
+    let method_index = find_column(method_column);
+
+    let mut counts = ImportedCounts::new();
+
+    for (i, line) in lines.enumerate() {
+        let row = split_csv_line(line);
+
+        let (timestamp, count) = match (row.get(timestamp_index), row.get(count_index)) {
+            (Some(timestamp), Some(count)) => (timestamp, count),
+            _ => {
+                warn!("skipping short row {}: {}", i + 1, line);
+                continue;
+            }
+        };
+
+        let timestamp = parse_timestamp(timestamp.trim())
+            .context(format!("row {} has an invalid timestamp", i + 1))?;
+
+        // some exports format counts like "1,234"
+        let count: u64 = count
+            .trim()
+            .replace(',', "")
+            .parse()
+            .context(format!("row {} has an invalid count", i + 1))?;
+
+        let method = method_index
+            .and_then(|x| row.get(x))
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty());
+
+        *counts.entry((timestamp, method)).or_default() += count;
+    }
+
+    Ok(counts)
+}
+
+/// Split a line of CSV into its fields. Handles quoted fields with commas and escaped quotes.
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+
+    fields.push(field);
+
+    fields
+}
+
+/// Dates, datetimes, RFC 3339, or unix timestamps in seconds or milliseconds
+fn parse_timestamp(x: &str) -> anyhow::Result<i64> {
+    if let Ok(x) = x.parse::<i64>() {
+        // anything this big must be milliseconds
+        return if x > 100_000_000_000 {
+            Ok(x / 1_000)
+        } else {
+            Ok(x)
+        };
+    }
+
+    if let Ok(x) = DateTime::parse_from_rfc3339(x) {
+        return Ok(x.timestamp());
+    }
+
+    if let Ok(x) = NaiveDateTime::parse_from_str(x, "%Y-%m-%d %H:%M:%S") {
+        return Ok(x.timestamp());
+    }
+
+    if let Ok(x) = NaiveDate::parse_from_str(x, "%Y-%m-%d") {
+        return Ok(x
+            .and_hms_opt(0, 0, 0)
+            .expect("midnight is always valid")
+            .timestamp());
+    }
+
+    Err(anyhow::anyhow!("unknown timestamp format: {}", x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_usage_csv() {
+        let csv = "Date,Method,Requests\n\
+            2023-05-01,eth_call,\"1,000\"\n\
+            2023-05-01,eth_call,5\n\
+            2023-05-01,eth_blockNumber,10\n\
+            2023-05-02,eth_call,1\n";
+
+        let counts = parse_usage_csv(csv, "date", "method", "requests").unwrap();
+
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts[&(1682899200, Some("eth_call".to_string()))], 1_005);
+        assert_eq!(
+            counts[&(1682899200, Some("eth_blockNumber".to_string()))],
+            10
+        );
+        assert_eq!(counts[&(1682985600, Some("eth_call".to_string()))], 1);
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1682899200").unwrap(), 1682899200);
+        assert_eq!(parse_timestamp("1682899200000").unwrap(), 1682899200);
+        assert_eq!(parse_timestamp("2023-05-01").unwrap(), 1682899200);
+        assert_eq!(parse_timestamp("2023-05-01 00:00:00").unwrap(), 1682899200);
+        assert_eq!(parse_timestamp("2023-05-01T00:00:00Z").unwrap(), 1682899200);
+        assert!(parse_timestamp("yesterday").is_err());
+    }
+}
@@ -1,3 +1,4 @@
+mod backup;
 mod change_admin_status;
 mod change_user_address;
 mod change_user_tier;
@@ -7,15 +8,23 @@ mod check_config;
 mod count_users;
 mod create_key;
 mod create_user;
+mod diff_backends;
 mod drop_migration_lock;
+mod hash_rpc_keys;
+mod import_stats;
 mod list_user_tier;
 mod migrate_stats_to_v2;
 mod pagerduty;
 mod popularity_contest;
 mod proxyd;
+mod reconcile_stats;
+mod rename_chain_stats;
+mod restore;
+mod rotate_row_encryption;
 mod rpc_accounting;
 mod search_kafka;
 mod sentryd;
+mod stats_verify;
 mod transfer_key;
 mod user_export;
 mod user_import;
@@ -32,7 +41,9 @@ use std::{
     sync::atomic::{self, AtomicUsize},
 };
 use tokio::runtime;
+use web3_proxy::frontend::authorization::RpcKeyHasher;
 use web3_proxy::pagerduty::panic_handler;
+use web3_proxy::row_encryption::RowCipher;
 use web3_proxy::{
     app::{get_db, get_migrated_db, APP_USER_AGENT},
     config::TopConfig,
@@ -75,6 +86,7 @@ pub struct Web3ProxyCli {
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
 enum SubCommand {
+    Backup(backup::BackupSubCommand),
     ChangeAdminStatus(change_admin_status::ChangeAdminStatusSubCommand),
     ChangeUserAddress(change_user_address::ChangeUserAddressSubCommand),
     ChangeUserTier(change_user_tier::ChangeUserTierSubCommand),
@@ -84,14 +96,22 @@ enum SubCommand {
     CountUsers(count_users::CountUsersSubCommand),
     CreateKey(create_key::CreateKeySubCommand),
     CreateUser(create_user::CreateUserSubCommand),
+    DiffBackends(diff_backends::DiffBackendsSubCommand),
     DropMigrationLock(drop_migration_lock::DropMigrationLockSubCommand),
+    HashRpcKeys(hash_rpc_keys::HashRpcKeysSubCommand),
+    ImportStats(import_stats::ImportStatsSubCommand),
     MigrateStatsToV2(migrate_stats_to_v2::MigrateStatsToV2),
     Pagerduty(pagerduty::PagerdutySubCommand),
     PopularityContest(popularity_contest::PopularityContestSubCommand),
     Proxyd(proxyd::ProxydSubCommand),
+    ReconcileStats(reconcile_stats::ReconcileStatsSubCommand),
+    RenameChainStats(rename_chain_stats::RenameChainStatsSubCommand),
+    Restore(restore::RestoreSubCommand),
+    RotateRowEncryption(rotate_row_encryption::RotateRowEncryptionSubCommand),
     RpcAccounting(rpc_accounting::RpcAccountingSubCommand),
     SearchKafka(search_kafka::SearchKafkaSubCommand),
     Sentryd(sentryd::SentrydSubCommand),
+    Stats(stats_verify::StatsSubCommand),
     TransferKey(transfer_key::TransferKeySubCommand),
     UserExport(user_export::UserExportSubCommand),
     UserImport(user_import::UserImportSubCommand),
@@ -182,7 +202,7 @@ fn main() -> anyhow::Result<()> {
 
         let top_config: String = fs::read_to_string(top_config_path.clone())?;
 
-        let mut top_config: TopConfig = toml::from_str(&top_config)?;
+        let mut top_config = TopConfig::from_toml(&top_config)?;
 
         // TODO: this doesn't seem to do anything
         proctitle::set_title(format!("web3_proxy-{}", top_config.app.chain_id));
@@ -303,6 +323,20 @@ fn main() -> anyhow::Result<()> {
 
     rt.block_on(async {
         match cli_config.sub_command {
+            SubCommand::Backup(x) => {
+                let top_config = top_config.expect("--config is required to back up a deployment");
+                let top_config_path =
+                    top_config_path.expect("path must be set if top_config exists");
+
+                // the database is optional with --skip-db
+                let db_conn = match cli_config.db_url.clone() {
+                    Some(db_url) => Some(get_db(db_url, 1, 1).await?),
+                    None => None,
+                };
+
+                x.main(top_config, top_config_path, cli_config.db_url, db_conn.as_ref())
+                    .await
+            }
             SubCommand::ChangeAdminStatus(x) => {
                 let db_url = cli_config.db_url.expect(
                     "'--config' (with a db) or '--db-url' is required to run change_admin_status",
@@ -346,7 +380,9 @@ fn main() -> anyhow::Result<()> {
 
                 let db_conn = get_db(db_url, 1, 1).await?;
 
-                x.main(&db_conn).await
+                let rpc_key_hasher = rpc_key_hasher(top_config.as_ref())?;
+
+                x.main(&db_conn, &rpc_key_hasher).await
             }
             SubCommand::CheckConfig(x) => x.main().await,
             SubCommand::CreateKey(x) => {
@@ -356,7 +392,9 @@ fn main() -> anyhow::Result<()> {
 
                 let db_conn = get_migrated_db(db_url, 1, 1).await?;
 
-                x.main(&db_conn).await
+                let rpc_key_hasher = rpc_key_hasher(top_config.as_ref())?;
+
+                x.main(&db_conn, &rpc_key_hasher).await
             }
             SubCommand::CreateUser(x) => {
                 let db_url = cli_config
@@ -365,7 +403,16 @@ fn main() -> anyhow::Result<()> {
 
                 let db_conn = get_migrated_db(db_url, 1, 1).await?;
 
-                x.main(&db_conn).await
+                // emails are encrypted if the config has keys
+                let row_cipher = top_config
+                    .as_ref()
+                    .and_then(|x| x.app.row_encryption_keyfile.as_ref())
+                    .map(|x| RowCipher::from_keyfile(Path::new(x)))
+                    .transpose()?;
+
+                let rpc_key_hasher = rpc_key_hasher(top_config.as_ref())?;
+
+                x.main(&db_conn, row_cipher.as_ref(), &rpc_key_hasher).await
             }
             SubCommand::CountUsers(x) => {
                 let db_url = cli_config
@@ -376,6 +423,7 @@ fn main() -> anyhow::Result<()> {
 
                 x.main(&db_conn).await
             }
+            SubCommand::DiffBackends(x) => x.main(top_config).await,
             SubCommand::Proxyd(x) => {
                 let top_config = top_config.expect("--config is required to run proxyd");
                 let top_config_path =
@@ -393,6 +441,24 @@ fn main() -> anyhow::Result<()> {
 
                 x.main(&db_conn).await
             }
+            SubCommand::HashRpcKeys(x) => {
+                let top_config = top_config.expect("--config is required to hash rpc keys");
+
+                let rpc_key_hasher = RpcKeyHasher::from_config(&top_config.app)?;
+
+                let db_url = cli_config
+                    .db_url
+                    .expect("'--config' (with a db) or '--db-url' is required to hash rpc keys");
+
+                let db_conn = get_migrated_db(db_url, 1, 1).await?;
+
+                x.main(&db_conn, &rpc_key_hasher).await
+            }
+            SubCommand::ImportStats(x) => {
+                let top_config = top_config.expect("--config is required to import stats");
+
+                x.main(top_config).await
+            }
             SubCommand::MigrateStatsToV2(x) => {
 
                 let top_config = top_config.expect("--config is required to run the migration from stats-mysql to stats-influx");
@@ -414,6 +480,42 @@ fn main() -> anyhow::Result<()> {
                 x.main(pagerduty_async, top_config).await
             }
             SubCommand::PopularityContest(x) => x.main().await,
+            SubCommand::ReconcileStats(x) => {
+                let top_config = top_config.expect("--config is required to reconcile stats");
+
+                let db_url = cli_config
+                    .db_url
+                    .expect("'--config' (with a db) or '--db-url' is required to reconcile stats");
+
+                let db_conn = get_db(db_url, 1, 1).await?;
+
+                x.main(top_config, &db_conn).await
+            }
+            SubCommand::RenameChainStats(x) => {
+                let top_config = top_config.expect("--config is required to rename chain stats");
+
+                // the database is optional. without it, only influx is updated
+                let db_conn = match cli_config.db_url {
+                    Some(db_url) => Some(get_db(db_url, 1, 1).await?),
+                    None => None,
+                };
+
+                x.main(top_config, db_conn.as_ref()).await
+            }
+            SubCommand::Restore(x) => {
+                let top_config = top_config.expect("--config is required to restore a backup");
+                let top_config_path =
+                    top_config_path.expect("path must be set if top_config exists");
+
+                // the database is optional with --skip-db
+                let db_conn = match cli_config.db_url.clone() {
+                    Some(db_url) => Some(get_db(db_url, 1, 1).await?),
+                    None => None,
+                };
+
+                x.main(top_config, top_config_path, cli_config.db_url, db_conn.as_ref())
+                    .await
+            }
             SubCommand::SearchKafka(x) => x.main(top_config.unwrap()).await,
             SubCommand::Sentryd(x) => {
                 if cli_config.sentry_url.is_none() {
@@ -422,6 +524,36 @@ fn main() -> anyhow::Result<()> {
 
                 x.main(pagerduty_async, top_config).await
             }
+            SubCommand::Stats(x) => {
+                let top_config = top_config.expect("--config is required to verify stats");
+
+                let db_url = cli_config
+                    .db_url
+                    .expect("'--config' (with a db) or '--db-url' is required to verify stats");
+
+                let db_conn = get_db(db_url, 1, 1).await?;
+
+                x.main(top_config, &db_conn).await
+            }
+            SubCommand::RotateRowEncryption(x) => {
+                let top_config =
+                    top_config.expect("--config is required to rotate row encryption");
+
+                let keyfile = top_config
+                    .app
+                    .row_encryption_keyfile
+                    .expect("row_encryption_keyfile is required to rotate row encryption");
+
+                let row_cipher = RowCipher::from_keyfile(Path::new(&keyfile))?;
+
+                let db_url = cli_config.db_url.expect(
+                    "'--config' (with a db) or '--db-url' is required to rotate row encryption",
+                );
+
+                let db_conn = get_migrated_db(db_url, 1, 1).await?;
+
+                x.main(&db_conn, &row_cipher).await
+            }
             SubCommand::RpcAccounting(x) => {
                 let db_url = cli_config
                     .db_url
@@ -437,7 +569,9 @@ fn main() -> anyhow::Result<()> {
                     .expect("'--config' (with a db) or '--db-url' is required to run transfer_key");
                 let db_conn = get_db(db_url, 1, 1).await?;
 
-                x.main(&db_conn).await
+                let rpc_key_hasher = rpc_key_hasher(top_config.as_ref())?;
+
+                x.main(&db_conn, &rpc_key_hasher).await
             }
             SubCommand::UserExport(x) => {
                 let db_url = cli_config
@@ -453,10 +587,24 @@ fn main() -> anyhow::Result<()> {
                     .db_url
                     .expect("'--config' (with a db) or '--db-url' is required to run user_import");
 
+                let rpc_key_hasher = rpc_key_hasher(top_config.as_ref())?;
+
                 let db_conn = get_migrated_db(db_url, 1, 1).await?;
 
-                x.main(&db_conn).await
+                x.main(&db_conn, &rpc_key_hasher).await
             }
         }
     })
 }
+
+/// The hasher from the config's rpc_key_hash_keyfile. These commands save keys, so a config with the keyfile is required
+fn rpc_key_hasher(top_config: Option<&TopConfig>) -> anyhow::Result<RpcKeyHasher> {
+    let top_config = top_config.context("--config with rpc_key_hash_keyfile is required")?;
+
+    anyhow::ensure!(
+        top_config.app.rpc_key_hash_keyfile.is_some(),
+        "rpc_key_hash_keyfile is required"
+    );
+
+    RpcKeyHasher::from_config(&top_config.app)
+}
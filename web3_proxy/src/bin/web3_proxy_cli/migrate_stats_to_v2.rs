@@ -80,6 +80,8 @@ impl MigrateStatsToV2 {
             influxdb_client.clone(),
             None,
             rpc_account_shutdown_recevier,
+            // the migration would rather wait than drop stats
+            None,
             1,
         )
         .context("Error spawning stat buffer")?
@@ -122,8 +124,9 @@ impl MigrateStatsToV2 {
                             .context("Could not find rpc_key_obj for the given rpc_key_id")?;
 
                         authorization.checks.user_id = rpc_key_obj.user_id;
+                        // keys that are only saved as a hash are tracked by their id alone
                         authorization.checks.rpc_secret_key =
-                            Some(RpcSecretKey::Uuid(rpc_key_obj.secret_key));
+                            rpc_key_obj.secret_key.map(RpcSecretKey::Uuid);
                         authorization.checks.rpc_secret_key_id =
                             NonZeroU64::try_from(rpc_key_id).ok();
                     }
@@ -174,19 +177,39 @@ impl MigrateStatsToV2 {
                         archive_request: x.archive_request.into(),
                         authorization: Some(authorization.clone()),
                         backend_requests: Mutex::new(backend_rpcs),
+                        // old stats are already in the balances
+                        balance: None,
+                        // old stats are already in the monthly totals
+                        bandwidth_used: None,
+                        // old stats were all for the proxy's own chain
+                        chain_id: None,
+                        // classifier scripts did not exist in the old stats
+                        classification: Default::default(),
                         error_response: x.error_response.into(),
                         // debug data is in kafka, not mysql or influx
                         kafka_debug_logger: None,
+                        // the limit was already enforced when these were served
+                        max_response_bytes: Default::default(),
                         method: x.method.clone(),
                         // This is not relevant in the new version
                         no_servers: 0.into(),
+                        // notifications were not tracked in the old stats
+                        notification: false,
+                        // payloads were never saved in the old stats
+                        payload_sample: None,
+                        // bots were not tagged in the old stats
+                        probable_bot: false,
                         // Get the mean of all the request bytes
                         request_bytes: int_request_bytes as usize,
+                        // old requests are not sent to shadow urls
+                        request_shadow: None,
                         response_bytes: int_response_bytes.into(),
                         // We did not initially record this data
                         response_from_backup_rpc: false.into(),
                         response_timestamp: x.period_datetime.timestamp().into(),
                         response_millis: int_response_millis.into(),
+                        // response size caps did not exist in the old stats
+                        response_too_large: false.into(),
                         // This is overwritten later on
                         start_instant: Instant::now(),
                         stat_sender: Some(stat_sender.clone()),
@@ -7,6 +7,7 @@ use std::path::PathBuf;
 use std::time::Duration;
 use std::{fs, thread};
 use tokio::sync::broadcast;
+use tokio::time::timeout;
 use web3_proxy::app::{flatten_handle, flatten_handles, Web3ProxyApp};
 use web3_proxy::config::TopConfig;
 use web3_proxy::{frontend, prometheus};
@@ -62,6 +63,8 @@ async fn run(
     let app_frontend_port = frontend_port;
     let app_prometheus_port = prometheus_port;
 
+    let shutdown_drain_seconds = top_config.app.shutdown_drain_seconds;
+
     // TODO: should we use a watch or broadcast for these?
     // Maybe this one ?
     // let mut shutdown_receiver = shutdown_sender.subscribe();
@@ -131,7 +134,7 @@ async fn run(
         {
             thread::spawn(move || loop {
                 match fs::read_to_string(&top_config_path) {
-                    Ok(new_top_config) => match toml::from_str(&new_top_config) {
+                    Ok(new_top_config) => match TopConfig::from_toml(&new_top_config) {
                         Ok(new_top_config) => {
                             if new_top_config != top_config {
                                 top_config = new_top_config;
@@ -192,6 +195,9 @@ async fn run(
 
     let _ = spawned_app.app.head_block_receiver().changed().await;
 
+    // the frontend takes the app, but we still need it to start draining
+    let app = spawned_app.app.clone();
+
     // start the frontend port
     let frontend_handle = tokio::spawn(frontend::serve(
         app_frontend_port,
@@ -234,13 +240,12 @@ async fn run(
                 }
             }
         }
-        x = tokio::signal::ctrl_c() => {
-            // TODO: unix terminate signal, too
+        x = shutdown_signal() => {
             match x {
-                Ok(_) => info!("quiting from ctrl-c"),
+                Ok(signal) => info!("quiting from {}", signal),
                 Err(e) => {
                     // TODO: i don't think this is possible
-                    error!("error quiting from ctrl-c: {:#?}", e);
+                    error!("error quiting from shutdown signal: {:#?}", e);
                     exited_with_err = true;
                 }
             }
@@ -262,6 +267,9 @@ async fn run(
         }
     };
 
+    // fail health checks and close websockets. without this, open websockets would keep the frontend running
+    app.start_draining();
+
     // TODO: This is also not there on the main branch
     // if a future above completed, make sure the frontend knows to start turning off
     if !frontend_exited {
@@ -271,14 +279,31 @@ async fn run(
         };
     }
 
-    // TODO: Also not there on main branch
-    // TODO: wait until the frontend completes
-    if let Err(err) = frontend_shutdown_complete_receiver.recv().await {
-        warn!("shutdown completition err={:?}", err);
+    // the frontend stops accepting connections and waits for in-flight requests to finish
+    let frontend_complete = frontend_shutdown_complete_receiver.recv();
+
+    let frontend_complete = if shutdown_drain_seconds == 0 {
+        Ok(frontend_complete.await)
     } else {
-        info!("frontend exited gracefully");
+        timeout(
+            Duration::from_secs(shutdown_drain_seconds),
+            frontend_complete,
+        )
+        .await
+    };
+
+    match frontend_complete {
+        Ok(Ok(_)) => info!("frontend exited gracefully"),
+        Ok(Err(err)) => warn!("shutdown completition err={:?}", err),
+        Err(_) => warn!(
+            "requests still running after {} seconds. saving stats without them",
+            shutdown_drain_seconds
+        ),
     }
 
+    // stop the background tasks in reverse dependency order. the stat buffer is stopped last so that it saves every stat
+    app.tasks.stop().await;
+
     // now that the frontend is complete, tell all the other futures to finish
     if let Err(err) = app_shutdown_sender.send(()) {
         warn!("backend sender err={:?}", err);
@@ -316,6 +341,24 @@ async fn run(
     }
 }
 
+/// ctrl-c, or SIGTERM on unix. SIGTERM is what docker and kubernetes send
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate())?;
+
+        tokio::select! {
+            x = tokio::signal::ctrl_c() => x.map(|_| "ctrl-c"),
+            _ = sigterm.recv() => Ok("SIGTERM"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.map(|_| "ctrl-c")
+}
+
 #[cfg(test)]
 mod tests {
     use ethers::{
@@ -405,6 +448,10 @@ mod tests {
             ]),
             private_rpcs: None,
             bundler_4337_rpcs: None,
+            beacon_rpcs: None,
+            ipfs_gateways: None,
+            chains: Default::default(),
+            profiles: Default::default(),
             extra: Default::default(),
         };
 
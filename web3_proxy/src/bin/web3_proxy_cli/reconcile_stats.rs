@@ -0,0 +1,214 @@
+//! Compare the request and credit totals in the relational database with the totals in influx.
+//! rpc_accounting_v2 rows cover a whole billing period, so the totals are compared one billing period at a time.
+//! The stat buffer saves to influx every second and to the database every minute. Expect a small difference in the current period
+use anyhow::Context;
+use argh::FromArgs;
+use chrono::{TimeZone, Utc};
+use entities::rpc_accounting_v2;
+use influxdb2::models::Query;
+use influxdb2_structmap::value::Value;
+use log::{info, warn};
+use migration::sea_orm::prelude::Decimal;
+use migration::sea_orm::{
+    self, ColumnTrait, DatabaseConnection, EntityTrait, FromQueryResult, QueryFilter, QuerySelect,
+};
+use num_traits::ToPrimitive;
+use web3_proxy::app::BILLING_PERIOD_SECONDS;
+use web3_proxy::config::TopConfig;
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// Check that the database and influx agree on how many requests and credits were used.
+#[argh(subcommand, name = "reconcile_stats")]
+pub struct ReconcileStatsSubCommand {
+    #[argh(option)]
+    /// unix timestamp to start checking at. rounded down to the start of its billing period. defaults to 4 periods ago
+    start: Option<i64>,
+
+    #[argh(option)]
+    /// unix timestamp to stop checking at (exclusive). defaults to now
+    end: Option<i64>,
+
+    #[argh(option, default = "1")]
+    /// the largest difference (in parts per thousand) that still counts as a match
+    tolerance_permille: u64,
+}
+
+#[derive(Debug, Default)]
+struct Totals {
+    frontend_requests: f64,
+    credits_used: f64,
+}
+
+impl ReconcileStatsSubCommand {
+    pub async fn main(
+        self,
+        top_config: TopConfig,
+        db_conn: &DatabaseConnection,
+    ) -> anyhow::Result<()> {
+        let chain_id = top_config.app.chain_id;
+
+        let end = self.end.unwrap_or_else(|| Utc::now().timestamp());
+
+        let start = self.start.unwrap_or(end - 4 * BILLING_PERIOD_SECONDS);
+        let start = start / BILLING_PERIOD_SECONDS * BILLING_PERIOD_SECONDS;
+
+        let influxdb_host = top_config
+            .app
+            .influxdb_host
+            .context("influxdb_host needed to reconcile stats")?;
+        let influxdb_org = top_config
+            .app
+            .influxdb_org
+            .context("influxdb_org needed to reconcile stats")?;
+        let influxdb_token = top_config
+            .app
+            .influxdb_token
+            .context("influxdb_token needed to reconcile stats")?;
+        let influxdb_bucket = top_config
+            .app
+            .influxdb_bucket
+            .context("influxdb_bucket needed to reconcile stats")?;
+
+        let influxdb_client = influxdb2::Client::new(influxdb_host, influxdb_org, influxdb_token);
+
+        let mut mismatches = 0;
+
+        let mut period_start = start;
+
+        while period_start < end {
+            let period_end = period_start + BILLING_PERIOD_SECONDS;
+
+            let db = db_totals(db_conn, chain_id, period_start).await?;
+
+            let query = format!(
+                r#"
+                from(bucket: "{influxdb_bucket}")
+                    |> range(start: {period_start}, stop: {period_end})
+                    |> filter(fn: (r) => r._measurement == "global_proxy")
+                    |> filter(fn: (r) => r.chain_id == "{chain_id}")
+                    |> filter(fn: (r) => r._field == "frontend_requests" or r._field == "sum_credits_used")
+                    |> group(columns: ["_field"])
+                    |> sum()
+                "#
+            );
+
+            let records = influxdb_client
+                .query_raw(Some(Query::new(query)))
+                .await
+                .context(format!(
+                    "querying influx from {} to {}",
+                    period_start, period_end
+                ))?;
+
+            let mut tsdb = Totals::default();
+
+            for record in records {
+                let value = match record.values.get("_value") {
+                    Some(Value::Long(x)) => *x as f64,
+                    Some(Value::UnsignedLong(x)) => *x as f64,
+                    Some(Value::Double(x)) => f64::from(*x),
+                    x => {
+                        warn!("unexpected value: {:?}", x);
+                        continue;
+                    }
+                };
+
+                match record.values.get("_field") {
+                    Some(Value::String(x)) if x == "frontend_requests" => {
+                        tsdb.frontend_requests = value
+                    }
+                    Some(Value::String(x)) if x == "sum_credits_used" => tsdb.credits_used = value,
+                    x => warn!("unexpected field: {:?}", x),
+                }
+            }
+
+            let period = Utc
+                .timestamp_opt(period_start, 0)
+                .single()
+                .context("timestamp out of range")?;
+
+            let requests_match = matches(
+                db.frontend_requests,
+                tsdb.frontend_requests,
+                self.tolerance_permille,
+            );
+            let credits_match =
+                matches(db.credits_used, tsdb.credits_used, self.tolerance_permille);
+
+            if requests_match && credits_match {
+                info!(
+                    "{}: ok. requests={} credits={}",
+                    period, db.frontend_requests, db.credits_used
+                );
+            } else {
+                warn!(
+                    "{}: mismatch! requests: db={} influx={}. credits: db={} influx={}",
+                    period,
+                    db.frontend_requests,
+                    tsdb.frontend_requests,
+                    db.credits_used,
+                    tsdb.credits_used
+                );
+
+                mismatches += 1;
+            }
+
+            period_start = period_end;
+        }
+
+        anyhow::ensure!(
+            mismatches == 0,
+            "{} billing periods do not match",
+            mismatches
+        );
+
+        Ok(())
+    }
+}
+
+/// Sum every key's accounting entry for the period. Anonymous requests are included
+async fn db_totals(
+    db_conn: &DatabaseConnection,
+    chain_id: u64,
+    period_start: i64,
+) -> anyhow::Result<Totals> {
+    #[derive(Debug, FromQueryResult)]
+    struct SelectResult {
+        frontend_requests: Option<Decimal>,
+        credits_used: Option<Decimal>,
+    }
+
+    let period_datetime = Utc
+        .timestamp_opt(period_start, 0)
+        .single()
+        .context("timestamp out of range")?;
+
+    let x = rpc_accounting_v2::Entity::find()
+        .select_only()
+        .column_as(
+            rpc_accounting_v2::Column::FrontendRequests.sum(),
+            "frontend_requests",
+        )
+        .column_as(
+            rpc_accounting_v2::Column::SumCreditsUsed.sum(),
+            "credits_used",
+        )
+        .filter(rpc_accounting_v2::Column::ChainId.eq(chain_id))
+        .filter(rpc_accounting_v2::Column::PeriodDatetime.eq(period_datetime))
+        .into_model::<SelectResult>()
+        .one(db_conn)
+        .await?
+        .context("sums always return a row")?;
+
+    let to_f64 = |x: Option<Decimal>| x.and_then(|x| x.to_f64()).unwrap_or_default();
+
+    Ok(Totals {
+        frontend_requests: to_f64(x.frontend_requests),
+        credits_used: to_f64(x.credits_used),
+    })
+}
+
+/// true if the difference is at most `tolerance_permille` of the larger total
+pub(crate) fn matches(a: f64, b: f64, tolerance_permille: u64) -> bool {
+    (a - b).abs() * 1000.0 <= a.max(b) * tolerance_permille as f64
+}
@@ -0,0 +1,334 @@
+//! Move stats that were saved under the wrong chain_id.
+//! Influx tags can not be edited in place, so each window of points is copied with the new tag and then the old points are deleted.
+//! Copying is idempotent (same tags and timestamp overwrite), so an interrupted run can be started again from its last finished window.
+use anyhow::Context;
+use argh::FromArgs;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use entities::{revert_log, rpc_accounting, rpc_accounting_v2};
+use futures::stream;
+use influxdb2::api::write::TimestampPrecision;
+use influxdb2::models::{DataPoint, Query};
+use influxdb2_structmap::value::Value;
+use log::{info, warn};
+use migration::sea_orm::{
+    ColumnTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter, TransactionTrait,
+};
+use migration::Expr;
+use std::fs;
+use web3_proxy::config::TopConfig;
+
+/// the measurements that the stat buffer saves
+const MEASUREMENTS: [&str; 2] = ["global_proxy", "opt_in_proxy"];
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// Re-tag stats saved under one chain_id with another chain_id.
+#[argh(subcommand, name = "rename_chain_stats")]
+pub struct RenameChainStatsSubCommand {
+    #[argh(positional)]
+    /// the chain_id the stats were saved with
+    old_chain_id: u64,
+
+    #[argh(positional)]
+    /// the chain_id the stats should have
+    new_chain_id: u64,
+
+    #[argh(option)]
+    /// unix timestamp of the first stat to move
+    start: i64,
+
+    #[argh(option)]
+    /// unix timestamp to stop moving at (exclusive). defaults to now
+    end: Option<i64>,
+
+    #[argh(option, default = "3600")]
+    /// how many seconds of points to move at a time
+    window_seconds: i64,
+
+    #[argh(option, default = "1000")]
+    /// how many points to write to influx at a time
+    batch_size: usize,
+
+    #[argh(option)]
+    /// file to save progress in. if it exists, the rename continues after the last finished window
+    resume_file: Option<String>,
+
+    #[argh(switch)]
+    /// only move the stats in influx. leave the database alone
+    skip_db: bool,
+
+    #[argh(switch)]
+    /// count what would be moved without writing or deleting anything
+    dry_run: bool,
+}
+
+impl RenameChainStatsSubCommand {
+    pub async fn main(
+        self,
+        top_config: TopConfig,
+        db_conn: Option<&DatabaseConnection>,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.old_chain_id != self.new_chain_id,
+            "old and new chain_id are the same"
+        );
+        anyhow::ensure!(self.window_seconds > 0, "window_seconds must be positive");
+        anyhow::ensure!(self.batch_size > 0, "batch_size must be positive");
+
+        let end = self.end.unwrap_or_else(|| Utc::now().timestamp());
+
+        let mut start = self.start;
+
+        if let Some(resume_file) = self.resume_file.as_ref() {
+            if let Ok(x) = fs::read_to_string(resume_file) {
+                start = x
+                    .trim()
+                    .parse()
+                    .context(format!("{} should hold a unix timestamp", resume_file))?;
+
+                info!("resuming from {}", start);
+            }
+        }
+
+        let influxdb_host = top_config
+            .app
+            .influxdb_host
+            .context("influxdb_host needed to rename chain stats")?;
+        let influxdb_org = top_config
+            .app
+            .influxdb_org
+            .context("influxdb_org needed to rename chain stats")?;
+        let influxdb_token = top_config
+            .app
+            .influxdb_token
+            .context("influxdb_token needed to rename chain stats")?;
+        let influxdb_bucket = top_config
+            .app
+            .influxdb_bucket
+            .context("influxdb_bucket needed to rename chain stats")?;
+
+        let influxdb_client = influxdb2::Client::new(influxdb_host, influxdb_org, influxdb_token);
+
+        let mut num_moved = 0;
+
+        while start < end {
+            let stop = (start + self.window_seconds).min(end);
+
+            for measurement in MEASUREMENTS {
+                let query = format!(
+                    r#"
+                    from(bucket: "{influxdb_bucket}")
+                        |> range(start: {start}, stop: {stop})
+                        |> filter(fn: (r) => r._measurement == "{measurement}")
+                        |> filter(fn: (r) => r.chain_id == "{old_chain_id}")
+                        |> pivot(rowKey: ["_time"], columnKey: ["_field"], valueColumn: "_value")
+                    "#,
+                    old_chain_id = self.old_chain_id,
+                );
+
+                let records = influxdb_client
+                    .query_raw(Some(Query::new(query)))
+                    .await
+                    .context(format!(
+                        "querying {} from {} to {}",
+                        measurement, start, stop
+                    ))?;
+
+                if records.is_empty() {
+                    continue;
+                }
+
+                let mut points = records
+                    .into_iter()
+                    .map(|x| self.retag_point(measurement, x.values))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+
+                num_moved += points.len();
+
+                if self.dry_run {
+                    info!(
+                        "dry run. would move {} {} points from {} to {}",
+                        points.len(),
+                        measurement,
+                        start,
+                        stop
+                    );
+                    continue;
+                }
+
+                while !points.is_empty() {
+                    let batch_size = points.len().min(self.batch_size);
+
+                    let batch: Vec<_> = points.drain(..batch_size).collect();
+
+                    influxdb_client
+                        .write_with_precision(
+                            &influxdb_bucket,
+                            stream::iter(batch),
+                            TimestampPrecision::Nanoseconds,
+                        )
+                        .await
+                        .context("saving re-tagged stats")?;
+                }
+
+                // only delete once every copy is saved. if this fails, running the window again is safe
+                influxdb_client
+                    .delete(
+                        &influxdb_bucket,
+                        naive_datetime(start, 0)?,
+                        // influx deletes are inclusive of stop. the next window starts there
+                        naive_datetime(stop - 1, 999_999_999)?,
+                        Some(format!(
+                            r#"_measurement="{}" AND chain_id="{}""#,
+                            measurement, self.old_chain_id
+                        )),
+                    )
+                    .await
+                    .context("deleting stats with the old chain_id")?;
+            }
+
+            if !self.dry_run {
+                if let Some(resume_file) = self.resume_file.as_ref() {
+                    fs::write(resume_file, stop.to_string())
+                        .context(format!("saving progress to {}", resume_file))?;
+                }
+            }
+
+            info!("moved {} points up to {}", num_moved, stop);
+
+            start = stop;
+        }
+
+        match (db_conn, self.skip_db) {
+            (_, true) => {}
+            (None, false) => {
+                warn!("no database configured. only influx stats were moved");
+            }
+            (Some(db_conn), false) => {
+                self.rename_db_stats(db_conn, self.start, end).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy a pivoted record into a point with the new chain_id. Everything else stays the same
+    fn retag_point(
+        &self,
+        measurement: &str,
+        values: std::collections::BTreeMap<String, Value>,
+    ) -> anyhow::Result<DataPoint> {
+        let mut builder =
+            DataPoint::builder(measurement).tag("chain_id", self.new_chain_id.to_string());
+
+        let mut timestamp = None;
+
+        for (key, value) in values.into_iter() {
+            match (key.as_str(), value) {
+                ("_time", Value::TimeRFC(x)) => timestamp = Some(x.timestamp_nanos()),
+                // columns that flux adds to every record
+                ("chain_id" | "result" | "table", _) => {}
+                (x, _) if x.starts_with('_') => {}
+                // every tag is a string. none of our fields are
+                (_, Value::String(x)) => builder = builder.tag(key, x),
+                (_, Value::Long(x)) => builder = builder.field(key, x),
+                (_, Value::UnsignedLong(x)) => builder = builder.field(key, x as i64),
+                (_, Value::Double(x)) => builder = builder.field(key, f64::from(x)),
+                (_, Value::Bool(x)) => builder = builder.field(key, x),
+                (_, x) => warn!("skipping unexpected value for {}: {:?}", key, x),
+            }
+        }
+
+        let timestamp = timestamp.context("record is missing _time")?;
+
+        let point = builder.timestamp(timestamp).build()?;
+
+        Ok(point)
+    }
+
+    /// The database copies of the stats also have a chain_id.
+    /// Deposit receipts are left alone. They are for the chain the payment was made on
+    async fn rename_db_stats(
+        &self,
+        db_conn: &DatabaseConnection,
+        start: i64,
+        end: i64,
+    ) -> anyhow::Result<()> {
+        let start = utc_datetime(start)?;
+        let end = utc_datetime(end)?;
+
+        if self.dry_run {
+            let num_v2 = rpc_accounting_v2::Entity::find()
+                .filter(rpc_accounting_v2::Column::ChainId.eq(self.old_chain_id))
+                .filter(rpc_accounting_v2::Column::PeriodDatetime.gte(start))
+                .filter(rpc_accounting_v2::Column::PeriodDatetime.lt(end))
+                .count(db_conn)
+                .await?;
+
+            info!(
+                "dry run. would move {} rpc_accounting_v2 rows to chain {}",
+                num_v2, self.new_chain_id
+            );
+
+            return Ok(());
+        }
+
+        // all or nothing. a unique index conflict means the new chain already has stats for the same period
+        let txn = db_conn.begin().await?;
+
+        let v2 = rpc_accounting_v2::Entity::update_many()
+            .col_expr(
+                rpc_accounting_v2::Column::ChainId,
+                Expr::value(self.new_chain_id),
+            )
+            .filter(rpc_accounting_v2::Column::ChainId.eq(self.old_chain_id))
+            .filter(rpc_accounting_v2::Column::PeriodDatetime.gte(start))
+            .filter(rpc_accounting_v2::Column::PeriodDatetime.lt(end))
+            .exec(&txn)
+            .await
+            .context(
+                "updating rpc_accounting_v2. the new chain may already have rows for these periods",
+            )?;
+
+        let v1 = rpc_accounting::Entity::update_many()
+            .col_expr(
+                rpc_accounting::Column::ChainId,
+                Expr::value(self.new_chain_id),
+            )
+            .filter(rpc_accounting::Column::ChainId.eq(self.old_chain_id))
+            .filter(rpc_accounting::Column::PeriodDatetime.gte(start))
+            .filter(rpc_accounting::Column::PeriodDatetime.lt(end))
+            .exec(&txn)
+            .await
+            .context(
+                "updating rpc_accounting. the new chain may already have rows for these periods",
+            )?;
+
+        let reverts = revert_log::Entity::update_many()
+            .col_expr(revert_log::Column::ChainId, Expr::value(self.new_chain_id))
+            .filter(revert_log::Column::ChainId.eq(self.old_chain_id))
+            .filter(revert_log::Column::Timestamp.gte(start))
+            .filter(revert_log::Column::Timestamp.lt(end))
+            .exec(&txn)
+            .await
+            .context("updating revert_log")?;
+
+        txn.commit().await?;
+
+        info!(
+            "moved {} rpc_accounting_v2, {} rpc_accounting, and {} revert_log rows to chain {}",
+            v2.rows_affected, v1.rows_affected, reverts.rows_affected, self.new_chain_id
+        );
+
+        Ok(())
+    }
+}
+
+fn naive_datetime(timestamp: i64, nanos: u32) -> anyhow::Result<NaiveDateTime> {
+    NaiveDateTime::from_timestamp_opt(timestamp, nanos).context("timestamp out of range")
+}
+
+fn utc_datetime(timestamp: i64) -> anyhow::Result<DateTime<Utc>> {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .context("timestamp out of range")
+}
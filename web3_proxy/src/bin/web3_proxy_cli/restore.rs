@@ -0,0 +1,190 @@
+use crate::backup::{
+    mysql_command, redis_conn, BackupManifest, RedisEntry, BACKUP_FORMAT_VERSION, CONFIG_FILE,
+    DATABASE_FILE, MANIFEST_FILE, REDIS_FILE,
+};
+use anyhow::Context;
+use argh::FromArgs;
+use log::{info, warn};
+use migration::sea_orm::DatabaseConnection;
+use migration::{Migrator, MigratorTrait};
+use redis_rate_limiter::redis;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use web3_proxy::app::migrate_db;
+use web3_proxy::config::TopConfig;
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// Restore a backup made by `backup` onto a new deployment.
+#[argh(subcommand, name = "restore")]
+pub struct RestoreSubCommand {
+    /// the directory that `backup` created
+    #[argh(positional)]
+    backup_dir: String,
+
+    /// restore even if the checks fail. this can overwrite a live database
+    #[argh(switch)]
+    force: bool,
+
+    /// do not restore the database
+    #[argh(switch)]
+    skip_db: bool,
+
+    /// do not restore redis keys
+    #[argh(switch)]
+    skip_redis: bool,
+
+    /// the mysql client binary
+    #[argh(option, default = "\"mysql\".to_string()")]
+    mysql: String,
+}
+
+impl RestoreSubCommand {
+    pub async fn main(
+        self,
+        top_config: TopConfig,
+        top_config_path: PathBuf,
+        db_url: Option<String>,
+        db_conn: Option<&DatabaseConnection>,
+    ) -> anyhow::Result<()> {
+        let backup_dir = Path::new(&self.backup_dir);
+
+        let manifest = fs::read_to_string(backup_dir.join(MANIFEST_FILE))
+            .context("reading the manifest. backups without one did not finish")?;
+
+        let manifest: BackupManifest = serde_json::from_str(&manifest)?;
+
+        info!("restoring {:?}", manifest);
+
+        anyhow::ensure!(
+            manifest.format_version == BACKUP_FORMAT_VERSION,
+            "backup format {} is not supported. this version of web3_proxy reads format {}",
+            manifest.format_version,
+            BACKUP_FORMAT_VERSION
+        );
+
+        let mut problems = vec![];
+
+        if manifest.chain_id != top_config.app.chain_id {
+            problems.push(format!(
+                "the backup is for chain {} but the config is for chain {}",
+                manifest.chain_id, top_config.app.chain_id
+            ));
+        }
+
+        if manifest.web3_proxy_version != env!("CARGO_PKG_VERSION") {
+            warn!(
+                "the backup was made by web3_proxy {}. this is {}",
+                manifest.web3_proxy_version,
+                env!("CARGO_PKG_VERSION")
+            );
+        }
+
+        let restore_db = manifest.database && !self.skip_db;
+
+        if restore_db {
+            // a backup from a newer web3_proxy has tables that this version does not know about
+            if let Some(migration) = manifest.migration.as_ref() {
+                if !Migrator::migrations().iter().any(|x| x.name() == migration) {
+                    problems.push(format!(
+                        "the backup's database is at migration {} which this version of web3_proxy does not have",
+                        migration
+                    ));
+                }
+            }
+
+            let db_conn = db_conn.context("'--config' (with a db) or '--db-url' is required to restore the database. use --skip-db to skip it")?;
+
+            if !Migrator::get_applied_migrations(db_conn).await?.is_empty() {
+                problems.push("the database already has migrations applied".to_string());
+            }
+        }
+
+        if !problems.is_empty() {
+            if self.force {
+                for problem in problems.iter() {
+                    warn!("ignoring because of --force: {}", problem);
+                }
+            } else {
+                anyhow::bail!(
+                    "not restoring: {}. use --force to restore anyways",
+                    problems.join("; ")
+                );
+            }
+        }
+
+        // the config is not replaced. operators usually change urls and secrets for a new deployment
+        if fs::read(backup_dir.join(CONFIG_FILE))? != fs::read(&top_config_path)? {
+            warn!(
+                "{} does not match the backup's {}",
+                top_config_path.display(),
+                CONFIG_FILE
+            );
+        }
+
+        if restore_db {
+            let db_url = db_url.context("db_url is required to restore the database")?;
+            let db_conn = db_conn.context("a db connection is required")?;
+
+            let (mut cmd, db_name) = mysql_command(&self.mysql, &db_url)?;
+
+            let dump = File::open(backup_dir.join(DATABASE_FILE))?;
+
+            let status = cmd
+                .arg(db_name)
+                .stdin(Stdio::from(dump))
+                .status()
+                .await
+                .context("running mysql")?;
+
+            anyhow::ensure!(status.success(), "mysql failed: {}", status);
+
+            info!("restored the database");
+
+            // bring an older backup up to this version's schema
+            migrate_db(db_conn, false).await?;
+        }
+
+        if manifest.redis && !self.skip_redis {
+            match redis_conn(&top_config).await? {
+                Some(mut redis_conn) => {
+                    let entries = fs::read_to_string(backup_dir.join(REDIS_FILE))?;
+
+                    let entries: Vec<RedisEntry> = serde_json::from_str(&entries)?;
+
+                    let elapsed_ms =
+                        (chrono::Utc::now().timestamp() - manifest.created_at).max(0) as u64 * 1000;
+
+                    let mut restored = 0;
+                    for entry in entries {
+                        // rate limit counters are only useful until their period ends
+                        let ttl_ms = if entry.ttl_ms == 0 {
+                            0
+                        } else if entry.ttl_ms > elapsed_ms {
+                            entry.ttl_ms - elapsed_ms
+                        } else {
+                            continue;
+                        };
+
+                        redis::cmd("RESTORE")
+                            .arg(&entry.key)
+                            .arg(ttl_ms)
+                            .arg(entry.dump.as_ref())
+                            .arg("REPLACE")
+                            .query_async::<_, ()>(&mut redis_conn)
+                            .await?;
+
+                        restored += 1;
+                    }
+
+                    info!("restored {} redis keys", restored);
+                }
+                None => warn!("no volatile_redis_url. skipping redis"),
+            }
+        }
+
+        info!("restored {}", backup_dir.display());
+
+        Ok(())
+    }
+}
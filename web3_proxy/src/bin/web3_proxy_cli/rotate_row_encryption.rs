@@ -0,0 +1,135 @@
+//! Re-encrypt sensitive columns with the newest row encryption key.
+//! Plaintext rows from before encryption was turned on are encrypted too. Running it again is safe
+use argh::FromArgs;
+use entities::{rpc_key, user};
+use log::info;
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder,
+    QuerySelect,
+};
+use web3_proxy::row_encryption::RowCipher;
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// Re-encrypt sensitive columns with the first key in row_encryption_keyfile.
+#[argh(subcommand, name = "rotate_row_encryption")]
+pub struct RotateRowEncryptionSubCommand {
+    #[argh(option, default = "1000")]
+    /// how many rows to load at once
+    batch_size: u64,
+
+    #[argh(switch)]
+    /// count the rows that need rotating without saving anything
+    dry_run: bool,
+}
+
+impl RotateRowEncryptionSubCommand {
+    pub async fn main(
+        self,
+        db_conn: &DatabaseConnection,
+        row_cipher: &RowCipher,
+    ) -> anyhow::Result<()> {
+        let batch_size = self.batch_size.max(1);
+
+        let mut last_id = 0;
+        let mut checked = 0;
+        let mut rotated = 0;
+
+        loop {
+            let users = user::Entity::find()
+                .filter(user::Column::Id.gt(last_id))
+                .filter(user::Column::Email.is_not_null())
+                .order_by_asc(user::Column::Id)
+                .limit(batch_size)
+                .all(db_conn)
+                .await?;
+
+            let num_users = users.len() as u64;
+
+            for u in users {
+                last_id = u.id;
+                checked += 1;
+
+                let email = match u.email.as_ref() {
+                    Some(x) if row_cipher.needs_rotation(x) => x,
+                    _ => continue,
+                };
+
+                let email = row_cipher.encrypt(&row_cipher.decrypt(email)?)?;
+
+                rotated += 1;
+
+                if self.dry_run {
+                    continue;
+                }
+
+                let mut u: user::ActiveModel = u.into();
+
+                u.email = sea_orm::Set(Some(email));
+
+                u.save(db_conn).await?;
+            }
+
+            if num_users < batch_size {
+                break;
+            }
+        }
+
+        if self.dry_run {
+            info!("{}/{} user emails need rotating", rotated, checked);
+        } else {
+            info!("rotated {}/{} user emails", rotated, checked);
+        }
+
+        let mut last_id = 0;
+        let mut checked = 0;
+        let mut rotated = 0;
+
+        loop {
+            let keys = rpc_key::Entity::find()
+                .filter(rpc_key::Column::Id.gt(last_id))
+                .filter(rpc_key::Column::ShadowSecret.is_not_null())
+                .order_by_asc(rpc_key::Column::Id)
+                .limit(batch_size)
+                .all(db_conn)
+                .await?;
+
+            let num_keys = keys.len() as u64;
+
+            for k in keys {
+                last_id = k.id;
+                checked += 1;
+
+                let shadow_secret = match k.shadow_secret.as_ref() {
+                    Some(x) if row_cipher.needs_rotation(x) => x,
+                    _ => continue,
+                };
+
+                let shadow_secret = row_cipher.encrypt(&row_cipher.decrypt(shadow_secret)?)?;
+
+                rotated += 1;
+
+                if self.dry_run {
+                    continue;
+                }
+
+                let mut k: rpc_key::ActiveModel = k.into();
+
+                k.shadow_secret = sea_orm::Set(Some(shadow_secret));
+
+                k.save(db_conn).await?;
+            }
+
+            if num_keys < batch_size {
+                break;
+            }
+        }
+
+        if self.dry_run {
+            info!("{}/{} key shadow secrets need rotating", rotated, checked);
+        } else {
+            info!("rotated {}/{} key shadow secrets", rotated, checked);
+        }
+
+        Ok(())
+    }
+}
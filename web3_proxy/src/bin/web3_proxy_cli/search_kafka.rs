@@ -3,14 +3,17 @@ use argh::FromArgs;
 use entities::rpc_key;
 use futures::TryStreamExt;
 use log::info;
-use migration::sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use migration::sea_orm::{EntityTrait, QueryFilter};
 use rdkafka::{
     consumer::{Consumer, StreamConsumer},
     ClientConfig, Message,
 };
 use std::num::NonZeroU64;
-use uuid::Uuid;
-use web3_proxy::{app::get_db, config::TopConfig, frontend::authorization::RpcSecretKey};
+use web3_proxy::{
+    app::get_db,
+    config::TopConfig,
+    frontend::authorization::{RpcKeyHasher, RpcSecretKey},
+};
 
 /// Second subcommand.
 #[derive(FromArgs, PartialEq, Debug, Eq)]
@@ -35,12 +38,12 @@ impl SearchKafkaSubCommand {
         let mut rpc_key_id = self.rpc_key_id.map(|x| x.get());
 
         if let Some(rpc_key) = self.rpc_key {
-            let db_conn = get_db(top_config.app.db_url.unwrap(), 1, 1).await?;
+            let db_conn = get_db(top_config.app.db_url.clone().unwrap(), 1, 1).await?;
 
-            let rpc_key: Uuid = rpc_key.into();
+            let rpc_key_hasher = RpcKeyHasher::from_config(&top_config.app)?;
 
             let x = rpc_key::Entity::find()
-                .filter(rpc_key::Column::SecretKey.eq(rpc_key))
+                .filter(rpc_key_hasher.condition(rpc_key))
                 .one(&db_conn)
                 .await?
                 .context("key not found")?;
@@ -0,0 +1,373 @@
+//! Compare each key's totals in the relational database with its totals in influx.
+//! rpc_accounting_v2 rows cover a whole billing period, not a day, so `--day` checks the billing period that contains it.
+//! Influx only has per-key stats for keys that opted into tracking. Keys with a `log_level` of "none" are skipped.
+//! Repair points add what influx is missing. They are tagged "repair" so they can be told apart from stats that the proxy saved itself
+use crate::reconcile_stats::matches;
+use anyhow::Context;
+use argh::FromArgs;
+use chrono::{NaiveDate, TimeZone, Utc};
+use entities::sea_orm_active_enums::TrackingLevel;
+use entities::{rpc_accounting_v2, rpc_key};
+use futures::stream;
+use hashbrown::{HashMap, HashSet};
+use influxdb2::api::write::TimestampPrecision;
+use influxdb2::models::{DataPoint, Query};
+use influxdb2_structmap::value::Value;
+use log::{info, warn};
+use migration::sea_orm::prelude::Decimal;
+use migration::sea_orm::{
+    self, ColumnTrait, DatabaseConnection, EntityTrait, FromQueryResult, QueryFilter, QuerySelect,
+};
+use num_traits::ToPrimitive;
+use web3_proxy::app::BILLING_PERIOD_SECONDS;
+use web3_proxy::config::TopConfig;
+
+/// the fields that are compared. the order matches `Totals`
+const FIELDS: [&str; 4] = [
+    "frontend_requests",
+    "sum_credits_used",
+    "sum_request_bytes",
+    "sum_response_bytes",
+];
+
+type Totals = [f64; 4];
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// Check the saved stats.
+#[argh(subcommand, name = "stats")]
+pub struct StatsSubCommand {
+    #[argh(subcommand)]
+    sub_command: StatsSubCommands,
+}
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+#[argh(subcommand)]
+enum StatsSubCommands {
+    Verify(StatsVerifySubCommand),
+}
+
+#[derive(FromArgs, PartialEq, Eq, Debug)]
+/// Check that every key's requests, credits, and bytes in the database match influx.
+#[argh(subcommand, name = "verify")]
+pub struct StatsVerifySubCommand {
+    #[argh(option)]
+    /// the day to check, like 2024-05-01. the whole billing period that contains it is checked
+    day: NaiveDate,
+
+    #[argh(option, default = "1")]
+    /// the largest difference (in parts per thousand) that still counts as a match
+    tolerance_permille: u64,
+
+    #[argh(switch)]
+    /// write points to influx with whatever influx is missing
+    repair: bool,
+}
+
+impl StatsSubCommand {
+    pub async fn main(
+        self,
+        top_config: TopConfig,
+        db_conn: &DatabaseConnection,
+    ) -> anyhow::Result<()> {
+        match self.sub_command {
+            StatsSubCommands::Verify(x) => x.main(top_config, db_conn).await,
+        }
+    }
+}
+
+impl StatsVerifySubCommand {
+    pub async fn main(
+        self,
+        top_config: TopConfig,
+        db_conn: &DatabaseConnection,
+    ) -> anyhow::Result<()> {
+        let chain_id = top_config.app.chain_id;
+
+        let day = Utc.from_utc_datetime(&self.day.and_hms_opt(0, 0, 0).expect("midnight is valid"));
+
+        let period_start = day.timestamp() / BILLING_PERIOD_SECONDS * BILLING_PERIOD_SECONDS;
+        let period_end = period_start + BILLING_PERIOD_SECONDS;
+
+        let influxdb_host = top_config
+            .app
+            .influxdb_host
+            .context("influxdb_host needed to verify stats")?;
+        let influxdb_org = top_config
+            .app
+            .influxdb_org
+            .context("influxdb_org needed to verify stats")?;
+        let influxdb_token = top_config
+            .app
+            .influxdb_token
+            .context("influxdb_token needed to verify stats")?;
+        let influxdb_bucket = top_config
+            .app
+            .influxdb_bucket
+            .context("influxdb_bucket needed to verify stats")?;
+
+        let influxdb_client = influxdb2::Client::new(influxdb_host, influxdb_org, influxdb_token);
+
+        let db = db_key_totals(db_conn, chain_id, period_start).await?;
+
+        let tracked = tracked_keys(db_conn, db.keys().copied().collect()).await?;
+
+        let query = format!(
+            r#"
+            from(bucket: "{influxdb_bucket}")
+                |> range(start: {period_start}, stop: {period_end})
+                |> filter(fn: (r) => r._measurement == "opt_in_proxy")
+                |> filter(fn: (r) => r.chain_id == "{chain_id}")
+                |> filter(fn: (r) => not exists r.imported)
+                |> filter(fn: (r) => contains(value: r._field, set: ["{}"]))
+                |> group(columns: ["rpc_secret_key_id", "_field"])
+                |> sum()
+            "#,
+            FIELDS.join(r#"", ""#)
+        );
+
+        let records = influxdb_client
+            .query_raw(Some(Query::new(query)))
+            .await
+            .context(format!(
+                "querying influx from {} to {}",
+                period_start, period_end
+            ))?;
+
+        let mut tsdb: HashMap<u64, Totals> = HashMap::new();
+
+        for record in records {
+            let rpc_secret_key_id = match record.values.get("rpc_secret_key_id") {
+                Some(Value::String(x)) => match x.parse() {
+                    Ok(x) => x,
+                    Err(_) => {
+                        warn!("unexpected rpc_secret_key_id: {}", x);
+                        continue;
+                    }
+                },
+                x => {
+                    warn!("unexpected rpc_secret_key_id: {:?}", x);
+                    continue;
+                }
+            };
+
+            let value = match record.values.get("_value") {
+                Some(Value::Long(x)) => *x as f64,
+                Some(Value::UnsignedLong(x)) => *x as f64,
+                Some(Value::Double(x)) => f64::from(*x),
+                x => {
+                    warn!("unexpected value: {:?}", x);
+                    continue;
+                }
+            };
+
+            let i = match record.values.get("_field") {
+                Some(Value::String(x)) => match FIELDS.iter().position(|y| *y == x.as_str()) {
+                    Some(i) => i,
+                    None => {
+                        warn!("unexpected field: {}", x);
+                        continue;
+                    }
+                },
+                x => {
+                    warn!("unexpected field: {:?}", x);
+                    continue;
+                }
+            };
+
+            tsdb.entry(rpc_secret_key_id).or_default()[i] = value;
+        }
+
+        let period = Utc
+            .timestamp_opt(period_start, 0)
+            .single()
+            .context("timestamp out of range")?;
+
+        let mut keys: Vec<u64> = tracked.iter().chain(tsdb.keys()).copied().collect();
+        keys.sort_unstable();
+        keys.dedup();
+
+        let num_keys = keys.len();
+
+        let mut mismatches = 0;
+        let mut repair_points = vec![];
+
+        for rpc_key_id in keys {
+            let db = db.get(&rpc_key_id).copied().unwrap_or_default();
+            let tsdb = tsdb.get(&rpc_key_id).copied().unwrap_or_default();
+
+            let mut missing: Totals = Default::default();
+            let mut repairable = true;
+
+            for (i, field) in FIELDS.iter().enumerate() {
+                if matches(db[i], tsdb[i], self.tolerance_permille) {
+                    continue;
+                }
+
+                warn!(
+                    "{}: key {} {} mismatch! db={} influx={}",
+                    period, rpc_key_id, field, db[i], tsdb[i]
+                );
+
+                if db[i] > tsdb[i] {
+                    missing[i] = db[i] - tsdb[i];
+                } else {
+                    // the database is the source of truth for billing. extra stats in influx need a person to look at them
+                    repairable = false;
+                }
+            }
+
+            if missing == Totals::default() && repairable {
+                continue;
+            }
+
+            if self.repair && repairable {
+                repair_points.push(repair_point(chain_id, rpc_key_id, period_start, missing)?);
+            } else {
+                mismatches += 1;
+            }
+        }
+
+        info!(
+            "{}: checked {} keys. {} need repairs. {} cannot be repaired",
+            period,
+            num_keys,
+            repair_points.len(),
+            mismatches
+        );
+
+        if !repair_points.is_empty() {
+            let num_points = repair_points.len();
+
+            influxdb_client
+                .write_with_precision(
+                    &influxdb_bucket,
+                    stream::iter(repair_points),
+                    TimestampPrecision::Seconds,
+                )
+                .await
+                .context("saving repair points")?;
+
+            info!("saved {} repair points", num_points);
+        }
+
+        anyhow::ensure!(mismatches == 0, "{} keys do not match", mismatches);
+
+        Ok(())
+    }
+}
+
+/// Sum each key's accounting entries for the period. Anonymous requests are not included
+async fn db_key_totals(
+    db_conn: &DatabaseConnection,
+    chain_id: u64,
+    period_start: i64,
+) -> anyhow::Result<HashMap<u64, Totals>> {
+    #[derive(Debug, FromQueryResult)]
+    struct SelectResult {
+        rpc_key_id: u64,
+        frontend_requests: Option<Decimal>,
+        sum_credits_used: Option<Decimal>,
+        sum_request_bytes: Option<Decimal>,
+        sum_response_bytes: Option<Decimal>,
+    }
+
+    let period_datetime = Utc
+        .timestamp_opt(period_start, 0)
+        .single()
+        .context("timestamp out of range")?;
+
+    let rows = rpc_accounting_v2::Entity::find()
+        .select_only()
+        .column(rpc_accounting_v2::Column::RpcKeyId)
+        .column_as(
+            rpc_accounting_v2::Column::FrontendRequests.sum(),
+            "frontend_requests",
+        )
+        .column_as(
+            rpc_accounting_v2::Column::SumCreditsUsed.sum(),
+            "sum_credits_used",
+        )
+        .column_as(
+            rpc_accounting_v2::Column::SumRequestBytes.sum(),
+            "sum_request_bytes",
+        )
+        .column_as(
+            rpc_accounting_v2::Column::SumResponseBytes.sum(),
+            "sum_response_bytes",
+        )
+        .filter(rpc_accounting_v2::Column::ChainId.eq(chain_id))
+        .filter(rpc_accounting_v2::Column::PeriodDatetime.eq(period_datetime))
+        .filter(rpc_accounting_v2::Column::RpcKeyId.is_not_null())
+        .group_by(rpc_accounting_v2::Column::RpcKeyId)
+        .into_model::<SelectResult>()
+        .all(db_conn)
+        .await?;
+
+    let to_f64 = |x: Option<Decimal>| x.and_then(|x| x.to_f64()).unwrap_or_default();
+
+    let x = rows
+        .into_iter()
+        .map(|x| {
+            let totals = [
+                to_f64(x.frontend_requests),
+                to_f64(x.sum_credits_used),
+                to_f64(x.sum_request_bytes),
+                to_f64(x.sum_response_bytes),
+            ];
+
+            (x.rpc_key_id, totals)
+        })
+        .collect();
+
+    Ok(x)
+}
+
+/// The keys that save per-key stats to influx
+async fn tracked_keys(
+    db_conn: &DatabaseConnection,
+    rpc_key_ids: Vec<u64>,
+) -> anyhow::Result<HashSet<u64>> {
+    if rpc_key_ids.is_empty() {
+        return Ok(Default::default());
+    }
+
+    let x = rpc_key::Entity::find()
+        .filter(rpc_key::Column::Id.is_in(rpc_key_ids))
+        .filter(rpc_key::Column::LogLevel.ne(TrackingLevel::None))
+        .all(db_conn)
+        .await?
+        .into_iter()
+        .map(|x| x.id)
+        .collect();
+
+    Ok(x)
+}
+
+/// A point with the amounts that influx is missing for a key
+fn repair_point(
+    chain_id: u64,
+    rpc_key_id: u64,
+    period_start: i64,
+    missing: Totals,
+) -> anyhow::Result<DataPoint> {
+    let point = DataPoint::builder("opt_in_proxy")
+        .tag("chain_id", chain_id.to_string())
+        .tag("rpc_secret_key_id", rpc_key_id.to_string())
+        .tag("repair", "stats_verify")
+        .tag("archive_needed", "false")
+        .tag("error_response", "false")
+        .field("frontend_requests", missing[0] as i64)
+        .field("backend_requests", 0i64)
+        .field("no_servers", 0i64)
+        .field("cache_misses", 0i64)
+        .field("cache_hits", 0i64)
+        .field("sum_request_bytes", missing[2] as i64)
+        .field("sum_response_millis", 0i64)
+        .field("sum_response_bytes", missing[3] as i64)
+        .field("sum_credits_used", missing[1])
+        .field("balance", 0f64)
+        .timestamp(period_start)
+        .build()?;
+
+    Ok(point)
+}
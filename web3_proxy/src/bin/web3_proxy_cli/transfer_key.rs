@@ -7,8 +7,7 @@ use migration::sea_orm::{
     self, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
     QueryFilter,
 };
-use sea_orm::prelude::Uuid;
-use web3_proxy::frontend::authorization::RpcSecretKey;
+use web3_proxy::frontend::authorization::{RpcKeyHasher, RpcSecretKey};
 
 /// change a key's owner.
 #[derive(FromArgs, PartialEq, Eq, Debug)]
@@ -24,15 +23,17 @@ pub struct TransferKeySubCommand {
 }
 
 impl TransferKeySubCommand {
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
-        let rpc_secret_key: Uuid = self.rpc_secret_key.into();
-
+    pub async fn main(
+        self,
+        db_conn: &DatabaseConnection,
+        rpc_key_hasher: &RpcKeyHasher,
+    ) -> anyhow::Result<()> {
         let new_address: Address = self.new_address.parse()?;
 
         let new_address: Vec<u8> = new_address.to_fixed_bytes().into();
 
         let uk = rpc_key::Entity::find()
-            .filter(rpc_key::Column::SecretKey.eq(rpc_secret_key))
+            .filter(rpc_key_hasher.condition(self.rpc_secret_key))
             .one(db_conn)
             .await?
             .context("No key found")?;
@@ -11,6 +11,7 @@ use migration::sea_orm::{
 };
 use std::path::{Path, PathBuf};
 use std::{fs::File, io::BufReader};
+use web3_proxy::frontend::authorization::{RpcKeyHasher, RpcSecretKey};
 
 #[derive(FromArgs, PartialEq, Eq, Debug)]
 /// Import users from another database.
@@ -29,7 +30,11 @@ pub struct UserImportSubCommand {
 type UserMap = HashMap<u64, u64>;
 
 impl UserImportSubCommand {
-    pub async fn main(self, db_conn: &DatabaseConnection) -> anyhow::Result<()> {
+    pub async fn main(
+        self,
+        db_conn: &DatabaseConnection,
+        rpc_key_hasher: &RpcKeyHasher,
+    ) -> anyhow::Result<()> {
         let import_dir = Path::new(&self.input_dir);
 
         anyhow::ensure!(
@@ -83,8 +88,9 @@ impl UserImportSubCommand {
         for entry in glob(&rpc_key_glob_path)? {
             match entry {
                 Ok(path) => {
-                    imported_rpc_key_count +=
-                        self.import_rpc_key_file(db_conn, path, &user_map).await?
+                    imported_rpc_key_count += self
+                        .import_rpc_key_file(db_conn, rpc_key_hasher, path, &user_map)
+                        .await?
                 }
                 Err(e) => {
                     warn!(
@@ -156,6 +162,7 @@ impl UserImportSubCommand {
     pub async fn import_rpc_key_file(
         &self,
         db_conn: &DatabaseConnection,
+        rpc_key_hasher: &RpcKeyHasher,
         path: PathBuf,
         user_map: &UserMap,
     ) -> anyhow::Result<u64> {
@@ -173,8 +180,23 @@ impl UserImportSubCommand {
                 .get(&import_rk.user_id)
                 .context("user mapping required")?;
 
+            // lookups only check the hash, so plaintext secrets from older exports are hashed here.
+            // hashed keys only match if both databases use the same rpc_key_hash_keyfile
+            let secret_key_hash = match (import_rk.secret_key, import_rk.secret_key_hash.as_ref()) {
+                (Some(secret_key), _) => rpc_key_hasher.hash(RpcSecretKey::Uuid(secret_key)),
+                (None, Some(secret_key_hash)) => secret_key_hash.clone(),
+                (None, None) => {
+                    return Err(anyhow::anyhow!(
+                        "rpc key #{} has no secret key or hash",
+                        import_rk.id
+                    ))
+                }
+            };
+
+            let same_key = rpc_key::Column::SecretKeyHash.eq(secret_key_hash.clone());
+
             if let Some(existing_rk) = rpc_key::Entity::find()
-                .filter(rpc_key::Column::SecretKey.eq(import_rk.secret_key))
+                .filter(same_key)
                 .one(db_conn)
                 .await?
             {
@@ -188,6 +210,8 @@ impl UserImportSubCommand {
 
                 new_rk.id = NotSet;
                 new_rk.user_id = Set(mapped_id);
+                new_rk.secret_key = Set(None);
+                new_rk.secret_key_hash = Set(Some(secret_key_hash));
 
                 new_rk.save(db_conn).await?;
 
@@ -130,7 +130,15 @@ pub async fn block_needed(
 ) -> Web3ProxyResult<BlockNeeded> {
     // some requests have potentially very large responses
     // TODO: only skip caching if the response actually is large
-    if method.starts_with("trace_") || method == "debug_traceTransaction" {
+    if method.starts_with("trace_")
+        || method.starts_with("arbtrace_")
+        || method == "debug_traceTransaction"
+    {
+        return Ok(BlockNeeded::CacheNever);
+    }
+
+    // op-node's sync status follows L1 too, so it can change without a new L2 block
+    if method == "optimism_syncStatus" {
         return Ok(BlockNeeded::CacheNever);
     }
 
@@ -161,9 +169,20 @@ pub async fn block_needed(
         }
         "eth_getBlockByNumber" => {
             // TODO: double check that any node can serve this
-            // TODO: CacheSuccessForever if the block is old enough
+            // cached with the requested block instead of the head. an old (or finalized) block stays cached after new heads
+            // blocks past the head do not exist yet, so those are still cached with the head
+            let block_num =
+                match clean_block_number(authorization, params, 0, head_block_num, rpcs).await {
+                    Ok(x) if x <= head_block_num => x,
+                    Ok(_) => head_block_num,
+                    Err(err) => {
+                        warn!("could not get block from params. err={:?}", err);
+                        head_block_num
+                    }
+                };
+
             return Ok(BlockNeeded::Cache {
-                block_num: head_block_num,
+                block_num,
                 cache_errors: true,
             });
         }
@@ -179,11 +198,9 @@ pub async fn block_needed(
             // TODO: jsonrpc has a specific code for this
             let obj = params
                 .get_mut(0)
-                .ok_or_else(|| Web3ProxyError::BadRequest("invalid format. no params".to_string()))?
+                .ok_or_else(|| Web3ProxyError::InvalidRpcParams("no params".into()))?
                 .as_object_mut()
-                .ok_or_else(|| {
-                    Web3ProxyError::BadRequest("invalid format. params not object".to_string())
-                })?;
+                .ok_or_else(|| Web3ProxyError::InvalidRpcParams("params not object".into()))?;
 
             if obj.contains_key("blockHash") {
                 return Ok(BlockNeeded::CacheSuccessForever);
@@ -265,6 +282,9 @@ pub async fn block_needed(
             return Ok(BlockNeeded::CacheSuccessForever);
         }
         "eth_getUncleCountByBlockNumber" => 0,
+        // rollup methods. anything else in their namespaces is cached with the head block
+        "optimism_outputAtBlock" => 0,
+        "zkevm_batchNumberByBlockNumber" => 0,
         _ => {
             // some other command that doesn't take block numbers as an argument
             // since we are caching with the head block, it should be safe to cache_errors
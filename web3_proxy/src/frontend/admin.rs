@@ -1,15 +1,23 @@
 //! Handle admin helper logic
 
+use super::admin_audit::{json_changes, AdminAction};
 use super::authorization::login_is_authorized;
 use super::errors::Web3ProxyResponse;
+use super::idempotency::{idempotency_key, with_idempotency_key};
+use super::users::rpc_keys::{parse_allowed_origins, parse_allowed_referers};
 use crate::admin_queries::query_admin_modify_usertier;
-use crate::app::Web3ProxyApp;
+use crate::app::{
+    CachePurgeSelector, ReadOnlySwitch, TierRebalance, TierRebalanceRollback, Web3ProxyApp,
+};
 use crate::frontend::errors::{Web3ProxyError, Web3ProxyErrorContext};
+use crate::http_params::{
+    AdminAuditParams, AdminIncreaseBalanceParams, AdminModifyRoleParams, AdminWalletParams, Params,
+};
 use crate::user_token::UserBearerToken;
 use crate::PostLogin;
 use anyhow::Context;
 use axum::{
-    extract::{Path, Query},
+    extract::Path,
     headers::{authorization::Bearer, Authorization},
     response::IntoResponse,
     Extension, Json, TypedHeader,
@@ -18,20 +26,24 @@ use axum_client_ip::InsecureClientIp;
 use axum_macros::debug_handler;
 use chrono::{TimeZone, Utc};
 use entities::{
-    admin, admin_increase_balance_receipt, admin_trail, balance, login, pending_login, rpc_key,
-    user, user_tier,
+    admin, admin_audit, admin_increase_balance_receipt, admin_trail, balance, login, org_member,
+    pending_login, rpc_accounting_v2, rpc_key, secondary_user, user, user_tier,
 };
 use ethers::{prelude::Address, types::Bytes};
 use hashbrown::HashMap;
-use http::StatusCode;
+use http::{HeaderMap, StatusCode};
 use log::{debug, info, warn};
 use migration::sea_orm::prelude::{Decimal, Uuid};
 use migration::sea_orm::{
-    self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter,
+    self, ActiveModelTrait, ColumnTrait, EntityTrait, FromQueryResult, IntoActiveModel,
+    PaginatorTrait, QueryFilter, QueryOrder, QuerySelect,
 };
 use migration::{Expr, OnConflict};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use siwe::{Message, VerificationOpts};
+use std::net::IpAddr;
 use std::ops::Add;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -45,12 +57,16 @@ use ulid::Ulid;
 #[debug_handler]
 pub async fn admin_increase_balance(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
-    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    Params(params): Params<AdminIncreaseBalanceParams>,
 ) -> Web3ProxyResponse {
     let (caller, _) = app.bearer_is_authorized(bearer).await?;
     let caller_id = caller.id;
 
+    let idempotency_key = idempotency_key(&headers)?;
+
     // Establish connections
     let db_conn = app
         .db_conn()
@@ -63,51 +79,52 @@ pub async fn admin_increase_balance(
         .await?
         .ok_or(Web3ProxyError::AccessDenied)?;
 
+    // the same params with the same key are the same request
+    let request_fingerprint = format!("{:?}", params);
+
+    with_idempotency_key(
+        &app,
+        caller_id,
+        idempotency_key,
+        "admin_increase_balance",
+        request_fingerprint,
+        _admin_increase_balance(app.clone(), admin_entry, ip, params),
+    )
+    .await
+}
+
+async fn _admin_increase_balance(
+    app: Arc<Web3ProxyApp>,
+    admin_entry: admin::Model,
+    ip: IpAddr,
+    params: AdminIncreaseBalanceParams,
+) -> Web3ProxyResponse {
+    let db_conn = app
+        .db_conn()
+        .context("query_admin_modify_user needs a db")?;
+
     // Get the user from params
     let user_address: Address = params
-        .get("user_address")
-        .ok_or_else(|| {
-            Web3ProxyError::BadRequest("Unable to find user_address key in request".to_string())
-        })?
-        .parse::<Address>()
-        .map_err(|_| {
-            Web3ProxyError::BadRequest("Unable to parse user_address as an Address".to_string())
-        })?;
+        .user_address
+        .ok_or(Web3ProxyError::MissingParam("user_address"))?;
     let user_address_bytes: Vec<u8> = user_address.to_fixed_bytes().into();
-    let note: String = params
-        .get("note")
-        .ok_or_else(|| {
-            Web3ProxyError::BadRequest("Unable to find 'note' key in request".to_string())
-        })?
-        .parse::<String>()
-        .map_err(|_| {
-            Web3ProxyError::BadRequest("Unable to parse 'note' as a String".to_string())
-        })?;
+    let note: String = params.note.ok_or(Web3ProxyError::MissingParam("note"))?;
     // Get the amount from params
-    // Decimal::from_str
     let amount: Decimal = params
-        .get("amount")
-        .ok_or_else(|| {
-            Web3ProxyError::BadRequest("Unable to get the amount key from the request".to_string())
-        })
-        .map(|x| Decimal::from_str(x))?
-        .map_err(|err| {
-            Web3ProxyError::BadRequest(format!("Unable to parse amount from the request {:?}", err))
-        })?;
+        .amount
+        .ok_or(Web3ProxyError::MissingParam("amount"))?;
 
     let user_entry: user::Model = user::Entity::find()
         .filter(user::Column::Address.eq(user_address_bytes.clone()))
         .one(&db_conn)
         .await?
-        .ok_or(Web3ProxyError::BadRequest(
-            "No user with this id found".to_string(),
-        ))?;
+        .ok_or(Web3ProxyError::UserNotFound)?;
 
     let increase_balance_receipt = admin_increase_balance_receipt::ActiveModel {
         amount: sea_orm::Set(amount),
         admin_id: sea_orm::Set(admin_entry.id),
         deposit_to_user_id: sea_orm::Set(user_entry.id),
-        note: sea_orm::Set(note),
+        note: sea_orm::Set(note.clone()),
         ..Default::default()
     };
     increase_balance_receipt.save(&db_conn).await?;
@@ -133,6 +150,8 @@ pub async fn admin_increase_balance(
         .await?
         .context("Premium tier was not found!")?;
 
+    let old_balance = balance_entry.available_balance;
+
     let balance_entry = balance_entry.into_active_model();
     balance::Entity::insert(balance_entry)
         .on_conflict(
@@ -161,6 +180,23 @@ pub async fn admin_increase_balance(
         .await?;
     // TODO: Downgrade otherwise, right now not functioning properly
 
+    let new_balance = balance::Entity::find()
+        .filter(balance::Column::UserId.eq(user_entry.id))
+        .one(&db_conn)
+        .await?
+        .map(|x| x.available_balance);
+
+    AdminAction::new("increase_balance")
+        .target_user(user_entry.id)
+        .old_value(json!({ "available_balance": old_balance }))
+        .new_value(json!({
+            "available_balance": new_balance,
+            "amount": amount,
+            "note": note,
+        }))
+        .save(&db_conn, admin_entry.user_id, ip)
+        .await?;
+
     // Then read and save in one transaction
     let response = (StatusCode::OK, Json(out)).into_response();
 
@@ -174,10 +210,11 @@ pub async fn admin_increase_balance(
 #[debug_handler]
 pub async fn admin_change_user_roles(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
-    Query(params): Query<HashMap<String, String>>,
+    Params(params): Params<AdminModifyRoleParams>,
 ) -> Web3ProxyResponse {
-    let response = query_admin_modify_usertier(&app, bearer, &params).await?;
+    let response = query_admin_modify_usertier(&app, ip, bearer, &params).await?;
 
     Ok(response)
 }
@@ -217,24 +254,16 @@ pub async fn admin_login_get(
     // get the admin field ...
     let admin_address: Address = params
         .get("admin_address")
-        .ok_or_else(|| {
-            Web3ProxyError::BadRequest("Unable to find admin_address key in request".to_string())
-        })?
+        .ok_or_else(|| Web3ProxyError::MissingParam("admin_address"))?
         .parse::<Address>()
-        .map_err(|_err| {
-            Web3ProxyError::BadRequest("Unable to parse admin_address as an Address".to_string())
-        })?;
+        .map_err(|_err| Web3ProxyError::InvalidParam("admin_address", "not an address".into()))?;
 
     // Fetch the user_address parameter from the login string ... (as who we want to be logging in ...)
     let user_address: Vec<u8> = params
         .get("user_address")
-        .ok_or_else(|| {
-            Web3ProxyError::BadRequest("Unable to find user_address key in request".to_string())
-        })?
+        .ok_or_else(|| Web3ProxyError::MissingParam("user_address"))?
         .parse::<Address>()
-        .map_err(|_err| {
-            Web3ProxyError::BadRequest("Unable to parse user_address as an Address".to_string())
-        })?
+        .map_err(|_err| Web3ProxyError::InvalidParam("user_address", "not an address".into()))?
         .to_fixed_bytes()
         .into();
 
@@ -283,9 +312,7 @@ pub async fn admin_login_get(
         .filter(user::Column::Address.eq(user_address))
         .one(db_replica.conn())
         .await?
-        .ok_or(Web3ProxyError::BadRequest(
-            "Could not find user in db".to_string(),
-        ))?;
+        .ok_or(Web3ProxyError::UserNotFound)?;
 
     // TODO: Gotta check if encoding messes up things maybe ...
     info!("Admin address is: {:?}", admin_address);
@@ -294,9 +321,7 @@ pub async fn admin_login_get(
         .filter(user::Column::Address.eq(admin_address))
         .one(db_replica.conn())
         .await?
-        .ok_or(Web3ProxyError::BadRequest(
-            "Could not find admin in db".to_string(),
-        ))?;
+        .ok_or(Web3ProxyError::UserNotFound)?;
 
     // Note that the admin is trying to log in as this user
     let trail = admin_trail::ActiveModel {
@@ -485,6 +510,11 @@ pub async fn admin_login_post(
         .await
         .web3_context("saving an admin trail post login")?;
 
+    AdminAction::new("imitate_login")
+        .target_user(imitating_user.id)
+        .save(&db_conn, admin.id, ip)
+        .await?;
+
     // I supposed we also get the rpc_key, whatever this is used for (?).
     // I think the RPC key should still belong to the admin though in this case ...
 
@@ -589,3 +619,732 @@ pub async fn admin_logout_post(
     // TODO: what should the response be? probably json something
     Ok("goodbye".into_response())
 }
+
+/// `POST /admin/cache/purge` -- As an admin, evict bad data from the response and block caches.
+///
+/// The JSON body selects what to purge. See `CachePurgeSelector`.
+#[debug_handler]
+pub async fn admin_cache_purge(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(selector): Json<CachePurgeSelector>,
+) -> Web3ProxyResponse {
+    let (caller, _) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().context("admin_cache_purge needs a db")?;
+
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(&db_conn)
+        .await?
+        .ok_or(Web3ProxyError::AccessDenied)?;
+
+    let result = app.purge_caches(&selector).await?;
+
+    AdminAction::new("cache_purge")
+        .new_value(json!({ "selector": selector, "result": result }))
+        .save(&db_conn, caller.id, ip)
+        .await?;
+
+    Ok(Json(result).into_response())
+}
+
+/// `POST /admin/read_only` -- As an admin, reject state-changing methods while still serving reads.
+///
+/// The JSON body is a `ReadOnlySwitch`. Without a `chain_id`, every chain is switched.
+#[debug_handler]
+pub async fn admin_read_only_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(switch): Json<ReadOnlySwitch>,
+) -> Web3ProxyResponse {
+    let (caller, _) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().context("admin_read_only needs a db")?;
+
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(&db_conn)
+        .await?
+        .ok_or(Web3ProxyError::AccessDenied)?;
+
+    let old_status = app.read_only_status();
+
+    let status = app.set_read_only(&switch).await?;
+
+    AdminAction::new("read_only")
+        .old_value(json!({ "status": old_status }))
+        .new_value(json!({ "switch": switch, "status": status }))
+        .save(&db_conn, caller.id, ip)
+        .await?;
+
+    let response_json = json!({
+        "chain_id": app.config.chain_id,
+        "read_only": status,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `POST /admin/tiers/rebalance` -- As an admin, move every user on some tiers to new tiers after pricing changes.
+///
+/// The JSON body is a `TierRebalance`. With `dry_run`, nothing changes and the response shows who would move.
+#[debug_handler]
+pub async fn admin_tiers_rebalance_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(rebalance): Json<TierRebalance>,
+) -> Web3ProxyResponse {
+    let (caller, _) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().context("admin_tiers_rebalance needs a db")?;
+
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(&db_conn)
+        .await?
+        .ok_or(Web3ProxyError::AccessDenied)?;
+
+    let result = app.rebalance_tiers(&rebalance).await?;
+
+    if !result.dry_run {
+        AdminAction::new("tiers_rebalance")
+            .target(format!("rebalance:{}", result.rebalance_id))
+            .new_value(json!({ "rebalance": rebalance, "result": result }))
+            .save(&db_conn, caller.id, ip)
+            .await?;
+    }
+
+    Ok(Json(result).into_response())
+}
+
+/// `POST /admin/tiers/rebalance/:rebalance_id/rollback` -- As an admin, move the users from a rebalance back to their old tiers.
+///
+/// Users that changed tiers again since the rebalance are left alone.
+#[debug_handler]
+pub async fn admin_tiers_rebalance_rollback_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rebalance_id): Path<String>,
+    Json(rollback): Json<TierRebalanceRollback>,
+) -> Web3ProxyResponse {
+    let (caller, _) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().context("admin_tiers_rebalance needs a db")?;
+
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(&db_conn)
+        .await?
+        .ok_or(Web3ProxyError::AccessDenied)?;
+
+    let result = app
+        .rollback_tier_rebalance(&rebalance_id, rollback.dry_run)
+        .await?;
+
+    if !result.dry_run {
+        AdminAction::new("tiers_rebalance_rollback")
+            .target(format!("rebalance:{}", rebalance_id))
+            .new_value(json!(result))
+            .save(&db_conn, caller.id, ip)
+            .await?;
+    }
+
+    Ok(Json(result).into_response())
+}
+
+/// `POST /admin/status/snapshots` -- As an admin, save the current backend health, error rates, and recent anomalies.
+///
+/// The snapshot is public and never changes. Support can link users to it.
+#[debug_handler]
+pub async fn admin_status_snapshot_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    let (caller, _) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().context("admin_status_snapshot needs a db")?;
+
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(&db_conn)
+        .await?
+        .ok_or(Web3ProxyError::AccessDenied)?;
+
+    let snapshot = app.create_status_snapshot(Some(caller.id)).await?;
+
+    AdminAction::new("status_snapshot")
+        .target(format!("status_snapshot:{}", snapshot.content_hash))
+        .save(&db_conn, caller.id, ip)
+        .await?;
+
+    let url = format!(
+        "{}/status/snapshots/{}",
+        app.config
+            .public_url
+            .as_deref()
+            .unwrap_or_default()
+            .trim_end_matches('/'),
+        snapshot.content_hash
+    );
+
+    let response_json = json!({
+        "content_hash": snapshot.content_hash,
+        "created_at": snapshot.created_at,
+        "url": url,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// The JSON body of `POST /admin/rpc_keys/:rpc_key_id/origins`.
+/// Lists are comma separated like in `POST /user/keys`. Missing fields are not changed and empty strings clear the list
+#[derive(Debug, Deserialize)]
+pub struct AdminKeyOrigins {
+    allowed_origins: Option<String>,
+    allowed_referers: Option<String>,
+}
+
+/// `GET /admin/rpc_keys/:rpc_key_id/origins` -- As an admin, see which origins and referers can use a key.
+#[debug_handler]
+pub async fn admin_rpc_key_origins_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+) -> Web3ProxyResponse {
+    let (caller, _) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app
+        .db_replica()
+        .context("admin_rpc_key_origins needs a db")?;
+
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(db_replica.conn())
+        .await?
+        .ok_or(Web3ProxyError::AccessDenied)?;
+
+    let key = rpc_key::Entity::find_by_id(rpc_key_id)
+        .one(db_replica.conn())
+        .await?
+        .ok_or(Web3ProxyError::RpcKeyNotFound)?;
+
+    let response_json = json!({
+        "rpc_key_id": key.id,
+        "allowed_origins": key.allowed_origins,
+        "allowed_referers": key.allowed_referers,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `POST /admin/rpc_keys/:rpc_key_id/origins` -- As an admin, change which origins and referers can use a key.
+///
+/// Requests from anywhere else get a 403 before they are sent to any backend.
+#[debug_handler]
+pub async fn admin_rpc_key_origins_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+    Json(payload): Json<AdminKeyOrigins>,
+) -> Web3ProxyResponse {
+    let (caller, _) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().context("admin_rpc_key_origins needs a db")?;
+
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(&db_conn)
+        .await?
+        .ok_or(Web3ProxyError::AccessDenied)?;
+
+    let key = rpc_key::Entity::find_by_id(rpc_key_id)
+        .one(&db_conn)
+        .await?
+        .ok_or(Web3ProxyError::RpcKeyNotFound)?;
+
+    let old_value = json!({
+        "allowed_origins": key.allowed_origins,
+        "allowed_referers": key.allowed_referers,
+    });
+
+    let mut key = key.into_active_model();
+
+    if let Some(allowed_origins) = payload.allowed_origins.as_ref() {
+        key.allowed_origins = sea_orm::Set(parse_allowed_origins(allowed_origins)?);
+    }
+
+    if let Some(allowed_referers) = payload.allowed_referers.as_ref() {
+        key.allowed_referers = sea_orm::Set(parse_allowed_referers(allowed_referers)?);
+    }
+
+    let key = key.update(&db_conn).await?;
+
+    // the new lists apply on the key's next request instead of when the cache expires
+    app.rpc_secret_key_cache
+        .remove_matching(|_, x| x.rpc_secret_key_id.map(|x| x.get()) == Some(key.id));
+    app.rpc_key_id_cache
+        .remove_matching(|x, _| x.get() == key.id);
+
+    let new_value = json!({
+        "allowed_origins": key.allowed_origins,
+        "allowed_referers": key.allowed_referers,
+    });
+
+    AdminAction::new("rpc_key_origins")
+        .target_user(key.user_id)
+        .target(format!("rpc_key:{}", key.id))
+        .old_value(old_value)
+        .new_value(new_value)
+        .save(&db_conn, caller.id, ip)
+        .await?;
+
+    let response_json = json!({
+        "rpc_key_id": key.id,
+        "allowed_origins": key.allowed_origins,
+        "allowed_referers": key.allowed_referers,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `POST /admin/rpc_keys/:rpc_key_id/freeze` -- As an admin, stop a leaked key from working on every proxy within seconds.
+///
+/// Open websockets that use the key are closed.
+#[debug_handler]
+pub async fn admin_rpc_key_freeze_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+) -> Web3ProxyResponse {
+    _admin_rpc_key_frozen(app, ip, bearer, rpc_key_id, true).await
+}
+
+/// `POST /admin/rpc_keys/:rpc_key_id/unfreeze` -- As an admin, let a frozen key work again.
+#[debug_handler]
+pub async fn admin_rpc_key_unfreeze_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+) -> Web3ProxyResponse {
+    _admin_rpc_key_frozen(app, ip, bearer, rpc_key_id, false).await
+}
+
+async fn _admin_rpc_key_frozen(
+    app: Arc<Web3ProxyApp>,
+    ip: IpAddr,
+    bearer: Bearer,
+    rpc_key_id: u64,
+    frozen: bool,
+) -> Web3ProxyResponse {
+    let (caller, _) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().context("admin_rpc_key_freeze needs a db")?;
+
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(&db_conn)
+        .await?
+        .ok_or(Web3ProxyError::AccessDenied)?;
+
+    let old_active = rpc_key::Entity::find_by_id(rpc_key_id)
+        .one(&db_conn)
+        .await?
+        .ok_or(Web3ProxyError::RpcKeyNotFound)?
+        .active;
+
+    let key = app.set_rpc_key_frozen(rpc_key_id, frozen).await?;
+
+    AdminAction::new(if frozen {
+        "rpc_key_freeze"
+    } else {
+        "rpc_key_unfreeze"
+    })
+    .target_user(key.user_id)
+    .target(format!("rpc_key:{}", key.id))
+    .old_value(json!({ "active": old_active }))
+    .new_value(json!({ "active": key.active }))
+    .save(&db_conn, caller.id, ip)
+    .await?;
+
+    let response_json = json!({
+        "rpc_key_id": key.id,
+        "user_id": key.user_id,
+        "active": key.active,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `GET /admin/bots` -- As an admin, see which keys and ips this proxy has tagged as probable bots.
+///
+/// Tags are kept in memory, so every proxy has its own list.
+#[debug_handler]
+pub async fn admin_bots_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    let (caller, _) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app.db_replica().context("admin_bots needs a db")?;
+
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(db_replica.conn())
+        .await?
+        .ok_or(Web3ProxyError::AccessDenied)?;
+
+    let response_json = json!({
+        "chain_id": app.config.chain_id,
+        "enabled": app.config.bot_detection.enabled,
+        "bots": app.tagged_bots(),
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `GET /admin/tasks` -- As an admin, see the status of this proxy's background tasks.
+///
+/// Tasks are in the order they were started. They are stopped in reverse.
+#[debug_handler]
+pub async fn admin_tasks_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    let (caller, _) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app.db_replica().context("admin_tasks needs a db")?;
+
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(db_replica.conn())
+        .await?
+        .ok_or(Web3ProxyError::AccessDenied)?;
+
+    let response_json = json!({
+        "chain_id": app.config.chain_id,
+        "healthy": app.tasks_healthy(),
+        "tasks": app.tasks.statuses(),
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// One key's (or all of a wallet's keys') stats from the accounting database
+#[derive(Debug, Default, Serialize)]
+struct WalletUsage {
+    frontend_requests: u64,
+    backend_requests: u64,
+    cache_hits: u64,
+    sum_response_bytes: u64,
+    credits_used: Decimal,
+}
+
+impl WalletUsage {
+    fn add(&mut self, other: &Self) {
+        self.frontend_requests += other.frontend_requests;
+        self.backend_requests += other.backend_requests;
+        self.cache_hits += other.cache_hits;
+        self.sum_response_bytes += other.sum_response_bytes;
+        self.credits_used += other.credits_used;
+    }
+}
+
+/// `GET /admin/wallets/:address` -- As an admin, look up everything about a wallet address in one call.
+///
+/// Support requests arrive with an address instead of internal ids.
+/// Returns the user with that address, their balance, their keys (owned and shared with them), their orgs,
+/// and their keys' stats totaled over the last `days` days.
+#[debug_handler]
+pub async fn admin_wallet_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(address): Path<String>,
+    Params(params): Params<AdminWalletParams>,
+) -> Web3ProxyResponse {
+    let (caller, _) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().context("admin_wallet needs a db")?;
+
+    let admin: admin::Model = admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(&db_conn)
+        .await?
+        .ok_or(Web3ProxyError::AccessDenied)?;
+
+    let address = Address::from_str(&address)
+        .map_err(|_| Web3ProxyError::InvalidParam("address", "not a valid address".into()))?;
+
+    // looking at a user's data is recorded the same as changing it
+    let trail = admin_trail::ActiveModel {
+        caller: sea_orm::Set(admin.id),
+        imitating_user: sea_orm::Set(None),
+        endpoint: sea_orm::Set("admin_wallet".to_string()),
+        payload: sea_orm::Set(format!("{:?} {:?}", address, params)),
+        ..Default::default()
+    };
+    trail
+        .save(&db_conn)
+        .await
+        .web3_context("saving an admin trail for a wallet lookup")?;
+
+    let db_replica = app
+        .db_replica()
+        .web3_context("admin_wallet needs a db replica")?;
+
+    let query_stop = Utc::now();
+    let query_start = query_stop - chrono::Duration::days(params.days.min(3660) as i64);
+
+    let user = user::Entity::find()
+        .filter(user::Column::Address.eq(address.as_bytes()))
+        .one(db_replica.conn())
+        .await?;
+
+    let user = match user {
+        Some(x) => x,
+        None => {
+            let response_json = json!({
+                "address": address,
+                "user": null,
+            });
+
+            return Ok((StatusCode::NOT_FOUND, Json(response_json)).into_response());
+        }
+    };
+
+    let balance = balance::Entity::find()
+        .filter(balance::Column::UserId.eq(user.id))
+        .one(db_replica.conn())
+        .await?;
+
+    let owned_keys = rpc_key::Entity::find()
+        .filter(rpc_key::Column::UserId.eq(user.id))
+        .all(db_replica.conn())
+        .await?;
+
+    let shared_keys = secondary_user::Entity::find()
+        .filter(secondary_user::Column::UserId.eq(user.id))
+        .all(db_replica.conn())
+        .await?;
+
+    let orgs = org_member::Entity::find()
+        .filter(org_member::Column::UserId.eq(user.id))
+        .all(db_replica.conn())
+        .await?;
+
+    #[derive(Debug, FromQueryResult)]
+    struct KeyUsage {
+        rpc_key_id: u64,
+        frontend_requests: Decimal,
+        backend_requests: Decimal,
+        cache_hits: Decimal,
+        sum_response_bytes: Decimal,
+        credits_used: Decimal,
+    }
+
+    let key_ids: Vec<u64> = owned_keys
+        .iter()
+        .map(|x| x.id)
+        .chain(shared_keys.iter().map(|x| x.rpc_secret_key_id))
+        .collect();
+
+    let usage: HashMap<u64, KeyUsage> = if key_ids.is_empty() {
+        HashMap::new()
+    } else {
+        let mut q = rpc_accounting_v2::Entity::find()
+            .select_only()
+            .column(rpc_accounting_v2::Column::RpcKeyId)
+            .column_as(
+                rpc_accounting_v2::Column::FrontendRequests.sum(),
+                "frontend_requests",
+            )
+            .column_as(
+                rpc_accounting_v2::Column::BackendRequests.sum(),
+                "backend_requests",
+            )
+            .column_as(rpc_accounting_v2::Column::CacheHits.sum(), "cache_hits")
+            .column_as(
+                rpc_accounting_v2::Column::SumResponseBytes.sum(),
+                "sum_response_bytes",
+            )
+            .column_as(
+                rpc_accounting_v2::Column::SumCreditsUsed.sum(),
+                "credits_used",
+            )
+            .filter(rpc_accounting_v2::Column::RpcKeyId.is_in(key_ids))
+            .filter(rpc_accounting_v2::Column::PeriodDatetime.gte(query_start));
+
+        if let Some(chain_id) = params.chain_id {
+            q = q.filter(rpc_accounting_v2::Column::ChainId.eq(chain_id));
+        }
+
+        q.group_by(rpc_accounting_v2::Column::RpcKeyId)
+            .into_model::<KeyUsage>()
+            .all(db_replica.conn())
+            .await?
+            .into_iter()
+            .map(|x| (x.rpc_key_id, x))
+            .collect()
+    };
+
+    let mut totals = WalletUsage::default();
+
+    let mut key_stats = |rpc_key_id: u64| -> Option<WalletUsage> {
+        let usage = usage.get(&rpc_key_id)?;
+
+        let x = WalletUsage {
+            frontend_requests: usage.frontend_requests.to_u64().unwrap_or_default(),
+            backend_requests: usage.backend_requests.to_u64().unwrap_or_default(),
+            cache_hits: usage.cache_hits.to_u64().unwrap_or_default(),
+            sum_response_bytes: usage.sum_response_bytes.to_u64().unwrap_or_default(),
+            credits_used: usage.credits_used,
+        };
+
+        totals.add(&x);
+
+        Some(x)
+    };
+
+    // the secrets are never shown. not even to admins
+    let owned_keys: Vec<_> = owned_keys
+        .into_iter()
+        .map(|x| {
+            json!({
+                "rpc_key_id": x.id,
+                "description": x.description,
+                "active": x.active,
+                "org_id": x.org_id,
+                "stats": key_stats(x.id),
+            })
+        })
+        .collect();
+
+    let shared_keys: Vec<_> = shared_keys
+        .into_iter()
+        .map(|x| {
+            json!({
+                "rpc_key_id": x.rpc_secret_key_id,
+                "role": x.role,
+                "stats": key_stats(x.rpc_secret_key_id),
+            })
+        })
+        .collect();
+
+    let orgs: Vec<_> = orgs
+        .into_iter()
+        .map(|x| json!({ "org_id": x.org_id, "role": x.role }))
+        .collect();
+
+    let response_json = json!({
+        "address": address,
+        "user": {
+            "id": user.id,
+            "description": user.description,
+            "user_tier_id": user.user_tier_id,
+        },
+        "balance": balance.map(|x| json!({
+            "available_balance": x.available_balance,
+            "used_balance": x.used_balance,
+        })),
+        "keys": owned_keys,
+        "shared_keys": shared_keys,
+        "orgs": orgs,
+        "stats": {
+            "query_start": query_start.timestamp(),
+            "query_stop": query_stop.timestamp(),
+            "chain_id": params.chain_id,
+            "totals": totals,
+        },
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `GET /admin/audit` -- As an admin, see the changes that admins have made. Newest first.
+///
+/// Filter with `?user_id=`, `?actor_id=`, `?action=`, `?query_start=`, and `?query_stop=`. Each page has 100 changes.
+#[debug_handler]
+pub async fn admin_audit_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Params(params): Params<AdminAuditParams>,
+) -> Web3ProxyResponse {
+    let (caller, _) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app.db_replica().context("admin_audit needs a db")?;
+
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(db_replica.conn())
+        .await?
+        .ok_or(Web3ProxyError::AccessDenied)?;
+
+    let page_size = 100;
+
+    let mut q = admin_audit::Entity::find()
+        .filter(admin_audit::Column::CreatedAt.gte(params.query_start))
+        .filter(admin_audit::Column::CreatedAt.lt(params.query_stop))
+        .order_by_desc(admin_audit::Column::Id);
+
+    if let Some(user_id) = params.user_id {
+        q = q.filter(admin_audit::Column::TargetUserId.eq(user_id));
+    }
+
+    if let Some(actor_id) = params.actor_id {
+        q = q.filter(admin_audit::Column::ActorId.eq(actor_id));
+    }
+
+    if let Some(action) = params.action.as_ref() {
+        q = q.filter(admin_audit::Column::Action.eq(action.as_str()));
+    }
+
+    let paginator = q.paginate(db_replica.conn(), page_size);
+
+    let num_items_and_pages = paginator.num_items_and_pages().await?;
+
+    let items = paginator.fetch_page(params.page).await?;
+
+    let parse = |x: Option<&String>| {
+        x.and_then(|x| serde_json::from_str(x).ok())
+            .unwrap_or(serde_json::Value::Null)
+    };
+
+    let items: Vec<_> = items
+        .into_iter()
+        .map(|x| {
+            let old_value = parse(x.old_value.as_ref());
+            let new_value = parse(x.new_value.as_ref());
+
+            json!({
+                "id": x.id,
+                "actor_id": x.actor_id,
+                "ip": x.ip,
+                "action": x.action,
+                "target_user_id": x.target_user_id,
+                "target": x.target,
+                "created_at": x.created_at,
+                "changes": json_changes(&old_value, &new_value),
+                "old_value": old_value,
+                "new_value": new_value,
+            })
+        })
+        .collect();
+
+    let response_json = json!({
+        "page": params.page,
+        "page_size": page_size,
+        "num_items": num_items_and_pages.number_of_items,
+        "num_pages": num_items_and_pages.number_of_pages,
+        "items": items,
+    });
+
+    Ok(Json(response_json).into_response())
+}
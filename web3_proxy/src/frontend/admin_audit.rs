@@ -0,0 +1,157 @@
+//! A record of every change an admin makes: who made it, from which ip, and the values before and after.
+//! `GET /admin/audit` shows the fields that changed instead of the whole values.
+use super::errors::{Web3ProxyErrorContext, Web3ProxyResult};
+use entities::admin_audit;
+use migration::sea_orm::{self, ActiveModelTrait, ConnectionTrait};
+use serde_json::json;
+use std::net::IpAddr;
+
+/// One change made by an admin. Build it up and then `save` it
+#[derive(Debug)]
+pub struct AdminAction {
+    action: &'static str,
+    target_user_id: Option<u64>,
+    target: Option<String>,
+    old_value: Option<serde_json::Value>,
+    new_value: Option<serde_json::Value>,
+}
+
+impl AdminAction {
+    pub fn new(action: &'static str) -> Self {
+        Self {
+            action,
+            target_user_id: None,
+            target: None,
+            old_value: None,
+            new_value: None,
+        }
+    }
+
+    /// the user whose data was changed
+    pub fn target_user(mut self, user_id: u64) -> Self {
+        self.target_user_id = Some(user_id);
+        self
+    }
+
+    /// what was changed if it isn't the user. like "rpc_key:12"
+    pub fn target(mut self, target: String) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    pub fn old_value(mut self, value: serde_json::Value) -> Self {
+        self.old_value = Some(value);
+        self
+    }
+
+    pub fn new_value(mut self, value: serde_json::Value) -> Self {
+        self.new_value = Some(value);
+        self
+    }
+
+    /// Save the action. `actor_id` is the admin's user id.
+    /// Pass a transaction to save the action together with the change
+    pub async fn save<C: ConnectionTrait>(
+        self,
+        db_conn: &C,
+        actor_id: u64,
+        ip: IpAddr,
+    ) -> Web3ProxyResult<()> {
+        let action = self.action;
+
+        let x = admin_audit::ActiveModel {
+            actor_id: sea_orm::Set(actor_id),
+            ip: sea_orm::Set(ip.to_string()),
+            action: sea_orm::Set(action.to_string()),
+            target_user_id: sea_orm::Set(self.target_user_id),
+            target: sea_orm::Set(self.target),
+            old_value: sea_orm::Set(self.old_value.map(|x| x.to_string())),
+            new_value: sea_orm::Set(self.new_value.map(|x| x.to_string())),
+            ..Default::default()
+        };
+
+        x.insert(db_conn)
+            .await
+            .web3_context(format!("saving an admin audit for {}", action))?;
+
+        Ok(())
+    }
+}
+
+/// Every field that is different between the two values. Keys are paths like `tier.title` or `keys[2].active`.
+/// Missing fields are compared as null. Arrays with different lengths are compared whole
+pub fn json_changes(
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut changes = serde_json::Map::new();
+
+    _json_changes("", old, new, &mut changes);
+
+    changes
+}
+
+fn _json_changes(
+    path: &str,
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+    changes: &mut serde_json::Map<String, serde_json::Value>,
+) {
+    use serde_json::Value;
+
+    match (old, new) {
+        (Value::Object(a), Value::Object(b)) => {
+            for key in a.keys().chain(b.keys().filter(|x| !a.contains_key(*x))) {
+                let child = if path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+
+                _json_changes(
+                    &child,
+                    a.get(key).unwrap_or(&Value::Null),
+                    b.get(key).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        (Value::Array(a), Value::Array(b)) if a.len() == b.len() => {
+            for (i, (a, b)) in a.iter().zip(b.iter()).enumerate() {
+                _json_changes(&format!("{}[{}]", path, i), a, b, changes);
+            }
+        }
+        (a, b) => {
+            if a != b {
+                changes.insert(path.to_string(), json!({ "old": a, "new": b }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_changes() {
+        let old = json!({"active": true, "tier": {"id": 1, "title": "Free"}, "origins": ["a"]});
+        let new = json!({"active": false, "tier": {"id": 2, "title": "Free"}, "origins": ["a", "b"], "note": "x"});
+
+        let changes = json_changes(&old, &new);
+
+        assert_eq!(
+            serde_json::Value::Object(changes),
+            json!({
+                "active": {"old": true, "new": false},
+                "tier.id": {"old": 1, "new": 2},
+                "origins": {"old": ["a"], "new": ["a", "b"]},
+                "note": {"old": null, "new": "x"},
+            })
+        );
+
+        // a value with nothing before it is all changes
+        assert_eq!(json_changes(&json!(null), &json!({"a": 1})).len(), 1);
+        assert!(json_changes(&new, &new).is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+//! Keys can be locked to a list of IPs and CIDRs (like a server's egress IPs).
+//! The list is checked on every request, so it is merged and sorted once when the key is loaded.
+use ipnet::{AddrParseError, IpNet, Ipv4Net, Ipv6Net};
+use std::net::IpAddr;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AllowedIps {
+    /// merged so that none of them overlap. sorted by network address
+    v4: Vec<Ipv4Net>,
+    v6: Vec<Ipv6Net>,
+}
+
+/// Parse a comma separated list of CIDRs. Plain addresses are a network of just that address
+pub fn parse_ip_list(allowed_ips: &str) -> Result<Vec<IpNet>, AddrParseError> {
+    allowed_ips
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            x.parse::<IpNet>()
+                .or_else(|err| x.parse::<IpAddr>().map(IpNet::from).map_err(|_| err))
+        })
+        .collect()
+}
+
+impl AllowedIps {
+    pub fn new(nets: &[IpNet]) -> Self {
+        let mut v4 = vec![];
+        let mut v6 = vec![];
+
+        for net in IpNet::aggregate(&nets.to_vec()) {
+            match net {
+                IpNet::V4(x) => v4.push(x),
+                IpNet::V6(x) => v6.push(x),
+            }
+        }
+
+        v4.sort();
+        v6.sort();
+
+        Self { v4, v6 }
+    }
+
+    pub fn parse(allowed_ips: &str) -> Result<Self, AddrParseError> {
+        parse_ip_list(allowed_ips).map(|x| Self::new(&x))
+    }
+
+    /// Binary search for the only network that could hold the ip. IPv4 clients on a dual stack listener show up as IPv4-mapped IPv6
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => {
+                let i = self.v4.partition_point(|x| x.network() <= *ip);
+
+                i > 0 && self.v4[i - 1].contains(ip)
+            }
+            IpAddr::V6(ip) => {
+                if let Some(ip) = ip.to_ipv4_mapped() {
+                    return self.contains(&IpAddr::V4(ip));
+                }
+
+                let i = self.v6.partition_point(|x| x.network() <= *ip);
+
+                i > 0 && self.v6[i - 1].contains(ip)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_ips() {
+        let allowed =
+            AllowedIps::parse("10.0.0.0/8, 10.1.0.0/16, 192.168.1.7, 2001:db8::/32").unwrap();
+
+        // 10.1.0.0/16 is merged into 10.0.0.0/8
+        assert_eq!(allowed.v4.len(), 2);
+
+        assert!(allowed.contains(&"10.200.3.4".parse().unwrap()));
+        assert!(allowed.contains(&"192.168.1.7".parse().unwrap()));
+        assert!(!allowed.contains(&"192.168.1.8".parse().unwrap()));
+        assert!(!allowed.contains(&"9.255.255.255".parse().unwrap()));
+        assert!(allowed.contains(&"::ffff:192.168.1.7".parse().unwrap()));
+        assert!(allowed.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!allowed.contains(&"2001:db9::1".parse().unwrap()));
+
+        assert!(AllowedIps::parse("10.0.0.0/33").is_err());
+    }
+}
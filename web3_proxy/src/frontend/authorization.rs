@@ -1,10 +1,20 @@
 //! Utilities for authorization of logged in and anonymous users.
 
-use super::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
+use super::allowed_ips::AllowedIps;
+use super::errors::{BackoffHint, Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
 use super::rpc_proxy_ws::ProxyMode;
-use crate::app::{AuthorizationChecks, Web3ProxyApp, APP_USER_AGENT};
+use crate::app::{
+    key_token_bucket, merge_method_requests_per_second, parse_allowed_chains,
+    parse_method_requests_per_second, tier_token_bucket, AuthorizationChecks, BalanceOwner,
+    Classification, DatabaseReplica, QueueResult, UserBalance, Web3ProxyApp, APP_USER_AGENT,
+    RATE_LIMIT_PERIOD_SECONDS,
+};
+use crate::config::AppConfig;
 use crate::jsonrpc::{JsonRpcForwardedResponse, JsonRpcRequest};
 use crate::rpcs::one::Web3Rpc;
+use crate::semaphore_registry::RegistryPermit;
+use crate::stats::payload_sample::PayloadSample;
+use crate::stats::request_shadow::{RequestShadow, ShadowTarget};
 use crate::stats::{AppStat, BackendRequests, RpcQueryStats};
 use crate::user_token::UserBearerToken;
 use anyhow::Context;
@@ -15,33 +25,42 @@ use core::fmt;
 use deferred_rate_limiter::DeferredRateLimitResult;
 use derive_more::From;
 use entities::sea_orm_active_enums::TrackingLevel;
-use entities::{balance, login, rpc_key, user, user_tier};
+use entities::{balance, login, org, rpc_key, rpc_key_pool, user, user_tier};
 use ethers::types::{Bytes, U64};
-use ethers::utils::keccak256;
+use ethers::utils::{hex, keccak256};
 use futures::TryFutureExt;
 use hashbrown::HashMap;
 use http::HeaderValue;
-use ipnet::IpNet;
 use log::{error, trace, warn};
-use migration::sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, Condition, DatabaseConnection, EntityTrait, QueryFilter,
+    QueryOrder, QuerySelect, Select,
+};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use rate_counter::RateCounter;
 use rdkafka::message::{Header as KafkaHeader, OwnedHeaders as KafkaOwnedHeaders, OwnedMessage};
 use rdkafka::producer::{FutureProducer, FutureRecord};
 use rdkafka::util::Timeout as KafkaTimeout;
 use redis_rate_limiter::redis::AsyncCommands;
-use redis_rate_limiter::RedisRateLimitResult;
+use redis_rate_limiter::{RedisRateLimitResult, TokenBucketResult};
+use ring::hmac;
 use std::convert::Infallible;
 use std::fmt::Display;
 use std::hash::{Hash, Hasher};
 use std::mem;
+use std::num::NonZeroU64;
 use std::sync::atomic::{self, AtomicBool, AtomicI64, AtomicU64, AtomicUsize};
 use std::time::Duration;
 use std::{net::IpAddr, str::FromStr, sync::Arc};
-use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio::task::JoinHandle;
-use tokio::time::Instant;
+use tokio::time::{sleep_until, Instant};
 use ulid::Ulid;
 use uuid::Uuid;
 
+/// how far back `GET /user/keys/:id/rate_limit` counts rejected requests
+pub const RATE_LIMIT_REJECTIONS_PERIOD: Duration = Duration::from_secs(60);
+
 /// This lets us use UUID and ULID while we transition to only ULIDs
 #[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
 pub enum RpcSecretKey {
@@ -49,10 +68,21 @@ pub enum RpcSecretKey {
     Uuid(Uuid),
 }
 
+/// The active keys in a key pool. Requests to the pool's url are spread across them.
+#[derive(Debug, Default)]
+pub struct RpcKeyPoolMembers {
+    /// database id of the pool. None if no active pool matched the key
+    pub rpc_key_pool_id: Option<NonZeroU64>,
+    /// only ids are kept. secrets are not saved
+    pub rpc_key_ids: Vec<NonZeroU64>,
+    /// where to start looking for an available key on the next request
+    next: AtomicUsize,
+}
+
 /// TODO: should this have IpAddr and Origin or AuthorizationChecks?
 #[derive(Debug)]
 pub enum RateLimitResult {
-    Allowed(Authorization, Option<OwnedSemaphorePermit>),
+    Allowed(Authorization, Option<RegistryPermit>),
     RateLimited(
         Authorization,
         /// when their rate limit resets and they can try more requests
@@ -62,8 +92,9 @@ pub enum RateLimitResult {
     UnknownKey,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum AuthorizationType {
+    /// the proxy itself or one of the operator's services. these get priority over user traffic
     Internal,
     Frontend,
 }
@@ -79,6 +110,8 @@ pub struct Authorization {
     pub referer: Option<Referer>,
     pub user_agent: Option<UserAgent>,
     pub authorization_type: AuthorizationType,
+    /// how long this request waited in the rate limit queue
+    pub rate_limit_queue_millis: u64,
 }
 
 pub struct KafkaDebugLogger {
@@ -253,6 +286,9 @@ pub struct RequestMetadata {
     /// but we still have to store the method at least temporarily for cost calculations
     pub method: Option<String>,
 
+    /// True if the request was a notification (no "id"). The client will not get a response
+    pub notification: bool,
+
     /// Instant that the request was received (or at least close to it)
     /// We use Instant and not timestamps to avoid problems with leap seconds and similar issues
     pub start_instant: tokio::time::Instant,
@@ -268,6 +304,12 @@ pub struct RequestMetadata {
     pub response_bytes: AtomicU64,
     /// How many milliseconds it took to respond to the request
     pub response_millis: AtomicU64,
+    /// True if the response was too large for the method's max_response_bytes
+    pub response_too_large: AtomicBool,
+    /// The lowest of the method's max_response_bytes and the user tier's. Set once the request's method is final
+    pub max_response_bytes: OnceCell<u64>,
+    /// True if the key or ip is tagged as a probable bot. See `bot_detection`
+    pub probable_bot: bool,
     /// What time the (first) response was proxied.
     /// TODO: think about how to store response times for ProxyMode::Versus
     pub response_timestamp: AtomicI64,
@@ -279,6 +321,24 @@ pub struct RequestMetadata {
     /// TODO: maybe this shouldn't be determined by ProxyMode. A request param should probably enable this
     pub kafka_debug_logger: Option<Arc<KafkaDebugLogger>>,
 
+    /// Some if this request was picked to have its payload sampled. Sent along with the stat
+    pub payload_sample: Option<PayloadSample>,
+
+    /// Some if the key sends its request metadata to its own endpoint. Queued along with the stat
+    pub request_shadow: Option<RequestShadow>,
+
+    /// Some if the key has a monthly byte quota. The request's and response's bytes are added to it
+    pub bandwidth_used: Option<Arc<AtomicU64>>,
+
+    /// Some if the key is cut off when its balance runs out. The stat's credits are taken from it
+    pub balance: Option<Arc<UserBalance>>,
+
+    /// None for the proxy's own chain. Some if the request was sent to one of the `chains`
+    pub chain_id: Option<u64>,
+
+    /// Set once the classifier script has run. Its credits are added to the stat and its capabilities are used for routing
+    pub classification: OnceCell<Classification>,
+
     /// Cancel-safe channel for sending stats to the buffer
     pub stat_sender: Option<flume::Sender<AppStat>>,
 }
@@ -289,16 +349,26 @@ impl Default for RequestMetadata {
             archive_request: Default::default(),
             authorization: Default::default(),
             backend_requests: Default::default(),
+            balance: Default::default(),
+            bandwidth_used: Default::default(),
+            chain_id: Default::default(),
+            classification: Default::default(),
             error_response: Default::default(),
             kafka_debug_logger: Default::default(),
+            max_response_bytes: Default::default(),
             method: Default::default(),
             no_servers: Default::default(),
+            notification: Default::default(),
+            payload_sample: Default::default(),
+            probable_bot: Default::default(),
             request_bytes: Default::default(),
+            request_shadow: Default::default(),
             request_ulid: Default::default(),
             response_bytes: Default::default(),
             response_from_backup_rpc: Default::default(),
             response_millis: Default::default(),
             response_timestamp: Default::default(),
+            response_too_large: Default::default(),
             start_instant: Instant::now(),
             stat_sender: Default::default(),
         }
@@ -382,11 +452,27 @@ impl RequestMetadata {
         authorization: Arc<Authorization>,
         request: R,
         head_block_num: Option<&U64>,
+    ) -> Arc<Self> {
+        Self::new_for_chain(app, None, authorization, request, head_block_num).await
+    }
+
+    /// Metadata for a request to one of the `chains`. Its stat is saved with that chain's id
+    pub async fn new_for_chain<'a, R: Into<RequestOrMethod<'a>>>(
+        app: &Web3ProxyApp,
+        chain_id: Option<u64>,
+        authorization: Arc<Authorization>,
+        request: R,
+        head_block_num: Option<&U64>,
     ) -> Arc<Self> {
         let request = request.into();
 
         let method = request.method().map(|x| x.to_string());
 
+        let notification = request
+            .jsonrpc_request()
+            .map(|x| x.notification)
+            .unwrap_or_default();
+
         let request_bytes = request.num_bytes();
 
         // TODO: modify the request here? I don't really like that very much. but its a sure way to get archive_request set correctly
@@ -417,20 +503,49 @@ impl RequestMetadata {
             }
         }
 
+        let payload_sample = request.jsonrpc_request().and_then(|request| {
+            PayloadSample::try_new(app, &authorization, request, head_block_num)
+        });
+
+        let request_shadow = RequestShadow::try_new(app, &authorization);
+
+        let bandwidth_used = app.bandwidth_used(&authorization);
+
+        if let Some(ref bandwidth_used) = bandwidth_used {
+            bandwidth_used.fetch_add(request_bytes as u64, atomic::Ordering::AcqRel);
+        }
+
+        let balance = app.user_balance(&authorization);
+
+        let probable_bot = method
+            .as_deref()
+            .map(|x| app.observe_probable_bot(&authorization, x))
+            .unwrap_or_default();
+
         let x = Self {
             archive_request: false.into(),
             backend_requests: Default::default(),
+            balance,
+            bandwidth_used,
+            chain_id,
+            classification: Default::default(),
             error_response: false.into(),
             kafka_debug_logger,
+            max_response_bytes: Default::default(),
             no_servers: 0.into(),
+            notification,
+            payload_sample,
+            probable_bot,
             authorization: Some(authorization),
             request_bytes,
+            request_shadow,
             method,
             response_bytes: 0.into(),
             response_from_backup_rpc: false.into(),
             response_millis: 0.into(),
             request_ulid,
             response_timestamp: 0.into(),
+            response_too_large: false.into(),
             start_instant: Instant::now(),
             stat_sender: app.stat_sender.clone(),
         };
@@ -465,15 +580,39 @@ impl RequestMetadata {
     }
 
     pub fn try_send_stat(mut self) -> Web3ProxyResult<Option<Self>> {
+        // shadows do not need the stat buffer
+        if let Some(request_shadow) = self.request_shadow.take() {
+            request_shadow.send(&self);
+        }
+
         if let Some(stat_sender) = self.stat_sender.take() {
             trace!("sending stat! {:?}", self);
 
+            if let Some(payload_sample) = self.payload_sample.take() {
+                payload_sample.send(&self);
+            }
+
             let stat: RpcQueryStats = self.try_into()?;
 
+            // a dropped stat is never saved. its credits can't be held against the balance forever
+            let balance = stat.balance.clone().map(|x| (x, stat.credits_used));
+
             let stat: AppStat = stat.into();
 
-            if let Err(err) = stat_sender.send(stat) {
-                error!("failed sending stat {:?}: {:?}", err.0, err);
+            // try_send so that a full stat buffer never blocks the request
+            if let Err(err) = stat_sender.try_send(stat) {
+                if let Some((balance, credits_used)) = balance {
+                    balance.settle(credits_used);
+                }
+
+                match err {
+                    flume::TrySendError::Full(stat) => {
+                        warn!("stat buffer is full. dropping {:?}", stat)
+                    }
+                    flume::TrySendError::Disconnected(stat) => {
+                        error!("failed sending stat {:?}", stat)
+                    }
+                }
                 // TODO: return it? that seems like it might cause an infinite loop
                 // TODO: but dropping stats is bad... hmm... i guess better to undercharge customers than overcharge
             };
@@ -493,6 +632,10 @@ impl RequestMetadata {
         self.response_bytes
             .fetch_add(num_bytes, atomic::Ordering::AcqRel);
 
+        if let Some(bandwidth_used) = self.bandwidth_used.as_ref() {
+            bandwidth_used.fetch_add(num_bytes, atomic::Ordering::AcqRel);
+        }
+
         self.response_millis.fetch_add(
             self.start_instant.elapsed().as_millis() as u64,
             atomic::Ordering::AcqRel,
@@ -531,7 +674,7 @@ impl RequestMetadata {
 // TODO: is this where the panic comes from?
 impl Drop for RequestMetadata {
     fn drop(&mut self) {
-        if self.stat_sender.is_some() {
+        if self.stat_sender.is_some() || self.request_shadow.is_some() {
             // turn `&mut self` into `self`
             let x = mem::take(self);
 
@@ -607,6 +750,177 @@ impl From<RpcSecretKey> for Uuid {
     }
 }
 
+/// Keyed hash of rpc secret keys. New keys only save this hash in the database
+#[derive(Clone, Debug)]
+pub struct RpcKeyHasher(hmac::Key);
+
+impl RpcKeyHasher {
+    /// The key is the hex in `rpc_key_hash_keyfile`.
+    /// Changing it makes every saved hash useless, so it must never change.
+    /// Keys are only saved in a database, so the keyfile is only optional without a `db_url`
+    pub fn from_config(app_config: &AppConfig) -> anyhow::Result<Self> {
+        let key_bytes = match app_config.rpc_key_hash_keyfile.as_ref() {
+            Some(path) => {
+                let key_hex = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading rpc key hash keyfile at {}", path))?;
+
+                let key_bytes =
+                    hex::decode(key_hex.trim()).context("rpc key hash key must be hex")?;
+
+                anyhow::ensure!(!key_bytes.is_empty(), "rpc key hash key is empty");
+
+                key_bytes
+            }
+            None => {
+                anyhow::ensure!(
+                    app_config.db_url.is_none(),
+                    "rpc_key_hash_keyfile is required when db_url is set"
+                );
+
+                // nothing is saved without a database. there is nothing to protect
+                vec![]
+            }
+        };
+
+        Ok(Self(hmac::Key::new(hmac::HMAC_SHA256, &key_bytes)))
+    }
+
+    pub fn hash(&self, rpc_secret_key: RpcSecretKey) -> String {
+        // ULID and UUID forms of the same key get the same hash
+        let x: Uuid = rpc_secret_key.into();
+
+        hex::encode(hmac::sign(&self.0, x.as_bytes()))
+    }
+
+    /// Find a key by its secret. Only the hash is checked. `hash_plaintext_keys` runs at startup so every key has one
+    pub fn condition(&self, rpc_secret_key: RpcSecretKey) -> Condition {
+        Condition::all().add(rpc_key::Column::SecretKeyHash.eq(self.hash(rpc_secret_key)))
+    }
+
+    /// Find a key pool by its secret. Only the hash is checked, like `condition`
+    pub fn pool_condition(&self, rpc_key_pool_key: RpcSecretKey) -> Condition {
+        Condition::all().add(rpc_key_pool::Column::SecretKeyHash.eq(self.hash(rpc_key_pool_key)))
+    }
+
+    /// Replace plaintext secrets from before m20230619_083412_rpc_key_secret_hash with their hash.
+    /// Key pool secrets from before m20230706_091204_rpc_key_pool_secret_hash are hashed too.
+    /// Returns how many keys and pools were (or with `dry_run`, would be) hashed. Running it again is safe
+    pub async fn hash_plaintext_keys(
+        &self,
+        db_conn: &DatabaseConnection,
+        batch_size: u64,
+        dry_run: bool,
+    ) -> anyhow::Result<u64> {
+        let batch_size = batch_size.max(1);
+
+        let mut last_id = 0;
+        let mut hashed = 0;
+
+        loop {
+            let rpc_keys = rpc_key::Entity::find()
+                .filter(rpc_key::Column::Id.gt(last_id))
+                .filter(rpc_key::Column::SecretKey.is_not_null())
+                .order_by_asc(rpc_key::Column::Id)
+                .limit(batch_size)
+                .all(db_conn)
+                .await?;
+
+            let num_keys = rpc_keys.len() as u64;
+
+            for rk in rpc_keys {
+                last_id = rk.id;
+
+                let secret_key = match rk.secret_key {
+                    Some(x) => RpcSecretKey::Uuid(x),
+                    None => continue,
+                };
+
+                hashed += 1;
+
+                if dry_run {
+                    continue;
+                }
+
+                let mut rk: rpc_key::ActiveModel = rk.into();
+
+                // both columns change in one update so the key is never unfindable
+                rk.secret_key = sea_orm::Set(None);
+                rk.secret_key_hash = sea_orm::Set(Some(self.hash(secret_key)));
+
+                rk.save(db_conn).await?;
+            }
+
+            if num_keys < batch_size {
+                break;
+            }
+        }
+
+        let pools_hashed = self
+            .hash_plaintext_pools(db_conn, batch_size, dry_run)
+            .await?;
+
+        Ok(hashed + pools_hashed)
+    }
+
+    async fn hash_plaintext_pools(
+        &self,
+        db_conn: &DatabaseConnection,
+        batch_size: u64,
+        dry_run: bool,
+    ) -> anyhow::Result<u64> {
+        let mut last_id = 0;
+        let mut hashed = 0;
+
+        loop {
+            let pools = rpc_key_pool::Entity::find()
+                .filter(rpc_key_pool::Column::Id.gt(last_id))
+                .filter(rpc_key_pool::Column::SecretKey.is_not_null())
+                .order_by_asc(rpc_key_pool::Column::Id)
+                .limit(batch_size)
+                .all(db_conn)
+                .await?;
+
+            let num_pools = pools.len() as u64;
+
+            for pool in pools {
+                last_id = pool.id;
+
+                let secret_key = match pool.secret_key {
+                    Some(x) => RpcSecretKey::Uuid(x),
+                    None => continue,
+                };
+
+                hashed += 1;
+
+                if dry_run {
+                    continue;
+                }
+
+                let mut pool: rpc_key_pool::ActiveModel = pool.into();
+
+                // both columns change in one update so the pool is never unfindable
+                pool.secret_key = sea_orm::Set(None);
+                pool.secret_key_hash = sea_orm::Set(Some(self.hash(secret_key)));
+
+                pool.save(db_conn).await?;
+            }
+
+            if num_pools < batch_size {
+                break;
+            }
+        }
+
+        Ok(hashed)
+    }
+}
+
+/// An active key by its id. rows that only hold a rotated-away secret are never used by id
+fn active_rpc_key_by_id(rpc_key_id: u64) -> Select<rpc_key::Entity> {
+    rpc_key::Entity::find_by_id(rpc_key_id)
+        .filter(rpc_key::Column::Active.eq(true))
+        .filter(rpc_key::Column::SupersededBy.is_null())
+}
+
 impl Authorization {
     pub fn internal(db_conn: Option<DatabaseConnection>) -> Web3ProxyResult<Self> {
         let authorization_checks = AuthorizationChecks {
@@ -631,6 +945,12 @@ impl Authorization {
         )
     }
 
+    /// Requests from the proxy itself or the operator's services.
+    /// These skip user rate limits and are not shed when a backend is throttled
+    pub fn is_internal(&self) -> bool {
+        self.authorization_type == AuthorizationType::Internal
+    }
+
     pub fn external(
         allowed_origin_requests_per_period: &HashMap<String, u64>,
         db_conn: Option<DatabaseConnection>,
@@ -682,7 +1002,7 @@ impl Authorization {
         match &authorization_checks.allowed_ips {
             None => {}
             Some(allowed_ips) => {
-                if !allowed_ips.iter().any(|x| x.contains(&ip)) {
+                if !allowed_ips.contains(&ip) {
                     return Err(Web3ProxyError::IpNotAllowed(ip));
                 }
             }
@@ -732,6 +1052,7 @@ impl Authorization {
             referer,
             user_agent,
             authorization_type,
+            rate_limit_queue_millis: 0,
         })
     }
 }
@@ -742,7 +1063,12 @@ pub async fn login_is_authorized(app: &Web3ProxyApp, ip: IpAddr) -> Web3ProxyRes
     let authorization = match app.rate_limit_login(ip, ProxyMode::Best).await? {
         RateLimitResult::Allowed(authorization, None) => authorization,
         RateLimitResult::RateLimited(authorization, retry_at) => {
-            return Err(Web3ProxyError::RateLimited(authorization, retry_at));
+            let hint = app.backoff_hint(
+                Some(app.config.login_rate_limit_per_period),
+                Some(RATE_LIMIT_PERIOD_SECONDS),
+            );
+
+            return Err(Web3ProxyError::RateLimited(authorization, retry_at, hint));
         }
         // TODO: don't panic. give the user an error
         x => unimplemented!("rate_limit_login shouldn't ever see these: {:?}", x),
@@ -757,7 +1083,7 @@ pub async fn ip_is_authorized(
     ip: IpAddr,
     origin: Option<Origin>,
     proxy_mode: ProxyMode,
-) -> Web3ProxyResult<(Authorization, Option<OwnedSemaphorePermit>)> {
+) -> Web3ProxyResult<(Authorization, Option<RegistryPermit>)> {
     // TODO: i think we could write an `impl From` for this
     // TODO: move this to an AuthorizedUser extrator
     let (authorization, semaphore) = match app
@@ -772,7 +1098,16 @@ pub async fn ip_is_authorized(
         RateLimitResult::Allowed(authorization, semaphore) => (authorization, semaphore),
         RateLimitResult::RateLimited(authorization, retry_at) => {
             // TODO: in the background, emit a stat (maybe simplest to use a channel?)
-            return Err(Web3ProxyError::RateLimited(authorization, retry_at));
+            // origins without their own limit use the public limit
+            let hint = app.backoff_hint(
+                authorization
+                    .checks
+                    .max_requests_per_period
+                    .or(app.config.public_requests_per_period),
+                Some(RATE_LIMIT_PERIOD_SECONDS),
+            );
+
+            return Err(Web3ProxyError::RateLimited(authorization, retry_at, hint));
         }
         // TODO: don't panic. give the user an error
         x => unimplemented!("rate_limit_by_ip shouldn't ever see these: {:?}", x),
@@ -817,6 +1152,7 @@ pub async fn ip_is_authorized(
 }
 
 /// like app.rate_limit_by_rpc_key but converts to a Web3ProxyError;
+#[allow(clippy::too_many_arguments)]
 pub async fn key_is_authorized(
     app: &Arc<Web3ProxyApp>,
     rpc_key: RpcSecretKey,
@@ -825,16 +1161,50 @@ pub async fn key_is_authorized(
     proxy_mode: ProxyMode,
     referer: Option<Referer>,
     user_agent: Option<UserAgent>,
-) -> Web3ProxyResult<(Authorization, Option<OwnedSemaphorePermit>)> {
+    chain_id: u64,
+) -> Web3ProxyResult<(Authorization, Option<RegistryPermit>)> {
+    let rate_limit_result = app
+        .rate_limit_by_rpc_key(
+            ip, origin, proxy_mode, referer, rpc_key, user_agent, chain_id,
+        )
+        .await?;
+
+    _key_is_authorized(app, rate_limit_result)
+}
+
+/// like app.rate_limit_by_rpc_key_id but converts to a Web3ProxyError;
+#[allow(clippy::too_many_arguments)]
+pub async fn key_id_is_authorized(
+    app: &Arc<Web3ProxyApp>,
+    rpc_key_id: NonZeroU64,
+    ip: IpAddr,
+    origin: Option<Origin>,
+    proxy_mode: ProxyMode,
+    referer: Option<Referer>,
+    user_agent: Option<UserAgent>,
+    chain_id: u64,
+) -> Web3ProxyResult<(Authorization, Option<RegistryPermit>)> {
+    let rate_limit_result = app
+        .rate_limit_by_rpc_key_id(
+            ip, origin, proxy_mode, referer, rpc_key_id, user_agent, chain_id,
+        )
+        .await?;
+
+    _key_is_authorized(app, rate_limit_result)
+}
+
+fn _key_is_authorized(
+    app: &Arc<Web3ProxyApp>,
+    rate_limit_result: RateLimitResult,
+) -> Web3ProxyResult<(Authorization, Option<RegistryPermit>)> {
     // check the rate limits. error if over the limit
     // TODO: i think this should be in an "impl From" or "impl Into"
-    let (authorization, semaphore) = match app
-        .rate_limit_by_rpc_key(ip, origin, proxy_mode, referer, rpc_key, user_agent)
-        .await?
-    {
+    let (authorization, semaphore) = match rate_limit_result {
         RateLimitResult::Allowed(authorization, semaphore) => (authorization, semaphore),
         RateLimitResult::RateLimited(authorization, retry_at) => {
-            return Err(Web3ProxyError::RateLimited(authorization, retry_at));
+            let hint = app.token_bucket_hint(&authorization.checks);
+
+            return Err(Web3ProxyError::RateLimited(authorization, retry_at, hint));
         }
         RateLimitResult::UnknownKey => return Err(Web3ProxyError::UnknownKey),
     };
@@ -879,21 +1249,150 @@ pub async fn key_is_authorized(
     Ok((authorization, semaphore))
 }
 
+/// Authorize a request to a key pool by picking the first member key that is not over its limits.
+/// Members are tried round robin so that traffic spreads across the pool's combined limits.
+#[allow(clippy::too_many_arguments)]
+pub async fn key_pool_is_authorized(
+    app: &Arc<Web3ProxyApp>,
+    rpc_key_pool_key: RpcSecretKey,
+    ip: IpAddr,
+    origin: Option<Origin>,
+    proxy_mode: ProxyMode,
+    referer: Option<Referer>,
+    user_agent: Option<UserAgent>,
+    chain_id: u64,
+) -> Web3ProxyResult<(Authorization, Option<RegistryPermit>)> {
+    let pool = app.rpc_key_pool_members(rpc_key_pool_key).await?;
+
+    let rpc_key_pool_id = pool.rpc_key_pool_id.ok_or(Web3ProxyError::UnknownKey)?;
+
+    let num_keys = pool.rpc_key_ids.len();
+
+    if num_keys == 0 {
+        return Err(Web3ProxyError::UnknownKey);
+    }
+
+    let start = pool.next.fetch_add(1, atomic::Ordering::Relaxed);
+
+    // each member's own limits. this only reads the local estimates, so it doesn't wait on redis
+    let mut members = Vec::with_capacity(num_keys);
+
+    for rpc_key_id in pool.rpc_key_ids.iter().copied() {
+        let checks = app
+            .authorization_checks_by_id(proxy_mode, rpc_key_id)
+            .await?;
+
+        members.push((rpc_key_id, app.peek_local_token_buckets(&checks)));
+    }
+
+    // the member that will be free the soonest. used for the error if every member is limited
+    let mut rate_limited: Option<(Authorization, Option<Instant>)> = None;
+
+    for rpc_key_id in pool_member_order(&members, start) {
+        match app
+            .rate_limit_by_rpc_key_id(
+                ip,
+                origin.clone(),
+                proxy_mode,
+                referer.clone(),
+                rpc_key_id,
+                user_agent.clone(),
+                chain_id,
+            )
+            .await?
+        {
+            RateLimitResult::Allowed(mut authorization, semaphore) => {
+                authorization.checks.rpc_key_pool_id = Some(rpc_key_pool_id);
+
+                return Ok((authorization, semaphore));
+            }
+            RateLimitResult::RateLimited(mut authorization, retry_at) => {
+                authorization.checks.rpc_key_pool_id = Some(rpc_key_pool_id);
+
+                let sooner = match (&rate_limited, retry_at) {
+                    (None, _) => true,
+                    (Some((_, Some(old))), Some(new)) => new < *old,
+                    (Some((_, None)), Some(_)) => true,
+                    (Some(_), None) => false,
+                };
+
+                if sooner {
+                    rate_limited = Some((authorization, retry_at));
+                }
+            }
+            RateLimitResult::UnknownKey => {
+                // the key was probably deactivated since the pool was cached
+                trace!("unknown key in rpc key pool {}", rpc_key_pool_id);
+            }
+        }
+    }
+
+    match rate_limited {
+        Some((authorization, retry_at)) => {
+            let hint = app.token_bucket_hint(&authorization.checks);
+
+            Err(Web3ProxyError::RateLimited(authorization, retry_at, hint))
+        }
+        None => Err(Web3ProxyError::UnknownKey),
+    }
+}
+
+/// The order to try a key pool's members in. Members take turns going first so that traffic is spread across the pool.
+/// Members whose buckets are known to be empty go last. They are still tried in case the estimate is stale
+fn pool_member_order(members: &[(NonZeroU64, Option<u64>)], start: usize) -> Vec<NonZeroU64> {
+    let num_keys = members.len();
+
+    let mut order: Vec<_> = (0..num_keys)
+        .map(|i| members[(start + i) % num_keys])
+        .collect();
+
+    // stable, so the members with room keep their turns
+    order.sort_by_key(|(_, capacity)| *capacity == Some(0));
+
+    order
+        .into_iter()
+        .map(|(rpc_key_id, _)| rpc_key_id)
+        .collect()
+}
+
 impl Web3ProxyApp {
+    /// Authorize a request on the internal lane. The bearer must match `internal_service_token`.
+    /// There are no rate limits or semaphores here. The operator is trusted to not flood their own proxy
+    pub fn internal_service_is_authorized(
+        &self,
+        bearer: &Bearer,
+        ip: IpAddr,
+        user_agent: Option<UserAgent>,
+    ) -> Web3ProxyResult<Authorization> {
+        let expected = self
+            .config
+            .internal_service_token
+            .as_ref()
+            .ok_or(Web3ProxyError::NotFound)?;
+
+        if !constant_time_eq(bearer.token().as_bytes(), expected.as_bytes()) {
+            return Err(Web3ProxyError::AccessDenied);
+        }
+
+        let mut authorization = Authorization::internal(self.db_conn())?;
+
+        // keep where the request came from. it helps when debugging the operator's services
+        authorization.ip = ip;
+        if user_agent.is_some() {
+            authorization.user_agent = user_agent;
+        }
+
+        Ok(authorization)
+    }
+
     /// Limit the number of concurrent requests from the given ip address.
-    pub async fn ip_semaphore(&self, ip: &IpAddr) -> Web3ProxyResult<Option<OwnedSemaphorePermit>> {
+    pub async fn ip_semaphore(&self, ip: &IpAddr) -> Web3ProxyResult<Option<RegistryPermit>> {
         if let Some(max_concurrent_requests) = self.config.public_max_concurrent_requests {
-            let semaphore = self
+            // TODO: set max_concurrent_requests dynamically based on load?
+            let semaphore_permit = self
                 .ip_semaphores
-                .get_or_insert_async::<Infallible>(ip, async move {
-                    // TODO: set max_concurrent_requests dynamically based on load?
-                    let s = Semaphore::new(max_concurrent_requests);
-                    Ok(Arc::new(s))
-                })
-                .await
-                .expect("infallible");
-
-            let semaphore_permit = semaphore.acquire_owned().await?;
+                .acquire(ip, max_concurrent_requests)
+                .await?;
 
             Ok(Some(semaphore_permit))
         } else {
@@ -905,23 +1404,17 @@ impl Web3ProxyApp {
     pub async fn user_semaphore(
         &self,
         authorization_checks: &AuthorizationChecks,
-    ) -> Web3ProxyResult<Option<OwnedSemaphorePermit>> {
+    ) -> Web3ProxyResult<Option<RegistryPermit>> {
         if let Some(max_concurrent_requests) = authorization_checks.max_concurrent_requests {
             let user_id = authorization_checks
                 .user_id
                 .try_into()
                 .or(Err(Web3ProxyError::UserIdZero))?;
 
-            let semaphore = self
+            let semaphore_permit = self
                 .user_semaphores
-                .get_or_insert_async::<Infallible>(&user_id, async move {
-                    let s = Semaphore::new(max_concurrent_requests as usize);
-                    Ok(Arc::new(s))
-                })
-                .await
-                .expect("infallible");
-
-            let semaphore_permit = semaphore.acquire_owned().await?;
+                .acquire(&user_id, max_concurrent_requests as usize)
+                .await?;
 
             Ok(Some(semaphore_permit))
         } else {
@@ -930,26 +1423,94 @@ impl Web3ProxyApp {
         }
     }
 
+    /// Remember that a user was rate limited. Shown on `GET /user/keys/:id/rate_limit`
+    pub async fn record_rate_limit_rejection(&self, user_id: u64) {
+        let user_id = match NonZeroU64::new(user_id) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let rejections = self
+            .user_rate_limit_rejections
+            .get_or_insert_async::<Infallible>(&user_id, async move {
+                Ok(Arc::new(Mutex::new(RateCounter::new(
+                    RATE_LIMIT_REJECTIONS_PERIOD,
+                ))))
+            })
+            .await
+            .expect("infallible");
+
+        rejections.lock().update(true);
+    }
+
+    /// How many of the user's requests were rate limited in the last `RATE_LIMIT_REJECTIONS_PERIOD`
+    pub fn recent_rate_limit_rejections(&self, user_id: NonZeroU64) -> usize {
+        self.user_rate_limit_rejections
+            .get(&user_id)
+            .map(|x| x.lock().update(false))
+            .unwrap_or_default()
+    }
+
+    /// check the local cache for the active keys in a key pool, or query the database
+    pub(crate) async fn rpc_key_pool_members(
+        &self,
+        rpc_key_pool_key: RpcSecretKey,
+    ) -> Web3ProxyResult<Arc<RpcKeyPoolMembers>> {
+        let secret_key_hash = self.rpc_key_hasher.hash(rpc_key_pool_key);
+
+        self.rpc_key_pool_cache
+            .try_get_or_insert_async(&secret_key_hash, async move {
+                let db_replica = self
+                    .db_replica()
+                    .web3_context("Getting database connection")?;
+
+                let pool = match rpc_key_pool::Entity::find()
+                    .filter(self.rpc_key_hasher.pool_condition(rpc_key_pool_key))
+                    .filter(rpc_key_pool::Column::Active.eq(true))
+                    .one(db_replica.conn())
+                    .await?
+                {
+                    Some(x) => x,
+                    None => return Ok(Default::default()),
+                };
+
+                let rpc_key_ids = rpc_key::Entity::find()
+                    .filter(rpc_key::Column::RpcKeyPoolId.eq(pool.id))
+                    .filter(rpc_key::Column::Active.eq(true))
+                    .all(db_replica.conn())
+                    .await?
+                    .into_iter()
+                    .map(|x| x.id.try_into().expect("db ids are never 0"))
+                    .collect();
+
+                let x = RpcKeyPoolMembers {
+                    rpc_key_pool_id: Some(pool.id.try_into().expect("db ids are never 0")),
+                    rpc_key_ids,
+                    next: Default::default(),
+                };
+
+                Ok(Arc::new(x))
+            })
+            .await
+    }
+
     /// Verify that the given bearer token and address are allowed to take the specified action.
     /// This includes concurrent request limiting.
     pub async fn bearer_is_authorized(
         &self,
         bearer: Bearer,
-    ) -> Web3ProxyResult<(user::Model, OwnedSemaphorePermit)> {
+    ) -> Web3ProxyResult<(user::Model, RegistryPermit)> {
         // get the user id for this bearer token
         let user_bearer_token = UserBearerToken::try_from(bearer)?;
 
         // limit concurrent requests
-        let semaphore = self
+        let semaphore_permit = self
             .bearer_token_semaphores
-            .get_or_insert_async::<Infallible>(&user_bearer_token, async move {
-                let s = Semaphore::new(self.config.bearer_token_max_concurrent_requests as usize);
-                Ok(Arc::new(s))
-            })
-            .await
-            .expect("infallible");
-
-        let semaphore_permit = semaphore.acquire_owned().await?;
+            .acquire(
+                &user_bearer_token,
+                self.config.bearer_token_max_concurrent_requests as usize,
+            )
+            .await?;
 
         // get the attached address from the database for the given auth_token.
         let db_replica = self
@@ -958,17 +1519,34 @@ impl Web3ProxyApp {
 
         let user_bearer_uuid: Uuid = user_bearer_token.into();
 
-        let user = user::Entity::find()
-            .left_join(login::Entity)
+        let (login, user) = login::Entity::find()
             .filter(login::Column::BearerToken.eq(user_bearer_uuid))
+            .find_also_related(user::Entity)
             .one(db_replica.conn())
             .await
             .web3_context("fetching user from db by bearer token")?
-            .web3_context("unknown bearer token")?;
+            .ok_or(Web3ProxyError::InvalidBearerToken)?;
+
+        // expired logins are only deleted when the user logs out. check here too
+        if login.expires_at <= Utc::now() {
+            return Err(Web3ProxyError::ExpiredBearerToken);
+        }
+
+        let user = user.ok_or(Web3ProxyError::InvalidBearerToken)?;
 
         Ok((user, semaphore_permit))
     }
 
+    /// What a client needs to back off from a rate limit or payment error. `window` is in seconds
+    pub fn backoff_hint(&self, limit: Option<u64>, window: Option<u64>) -> BackoffHint {
+        BackoffHint {
+            limit,
+            window,
+            upgrade_url: self.config.upgrade_url.clone(),
+            ..Default::default()
+        }
+    }
+
     pub async fn rate_limit_login(
         &self,
         ip: IpAddr,
@@ -1098,114 +1676,78 @@ impl Web3ProxyApp {
                     .db_replica()
                     .web3_context("Getting database connection")?;
 
-                // TODO: join the user table to this to return the User? we don't always need it
-                // TODO: join on secondary users
-                // TODO: join on user tier
-                match rpc_key::Entity::find()
-                    .filter(rpc_key::Column::SecretKey.eq(<Uuid>::from(rpc_secret_key)))
+                // only a hash of the secret is saved. hashing is only done on a cache miss
+                let rpc_key_model = match rpc_key::Entity::find()
+                    .filter(self.rpc_key_hasher.condition(rpc_secret_key))
                     .filter(rpc_key::Column::Active.eq(true))
                     .one(db_replica.conn())
                     .await?
                 {
-                    Some(rpc_key_model) => {
-                        // TODO: move these splits into helper functions
-                        // TODO: can we have sea orm handle this for us?
-                        let user_model = user::Entity::find_by_id(rpc_key_model.user_id)
-                            .one(db_replica.conn())
-                            .await?
-                            .context("no related user")?;
+                    Some(x) => x,
+                    None => return Ok(AuthorizationChecks::default()),
+                };
+
+                // a rotated-away secret works as the key that replaced it until it expires
+                let (rpc_key_model, secret_expires_at) = match rpc_key_model.superseded_by {
+                    Some(superseded_by) => {
+                        let expires_at = rpc_key_model.expires_at.map(|x| x.timestamp());
 
-                        let balance = balance::Entity::find()
-                            .filter(balance::Column::UserId.eq(user_model.id))
+                        if expires_at.unwrap_or_default() <= Utc::now().timestamp() {
+                            return Ok(AuthorizationChecks::default());
+                        }
+
+                        match rpc_key::Entity::find_by_id(superseded_by)
+                            .filter(rpc_key::Column::Active.eq(true))
                             .one(db_replica.conn())
                             .await?
-                            .map(|x| x.available_balance)
-                            .unwrap_or_default();
-
-                        let user_tier_model =
-                            user_tier::Entity::find_by_id(user_model.user_tier_id)
-                                .one(db_replica.conn())
-                                .await?
-                                .context("no related user tier")?;
-
-                        let allowed_ips: Option<Vec<IpNet>> =
-                            if let Some(allowed_ips) = rpc_key_model.allowed_ips {
-                                let x = allowed_ips
-                                    .split(',')
-                                    .map(|x| x.trim().parse::<IpNet>())
-                                    .collect::<Result<Vec<_>, _>>()?;
-                                Some(x)
-                            } else {
-                                None
-                            };
-
-                        let allowed_origins: Option<Vec<Origin>> =
-                            if let Some(allowed_origins) = rpc_key_model.allowed_origins {
-                                // TODO: do this without collecting twice?
-                                let x = allowed_origins
-                                    .split(',')
-                                    .map(|x| HeaderValue::from_str(x.trim()))
-                                    .collect::<Result<Vec<_>, _>>()?
-                                    .into_iter()
-                                    .map(|x| Origin::decode(&mut [x].iter()))
-                                    .collect::<Result<Vec<_>, _>>()?;
-
-                                Some(x)
-                            } else {
-                                None
-                            };
-
-                        let allowed_referers: Option<Vec<Referer>> =
-                            if let Some(allowed_referers) = rpc_key_model.allowed_referers {
-                                let x = allowed_referers
-                                    .split(',')
-                                    .map(|x| {
-                                        x.trim()
-                                            .parse::<Referer>()
-                                            .or(Err(Web3ProxyError::InvalidReferer))
-                                    })
-                                    .collect::<Result<Vec<_>, _>>()?;
-
-                                Some(x)
-                            } else {
-                                None
-                            };
-
-                        let allowed_user_agents: Option<Vec<UserAgent>> =
-                            if let Some(allowed_user_agents) = rpc_key_model.allowed_user_agents {
-                                let x: Result<Vec<_>, _> = allowed_user_agents
-                                    .split(',')
-                                    .map(|x| {
-                                        x.trim()
-                                            .parse::<UserAgent>()
-                                            .or(Err(Web3ProxyError::InvalidUserAgent))
-                                    })
-                                    .collect();
-
-                                Some(x?)
-                            } else {
-                                None
-                            };
-
-                        let rpc_key_id =
-                            Some(rpc_key_model.id.try_into().expect("db ids are never 0"));
-
-                        Ok(AuthorizationChecks {
-                            user_id: rpc_key_model.user_id,
-                            rpc_secret_key: Some(rpc_secret_key),
-                            rpc_secret_key_id: rpc_key_id,
-                            allowed_ips,
-                            allowed_origins,
-                            allowed_referers,
-                            allowed_user_agents,
-                            tracking_level: rpc_key_model.log_level,
-                            log_revert_chance: rpc_key_model.log_revert_chance,
-                            max_concurrent_requests: user_tier_model.max_concurrent_requests,
-                            max_requests_per_period: user_tier_model.max_requests_per_period,
-                            private_txs: rpc_key_model.private_txs,
+                        {
+                            Some(x) => (x, expires_at),
+                            None => return Ok(AuthorizationChecks::default()),
+                        }
+                    }
+                    None => (rpc_key_model, None),
+                };
+
+                let mut checks = self
+                    .authorization_checks_from_model(
+                        proxy_mode,
+                        Some(rpc_secret_key),
+                        rpc_key_model,
+                        &db_replica,
+                    )
+                    .await?;
+
+                checks.secret_expires_at = secret_expires_at;
+
+                Ok(checks)
+            })
+            .await
+    }
+
+    /// Like `authorization_checks`, but for keys that we only know the id of (key pools, presigned urls, rate limit status)
+    pub(crate) async fn authorization_checks_by_id(
+        &self,
+        proxy_mode: ProxyMode,
+        rpc_key_id: NonZeroU64,
+    ) -> Web3ProxyResult<AuthorizationChecks> {
+        self.rpc_key_id_cache
+            .try_get_or_insert_async(&rpc_key_id, async move {
+                let db_replica = self
+                    .db_replica()
+                    .web3_context("Getting database connection")?;
+
+                match active_rpc_key_by_id(rpc_key_id.get())
+                    .one(db_replica.conn())
+                    .await?
+                {
+                    Some(rpc_key_model) => {
+                        self.authorization_checks_from_model(
                             proxy_mode,
-                            balance: Some(balance),
-                        })
+                            None,
+                            rpc_key_model,
+                            &db_replica,
+                        )
+                        .await
                     }
                     None => Ok(AuthorizationChecks::default()),
                 }
@@ -1213,7 +1755,202 @@ impl Web3ProxyApp {
             .await
     }
 
+    async fn authorization_checks_from_model(
+        &self,
+        proxy_mode: ProxyMode,
+        rpc_secret_key: Option<RpcSecretKey>,
+        rpc_key_model: rpc_key::Model,
+        db_replica: &DatabaseReplica,
+    ) -> Web3ProxyResult<AuthorizationChecks> {
+        // TODO: join the user table to this to return the User? we don't always need it
+        // TODO: join on secondary users
+        // TODO: join on user tier
+        // TODO: move these splits into helper functions
+        // TODO: can we have sea orm handle this for us?
+        let user_model = user::Entity::find_by_id(rpc_key_model.user_id)
+            .one(db_replica.conn())
+            .await?
+            .context("no related user")?;
+
+        let balance = match rpc_key_model.org_id {
+            // org keys spend the org's balance
+            Some(org_id) => org::Entity::find_by_id(org_id)
+                .one(db_replica.conn())
+                .await?
+                .map(|x| x.available_balance)
+                .unwrap_or_default(),
+            None => balance::Entity::find()
+                .filter(balance::Column::UserId.eq(user_model.id))
+                .one(db_replica.conn())
+                .await?
+                .map(|x| x.available_balance)
+                .unwrap_or_default(),
+        };
+
+        let user_tier_model = user_tier::Entity::find_by_id(user_model.user_tier_id)
+            .one(db_replica.conn())
+            .await?
+            .context("no related user tier")?;
+
+        let cutoff = &self.config.balance_cutoff;
+
+        let balance_owner = if cutoff.enabled && cutoff.tiers.contains(&user_tier_model.title) {
+            Some(match rpc_key_model.org_id {
+                Some(org_id) => BalanceOwner::Org(org_id),
+                None => BalanceOwner::User(user_model.id),
+            })
+        } else {
+            None
+        };
+
+        let allowed_ips = rpc_key_model
+            .allowed_ips
+            .map(|x| AllowedIps::parse(&x))
+            .transpose()?;
+
+        let allowed_origins: Option<Vec<Origin>> =
+            if let Some(allowed_origins) = rpc_key_model.allowed_origins {
+                // TODO: do this without collecting twice?
+                let x = allowed_origins
+                    .split(',')
+                    .map(|x| HeaderValue::from_str(x.trim()))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .into_iter()
+                    .map(|x| Origin::decode(&mut [x].iter()))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Some(x)
+            } else {
+                None
+            };
+
+        let allowed_referers: Option<Vec<Referer>> =
+            if let Some(allowed_referers) = rpc_key_model.allowed_referers {
+                let x = allowed_referers
+                    .split(',')
+                    .map(|x| {
+                        x.trim()
+                            .parse::<Referer>()
+                            .or(Err(Web3ProxyError::InvalidReferer))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Some(x)
+            } else {
+                None
+            };
+
+        let allowed_user_agents: Option<Vec<UserAgent>> =
+            if let Some(allowed_user_agents) = rpc_key_model.allowed_user_agents {
+                let x: Result<Vec<_>, _> = allowed_user_agents
+                    .split(',')
+                    .map(|x| {
+                        x.trim()
+                            .parse::<UserAgent>()
+                            .or(Err(Web3ProxyError::InvalidUserAgent))
+                    })
+                    .collect();
+
+                Some(x?)
+            } else {
+                None
+            };
+
+        let allowed_chains = match rpc_key_model.allowed_chains {
+            Some(x) => Some(parse_allowed_chains(&x)?),
+            None => None,
+        };
+
+        let tier_method_requests_per_second = match user_tier_model.method_requests_per_second {
+            Some(x) => parse_method_requests_per_second(&x)?,
+            None => Default::default(),
+        };
+
+        let key_method_requests_per_second = match rpc_key_model.method_requests_per_second {
+            Some(x) => parse_method_requests_per_second(&x)?,
+            None => Default::default(),
+        };
+
+        let method_requests_per_second = merge_method_requests_per_second(
+            tier_method_requests_per_second,
+            key_method_requests_per_second,
+        );
+
+        let rpc_key_id = Some(rpc_key_model.id.try_into().expect("db ids are never 0"));
+
+        let token_bucket = tier_token_bucket(
+            user_tier_model.max_requests_per_period,
+            user_tier_model.burst_capacity,
+            user_tier_model.sustained_requests_per_period,
+        );
+
+        let key_token_bucket = key_token_bucket(
+            token_bucket,
+            rpc_key_model.burst_capacity,
+            rpc_key_model.sustained_requests_per_period,
+        );
+
+        let cache_partition = if self
+            .config
+            .partitioned_cache_tiers
+            .contains(&user_tier_model.title)
+        {
+            Some(user_tier_model.id.try_into().expect("db ids are never 0"))
+        } else {
+            None
+        };
+
+        let shadowing = &self.config.request_shadowing;
+
+        let request_shadow = match (rpc_key_model.shadow_url, rpc_key_model.shadow_secret) {
+            (Some(url), Some(secret))
+                if shadowing.enabled && shadowing.tiers.contains(&user_tier_model.title) =>
+            {
+                let secret = self.unseal_row(secret)?;
+
+                Some(Arc::new(ShadowTarget::new(rpc_key_model.id, url, &secret)))
+            }
+            _ => None,
+        };
+
+        Ok(AuthorizationChecks {
+            user_id: rpc_key_model.user_id,
+            rpc_secret_key,
+            rpc_secret_key_id: rpc_key_id,
+            allowed_ips,
+            allowed_origins,
+            allowed_referers,
+            allowed_user_agents,
+            allowed_chains,
+            tracking_level: rpc_key_model.log_level,
+            log_revert_chance: rpc_key_model.log_revert_chance,
+            max_concurrent_requests: user_tier_model.max_concurrent_requests,
+            max_requests_per_period: user_tier_model.max_requests_per_period,
+            token_bucket,
+            key_token_bucket,
+            max_monthly_bytes: user_tier_model.max_monthly_bytes,
+            max_queue_millis: user_tier_model.max_queue_millis,
+            max_batch_size: user_tier_model.max_batch_size,
+            max_response_bytes: user_tier_model.max_response_bytes,
+            method_requests_per_second: Arc::new(method_requests_per_second),
+            batch_overhead_cost: user_tier_model.batch_overhead_cost,
+            private_txs: rpc_key_model.private_txs,
+            proxy_mode,
+            balance: Some(balance),
+            balance_owner,
+            rpc_key_pool_id: None,
+            payload_sampling_opt_out: rpc_key_model.payload_sampling_opt_out,
+            bypass_cache: rpc_key_model.bypass_cache,
+            cache_partition,
+            canary: rpc_key_model.canary,
+            canary_freeze_siblings: rpc_key_model.canary_freeze_siblings,
+            request_shadow,
+            secret_expires_at: None,
+        })
+    }
+
     /// Authorized the ip/origin/referer/useragent and rate limit and concurrency
+    #[allow(clippy::too_many_arguments)]
     pub async fn rate_limit_by_rpc_key(
         &self,
         ip: IpAddr,
@@ -1222,21 +1959,79 @@ impl Web3ProxyApp {
         referer: Option<Referer>,
         rpc_key: RpcSecretKey,
         user_agent: Option<UserAgent>,
+        chain_id: u64,
     ) -> Web3ProxyResult<RateLimitResult> {
         let authorization_checks = self.authorization_checks(proxy_mode, rpc_key).await?;
 
+        // the checks might have been cached before the old secret's grace period ended
+        if let Some(secret_expires_at) = authorization_checks.secret_expires_at {
+            if secret_expires_at <= Utc::now().timestamp() {
+                return Ok(RateLimitResult::UnknownKey);
+            }
+        }
+
+        self.rate_limit_by_checks(
+            ip,
+            origin,
+            referer,
+            user_agent,
+            authorization_checks,
+            chain_id,
+        )
+        .await
+    }
+
+    /// Like `rate_limit_by_rpc_key`, but for keys that we only know the id of
+    #[allow(clippy::too_many_arguments)]
+    pub async fn rate_limit_by_rpc_key_id(
+        &self,
+        ip: IpAddr,
+        origin: Option<Origin>,
+        proxy_mode: ProxyMode,
+        referer: Option<Referer>,
+        rpc_key_id: NonZeroU64,
+        user_agent: Option<UserAgent>,
+        chain_id: u64,
+    ) -> Web3ProxyResult<RateLimitResult> {
+        let authorization_checks = self
+            .authorization_checks_by_id(proxy_mode, rpc_key_id)
+            .await?;
+
+        self.rate_limit_by_checks(
+            ip,
+            origin,
+            referer,
+            user_agent,
+            authorization_checks,
+            chain_id,
+        )
+        .await
+    }
+
+    async fn rate_limit_by_checks(
+        &self,
+        ip: IpAddr,
+        origin: Option<Origin>,
+        referer: Option<Referer>,
+        user_agent: Option<UserAgent>,
+        authorization_checks: AuthorizationChecks,
+        chain_id: u64,
+    ) -> Web3ProxyResult<RateLimitResult> {
         // if no rpc_key_id matching the given rpc was found, then we can't rate limit by key
         if authorization_checks.rpc_secret_key_id.is_none() {
             return Ok(RateLimitResult::UnknownKey);
         }
 
+        // before the semaphore and the token buckets so that a key on the wrong chain doesn't use up its limits
+        self.check_allowed_chain(&authorization_checks, chain_id)?;
+
         // TODO: rpc_key should have an option to rate limit by ip instead of by key
 
         // only allow this rpc_key to run a limited amount of concurrent requests
         // TODO: rate limit should be BEFORE the semaphore!
         let semaphore = self.user_semaphore(&authorization_checks).await?;
 
-        let authorization = Authorization::try_new(
+        let mut authorization = Authorization::try_new(
             authorization_checks,
             self.db_conn(),
             ip,
@@ -1246,79 +2041,387 @@ impl Web3ProxyApp {
             AuthorizationType::Frontend,
         )?;
 
-        let user_max_requests_per_period = match authorization.checks.max_requests_per_period {
-            None => {
-                return Ok(RateLimitResult::Allowed(authorization, semaphore));
-            }
-            Some(x) => x,
-        };
+        if authorization.checks.canary {
+            self.canary_tripped(&authorization).await;
+
+            // look like any other unknown key
+            return Ok(RateLimitResult::UnknownKey);
+        }
+
+        self.check_bandwidth_quota(&authorization).await?;
+
+        self.check_balance(&authorization).await?;
+
+        if authorization.checks.token_bucket.is_none()
+            && authorization.checks.key_token_bucket.is_none()
+        {
+            return Ok(RateLimitResult::Allowed(authorization, semaphore));
+        }
 
         // user key is valid. now check rate limits
-        if let Some(rate_limiter) = &self.frontend_registered_user_rate_limiter {
-            match rate_limiter
-                .throttle(
-                    authorization.checks.user_id,
-                    Some(user_max_requests_per_period),
-                    1,
-                )
-                .await
-            {
-                Ok(DeferredRateLimitResult::Allowed) => {
-                    Ok(RateLimitResult::Allowed(authorization, semaphore))
-                }
-                Ok(DeferredRateLimitResult::RetryAt(retry_at)) => {
-                    // TODO: set headers so they know when they can retry
-                    // TODO: debug or trace?
-                    // this is too verbose, but a stat might be good
-                    // TODO: keys are secrets! use the id instead
-                    // TODO: emit a stat
-                    // // trace!(?rpc_key, "rate limit exceeded until {:?}", retry_at);
-                    Ok(RateLimitResult::RateLimited(authorization, Some(retry_at)))
-                }
-                Ok(DeferredRateLimitResult::RetryNever) => {
-                    // TODO: keys are secret. don't log them!
-                    // // trace!(?rpc_key, "rate limit is 0");
-                    // TODO: emit a stat
-                    Ok(RateLimitResult::RateLimited(authorization, None))
+        // the buckets are only asked once. requests that wait in line don't ask again
+        match self.throttle_token_buckets(&authorization.checks).await {
+            Ok(None) => {
+                // TODO: if no redis, rate limit with just a local cache?
+                Ok(RateLimitResult::Allowed(authorization, semaphore))
+            }
+            Ok(Some(TokenBucketResult::Allowed(_))) => {
+                Ok(RateLimitResult::Allowed(authorization, semaphore))
+            }
+            Ok(Some(TokenBucketResult::RetryAt(retry_at, _))) => {
+                // some tiers would rather wait for their bucket to refill than get an error
+                if let Some(max_queue_millis) = authorization.checks.max_queue_millis {
+                    let rpc_key_id = authorization
+                        .checks
+                        .rpc_secret_key_id
+                        .expect("checked above");
+
+                    let queue_start = Instant::now();
+
+                    match self
+                        .rate_limit_queue
+                        .enqueue(
+                            rpc_key_id,
+                            retry_at,
+                            Self::queue_interval(&authorization.checks),
+                            queue_start + Duration::from_millis(max_queue_millis),
+                        )
+                        .await
+                    {
+                        QueueResult::Turn(turn) => {
+                            trace!("queueing rpc key {} until {:?}", rpc_key_id, turn.at);
+
+                            sleep_until(turn.at).await;
+
+                            authorization.rate_limit_queue_millis =
+                                queue_start.elapsed().as_millis() as u64;
+
+                            return Ok(RateLimitResult::Allowed(authorization, semaphore));
+                        }
+                        QueueResult::Full => {
+                            self.record_rate_limit_rejection(authorization.checks.user_id)
+                                .await;
+
+                            return Err(Web3ProxyError::RateLimitQueueFull(
+                                rpc_key_id,
+                                self.rate_limit_queue.max_depth(),
+                            ));
+                        }
+                        QueueResult::TooLate => {}
+                    }
                 }
-                Err(err) => {
-                    // internal error, not rate limit being hit
-                    // TODO: i really want axum to do this for us in a single place.
-                    error!("rate limiter is unhappy. allowing ip. err={:?}", err);
 
-                    Ok(RateLimitResult::Allowed(authorization, semaphore))
-                }
+                self.record_rate_limit_rejection(authorization.checks.user_id)
+                    .await;
+
+                // TODO: emit a stat
+                Ok(RateLimitResult::RateLimited(authorization, Some(retry_at)))
+            }
+            Ok(Some(TokenBucketResult::RetryNever)) => {
+                self.record_rate_limit_rejection(authorization.checks.user_id)
+                    .await;
+
+                // TODO: emit a stat
+                Ok(RateLimitResult::RateLimited(authorization, None))
+            }
+            Err(err) => {
+                // internal error, not rate limit being hit
+                // TODO: i really want axum to do this for us in a single place.
+                error!("token buckets are unhappy. allowing key. err={:?}", err);
+
+                Ok(RateLimitResult::Allowed(authorization, semaphore))
             }
-        } else {
-            // TODO: if no redis, rate limit with just a local cache?
-            Ok(RateLimitResult::Allowed(authorization, semaphore))
         }
     }
 }
 
+/// compare secrets without leaking how much of them matched through timing
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 impl Authorization {
     pub async fn check_again(
         &self,
         app: &Arc<Web3ProxyApp>,
-    ) -> Web3ProxyResult<(Arc<Self>, Option<OwnedSemaphorePermit>)> {
+    ) -> Web3ProxyResult<(Arc<Self>, Option<RegistryPermit>)> {
+        // the internal lane is never rate limited
+        if self.is_internal() {
+            return Ok((Arc::new(self.clone()), None));
+        }
+
         // TODO: we could probably do this without clones. but this is easy
-        let (a, s) = if let Some(rpc_secret_key) = self.checks.rpc_secret_key {
-            key_is_authorized(
+        // the id is used because requests through key pools and presigned urls don't have the secret
+        let (mut a, s) = if let Some(rpc_key_id) = self.checks.rpc_secret_key_id {
+            key_id_is_authorized(
                 app,
-                rpc_secret_key,
+                rpc_key_id,
                 self.ip,
                 self.origin.clone(),
                 self.checks.proxy_mode,
                 self.referer.clone(),
                 self.user_agent.clone(),
+                app.config.chain_id,
             )
             .await?
         } else {
             ip_is_authorized(app, self.ip, self.origin.clone(), self.checks.proxy_mode).await?
         };
 
+        // keep stats for key pools grouped under the pool
+        a.checks.rpc_key_pool_id = self.checks.rpc_key_pool_id;
+
         let a = Arc::new(a);
 
         Ok((a, s))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::spawn_test_app;
+    use migration::sea_orm::{DbBackend, QueryTrait};
+    use std::str::FromStr;
+
+    fn hasher_with_key(name: &str, key_hex: &str) -> RpcKeyHasher {
+        let path = std::env::temp_dir().join(format!(
+            "web3_proxy_rpc_key_hash_{}_{}",
+            name,
+            std::process::id()
+        ));
+
+        std::fs::write(&path, key_hex).unwrap();
+
+        let app_config = AppConfig {
+            db_url: Some("mysql://localhost/test".to_string()),
+            rpc_key_hash_keyfile: Some(path.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+
+        let x = RpcKeyHasher::from_config(&app_config).unwrap();
+
+        std::fs::remove_file(path).unwrap();
+
+        x
+    }
+
+    #[test]
+    fn test_keyfile_required_with_db() {
+        let app_config = AppConfig {
+            db_url: Some("mysql://localhost/test".to_string()),
+            ..Default::default()
+        };
+
+        assert!(RpcKeyHasher::from_config(&app_config).is_err());
+
+        // without a database, nothing is saved
+        assert!(RpcKeyHasher::from_config(&AppConfig::default()).is_ok());
+
+        let app_config = AppConfig {
+            db_url: Some("mysql://localhost/test".to_string()),
+            rpc_key_hash_keyfile: Some("/nonexistent/rpc_key_hash_key".to_string()),
+            ..Default::default()
+        };
+
+        assert!(RpcKeyHasher::from_config(&app_config).is_err());
+    }
+
+    #[test]
+    fn test_hash() {
+        let a = hasher_with_key("a", "00112233445566778899aabbccddeeff\n");
+        let b = hasher_with_key("b", "ffeeddccbbaa99887766554433221100");
+
+        let uuid = Uuid::from_str("bbd2a5fa-5d4e-4b11-8e4e-9e7b1b6bd9f4").unwrap();
+        let ulid: Ulid = uuid.into();
+
+        // the same key in either form gets the same hash
+        assert_eq!(
+            a.hash(RpcSecretKey::Uuid(uuid)),
+            a.hash(RpcSecretKey::Ulid(ulid))
+        );
+
+        assert_eq!(a.hash(RpcSecretKey::Uuid(uuid)).len(), 64);
+
+        // a different key is a different hash. the keyfile must never change
+        assert_ne!(
+            a.hash(RpcSecretKey::Uuid(uuid)),
+            b.hash(RpcSecretKey::Uuid(uuid))
+        );
+
+        // the trailing newline in the keyfile is ignored
+        let a2 = hasher_with_key("a2", "00112233445566778899aabbccddeeff");
+
+        assert_eq!(
+            a.hash(RpcSecretKey::Uuid(uuid)),
+            a2.hash(RpcSecretKey::Uuid(uuid))
+        );
+    }
+
+    #[test]
+    fn test_condition_only_checks_hash() {
+        let hasher = hasher_with_key("condition", "00112233445566778899aabbccddeeff");
+
+        let rpc_secret_key = RpcSecretKey::new();
+
+        let sql = rpc_key::Entity::find()
+            .filter(hasher.condition(rpc_secret_key))
+            .build(DbBackend::MySql)
+            .to_string();
+
+        let (_, filters) = sql.split_once("WHERE").unwrap();
+
+        assert!(filters.contains(&hasher.hash(rpc_secret_key)));
+        assert!(filters.contains("`rpc_key`.`secret_key_hash` ="));
+        assert!(!filters.contains("`rpc_key`.`secret_key` ="));
+    }
+
+    #[test]
+    fn test_pool_condition_only_checks_hash() {
+        let hasher = hasher_with_key("pool", "00112233445566778899aabbccddeeff");
+
+        let rpc_key_pool_key = RpcSecretKey::new();
+
+        let sql = rpc_key_pool::Entity::find()
+            .filter(hasher.pool_condition(rpc_key_pool_key))
+            .build(DbBackend::MySql)
+            .to_string();
+
+        let (_, filters) = sql.split_once("WHERE").unwrap();
+
+        assert!(filters.contains(&hasher.hash(rpc_key_pool_key)));
+        assert!(filters.contains("`rpc_key_pool`.`secret_key_hash` ="));
+        assert!(!filters.contains("`rpc_key_pool`.`secret_key` ="));
+    }
+
+    #[test]
+    fn test_pool_secrets_are_not_serialized() {
+        let pool = rpc_key_pool::Model {
+            id: 1,
+            user_id: 2,
+            secret_key: Some(RpcSecretKey::new().into()),
+            secret_key_hash: Some("abc".to_string()),
+            description: None,
+            active: true,
+        };
+
+        let x = serde_json::to_value(pool).unwrap();
+
+        assert!(x.get("secret_key").is_none());
+        assert!(x.get("secret_key_hash").is_none());
+        assert_eq!(x["id"], 1);
+    }
+
+    #[test]
+    fn test_active_rpc_key_by_id() {
+        let sql = active_rpc_key_by_id(5).build(DbBackend::MySql).to_string();
+
+        let (_, filters) = sql.split_once("WHERE").unwrap();
+
+        assert!(filters.contains("`rpc_key`.`id` = 5"));
+        assert!(filters.contains("`rpc_key`.`active` = TRUE"));
+        assert!(filters.contains("`rpc_key`.`superseded_by` IS NULL"));
+    }
+
+    #[test]
+    fn test_pool_member_order() {
+        let ids: Vec<NonZeroU64> = (1..=3).map(|x| NonZeroU64::new(x).unwrap()).collect();
+
+        let members: Vec<_> = ids.iter().map(|x| (*x, None)).collect();
+
+        // members take turns going first
+        assert_eq!(pool_member_order(&members, 0), ids);
+        assert_eq!(pool_member_order(&members, 1), vec![ids[1], ids[2], ids[0]]);
+        assert_eq!(pool_member_order(&members, 5), vec![ids[2], ids[0], ids[1]]);
+
+        // an empty member goes last, but is still tried
+        let members = vec![(ids[0], Some(10)), (ids[1], Some(0)), (ids[2], None)];
+
+        assert_eq!(pool_member_order(&members, 0), vec![ids[0], ids[2], ids[1]]);
+        assert_eq!(pool_member_order(&members, 1), vec![ids[2], ids[0], ids[1]]);
+
+        let members = vec![(ids[0], Some(0)), (ids[1], Some(0))];
+
+        assert_eq!(pool_member_order(&members, 1), vec![ids[1], ids[0]]);
+    }
+
+    #[tokio::test]
+    async fn test_key_pool_is_authorized() {
+        let app = spawn_test_app().await;
+
+        let ids: Vec<NonZeroU64> = (1..=3).map(|x| NonZeroU64::new(x).unwrap()).collect();
+
+        for rpc_key_id in &ids[..2] {
+            app.rpc_key_id_cache
+                .try_insert(
+                    *rpc_key_id,
+                    AuthorizationChecks {
+                        user_id: 1,
+                        rpc_secret_key_id: Some(*rpc_key_id),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+
+        // the third key was deactivated after the pool was cached
+        app.rpc_key_id_cache
+            .try_insert(ids[2], AuthorizationChecks::default())
+            .unwrap();
+
+        let rpc_key_pool_key = RpcSecretKey::new();
+
+        app.rpc_key_pool_cache
+            .try_insert(
+                app.rpc_key_hasher.hash(rpc_key_pool_key),
+                Arc::new(RpcKeyPoolMembers {
+                    rpc_key_pool_id: NonZeroU64::new(7),
+                    rpc_key_ids: ids.clone(),
+                    ..Default::default()
+                }),
+            )
+            .unwrap();
+
+        let ip = IpAddr::from_str("127.0.0.1").unwrap();
+
+        let mut used = vec![];
+
+        for _ in 0..3 {
+            let (authorization, _) = key_pool_is_authorized(
+                &app,
+                rpc_key_pool_key,
+                ip,
+                None,
+                ProxyMode::Best,
+                None,
+                None,
+                1,
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(authorization.checks.rpc_key_pool_id, NonZeroU64::new(7));
+
+            used.push(authorization.checks.rpc_secret_key_id.unwrap());
+        }
+
+        // the deactivated key is skipped for the next member
+        assert_eq!(used, vec![ids[0], ids[1], ids[0]]);
+
+        // a pool that doesn't exist
+        let unknown = RpcSecretKey::new();
+
+        app.rpc_key_pool_cache
+            .try_insert(app.rpc_key_hasher.hash(unknown), Default::default())
+            .unwrap();
+
+        assert!(matches!(
+            key_pool_is_authorized(&app, unknown, ip, None, ProxyMode::Best, None, None, 1).await,
+            Err(Web3ProxyError::UnknownKey)
+        ));
+    }
+}
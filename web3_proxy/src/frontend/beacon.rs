@@ -0,0 +1,178 @@
+//! Proxy consensus layer (beacon node) REST apis so that staking infra can use the same keys as json-rpc.
+
+use super::authorization::{
+    ip_is_authorized, key_is_authorized, Authorization, RequestMetadata, RequestOrMethod,
+};
+use super::errors::{Web3ProxyError, Web3ProxyResponse};
+use super::rpc_proxy_ws::ProxyMode;
+use crate::app::Web3ProxyApp;
+use crate::rpcs::beacon::beacon_stat_label;
+use crate::semaphore_registry::RegistryPermit;
+use axum::body::Bytes;
+use axum::extract::{Path, RawQuery};
+use axum::headers::{Origin, Referer, UserAgent};
+use axum::response::IntoResponse;
+use axum::{Extension, TypedHeader};
+use axum_client_ip::InsecureClientIp;
+use axum_macros::debug_handler;
+use http::header::{ACCEPT, CONTENT_TYPE};
+use http::{HeaderMap, HeaderName, Method};
+use itertools::Itertools;
+use std::sync::atomic;
+use std::sync::Arc;
+
+/// `GET|POST /beacon/eth/*path` -- Public entrypoint for beacon node REST requests.
+/// Rate limited by IP the same as the public rpc.
+#[debug_handler]
+#[allow(clippy::too_many_arguments)]
+pub async fn beacon_proxy(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    method: Method,
+    Path(path): Path<String>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Web3ProxyResponse {
+    let (authorization, semaphore) =
+        ip_is_authorized(&app, ip, origin.map(|x| x.0), ProxyMode::Best).await?;
+
+    _beacon_proxy(
+        app,
+        authorization,
+        semaphore,
+        method,
+        path,
+        query,
+        headers,
+        body,
+    )
+    .await
+}
+
+/// `GET|POST /beacon/:rpc_key/eth/*path` -- Authenticated entrypoint for beacon node REST requests.
+/// Rate limits, billing, and caching are based on the key the same as json-rpc.
+#[debug_handler]
+#[allow(clippy::too_many_arguments)]
+pub async fn beacon_proxy_with_key(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    method: Method,
+    Path((rpc_key, path)): Path<(String, String)>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Web3ProxyResponse {
+    let rpc_key = rpc_key.parse()?;
+
+    let (authorization, semaphore) = key_is_authorized(
+        &app,
+        rpc_key,
+        ip,
+        origin.map(|x| x.0),
+        ProxyMode::Best,
+        referer.map(|x| x.0),
+        user_agent.map(|x| x.0),
+        app.config.chain_id,
+    )
+    .await?;
+
+    _beacon_proxy(
+        app,
+        authorization,
+        semaphore,
+        method,
+        path,
+        query,
+        headers,
+        body,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn _beacon_proxy(
+    app: Arc<Web3ProxyApp>,
+    authorization: Authorization,
+    _semaphore: Option<RegistryPermit>,
+    method: Method,
+    path: String,
+    query: Option<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Web3ProxyResponse {
+    let beacon_rpcs = app.beacon_rpcs.as_ref().ok_or(Web3ProxyError::NotFound)?;
+
+    let authorization = Arc::new(authorization);
+
+    // the route matched "eth/". put it back
+    let path = format!("eth/{}", path.trim_start_matches('/'));
+
+    let label = beacon_stat_label(&method, &path);
+
+    let request_metadata = RequestMetadata::new(
+        &app,
+        authorization.clone(),
+        RequestOrMethod::Method(&label, body.len()),
+        None,
+    )
+    .await;
+
+    let accept = headers.get(ACCEPT).and_then(|x| x.to_str().ok());
+
+    let (status, content_type, response_body) = match beacon_rpcs
+        .request(
+            method,
+            &path,
+            query.as_deref(),
+            accept,
+            body,
+            authorization.checks.bypass_cache,
+            authorization.checks.cache_partition,
+            &request_metadata,
+        )
+        .await
+    {
+        Ok(x) => x,
+        Err(err) => {
+            request_metadata
+                .error_response
+                .store(true, atomic::Ordering::Release);
+
+            return Err(err);
+        }
+    };
+
+    request_metadata.add_response(response_body.len() as u64);
+
+    if status.is_server_error() {
+        request_metadata
+            .error_response
+            .store(true, atomic::Ordering::Release);
+    }
+
+    let rpcs = request_metadata
+        .backend_requests
+        .lock()
+        .iter()
+        .map(|x| x.name.clone())
+        .join(",");
+
+    let content_type = content_type.unwrap_or_else(|| "application/json".to_string());
+
+    let response = (
+        status,
+        [
+            (CONTENT_TYPE, content_type),
+            (HeaderName::from_static("x-w3p-backend-rpcs"), rpcs),
+        ],
+        response_body,
+    )
+        .into_response();
+
+    Ok(response)
+}
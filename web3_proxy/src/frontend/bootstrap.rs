@@ -0,0 +1,95 @@
+//! Everything a client SDK needs to configure itself in one request.
+
+use super::authorization::key_is_authorized;
+use super::errors::Web3ProxyResponse;
+use super::rpc_proxy_ws::ProxyMode;
+use crate::app::{Web3ProxyApp, APP_USER_AGENT, RATE_LIMIT_PERIOD_SECONDS};
+use axum::extract::Path;
+use axum::headers::{Origin, Referer, UserAgent};
+use axum::TypedHeader;
+use axum::{response::IntoResponse, Extension, Json};
+use axum_client_ip::InsecureClientIp;
+use axum_macros::debug_handler;
+use serde_json::json;
+use std::sync::Arc;
+
+/// `GET /v1/bootstrap/:rpc_key` -- The key's endpoints, limits, and the features it can use.
+/// The key is checked (and rate limited) the same as a json-rpc request, so a key that can bootstrap can make requests.
+#[debug_handler]
+pub async fn bootstrap_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    Path(rpc_key): Path<String>,
+) -> Web3ProxyResponse {
+    let rpc_key = rpc_key.parse()?;
+
+    let (authorization, _semaphore) = key_is_authorized(
+        &app,
+        rpc_key,
+        ip,
+        origin.map(|x| x.0),
+        ProxyMode::Best,
+        referer.map(|x| x.0),
+        user_agent.map(|x| x.0),
+        app.config.chain_id,
+    )
+    .await?;
+
+    let checks = &authorization.checks;
+
+    // without a public_url, the urls are relative to wherever the client sent this request
+    let http_base = app
+        .config
+        .public_url
+        .as_deref()
+        .unwrap_or_default()
+        .trim_end_matches('/');
+
+    let ws_base = if let Some(x) = http_base.strip_prefix("http") {
+        format!("ws{}", x)
+    } else {
+        http_base.to_string()
+    };
+
+    // the key's own bucket is the one that matters if it has one
+    let token_bucket = checks.key_token_bucket.or(checks.token_bucket);
+
+    let response_json = json!({
+        "version": APP_USER_AGENT,
+        "chains": [{
+            "chain_id": app.config.chain_id,
+            "name": app.chain_name(),
+            "http_url": format!("{}/rpc/{}", http_base, rpc_key),
+            "ws_url": format!("{}/rpc/{}", ws_base, rpc_key),
+            "namespaces": app.balanced_rpcs.namespaces(),
+            "archive": app.balanced_rpcs.has_archive(),
+            "rollup": app.config.rollup,
+            "l1_fee_oracle": app.config.rollup.and_then(|x| x.l1_fee_oracle()),
+        }],
+        "limits": {
+            "max_requests_per_period": checks.max_requests_per_period,
+            "burst_capacity": token_bucket.map(|x| x.burst),
+            "sustained_requests_per_period": token_bucket.map(|x| x.sustained),
+            "period_seconds": RATE_LIMIT_PERIOD_SECONDS,
+            "max_concurrent_requests": checks.max_concurrent_requests,
+            "max_queue_millis": checks.max_queue_millis,
+            "max_batch_size": checks.max_batch_size,
+            "method_requests_per_second": checks.method_requests_per_second,
+            "batch_overhead_cost": checks.batch_overhead_cost,
+            "max_response_bytes": app.config.max_response_bytes,
+        },
+        "features": {
+            "batch_requests": true,
+            "bypass_cache": checks.bypass_cache,
+            "private_txs": checks.private_txs,
+            "tracking_level": checks.tracking_level,
+            "ws_resume": app.config.ws_resume_grace_seconds > 0,
+            "ws_new_heads_modes": ["full", "compact", "delta"],
+        },
+    });
+
+    Ok(Json(response_json).into_response())
+}
@@ -0,0 +1,315 @@
+//! Rewrite error messages with the operator's branding and the user's language.
+//! The `error_code` in each error's data is never changed, so clients that match on codes keep working.
+use crate::app::Web3ProxyApp;
+use crate::config::ErrorMessagesConfig;
+use axum::body::{self, Body, Bytes, Full, HttpBody};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Extension;
+use hashbrown::HashMap;
+use http::header::{ACCEPT_LANGUAGE, CONTENT_LENGTH, CONTENT_TYPE};
+use http::Request;
+use log::warn;
+use serde_json::json;
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+/// error bodies are small. anything bigger than this is not an error body and is left alone
+const MAX_ERROR_BODY_BYTES: u64 = 64 * 1024;
+
+type Templates = HashMap<String, String>;
+
+impl ErrorMessagesConfig {
+    /// Nothing to rewrite
+    pub fn is_empty(&self) -> bool {
+        self.brand.is_none() && self.support_url.is_none() && self.locales.is_empty()
+    }
+
+    /// Pick the best configured locale for an Accept-Language header
+    fn select_locale(&self, accept_language: Option<&str>) -> Option<&Templates> {
+        let find = |tag: &str| {
+            self.locales
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(tag))
+                .map(|(_, v)| v)
+        };
+
+        for tag in accept_language
+            .map(parse_accept_language)
+            .unwrap_or_default()
+        {
+            if tag == "*" {
+                break;
+            }
+
+            // "pt-BR" falls back to "pt"
+            let primary = tag.split('-').next().unwrap_or(tag);
+
+            if let Some(templates) = find(tag).or_else(|| find(primary)) {
+                return Some(templates);
+            }
+        }
+
+        self.default_locale.as_deref().and_then(find)
+    }
+
+    /// The rewritten message for an error. None if there is no template for it
+    fn render(&self, templates: &Templates, error_code: &str, message: &str) -> Option<String> {
+        let template = templates.get(error_code).or_else(|| templates.get("*"))?;
+
+        let rendered = template
+            .replace("{message}", message)
+            .replace("{error_code}", error_code)
+            .replace("{brand}", self.brand.as_deref().unwrap_or_default())
+            .replace(
+                "{support_url}",
+                self.support_url.as_deref().unwrap_or_default(),
+            );
+
+        Some(rendered)
+    }
+
+    /// Rewrite the errors in a JSON-RPC response body. Returns true if anything changed
+    pub fn localize(&self, accept_language: Option<&str>, body: &mut serde_json::Value) -> bool {
+        if let Some(responses) = body.as_array_mut() {
+            let mut changed = false;
+            for response in responses {
+                changed |= self.localize(accept_language, response);
+            }
+            return changed;
+        }
+
+        let err = match body.get_mut("error") {
+            Some(serde_json::Value::Object(x)) => x,
+            _ => return false,
+        };
+
+        let error_code = match err
+            .get("data")
+            .and_then(|x| x.get("error_code"))
+            .and_then(|x| x.as_str())
+        {
+            Some(x) => x.to_string(),
+            // not one of our errors. backend errors are passed through as-is
+            None => return false,
+        };
+
+        let mut changed = false;
+
+        if let Some(support_url) = self.support_url.as_ref() {
+            if let Some(data) = err.get_mut("data").and_then(|x| x.as_object_mut()) {
+                data.insert("support_url".to_string(), json!(support_url));
+                changed = true;
+            }
+        }
+
+        if let Some(templates) = self.select_locale(accept_language) {
+            let message = err
+                .get("message")
+                .and_then(|x| x.as_str())
+                .unwrap_or_default();
+
+            if let Some(rendered) = self.render(templates, &error_code, message) {
+                err.insert("message".to_string(), json!(rendered));
+                changed = true;
+            }
+        }
+
+        changed
+    }
+}
+
+/// Language tags from an Accept-Language header, best first
+fn parse_accept_language(header: &str) -> Vec<&str> {
+    let mut tags: Vec<(&str, f32)> = header
+        .split(',')
+        .filter_map(|x| {
+            let mut parts = x.split(';');
+
+            let tag = parts.next()?.trim();
+            if tag.is_empty() {
+                return None;
+            }
+
+            let q = parts
+                .find_map(|x| x.trim().strip_prefix("q="))
+                .and_then(|x| x.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            // q=0 means "not acceptable"
+            if q <= 0.0 {
+                None
+            } else {
+                Some((tag, q))
+            }
+        })
+        .collect();
+
+    // the sort is stable, so equal weights keep the header's order
+    tags.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+
+    tags.into_iter().map(|x| x.0).collect()
+}
+
+/// Axum middleware that applies `error_messages` from the config to error responses.
+/// Successful responses are streamed through untouched.
+pub async fn localize_errors(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let error_messages = &app.config.error_messages;
+
+    if error_messages.is_empty() {
+        return next.run(request).await;
+    }
+
+    let accept_language = request
+        .headers()
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|x| x.to_str().ok())
+        .map(|x| x.to_string());
+
+    let response = next.run(request).await;
+
+    if response.status().is_success() {
+        return response;
+    }
+
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .map_or(false, |x| x.starts_with("application/json"));
+
+    // handlers build error bodies in memory, so their exact size is known without reading them
+    let is_small = response
+        .body()
+        .size_hint()
+        .exact()
+        .map_or(false, |x| x <= MAX_ERROR_BODY_BYTES);
+
+    if !is_json || !is_small {
+        return response;
+    }
+
+    let (mut parts, old_body) = response.into_parts();
+
+    let bytes = match hyper::body::to_bytes(old_body).await {
+        Ok(x) => x,
+        Err(err) => {
+            warn!("unable to read error body: {:?}", err);
+            return Response::from_parts(parts, body::boxed(Full::from(Bytes::new())));
+        }
+    };
+
+    let mut json = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(x) => x,
+        Err(_) => return Response::from_parts(parts, body::boxed(Full::from(bytes))),
+    };
+
+    if !error_messages.localize(accept_language.as_deref(), &mut json) {
+        return Response::from_parts(parts, body::boxed(Full::from(bytes)));
+    }
+
+    let bytes = match serde_json::to_vec(&json) {
+        Ok(x) => Bytes::from(x),
+        Err(err) => {
+            warn!("unable to serialize localized error: {:?}", err);
+            bytes
+        }
+    };
+
+    // the length changed. hyper sets it again from the new body
+    parts.headers.remove(CONTENT_LENGTH);
+
+    Response::from_parts(parts, body::boxed(Full::from(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ErrorMessagesConfig {
+        let mut en = HashMap::new();
+        en.insert(
+            "*".to_string(),
+            "{message}. Contact {brand} at {support_url}".to_string(),
+        );
+
+        let mut es = HashMap::new();
+        es.insert(
+            "auth.expired_bearer".to_string(),
+            "Su sesión de {brand} expiró ({error_code})".to_string(),
+        );
+
+        let mut locales = HashMap::new();
+        locales.insert("en".to_string(), en);
+        locales.insert("es".to_string(), es);
+
+        ErrorMessagesConfig {
+            brand: Some("Llama".to_string()),
+            support_url: Some("https://example.com/support".to_string()),
+            default_locale: Some("en".to_string()),
+            locales,
+        }
+    }
+
+    fn error_body(error_code: &str) -> serde_json::Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": {
+                "code": 401,
+                "message": "bearer token expired",
+                "data": { "error_code": error_code },
+            },
+        })
+    }
+
+    #[test]
+    fn test_parse_accept_language() {
+        assert_eq!(
+            parse_accept_language("fr-CH, fr;q=0.9, en;q=0.8, de;q=0, *;q=0.5"),
+            vec!["fr-CH", "fr", "en", "*"]
+        );
+        assert_eq!(parse_accept_language("es, en"), vec!["es", "en"]);
+        assert!(parse_accept_language("").is_empty());
+    }
+
+    #[test]
+    fn test_localize_errors() {
+        let config = config();
+
+        // a region falls back to its language
+        let mut body = error_body("auth.expired_bearer");
+        assert!(config.localize(Some("es-MX,en;q=0.5"), &mut body));
+        assert_eq!(
+            body["error"]["message"],
+            "Su sesión de Llama expiró (auth.expired_bearer)"
+        );
+        // the code never changes
+        assert_eq!(body["error"]["data"]["error_code"], "auth.expired_bearer");
+        assert_eq!(
+            body["error"]["data"]["support_url"],
+            "https://example.com/support"
+        );
+
+        // unknown languages use the default locale and its "*" template
+        let mut body = error_body("auth.expired_bearer");
+        assert!(config.localize(Some("ja"), &mut body));
+        assert_eq!(
+            body["error"]["message"],
+            "bearer token expired. Contact Llama at https://example.com/support"
+        );
+
+        // errors from backends do not have a code and are not ours to change
+        let mut body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": { "code": -32000, "message": "execution reverted" },
+        });
+        assert!(!config.localize(Some("es"), &mut body));
+        assert_eq!(body["error"]["message"], "execution reverted");
+    }
+}
@@ -1,11 +1,11 @@
 //! Utlities for logging errors for admins and displaying errors to users.
 
 use super::authorization::Authorization;
-use crate::jsonrpc::{JsonRpcErrorData, JsonRpcForwardedResponse};
+use crate::jsonrpc::{JsonRpcErrorData, JsonRpcForwardedResponse, JsonRpcRequestEnum};
 use crate::response_cache::JsonRpcResponseData;
 
 use std::error::Error;
-use std::{borrow::Cow, net::IpAddr};
+use std::{borrow::Cow, net::IpAddr, num::NonZeroU64};
 
 use axum::{
     headers,
@@ -14,18 +14,35 @@ use axum::{
     Json,
 };
 use derive_more::{Display, Error, From};
-use http::header::InvalidHeaderValue;
+use http::header::{HeaderValue, InvalidHeaderValue, RETRY_AFTER};
 use ipnet::AddrParseError;
 use log::{debug, error, info, trace, warn};
+use migration::sea_orm::prelude::Decimal;
 use migration::sea_orm::DbErr;
 use redis_rate_limiter::redis::RedisError;
 use reqwest::header::ToStrError;
+use serde_json::json;
+use serde_json::value::RawValue;
 use tokio::{sync::AcquireError, task::JoinError, time::Instant};
 
 pub type Web3ProxyResult<T> = Result<T, Web3ProxyError>;
 // TODO: take "IntoResponse" instead of Response?
 pub type Web3ProxyResponse = Web3ProxyResult<Response>;
 
+/// Sent in the `data` of rate limit and payment errors so that clients can back off correctly instead of guessing.
+/// `retry_after_ms` is added when the error is turned into a response
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackoffHint {
+    /// requests allowed per window
+    pub limit: Option<u64>,
+    /// seconds
+    pub window: Option<u64>,
+    /// requests allowed at once. only set for token buckets
+    pub burst: Option<u64>,
+    /// where the user can get higher limits or more credits
+    pub upgrade_url: Option<String>,
+}
+
 impl From<Web3ProxyError> for Web3ProxyResult<()> {
     fn from(value: Web3ProxyError) -> Self {
         Err(value)
@@ -39,22 +56,51 @@ pub enum Web3ProxyError {
     Anyhow(anyhow::Error),
     #[error(ignore)]
     #[from(ignore)]
-    BadRequest(String),
-    #[error(ignore)]
-    #[from(ignore)]
     BadResponse(String),
     BadRouting,
+    /// the key's balance and the grace are used up. `balance` is negative while in the grace
+    #[display(fmt = "{}", balance)]
+    #[from(ignore)]
+    BalanceExhausted {
+        balance: Decimal,
+        grace: Decimal,
+        hint: BackoffHint,
+    },
+    /// the key used all of its tier's bytes for the month. `resets_at` is a unix timestamp
+    #[display(fmt = "{}/{}", used_bytes, max_bytes)]
+    #[from(ignore)]
+    BandwidthExceeded {
+        used_bytes: u64,
+        max_bytes: u64,
+        resets_at: i64,
+    },
+    /// the batch has more requests than the user's tier allows
+    #[display(fmt = "{}/{}", num_requests, max_batch_size)]
+    #[from(ignore)]
+    BatchTooLarge {
+        num_requests: usize,
+        max_batch_size: u64,
+    },
+    /// the key isn't allowed on this chain id
+    #[error(ignore)]
+    #[from(ignore)]
+    ChainNotAllowed(u64),
     Database(DbErr),
     #[display(fmt = "{:#?}, {:#?}", _0, _1)]
     EipVerificationFailed(Box<Web3ProxyError>, Box<Web3ProxyError>),
     EthersHttpClient(ethers::prelude::HttpClientError),
     EthersProvider(ethers::prelude::ProviderError),
     EthersWsClient(ethers::prelude::WsClientError),
+    ExpiredBearerToken,
     FlumeRecv(flume::RecvError),
     GasEstimateNotU256,
     Headers(headers::Error),
     HeaderToString(ToStrError),
     Hyper(hyper::Error),
+    /// another request with the same Idempotency-Key has not finished yet
+    IdempotencyKeyInProgress,
+    /// the Idempotency-Key was already used for a different request
+    IdempotencyKeyReused,
     InfluxDb2Request(influxdb2::RequestError),
     #[display(fmt = "{} > {}", min, max)]
     #[from(ignore)]
@@ -62,9 +108,27 @@ pub enum Web3ProxyError {
         min: u64,
         max: u64,
     },
+    InvalidBearerToken,
     InvalidHeaderValue(InvalidHeaderValue),
     InvalidEip,
     InvalidInviteCode,
+    /// the refresh token is unknown, expired, or was already used
+    InvalidRefreshToken,
+    /// a query param or json body field is present but unusable
+    #[display(fmt = "{}: {}", _0, _1)]
+    #[error(ignore)]
+    #[from(ignore)]
+    InvalidParam(&'static str, Cow<'static, str>),
+    /// why the presigned url was rejected
+    #[display(fmt = "{}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    InvalidPresignedUrl(&'static str),
+    InvalidQueryWindow,
+    /// the params of a json-rpc request are not what the method needs
+    #[error(ignore)]
+    #[from(ignore)]
+    InvalidRpcParams(Cow<'static, str>),
     Io(std::io::Error),
     UnknownReferralCode,
     InvalidReferer,
@@ -76,6 +140,20 @@ pub enum Web3ProxyError {
     #[from(ignore)]
     IpNotAllowed(IpAddr),
     JoinError(JoinError),
+    /// a query param or json body field is required
+    #[error(ignore)]
+    #[from(ignore)]
+    MissingParam(&'static str),
+    /// the method is not in the presigned url's scope
+    #[display(fmt = "{}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    MethodNotAllowed(String),
+    /// the key or ip used up its limit for this method. other methods still work
+    #[display(fmt = "{}, {:?}", _0, _1)]
+    #[error(ignore)]
+    #[from(ignore)]
+    MethodRateLimited(String, Option<Instant>, BackoffHint),
     #[display(fmt = "{:?}", _0)]
     #[error(ignore)]
     MsgPackEncode(rmp_serde::encode::Error),
@@ -83,6 +161,7 @@ pub enum Web3ProxyError {
     NoBlocksKnown,
     NoConsensusHeadBlock,
     NoHandleReady,
+    NoRpcKeys,
     NoServersSynced,
     #[display(fmt = "{}/{}", num_known, min_head_rpcs)]
     #[from(ignore)]
@@ -98,6 +177,7 @@ pub enum Web3ProxyError {
     },
     NotFound,
     NotImplemented,
+    NotRpcKeyOwner,
     OriginRequired,
     #[error(ignore)]
     #[from(ignore)]
@@ -107,17 +187,71 @@ pub enum Web3ProxyError {
     ParseBytesError(Option<ethers::types::ParseBytesError>),
     ParseMsgError(siwe::ParseError),
     ParseAddressError,
+    PaymentAlreadyCredited,
+    /// one of the chain's worker pools stayed full for too long
+    #[error(ignore)]
+    #[from(ignore)]
+    PoolSaturated(&'static str),
+    PaymentNotFound,
+    QueryRangeEmpty,
     #[display(fmt = "{:?}, {:?}", _0, _1)]
-    RateLimited(Authorization, Option<Instant>),
+    RateLimited(Authorization, Option<Instant>, BackoffHint),
+    /// too many of the key's requests are already waiting for its bucket to refill
+    #[display(fmt = "{} {}", _0, _1)]
+    #[error(ignore)]
+    #[from(ignore)]
+    RateLimitQueueFull(NonZeroU64, usize),
+    /// state-changing methods are disabled. the reason is shown to the user
+    #[display(fmt = "{}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    ReadOnly(String),
+    #[display(fmt = "{} {}/{}", method, num_bytes, max_bytes)]
+    #[from(ignore)]
+    ResponseTooLarge {
+        method: String,
+        num_bytes: u64,
+        max_bytes: u64,
+    },
     Redis(RedisError),
     RefererRequired,
     #[display(fmt = "{:?}", _0)]
     #[error(ignore)]
     #[from(ignore)]
     RefererNotAllowed(headers::Referer),
+    /// the request classifier script rejected the request. the reason is shown to the user
+    #[display(fmt = "{}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    RequestRejected(String),
+    #[display(fmt = "{} in {}", rpc_key_id, rpc_key_pool_id)]
+    #[from(ignore)]
+    RpcKeyInOtherPool {
+        rpc_key_id: u64,
+        rpc_key_pool_id: u64,
+    },
+    RpcKeyNotFound,
     SemaphoreAcquireError(AcquireError),
     SendAppStatError(flume::SendError<crate::stats::AppStat>),
     SerdeJson(serde_json::Error),
+    /// influx took longer than `stats_query_timeout_seconds`
+    StatsQueryTimeout,
+    /// the stats query covers more time than the user's tier allows
+    #[display(fmt = "{}/{}", range_seconds, max_range_seconds)]
+    #[from(ignore)]
+    StatsRangeTooLarge {
+        range_seconds: u64,
+        max_range_seconds: u64,
+    },
+    StatsRequireBearer,
+    /// the stats query would return more than `stats_max_buckets` buckets
+    #[display(fmt = "{}/{}", num_buckets, max_buckets)]
+    #[from(ignore)]
+    StatsTooManyBuckets {
+        num_buckets: u64,
+        max_buckets: u64,
+        min_window_seconds: u64,
+    },
     /// simple way to return an error message to the user and an anyhow to our logs
     #[display(fmt = "{}, {}, {:?}", _0, _1, _2)]
     StatusCode(StatusCode, String, Option<anyhow::Error>),
@@ -125,6 +259,16 @@ pub enum Web3ProxyError {
     #[display(fmt = "{:?}", _0)]
     #[error(ignore)]
     Timeout(Option<tokio::time::error::Elapsed>),
+    /// the transaction matched the sanctions screening and `screening.mode` is "block"
+    #[display(fmt = "{:?}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    TransactionScreened(ethers::types::TxHash),
+    /// the socket already has `max_subscriptions_per_socket` subscriptions
+    #[display(fmt = "{}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    TooManySubscriptions(usize),
     UlidDecode(ulid::DecodeError),
     UnknownBlockNumber,
     UnknownKey,
@@ -132,10 +276,16 @@ pub enum Web3ProxyError {
     #[error(ignore)]
     UserAgentNotAllowed(headers::UserAgent),
     UserIdZero,
-    PaymentRequired,
+    UserNotFound,
+    UserTierNotFound,
+    #[display(fmt = "{:?}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    PaymentRequired(BackoffHint),
     VerificationError(siwe::VerificationError),
     WatchRecvError(tokio::sync::watch::error::RecvError),
     WatchSendError,
+    SubuserIsSelf,
     WebsocketOnly,
     #[display(fmt = "{:?}, {}", _0, _1)]
     #[error(ignore)]
@@ -143,9 +293,125 @@ pub enum Web3ProxyError {
 }
 
 impl Web3ProxyError {
+    /// A stable, machine-readable code for this error. Clients should branch on this instead of the message.
+    /// These are part of the public api! Don't change existing codes.
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            Self::AccessDenied => "auth.access_denied",
+            Self::ExpiredBearerToken => "auth.expired_bearer",
+            Self::InvalidBearerToken => "auth.invalid_bearer",
+            Self::InvalidEip => "auth.invalid_eip",
+            Self::InvalidInviteCode => "auth.invalid_invite_code",
+            Self::InvalidRefreshToken => "auth.invalid_refresh_token",
+            Self::ParseMsgError(..) => "auth.invalid_message",
+            Self::InvalidReferer => "auth.invalid_referer",
+            Self::InvalidUserKey => "auth.invalid_rpc_key",
+            Self::EipVerificationFailed(..)
+            | Self::InvalidSignatureLength
+            | Self::VerificationError(..) => "auth.invalid_signature",
+            Self::InvalidUserAgent => "auth.invalid_user_agent",
+            Self::IpNotAllowed(..) => "auth.ip_not_allowed",
+            Self::OriginNotAllowed(..) => "auth.origin_not_allowed",
+            Self::OriginRequired => "auth.origin_required",
+            Self::RefererNotAllowed(..) => "auth.referer_not_allowed",
+            Self::RefererRequired => "auth.referer_required",
+            Self::UnknownReferralCode => "auth.unknown_referral_code",
+            Self::InvalidPresignedUrl(..) => "auth.invalid_presigned_url",
+            Self::MethodNotAllowed(..) => "auth.method_not_allowed",
+            Self::UnknownKey => "auth.unknown_rpc_key",
+            Self::UserAgentNotAllowed(..) => "auth.user_agent_not_allowed",
+            Self::UserAgentRequired => "auth.user_agent_required",
+            Self::PaymentAlreadyCredited => "payment.already_credited",
+            Self::PaymentNotFound => "payment.not_found",
+            Self::BalanceExhausted { .. } => "payment.balance_exhausted",
+            Self::PaymentRequired(..) => "payment.required",
+            Self::RateLimited(..) => "rate_limit.exceeded",
+            Self::RateLimitQueueFull(..) => "rate_limit.queue_full",
+            Self::MethodRateLimited(..) => "rate_limit.method_exceeded",
+            Self::BandwidthExceeded { .. } => "rate_limit.bandwidth_exceeded",
+            Self::ParseAddressError => "request.invalid_address",
+            Self::ParseBytesError(..) => "request.invalid_bytes",
+            Self::Headers(..) | Self::HeaderToString(..) | Self::InvalidHeaderValue(..) => {
+                "request.invalid_header"
+            }
+            Self::IpAddrParse(..) => "request.invalid_ip",
+            Self::IdempotencyKeyInProgress => "request.idempotency_key_in_progress",
+            Self::IdempotencyKeyReused => "request.idempotency_key_reused",
+            Self::SerdeJson(..) => "request.invalid_json",
+            Self::InvalidParam(..) => "request.invalid_param",
+            Self::UlidDecode(..) => "request.invalid_ulid",
+            Self::MissingParam(..) => "request.missing_param",
+            Self::NotFound => "request.not_found",
+            Self::NotImplemented => "request.not_implemented",
+            Self::Timeout(..) => "request.timeout",
+            Self::WebsocketOnly => "request.websocket_only",
+            Self::InvalidBlockBounds { .. } => "rpc.invalid_block_bounds",
+            Self::InvalidRpcParams(..) => "rpc.invalid_params",
+            Self::ReadOnly(..) => "rpc.read_only",
+            Self::RequestRejected(..) => "rpc.request_rejected",
+            Self::TransactionScreened(..) => "rpc.transaction_screened",
+            Self::TooManySubscriptions(..) => "rpc.too_many_subscriptions",
+            Self::BatchTooLarge { .. } => "rpc.batch_too_large",
+            Self::ResponseTooLarge { .. } => "rpc.response_too_large",
+            Self::RpcKeyNotFound => "rpc_key.not_found",
+            Self::ChainNotAllowed(..) => "rpc_key.chain_not_allowed",
+            Self::NotRpcKeyOwner => "rpc_key.not_owner",
+            Self::RpcKeyInOtherPool { .. } => "rpc_key_pool.conflict",
+            Self::BadRouting => "server.bad_routing",
+            Self::Database(..) => "server.database",
+            Self::InfluxDb2Request(..) => "server.influxdb",
+            Self::Anyhow(..)
+            | Self::FlumeRecv(..)
+            | Self::Hyper(..)
+            | Self::Io(..)
+            | Self::JoinError(..)
+            | Self::MsgPackEncode(..)
+            | Self::NoBlockNumberOrHash
+            | Self::SemaphoreAcquireError(..)
+            | Self::SendAppStatError(..)
+            | Self::WatchRecvError(..)
+            | Self::WatchSendError => "server.internal",
+            Self::Redis(..) => "server.redis",
+            Self::StatsRequireBearer => "stats.bearer_required",
+            Self::StatsQueryTimeout => "stats.query_timeout",
+            Self::StatsRangeTooLarge { .. } => "stats.range_too_large",
+            Self::StatsTooManyBuckets { .. } => "stats.too_many_buckets",
+            Self::QueryRangeEmpty => "stats.invalid_range",
+            Self::InvalidQueryWindow => "stats.invalid_window",
+            Self::NoRpcKeys => "stats.no_rpc_keys",
+            Self::SubuserIsSelf => "subuser.self",
+            Self::BadResponse(..) | Self::GasEstimateNotU256 => "upstream.bad_response",
+            Self::EthersHttpClient(..) => "upstream.http_client",
+            Self::NoBlocksKnown => "upstream.no_blocks",
+            Self::NoConsensusHeadBlock => "upstream.no_consensus",
+            Self::NoServersSynced => "upstream.no_servers_synced",
+            Self::PoolSaturated(..) => "upstream.saturated",
+            Self::NotEnoughRpcs { .. } => "upstream.not_enough_rpcs",
+            Self::NotEnoughSoftLimit { .. } => "upstream.not_enough_soft_limit",
+            Self::EthersProvider(..) => "upstream.provider",
+            Self::NoHandleReady => "upstream.unavailable",
+            Self::UnknownBlockNumber => "upstream.unknown_block_number",
+            Self::EthersWsClient(..) => "upstream.ws_client",
+            Self::UserIdZero => "user.invalid_id",
+            Self::UserNotFound => "user.not_found",
+            Self::UserTierNotFound => "user_tier.not_found",
+            Self::StatusCode(status_code, ..) => {
+                if status_code.is_client_error() {
+                    "request.rejected"
+                } else {
+                    "server.internal"
+                }
+            }
+            Self::WithContext(Some(err), _) => err.error_code(),
+            Self::WithContext(None, _) => "server.internal",
+        }
+    }
+
     pub fn into_response_parts(self) -> (StatusCode, JsonRpcResponseData) {
+        let error_code = self.error_code();
+
         // TODO: include a unique request id in the data
-        let (code, err): (StatusCode, JsonRpcErrorData) = match self {
+        let (code, mut err): (StatusCode, JsonRpcErrorData) = match self {
             Self::AccessDenied => {
                 // TODO: attach something to this trace. probably don't include much in the message though. don't want to leak creds by accident
                 trace!("access denied");
@@ -170,17 +436,6 @@ impl Web3ProxyError {
                     },
                 )
             }
-            Self::BadRequest(err) => {
-                debug!("BAD_REQUEST: {}", err);
-                (
-                    StatusCode::BAD_REQUEST,
-                    JsonRpcErrorData {
-                        message: Cow::Owned(format!("bad request: {}", err)),
-                        code: StatusCode::BAD_REQUEST.as_u16().into(),
-                        data: None,
-                    },
-                )
-            }
             Self::BadResponse(err) => {
                 // TODO: think about this one more. ankr gives us this because ethers fails to parse responses without an id
                 debug!("BAD_RESPONSE: {}", err);
@@ -204,6 +459,85 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::BalanceExhausted {
+                balance,
+                grace,
+                hint,
+            } => {
+                trace!("BalanceExhausted {} grace={}", balance, grace);
+
+                let mut data = backoff_data(None, hint);
+
+                if let Some(x) = data.as_object_mut() {
+                    x.insert("balance".to_string(), balance.to_string().into());
+                    x.insert("grace".to_string(), grace.to_string().into());
+                }
+
+                (
+                    StatusCode::PAYMENT_REQUIRED,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed(
+                            "balance exhausted. add credits to keep using this key",
+                        ),
+                        code: StatusCode::PAYMENT_REQUIRED.as_u16().into(),
+                        data: Some(data),
+                    },
+                )
+            }
+            Self::BandwidthExceeded {
+                used_bytes,
+                max_bytes,
+                resets_at,
+            } => {
+                trace!("BandwidthExceeded {}/{}", used_bytes, max_bytes);
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!(
+                            "monthly bandwidth exceeded. {} of {} bytes used",
+                            used_bytes, max_bytes
+                        )),
+                        code: StatusCode::TOO_MANY_REQUESTS.as_u16().into(),
+                        data: Some(json!({
+                            "used_bytes": used_bytes,
+                            "max_bytes": max_bytes,
+                            "resets_at": resets_at,
+                        })),
+                    },
+                )
+            }
+            Self::BatchTooLarge {
+                num_requests,
+                max_batch_size,
+            } => {
+                trace!("BatchTooLarge {}/{}", num_requests, max_batch_size);
+                (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!(
+                            "batch of {} requests is too large. your tier allows {} requests per batch",
+                            num_requests, max_batch_size
+                        )),
+                        // the json-rpc spec's code for an invalid request. the whole batch is rejected with this one error
+                        code: -32600,
+                        data: None,
+                    },
+                )
+            }
+            Self::ChainNotAllowed(chain_id) => {
+                trace!("ChainNotAllowed {}", chain_id);
+                (
+                    StatusCode::FORBIDDEN,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!(
+                            "this key is not allowed on chain {}. add it to the key's allowed_chains",
+                            chain_id
+                        )),
+                        code: StatusCode::FORBIDDEN.as_u16().into(),
+                        data: Some(json!({ "chain_id": chain_id })),
+                    },
+                )
+            }
             Self::Database(err) => {
                 error!("database err={:?}", err);
                 (
@@ -265,6 +599,17 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::ExpiredBearerToken => {
+                debug!("ExpiredBearerToken");
+                (
+                    StatusCode::UNAUTHORIZED,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed("bearer token has expired. log in again"),
+                        code: StatusCode::UNAUTHORIZED.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::FlumeRecv(err) => {
                 warn!("FlumeRecvError err={:#?}", err);
                 (
@@ -311,6 +656,32 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::IdempotencyKeyInProgress => {
+                trace!("IdempotencyKeyInProgress");
+                (
+                    StatusCode::CONFLICT,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed(
+                            "a request with this Idempotency-Key is still running. retry after it finishes",
+                        ),
+                        code: StatusCode::CONFLICT.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::IdempotencyKeyReused => {
+                trace!("IdempotencyKeyReused");
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed(
+                            "this Idempotency-Key was already used for a different request",
+                        ),
+                        code: StatusCode::UNPROCESSABLE_ENTITY.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::InfluxDb2Request(err) => {
                 // TODO: attach a request id to the message and to this error so that if people report problems, we can dig in sentry to find out more
                 error!("influxdb2 err={:?}", err);
@@ -359,6 +730,28 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::InvalidBearerToken => {
+                debug!("InvalidBearerToken");
+                (
+                    StatusCode::UNAUTHORIZED,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed("unknown bearer token"),
+                        code: StatusCode::UNAUTHORIZED.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::InvalidRefreshToken => {
+                debug!("InvalidRefreshToken");
+                (
+                    StatusCode::UNAUTHORIZED,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed("unknown or expired refresh token"),
+                        code: StatusCode::UNAUTHORIZED.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::InvalidHeaderValue(err) => {
                 debug!("InvalidHeaderValue err={:?}", err);
                 (
@@ -392,6 +785,50 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::InvalidParam(name, reason) => {
+                debug!("InvalidParam {}: {}", name, reason);
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!("invalid {}: {}", name, reason)),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::InvalidPresignedUrl(reason) => {
+                trace!("InvalidPresignedUrl {}", reason);
+                (
+                    StatusCode::UNAUTHORIZED,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!("invalid presigned url: {}", reason)),
+                        code: StatusCode::UNAUTHORIZED.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::InvalidQueryWindow => {
+                debug!("InvalidQueryWindow");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed("query_window_seconds must be greater than 0"),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::InvalidRpcParams(reason) => {
+                debug!("InvalidRpcParams: {}", reason);
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!("invalid params: {}", reason)),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::Io(err) => {
                 warn!("std io err={:?}", err);
                 (
@@ -478,6 +915,52 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::MethodNotAllowed(method) => {
+                trace!("MethodNotAllowed {}", method);
+                (
+                    StatusCode::FORBIDDEN,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!(
+                            "{} is not allowed with this presigned url",
+                            method
+                        )),
+                        code: StatusCode::FORBIDDEN.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::MethodRateLimited(method, retry_at, hint) => {
+                trace!("MethodRateLimited {} {:?}", method, retry_at);
+
+                // method limits are per second, so whole seconds would usually round to 0
+                let retry_msg = if let Some(retry_at) = retry_at {
+                    let retry_in = retry_at.duration_since(Instant::now()).as_millis();
+
+                    format!(" Retry in {} milliseconds", retry_in)
+                } else {
+                    "".to_string()
+                };
+
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!("too many {} requests.{}", method, retry_msg)),
+                        code: StatusCode::TOO_MANY_REQUESTS.as_u16().into(),
+                        data: Some(backoff_data(retry_at, hint)),
+                    },
+                )
+            }
+            Self::MissingParam(name) => {
+                debug!("MissingParam {}", name);
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!("missing {}", name)),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::MsgPackEncode(err) => {
                 warn!("MsgPackEncode Error: {}", err);
                 (
@@ -533,6 +1016,17 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::NoRpcKeys => {
+                debug!("NoRpcKeys");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed("user has no rpc keys yet"),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::NoServersSynced => {
                 warn!("NoServersSynced");
                 (
@@ -598,6 +1092,17 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::NotRpcKeyOwner => {
+                debug!("NotRpcKeyOwner");
+                (
+                    StatusCode::FORBIDDEN,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed("only the owner of the rpc key can do this"),
+                        code: StatusCode::FORBIDDEN.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::OriginRequired => {
                 trace!("OriginRequired");
                 (
@@ -653,25 +1158,75 @@ impl Web3ProxyError {
                     },
                 )
             }
-            Self::PaymentRequired => {
+            Self::PaymentAlreadyCredited => {
+                debug!("PaymentAlreadyCredited");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed("this transaction has already been credited"),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::PaymentNotFound => {
+                debug!("PaymentNotFound");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed(
+                            "no such transaction was found, or the token is not supported",
+                        ),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::PaymentRequired(hint) => {
                 trace!("PaymentRequiredError");
                 (
                     StatusCode::PAYMENT_REQUIRED,
                     JsonRpcErrorData {
                         message: Cow::Borrowed("Payment is required and user is not premium"),
                         code: StatusCode::PAYMENT_REQUIRED.as_u16().into(),
+                        data: Some(backoff_data(None, hint)),
+                    },
+                )
+            }
+            Self::QueryRangeEmpty => {
+                debug!("QueryRangeEmpty");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed("query_start and query_stop cannot be equal"),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::ReadOnly(reason) => {
+                trace!("ReadOnly {}", reason);
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!(
+                            "transactions are paused. reads still work. reason: {}",
+                            reason
+                        )),
+                        code: StatusCode::SERVICE_UNAVAILABLE.as_u16().into(),
                         data: None,
                     },
                 )
             }
             // TODO: this should actually by the id of the key. multiple users might control one key
-            Self::RateLimited(authorization, retry_at) => {
+            Self::RateLimited(authorization, retry_at, hint) => {
                 // TODO: emit a stat
 
+                // token buckets usually refill in less than a second
                 let retry_msg = if let Some(retry_at) = retry_at {
-                    let retry_in = retry_at.duration_since(Instant::now()).as_secs();
+                    let retry_in = retry_at.duration_since(Instant::now()).as_millis();
 
-                    format!(" Retry in {} seconds", retry_in)
+                    format!(" Retry in {} milliseconds", retry_in)
                 } else {
                     "".to_string()
                 };
@@ -692,6 +1247,39 @@ impl Web3ProxyError {
                     JsonRpcErrorData {
                         message: Cow::Owned(msg),
                         code: StatusCode::TOO_MANY_REQUESTS.as_u16().into(),
+                        data: Some(backoff_data(retry_at, hint)),
+                    },
+                )
+            }
+            Self::RateLimitQueueFull(rpc_key_id, max_depth) => {
+                trace!("RateLimitQueueFull {} {}", rpc_key_id, max_depth);
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!(
+                            "too many requests from rpc key #{}. {} requests are already queued. wait for some of them to finish",
+                            rpc_key_id, max_depth
+                        )),
+                        code: StatusCode::TOO_MANY_REQUESTS.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::ResponseTooLarge {
+                method,
+                num_bytes,
+                max_bytes,
+            } => {
+                // TODO: emit a stat
+                trace!("ResponseTooLarge {} {}/{}", method, num_bytes, max_bytes);
+                (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!(
+                            "response for {} is too large ({} > {} bytes). split the request into smaller block ranges or paginate it",
+                            method, num_bytes, max_bytes
+                        )),
+                        code: StatusCode::PAYLOAD_TOO_LARGE.as_u16().into(),
                         data: None,
                     },
                 )
@@ -729,6 +1317,45 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::RequestRejected(reason) => {
+                trace!("RequestRejected {}", reason);
+                (
+                    StatusCode::FORBIDDEN,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!("request rejected: {}", reason)),
+                        code: StatusCode::FORBIDDEN.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::RpcKeyInOtherPool {
+                rpc_key_id,
+                rpc_key_pool_id,
+            } => {
+                debug!("RpcKeyInOtherPool {} {}", rpc_key_id, rpc_key_pool_id);
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!(
+                            "rpc key {} is already in key pool {}",
+                            rpc_key_id, rpc_key_pool_id
+                        )),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::RpcKeyNotFound => {
+                debug!("RpcKeyNotFound");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed("rpc key not found"),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::SemaphoreAcquireError(err) => {
                 warn!("semaphore acquire err={:?}", err);
                 (
@@ -763,6 +1390,76 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::StatsQueryTimeout => {
+                warn!("StatsQueryTimeout");
+                (
+                    StatusCode::GATEWAY_TIMEOUT,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed(
+                            "stats query timed out. try a shorter range or a larger query_window_seconds",
+                        ),
+                        code: StatusCode::GATEWAY_TIMEOUT.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::StatsRangeTooLarge {
+                range_seconds,
+                max_range_seconds,
+            } => {
+                debug!("StatsRangeTooLarge {}/{}", range_seconds, max_range_seconds);
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!(
+                            "stats range of {} seconds is too large. your tier allows {} seconds",
+                            range_seconds, max_range_seconds
+                        )),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::StatsTooManyBuckets {
+                num_buckets,
+                max_buckets,
+                min_window_seconds,
+            } => {
+                debug!("StatsTooManyBuckets {}/{}", num_buckets, max_buckets);
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!(
+                            "stats query would return {} buckets. the most is {}. use a query_window_seconds of at least {}",
+                            num_buckets, max_buckets, min_window_seconds
+                        )),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::StatsRequireBearer => {
+                debug!("StatsRequireBearer");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed("detailed stats require a bearer token"),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::SubuserIsSelf => {
+                debug!("SubuserIsSelf");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed("you cannot make a subuser out of yourself"),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::StatusCode(status_code, err_msg, err) => {
                 // different status codes should get different error levels. 500s should warn. 400s should stat
                 let code = status_code.as_u16();
@@ -781,6 +1478,17 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::PoolSaturated(name) => {
+                warn!("PoolSaturated {}", name);
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!("{} pool is saturated. try again later", name)),
+                        code: StatusCode::SERVICE_UNAVAILABLE.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::Timeout(x) => (
                 StatusCode::REQUEST_TIMEOUT,
                 JsonRpcErrorData {
@@ -790,6 +1498,34 @@ impl Web3ProxyError {
                     data: None,
                 },
             ),
+            Self::TransactionScreened(tx_hash) => {
+                trace!("TransactionScreened {:?}", tx_hash);
+                // don't say which address matched
+                (
+                    StatusCode::FORBIDDEN,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed(
+                            "transaction rejected by this provider's compliance screening",
+                        ),
+                        code: StatusCode::FORBIDDEN.as_u16().into(),
+                        data: Some(json!({ "tx_hash": tx_hash })),
+                    },
+                )
+            }
+            Self::TooManySubscriptions(max) => {
+                trace!("TooManySubscriptions {}", max);
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    JsonRpcErrorData {
+                        message: Cow::Owned(format!(
+                            "too many subscriptions on this socket. the max is {}",
+                            max
+                        )),
+                        code: StatusCode::TOO_MANY_REQUESTS.as_u16().into(),
+                        data: Some(json!({ "max_subscriptions": max })),
+                    },
+                )
+            }
             Self::HeaderToString(err) => {
                 // trace!(?err, "HeaderToString");
                 (
@@ -866,6 +1602,28 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::UserNotFound => {
+                debug!("UserNotFound");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed("user not found"),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
+            Self::UserTierNotFound => {
+                debug!("UserTierNotFound");
+                (
+                    StatusCode::BAD_REQUEST,
+                    JsonRpcErrorData {
+                        message: Cow::Borrowed("user tier not found"),
+                        code: StatusCode::BAD_REQUEST.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::VerificationError(err) => {
                 trace!("VerificationError err={:?}", err);
                 (
@@ -931,10 +1689,66 @@ impl Web3ProxyError {
             },
         };
 
+        // the http status is for the transport. the body gets the json-rpc code for the same problem
+        if (100..600).contains(&err.code) {
+            err.code = jsonrpc_code(code, error_code);
+        }
+
+        // every error body includes the machine-readable code
+        match err.data.as_mut().and_then(|x| x.as_object_mut()) {
+            Some(data) => {
+                data.insert("error_code".to_string(), error_code.into());
+            }
+            None => err.data = Some(json!({ "error_code": error_code })),
+        }
+
         (code, JsonRpcResponseData::from(err))
     }
 }
 
+/// The json-rpc code for an error. The spec's own codes are used when they fit, then the server error codes from EIP-1474
+fn jsonrpc_code(status_code: StatusCode, error_code: &str) -> i64 {
+    match error_code {
+        "request.invalid_json" => -32700,
+        "request.not_implemented" => -32601,
+        "request.invalid_address"
+        | "request.invalid_bytes"
+        | "request.invalid_param"
+        | "request.missing_param"
+        | "rpc.invalid_block_bounds"
+        | "rpc.invalid_params" => -32602,
+        "rpc.read_only" | "rpc.transaction_screened" => -32003,
+        "auth.method_not_allowed" => -32004,
+        // a request limit resets within seconds. a bandwidth quota resets next month
+        "rate_limit.bandwidth_exceeded" => -32006,
+        _ => match status_code {
+            StatusCode::NOT_FOUND => -32001,
+            StatusCode::PAYMENT_REQUIRED
+            | StatusCode::PAYLOAD_TOO_LARGE
+            | StatusCode::TOO_MANY_REQUESTS => -32005,
+            StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT => -32002,
+            x if x.is_client_error() => -32600,
+            _ => -32603,
+        },
+    }
+}
+
+/// The `data` for errors that clients should back off from. `retry_after_ms` is null if retrying won't help
+fn backoff_data(retry_at: Option<Instant>, hint: BackoffHint) -> serde_json::Value {
+    let retry_after_ms =
+        retry_at.map(|x| x.saturating_duration_since(Instant::now()).as_millis() as u64);
+
+    json!({
+        "retry_after_ms": retry_after_ms,
+        "limit": hint.limit,
+        "window": hint.window,
+        "burst": hint.burst,
+        "upgrade_url": hint.upgrade_url,
+    })
+}
+
 impl From<ethers::types::ParseBytesError> for Web3ProxyError {
     fn from(err: ethers::types::ParseBytesError) -> Self {
         Self::ParseBytesError(Some(err))
@@ -949,16 +1763,94 @@ impl From<tokio::time::error::Elapsed> for Web3ProxyError {
 
 impl IntoResponse for Web3ProxyError {
     fn into_response(self) -> Response {
+        let retry_after = self.retry_after();
+
         // TODO: include the request id in these so that users can give us something that will point to logs
         // TODO: status code is in the jsonrpc response and is also the first item in the tuple. DRY
         let (status_code, response_data) = self.into_response_parts();
 
         // this will be missing the jsonrpc id!
-        // its better to get request id and call from_response_data with it then to use this IntoResponse helper.
+        // its better to use `into_response_with_ids` (or get the request id and call from_response_data with it) than this IntoResponse helper.
         let response =
             JsonRpcForwardedResponse::from_response_data(response_data, Default::default());
 
-        (status_code, Json(response)).into_response()
+        with_retry_after((status_code, Json(response)).into_response(), retry_after)
+    }
+}
+
+/// Set the "Retry-After" header if the error has one
+fn with_retry_after(mut response: Response, retry_after: Option<HeaderValue>) -> Response {
+    if let Some(x) = retry_after {
+        response.headers_mut().insert(RETRY_AFTER, x);
+    }
+
+    response
+}
+
+/// The ids of a payload's requests. Kept so that an error can still answer each request after the payload is gone
+pub enum RequestIds {
+    Single(Box<RawValue>),
+    Batch(Vec<Box<RawValue>>),
+}
+
+impl From<&JsonRpcRequestEnum> for RequestIds {
+    fn from(payload: &JsonRpcRequestEnum) -> Self {
+        match payload {
+            JsonRpcRequestEnum::Single(x) => Self::Single(x.id.clone()),
+            JsonRpcRequestEnum::Batch(x) => Self::Batch(x.iter().map(|x| x.id.clone()).collect()),
+        }
+    }
+}
+
+impl Web3ProxyError {
+    /// Whole seconds until a rate limited request can be retried, rounded up for the "Retry-After" header.
+    /// None if the error isn't a rate limit or if retrying won't help
+    pub fn retry_after(&self) -> Option<HeaderValue> {
+        let retry_at = match self {
+            Self::RateLimited(_, retry_at, _) | Self::MethodRateLimited(_, retry_at, _) => {
+                (*retry_at)?
+            }
+            _ => return None,
+        };
+
+        let retry_in = retry_at.saturating_duration_since(Instant::now());
+
+        let seconds = retry_in.as_secs() + u64::from(retry_in.subsec_nanos() > 0);
+
+        Some(HeaderValue::from(seconds))
+    }
+
+    /// Like `into_response`, but the body has the ids of the requests. Every request in a batch gets the error
+    pub fn into_response_with_ids(self, ids: RequestIds) -> Response {
+        let retry_after = self.retry_after();
+
+        let (status_code, response_data) = self.into_response_parts();
+
+        let response = match ids {
+            RequestIds::Single(id) => {
+                let response = JsonRpcForwardedResponse::from_response_data(response_data, id);
+
+                (status_code, Json(response)).into_response()
+            }
+            RequestIds::Batch(ids) if ids.is_empty() => {
+                let response =
+                    JsonRpcForwardedResponse::from_response_data(response_data, Default::default());
+
+                (status_code, Json(response)).into_response()
+            }
+            RequestIds::Batch(ids) => {
+                let responses: Vec<_> = ids
+                    .into_iter()
+                    .map(|id| {
+                        JsonRpcForwardedResponse::from_response_data(response_data.clone(), id)
+                    })
+                    .collect();
+
+                (status_code, Json(responses)).into_response()
+            }
+        };
+
+        with_retry_after(response, retry_after)
     }
 }
 
@@ -984,3 +1876,123 @@ where
         self.map_err(|err| Web3ProxyError::WithContext(Some(Box::new(err.into())), msg.into()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_error_codes_in_body() {
+        let (status_code, response_data) = Web3ProxyError::InvalidQueryWindow.into_response_parts();
+
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+
+        match response_data {
+            JsonRpcResponseData::Error { value, .. } => {
+                assert_eq!(
+                    value.data,
+                    Some(json!({ "error_code": "stats.invalid_window" }))
+                );
+            }
+            JsonRpcResponseData::Result { .. } => panic!("errors should not be results"),
+        }
+
+        // context does not hide the code of the error it wraps
+        let err = Web3ProxyError::WithContext(
+            Some(Box::new(Web3ProxyError::ExpiredBearerToken)),
+            "checking bearer".to_string(),
+        );
+        assert_eq!(err.error_code(), "auth.expired_bearer");
+    }
+
+    #[test]
+    fn test_jsonrpc_codes() {
+        let code = |err: Web3ProxyError| match err.into_response_parts().1 {
+            JsonRpcResponseData::Error { value, .. } => value.code,
+            JsonRpcResponseData::Result { .. } => panic!("errors should not be results"),
+        };
+
+        assert_eq!(code(Web3ProxyError::InvalidQueryWindow), -32600);
+        assert_eq!(
+            code(Web3ProxyError::InvalidParam("foo", "bar".into())),
+            -32602
+        );
+        assert_eq!(code(Web3ProxyError::NotFound), -32001);
+        assert_eq!(
+            code(Web3ProxyError::PaymentRequired(BackoffHint::default())),
+            -32005
+        );
+        assert_eq!(
+            code(Web3ProxyError::BalanceExhausted {
+                balance: Decimal::ZERO,
+                grace: Decimal::ZERO,
+                hint: BackoffHint::default(),
+            }),
+            -32005
+        );
+        assert_eq!(code(Web3ProxyError::NoServersSynced), -32002);
+        assert_eq!(
+            code(Web3ProxyError::BandwidthExceeded {
+                used_bytes: 2,
+                max_bytes: 1,
+                resets_at: 0,
+            }),
+            -32006
+        );
+        assert_eq!(
+            code(Web3ProxyError::Anyhow(anyhow::anyhow!("oops"))),
+            -32603
+        );
+    }
+
+    #[test]
+    fn test_backoff_hints_in_body() {
+        let hint = BackoffHint {
+            limit: Some(10),
+            window: Some(1),
+            upgrade_url: Some("https://example.com/upgrade".to_string()),
+            ..Default::default()
+        };
+
+        let err = Web3ProxyError::MethodRateLimited(
+            "eth_getLogs".to_string(),
+            Some(Instant::now() + Duration::from_millis(500)),
+            hint,
+        );
+
+        // 500ms rounds up to a whole second
+        assert_eq!(err.retry_after(), Some(HeaderValue::from(1u64)));
+
+        let (status_code, response_data) = err.into_response_parts();
+
+        assert_eq!(status_code, StatusCode::TOO_MANY_REQUESTS);
+
+        let data = match response_data {
+            JsonRpcResponseData::Error { value, .. } => value.data.unwrap(),
+            JsonRpcResponseData::Result { .. } => panic!("errors should not be results"),
+        };
+
+        assert_eq!(data["error_code"], "rate_limit.method_exceeded");
+        assert_eq!(data["limit"], 10);
+        assert_eq!(data["window"], 1);
+        assert_eq!(data["upgrade_url"], "https://example.com/upgrade");
+
+        let retry_after_ms = data["retry_after_ms"].as_u64().unwrap();
+        assert!(retry_after_ms <= 500);
+
+        // retrying won't help
+        let (_, response_data) =
+            Web3ProxyError::PaymentRequired(BackoffHint::default()).into_response_parts();
+
+        match response_data {
+            JsonRpcResponseData::Error { value, .. } => {
+                let data = value.data.unwrap();
+
+                assert_eq!(data["error_code"], "payment.required");
+                assert!(data["retry_after_ms"].is_null());
+            }
+            JsonRpcResponseData::Result { .. } => panic!("errors should not be results"),
+        }
+    }
+}
@@ -0,0 +1,529 @@
+//! `POST /graphql` -- A typed schema for the user's dashboard data.
+//!
+//! The REST endpoints each take their own bag of query params and return loosely structured JSON.
+//! This reads the same data (stats, balance, keys, and referrals) through one schema, so dashboards can ask for exactly the fields they need.
+//! Every query is for the user that the bearer token belongs to.
+use super::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
+use crate::app::Web3ProxyApp;
+use crate::http_params::StatsParams;
+use crate::stats::influxdb_queries::{query_user_stats_json, StatsCursor};
+use crate::stats::schema::{StatDimension, StatValueKind, STAT_GROUPS};
+use crate::stats::StatType;
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, ErrorExtensions, Object, Schema, SimpleObject,
+};
+use axum::{
+    headers::{authorization::Bearer, Authorization},
+    Extension, Json, TypedHeader,
+};
+use axum_macros::debug_handler;
+use chrono::{NaiveDateTime, Utc};
+use entities::{balance, referee, referrer, rpc_key, user};
+use ethers::types::Address;
+use migration::sea_orm::{ColumnTrait, DbErr, EntityTrait, QueryFilter};
+use serde::Deserialize;
+use std::sync::Arc;
+use ulid::Ulid;
+
+pub type UserSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+/// the bearer header of the request. resolvers check it themselves
+type BearerHeader = Option<TypedHeader<Authorization<Bearer>>>;
+
+/// Build the schema once when the frontend starts
+pub fn schema() -> UserSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        // dashboards only need a few levels. this keeps a single query from loading everything
+        .limit_depth(6)
+        .limit_complexity(500)
+        .finish()
+}
+
+#[debug_handler]
+pub async fn graphql_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    Extension(schema): Extension<UserSchema>,
+    bearer: BearerHeader,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    let request = request.data(app).data(bearer);
+
+    Json(schema.execute(request).await)
+}
+
+/// GraphQL errors have the same `error_code` as the REST endpoints
+fn graphql_error(err: Web3ProxyError) -> async_graphql::Error {
+    let error_code = err.error_code();
+
+    async_graphql::Error::new(err.to_string()).extend_with(|_, e| e.set("error_code", error_code))
+}
+
+fn db_error(err: DbErr) -> async_graphql::Error {
+    graphql_error(err.into())
+}
+
+fn app<'a>(ctx: &Context<'a>) -> async_graphql::Result<&'a Arc<Web3ProxyApp>> {
+    ctx.data::<Arc<Web3ProxyApp>>()
+}
+
+fn bearer(ctx: &Context<'_>) -> async_graphql::Result<TypedHeader<Authorization<Bearer>>> {
+    ctx.data::<BearerHeader>()?
+        .clone()
+        .ok_or_else(|| graphql_error(Web3ProxyError::AccessDenied))
+}
+
+pub struct QueryRoot;
+
+#[Object(rename_fields = "snake_case", rename_args = "snake_case")]
+impl QueryRoot {
+    /// The user that the bearer token belongs to
+    async fn me(&self, ctx: &Context<'_>) -> async_graphql::Result<User> {
+        let TypedHeader(Authorization(bearer)) = bearer(ctx)?;
+
+        let (user, _semaphore) = app(ctx)?
+            .bearer_is_authorized(bearer)
+            .await
+            .map_err(graphql_error)?;
+
+        Ok(User(user))
+    }
+}
+
+pub struct User(user::Model);
+
+#[Object(rename_fields = "snake_case", rename_args = "snake_case")]
+impl User {
+    async fn id(&self) -> u64 {
+        self.0.id
+    }
+
+    async fn address(&self) -> String {
+        format!("{:?}", Address::from_slice(&self.0.address))
+    }
+
+    async fn description(&self) -> Option<&str> {
+        self.0.description.as_deref()
+    }
+
+    async fn user_tier_id(&self) -> u64 {
+        self.0.user_tier_id
+    }
+
+    async fn balance(&self, ctx: &Context<'_>) -> async_graphql::Result<Balance> {
+        let db_replica = app(ctx)?
+            .db_replica()
+            .web3_context("graphql balance needs a db replica")
+            .map_err(graphql_error)?;
+
+        let balance = balance::Entity::find()
+            .filter(balance::Column::UserId.eq(self.0.id))
+            .one(db_replica.conn())
+            .await
+            .map_err(db_error)?;
+
+        // a user without a balance row has not deposited yet
+        Ok(balance
+            .map(|x| Balance {
+                available_balance: x.available_balance.to_string(),
+                used_balance: x.used_balance.to_string(),
+            })
+            .unwrap_or_else(|| Balance {
+                available_balance: "0".to_string(),
+                used_balance: "0".to_string(),
+            }))
+    }
+
+    async fn rpc_keys(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<RpcKey>> {
+        let db_replica = app(ctx)?
+            .db_replica()
+            .web3_context("graphql rpc_keys needs a db replica")
+            .map_err(graphql_error)?;
+
+        let keys = rpc_key::Entity::find()
+            .filter(rpc_key::Column::UserId.eq(self.0.id))
+            .all(db_replica.conn())
+            .await
+            .map_err(db_error)?;
+
+        Ok(keys.into_iter().map(RpcKey::from).collect())
+    }
+
+    async fn referral(&self, ctx: &Context<'_>) -> async_graphql::Result<Referral> {
+        let db_replica = app(ctx)?
+            .db_replica()
+            .web3_context("graphql referral needs a db replica")
+            .map_err(graphql_error)?;
+
+        // unlike `GET /user/referral`, reading never creates a referral code
+        let referrer = referrer::Entity::find()
+            .filter(referrer::Column::UserId.eq(self.0.id))
+            .one(db_replica.conn())
+            .await
+            .map_err(db_error)?;
+
+        let referees = match referrer.as_ref() {
+            None => vec![],
+            Some(referrer) => referee::Entity::find()
+                .filter(referee::Column::UsedReferralCode.eq(referrer.id))
+                .all(db_replica.conn())
+                .await
+                .map_err(db_error)?
+                .into_iter()
+                .map(|x| Referee {
+                    referral_start_date: x.referral_start_date.timestamp(),
+                    credits_applied_for_referee: x.credits_applied_for_referee,
+                    credits_applied_for_referrer: x.credits_applied_for_referrer.to_string(),
+                })
+                .collect(),
+        };
+
+        Ok(Referral {
+            referral_code: referrer.map(|x| x.referral_code),
+            referees,
+        })
+    }
+
+    /// The same stats as `/user/stats/aggregate` (or `/user/stats/detailed` if `detailed` is true)
+    #[allow(clippy::too_many_arguments)]
+    async fn stats(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "defaults to the proxy's chain. 0 is every chain")] chain_id: Option<u64>,
+        #[graphql(desc = "unix epoch seconds. defaults to 30 days ago")] query_start: Option<i64>,
+        #[graphql(desc = "unix epoch seconds. defaults to now")] query_stop: Option<i64>,
+        #[graphql(default = 60)] query_window_seconds: u64,
+        #[graphql(default = true)] align: bool,
+        org_id: Option<u64>,
+        #[graphql(desc = "names from `/user/stats/schema`. defaults to every group")]
+        group_by: Option<Vec<String>>,
+        #[graphql(desc = "the most items to return. defaults to every item")] limit: Option<u64>,
+        #[graphql(desc = "`next_cursor` from the previous page")] cursor: Option<String>,
+        #[graphql(default = false)] detailed: bool,
+    ) -> async_graphql::Result<Stats> {
+        let params = stats_params(
+            chain_id,
+            query_start,
+            query_stop,
+            query_window_seconds,
+            align,
+            org_id,
+            group_by,
+            limit,
+            cursor,
+        )
+        .map_err(graphql_error)?;
+
+        let stat_type = if detailed {
+            StatType::Detailed
+        } else {
+            StatType::Aggregated
+        };
+
+        let response = query_user_stats_json(app(ctx)?, Some(bearer(ctx)?), &params, stat_type)
+            .await
+            .map_err(graphql_error)?;
+
+        let response: StatsResponse = serde_json::from_value(response)?;
+
+        Ok(Stats {
+            query_start: response.query_start,
+            query_stop: response.query_stop,
+            query_window_seconds: response.query_window_seconds,
+            chain_id: response.chain_id,
+            items: response
+                .result
+                .into_iter()
+                .map(StatsItem::from_json)
+                .collect::<Result<_, _>>()?,
+            next_cursor: response.next_cursor,
+        })
+    }
+}
+
+/// The same params as `/user/stats/aggregate`
+#[allow(clippy::too_many_arguments)]
+fn stats_params(
+    chain_id: Option<u64>,
+    query_start: Option<i64>,
+    query_stop: Option<i64>,
+    query_window_seconds: u64,
+    align: bool,
+    org_id: Option<u64>,
+    group_by: Option<Vec<String>>,
+    limit: Option<u64>,
+    cursor: Option<String>,
+) -> Web3ProxyResult<StatsParams> {
+    let timestamp = |name: &'static str, x: i64| {
+        NaiveDateTime::from_timestamp_opt(x, 0)
+            .ok_or_else(|| Web3ProxyError::InvalidParam(name, "unix timestamp out of range".into()))
+    };
+
+    let query_start = match query_start {
+        Some(x) => timestamp("query_start", x)?,
+        None => (Utc::now() - chrono::Duration::days(30)).naive_utc(),
+    };
+
+    let query_stop = match query_stop {
+        Some(x) => timestamp("query_stop", x)?,
+        None => Utc::now().naive_utc(),
+    };
+
+    let group_by = group_by
+        .map(|names| {
+            names
+                .iter()
+                .map(|x| {
+                    StatDimension::from_name(x).ok_or_else(|| {
+                        Web3ProxyError::InvalidParam(
+                            "group_by",
+                            format!("unknown group {}", x).into(),
+                        )
+                    })
+                })
+                .collect::<Web3ProxyResult<Vec<_>>>()
+        })
+        .transpose()?;
+
+    let cursor = cursor
+        .map(|x| {
+            StatsCursor::decode(&x).ok_or_else(|| {
+                Web3ProxyError::InvalidParam("cursor", "not a cursor from next_cursor".into())
+            })
+        })
+        .transpose()?;
+
+    Ok(StatsParams {
+        chain_id,
+        query_start,
+        query_stop,
+        query_window_seconds,
+        align,
+        rpc_key_id: None,
+        user_id: None,
+        org_id,
+        page: 0,
+        limit,
+        cursor,
+        fields: None,
+        group_by,
+        query_stats_column: None,
+        format: Default::default(),
+    })
+}
+
+/// Decimals are strings so that no precision is lost
+#[derive(SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct Balance {
+    available_balance: String,
+    used_balance: String,
+}
+
+/// A key's settings. The secret key is only shown to its owner
+#[derive(SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct RpcKey {
+    id: u64,
+    secret_key: Option<String>,
+    description: Option<String>,
+    active: bool,
+    private_txs: bool,
+    allowed_ips: Option<String>,
+    allowed_origins: Option<String>,
+    allowed_referers: Option<String>,
+    allowed_user_agents: Option<String>,
+    log_revert_chance: f64,
+    bypass_cache: bool,
+    canary: bool,
+}
+
+impl From<rpc_key::Model> for RpcKey {
+    fn from(x: rpc_key::Model) -> Self {
+        Self {
+            id: x.id,
+            secret_key: x.secret_key.map(|x| Ulid::from(x).to_string()),
+            description: x.description,
+            active: x.active,
+            private_txs: x.private_txs,
+            allowed_ips: x.allowed_ips,
+            allowed_origins: x.allowed_origins,
+            allowed_referers: x.allowed_referers,
+            allowed_user_agents: x.allowed_user_agents,
+            log_revert_chance: x.log_revert_chance,
+            bypass_cache: x.bypass_cache,
+            canary: x.canary,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct Referral {
+    /// None until the user asks for a link with `GET /user/referral`
+    referral_code: Option<String>,
+    /// users that signed up with this user's code
+    referees: Vec<Referee>,
+}
+
+#[derive(SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct Referee {
+    /// unix epoch seconds
+    referral_start_date: i64,
+    credits_applied_for_referee: bool,
+    credits_applied_for_referrer: String,
+}
+
+#[derive(SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct Stats {
+    query_start: i64,
+    query_stop: i64,
+    query_window_seconds: u64,
+    chain_id: u64,
+    items: Vec<StatsItem>,
+    /// null on the last page
+    next_cursor: Option<String>,
+}
+
+/// The parts of `query_user_stats_json` that the schema exposes
+#[derive(Deserialize)]
+struct StatsResponse {
+    query_start: i64,
+    query_stop: i64,
+    query_window_seconds: u64,
+    chain_id: u64,
+    result: Vec<serde_json::Value>,
+    next_cursor: Option<String>,
+}
+
+/// One item of a stats response. `/user/stats/schema` describes every field
+#[derive(Debug, Default, Deserialize, SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+#[serde(default)]
+pub struct StatsItem {
+    time: Option<String>,
+    stop_time: Option<String>,
+    /// the groups from `/user/stats/schema` that this item has. `group_by` picks which ones
+    #[serde(skip)]
+    groups: Vec<StatGroup>,
+    total_frontend_requests: Option<i64>,
+    total_backend_requests: Option<i64>,
+    total_cache_hits: Option<i64>,
+    total_cache_misses: Option<i64>,
+    no_servers: Option<i64>,
+    total_request_bytes: Option<i64>,
+    total_response_bytes: Option<i64>,
+    total_response_millis: Option<i64>,
+    total_credits_used: Option<f64>,
+    balance: Option<f64>,
+}
+
+/// One group of a stats item. Bools are "true" or "false"
+#[derive(Debug, PartialEq, Eq, SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct StatGroup {
+    name: String,
+    value: String,
+}
+
+impl StatsItem {
+    /// The groups come from `STAT_GROUPS`, so new groups show up here without changing the schema
+    fn from_json(item: serde_json::Value) -> serde_json::Result<Self> {
+        let groups = STAT_GROUPS
+            .iter()
+            .filter(|x| x.kind != StatValueKind::Time)
+            .filter_map(|x| {
+                let value = match item.get(x.name)? {
+                    serde_json::Value::Null => return None,
+                    serde_json::Value::String(x) => x.clone(),
+                    x => x.to_string(),
+                };
+
+                Some(StatGroup {
+                    name: x.name.to_string(),
+                    value,
+                })
+            })
+            .collect();
+
+        let mut item: Self = serde_json::from_value(item)?;
+
+        item.groups = groups;
+
+        Ok(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_stats_item() {
+        let item = StatsItem::from_json(json!({
+            "time": "2023-05-01 00:00:00 +00:00",
+            "archive_needed": "error",
+            "error_response": false,
+            "origin": "https://example.com",
+            "method": null,
+            "total_frontend_requests": 10,
+            "total_credits_used": 0.5,
+        }))
+        .unwrap();
+
+        assert_eq!(item.time.as_deref(), Some("2023-05-01 00:00:00 +00:00"));
+        assert_eq!(
+            item.groups,
+            vec![
+                StatGroup {
+                    name: "archive_needed".into(),
+                    value: "error".into()
+                },
+                StatGroup {
+                    name: "error_response".into(),
+                    value: "false".into()
+                },
+                StatGroup {
+                    name: "origin".into(),
+                    value: "https://example.com".into()
+                },
+            ]
+        );
+        assert_eq!(item.total_frontend_requests, Some(10));
+
+        // every group in the schema can be read
+        let all: serde_json::Map<_, _> = STAT_GROUPS
+            .iter()
+            .map(|x| (x.name.to_string(), json!("x")))
+            .collect();
+
+        let item = StatsItem::from_json(all.into()).unwrap();
+
+        assert_eq!(
+            item.groups
+                .iter()
+                .map(|x| x.name.as_str())
+                .collect::<Vec<_>>(),
+            STAT_GROUPS
+                .iter()
+                .filter(|x| x.kind != StatValueKind::Time)
+                .map(|x| x.name)
+                .collect::<Vec<_>>()
+        );
+
+        assert!(stats_params(
+            None,
+            None,
+            None,
+            60,
+            true,
+            None,
+            Some(vec!["rpc".into()]),
+            None,
+            None
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! `Idempotency-Key` support for endpoints that change balances.
+//! The first response for a key is saved. Retries with the same key get that response back instead of crediting twice
+use super::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse, Web3ProxyResult};
+use crate::app::Web3ProxyApp;
+use anyhow::Context;
+use axum::response::{IntoResponse, Response};
+use entities::idempotency_key;
+use ethers::types::H256;
+use ethers::utils::keccak256;
+use http::header::CONTENT_TYPE;
+use http::{HeaderMap, HeaderName, StatusCode};
+use log::{debug, warn};
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, EntityTrait, ModelTrait, QueryFilter,
+};
+use std::future::Future;
+
+/// keys longer than this are rejected. the column is sized to match
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// Get the optional `Idempotency-Key` header
+pub fn idempotency_key(headers: &HeaderMap) -> Web3ProxyResult<Option<String>> {
+    let key = match headers.get("idempotency-key") {
+        None => return Ok(None),
+        Some(x) => x.to_str().map_err(|_| {
+            Web3ProxyError::InvalidParam("Idempotency-Key", "not a valid string".into())
+        })?,
+    };
+
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+        return Err(Web3ProxyError::InvalidParam(
+            "Idempotency-Key",
+            format!("must be 1 to {} characters", MAX_IDEMPOTENCY_KEY_LEN).into(),
+        ));
+    }
+
+    Ok(Some(key.to_string()))
+}
+
+/// Run `f` at most once per user and key.
+///
+/// `request_fingerprint` is everything about the request that changes what it does.
+/// Reusing a key with a different fingerprint (or on a different endpoint) is an error instead of a replay.
+/// Server errors are not saved so that the request can be retried with the same key.
+pub async fn with_idempotency_key(
+    app: &Web3ProxyApp,
+    user_id: u64,
+    key: Option<String>,
+    endpoint: &'static str,
+    request_fingerprint: String,
+    f: impl Future<Output = Web3ProxyResponse>,
+) -> Web3ProxyResponse {
+    let key = match key {
+        None => return f.await,
+        Some(x) => x,
+    };
+
+    let db_conn = app
+        .db_conn()
+        .web3_context("idempotency keys need a database")?;
+
+    let request_hash = format!(
+        "{:?}",
+        H256::from(keccak256(request_fingerprint.as_bytes()))
+    );
+
+    let existing = idempotency_key::Entity::find()
+        .filter(idempotency_key::Column::UserId.eq(user_id))
+        .filter(idempotency_key::Column::IdempotencyKey.eq(&key))
+        .one(&db_conn)
+        .await?;
+
+    if let Some(existing) = existing {
+        if existing.endpoint != endpoint || existing.request_hash != request_hash {
+            return Err(Web3ProxyError::IdempotencyKeyReused);
+        }
+
+        return match (existing.status_code, existing.response) {
+            (Some(status_code), Some(body)) => {
+                debug!("replaying idempotent response for user {}", user_id);
+
+                let status_code = StatusCode::from_u16(status_code)
+                    .ok()
+                    .web3_context("invalid status code saved for idempotency key")?;
+
+                Ok(replayed_response(status_code, body))
+            }
+            _ => Err(Web3ProxyError::IdempotencyKeyInProgress),
+        };
+    }
+
+    // save a placeholder first. the unique index makes sure only one request with this key runs
+    let placeholder = idempotency_key::ActiveModel {
+        user_id: sea_orm::Set(user_id),
+        idempotency_key: sea_orm::Set(key),
+        endpoint: sea_orm::Set(endpoint.to_string()),
+        request_hash: sea_orm::Set(request_hash),
+        ..Default::default()
+    };
+
+    let placeholder = match placeholder.insert(&db_conn).await {
+        Ok(x) => x,
+        Err(err) => {
+            debug!("idempotency key was inserted concurrently: {:?}", err);
+            return Err(Web3ProxyError::IdempotencyKeyInProgress);
+        }
+    };
+
+    // client errors are saved too. retrying them would give the same error
+    let response = f.await.unwrap_or_else(|err| err.into_response());
+
+    save_response(&db_conn, placeholder, response).await
+}
+
+/// Save the response on the placeholder row, or delete the row if the request can be retried
+async fn save_response(
+    db_conn: &sea_orm::DatabaseConnection,
+    placeholder: idempotency_key::Model,
+    response: Response,
+) -> Web3ProxyResponse {
+    let status_code = response.status();
+
+    if status_code.is_server_error() {
+        if let Err(err) = placeholder.delete(db_conn).await {
+            warn!("failed deleting idempotency key: {:?}", err);
+        }
+
+        return Ok(response);
+    }
+
+    // these endpoints only return small json bodies
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .context("reading response for idempotency key")?;
+
+    let body = String::from_utf8_lossy(&body).to_string();
+
+    let mut placeholder: idempotency_key::ActiveModel = placeholder.into();
+
+    placeholder.status_code = sea_orm::Set(Some(status_code.as_u16()));
+    placeholder.response = sea_orm::Set(Some(body.clone()));
+
+    placeholder
+        .update(db_conn)
+        .await
+        .web3_context("saving response for idempotency key")?;
+
+    Ok((status_code, [(CONTENT_TYPE, "application/json")], body).into_response())
+}
+
+fn replayed_response(status_code: StatusCode, body: String) -> Response {
+    (
+        status_code,
+        [
+            (CONTENT_TYPE, "application/json"),
+            (HeaderName::from_static("idempotent-replayed"), "true"),
+        ],
+        body,
+    )
+        .into_response()
+}
@@ -0,0 +1,191 @@
+//! Proxy IPFS content so that dapps can use one authenticated gateway for both json-rpc and content addressing.
+
+use super::authorization::{
+    ip_is_authorized, key_is_authorized, Authorization, RequestMetadata, RequestOrMethod,
+};
+use super::errors::{Web3ProxyError, Web3ProxyResponse};
+use super::rpc_proxy_ws::ProxyMode;
+use crate::app::Web3ProxyApp;
+use crate::semaphore_registry::RegistryPermit;
+use axum::extract::Path;
+use axum::headers::{Origin, Referer, UserAgent};
+use axum::response::IntoResponse;
+use axum::{Extension, TypedHeader};
+use axum_client_ip::InsecureClientIp;
+use axum_macros::debug_handler;
+use http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use http::{HeaderName, HeaderValue};
+use itertools::Itertools;
+use std::sync::atomic;
+use std::sync::Arc;
+
+/// `GET /ipfs/:cid` -- Public entrypoint for IPFS content. Rate limited by IP the same as the public rpc.
+#[debug_handler]
+pub async fn ipfs_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    Path(cid): Path<String>,
+) -> Web3ProxyResponse {
+    let (authorization, semaphore) =
+        ip_is_authorized(&app, ip, origin.map(|x| x.0), ProxyMode::Best).await?;
+
+    _ipfs_get(app, authorization, semaphore, cid, String::new()).await
+}
+
+/// `GET /ipfs/:cid/*path` -- Public entrypoint for a file inside an IPFS directory.
+#[debug_handler]
+pub async fn ipfs_get_path(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    Path((cid, path)): Path<(String, String)>,
+) -> Web3ProxyResponse {
+    let (authorization, semaphore) =
+        ip_is_authorized(&app, ip, origin.map(|x| x.0), ProxyMode::Best).await?;
+
+    _ipfs_get(app, authorization, semaphore, cid, path).await
+}
+
+/// `GET /rpc/:rpc_key/ipfs/:cid` -- Authenticated entrypoint for IPFS content.
+/// Rate limits, billing, and caching are based on the key the same as json-rpc.
+#[debug_handler]
+pub async fn ipfs_get_with_key(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    ip: InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    Path((rpc_key, cid)): Path<(String, String)>,
+) -> Web3ProxyResponse {
+    _ipfs_get_with_key(
+        app,
+        ip,
+        origin,
+        referer,
+        user_agent,
+        rpc_key,
+        cid,
+        String::new(),
+    )
+    .await
+}
+
+/// `GET /rpc/:rpc_key/ipfs/:cid/*path` -- Authenticated entrypoint for a file inside an IPFS directory.
+#[debug_handler]
+pub async fn ipfs_get_path_with_key(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    ip: InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    Path((rpc_key, cid, path)): Path<(String, String, String)>,
+) -> Web3ProxyResponse {
+    _ipfs_get_with_key(app, ip, origin, referer, user_agent, rpc_key, cid, path).await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn _ipfs_get_with_key(
+    app: Arc<Web3ProxyApp>,
+    InsecureClientIp(ip): InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    rpc_key: String,
+    cid: String,
+    path: String,
+) -> Web3ProxyResponse {
+    let rpc_key = rpc_key.parse()?;
+
+    let (authorization, semaphore) = key_is_authorized(
+        &app,
+        rpc_key,
+        ip,
+        origin.map(|x| x.0),
+        ProxyMode::Best,
+        referer.map(|x| x.0),
+        user_agent.map(|x| x.0),
+        app.config.chain_id,
+    )
+    .await?;
+
+    _ipfs_get(app, authorization, semaphore, cid, path).await
+}
+
+async fn _ipfs_get(
+    app: Arc<Web3ProxyApp>,
+    authorization: Authorization,
+    _semaphore: Option<RegistryPermit>,
+    cid: String,
+    path: String,
+) -> Web3ProxyResponse {
+    let ipfs_gateways = app.ipfs_gateways.as_ref().ok_or(Web3ProxyError::NotFound)?;
+
+    let authorization = Arc::new(authorization);
+
+    // every cid is different. stats only get one method so that they stay small
+    let request_metadata = RequestMetadata::new(
+        &app,
+        authorization.clone(),
+        RequestOrMethod::Method("ipfs", 0),
+        None,
+    )
+    .await;
+
+    let (status, content_type, body) = match ipfs_gateways
+        .request(
+            &cid,
+            &path,
+            authorization.checks.bypass_cache,
+            authorization.checks.cache_partition,
+            &request_metadata,
+        )
+        .await
+    {
+        Ok(x) => x,
+        Err(err) => {
+            request_metadata
+                .error_response
+                .store(true, atomic::Ordering::Release);
+
+            return Err(err);
+        }
+    };
+
+    request_metadata.add_response(body.len() as u64);
+
+    if !status.is_success() {
+        request_metadata
+            .error_response
+            .store(true, atomic::Ordering::Release);
+    }
+
+    let rpcs = request_metadata
+        .backend_requests
+        .lock()
+        .iter()
+        .map(|x| x.name.clone())
+        .join(",");
+
+    let content_type = content_type.unwrap_or_else(|| "application/octet-stream".to_string());
+
+    let mut response = (
+        status,
+        [
+            (CONTENT_TYPE, content_type),
+            (HeaderName::from_static("x-w3p-backend-rpcs"), rpcs),
+        ],
+        body,
+    )
+        .into_response();
+
+    // content addressed data never changes. errors might be fixed by the time the user retries
+    if status.is_success() {
+        response.headers_mut().insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=29030400, immutable"),
+        );
+    }
+
+    Ok(response)
+}
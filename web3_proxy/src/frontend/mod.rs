@@ -3,17 +3,30 @@
 //! Important reading about axum extractors: <https://docs.rs/axum/latest/axum/extract/index.html#the-order-of-extractors>
 
 pub mod admin;
+pub mod admin_audit;
+pub mod allowed_ips;
 pub mod authorization;
+pub mod beacon;
+pub mod bootstrap;
+pub mod error_messages;
 pub mod errors;
+pub mod graphql;
+pub mod idempotency;
+pub mod ipfs;
+pub mod presigned_url;
+pub mod receipts;
 // TODO: these are only public so docs are generated. What's a better way to do this?
 pub mod rpc_proxy_http;
 pub mod rpc_proxy_ws;
 pub mod status;
+pub mod subscriptions;
+pub mod transactions;
 pub mod users;
 
 use crate::app::Web3ProxyApp;
 use axum::{
-    routing::{get, post, put},
+    middleware,
+    routing::{delete, get, post, put},
     Extension, Router,
 };
 use http::{header::AUTHORIZATION, StatusCode};
@@ -33,8 +46,11 @@ use self::errors::Web3ProxyResult;
 /// simple keys for caching responses
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, EnumCount, EnumIter)]
 pub enum ResponseCacheKey {
+    Backends,
     BackupsNeeded,
+    Chains,
     Health,
+    Ready,
     Status,
 }
 
@@ -45,13 +61,8 @@ pub type ResponseCache = quick_cache_ttl::CacheWithTTL<
     quick_cache_ttl::DefaultHashBuilder,
 >;
 
-/// Start the frontend server.
-pub async fn serve(
-    port: u16,
-    proxy_app: Arc<Web3ProxyApp>,
-    mut shutdown_receiver: broadcast::Receiver<()>,
-    shutdown_complete_sender: broadcast::Sender<()>,
-) -> Web3ProxyResult<()> {
+/// Every route and layer of the frontend
+pub(crate) async fn router(proxy_app: Arc<Web3ProxyApp>) -> Router {
     // setup caches for whatever the frontend needs
     // no need for max items since it is limited by the enum key
     // TODO: latest moka allows for different ttls for different
@@ -69,7 +80,7 @@ pub async fn serve(
     // TODO: read config for if fastest/versus should be available publicly. default off
 
     // build our axum Router
-    let app = Router::new()
+    Router::new()
         // TODO: i think these routes could be done a lot better
         //
         // HTTP RPC (POST)
@@ -93,6 +104,26 @@ pub async fn serve(
             post(rpc_proxy_http::proxy_web3_rpc_with_key)
                 .get(rpc_proxy_ws::websocket_handler_with_key),
         )
+        // websocket only. same as "/" and "/rpc/:rpc_key" for clients that want a separate url
+        .route("/ws", get(rpc_proxy_ws::websocket_handler))
+        .route("/ws/", get(rpc_proxy_ws::websocket_handler))
+        .route(
+            "/ws/:rpc_key",
+            get(rpc_proxy_ws::websocket_handler_with_key),
+        )
+        .route(
+            "/ws/:rpc_key/",
+            get(rpc_proxy_ws::websocket_handler_with_key),
+        )
+        // authenticated key pools with and without trailing slash
+        .route(
+            "/pool/:rpc_key_pool_key/",
+            post(rpc_proxy_http::proxy_web3_rpc_with_key_pool),
+        )
+        .route(
+            "/pool/:rpc_key_pool_key",
+            post(rpc_proxy_http::proxy_web3_rpc_with_key_pool),
+        )
         // authenticated debug route with and without trailing slash
         .route(
             "/debug/:rpc_key/",
@@ -146,12 +177,63 @@ pub async fn serve(
             post(rpc_proxy_http::versus_proxy_web3_rpc_with_key)
                 .get(rpc_proxy_ws::versus_websocket_handler_with_key),
         )
+        // presigned urls
+        .route(
+            "/presigned/:token",
+            post(rpc_proxy_http::proxy_web3_rpc_with_presigned_url),
+        )
+        // consensus layer REST apis. public and authenticated
+        .route(
+            "/beacon/eth/*path",
+            get(beacon::beacon_proxy).post(beacon::beacon_proxy),
+        )
+        .route(
+            "/beacon/:rpc_key/eth/*path",
+            get(beacon::beacon_proxy_with_key).post(beacon::beacon_proxy_with_key),
+        )
+        // ipfs content. public and authenticated
+        .route("/ipfs/:cid", get(ipfs::ipfs_get))
+        .route("/ipfs/:cid/*path", get(ipfs::ipfs_get_path))
+        .route("/rpc/:rpc_key/ipfs/:cid", get(ipfs::ipfs_get_with_key))
+        .route(
+            "/rpc/:rpc_key/ipfs/:cid/*path",
+            get(ipfs::ipfs_get_path_with_key),
+        )
+        // the operator's own services
+        .route(
+            "/internal/rpc",
+            post(rpc_proxy_http::internal_proxy_web3_rpc),
+        )
+        // more chains. the app's own chain works here too
+        .route(
+            "/v1/:chain/:rpc_key",
+            post(rpc_proxy_http::proxy_web3_rpc_with_chain),
+        )
+        // bulk receipts
+        .route("/v1/:chain/receipts", post(receipts::receipts_post))
+        .route(
+            "/v1/:chain/tx_journal/:tx_hash",
+            get(transactions::tx_journal_get),
+        )
+        .route(
+            "/v1/:chain/tx_status/:tx_hash",
+            get(transactions::tx_status_get),
+        )
+        .route("/v1/:chain/mempool", get(transactions::mempool_get))
         //
         // System things
         //
         .route("/health", get(status::health))
+        .route("/ready", get(status::ready))
         .route("/status", get(status::status))
+        .route("/v1/chains", get(status::chains))
+        .route("/v1/bootstrap/:rpc_key", get(bootstrap::bootstrap_get))
         .route("/status/backups_needed", get(status::backups_needed))
+        .route("/status/backends", get(status::backends))
+        .route(
+            "/status/snapshots/:content_hash",
+            get(status::status_snapshot_get),
+        )
         //
         // User stuff
         //
@@ -164,6 +246,14 @@ pub async fn serve(
             get(users::authentication::user_login_get),
         )
         .route("/user/login", post(users::authentication::user_login_post))
+        .route(
+            "/user/refresh",
+            post(users::authentication::user_refresh_post),
+        )
+        .route(
+            "/user/sessions/revoke",
+            post(users::authentication::user_sessions_revoke_post),
+        )
         .route(
             // /:rpc_key/:subuser_address/:new_status/:new_role
             "/user/subuser",
@@ -185,6 +275,66 @@ pub async fn serve(
         .route("/user/keys", get(users::rpc_keys::rpc_keys_get))
         .route("/user/keys", post(users::rpc_keys::rpc_keys_management))
         .route("/user/keys", put(users::rpc_keys::rpc_keys_management))
+        .route(
+            "/user/keys/pools",
+            get(users::rpc_key_pools::rpc_key_pools_get),
+        )
+        .route(
+            "/user/keys/:rpc_key_id/rate_limit",
+            get(users::rpc_keys::rpc_key_rate_limit_get),
+        )
+        .route(
+            "/user/keys/:rpc_key_id/rotate",
+            post(users::rpc_keys::rpc_key_rotate_post),
+        )
+        .route(
+            "/user/keys/:rpc_key_id/canary_hits",
+            get(users::rpc_keys::rpc_key_canary_hits_get),
+        )
+        .route(
+            "/user/keys/pools",
+            post(users::rpc_key_pools::rpc_key_pools_management),
+        )
+        .route(
+            "/user/keys/:rpc_key_id/presigned",
+            post(users::presigned_urls::presigned_url_post),
+        )
+        .route(
+            "/user/keys/:rpc_key_id/presigned/:jti",
+            delete(users::presigned_urls::presigned_url_delete),
+        )
+        .route("/user/orgs", get(users::org::orgs_get))
+        .route("/user/orgs", post(users::org::org_post))
+        .route("/user/orgs/:org_id", get(users::org::org_get))
+        .route(
+            "/user/orgs/:org_id/balance",
+            post(users::org::org_balance_post),
+        )
+        .route(
+            "/user/orgs/:org_id/billing_export",
+            get(users::org::org_billing_export_get),
+        )
+        .route(
+            "/user/orgs/:org_id/invites",
+            post(users::org::org_invite_post),
+        )
+        .route(
+            "/user/orgs/:org_id/keys",
+            post(users::org::org_keys_management),
+        )
+        .route(
+            "/user/orgs/:org_id/members/:user_id",
+            put(users::org::org_member_put).delete(users::org::org_member_delete),
+        )
+        .route(
+            "/user/orgs/:org_id/transfer",
+            post(users::org::org_transfer_post),
+        )
+        .route("/user/org_invites", get(users::org::org_invites_get))
+        .route(
+            "/user/org_invites/:invite_id",
+            post(users::org::org_invite_accept).delete(users::org::org_invite_delete),
+        )
         // .route("/user/referral/:referral_link", get(users::user_referral_link_get))
         .route(
             "/user/referral",
@@ -203,6 +353,11 @@ pub async fn serve(
             "/user/stats/detailed",
             get(users::stats::user_stats_detailed_get),
         )
+        .route(
+            "/user/stats/schema",
+            get(users::stats::user_stats_schema_get),
+        )
+        .route("/graphql", post(graphql::graphql_post))
         .route(
             "/user/logout",
             post(users::authentication::user_logout_post),
@@ -212,6 +367,12 @@ pub async fn serve(
             get(admin::admin_increase_balance),
         )
         .route("/admin/modify_role", get(admin::admin_change_user_roles))
+        .route("/admin/cache/purge", post(admin::admin_cache_purge))
+        .route("/admin/read_only", post(admin::admin_read_only_post))
+        .route(
+            "/admin/status/snapshots",
+            post(admin::admin_status_snapshot_post),
+        )
         .route(
             "/admin/imitate-login/:admin_address/:user_address",
             get(admin::admin_login_get),
@@ -220,6 +381,30 @@ pub async fn serve(
             "/admin/imitate-login/:admin_address/:user_address/:message_eip",
             get(admin::admin_login_get),
         )
+        .route(
+            "/admin/rpc_keys/:rpc_key_id/origins",
+            get(admin::admin_rpc_key_origins_get).post(admin::admin_rpc_key_origins_post),
+        )
+        .route(
+            "/admin/rpc_keys/:rpc_key_id/freeze",
+            post(admin::admin_rpc_key_freeze_post),
+        )
+        .route(
+            "/admin/rpc_keys/:rpc_key_id/unfreeze",
+            post(admin::admin_rpc_key_unfreeze_post),
+        )
+        .route(
+            "/admin/tiers/rebalance",
+            post(admin::admin_tiers_rebalance_post),
+        )
+        .route(
+            "/admin/tiers/rebalance/:rebalance_id/rollback",
+            post(admin::admin_tiers_rebalance_rollback_post),
+        )
+        .route("/admin/bots", get(admin::admin_bots_get))
+        .route("/admin/tasks", get(admin::admin_tasks_get))
+        .route("/admin/wallets/:address", get(admin::admin_wallet_get))
+        .route("/admin/audit", get(admin::admin_audit_get))
         .route("/admin/imitate-login", post(admin::admin_login_post))
         .route("/admin/imitate-logout", post(admin::admin_logout_post))
         //
@@ -231,12 +416,26 @@ pub async fn serve(
         .layer(SetSensitiveRequestHeadersLayer::new(once(AUTHORIZATION)))
         // handle cors
         .layer(CorsLayer::very_permissive())
+        // branded and translated error messages. needs the application state below
+        .layer(middleware::from_fn(error_messages::localize_errors))
         // application state
         .layer(Extension(proxy_app))
         // frontend caches
         .layer(Extension(Arc::new(response_cache)))
+        // the schema is only built once
+        .layer(Extension(graphql::schema()))
         // 404 for any unknown routes
-        .fallback(errors::handler_404);
+        .fallback(errors::handler_404)
+}
+
+/// Start the frontend server.
+pub async fn serve(
+    port: u16,
+    proxy_app: Arc<Web3ProxyApp>,
+    mut shutdown_receiver: broadcast::Receiver<()>,
+    shutdown_complete_sender: broadcast::Sender<()>,
+) -> Web3ProxyResult<()> {
+    let app = router(proxy_app).await;
 
     let server_builder = if let Some(listener) = ListenFd::from_env().take_tcp_listener(0)? {
         // use systemd socket magic for no downtime deploys
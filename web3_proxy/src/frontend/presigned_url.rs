@@ -0,0 +1,236 @@
+//! Signed urls that use a key without sharing its secret.
+//! The token is the hex-encoded claims and an HMAC of them, so the proxy does not need to store anything until a url is revoked.
+use super::authorization::{constant_time_eq, key_id_is_authorized, Authorization};
+use super::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
+use super::rpc_proxy_ws::ProxyMode;
+use crate::app::Web3ProxyApp;
+use crate::jsonrpc::JsonRpcRequestEnum;
+use crate::semaphore_registry::RegistryPermit;
+use axum::headers::{Origin, Referer, UserAgent};
+use chrono::Utc;
+use ethers::utils::{hex, keccak256};
+use redis_rate_limiter::redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use ulid::Ulid;
+
+/// keccak256's block size. HMAC pads the key to this
+const HMAC_BLOCK_BYTES: usize = 136;
+
+/// What a presigned url is allowed to do
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct PresignedClaims {
+    /// database id of the key that the url uses
+    pub rpc_key_id: u64,
+    /// unix epoch seconds
+    pub exp: i64,
+    /// json-rpc methods the url may call. empty allows every method
+    pub methods: Vec<String>,
+    /// unique id for revoking the url
+    pub jti: Ulid,
+}
+
+impl PresignedClaims {
+    /// The token for a url
+    pub fn sign(&self, secret: &[u8]) -> Web3ProxyResult<String> {
+        let claims = serde_json::to_vec(self)?;
+
+        let mac = hmac_keccak256(secret, &claims);
+
+        Ok(format!("{}.{}", hex::encode(claims), hex::encode(mac)))
+    }
+
+    /// Check the signature and expiration of a token. This does not check for revocation
+    pub fn verify(token: &str, secret: &[u8]) -> Web3ProxyResult<Self> {
+        let (claims, mac) = token
+            .split_once('.')
+            .ok_or(Web3ProxyError::InvalidPresignedUrl("malformed token"))?;
+
+        let claims =
+            hex::decode(claims).or(Err(Web3ProxyError::InvalidPresignedUrl("malformed token")))?;
+        let mac =
+            hex::decode(mac).or(Err(Web3ProxyError::InvalidPresignedUrl("malformed token")))?;
+
+        if !constant_time_eq(&hmac_keccak256(secret, &claims), &mac) {
+            return Err(Web3ProxyError::InvalidPresignedUrl("bad signature"));
+        }
+
+        let claims: Self = serde_json::from_slice(&claims)
+            .or(Err(Web3ProxyError::InvalidPresignedUrl("malformed token")))?;
+
+        if claims.exp <= Utc::now().timestamp() {
+            return Err(Web3ProxyError::InvalidPresignedUrl("expired"));
+        }
+
+        Ok(claims)
+    }
+
+    /// Error if the request calls a method that the url is not scoped to
+    pub fn check_methods(&self, request: &JsonRpcRequestEnum) -> Web3ProxyResult<()> {
+        if self.methods.is_empty() {
+            return Ok(());
+        }
+
+        let requests = match request {
+            JsonRpcRequestEnum::Single(x) => std::slice::from_ref(x),
+            JsonRpcRequestEnum::Batch(x) => x.as_slice(),
+        };
+
+        for request in requests {
+            if !self.methods.contains(&request.method) {
+                return Err(Web3ProxyError::MethodNotAllowed(request.method.clone()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The key's id is part of the redis key so that revoking a jti only affects urls for that key
+fn revoked_redis_key(rpc_key_id: u64, jti: &Ulid) -> String {
+    format!("presigned_url_revoked:{}:{}", rpc_key_id, jti)
+}
+
+impl Web3ProxyApp {
+    fn presigned_url_secret(&self) -> Web3ProxyResult<&[u8]> {
+        self.config
+            .presigned_url_secret
+            .as_deref()
+            .map(|x| x.as_bytes())
+            .ok_or(Web3ProxyError::NotFound)
+    }
+
+    /// Mint a token for a url
+    pub fn sign_presigned_url(&self, claims: &PresignedClaims) -> Web3ProxyResult<String> {
+        claims.sign(self.presigned_url_secret()?)
+    }
+
+    /// Revoked urls are remembered in redis until any url could have expired
+    pub async fn revoke_presigned_url(&self, rpc_key_id: u64, jti: &Ulid) -> Web3ProxyResult<()> {
+        let ttl = self.config.presigned_url_max_seconds;
+
+        let mut redis_conn = self
+            .redis_conn()
+            .await?
+            .web3_context("redis is required to revoke presigned urls")?;
+
+        redis_conn
+            .set_ex::<_, _, ()>(revoked_redis_key(rpc_key_id, jti), 1, ttl as usize)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Authorize a request to a presigned url the same as a request with its key.
+    pub async fn presigned_url_is_authorized(
+        self: &Arc<Self>,
+        token: &str,
+        request: &JsonRpcRequestEnum,
+        ip: IpAddr,
+        origin: Option<Origin>,
+        referer: Option<Referer>,
+        user_agent: Option<UserAgent>,
+    ) -> Web3ProxyResult<(Authorization, Option<RegistryPermit>)> {
+        let claims = PresignedClaims::verify(token, self.presigned_url_secret()?)?;
+
+        claims.check_methods(request)?;
+
+        // without redis, we can't know if the url was revoked. fail closed
+        let mut redis_conn = self
+            .redis_conn()
+            .await?
+            .web3_context("redis is required for presigned urls")?;
+
+        let revoked: bool = redis_conn
+            .exists(revoked_redis_key(claims.rpc_key_id, &claims.jti))
+            .await?;
+
+        if revoked {
+            return Err(Web3ProxyError::InvalidPresignedUrl("revoked"));
+        }
+
+        let rpc_key_id = NonZeroU64::new(claims.rpc_key_id).ok_or(Web3ProxyError::UnknownKey)?;
+
+        // the key's checks are cached by id. an unknown or disabled key is rejected there
+        key_id_is_authorized(
+            self,
+            rpc_key_id,
+            ip,
+            origin,
+            ProxyMode::Best,
+            referer,
+            user_agent,
+            self.config.chain_id,
+        )
+        .await
+    }
+}
+
+/// HMAC (RFC 2104) with keccak256
+fn hmac_keccak256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut padded_key = [0u8; HMAC_BLOCK_BYTES];
+
+    if key.len() > HMAC_BLOCK_BYTES {
+        padded_key[..32].copy_from_slice(&keccak256(key));
+    } else {
+        padded_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Vec::with_capacity(HMAC_BLOCK_BYTES + message.len());
+    inner.extend(padded_key.iter().map(|x| x ^ 0x36));
+    inner.extend_from_slice(message);
+
+    let mut outer = Vec::with_capacity(HMAC_BLOCK_BYTES + 32);
+    outer.extend(padded_key.iter().map(|x| x ^ 0x5c));
+    outer.extend_from_slice(&keccak256(inner));
+
+    keccak256(outer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presigned_claims() {
+        let claims = PresignedClaims {
+            rpc_key_id: 1,
+            exp: Utc::now().timestamp() + 60,
+            methods: vec!["eth_blockNumber".to_string()],
+            jti: Ulid::new(),
+        };
+
+        let token = claims.sign(b"secret").unwrap();
+
+        assert_eq!(PresignedClaims::verify(&token, b"secret").unwrap(), claims);
+
+        assert!(matches!(
+            PresignedClaims::verify(&token, b"other secret"),
+            Err(Web3ProxyError::InvalidPresignedUrl("bad signature"))
+        ));
+
+        let expired = PresignedClaims {
+            exp: Utc::now().timestamp() - 1,
+            ..claims
+        };
+        let token = expired.sign(b"secret").unwrap();
+
+        assert!(matches!(
+            PresignedClaims::verify(&token, b"secret"),
+            Err(Web3ProxyError::InvalidPresignedUrl("expired"))
+        ));
+    }
+
+    #[test]
+    fn test_revoked_redis_key_includes_key_id() {
+        let jti = Ulid::new();
+
+        assert_ne!(revoked_redis_key(1, &jti), revoked_redis_key(2, &jti));
+        assert_eq!(
+            revoked_redis_key(1, &jti),
+            format!("presigned_url_revoked:1:{}", jti)
+        );
+    }
+}
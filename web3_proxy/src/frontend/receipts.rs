@@ -0,0 +1,186 @@
+//! Look up many transaction receipts with a single REST request.
+
+use super::authorization::ip_is_authorized;
+use super::errors::{Web3ProxyError, Web3ProxyResponse};
+use super::rpc_proxy_ws::ProxyMode;
+use crate::app::Web3ProxyApp;
+use crate::jsonrpc::{JsonRpcForwardedResponseEnum, JsonRpcId, JsonRpcRequest, JsonRpcRequestEnum};
+use axum::extract::Path;
+use axum::headers::Origin;
+use axum::TypedHeader;
+use axum::{response::IntoResponse, Extension, Json};
+use axum_client_ip::InsecureClientIp;
+use axum_macros::debug_handler;
+use ethers::types::H256;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use futures::Future;
+use serde_json::json;
+use std::sync::Arc;
+
+/// the most transaction hashes that one request can look up
+/// TODO: put this in the config?
+pub const MAX_RECEIPT_TX_HASHES: usize = 1_000;
+
+/// `POST /v1/:chain/receipts` -- Get the receipts for a JSON list of transaction hashes.
+/// Results are in the same order as the hashes. Unknown transactions have a null receipt.
+/// Up to `receipts_concurrency` receipts are looked up at once.
+/// Rate limited by IP the same as the public rpc.
+#[debug_handler]
+pub async fn receipts_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    Path(chain_id): Path<u64>,
+    Json(tx_hashes): Json<Vec<H256>>,
+) -> Web3ProxyResponse {
+    if chain_id != app.config.chain_id {
+        return Err(Web3ProxyError::NotFound);
+    }
+
+    if tx_hashes.is_empty() {
+        return Err(Web3ProxyError::InvalidParam(
+            "tx_hashes",
+            "at least one transaction hash is required".into(),
+        ));
+    }
+
+    if tx_hashes.len() > MAX_RECEIPT_TX_HASHES {
+        return Err(Web3ProxyError::InvalidParam(
+            "tx_hashes",
+            format!(
+                "no more than {} transaction hashes are allowed per request",
+                MAX_RECEIPT_TX_HASHES
+            )
+            .into(),
+        ));
+    }
+
+    let origin = origin.map(|x| x.0);
+
+    let (authorization, _semaphore) = ip_is_authorized(&app, ip, origin, ProxyMode::Best).await?;
+
+    let authorization = Arc::new(authorization);
+
+    // each receipt is its own request so that caching and stats work the same as json-rpc.
+    // only `receipts_concurrency` of them run at once so that one request can't take every backend
+    let receipts =
+        buffer_unordered_in_order(tx_hashes, app.config.receipts_concurrency, |i, tx_hash| {
+            let app = app.clone();
+            let authorization = authorization.clone();
+
+            async move {
+                let request = JsonRpcRequest::new(
+                    JsonRpcId::Number(i as u64),
+                    "eth_getTransactionReceipt".to_string(),
+                    Some(json!([tx_hash])),
+                )?;
+
+                let (_, response, _) = app
+                    .proxy_web3_rpc(authorization, JsonRpcRequestEnum::Single(request))
+                    .await?;
+
+                let response = match response {
+                    Some(JsonRpcForwardedResponseEnum::Single(x)) => x,
+                    _ => {
+                        return Err(Web3ProxyError::BadResponse(
+                            "receipt request did not get a single response".to_string(),
+                        ))
+                    }
+                };
+
+                Ok(json!({
+                    "tx_hash": tx_hash,
+                    "receipt": response.result,
+                    "error": response.error,
+                }))
+            }
+        })
+        .await?;
+
+    let response_json = json!({
+        "chain_id": chain_id,
+        "receipts": receipts,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// Run `f` on every item with at most `concurrency` running at once.
+/// They finish in any order, but the results are in the same order as `items`
+async fn buffer_unordered_in_order<T, R, E, F, Fut>(
+    items: Vec<T>,
+    concurrency: usize,
+    f: F,
+) -> Result<Vec<R>, E>
+where
+    F: Fn(usize, T) -> Fut,
+    Fut: Future<Output = Result<R, E>>,
+{
+    let mut results: Vec<(usize, R)> = stream::iter(items.into_iter().enumerate())
+        .map(|(i, x)| {
+            let fut = f(i, x);
+
+            async move { fut.await.map(|x| (i, x)) }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect()
+        .await?;
+
+    results.sort_unstable_by_key(|(i, _)| *i);
+
+    Ok(results.into_iter().map(|(_, x)| x).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::buffer_unordered_in_order;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_buffer_unordered_in_order() {
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+
+        // later items finish first
+        let results = buffer_unordered_in_order((0..20u64).collect(), 4, |i, x| {
+            let running = &running;
+            let max_running = &max_running;
+
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+
+                sleep(Duration::from_millis(100 - x * 5)).await;
+
+                running.fetch_sub(1, Ordering::SeqCst);
+
+                Ok::<_, ()>((i, x * 2))
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            results,
+            (0..20u64).map(|x| (x as usize, x * 2)).collect::<Vec<_>>()
+        );
+
+        assert_eq!(max_running.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_unordered_in_order_error() {
+        let results = buffer_unordered_in_order(vec![1, 2, 3], 0, |_, x| async move {
+            if x == 2 {
+                Err("bad")
+            } else {
+                Ok(x)
+            }
+        })
+        .await;
+
+        assert_eq!(results, Err("bad"));
+    }
+}
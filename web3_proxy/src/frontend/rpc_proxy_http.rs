@@ -1,15 +1,25 @@
 //! Take a user's HTTP JSON-RPC requests and either respond from local data or proxy the request to a backend rpc server.
 
-use super::authorization::{ip_is_authorized, key_is_authorized};
-use super::errors::Web3ProxyResponse;
+use super::authorization::{
+    ip_is_authorized, key_is_authorized, key_pool_is_authorized, Authorization,
+};
+use super::errors::{RequestIds, Web3ProxyResponse, Web3ProxyResult};
 use super::rpc_proxy_ws::ProxyMode;
-use crate::{app::Web3ProxyApp, jsonrpc::JsonRpcRequestEnum};
+use crate::app::{StreamedResponse, Web3ProxyApp};
+use crate::jsonrpc::JsonRpcRequestEnum;
+use crate::rpcs::one::Web3Rpc;
+use crate::semaphore_registry::RegistryPermit;
+use axum::body::StreamBody;
 use axum::extract::Path;
-use axum::headers::{Origin, Referer, UserAgent};
+use axum::headers::authorization::Bearer;
+use axum::headers::{Authorization as AuthorizationHeader, Origin, Referer, UserAgent};
+use axum::response::{IntoResponse, Response};
 use axum::TypedHeader;
-use axum::{response::IntoResponse, Extension, Json};
+use axum::{Extension, Json};
 use axum_client_ip::InsecureClientIp;
 use axum_macros::debug_handler;
+use http::header::CONTENT_TYPE;
+use http::StatusCode;
 use itertools::Itertools;
 use std::sync::Arc;
 
@@ -59,49 +69,9 @@ async fn _proxy_web3_rpc(
     // TODO: do we care about keeping the TypedHeader wrapper?
     let origin = origin.map(|x| x.0);
 
-    let (authorization, semaphore) = ip_is_authorized(&app, ip, origin, proxy_mode).await?;
+    let authorization = ip_is_authorized(&app, ip, origin, proxy_mode).await;
 
-    let authorization = Arc::new(authorization);
-
-    // TODO: calculate payload bytes here (before turning into serde_json::Value). that will save serializing later
-
-    let (status_code, response, rpcs, _semaphore) = app
-        .proxy_web3_rpc(authorization, payload)
-        .await
-        .map(|(s, x, y)| (s, x, y, semaphore))?;
-
-    let mut response = (status_code, Json(response)).into_response();
-
-    let headers = response.headers_mut();
-
-    // TODO: this might be slow. think about this more
-    // TODO: special string if no rpcs were used (cache hit)?
-    let mut backup_used = false;
-
-    let rpcs: String = rpcs
-        .into_iter()
-        .map(|x| {
-            if x.backup {
-                backup_used = true;
-            }
-            x.name.clone()
-        })
-        .join(",");
-
-    headers.insert(
-        "X-W3P-BACKEND-RPCS",
-        rpcs.parse().expect("W3P-BACKEND-RPCS should always parse"),
-    );
-
-    headers.insert(
-        "X-W3P-BACKUP-RPC",
-        backup_used
-            .to_string()
-            .parse()
-            .expect("W3P-BACKEND-RPCS should always parse"),
-    );
-
-    Ok(response)
+    _proxy_authorized_web3_rpc(app, authorization, payload, None).await
 }
 
 /// Authenticated entrypoint for HTTP JSON-RPC requests. Web3 wallets use this.
@@ -131,6 +101,53 @@ pub async fn proxy_web3_rpc_with_key(
     .await
 }
 
+/// POST /v1/:chain/:rpc_key -- Authenticated entrypoint for any chain that this proxy serves.
+/// The app's own chain is the same as `/rpc/:rpc_key`. Other chains are sent to their backends in the `chains` config.
+/// Rate limits and billing are shared by every chain.
+#[debug_handler]
+pub async fn proxy_web3_rpc_with_chain(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    ip: InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    Path((chain_id, rpc_key)): Path<(u64, String)>,
+    Json(payload): Json<JsonRpcRequestEnum>,
+) -> Web3ProxyResponse {
+    if chain_id == app.config.chain_id {
+        return _proxy_web3_rpc_with_key(
+            app,
+            ip,
+            origin,
+            referer,
+            user_agent,
+            rpc_key,
+            payload,
+            ProxyMode::Best,
+        )
+        .await;
+    }
+
+    let authorization = match rpc_key.parse() {
+        Ok(rpc_key) => {
+            key_is_authorized(
+                &app,
+                rpc_key,
+                ip.0,
+                origin.map(|x| x.0),
+                ProxyMode::Best,
+                referer.map(|x| x.0),
+                user_agent.map(|x| x.0),
+                chain_id,
+            )
+            .await
+        }
+        Err(err) => Err(err),
+    };
+
+    _proxy_authorized_web3_rpc(app, authorization, payload, Some(chain_id)).await
+}
+
 // TODO: if a /debug/ request gets rejected by an invalid request, there won't be any kafka log
 // TODO:
 #[debug_handler]
@@ -213,31 +230,151 @@ async fn _proxy_web3_rpc_with_key(
     payload: JsonRpcRequestEnum,
     proxy_mode: ProxyMode,
 ) -> Web3ProxyResponse {
-    // TODO: DRY w/ proxy_web3_rpc
-    // the request can take a while, so we spawn so that we can start serving another request
-    let rpc_key = rpc_key.parse()?;
+    let authorization = match rpc_key.parse() {
+        Ok(rpc_key) => {
+            key_is_authorized(
+                &app,
+                rpc_key,
+                ip,
+                origin.map(|x| x.0),
+                proxy_mode,
+                referer.map(|x| x.0),
+                user_agent.map(|x| x.0),
+                app.config.chain_id,
+            )
+            .await
+        }
+        Err(err) => Err(err),
+    };
+
+    _proxy_authorized_web3_rpc(app, authorization, payload, None).await
+}
 
-    let (authorization, semaphore) = key_is_authorized(
-        &app,
-        rpc_key,
-        ip,
-        origin.map(|x| x.0),
-        proxy_mode,
-        referer.map(|x| x.0),
-        user_agent.map(|x| x.0),
-    )
-    .await?;
+/// POST /pool/:rpc_key_pool_key -- Authenticated entrypoint for HTTP JSON-RPC requests to a key pool.
+/// Each request is served by one of the pool's keys. Rate limits and billing are based on that key.
+#[debug_handler]
+pub async fn proxy_web3_rpc_with_key_pool(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    Path(rpc_key_pool_key): Path<String>,
+    Json(payload): Json<JsonRpcRequestEnum>,
+) -> Web3ProxyResponse {
+    let authorization = match rpc_key_pool_key.parse() {
+        Ok(rpc_key_pool_key) => {
+            key_pool_is_authorized(
+                &app,
+                rpc_key_pool_key,
+                ip,
+                origin.map(|x| x.0),
+                ProxyMode::Best,
+                referer.map(|x| x.0),
+                user_agent.map(|x| x.0),
+                app.config.chain_id,
+            )
+            .await
+        }
+        Err(err) => Err(err),
+    };
+
+    _proxy_authorized_web3_rpc(app, authorization, payload, None).await
+}
 
-    let authorization = Arc::new(authorization);
+/// POST /presigned/:token -- Entrypoint for HTTP JSON-RPC requests with a presigned url.
+/// The url stands in for its key until it expires or is revoked. Rate limits and billing are based on that key.
+#[debug_handler]
+pub async fn proxy_web3_rpc_with_presigned_url(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    referer: Option<TypedHeader<Referer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    Path(token): Path<String>,
+    Json(payload): Json<JsonRpcRequestEnum>,
+) -> Web3ProxyResponse {
+    let authorization = app
+        .presigned_url_is_authorized(
+            &token,
+            &payload,
+            ip,
+            origin.map(|x| x.0),
+            referer.map(|x| x.0),
+            user_agent.map(|x| x.0),
+        )
+        .await;
+
+    _proxy_authorized_web3_rpc(app, authorization, payload, None).await
+}
 
-    let rpc_secret_key_id = authorization.checks.rpc_secret_key_id;
+/// POST /internal/rpc -- Entrypoint for the operator's own services (deposit watchers, oracles, ...).
+/// Authenticated by the `internal_service_token` bearer. Skips user rate limits and gets priority when picking a backend
+/// so that a spike in user traffic can't starve them.
+#[debug_handler]
+pub async fn internal_proxy_web3_rpc(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    TypedHeader(AuthorizationHeader(bearer)): TypedHeader<AuthorizationHeader<Bearer>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    Json(payload): Json<JsonRpcRequestEnum>,
+) -> Web3ProxyResponse {
+    let authorization = app
+        .internal_service_is_authorized(&bearer, ip, user_agent.map(|x| x.0))
+        .map(|x| (x, None));
+
+    _proxy_authorized_web3_rpc(app, authorization, payload, None).await
+}
+
+/// Errors (including failed authorization) are sent as json-rpc errors with the ids of the requests in the payload.
+/// `chain_id` is Some for requests to one of the `chains` instead of the app's own chain
+async fn _proxy_authorized_web3_rpc(
+    app: Arc<Web3ProxyApp>,
+    authorization: Web3ProxyResult<(Authorization, Option<RegistryPermit>)>,
+    mut payload: JsonRpcRequestEnum,
+    chain_id: Option<u64>,
+) -> Web3ProxyResponse {
+    let request_ids = RequestIds::from(&payload);
+
+    let (authorization, semaphore) = match authorization {
+        Ok(x) => x,
+        Err(err) => return Ok(err.into_response_with_ids(request_ids)),
+    };
 
-    let (status_code, response, rpcs, _semaphore) = app
-        .proxy_web3_rpc(authorization, payload)
-        .await
-        .map(|(s, x, y)| (s, x, y, semaphore))?;
+    let authorization = Arc::new(authorization);
 
-    let mut response = (status_code, Json(response)).into_response();
+    let rpc_secret_key_id = authorization.checks.rpc_secret_key_id;
+    let rpc_key_pool_id = authorization.checks.rpc_key_pool_id;
+    let rate_limit_queue_millis = authorization.rate_limit_queue_millis;
+
+    // the other chains don't stream responses
+    let stream_result = match chain_id {
+        Some(_) => Err(semaphore),
+        None => try_stream_web3_rpc(&app, &authorization, &mut payload, semaphore).await,
+    };
+
+    let (mut response, rpcs) = match stream_result {
+        Ok(x) => x,
+        Err(semaphore) => {
+            let proxied = match chain_id {
+                Some(chain_id) => app.proxy_chain_rpc(chain_id, authorization, payload).await,
+                None => app.proxy_web3_rpc(authorization, payload).await,
+            };
+
+            let (status_code, response, rpcs, _semaphore) = match proxied {
+                Ok((s, x, y)) => (s, x, y, semaphore),
+                Err(err) => return Ok(err.into_response_with_ids(request_ids)),
+            };
+
+            let response = match response {
+                Some(response) => (status_code, Json(response)).into_response(),
+                // notifications were processed, but the client does not get a body back
+                None => StatusCode::NO_CONTENT.into_response(),
+            };
+
+            (response, rpcs)
+        }
+    };
 
     let headers = response.headers_mut();
 
@@ -277,5 +414,62 @@ async fn _proxy_web3_rpc_with_key(
         );
     }
 
+    if let Some(rpc_key_pool_id) = rpc_key_pool_id {
+        headers.insert(
+            "X-W3P-KEY-POOL-ID",
+            rpc_key_pool_id
+                .to_string()
+                .parse()
+                .expect("X-W3P-KEY-POOL-ID should always parse"),
+        );
+    }
+
+    if rate_limit_queue_millis > 0 {
+        headers.insert(
+            "X-W3P-QUEUE-MILLIS",
+            rate_limit_queue_millis
+                .to_string()
+                .parse()
+                .expect("X-W3P-QUEUE-MILLIS should always parse"),
+        );
+    }
+
     Ok(response)
 }
+
+/// Stream the backend's response body if the request is for one of `stream_response_methods`.
+/// Gives the semaphore back if the request should use the normal path
+async fn try_stream_web3_rpc(
+    app: &Arc<Web3ProxyApp>,
+    authorization: &Arc<Authorization>,
+    payload: &mut JsonRpcRequestEnum,
+    semaphore: Option<RegistryPermit>,
+) -> Result<(Response, Vec<Arc<Web3Rpc>>), Option<RegistryPermit>> {
+    let request = match payload {
+        JsonRpcRequestEnum::Single(x) => x,
+        JsonRpcRequestEnum::Batch(_) => return Err(semaphore),
+    };
+
+    let (response, rpcs) = match app.proxy_web3_rpc_streaming(authorization, request).await {
+        Some(x) => x,
+        None => return Err(semaphore),
+    };
+
+    let response = match response {
+        StreamedResponse::Stream(mut stream) => {
+            // the user's concurrency limit covers the whole body
+            stream.hold(semaphore);
+
+            (
+                [(CONTENT_TYPE, "application/json")],
+                StreamBody::new(stream),
+            )
+                .into_response()
+        }
+        StreamedResponse::Error(status_code, response) => {
+            (status_code, Json(response)).into_response()
+        }
+    };
+
+    Ok((response, rpcs))
+}
@@ -4,7 +4,10 @@
 
 use super::authorization::{ip_is_authorized, key_is_authorized, Authorization, RequestMetadata};
 use super::errors::{Web3ProxyError, Web3ProxyResponse};
+use crate::app::{take_utf8, StreamedResponse, WsSession};
+use crate::http_params::{Params, WebsocketParams};
 use crate::jsonrpc::JsonRpcId;
+use crate::semaphore_registry::RegistryPermit;
 use crate::{
     app::Web3ProxyApp,
     frontend::errors::Web3ProxyResult,
@@ -13,7 +16,7 @@ use crate::{
 use anyhow::Context;
 use axum::headers::{Origin, Referer, UserAgent};
 use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
     extract::Path,
     response::{IntoResponse, Redirect},
     Extension, TypedHeader,
@@ -30,11 +33,16 @@ use futures::{
 use handlebars::Handlebars;
 use hashbrown::HashMap;
 use http::StatusCode;
-use log::{info, trace};
+use log::{info, trace, warn};
 use serde_json::json;
+use std::borrow::Cow;
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
-use std::{str::from_utf8_mut, sync::atomic::AtomicUsize};
-use tokio::sync::{broadcast, OwnedSemaphorePermit, RwLock};
+use tokio::sync::{broadcast, RwLock};
+use tokio::time::{interval_at, sleep, Duration, Instant, MissedTickBehavior};
+
+/// chunks waiting for the socket's writer before a stream stops reading from its backend
+const MAX_QUEUED_CHUNKS: usize = 16;
 
 /// How to select backend servers for a request
 #[derive(Copy, Clone, Debug)]
@@ -63,9 +71,10 @@ pub async fn websocket_handler(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    Params(params): Params<WebsocketParams>,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
-    _websocket_handler(ProxyMode::Best, app, ip, origin, ws_upgrade).await
+    _websocket_handler(ProxyMode::Best, app, ip, origin, params.resume, ws_upgrade).await
 }
 
 /// Public entrypoint for WebSocket JSON-RPC requests that uses all synced servers.
@@ -75,11 +84,20 @@ pub async fn fastest_websocket_handler(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    Params(params): Params<WebsocketParams>,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
     // TODO: get the fastest number from the url params (default to 0/all)
     // TODO: config to disable this
-    _websocket_handler(ProxyMode::Fastest(0), app, ip, origin, ws_upgrade).await
+    _websocket_handler(
+        ProxyMode::Fastest(0),
+        app,
+        ip,
+        origin,
+        params.resume,
+        ws_upgrade,
+    )
+    .await
 }
 
 /// Public entrypoint for WebSocket JSON-RPC requests that uses all synced servers.
@@ -89,10 +107,19 @@ pub async fn versus_websocket_handler(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     ip: InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    Params(params): Params<WebsocketParams>,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
     // TODO: config to disable this
-    _websocket_handler(ProxyMode::Versus, app, ip, origin, ws_upgrade).await
+    _websocket_handler(
+        ProxyMode::Versus,
+        app,
+        ip,
+        origin,
+        params.resume,
+        ws_upgrade,
+    )
+    .await
 }
 
 async fn _websocket_handler(
@@ -100,6 +127,7 @@ async fn _websocket_handler(
     app: Arc<Web3ProxyApp>,
     InsecureClientIp(ip): InsecureClientIp,
     origin: Option<TypedHeader<Origin>>,
+    resume: Option<String>,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
     let origin = origin.map(|x| x.0);
@@ -109,9 +137,7 @@ async fn _websocket_handler(
     let authorization = Arc::new(authorization);
 
     match ws_upgrade {
-        Some(ws) => Ok(ws
-            .on_upgrade(move |socket| proxy_web3_socket(app, authorization, socket))
-            .into_response()),
+        Some(ws) => upgrade_web3_socket(app, authorization, resume, ws),
         None => {
             if let Some(redirect) = &app.config.redirect_public_url {
                 // this is not a websocket. redirect to a friendly page
@@ -134,6 +160,7 @@ pub async fn websocket_handler_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    Params(params): Params<WebsocketParams>,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
     _websocket_handler_with_key(
@@ -144,6 +171,7 @@ pub async fn websocket_handler_with_key(
         origin,
         referer,
         user_agent,
+        params.resume,
         ws_upgrade,
     )
     .await
@@ -157,6 +185,7 @@ pub async fn debug_websocket_handler_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    Params(params): Params<WebsocketParams>,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
     _websocket_handler_with_key(
@@ -167,6 +196,7 @@ pub async fn debug_websocket_handler_with_key(
         origin,
         referer,
         user_agent,
+        params.resume,
         ws_upgrade,
     )
     .await
@@ -180,6 +210,7 @@ pub async fn fastest_websocket_handler_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    Params(params): Params<WebsocketParams>,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
     // TODO: get the fastest number from the url params (default to 0/all)
@@ -191,6 +222,7 @@ pub async fn fastest_websocket_handler_with_key(
         origin,
         referer,
         user_agent,
+        params.resume,
         ws_upgrade,
     )
     .await
@@ -204,6 +236,7 @@ pub async fn versus_websocket_handler_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    Params(params): Params<WebsocketParams>,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
     _websocket_handler_with_key(
@@ -214,6 +247,7 @@ pub async fn versus_websocket_handler_with_key(
         origin,
         referer,
         user_agent,
+        params.resume,
         ws_upgrade,
     )
     .await
@@ -228,6 +262,7 @@ async fn _websocket_handler_with_key(
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    resume: Option<String>,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
     let rpc_key = rpc_key.parse()?;
@@ -240,6 +275,7 @@ async fn _websocket_handler_with_key(
         proxy_mode,
         referer.map(|x| x.0),
         user_agent.map(|x| x.0),
+        app.config.chain_id,
     )
     .await?;
 
@@ -248,9 +284,7 @@ async fn _websocket_handler_with_key(
     let authorization = Arc::new(authorization);
 
     match ws_upgrade {
-        Some(ws_upgrade) => {
-            Ok(ws_upgrade.on_upgrade(move |socket| proxy_web3_socket(app, authorization, socket)))
-        }
+        Some(ws_upgrade) => upgrade_web3_socket(app, authorization, resume, ws_upgrade),
         None => {
             // if no websocket upgrade, this is probably a user loading the url with their browser
 
@@ -302,9 +336,37 @@ async fn _websocket_handler_with_key(
     }
 }
 
+/// Accept the websocket. If `resume` is the token of a recently closed socket, its subscriptions are started again.
+/// The socket's own token is in the `X-W3P-RESUME-TOKEN` header and the `w3p_resumeToken` method
+fn upgrade_web3_socket(
+    app: Arc<Web3ProxyApp>,
+    authorization: Arc<Authorization>,
+    resume: Option<String>,
+    ws_upgrade: WebSocketUpgrade,
+) -> Web3ProxyResponse {
+    let session = app.open_ws_session(&authorization, resume.as_deref())?;
+
+    let token = session.token;
+
+    let mut response = ws_upgrade
+        .on_upgrade(move |socket| proxy_web3_socket(app, authorization, session, socket))
+        .into_response();
+
+    response.headers_mut().insert(
+        "X-W3P-RESUME-TOKEN",
+        token
+            .to_string()
+            .parse()
+            .expect("X-W3P-RESUME-TOKEN should always parse"),
+    );
+
+    Ok(response)
+}
+
 async fn proxy_web3_socket(
     app: Arc<Web3ProxyApp>,
     authorization: Arc<Authorization>,
+    session: Arc<WsSession>,
     socket: WebSocket,
 ) {
     // split the websocket so we can read and write concurrently
@@ -314,7 +376,13 @@ async fn proxy_web3_socket(
     let (response_sender, response_receiver) = flume::unbounded::<Message>();
 
     tokio::spawn(write_web3_socket(response_receiver, ws_tx));
-    tokio::spawn(read_web3_socket(app, authorization, ws_rx, response_sender));
+    tokio::spawn(read_web3_socket(
+        app,
+        authorization,
+        session,
+        ws_rx,
+        response_sender,
+    ));
 }
 
 /// websockets support a few more methods than http clients
@@ -324,9 +392,10 @@ async fn handle_socket_payload(
     authorization: &Arc<Authorization>,
     payload: &str,
     response_sender: &flume::Sender<Message>,
+    session: &WsSession,
     subscription_count: &AtomicUsize,
     subscriptions: Arc<RwLock<HashMap<U64, AbortHandle>>>,
-) -> Web3ProxyResult<(Message, Option<OwnedSemaphorePermit>)> {
+) -> Web3ProxyResult<(Option<Message>, Option<RegistryPermit>)> {
     let (authorization, semaphore) = match authorization.check_again(&app).await {
         Ok((a, s)) => (a, s),
         Err(err) => {
@@ -336,18 +405,19 @@ async fn handle_socket_payload(
 
             let err = serde_json::to_string(&err)?;
 
-            return Ok((Message::Text(err), None));
+            return Ok((Some(Message::Text(err)), None));
         }
     };
 
     // TODO: do any clients send batches over websockets?
     // TODO: change response into response_data
     let (response_id, response) = match serde_json::from_str::<JsonRpcRequest>(payload) {
-        Ok(json_request) => {
-            let response_id = json_request.id.clone();
+        Ok(mut json_request) => {
+            // notifications do not have an id to respond to
+            let response_id = (!json_request.notification).then(|| json_request.id.clone());
 
             // TODO: move this to a seperate function so we can use the try operator
-            let response: Web3ProxyResult<JsonRpcForwardedResponseEnum> =
+            let response: Web3ProxyResult<Option<JsonRpcForwardedResponseEnum>> =
                 match &json_request.method[..] {
                     "eth_subscribe" => {
                         // TODO: how can we subscribe with proxy_mode?
@@ -356,6 +426,7 @@ async fn handle_socket_payload(
                                 authorization.clone(),
                                 json_request,
                                 subscription_count,
+                                session,
                                 response_sender.clone(),
                             )
                             .await
@@ -376,7 +447,7 @@ async fn handle_socket_payload(
                                     x.insert(k, handle);
                                 };
 
-                                Ok(response.into())
+                                Ok(Some(response.into()))
                             }
                             Err(err) => Err(err),
                         }
@@ -401,6 +472,7 @@ async fn handle_socket_payload(
                                             None => false,
                                             Some(handle) => {
                                                 handle.abort();
+                                                session.forget_subscription(subscription_id);
                                                 true
                                             }
                                         }
@@ -409,39 +481,99 @@ async fn handle_socket_payload(
                                     // TODO: don't create the response here. use a JsonRpcResponseData instead
                                     let response = JsonRpcForwardedResponse::from_value(
                                         json!(partial_response),
-                                        response_id.clone(),
+                                        json_request.id.clone(),
                                     );
 
                                     request_metadata.add_response(&response);
 
-                                    Ok(response.into())
+                                    Ok(Some(response.into()))
                                 }
-                                Err(err) => Err(Web3ProxyError::BadRequest(f!(
-                                    "incorrect params given for eth_unsubscribe. {err:?}"
-                                ))),
+                                Err(err) => Err(Web3ProxyError::InvalidRpcParams(
+                                    f!("incorrect params given for eth_unsubscribe. {err:?}")
+                                        .into(),
+                                )),
                             }
                         } else {
-                            Err(Web3ProxyError::BadRequest(
-                                "no params given for eth_unsubscribe".to_string(),
+                            Err(Web3ProxyError::InvalidRpcParams(
+                                "no params given for eth_unsubscribe".into(),
                             ))
                         }
                     }
-                    _ => app
-                        .proxy_web3_rpc(authorization.clone(), json_request.into())
-                        .await
-                        .map(|(status_code, response, _)| response),
+                    "w3p_resumeToken" => {
+                        // reconnect with `?resume=TOKEN` to get this socket's subscriptions back
+                        let response = JsonRpcForwardedResponse::from_value(
+                            json!(session.token),
+                            json_request.id.clone(),
+                        );
+
+                        Ok(Some(response.into()))
+                    }
+                    "w3p_streamChunks" => {
+                        // `[true]` sends large responses as `w3p_chunk` notifications instead of one message
+                        match json_request
+                            .params
+                            .as_ref()
+                            .and_then(|x| x.get(0))
+                            .and_then(|x| x.as_bool())
+                        {
+                            Some(enabled) => {
+                                session.set_stream_chunks(enabled);
+
+                                let response = JsonRpcForwardedResponse::from_value(
+                                    json!(enabled),
+                                    json_request.id.clone(),
+                                );
+
+                                Ok(Some(response.into()))
+                            }
+                            None => Err(Web3ProxyError::InvalidParam(
+                                "params",
+                                "w3p_streamChunks takes one bool".into(),
+                            )),
+                        }
+                    }
+                    _ => {
+                        let streamed = if session.stream_chunks() {
+                            stream_ws_chunks(
+                                &app,
+                                &authorization,
+                                &mut json_request,
+                                response_sender,
+                            )
+                            .await
+                        } else {
+                            None
+                        };
+
+                        match streamed {
+                            Some(response) => response.map(|x| Some(x.into())),
+                            None => app
+                                .proxy_web3_rpc(authorization.clone(), json_request.into())
+                                .await
+                                .map(|(status_code, response, _)| response),
+                        }
+                    }
                 };
 
             (response_id, response)
         }
         Err(err) => {
             let id = JsonRpcId::None.to_raw_value();
-            (id, Err(err.into()))
+            (Some(id), Err(err.into()))
+        }
+    };
+
+    let response_id = match response_id {
+        Some(x) => x,
+        None => {
+            // the request was handled, but notifications never get a response. not even errors
+            return Ok((None, semaphore));
         }
     };
 
     let response_str = match response {
-        Ok(x) => serde_json::to_string(&x).expect("to_string should always work here"),
+        Ok(Some(x)) => serde_json::to_string(&x).expect("to_string should always work here"),
+        Ok(None) => return Ok((None, semaphore)),
         Err(err) => {
             let (_, response_data) = err.into_response_parts();
 
@@ -451,31 +583,173 @@ async fn handle_socket_payload(
         }
     };
 
-    Ok((Message::Text(response_str), semaphore))
+    Ok((Some(Message::Text(response_str)), semaphore))
+}
+
+/// An error response for a frame that could not be handled. It has no request id to respond to
+fn error_message(err: Web3ProxyError) -> Message {
+    let (_, response_data) = err.into_response_parts();
+
+    let response =
+        JsonRpcForwardedResponse::from_response_data(response_data, JsonRpcId::None.to_raw_value());
+
+    Message::Text(serde_json::to_string(&response).expect("to_string should always work here"))
+}
+
+/// Send the backend's response body as `w3p_chunk` notifications. Joining their `data` gives the backend's whole response.
+/// The final response only has the number of chunks and bytes. Returns None if the request should use the normal path
+async fn stream_ws_chunks(
+    app: &Arc<Web3ProxyApp>,
+    authorization: &Arc<Authorization>,
+    request: &mut JsonRpcRequest,
+    response_sender: &flume::Sender<Message>,
+) -> Option<Web3ProxyResult<JsonRpcForwardedResponse>> {
+    let (response, _) = app.proxy_web3_rpc_streaming(authorization, request).await?;
+
+    let mut stream = match response {
+        StreamedResponse::Stream(x) => x,
+        StreamedResponse::Error(_, response) => return Some(Ok(response)),
+    };
+
+    let mut buf = vec![];
+    let mut index = 0u64;
+    let mut num_bytes = 0;
+
+    loop {
+        let (data, done) = match stream.next().await {
+            Some(Ok(chunk)) => {
+                num_bytes += chunk.len();
+                buf.extend_from_slice(&chunk);
+
+                (take_utf8(&mut buf), false)
+            }
+            Some(Err(err)) => {
+                return Some(Err(Web3ProxyError::Anyhow(
+                    anyhow::Error::new(err).context(f!("stream failed after {index} chunks")),
+                )))
+            }
+            None => (String::from_utf8_lossy(&buf).to_string(), true),
+        };
+
+        if !data.is_empty() {
+            // the channel is unbounded. wait for the writer instead of buffering the whole body in it
+            while response_sender.len() >= MAX_QUEUED_CHUNKS && !response_sender.is_disconnected() {
+                sleep(Duration::from_millis(10)).await;
+            }
+
+            let notification = json!({
+                "jsonrpc": "2.0",
+                "method": "w3p_chunk",
+                "params": {
+                    "id": request.id,
+                    "index": index,
+                    "data": data,
+                },
+            });
+
+            if response_sender
+                .send_async(Message::Text(notification.to_string()))
+                .await
+                .is_err()
+            {
+                // the client hung up. dropping the stream still bills for what was sent
+                return Some(Ok(JsonRpcForwardedResponse::from_value(
+                    json!(null),
+                    request.id.clone(),
+                )));
+            }
+
+            index += 1;
+        }
+
+        if done {
+            break;
+        }
+    }
+
+    Some(Ok(JsonRpcForwardedResponse::from_value(
+        json!({ "chunks": index, "bytes": num_bytes }),
+        request.id.clone(),
+    )))
 }
 
 async fn read_web3_socket(
     app: Arc<Web3ProxyApp>,
     authorization: Arc<Authorization>,
+    session: Arc<WsSession>,
     mut ws_rx: SplitStream<WebSocket>,
     response_sender: flume::Sender<Message>,
 ) {
+    let mut resumed_subscriptions = HashMap::new();
+    let mut next_subscription_id = 1;
+
+    // a resumed session keeps its subscription ids so that the client's handlers still work
+    for (subscription_id, params) in session.subscriptions() {
+        match app.resume_subscription(
+            authorization.clone(),
+            &session,
+            subscription_id,
+            &params,
+            response_sender.clone(),
+        ) {
+            Ok(handle) => {
+                resumed_subscriptions.insert(subscription_id, handle);
+                next_subscription_id = next_subscription_id.max(subscription_id.as_usize() + 1);
+            }
+            Err(err) => {
+                warn!(
+                    "unable to resume subscription {}: {:?}",
+                    subscription_id, err
+                );
+                session.forget_subscription(&subscription_id);
+            }
+        }
+    }
+
     // RwLock should be fine here. a user isn't going to be opening tons of subscriptions
-    let subscriptions = Arc::new(RwLock::new(HashMap::new()));
-    let subscription_count = Arc::new(AtomicUsize::new(1));
+    let subscriptions = Arc::new(RwLock::new(resumed_subscriptions));
+    let subscription_count = Arc::new(AtomicUsize::new(next_subscription_id));
 
     let (close_sender, mut close_receiver) = broadcast::channel(1);
 
+    let ping_seconds = app.config.ws_ping_interval_seconds;
+    let ping_period = Duration::from_secs(ping_seconds.max(1));
+    let mut ping_interval = interval_at(Instant::now() + ping_period, ping_period);
+    ping_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut last_seen = Instant::now();
+
+    let rpc_key_id = authorization.checks.rpc_secret_key_id.map(|x| x.get());
+    let mut frozen_rpc_keys = app.frozen_rpc_keys();
+
+    let mut draining = app.draining_receiver();
+
     loop {
+        // the frontend can't finish shutting down while the socket is open
+        if *draining.borrow() {
+            info!("closing websocket because the server is shutting down");
+            let close = CloseFrame {
+                code: close_code::AWAY,
+                reason: Cow::Borrowed("server is shutting down"),
+            };
+            let _ = response_sender
+                .send_async(Message::Close(Some(close)))
+                .await;
+            break;
+        }
+
         tokio::select! {
             msg = ws_rx.next() => {
                 if let Some(Ok(msg)) = msg {
+                    last_seen = Instant::now();
+
                     // spawn so that we can serve responses from this loop even faster
                     // TODO: only do these clones if the msg is text/binary?
                     let close_sender = close_sender.clone();
                     let app = app.clone();
                     let authorization = authorization.clone();
                     let response_sender = response_sender.clone();
+                    let session = session.clone();
                     let subscriptions = subscriptions.clone();
                     let subscription_count = subscription_count.clone();
 
@@ -483,26 +757,13 @@ async fn read_web3_socket(
                         let mut _semaphore = None;
 
                         // new message from our client. forward to a backend and then send it through response_tx
-                        let response_msg = match msg {
-                            Message::Text(ref payload) => {
-                                // TODO: do not unwrap!
-                                let (msg, s) = handle_socket_payload(
-                                    app.clone(),
-                                    &authorization,
-                                    payload,
-                                    &response_sender,
-                                    &subscription_count,
-                                    subscriptions,
-                                )
-                                .await.unwrap();
-
-                                _semaphore = s;
-
-                                msg
-                            }
+                        let payload = match msg {
+                            Message::Text(ref payload) => Ok(payload.as_str()),
+                            Message::Binary(ref payload) => std::str::from_utf8(payload),
                             Message::Ping(x) => {
                                 trace!("ping: {:?}", x);
-                                Message::Pong(x)
+                                let _ = response_sender.send_async(Message::Pong(x)).await;
+                                return;
                             }
                             Message::Pong(x) => {
                                 trace!("pong: {:?}", x);
@@ -514,31 +775,41 @@ async fn read_web3_socket(
                                 let _ = close_sender.send(true);
                                 return;
                             }
-                            Message::Binary(mut payload) => {
-                                let payload = from_utf8_mut(&mut payload).unwrap();
-
-                                // TODO: do not unwrap!
-                                let (msg, s) = handle_socket_payload(
-                                    app.clone(),
-                                    &authorization,
-                                    payload,
-                                    &response_sender,
-                                    &subscription_count,
-                                    subscriptions,
-                                )
-                                .await.unwrap();
-
-                                _semaphore = s;
-
-                                msg
-                            }
                         };
 
-                        if response_sender.send_async(response_msg).await.is_err() {
-                            let _ = close_sender.send(true);
-                            return;
+                        // malformed frames get an error response instead of closing the socket
+                        let response_msg = match payload {
+                            Ok(payload) => match handle_socket_payload(
+                                app.clone(),
+                                &authorization,
+                                payload,
+                                &response_sender,
+                                &session,
+                                &subscription_count,
+                                subscriptions,
+                            )
+                            .await
+                            {
+                                Ok((msg, s)) => {
+                                    _semaphore = s;
+                                    msg
+                                }
+                                Err(err) => Some(error_message(err)),
+                            },
+                            Err(err) => Some(error_message(Web3ProxyError::InvalidParam(
+                                "payload",
+                                f!("websocket frames must be utf-8. {err}").into(),
+                            ))),
                         };
 
+                        // notifications do not get a response
+                        if let Some(response_msg) = response_msg {
+                            if response_sender.send_async(response_msg).await.is_err() {
+                                let _ = close_sender.send(true);
+                                return;
+                            };
+                        }
+
                         _semaphore = None;
                     };
 
@@ -550,8 +821,42 @@ async fn read_web3_socket(
             _ = close_receiver.recv() => {
                 break;
             }
+            change = frozen_rpc_keys.recv(), if rpc_key_id.is_some() => {
+                // check_again stops requests, but subscriptions would keep sending
+                if let Ok(change) = change {
+                    if change.frozen && Some(change.rpc_key_id) == rpc_key_id {
+                        info!("closing websocket for frozen rpc key {}", change.rpc_key_id);
+                        let _ = response_sender.send_async(Message::Close(None)).await;
+                        break;
+                    }
+                }
+            }
+            x = draining.changed() => {
+                // draining is checked at the top of the loop
+                if x.is_err() {
+                    break;
+                }
+            }
+            _ = ping_interval.tick(), if ping_seconds > 0 => {
+                if last_seen.elapsed() > ping_period * 2 {
+                    info!("closing websocket that stopped answering pings");
+                    let _ = response_sender.send_async(Message::Close(None)).await;
+                    break;
+                }
+
+                if response_sender.send_async(Message::Ping(vec![])).await.is_err() {
+                    break;
+                }
+            }
         }
     }
+
+    // stop the subscriptions now. the client might resume them on another socket
+    for (_, handle) in subscriptions.write().await.drain() {
+        handle.abort();
+    }
+
+    app.close_ws_session(session);
 }
 
 async fn write_web3_socket(
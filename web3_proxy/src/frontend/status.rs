@@ -3,15 +3,18 @@
 //! For ease of development, users can currently access these endponts.
 //! They will eventually move to another port.
 
+use super::errors::Web3ProxyResponse;
 use super::{ResponseCache, ResponseCacheKey};
 use crate::app::{Web3ProxyApp, APP_USER_AGENT};
 use axum::{
     body::{Bytes, Full},
+    extract::Path,
     http::StatusCode,
     response::{IntoResponse, Response},
     Extension,
 };
 use axum_macros::debug_handler;
+use ethers::types::Chain;
 use log::trace;
 use once_cell::sync::Lazy;
 use serde_json::json;
@@ -48,7 +51,49 @@ pub async fn health(
 async fn _health(app: Arc<Web3ProxyApp>) -> (StatusCode, &'static str, Bytes) {
     trace!("health is not cached");
 
-    if app.balanced_rpcs.synced() {
+    // a draining proxy is about to exit. load balancers should send traffic elsewhere
+    if app.is_draining() {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            CONTENT_TYPE_PLAIN,
+            HEALTH_NOT_OK.clone(),
+        )
+    } else if app.config.sandbox.enabled || app.balanced_rpcs.synced() {
+        (StatusCode::OK, CONTENT_TYPE_PLAIN, HEALTH_OK.clone())
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            CONTENT_TYPE_PLAIN,
+            HEALTH_NOT_OK.clone(),
+        )
+    }
+}
+
+/// Readiness check for load balancers. Unlike `/health`, this stays unhealthy until startup warmup is done.
+#[debug_handler]
+pub async fn ready(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    Extension(cache): Extension<Arc<ResponseCache>>,
+) -> impl IntoResponse {
+    let (code, content_type, body) = cache
+        .get_or_insert_async(&ResponseCacheKey::Ready, async move { _ready(app).await })
+        .await;
+
+    Response::builder()
+        .status(code)
+        .header("content-type", content_type)
+        .body(Full::from(body))
+        .unwrap()
+}
+
+#[inline]
+async fn _ready(app: Arc<Web3ProxyApp>) -> (StatusCode, &'static str, Bytes) {
+    trace!("ready is not cached");
+
+    if !app.is_draining()
+        && app.warmup.is_ready()
+        && (app.config.sandbox.enabled || app.balanced_rpcs.synced())
+    {
         (StatusCode::OK, CONTENT_TYPE_PLAIN, HEALTH_OK.clone())
     } else {
         (
@@ -108,6 +153,114 @@ async fn _backups_needed(app: Arc<Web3ProxyApp>) -> (StatusCode, &'static str, B
     }
 }
 
+/// Every balanced rpc's health score and whether it is demoted
+#[debug_handler]
+pub async fn backends(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    Extension(cache): Extension<Arc<ResponseCache>>,
+) -> impl IntoResponse {
+    let (code, content_type, body) = cache
+        .get_or_insert_async(
+            &ResponseCacheKey::Backends,
+            async move { _backends(app).await },
+        )
+        .await;
+
+    Response::builder()
+        .status(code)
+        .header("content-type", content_type)
+        .body(Full::from(body))
+        .unwrap()
+}
+
+#[inline]
+async fn _backends(app: Arc<Web3ProxyApp>) -> (StatusCode, &'static str, Bytes) {
+    trace!("backends is not cached");
+
+    let body = json!({
+        "demotion_enabled": app.config.backend_health.enabled,
+        "backends": app.balanced_rpcs.backend_scores(),
+    });
+
+    let body = Bytes::from(body.to_string().into_bytes());
+
+    (StatusCode::OK, CONTENT_TYPE_JSON, body)
+}
+
+/// The chains this deployment serves and what they support. For SDKs and dashboards to discover what is available.
+/// The app's own chain is first. The `chains` from the config follow it. A load balancer in front of several proxies can merge them
+#[debug_handler]
+pub async fn chains(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    Extension(cache): Extension<Arc<ResponseCache>>,
+) -> impl IntoResponse {
+    let (code, content_type, body) = cache
+        .get_or_insert_async(&ResponseCacheKey::Chains, async move { _chains(app).await })
+        .await;
+
+    Response::builder()
+        .status(code)
+        .header("content-type", content_type)
+        .body(Full::from(body))
+        .unwrap()
+}
+
+#[inline]
+async fn _chains(app: Arc<Web3ProxyApp>) -> (StatusCode, &'static str, Bytes) {
+    trace!("chains is not cached");
+
+    let chain_id = app.config.chain_id;
+
+    let head_block = app
+        .watch_consensus_head_receiver
+        .borrow()
+        .as_ref()
+        .map(|x| json!({"number": x.number(), "hash": x.hash()}));
+
+    let mut body = json!({
+        "chains": [{
+            "chain_id": chain_id,
+            "name": app.chain_name(),
+            "namespaces": app.balanced_rpcs.namespaces(),
+            "archive": app.balanced_rpcs.has_archive(),
+            "rollup": app.config.rollup,
+            "l1_fee_oracle": app.config.rollup.and_then(|x| x.l1_fee_oracle()),
+            // the frontend always takes websockets. subscriptions are served from the proxy's own head block
+            "websocket": true,
+            "head_block": head_block,
+        }],
+    });
+
+    // the other chains are only served over http at /v1/:chain/:rpc_key
+    let mut extra_chains: Vec<_> = app.chains.values().collect();
+    extra_chains.sort_by_key(|x| x.chain_id);
+
+    for chain in extra_chains {
+        let head_block = chain
+            .rpcs
+            .head_block()
+            .map(|x| json!({"number": x.number(), "hash": x.hash()}));
+
+        body["chains"]
+            .as_array_mut()
+            .expect("chains is an array")
+            .push(json!({
+                "chain_id": chain.chain_id,
+                "name": Chain::try_from(chain.chain_id).ok().map(|x| x.to_string()),
+                "namespaces": chain.rpcs.namespaces(),
+                "archive": chain.rpcs.has_archive(),
+                "rollup": None::<()>,
+                "l1_fee_oracle": None::<()>,
+                "websocket": false,
+                "head_block": head_block,
+            }));
+    }
+
+    let body = Bytes::from(body.to_string().into_bytes());
+
+    (StatusCode::OK, CONTENT_TYPE_JSON, body)
+}
+
 /// Very basic status page.
 ///
 /// TODO: replace this with proper stats and monitoring. frontend uses it for their public dashboards though
@@ -137,17 +290,23 @@ async fn _status(app: Arc<Web3ProxyApp>) -> (StatusCode, &'static str, Bytes) {
     let body = json!({
         "version": APP_USER_AGENT,
         "chain_id": app.config.chain_id,
+        "profile": app.config.profile,
         "balanced_rpcs": app.balanced_rpcs,
         "private_rpcs": app.private_rpcs,
         "bundler_4337_rpcs": app.bundler_4337_rpcs,
         "hostname": app.hostname,
+        "pools": app.pool_status(),
+        "semaphores": app.semaphore_status(),
+        "warmup": app.warmup,
+        "read_only": app.read_only_status(),
+        "coalesced_requests": app.coalesced_requests,
     });
 
     let body = body.to_string().into_bytes();
 
     let body = Bytes::from(body);
 
-    let code = if app.balanced_rpcs.synced() {
+    let code = if app.config.sandbox.enabled || app.balanced_rpcs.synced() {
         StatusCode::OK
     } else {
         StatusCode::INTERNAL_SERVER_ERROR
@@ -155,3 +314,22 @@ async fn _status(app: Arc<Web3ProxyApp>) -> (StatusCode, &'static str, Bytes) {
 
     (code, CONTENT_TYPE_JSON, body)
 }
+
+/// `GET /status/snapshots/:content_hash` -- A status snapshot that an admin saved. The document never changes.
+#[debug_handler]
+pub async fn status_snapshot_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    Path(content_hash): Path<String>,
+) -> Web3ProxyResponse {
+    let snapshot = app.status_snapshot(&content_hash).await?;
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", CONTENT_TYPE_JSON)
+        .header("cache-control", "public, max-age=29030400, immutable")
+        .body(Full::from(snapshot.document))
+        .unwrap()
+        .into_response();
+
+    Ok(response)
+}
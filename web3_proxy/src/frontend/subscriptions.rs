@@ -0,0 +1,373 @@
+//! Shared upstream work for websocket `eth_subscribe`.
+//!
+//! newHeads and the pending transaction subscriptions already read from channels that the app fills once for every client.
+//! `logs` works the same way. One background task asks the backends for each new head's logs (with `eth_getLogs` by block hash)
+//! and every client's subscription filters that one list. The backends see one request per block no matter how many clients there are.
+//!
+//! The task only starts when the first logs subscription is made and it skips blocks while nobody is subscribed.
+use super::authorization::Authorization;
+use super::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::app::Web3ProxyApp;
+use crate::jsonrpc::{JsonRpcId, JsonRpcRequest};
+use crate::response_cache::JsonRpcResponseData;
+use crate::rpcs::blockchain::Web3ProxyBlock;
+use crate::rpcs::head_events::HeadEvent;
+use ethers::types::{Address, Log, H256, U64};
+use log::{debug, trace, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::timeout;
+
+/// blocks that are remembered so that their logs can be sent again with `removed: true` after a reorg
+const MAX_RECENT_BLOCKS: usize = 64;
+
+/// most skipped blocks to fetch logs for when the head jumps forward more than one block
+const MAX_MISSED_BLOCKS: u64 = 16;
+
+/// the most topic positions that a log can have
+const MAX_TOPICS: usize = 4;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> From<OneOrMany<T>> for Vec<T> {
+    fn from(x: OneOrMany<T>) -> Self {
+        match x {
+            OneOrMany::One(x) => vec![x],
+            OneOrMany::Many(x) => x,
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogsOptions {
+    address: Option<OneOrMany<Address>>,
+    topics: Vec<Option<OneOrMany<H256>>>,
+}
+
+/// The filter object in `["logs", {"address": ..., "topics": [...]}]`. Same rules as geth
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LogsFilter {
+    /// empty matches every address
+    address: Vec<Address>,
+    /// None (or an empty list) matches any topic in that position
+    topics: Vec<Option<Vec<H256>>>,
+}
+
+impl LogsFilter {
+    /// The filter for `eth_subscribe` params. None if they are not for logs
+    pub fn from_params(params: Option<&serde_json::Value>) -> Web3ProxyResult<Option<Self>> {
+        let params = match params.and_then(|x| x.as_array()) {
+            Some(x) if x.first().and_then(|x| x.as_str()) == Some("logs") => x,
+            _ => return Ok(None),
+        };
+
+        let options: LogsOptions = match params.get(1) {
+            None if params.len() == 1 => Default::default(),
+            Some(options) if params.len() == 2 => {
+                serde_json::from_value(options.clone()).map_err(|err| {
+                    Web3ProxyError::InvalidRpcParams(format!("invalid logs filter. {}", err).into())
+                })?
+            }
+            _ => {
+                return Err(Web3ProxyError::InvalidRpcParams(
+                    "logs takes at most one filter object".into(),
+                ))
+            }
+        };
+
+        if options.topics.len() > MAX_TOPICS {
+            return Err(Web3ProxyError::InvalidRpcParams(
+                format!("logs filter has more than {} topics", MAX_TOPICS).into(),
+            ));
+        }
+
+        Ok(Some(Self {
+            address: options.address.map(Into::into).unwrap_or_default(),
+            topics: options
+                .topics
+                .into_iter()
+                .map(|x| x.map(Into::into))
+                .collect(),
+        }))
+    }
+
+    pub fn matches(&self, log: &Log) -> bool {
+        if !self.address.is_empty() && !self.address.contains(&log.address) {
+            return false;
+        }
+
+        self.topics
+            .iter()
+            .enumerate()
+            .all(|(i, wanted)| match wanted {
+                Some(wanted) if !wanted.is_empty() => log
+                    .topics
+                    .get(i)
+                    .map(|x| wanted.contains(x))
+                    .unwrap_or(false),
+                _ => true,
+            })
+    }
+}
+
+/// A block's logs, remembered in case the block is reorged out
+struct RecentBlock {
+    hash: H256,
+    number: U64,
+    logs: Arc<Vec<Log>>,
+}
+
+/// Upstream work shared by every websocket subscription on this app
+#[derive(Debug)]
+pub struct SubscriptionManager {
+    /// every log of every new block. subscriptions filter these themselves
+    logs_sender: broadcast::Sender<Arc<Vec<Log>>>,
+    logs_started: AtomicBool,
+}
+
+impl Default for SubscriptionManager {
+    fn default() -> Self {
+        // one message per block. a client this far behind is dropping logs anyways
+        let (logs_sender, _) = broadcast::channel(256);
+
+        Self {
+            logs_sender,
+            logs_started: AtomicBool::new(false),
+        }
+    }
+}
+
+impl SubscriptionManager {
+    /// Receive the logs of every new block. Logs from blocks that were reorged out are sent again with `removed: true`.
+    /// Starts the shared upstream task the first time it is called
+    pub fn subscribe_logs(&self, app: &Arc<Web3ProxyApp>) -> broadcast::Receiver<Arc<Vec<Log>>> {
+        // subscribe before starting so that the task never sees zero receivers on its first block
+        let receiver = self.logs_sender.subscribe();
+
+        if self
+            .logs_started
+            .compare_exchange(
+                false,
+                true,
+                atomic::Ordering::AcqRel,
+                atomic::Ordering::Acquire,
+            )
+            .is_ok()
+        {
+            let app = app.clone();
+            let logs_sender = self.logs_sender.clone();
+
+            tokio::spawn(async move {
+                if let Err(err) = logs_fanout(app, logs_sender).await {
+                    warn!("logs subscriptions stopped: {:?}", err);
+                }
+            });
+        }
+
+        receiver
+    }
+
+    /// How many logs subscriptions are open across every socket
+    pub fn logs_subscribers(&self) -> usize {
+        self.logs_sender.receiver_count()
+    }
+}
+
+/// Fetch the logs of every new head once and send them to every logs subscription
+async fn logs_fanout(
+    app: Arc<Web3ProxyApp>,
+    logs_sender: broadcast::Sender<Arc<Vec<Log>>>,
+) -> Web3ProxyResult<()> {
+    let authorization = Arc::new(Authorization::internal(app.db_conn())?);
+
+    let mut head_events = app.head_events();
+
+    let mut recent: VecDeque<RecentBlock> = VecDeque::with_capacity(MAX_RECENT_BLOCKS);
+
+    loop {
+        let new_head = match head_events.recv().await {
+            Ok(HeadEvent::NewHead(x)) | Ok(HeadEvent::Reorg { new: x, .. }) => x,
+            Ok(HeadEvent::Finalized(_)) => continue,
+            Err(broadcast::error::RecvError::Lagged(x)) => {
+                // the missed blocks are filled in below
+                warn!("logs subscriptions lagged {} head events", x);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+
+        if logs_sender.receiver_count() == 0 {
+            // nobody would see the removed logs either
+            recent.clear();
+            continue;
+        }
+
+        // blocks that are no longer on the canonical chain
+        let mut removed = vec![];
+
+        while let Some(x) = recent.back() {
+            let orphaned = if x.number >= *new_head.number() {
+                x.hash != *new_head.hash() || x.number > *new_head.number()
+            } else if x.number + U64::one() == *new_head.number() {
+                x.hash != *new_head.parent_hash()
+            } else {
+                app.balanced_rpcs
+                    .cached_block_by_number(&x.number)
+                    .map(|canonical| canonical.hash() != &x.hash)
+                    .unwrap_or(false)
+            };
+
+            if !orphaned {
+                break;
+            }
+
+            removed.push(recent.pop_back().expect("back was just checked"));
+        }
+
+        if recent.back().map(|x| x.hash) == Some(*new_head.hash()) {
+            // already sent
+            continue;
+        }
+
+        if !removed.is_empty() {
+            trace!("{} blocks of logs removed by a reorg", removed.len());
+
+            // newest first, the same as geth
+            let removed_logs: Vec<Log> = removed
+                .iter()
+                .flat_map(|x| x.logs.iter())
+                .cloned()
+                .map(|mut x| {
+                    x.removed = Some(true);
+                    x
+                })
+                .collect();
+
+            if !removed_logs.is_empty() {
+                let _ = logs_sender.send(Arc::new(removed_logs));
+            }
+        }
+
+        let mut blocks = vec![];
+
+        if let Some(last) = recent.back() {
+            let first = (last.number + U64::one())
+                .max(new_head.number().saturating_sub(MAX_MISSED_BLOCKS.into()));
+
+            let mut num = first;
+            while num < *new_head.number() {
+                match app.balanced_rpcs.cached_block_by_number(&num) {
+                    Some(x) => blocks.push(x),
+                    None => debug!("no cached block {} for logs subscriptions", num),
+                }
+
+                num += U64::one();
+            }
+        }
+
+        blocks.push(new_head);
+
+        for block in blocks {
+            let logs = match block_logs(&app, &authorization, &block).await {
+                Ok(x) => Arc::new(x),
+                Err(err) => {
+                    warn!("unable to get logs for {}: {:?}", block.hash(), err);
+                    continue;
+                }
+            };
+
+            if !logs.is_empty() {
+                let _ = logs_sender.send(logs.clone());
+            }
+
+            if recent.len() == MAX_RECENT_BLOCKS {
+                recent.pop_front();
+            }
+
+            recent.push_back(RecentBlock {
+                hash: *block.hash(),
+                number: *block.number(),
+                logs,
+            });
+        }
+    }
+}
+
+/// All the logs of one block. Asked by hash so that a backend on a different fork can't answer with the wrong block's logs
+async fn block_logs(
+    app: &Web3ProxyApp,
+    authorization: &Arc<Authorization>,
+    block: &Web3ProxyBlock,
+) -> Web3ProxyResult<Vec<Log>> {
+    let request = JsonRpcRequest::new(
+        JsonRpcId::Number(1),
+        "eth_getLogs".to_string(),
+        Some(json!([{ "blockHash": block.hash() }])),
+    )?;
+
+    let response = timeout(
+        Duration::from_secs(30),
+        app.balanced_rpcs.try_send_best_connection(
+            authorization,
+            &request,
+            None,
+            Some(block.number()),
+            Some(block.number()),
+        ),
+    )
+    .await??;
+
+    match response {
+        JsonRpcResponseData::Result { value, .. } => Ok(serde_json::from_str(value.get())?),
+        JsonRpcResponseData::Error { value, .. } => {
+            Err(Web3ProxyError::BadResponse(value.message.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logs_filter() {
+        let token = Address::from_low_u64_be(1);
+        let transfer = H256::from_low_u64_be(2);
+        let approval = H256::from_low_u64_be(3);
+        let alice = H256::from_low_u64_be(4);
+
+        let log = Log {
+            address: token,
+            topics: vec![transfer, alice],
+            ..Default::default()
+        };
+
+        let filter =
+            |params: serde_json::Value| LogsFilter::from_params(Some(&params)).unwrap().unwrap();
+
+        assert!(filter(json!(["logs"])).matches(&log));
+        assert!(filter(json!(["logs", { "address": token }])).matches(&log));
+        assert!(!filter(json!(["logs", { "address": [Address::zero()] }])).matches(&log));
+        assert!(filter(json!(["logs", { "topics": [[approval, transfer]] }])).matches(&log));
+        assert!(filter(json!(["logs", { "topics": [null, alice] }])).matches(&log));
+        assert!(!filter(json!(["logs", { "topics": [approval] }])).matches(&log));
+        assert!(!filter(json!(["logs", { "topics": [null, null, alice] }])).matches(&log));
+
+        assert_eq!(
+            LogsFilter::from_params(Some(&json!(["newHeads"]))).unwrap(),
+            None
+        );
+        assert!(LogsFilter::from_params(Some(&json!(["logs", { "blocks": 1 }]))).is_err());
+    }
+}
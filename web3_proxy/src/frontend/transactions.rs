@@ -0,0 +1,137 @@
+//! What the proxy knows about transactions that were sent through it.
+use super::authorization::ip_is_authorized;
+use super::errors::{Web3ProxyError, Web3ProxyResponse};
+use super::rpc_proxy_ws::ProxyMode;
+use crate::app::Web3ProxyApp;
+use axum::extract::Path;
+use axum::headers::Origin;
+use axum::TypedHeader;
+use axum::{response::IntoResponse, Extension, Json};
+use axum_client_ip::InsecureClientIp;
+use axum_macros::debug_handler;
+use chrono::Utc;
+use entities::sea_orm_active_enums::TxJournalStatus;
+use ethers::types::H256;
+use serde_json::json;
+use std::sync::Arc;
+
+/// `GET /v1/:chain/tx_journal/:tx_hash` -- Get the broadcast status of a transaction sent with `eth_sendRawTransaction`.
+/// Only transactions sent while `tx_journal` was enabled are known.
+/// Rate limited by IP the same as the public rpc.
+#[debug_handler]
+pub async fn tx_journal_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    Path((chain_id, tx_hash)): Path<(u64, H256)>,
+) -> Web3ProxyResponse {
+    if chain_id != app.config.chain_id {
+        return Err(Web3ProxyError::NotFound);
+    }
+
+    let origin = origin.map(|x| x.0);
+
+    // the permit is held until the response is ready
+    let (_authorization, _semaphore) = ip_is_authorized(&app, ip, origin, ProxyMode::Best).await?;
+
+    let entry = app
+        .tx_journal_entry(tx_hash)
+        .await?
+        .ok_or(Web3ProxyError::NotFound)?;
+
+    let response_json = json!({
+        "chain_id": chain_id,
+        "tx_hash": tx_hash,
+        "status": entry.status,
+        "attempts": entry.attempts,
+        "last_error": entry.last_error,
+        "created_at": entry.created_at,
+        "updated_at": entry.updated_at,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `GET /v1/:chain/tx_status/:tx_hash` -- Follow a transaction sent with `eth_sendRawTransaction` after it was broadcast.
+/// This proxy's tracker knows the most. Transactions sent through other proxies only have what the journal saved.
+/// Rate limited by IP the same as the public rpc.
+#[debug_handler]
+pub async fn tx_status_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    Path((chain_id, tx_hash)): Path<(u64, H256)>,
+) -> Web3ProxyResponse {
+    if chain_id != app.config.chain_id {
+        return Err(Web3ProxyError::NotFound);
+    }
+
+    let origin = origin.map(|x| x.0);
+
+    // the permit is held until the response is ready
+    let (_authorization, _semaphore) = ip_is_authorized(&app, ip, origin, ProxyMode::Best).await?;
+
+    if let Some(tracked) = app.tracked_transaction(&tx_hash) {
+        let mut response_json = json!(tracked);
+
+        response_json["chain_id"] = json!(chain_id);
+        response_json["source"] = json!("tracker");
+
+        return Ok(Json(response_json).into_response());
+    }
+
+    if !app.config.tx_journal.enabled {
+        return Err(Web3ProxyError::NotFound);
+    }
+
+    let entry = app
+        .tx_journal_entry(tx_hash)
+        .await?
+        .ok_or(Web3ProxyError::NotFound)?;
+
+    let status = match entry.status {
+        TxJournalStatus::Pending => "received",
+        TxJournalStatus::Broadcast => "broadcast",
+        TxJournalStatus::Failed => "rejected",
+    };
+
+    let response_json = json!({
+        "chain_id": chain_id,
+        "tx_hash": tx_hash,
+        "status": status,
+        "last_error": entry.last_error,
+        "received_at": entry.created_at,
+        "updated_at": entry.updated_at,
+        "source": "journal",
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `GET /v1/:chain/mempool` -- Get the pending and queued transaction counts of a chain's backends.
+/// The counts are saved every `mempool_stats.poll_seconds`, so polling this never reaches a backend.
+/// Rate limited by IP the same as the public rpc.
+#[debug_handler]
+pub async fn mempool_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    origin: Option<TypedHeader<Origin>>,
+    Path(chain_id): Path<u64>,
+) -> Web3ProxyResponse {
+    let origin = origin.map(|x| x.0);
+
+    // the permit is held until the response is ready
+    let (_authorization, _semaphore) = ip_is_authorized(&app, ip, origin, ProxyMode::Best).await?;
+
+    let stats = app
+        .mempool_stats(chain_id)
+        .ok_or(Web3ProxyError::NotFound)?;
+
+    let age_seconds = (Utc::now().timestamp() - stats.updated_at).max(0);
+
+    let mut response_json = json!(stats);
+
+    response_json["age_seconds"] = json!(age_seconds);
+
+    Ok(Json(response_json).into_response())
+}
@@ -1,8 +1,10 @@
 //! Handle registration, logins, and managing account data.
 use crate::app::Web3ProxyApp;
 use crate::frontend::authorization::{login_is_authorized, RpcSecretKey};
-use crate::frontend::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse};
-use crate::user_token::UserBearerToken;
+use crate::frontend::errors::{
+    Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse, Web3ProxyResult,
+};
+use crate::user_token::{UserBearerToken, UserRefreshToken};
 use crate::{PostLogin, PostLoginQuery};
 use axum::{
     extract::{Path, Query},
@@ -14,16 +16,17 @@ use axum_client_ip::InsecureClientIp;
 use axum_macros::debug_handler;
 use chrono::{TimeZone, Utc};
 use entities;
-use entities::{balance, login, pending_login, referee, referrer, rpc_key, user};
+use entities::{balance, login, pending_login, referee, referrer, refresh_token, rpc_key, user};
 use ethers::{prelude::Address, types::Bytes};
 use hashbrown::HashMap;
 use http::StatusCode;
 use log::{debug, warn};
 use migration::sea_orm::prelude::{Decimal, Uuid};
 use migration::sea_orm::{
-    self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter,
-    TransactionTrait,
+    self, ActiveModelTrait, ColumnTrait, DatabaseTransaction, EntityTrait, IntoActiveModel,
+    QueryFilter, TransactionTrait,
 };
+use serde::Deserialize;
 use serde_json::json;
 use siwe::{Message, VerificationOpts};
 use std::ops::Add;
@@ -249,7 +252,7 @@ pub async fn user_login_post(
 
     let db_conn = app.db_conn().web3_context("login requires a db")?;
 
-    let (caller, user_rpc_keys, status_code) = match caller {
+    let (caller, user_rpc_keys, new_rpc_secret_key, status_code) = match caller {
         None => {
             // user does not exist yet
 
@@ -285,7 +288,8 @@ pub async fn user_login_post(
 
             let user_rpc_key = rpc_key::ActiveModel {
                 user_id: sea_orm::Set(caller.id),
-                secret_key: sea_orm::Set(rpc_secret_key.into()),
+                secret_key: sea_orm::Set(None),
+                secret_key_hash: sea_orm::Set(Some(app.rpc_key_hasher.hash(rpc_secret_key))),
                 description: sea_orm::Set(None),
                 ..Default::default()
             };
@@ -304,6 +308,8 @@ pub async fn user_login_post(
             };
             user_balance.insert(&txn).await?;
 
+            let new_rpc_secret_key = Some((user_rpc_key.id, rpc_secret_key));
+
             let user_rpc_keys = vec![user_rpc_key];
 
             // Also add a part for the invite code, i.e. who invited this guy
@@ -338,7 +344,12 @@ pub async fn user_login_post(
             }
             txn.commit().await?;
 
-            (caller, user_rpc_keys, StatusCode::CREATED)
+            (
+                caller,
+                user_rpc_keys,
+                new_rpc_secret_key,
+                StatusCode::CREATED,
+            )
         }
         Some(caller) => {
             // Let's say that a user that exists can actually also redeem a key in retrospect...
@@ -352,10 +363,7 @@ pub async fn user_login_post(
                     .filter(referrer::Column::ReferralCode.eq(referral_code))
                     .one(db_replica.conn())
                     .await?
-                    .ok_or(Web3ProxyError::BadRequest(format!(
-                        "The referral_link you provided does not exist {}",
-                        referral_code
-                    )))?;
+                    .ok_or(Web3ProxyError::UnknownReferralCode)?;
 
                 // Create a new item in the database,
                 // marking this guy as the referrer (and ignoring a duplicate insert, if there is any...)
@@ -379,45 +387,54 @@ pub async fn user_login_post(
                 .await
                 .web3_context("failed loading user's key")?;
 
-            (caller, user_rpc_keys, StatusCode::OK)
+            (caller, user_rpc_keys, None, StatusCode::OK)
         }
     };
 
     // create a bearer token for the user.
     let user_bearer_token = UserBearerToken::default();
+    let user_refresh_token = UserRefreshToken::new()?;
+
+    // only hashes of secret keys are saved. a new key's secret is shown here and nowhere else
+    let mut rpc_keys = HashMap::with_capacity(user_rpc_keys.len());
+    for user_rpc_key in user_rpc_keys {
+        let id = user_rpc_key.id;
+
+        let mut user_rpc_key = serde_json::to_value(user_rpc_key)?;
+
+        if let Some((new_id, rpc_secret_key)) = new_rpc_secret_key {
+            if new_id == id {
+                user_rpc_key["secret_key"] = json!(rpc_secret_key.to_string());
+            }
+        }
+
+        rpc_keys.insert(id, user_rpc_key);
+    }
 
     // json response with everything in it
     // we could return just the bearer token, but I think they will always request api keys and the user profile
     let response_json = json!({
-        "rpc_keys": user_rpc_keys
-            .into_iter()
-            .map(|user_rpc_key| (user_rpc_key.id, user_rpc_key))
-            .collect::<HashMap<_, _>>(),
+        "rpc_keys": rpc_keys,
         "bearer_token": user_bearer_token,
-        "user": caller,
+        "refresh_token": user_refresh_token,
+        "user": app.unseal_user(caller.clone())?,
     });
 
     let response = (status_code, Json(response_json)).into_response();
 
     // add bearer to the database
+    let txn = db_conn.begin().await?;
 
-    // expire in 4 weeks
-    let expires_at = Utc::now()
-        .checked_add_signed(chrono::Duration::weeks(4))
-        .unwrap();
-
-    let user_login = login::ActiveModel {
-        id: sea_orm::NotSet,
-        bearer_token: sea_orm::Set(user_bearer_token.uuid()),
-        user_id: sea_orm::Set(caller.id),
-        expires_at: sea_orm::Set(expires_at),
-        read_only: sea_orm::Set(false),
-    };
+    save_session(
+        &app,
+        &txn,
+        caller.id,
+        &user_bearer_token,
+        &user_refresh_token,
+    )
+    .await?;
 
-    user_login
-        .save(&db_conn)
-        .await
-        .web3_context("saving user login")?;
+    txn.commit().await?;
 
     if let Err(err) = user_pending_login
         .into_active_model()
@@ -450,6 +467,19 @@ pub async fn user_logout_post(
         debug!("Failed to delete {}: {}", user_bearer.redis_key(), err);
     }
 
+    // the refresh token that came with this login can not bring it back
+    if let Err(err) = refresh_token::Entity::delete_many()
+        .filter(refresh_token::Column::BearerToken.eq(user_bearer.uuid()))
+        .exec(&db_conn)
+        .await
+    {
+        debug!(
+            "Failed to delete refresh token for {}: {}",
+            user_bearer.redis_key(),
+            err
+        );
+    }
+
     let now = Utc::now();
 
     // also delete any expired logins
@@ -471,3 +501,158 @@ pub async fn user_logout_post(
     // TODO: what should the response be? probably json something
     Ok("goodbye".into_response())
 }
+
+/// JSON body to our `user_refresh_post` handler.
+#[derive(Debug, Deserialize)]
+pub struct PostRefresh {
+    refresh_token: UserRefreshToken,
+}
+
+/// `POST /user/refresh` - Trade a refresh token for a new bearer token and a new refresh token.
+/// Each refresh token only works once. The bearer token that came with it stops working.
+///
+/// Rate limited by IP address like the login endpoints.
+#[debug_handler]
+pub async fn user_refresh_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    InsecureClientIp(ip): InsecureClientIp,
+    Json(payload): Json<PostRefresh>,
+) -> Web3ProxyResponse {
+    login_is_authorized(&app, ip).await?;
+
+    let db_conn = app
+        .db_conn()
+        .web3_context("refreshing a login requires a db")?;
+
+    let txn = db_conn.begin().await?;
+
+    let old_refresh_token = refresh_token::Entity::find()
+        .filter(refresh_token::Column::TokenHash.eq(payload.refresh_token.hash()))
+        .one(&txn)
+        .await?
+        .ok_or(Web3ProxyError::InvalidRefreshToken)?;
+
+    // deleting in the same transaction keeps two requests from both using the token
+    let deleted = refresh_token::Entity::delete_by_id(old_refresh_token.id)
+        .exec(&txn)
+        .await?;
+
+    if deleted.rows_affected == 0 {
+        return Err(Web3ProxyError::InvalidRefreshToken);
+    }
+
+    // the old login is replaced
+    login::Entity::delete_many()
+        .filter(login::Column::BearerToken.eq(old_refresh_token.bearer_token))
+        .exec(&txn)
+        .await?;
+
+    if old_refresh_token.expires_at <= Utc::now() {
+        // save the deletes. expired tokens are only cleaned up when they are used
+        txn.commit().await?;
+
+        return Err(Web3ProxyError::InvalidRefreshToken);
+    }
+
+    let user_bearer_token = UserBearerToken::default();
+    let user_refresh_token = UserRefreshToken::new()?;
+
+    save_session(
+        &app,
+        &txn,
+        old_refresh_token.user_id,
+        &user_bearer_token,
+        &user_refresh_token,
+    )
+    .await?;
+
+    txn.commit().await?;
+
+    let response_json = json!({
+        "bearer_token": user_bearer_token,
+        "refresh_token": user_refresh_token,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `POST /user/sessions/revoke` - Log out everywhere. Every bearer token and refresh token for the user stops working.
+/// This includes the bearer token in the `Authentication` header.
+#[debug_handler]
+pub async fn user_sessions_revoke_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app
+        .db_conn()
+        .web3_context("revoking sessions requires a db")?;
+
+    let txn = db_conn.begin().await?;
+
+    let refresh_tokens = refresh_token::Entity::delete_many()
+        .filter(refresh_token::Column::UserId.eq(user.id))
+        .exec(&txn)
+        .await?;
+
+    let logins = login::Entity::delete_many()
+        .filter(login::Column::UserId.eq(user.id))
+        .exec(&txn)
+        .await?;
+
+    txn.commit().await?;
+
+    let response_json = json!({
+        "logins_revoked": logins.rows_affected,
+        "refresh_tokens_revoked": refresh_tokens.rows_affected,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// Save a login and the refresh token that can replace it
+async fn save_session(
+    app: &Web3ProxyApp,
+    txn: &DatabaseTransaction,
+    user_id: u64,
+    user_bearer_token: &UserBearerToken,
+    user_refresh_token: &UserRefreshToken,
+) -> Web3ProxyResult<()> {
+    let now = Utc::now();
+
+    // expire in 4 weeks
+    let expires_at = now.checked_add_signed(chrono::Duration::weeks(4)).unwrap();
+
+    let user_login = login::ActiveModel {
+        id: sea_orm::NotSet,
+        bearer_token: sea_orm::Set(user_bearer_token.uuid()),
+        user_id: sea_orm::Set(user_id),
+        expires_at: sea_orm::Set(expires_at),
+        read_only: sea_orm::Set(false),
+    };
+
+    user_login
+        .save(txn)
+        .await
+        .web3_context("saving user login")?;
+
+    let refresh_expires_at = now
+        .checked_add_signed(chrono::Duration::days(app.config.refresh_token_days as i64))
+        .web3_context("refresh_token_days is too large")?;
+
+    let user_refresh_token = refresh_token::ActiveModel {
+        token_hash: sea_orm::Set(user_refresh_token.hash()),
+        user_id: sea_orm::Set(user_id),
+        bearer_token: sea_orm::Set(user_bearer_token.uuid()),
+        expires_at: sea_orm::Set(refresh_expires_at),
+        ..Default::default()
+    };
+
+    user_refresh_token
+        .save(txn)
+        .await
+        .web3_context("saving refresh token")?;
+
+    Ok(())
+}
@@ -1,7 +1,10 @@
 //! Handle registration, logins, and managing account data.
 pub mod authentication;
+pub mod org;
 pub mod payment;
+pub mod presigned_urls;
 pub mod referral;
+pub mod rpc_key_pools;
 pub mod rpc_keys;
 pub mod stats;
 pub mod subuser;
@@ -33,6 +36,8 @@ pub async fn user_get(
 ) -> Web3ProxyResponse {
     let (user, _semaphore) = app.bearer_is_authorized(bearer_token).await?;
 
+    let user = app.unseal_user(user)?;
+
     Ok(Json(user).into_response())
 }
 
@@ -62,7 +67,7 @@ pub async fn user_post(
             // TODO: do some basic validation
             // TODO: don't set immediatly, send a confirmation email first
             // TODO: compare first? or is sea orm smart enough to do that for us?
-            user.email = sea_orm::Set(Some(x));
+            user.email = sea_orm::Set(Some(app.seal_row(x)?));
         }
     }
 
@@ -79,5 +84,7 @@ pub async fn user_post(
 
     let user: user::Model = user.try_into().web3_context("Returning updated user")?;
 
+    let user = app.unseal_user(user)?;
+
     Ok(Json(user).into_response())
 }
@@ -0,0 +1,1025 @@
+//! Organizations group users so that they can share a balance and keys.
+//!
+//! Org keys belong to the org's owner (so they share the owner's tier and rate limits) but are billed to the org's balance.
+//! Members fund the org by moving credits from their own balance.
+use super::super::authorization::RpcSecretKey;
+use super::super::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse};
+use crate::app::Web3ProxyApp;
+use crate::http_params::{ExportFormat, OrgBillingExportParams, Params};
+use axum::extract::Path;
+use axum::{
+    headers::{authorization::Bearer, Authorization},
+    response::IntoResponse,
+    Extension, Json, TypedHeader,
+};
+use axum_macros::debug_handler;
+use chrono::{DateTime, Months, Utc};
+use entities::sea_orm_active_enums::{OrgRole, TrackingLevel};
+use entities::{balance, org, org_invite, org_member, rpc_accounting_v2, rpc_key, user};
+use ethers::types::Address;
+use http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use migration::sea_orm::prelude::Decimal;
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, ConnectionTrait, EntityTrait, FromQueryResult,
+    IntoActiveModel, QueryFilter, QuerySelect, TransactionTrait, TryIntoModel,
+};
+use migration::Expr;
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+/// How long an invite can be accepted for
+pub const ORG_INVITE_DAYS: i64 = 7;
+
+/// Owners and billing admins can add funds and see what the org has spent
+pub fn org_role_can_view_balance(role: &OrgRole) -> bool {
+    matches!(role, OrgRole::Owner | OrgRole::BillingAdmin)
+}
+
+/// Owners and developers can create and change the org's keys
+pub fn org_role_can_manage_keys(role: &OrgRole) -> bool {
+    matches!(role, OrgRole::Owner | OrgRole::Developer)
+}
+
+/// The owner can remove anyone else. Other members can only remove themselves. The owner has to transfer ownership before leaving
+pub fn org_member_can_remove(
+    role: &OrgRole,
+    user_id: u64,
+    member_id: u64,
+) -> Result<(), Web3ProxyError> {
+    if member_id == user_id {
+        if *role == OrgRole::Owner {
+            return Err(Web3ProxyError::InvalidParam(
+                "user_id",
+                "transfer ownership before leaving".into(),
+            ));
+        }
+    } else if *role != OrgRole::Owner {
+        return Err(Web3ProxyError::AccessDenied);
+    }
+
+    Ok(())
+}
+
+/// The user's role in the org. Errors if they are not a member
+pub async fn org_member_role<C: ConnectionTrait>(
+    db: &C,
+    org_id: u64,
+    user_id: u64,
+) -> Result<OrgRole, Web3ProxyError> {
+    org_member::Entity::find()
+        .filter(org_member::Column::OrgId.eq(org_id))
+        .filter(org_member::Column::UserId.eq(user_id))
+        .one(db)
+        .await?
+        .map(|x| x.role)
+        .ok_or(Web3ProxyError::AccessDenied)
+}
+
+/// `GET /user/orgs` -- Use a bearer token to get the orgs that the user is a member of.
+#[debug_handler]
+pub async fn orgs_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app
+        .db_replica()
+        .web3_context("db_replica is required to fetch a user's orgs")?;
+
+    let orgs: Vec<_> = org_member::Entity::find()
+        .filter(org_member::Column::UserId.eq(user.id))
+        .find_also_related(org::Entity)
+        .all(db_replica.conn())
+        .await
+        .web3_context("failed loading user's orgs")?
+        .into_iter()
+        .filter_map(|(member, org)| {
+            let org = org?;
+
+            Some(json!({
+                "org_id": org.id,
+                "name": org.name,
+                "owner_id": org.owner_id,
+                "role": member.role,
+            }))
+        })
+        .collect();
+
+    let response_json = json!({
+        "user_id": user.id,
+        "orgs": orgs,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OrgPost {
+    name: String,
+}
+
+/// `POST /user/orgs` -- Use a bearer token to create an org. The user becomes its owner.
+#[debug_handler]
+pub async fn org_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<OrgPost>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let name = payload.name.trim();
+
+    if name.is_empty() {
+        return Err(Web3ProxyError::InvalidParam(
+            "name",
+            "must not be empty".into(),
+        ));
+    }
+
+    let db_conn = app.db_conn().web3_context("creating an org needs a db")?;
+
+    let txn = db_conn.begin().await?;
+
+    let new_org = org::ActiveModel {
+        name: sea_orm::Set(name.to_string()),
+        owner_id: sea_orm::Set(user.id),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+
+    org_member::ActiveModel {
+        org_id: sea_orm::Set(new_org.id),
+        user_id: sea_orm::Set(user.id),
+        role: sea_orm::Set(OrgRole::Owner),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+
+    txn.commit().await?;
+
+    Ok(Json(new_org).into_response())
+}
+
+/// `GET /user/orgs/:org_id` -- Use a bearer token to see an org's members and keys.
+/// The balance is only shown to owners and billing admins.
+#[debug_handler]
+pub async fn org_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(org_id): Path<u64>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app
+        .db_replica()
+        .web3_context("db_replica is required to fetch an org")?;
+
+    let role = org_member_role(db_replica.conn(), org_id, user.id).await?;
+
+    let org = org::Entity::find_by_id(org_id)
+        .one(db_replica.conn())
+        .await?
+        .ok_or(Web3ProxyError::NotFound)?;
+
+    let members: Vec<_> = org_member::Entity::find()
+        .filter(org_member::Column::OrgId.eq(org_id))
+        .find_also_related(user::Entity)
+        .all(db_replica.conn())
+        .await
+        .web3_context("failed loading org members")?
+        .into_iter()
+        .map(|(member, member_user)| {
+            json!({
+                "user_id": member.user_id,
+                "address": member_user.map(|x| Address::from_slice(&x.address)),
+                "role": member.role,
+            })
+        })
+        .collect();
+
+    // secrets are never shown after a key is created
+    let keys: Vec<_> = rpc_key::Entity::find()
+        .filter(rpc_key::Column::OrgId.eq(org_id))
+        .all(db_replica.conn())
+        .await
+        .web3_context("failed loading org keys")?
+        .into_iter()
+        .map(|x| {
+            json!({
+                "rpc_key_id": x.id,
+                "description": x.description,
+                "project_tag": x.project_tag,
+                "active": x.active,
+            })
+        })
+        .collect();
+
+    let mut response_json = json!({
+        "org_id": org.id,
+        "name": org.name,
+        "owner_id": org.owner_id,
+        "role": role,
+        "members": members,
+        "rpc_keys": keys,
+    });
+
+    if org_role_can_view_balance(&role) {
+        response_json["available_balance"] = json!(org.available_balance);
+        response_json["used_balance"] = json!(org.used_balance);
+    }
+
+    Ok(Json(response_json).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OrgInvitePost {
+    address: Address,
+    role: OrgRole,
+}
+
+/// `POST /user/orgs/:org_id/invites` -- Use a bearer token to invite an address to the org. Only the owner can invite.
+/// Inviting the same address again replaces the old invite.
+#[debug_handler]
+pub async fn org_invite_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(org_id): Path<u64>,
+    Json(payload): Json<OrgInvitePost>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    if payload.role == OrgRole::Owner {
+        return Err(Web3ProxyError::InvalidParam(
+            "role",
+            "use POST /user/orgs/:org_id/transfer to change the owner".into(),
+        ));
+    }
+
+    let db_conn = app
+        .db_conn()
+        .web3_context("inviting to an org needs a db")?;
+
+    if org_member_role(&db_conn, org_id, user.id).await? != OrgRole::Owner {
+        return Err(Web3ProxyError::AccessDenied);
+    }
+
+    let address = payload.address.as_bytes().to_vec();
+
+    let txn = db_conn.begin().await?;
+
+    org_invite::Entity::delete_many()
+        .filter(org_invite::Column::OrgId.eq(org_id))
+        .filter(org_invite::Column::Address.eq(address.clone()))
+        .exec(&txn)
+        .await?;
+
+    let invite = org_invite::ActiveModel {
+        org_id: sea_orm::Set(org_id),
+        address: sea_orm::Set(address),
+        role: sea_orm::Set(payload.role),
+        invited_by: sea_orm::Set(user.id),
+        expires_at: sea_orm::Set(Utc::now() + chrono::Duration::days(ORG_INVITE_DAYS)),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+
+    txn.commit().await?;
+
+    Ok(Json(invite).into_response())
+}
+
+/// `GET /user/org_invites` -- Use a bearer token to see the unexpired invites for the user's address.
+#[debug_handler]
+pub async fn org_invites_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app
+        .db_replica()
+        .web3_context("db_replica is required to fetch a user's invites")?;
+
+    let invites: Vec<_> = org_invite::Entity::find()
+        .filter(org_invite::Column::Address.eq(user.address.clone()))
+        .filter(org_invite::Column::ExpiresAt.gt(Utc::now()))
+        .find_also_related(org::Entity)
+        .all(db_replica.conn())
+        .await
+        .web3_context("failed loading user's invites")?
+        .into_iter()
+        .map(|(invite, org)| {
+            json!({
+                "invite_id": invite.id,
+                "org_id": invite.org_id,
+                "org_name": org.map(|x| x.name),
+                "role": invite.role,
+                "invited_by": invite.invited_by,
+                "expires_at": invite.expires_at,
+            })
+        })
+        .collect();
+
+    let response_json = json!({
+        "user_id": user.id,
+        "invites": invites,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `POST /user/org_invites/:invite_id` -- Use a bearer token to accept an invite.
+#[debug_handler]
+pub async fn org_invite_accept(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(invite_id): Path<u64>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().web3_context("joining an org needs a db")?;
+
+    let invite = org_invite::Entity::find_by_id(invite_id)
+        .filter(org_invite::Column::Address.eq(user.address.clone()))
+        .one(&db_conn)
+        .await?
+        .ok_or(Web3ProxyError::NotFound)?;
+
+    if invite.expires_at <= Utc::now() {
+        return Err(Web3ProxyError::NotFound);
+    }
+
+    let txn = db_conn.begin().await?;
+
+    // an existing member keeps their membership. the invite only changes their role
+    let member = match org_member::Entity::find()
+        .filter(org_member::Column::OrgId.eq(invite.org_id))
+        .filter(org_member::Column::UserId.eq(user.id))
+        .one(&txn)
+        .await?
+    {
+        Some(x) if x.role == OrgRole::Owner => x,
+        Some(x) => {
+            let mut x = x.into_active_model();
+            x.role = sea_orm::Set(invite.role.clone());
+            x.update(&txn).await?
+        }
+        None => {
+            org_member::ActiveModel {
+                org_id: sea_orm::Set(invite.org_id),
+                user_id: sea_orm::Set(user.id),
+                role: sea_orm::Set(invite.role.clone()),
+                ..Default::default()
+            }
+            .insert(&txn)
+            .await?
+        }
+    };
+
+    org_invite::Entity::delete_by_id(invite.id)
+        .exec(&txn)
+        .await?;
+
+    txn.commit().await?;
+
+    Ok(Json(member).into_response())
+}
+
+/// `DELETE /user/org_invites/:invite_id` -- Use a bearer token to decline an invite.
+#[debug_handler]
+pub async fn org_invite_delete(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(invite_id): Path<u64>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app
+        .db_conn()
+        .web3_context("declining an invite needs a db")?;
+
+    let deleted = org_invite::Entity::delete_many()
+        .filter(org_invite::Column::Id.eq(invite_id))
+        .filter(org_invite::Column::Address.eq(user.address.clone()))
+        .exec(&db_conn)
+        .await?
+        .rows_affected;
+
+    if deleted == 0 {
+        return Err(Web3ProxyError::NotFound);
+    }
+
+    Ok(Json(json!({ "invite_id": invite_id, "deleted": true })).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OrgMemberPut {
+    role: OrgRole,
+}
+
+/// `PUT /user/orgs/:org_id/members/:user_id` -- Use a bearer token to change a member's role. Only the owner can do this.
+#[debug_handler]
+pub async fn org_member_put(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path((org_id, member_id)): Path<(u64, u64)>,
+    Json(payload): Json<OrgMemberPut>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    if payload.role == OrgRole::Owner {
+        return Err(Web3ProxyError::InvalidParam(
+            "role",
+            "use POST /user/orgs/:org_id/transfer to change the owner".into(),
+        ));
+    }
+
+    if member_id == user.id {
+        return Err(Web3ProxyError::InvalidParam(
+            "user_id",
+            "the owner's role can only change by transferring ownership".into(),
+        ));
+    }
+
+    let db_conn = app.db_conn().web3_context("changing a role needs a db")?;
+
+    if org_member_role(&db_conn, org_id, user.id).await? != OrgRole::Owner {
+        return Err(Web3ProxyError::AccessDenied);
+    }
+
+    let mut member = org_member::Entity::find()
+        .filter(org_member::Column::OrgId.eq(org_id))
+        .filter(org_member::Column::UserId.eq(member_id))
+        .one(&db_conn)
+        .await?
+        .ok_or(Web3ProxyError::NotFound)?
+        .into_active_model();
+
+    member.role = sea_orm::Set(payload.role);
+
+    let member = member.update(&db_conn).await?;
+
+    Ok(Json(member).into_response())
+}
+
+/// `DELETE /user/orgs/:org_id/members/:user_id` -- Use a bearer token to remove a member.
+/// The owner can remove anyone else. Other members can only remove themselves.
+#[debug_handler]
+pub async fn org_member_delete(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path((org_id, member_id)): Path<(u64, u64)>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().web3_context("removing a member needs a db")?;
+
+    let role = org_member_role(&db_conn, org_id, user.id).await?;
+
+    org_member_can_remove(&role, user.id, member_id)?;
+
+    let deleted = org_member::Entity::delete_many()
+        .filter(org_member::Column::OrgId.eq(org_id))
+        .filter(org_member::Column::UserId.eq(member_id))
+        .exec(&db_conn)
+        .await?
+        .rows_affected;
+
+    if deleted == 0 {
+        return Err(Web3ProxyError::NotFound);
+    }
+
+    Ok(Json(json!({ "org_id": org_id, "user_id": member_id, "deleted": true })).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OrgTransferPost {
+    user_id: u64,
+}
+
+/// `POST /user/orgs/:org_id/transfer` -- Use a bearer token to make another member the owner.
+/// The old owner becomes a billing admin. The org's keys move to the new owner.
+#[debug_handler]
+pub async fn org_transfer_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(org_id): Path<u64>,
+    Json(payload): Json<OrgTransferPost>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    if payload.user_id == user.id {
+        return Err(Web3ProxyError::InvalidParam(
+            "user_id",
+            "already the owner".into(),
+        ));
+    }
+
+    let db_conn = app
+        .db_conn()
+        .web3_context("transferring an org needs a db")?;
+
+    let txn = db_conn.begin().await?;
+
+    if org_member_role(&txn, org_id, user.id).await? != OrgRole::Owner {
+        return Err(Web3ProxyError::AccessDenied);
+    }
+
+    // the new owner must already be a member
+    org_member_role(&txn, org_id, payload.user_id)
+        .await
+        .map_err(|_| Web3ProxyError::InvalidParam("user_id", "not a member".into()))?;
+
+    org_member::Entity::update_many()
+        .col_expr(org_member::Column::Role, Expr::value(OrgRole::BillingAdmin))
+        .filter(org_member::Column::OrgId.eq(org_id))
+        .filter(org_member::Column::UserId.eq(user.id))
+        .exec(&txn)
+        .await?;
+
+    org_member::Entity::update_many()
+        .col_expr(org_member::Column::Role, Expr::value(OrgRole::Owner))
+        .filter(org_member::Column::OrgId.eq(org_id))
+        .filter(org_member::Column::UserId.eq(payload.user_id))
+        .exec(&txn)
+        .await?;
+
+    org::Entity::update_many()
+        .col_expr(org::Column::OwnerId, Expr::value(payload.user_id))
+        .filter(org::Column::Id.eq(org_id))
+        .exec(&txn)
+        .await?;
+
+    let moved_keys = rpc_key::Entity::update_many()
+        .col_expr(rpc_key::Column::UserId, Expr::value(payload.user_id))
+        .filter(rpc_key::Column::OrgId.eq(org_id))
+        .exec(&txn)
+        .await?
+        .rows_affected;
+
+    txn.commit().await?;
+
+    // org keys were cached with the old owner's tier and rate limits
+    app.rpc_secret_key_cache
+        .remove_matching(|_, x| x.user_id == user.id);
+    app.rpc_key_id_cache
+        .remove_matching(|_, x| x.user_id == user.id);
+
+    let response_json = json!({
+        "org_id": org_id,
+        "owner_id": payload.user_id,
+        "moved_rpc_keys": moved_keys,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OrgKeyManagement {
+    key_id: Option<u64>,
+    active: Option<bool>,
+    description: Option<String>,
+    log_level: Option<TrackingLevel>,
+    /// groups keys in the billing export. empty clears it
+    project_tag: Option<String>,
+}
+
+/// `POST /user/orgs/:org_id/keys` -- Use a bearer token to create or update one of the org's keys.
+/// Owners and developers can do this. The secret is only in the response when a key is created.
+#[debug_handler]
+pub async fn org_keys_management(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(org_id): Path<u64>,
+    Json(payload): Json<OrgKeyManagement>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().web3_context("managing org keys needs a db")?;
+
+    let role = org_member_role(&db_conn, org_id, user.id).await?;
+
+    if !org_role_can_manage_keys(&role) {
+        return Err(Web3ProxyError::AccessDenied);
+    }
+
+    let org = org::Entity::find_by_id(org_id)
+        .one(&db_conn)
+        .await?
+        .ok_or(Web3ProxyError::NotFound)?;
+
+    let (mut uk, new_secret_key) = if let Some(existing_key_id) = payload.key_id {
+        let uk = rpc_key::Entity::find_by_id(existing_key_id)
+            .filter(rpc_key::Column::OrgId.eq(org_id))
+            .one(&db_conn)
+            .await?
+            .ok_or(Web3ProxyError::NotFound)?
+            .into_active_model();
+
+        (uk, None)
+    } else {
+        let secret_key = RpcSecretKey::new();
+
+        let log_level = payload
+            .log_level
+            .web3_context("log level must be 'none', 'detailed', or 'aggregated'")?;
+
+        let uk = rpc_key::ActiveModel {
+            user_id: sea_orm::Set(org.owner_id),
+            org_id: sea_orm::Set(Some(org_id)),
+            created_by: sea_orm::Set(Some(user.id)),
+            secret_key: sea_orm::Set(None),
+            secret_key_hash: sea_orm::Set(Some(app.rpc_key_hasher.hash(secret_key))),
+            log_level: sea_orm::Set(log_level),
+            ..Default::default()
+        };
+
+        (uk, Some(secret_key))
+    };
+
+    if let Some(description) = payload.description {
+        if description.is_empty() {
+            uk.description = sea_orm::Set(None);
+        } else {
+            uk.description = sea_orm::Set(Some(description));
+        }
+    }
+
+    if let Some(project_tag) = payload.project_tag {
+        if project_tag.is_empty() {
+            uk.project_tag = sea_orm::Set(None);
+        } else if project_tag.len() > 64 {
+            return Err(Web3ProxyError::InvalidParam(
+                "project_tag",
+                "must be 64 bytes or less".into(),
+            ));
+        } else {
+            uk.project_tag = sea_orm::Set(Some(project_tag));
+        }
+    }
+
+    if let Some(active) = payload.active {
+        uk.active = sea_orm::Set(active);
+    }
+
+    let uk = uk.save(&db_conn).await?.try_into_model()?;
+
+    let mut response = serde_json::to_value(uk)?;
+
+    if let Some(secret_key) = new_secret_key {
+        response["secret_key"] = json!(secret_key.to_string());
+    }
+
+    Ok(Json(response).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OrgBalancePost {
+    amount: Decimal,
+}
+
+/// `POST /user/orgs/:org_id/balance` -- Use a bearer token to move credits from the user's balance to the org's.
+/// Owners and billing admins can do this. Moving credits back out is not supported.
+#[debug_handler]
+pub async fn org_balance_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(org_id): Path<u64>,
+    Json(payload): Json<OrgBalancePost>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    if payload.amount <= Decimal::ZERO {
+        return Err(Web3ProxyError::InvalidParam(
+            "amount",
+            "must be positive".into(),
+        ));
+    }
+
+    let db_conn = app.db_conn().web3_context("funding an org needs a db")?;
+
+    let role = org_member_role(&db_conn, org_id, user.id).await?;
+
+    if !org_role_can_view_balance(&role) {
+        return Err(Web3ProxyError::AccessDenied);
+    }
+
+    let txn = db_conn.begin().await?;
+
+    // the filter makes the check and the subtraction one statement
+    let taken = balance::Entity::update_many()
+        .col_expr(
+            balance::Column::AvailableBalance,
+            Expr::col(balance::Column::AvailableBalance).sub(payload.amount),
+        )
+        .filter(balance::Column::UserId.eq(user.id))
+        .filter(balance::Column::AvailableBalance.gte(payload.amount))
+        .exec(&txn)
+        .await?
+        .rows_affected;
+
+    if taken == 0 {
+        return Err(Web3ProxyError::PaymentRequired(
+            app.backoff_hint(None, None),
+        ));
+    }
+
+    org::Entity::update_many()
+        .col_expr(
+            org::Column::AvailableBalance,
+            Expr::col(org::Column::AvailableBalance).add(payload.amount),
+        )
+        .filter(org::Column::Id.eq(org_id))
+        .exec(&txn)
+        .await?;
+
+    let org = org::Entity::find_by_id(org_id)
+        .one(&txn)
+        .await?
+        .ok_or(Web3ProxyError::NotFound)?;
+
+    txn.commit().await?;
+
+    // org keys were cached with the old balance
+    app.rpc_secret_key_cache
+        .remove_matching(|_, x| x.user_id == org.owner_id);
+    app.rpc_key_id_cache
+        .remove_matching(|_, x| x.user_id == org.owner_id);
+
+    let response_json = json!({
+        "org_id": org_id,
+        "amount": payload.amount,
+        "available_balance": org.available_balance,
+        "used_balance": org.used_balance,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// One line of a billing export. `group` is "key", "member", "project_tag", or "total"
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct OrgBillingRow {
+    group: &'static str,
+    rpc_key_id: Option<u64>,
+    description: Option<String>,
+    project_tag: Option<String>,
+    /// the member that created the key
+    member_id: Option<u64>,
+    member_address: Option<Address>,
+    frontend_requests: u64,
+    credits_used: Decimal,
+}
+
+impl OrgBillingRow {
+    fn add(&mut self, other: &Self) {
+        self.frontend_requests += other.frontend_requests;
+        self.credits_used += other.credits_used;
+    }
+}
+
+/// `GET /user/orgs/:org_id/billing_export` -- Use a bearer token to get a month of the org's usage for cost allocation.
+/// Usage from every chain is totaled by key, by the member that created the key, and by project tag.
+/// Owners and billing admins can do this. `format=csv` returns the same rows as a csv file.
+#[debug_handler]
+pub async fn org_billing_export_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(org_id): Path<u64>,
+    Params(params): Params<OrgBillingExportParams>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app
+        .db_replica()
+        .web3_context("db_replica is required for billing exports")?;
+
+    let role = org_member_role(db_replica.conn(), org_id, user.id).await?;
+
+    if !org_role_can_view_balance(&role) {
+        return Err(Web3ProxyError::AccessDenied);
+    }
+
+    // months are in UTC so that every member gets the same numbers
+    let period_start = DateTime::<Utc>::from_utc(
+        params
+            .month
+            .and_hms_opt(0, 0, 0)
+            .expect("midnight is valid"),
+        Utc,
+    );
+    let period_end = period_start
+        .checked_add_months(Months::new(1))
+        .web3_context("month is out of range")?;
+
+    let keys = rpc_key::Entity::find()
+        .filter(rpc_key::Column::OrgId.eq(org_id))
+        .all(db_replica.conn())
+        .await
+        .web3_context("failed loading org keys")?;
+
+    #[derive(Debug, FromQueryResult)]
+    struct KeyUsage {
+        rpc_key_id: u64,
+        frontend_requests: Decimal,
+        credits_used: Decimal,
+    }
+
+    let usage: HashMap<u64, KeyUsage> = if keys.is_empty() {
+        HashMap::new()
+    } else {
+        rpc_accounting_v2::Entity::find()
+            .select_only()
+            .column(rpc_accounting_v2::Column::RpcKeyId)
+            .column_as(
+                rpc_accounting_v2::Column::FrontendRequests.sum(),
+                "frontend_requests",
+            )
+            .column_as(
+                rpc_accounting_v2::Column::SumCreditsUsed.sum(),
+                "credits_used",
+            )
+            .filter(rpc_accounting_v2::Column::RpcKeyId.is_in(keys.iter().map(|x| x.id)))
+            .filter(rpc_accounting_v2::Column::PeriodDatetime.gte(period_start))
+            .filter(rpc_accounting_v2::Column::PeriodDatetime.lt(period_end))
+            .group_by(rpc_accounting_v2::Column::RpcKeyId)
+            .into_model::<KeyUsage>()
+            .all(db_replica.conn())
+            .await
+            .web3_context("failed loading org usage")?
+            .into_iter()
+            .map(|x| (x.rpc_key_id, x))
+            .collect()
+    };
+
+    // keys from before created_by was tracked are counted for the owner
+    let member_ids: HashSet<u64> = keys
+        .iter()
+        .map(|x| x.created_by.unwrap_or(x.user_id))
+        .collect();
+
+    let member_addresses: HashMap<u64, Address> = user::Entity::find()
+        .filter(user::Column::Id.is_in(member_ids))
+        .all(db_replica.conn())
+        .await
+        .web3_context("failed loading org members")?
+        .into_iter()
+        .map(|x| (x.id, Address::from_slice(&x.address)))
+        .collect();
+
+    let mut key_rows = Vec::with_capacity(keys.len());
+    let mut member_rows = BTreeMap::<u64, OrgBillingRow>::new();
+    let mut project_tag_rows = BTreeMap::<Option<String>, OrgBillingRow>::new();
+    let mut total = OrgBillingRow {
+        group: "total",
+        ..Default::default()
+    };
+
+    for key in keys {
+        let member_id = key.created_by.unwrap_or(key.user_id);
+        let member_address = member_addresses.get(&member_id).copied();
+
+        let (frontend_requests, credits_used) = match usage.get(&key.id) {
+            Some(x) => (
+                x.frontend_requests.to_u64().unwrap_or_default(),
+                x.credits_used,
+            ),
+            None => (0, Decimal::ZERO),
+        };
+
+        let row = OrgBillingRow {
+            group: "key",
+            rpc_key_id: Some(key.id),
+            description: key.description,
+            project_tag: key.project_tag,
+            member_id: Some(member_id),
+            member_address,
+            frontend_requests,
+            credits_used,
+        };
+
+        member_rows
+            .entry(member_id)
+            .or_insert_with(|| OrgBillingRow {
+                group: "member",
+                member_id: Some(member_id),
+                member_address,
+                ..Default::default()
+            })
+            .add(&row);
+
+        project_tag_rows
+            .entry(row.project_tag.clone())
+            .or_insert_with(|| OrgBillingRow {
+                group: "project_tag",
+                project_tag: row.project_tag.clone(),
+                ..Default::default()
+            })
+            .add(&row);
+
+        total.add(&row);
+
+        key_rows.push(row);
+    }
+
+    let month = params.month.format("%Y-%m").to_string();
+
+    match params.format {
+        ExportFormat::Json => {
+            let response_json = json!({
+                "org_id": org_id,
+                "month": month,
+                "period_start": period_start.timestamp(),
+                "period_end": period_end.timestamp(),
+                "keys": key_rows,
+                "members": member_rows.into_values().collect::<Vec<_>>(),
+                "project_tags": project_tag_rows.into_values().collect::<Vec<_>>(),
+                "total": total,
+            });
+
+            Ok(Json(response_json).into_response())
+        }
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+
+            for row in key_rows
+                .iter()
+                .chain(member_rows.values())
+                .chain(project_tag_rows.values())
+                .chain([&total])
+            {
+                writer
+                    .serialize(row)
+                    .map_err(anyhow::Error::from)
+                    .web3_context("failed writing billing export")?;
+            }
+
+            let body = writer
+                .into_inner()
+                .map_err(|err| anyhow::Error::from(err.into_error()))
+                .web3_context("failed writing billing export")?;
+
+            let content_disposition = format!(
+                "attachment; filename=\"org-{}-billing-{}.csv\"",
+                org_id, month
+            );
+
+            Ok((
+                [
+                    (CONTENT_TYPE, "text/csv".to_string()),
+                    (CONTENT_DISPOSITION, content_disposition),
+                ],
+                body,
+            )
+                .into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROLES: [OrgRole; 4] = [
+        OrgRole::Owner,
+        OrgRole::BillingAdmin,
+        OrgRole::Developer,
+        OrgRole::Viewer,
+    ];
+
+    #[test]
+    fn test_org_role_permissions() {
+        let can_view_balance: Vec<_> = ROLES.iter().map(org_role_can_view_balance).collect();
+        let can_manage_keys: Vec<_> = ROLES.iter().map(org_role_can_manage_keys).collect();
+
+        assert_eq!(can_view_balance, [true, true, false, false]);
+        assert_eq!(can_manage_keys, [true, false, true, false]);
+    }
+
+    #[test]
+    fn test_org_member_can_remove() {
+        // the owner can remove anyone but themselves
+        org_member_can_remove(&OrgRole::Owner, 1, 2).unwrap();
+        assert!(matches!(
+            org_member_can_remove(&OrgRole::Owner, 1, 1),
+            Err(Web3ProxyError::InvalidParam("user_id", _))
+        ));
+
+        for role in &ROLES[1..] {
+            // everyone else can leave
+            org_member_can_remove(role, 2, 2).unwrap();
+
+            // but can't remove anyone else
+            assert!(matches!(
+                org_member_can_remove(role, 2, 3),
+                Err(Web3ProxyError::AccessDenied)
+            ));
+        }
+    }
+}
@@ -1,6 +1,9 @@
 use crate::app::Web3ProxyApp;
 use crate::frontend::authorization::Authorization as InternalAuthorization;
 use crate::frontend::errors::{Web3ProxyError, Web3ProxyResponse};
+use crate::frontend::idempotency::{idempotency_key, with_idempotency_key};
+use crate::frontend::users::subuser::subuser_can_view_balance;
+use crate::http_params::{BalanceParams, Params};
 use crate::rpcs::request::OpenRequestResult;
 use anyhow::{anyhow, Context};
 use axum::{
@@ -10,13 +13,15 @@ use axum::{
     Extension, Json, TypedHeader,
 };
 use axum_macros::debug_handler;
-use entities::{balance, increase_on_chain_balance_receipt, user, user_tier};
+use entities::{
+    balance, increase_on_chain_balance_receipt, rpc_key, secondary_user, user, user_tier,
+};
 use ethers::abi::{AbiEncode, ParamType};
 use ethers::types::{Address, TransactionReceipt, H256, U256};
 use ethers::utils::{hex, keccak256};
 use hashbrown::HashMap;
 use hex_fmt::HexFmt;
-use http::StatusCode;
+use http::{HeaderMap, StatusCode};
 use log::{debug, info, warn, Level};
 use migration::sea_orm;
 use migration::sea_orm::prelude::Decimal;
@@ -29,10 +34,58 @@ use migration::sea_orm::TransactionTrait;
 use serde_json::json;
 use std::sync::Arc;
 
+/// Whose balance the user can see. A key's owner can see their own. Subusers of a shared key can see its owner's
+/// only if they are the key's owner or admin. Collaborators never can, even with `stats_access`
+pub fn balance_user_id(
+    user_id: u64,
+    key_owner_id: u64,
+    subuser: Option<&secondary_user::Model>,
+) -> Result<u64, Web3ProxyError> {
+    if key_owner_id == user_id {
+        return Ok(user_id);
+    }
+
+    match subuser {
+        Some(subuser) if subuser.user_id == user_id && subuser_can_view_balance(subuser) => {
+            Ok(key_owner_id)
+        }
+        _ => Err(Web3ProxyError::AccessDenied),
+    }
+}
+
+/// The user's own id, or the owner of the shared `rpc_key` in the params if the user may see their balance
+async fn balance_user_id_from_params(
+    app: &Web3ProxyApp,
+    user: &user::Model,
+    params: &BalanceParams,
+) -> Result<u64, Web3ProxyError> {
+    let rpc_key = match params.rpc_key {
+        Some(x) => x,
+        None => return Ok(user.id),
+    };
+
+    let db_replica = app.db_replica().context("Getting database connection")?;
+
+    let rpc_key = rpc_key::Entity::find()
+        .filter(app.rpc_key_hasher.condition(rpc_key.into()))
+        .one(db_replica.conn())
+        .await?
+        .ok_or(Web3ProxyError::RpcKeyNotFound)?;
+
+    let subuser = secondary_user::Entity::find()
+        .filter(secondary_user::Column::UserId.eq(user.id))
+        .filter(secondary_user::Column::RpcSecretKeyId.eq(rpc_key.id))
+        .one(db_replica.conn())
+        .await?;
+
+    balance_user_id(user.id, rpc_key.user_id, subuser.as_ref())
+}
+
 /// Implements any logic related to payments
 /// Removed this mainly from "user" as this was getting clogged
 ///
 /// `GET /user/balance` -- Use a bearer token to get the user's balance and spend.
+/// With `rpc_key`, owners and admins of a shared key get the key owner's balance.
 ///
 /// - show balance in USD
 /// - show deposits history (currency, amounts, transaction id)
@@ -40,14 +93,17 @@ use std::sync::Arc;
 pub async fn user_balance_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Params(params): Params<BalanceParams>,
 ) -> Web3ProxyResponse {
-    let (_user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let balance_user_id = balance_user_id_from_params(&app, &user, &params).await?;
 
     let db_replica = app.db_replica().context("Getting database connection")?;
 
     // Just return the balance for the user
     let user_balance = match balance::Entity::find()
-        .filter(balance::Column::UserId.eq(_user.id))
+        .filter(balance::Column::UserId.eq(balance_user_id))
         .one(db_replica.conn())
         .await?
     {
@@ -66,20 +122,24 @@ pub async fn user_balance_get(
 }
 
 /// `GET /user/deposits` -- Use a bearer token to get the user's balance and spend.
+/// With `rpc_key`, owners and admins of a shared key get the key owner's deposits.
 ///
 /// - shows a list of all deposits, including their chain-id, amount and tx-hash
 #[debug_handler]
 pub async fn user_deposits_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Params(params): Params<BalanceParams>,
 ) -> Web3ProxyResponse {
     let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
 
+    let balance_user_id = balance_user_id_from_params(&app, &user, &params).await?;
+
     let db_replica = app.db_replica().context("Getting database connection")?;
 
     // Filter by user ...
     let receipts = increase_on_chain_balance_receipt::Entity::find()
-        .filter(increase_on_chain_balance_receipt::Column::DepositToUserId.eq(user.id))
+        .filter(increase_on_chain_balance_receipt::Column::DepositToUserId.eq(balance_user_id))
         .all(db_replica.conn())
         .await?;
 
@@ -112,23 +172,36 @@ pub async fn user_deposits_get(
 pub async fn user_balance_post(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    headers: HeaderMap,
     Path(mut params): Path<HashMap<String, String>>,
 ) -> Web3ProxyResponse {
     // I suppose this is ok / good, so people don't spam this endpoint as it is not "cheap"
     // Check that the user is logged-in and authorized. We don't need a semaphore here btw
-    let (_, _semaphore) = app.bearer_is_authorized(bearer).await?;
+    let (caller, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let idempotency_key = idempotency_key(&headers)?;
 
     // Get the transaction hash, and the amount that the user wants to top up by.
     // Let's say that for now, 1 credit is equivalent to 1 dollar (assuming any stablecoin has a 1:1 peg)
     let tx_hash: H256 = params
         .remove("tx_hash")
         // TODO: map_err so this becomes a 500. routing must be bad
-        .ok_or(Web3ProxyError::BadRequest(
-            "You have not provided the tx_hash in which you paid in".to_string(),
-        ))?
+        .ok_or(Web3ProxyError::MissingParam("tx_hash"))?
         .parse()
         .context("unable to parse tx_hash")?;
 
+    with_idempotency_key(
+        &app,
+        caller.id,
+        idempotency_key,
+        "user_balance_post",
+        format!("{:?}", tx_hash),
+        _user_balance_post(app.clone(), tx_hash),
+    )
+    .await
+}
+
+async fn _user_balance_post(app: Arc<Web3ProxyApp>, tx_hash: H256) -> Web3ProxyResponse {
     let db_conn = app.db_conn().context("query_user_stats needs a db")?;
     let db_replica = app
         .db_replica()
@@ -140,9 +213,7 @@ pub async fn user_balance_post(
         .one(&db_conn)
         .await?;
     if receipt.is_some() {
-        return Err(Web3ProxyError::BadRequest(
-            "The transaction you provided has already been accounted for!".to_string(),
-        ));
+        return Err(Web3ProxyError::PaymentAlreadyCredited);
     }
     debug!("Receipt: {:?}", receipt);
 
@@ -391,9 +462,7 @@ pub async fn user_balance_post(
             .await?
         {
             Some(x) => Ok(x),
-            None => Err(Web3ProxyError::BadRequest(
-                "The user must have signed up first. They are currently not signed up!".to_string(),
-            )),
+            None => Err(Web3ProxyError::UserNotFound),
         }?;
 
         // For now we only accept stablecoins
@@ -493,7 +562,53 @@ pub async fn user_balance_post(
         return Ok(response);
     }
 
-    Err(Web3ProxyError::BadRequest(
-        "No such transaction was found, or token is not supported!".to_string(),
-    ))
+    Err(Web3ProxyError::PaymentNotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entities::sea_orm_active_enums::Role;
+
+    fn subuser(user_id: u64, role: Role, stats_access: bool) -> secondary_user::Model {
+        secondary_user::Model {
+            id: 1,
+            user_id,
+            description: None,
+            rpc_secret_key_id: 10,
+            role,
+            stats_access,
+        }
+    }
+
+    #[test]
+    fn test_balance_user_id() {
+        // a key's owner always sees their own balance
+        assert_eq!(balance_user_id(1, 1, None).unwrap(), 1);
+
+        // owners and admins of a shared key see the key owner's balance
+        for role in [Role::Owner, Role::Admin] {
+            let x = subuser(2, role, false);
+            assert_eq!(balance_user_id(2, 1, Some(&x)).unwrap(), 1);
+        }
+
+        // collaborators don't, even if they can see the key's stats
+        for stats_access in [false, true] {
+            let x = subuser(2, Role::Collaborator, stats_access);
+            assert!(matches!(
+                balance_user_id(2, 1, Some(&x)),
+                Err(Web3ProxyError::AccessDenied)
+            ));
+        }
+
+        // no access to the key at all, or a subuser row for someone else
+        assert!(matches!(
+            balance_user_id(2, 1, None),
+            Err(Web3ProxyError::AccessDenied)
+        ));
+        assert!(matches!(
+            balance_user_id(3, 1, Some(&subuser(2, Role::Admin, true))),
+            Err(Web3ProxyError::AccessDenied)
+        ));
+    }
 }
@@ -0,0 +1,125 @@
+//! Share a key's access for a limited time without sharing the key.
+use super::super::errors::{
+    Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse, Web3ProxyResult,
+};
+use super::super::presigned_url::PresignedClaims;
+use crate::app::Web3ProxyApp;
+use axum::extract::Path;
+use axum::{
+    headers::{authorization::Bearer, Authorization},
+    response::IntoResponse,
+    Extension, Json, TypedHeader,
+};
+use axum_macros::debug_handler;
+use chrono::Utc;
+use entities::rpc_key;
+use migration::sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use ulid::Ulid;
+
+/// the JSON input to the `presigned_url_post` handler
+#[derive(Debug, Deserialize)]
+pub struct PresignedUrlPost {
+    expires_in_seconds: u64,
+    /// empty allows every method
+    #[serde(default)]
+    methods: Vec<String>,
+}
+
+/// Only the key's owner can share it
+async fn check_key_owner(
+    app: &Web3ProxyApp,
+    bearer: Bearer,
+    rpc_key_id: u64,
+) -> Web3ProxyResult<()> {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app
+        .db_replica()
+        .web3_context("db_replica is required for presigned urls")?;
+
+    rpc_key::Entity::find_by_id(rpc_key_id)
+        .filter(rpc_key::Column::UserId.eq(user.id))
+        .one(db_replica.conn())
+        .await
+        .web3_context("failed loading rpc key")?
+        .ok_or(Web3ProxyError::NotRpcKeyOwner)?;
+
+    Ok(())
+}
+
+/// `POST /user/keys/:rpc_key_id/presigned` -- Use a bearer token to mint a temporary url for one of the user's keys.
+/// The url can only call the given methods. Anyone with the url can use it until it expires or is revoked.
+#[debug_handler]
+pub async fn presigned_url_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+    Json(payload): Json<PresignedUrlPost>,
+) -> Web3ProxyResponse {
+    check_key_owner(&app, bearer, rpc_key_id).await?;
+
+    if payload.expires_in_seconds == 0
+        || payload.expires_in_seconds > app.config.presigned_url_max_seconds
+    {
+        return Err(Web3ProxyError::InvalidParam(
+            "expires_in_seconds",
+            format!(
+                "must be between 1 and {}",
+                app.config.presigned_url_max_seconds
+            )
+            .into(),
+        ));
+    }
+
+    // urls can't be checked for revocation without redis, so don't hand any out
+    app.vredis_pool
+        .as_ref()
+        .web3_context("redis is required for presigned urls")?;
+
+    let claims = PresignedClaims {
+        rpc_key_id,
+        exp: Utc::now().timestamp() + payload.expires_in_seconds as i64,
+        methods: payload.methods,
+        jti: Ulid::new(),
+    };
+
+    let token = app.sign_presigned_url(&claims)?;
+
+    let url = format!(
+        "{}/presigned/{}",
+        app.config
+            .public_url
+            .as_deref()
+            .unwrap_or_default()
+            .trim_end_matches('/'),
+        token
+    );
+
+    let response_json = json!({
+        "url": url,
+        "jti": claims.jti,
+        "exp": claims.exp,
+        "methods": claims.methods,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `DELETE /user/keys/:rpc_key_id/presigned/:jti` -- Use a bearer token to revoke a presigned url before it expires.
+#[debug_handler]
+pub async fn presigned_url_delete(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path((rpc_key_id, jti)): Path<(u64, String)>,
+) -> Web3ProxyResponse {
+    check_key_owner(&app, bearer, rpc_key_id).await?;
+
+    let jti: Ulid = jti.parse()?;
+
+    app.revoke_presigned_url(rpc_key_id, &jti).await?;
+
+    Ok(Json(json!({ "revoked": jti })).into_response())
+}
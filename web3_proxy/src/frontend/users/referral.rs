@@ -4,14 +4,12 @@ use crate::frontend::errors::Web3ProxyResponse;
 use crate::referral_code::ReferralCode;
 use anyhow::Context;
 use axum::{
-    extract::Query,
     headers::{authorization::Bearer, Authorization},
     response::IntoResponse,
     Extension, Json, TypedHeader,
 };
 use axum_macros::debug_handler;
 use entities::referrer;
-use hashbrown::HashMap;
 use http::StatusCode;
 use migration::sea_orm;
 use migration::sea_orm::ActiveModelTrait;
@@ -28,7 +26,6 @@ use std::sync::Arc;
 pub async fn user_referral_link_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
-    Query(_params): Query<HashMap<String, String>>,
 ) -> Web3ProxyResponse {
     // First get the bearer token and check if the user is logged in
     let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
@@ -64,7 +61,7 @@ pub async fn user_referral_link_get(
 
     let response_json = json!({
         "referral_code": referral_code,
-        "user": user,
+        "user": app.unseal_user(user)?,
     });
 
     let response = (status_code, Json(response_json)).into_response();
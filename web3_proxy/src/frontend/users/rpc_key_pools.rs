@@ -0,0 +1,221 @@
+//! Group multiple rpc keys behind a single url.
+use super::super::authorization::RpcSecretKey;
+use super::super::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse};
+use crate::app::Web3ProxyApp;
+use axum::{
+    headers::{authorization::Bearer, Authorization},
+    response::IntoResponse,
+    Extension, Json, TypedHeader,
+};
+use axum_macros::debug_handler;
+use entities::sea_orm_active_enums::Role;
+use entities::{rpc_key, rpc_key_pool, secondary_user};
+use hashbrown::{HashMap, HashSet};
+use migration::sea_orm::{
+    self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter,
+    TransactionTrait, TryIntoModel,
+};
+use migration::Expr;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+/// `GET /user/keys/pools` -- Use a bearer token to get the user's key pools and the ids of the keys in them.
+#[debug_handler]
+pub async fn rpc_key_pools_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app
+        .db_replica()
+        .web3_context("db_replica is required to fetch a user's key pools")?;
+
+    let pools = rpc_key_pool::Entity::find()
+        .filter(rpc_key_pool::Column::UserId.eq(user.id))
+        .find_with_related(rpc_key::Entity)
+        .all(db_replica.conn())
+        .await
+        .web3_context("failed loading user's key pools")?;
+
+    let response_json = json!({
+        "user_id": user.id,
+        "user_rpc_key_pools": pools
+            .into_iter()
+            .map(|(pool, keys)| {
+                let pool_id = pool.id;
+                let rpc_key_ids: Vec<_> = keys.into_iter().map(|x| x.id).collect();
+
+                (pool_id, json!({
+                    "pool": pool,
+                    "rpc_key_ids": rpc_key_ids,
+                }))
+            })
+            .collect::<HashMap::<_, _>>(),
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// the JSON input to the `rpc_key_pools_management` handler.
+/// If `pool_id` is set, it updates an existing pool.
+/// If `pool_id` is not set, it creates a new pool.
+#[derive(Debug, Deserialize)]
+pub struct UserKeyPoolManagement {
+    pool_id: Option<u64>,
+    active: Option<bool>,
+    description: Option<String>,
+    /// replaces all the keys in the pool.
+    /// keys must belong to the user or be shared with them as an owner or admin
+    rpc_key_ids: Option<Vec<u64>>,
+}
+
+/// `POST /user/keys/pools` -- Use a bearer token to create or update a key pool.
+#[debug_handler]
+pub async fn rpc_key_pools_management(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<UserKeyPoolManagement>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app
+        .db_conn()
+        .web3_context("getting db for user's key pools")?;
+
+    let (mut pool, new_secret_key) = if let Some(existing_pool_id) = payload.pool_id {
+        // get the pool and make sure it belongs to the user
+        let pool = rpc_key_pool::Entity::find()
+            .filter(rpc_key_pool::Column::UserId.eq(user.id))
+            .filter(rpc_key_pool::Column::Id.eq(existing_pool_id))
+            .one(&db_conn)
+            .await
+            .web3_context("failed loading user's key pool")?
+            .web3_context("key pool does not exist or is not controlled by this bearer token")?
+            .into_active_model();
+
+        (pool, None)
+    } else {
+        // make a new pool
+        let secret_key = RpcSecretKey::new();
+
+        // only the hash is saved. the secret is in this response and nowhere else
+        let pool = rpc_key_pool::ActiveModel {
+            user_id: sea_orm::Set(user.id),
+            secret_key: sea_orm::Set(None),
+            secret_key_hash: sea_orm::Set(Some(app.rpc_key_hasher.hash(secret_key))),
+            active: sea_orm::Set(true),
+            ..Default::default()
+        };
+
+        (pool, Some(secret_key))
+    };
+
+    if let Some(description) = payload.description {
+        if description.is_empty() {
+            pool.description = sea_orm::Set(None);
+        } else {
+            pool.description = sea_orm::Set(Some(description));
+        }
+    }
+
+    if let Some(active) = payload.active {
+        pool.active = sea_orm::Set(active);
+    }
+
+    let txn = db_conn.begin().await?;
+
+    let pool = if pool.is_changed() {
+        pool.save(&txn)
+            .await
+            .web3_context("Failed saving key pool")?
+    } else {
+        pool
+    };
+
+    let pool: rpc_key_pool::Model = pool.try_into_model()?;
+
+    if let Some(rpc_key_ids) = payload.rpc_key_ids {
+        let rpc_key_ids: HashSet<u64> = rpc_key_ids.into_iter().collect();
+
+        // keys shared with this user by their owner
+        let shared_key_ids: HashSet<u64> = secondary_user::Entity::find()
+            .filter(secondary_user::Column::UserId.eq(user.id))
+            .filter(secondary_user::Column::Role.is_in([Role::Owner, Role::Admin]))
+            .all(&txn)
+            .await?
+            .into_iter()
+            .map(|x| x.rpc_secret_key_id)
+            .collect();
+
+        let keys = rpc_key::Entity::find()
+            .filter(rpc_key::Column::Id.is_in(rpc_key_ids.iter().copied()))
+            .all(&txn)
+            .await?;
+
+        if keys.len() != rpc_key_ids.len() {
+            return Err(Web3ProxyError::RpcKeyNotFound);
+        }
+
+        for key in keys.iter() {
+            if key.user_id != user.id && !shared_key_ids.contains(&key.id) {
+                return Err(Web3ProxyError::AccessDenied);
+            }
+
+            if let Some(other_pool_id) = key.rpc_key_pool_id {
+                if other_pool_id != pool.id {
+                    return Err(Web3ProxyError::RpcKeyInOtherPool {
+                        rpc_key_id: key.id,
+                        rpc_key_pool_id: other_pool_id,
+                    });
+                }
+            }
+        }
+
+        // remove keys that are no longer in the pool
+        rpc_key::Entity::update_many()
+            .col_expr(
+                rpc_key::Column::RpcKeyPoolId,
+                Expr::value(Option::<u64>::None),
+            )
+            .filter(rpc_key::Column::RpcKeyPoolId.eq(pool.id))
+            .filter(rpc_key::Column::Id.is_not_in(rpc_key_ids.iter().copied()))
+            .exec(&txn)
+            .await?;
+
+        // add the new keys
+        rpc_key::Entity::update_many()
+            .col_expr(rpc_key::Column::RpcKeyPoolId, Expr::value(pool.id))
+            .filter(rpc_key::Column::Id.is_in(rpc_key_ids.iter().copied()))
+            .exec(&txn)
+            .await?;
+    }
+
+    txn.commit().await?;
+
+    // the pool's members might have changed. clear the cache so the next request loads them fresh
+    if let Some(secret_key_hash) = pool.secret_key_hash.as_ref() {
+        app.rpc_key_pool_cache.remove(secret_key_hash);
+    }
+
+    let rpc_key_ids: Vec<u64> = rpc_key::Entity::find()
+        .filter(rpc_key::Column::RpcKeyPoolId.eq(pool.id))
+        .all(&db_conn)
+        .await?
+        .into_iter()
+        .map(|x| x.id)
+        .collect();
+
+    let mut response_json = json!({
+        "pool": pool,
+        "rpc_key_ids": rpc_key_ids,
+    });
+
+    // the secret can't be shown again after this
+    if let Some(secret_key) = new_secret_key {
+        response_json["secret_key"] = json!(secret_key.to_string());
+    }
+
+    Ok(Json(response_json).into_response())
+}
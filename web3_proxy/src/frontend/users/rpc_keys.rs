@@ -1,7 +1,16 @@
 //! Handle registration, logins, and managing account data.
-use super::super::authorization::RpcSecretKey;
-use super::super::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse};
-use crate::app::Web3ProxyApp;
+use super::super::allowed_ips::parse_ip_list;
+use super::super::authorization::{RpcSecretKey, RATE_LIMIT_REJECTIONS_PERIOD};
+use super::super::errors::{
+    Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse, Web3ProxyResult,
+};
+use super::super::rpc_proxy_ws::ProxyMode;
+use super::subuser::subuser_can_view_stats;
+use crate::app::{
+    parse_allowed_chains, parse_method_requests_per_second, RpcKeyFrozen, Web3ProxyApp,
+    RATE_LIMIT_PERIOD_SECONDS,
+};
+use axum::extract::Path;
 use axum::headers::{Header, Origin, Referer, UserAgent};
 use axum::{
     headers::{authorization::Bearer, Authorization},
@@ -9,19 +18,24 @@ use axum::{
     Extension, Json, TypedHeader,
 };
 use axum_macros::debug_handler;
+use chrono::Utc;
 use entities;
-use entities::rpc_key;
 use entities::sea_orm_active_enums::TrackingLevel;
+use entities::{canary_hit, rpc_key, secondary_user, user_tier};
+use ethers::utils::hex;
 use hashbrown::HashMap;
 use http::HeaderValue;
-use ipnet::IpNet;
 use itertools::Itertools;
 use migration::sea_orm::{
-    self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter, TryIntoModel,
+    self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter, QueryOrder,
+    QuerySelect, TransactionTrait, TryIntoModel,
 };
+use ring::rand::{SecureRandom, SystemRandom};
 use serde::Deserialize;
 use serde_json::json;
+use std::num::NonZeroU64;
 use std::sync::Arc;
+use url::{Host, Url};
 
 /// `GET /user/keys` -- Use a bearer token to get the user's api keys and their settings.
 #[debug_handler]
@@ -81,6 +95,113 @@ pub struct UserKeyManagement {
     log_level: Option<TrackingLevel>,
     // TODO: enable log_revert_trace: Option<f64>,
     private_txs: Option<bool>,
+    payload_sampling_opt_out: Option<bool>,
+    bypass_cache: Option<bool>,
+    canary: Option<bool>,
+    canary_freeze_siblings: Option<bool>,
+    /// comma separated "method=limit" pairs. these can only lower the user tier's limits
+    method_requests_per_second: Option<String>,
+    /// these can only lower the user tier's token bucket. 0 removes them
+    burst_capacity: Option<u64>,
+    sustained_requests_per_period: Option<u64>,
+    /// comma separated chain ids. empty only allows the proxy's own chain
+    allowed_chains: Option<String>,
+    /// https endpoint for signed copies of this key's request metadata. setting it makes a new secret. empty stops shadowing
+    shadow_url: Option<String>,
+}
+
+/// Check a comma separated list of origins and normalize it for the database. Empty clears the list
+// TODO: this should actually be bytes
+pub(crate) fn parse_allowed_origins(allowed_origins: &str) -> Web3ProxyResult<Option<String>> {
+    if allowed_origins.is_empty() {
+        return Ok(None);
+    }
+
+    // split allowed_origins on ',' and try to parse them all. error on invalid input
+    let allowed_origins = allowed_origins
+        .split(',')
+        .map(|x| HeaderValue::from_str(x.trim()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .map(|x| Origin::decode(&mut [x].iter()))
+        .collect::<Result<Vec<_>, _>>()?
+        // parse worked. convert back to String and join them back together
+        .into_iter()
+        .map(|x| x.to_string());
+
+    let allowed_origins: String =
+        Itertools::intersperse(allowed_origins, ", ".to_string()).collect();
+
+    Ok(Some(allowed_origins))
+}
+
+/// Check a comma separated list of referers and normalize it for the database. Empty clears the list
+// TODO: this should actually be bytes
+pub(crate) fn parse_allowed_referers(allowed_referers: &str) -> Web3ProxyResult<Option<String>> {
+    if allowed_referers.is_empty() {
+        return Ok(None);
+    }
+
+    // split allowed ips on ',' and try to parse them all. error on invalid input
+    let allowed_referers = allowed_referers
+        .split(',')
+        .map(|x| HeaderValue::from_str(x.trim()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .map(|x| Referer::decode(&mut [x].iter()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // parse worked. now we can put it back together.
+    // but we can't go directly to String.
+    // so we convert to HeaderValues first
+    let mut header_map = vec![];
+    for x in allowed_referers {
+        x.encode(&mut header_map);
+    }
+
+    // convert HeaderValues to Strings
+    // since we got these from strings, this should always work (unless we figure out using bytes)
+    let allowed_referers = header_map
+        .into_iter()
+        .map(|x| x.to_str().map(|x| x.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // join strings together with commas
+    let allowed_referers: String =
+        Itertools::intersperse(allowed_referers.into_iter(), ", ".to_string()).collect();
+
+    Ok(Some(allowed_referers))
+}
+
+/// Check a shadow url and normalize it for the database. It must be https and can't be a private ip
+pub(crate) fn parse_shadow_url(shadow_url: &str) -> Web3ProxyResult<String> {
+    let url = Url::parse(shadow_url.trim())
+        .map_err(|err| Web3ProxyError::InvalidParam("shadow_url", err.to_string().into()))?;
+
+    if url.scheme() != "https" {
+        return Err(Web3ProxyError::InvalidParam(
+            "shadow_url",
+            "must be https".into(),
+        ));
+    }
+
+    let public = match url.host() {
+        Some(Host::Domain(x)) => x != "localhost",
+        Some(Host::Ipv4(x)) => {
+            !(x.is_private() || x.is_loopback() || x.is_link_local() || x.is_unspecified())
+        }
+        Some(Host::Ipv6(x)) => !(x.is_loopback() || x.is_unspecified()),
+        None => false,
+    };
+
+    if !public {
+        return Err(Web3ProxyError::InvalidParam(
+            "shadow_url",
+            "must be a public host".into(),
+        ));
+    }
+
+    Ok(url.to_string())
 }
 
 /// `POST /user/keys` or `PUT /user/keys` -- Use a bearer token to create or update an existing key.
@@ -98,16 +219,19 @@ pub async fn rpc_keys_management(
         .db_replica()
         .web3_context("getting db for user's keys")?;
 
-    let mut uk = if let Some(existing_key_id) = payload.key_id {
+    let (mut uk, new_secret_key) = if let Some(existing_key_id) = payload.key_id {
         // get the key and make sure it belongs to the user
-        rpc_key::Entity::find()
+        let uk = rpc_key::Entity::find()
             .filter(rpc_key::Column::UserId.eq(user.id))
             .filter(rpc_key::Column::Id.eq(existing_key_id))
+            .filter(rpc_key::Column::SupersededBy.is_null())
             .one(db_replica.conn())
             .await
             .web3_context("failed loading user's key")?
             .web3_context("key does not exist or is not controlled by this bearer token")?
-            .into_active_model()
+            .into_active_model();
+
+        (uk, None)
     } else {
         // make a new key
         // TODO: limit to 10 keys?
@@ -117,12 +241,16 @@ pub async fn rpc_keys_management(
             .log_level
             .web3_context("log level must be 'none', 'detailed', or 'aggregated'")?;
 
-        rpc_key::ActiveModel {
+        // only the hash is saved. the secret is in this response and nowhere else
+        let uk = rpc_key::ActiveModel {
             user_id: sea_orm::Set(user.id),
-            secret_key: sea_orm::Set(secret_key.into()),
+            secret_key: sea_orm::Set(None),
+            secret_key_hash: sea_orm::Set(Some(app.rpc_key_hasher.hash(secret_key))),
             log_level: sea_orm::Set(log_level),
             ..Default::default()
-        }
+        };
+
+        (uk, Some(secret_key))
     };
 
     // TODO: do we need null descriptions? default to empty string should be fine, right?
@@ -138,19 +266,87 @@ pub async fn rpc_keys_management(
         uk.private_txs = sea_orm::Set(private_txs);
     }
 
+    if let Some(payload_sampling_opt_out) = payload.payload_sampling_opt_out {
+        uk.payload_sampling_opt_out = sea_orm::Set(payload_sampling_opt_out);
+    }
+
+    if let Some(bypass_cache) = payload.bypass_cache {
+        uk.bypass_cache = sea_orm::Set(bypass_cache);
+    }
+
+    if let Some(canary) = payload.canary {
+        uk.canary = sea_orm::Set(canary);
+    }
+
+    if let Some(canary_freeze_siblings) = payload.canary_freeze_siblings {
+        uk.canary_freeze_siblings = sea_orm::Set(canary_freeze_siblings);
+    }
+
     if let Some(active) = payload.active {
         uk.active = sea_orm::Set(active);
     }
 
+    if let Some(method_requests_per_second) = payload.method_requests_per_second {
+        let limits =
+            parse_method_requests_per_second(&method_requests_per_second).map_err(|err| {
+                Web3ProxyError::InvalidParam("method_requests_per_second", err.to_string().into())
+            })?;
+
+        if limits.is_empty() {
+            uk.method_requests_per_second = sea_orm::Set(None);
+        } else {
+            // parse worked. put it back together in a consistent order
+            let limits = limits
+                .into_iter()
+                .sorted()
+                .map(|(method, limit)| format!("{}={}", method, limit));
+
+            let limits: String = Itertools::intersperse(limits, ", ".to_string()).collect();
+
+            uk.method_requests_per_second = sea_orm::Set(Some(limits));
+        }
+    }
+
+    if let Some(allowed_chains) = payload.allowed_chains {
+        let chain_ids = parse_allowed_chains(&allowed_chains).map_err(|err| {
+            Web3ProxyError::InvalidParam("allowed_chains", err.to_string().into())
+        })?;
+
+        let served_chain_ids = app.served_chain_ids();
+
+        if let Some(x) = chain_ids.iter().find(|x| !served_chain_ids.contains(x)) {
+            return Err(Web3ProxyError::InvalidParam(
+                "allowed_chains",
+                format!("chain {} is not served here", x).into(),
+            ));
+        }
+
+        if chain_ids.is_empty() {
+            uk.allowed_chains = sea_orm::Set(None);
+        } else {
+            let chain_ids = chain_ids.into_iter().map(|x| x.to_string());
+
+            let chain_ids: String = Itertools::intersperse(chain_ids, ", ".to_string()).collect();
+
+            uk.allowed_chains = sea_orm::Set(Some(chain_ids));
+        }
+    }
+
+    if let Some(burst_capacity) = payload.burst_capacity {
+        uk.burst_capacity = sea_orm::Set(Some(burst_capacity).filter(|x| *x > 0));
+    }
+
+    if let Some(sustained_requests_per_period) = payload.sustained_requests_per_period {
+        uk.sustained_requests_per_period =
+            sea_orm::Set(Some(sustained_requests_per_period).filter(|x| *x > 0));
+    }
+
     if let Some(allowed_ips) = payload.allowed_ips {
         if allowed_ips.is_empty() {
             uk.allowed_ips = sea_orm::Set(None);
         } else {
             // split allowed ips on ',' and try to parse them all. error on invalid input
-            let allowed_ips = allowed_ips
-                .split(',')
-                .map(|x| x.trim().parse::<IpNet>())
-                .collect::<Result<Vec<_>, _>>()?
+            let allowed_ips = parse_ip_list(&allowed_ips)?
                 // parse worked. convert back to Strings
                 .into_iter()
                 .map(|x| x.to_string());
@@ -163,65 +359,12 @@ pub async fn rpc_keys_management(
         }
     }
 
-    // TODO: this should actually be bytes
     if let Some(allowed_origins) = payload.allowed_origins {
-        if allowed_origins.is_empty() {
-            uk.allowed_origins = sea_orm::Set(None);
-        } else {
-            // split allowed_origins on ',' and try to parse them all. error on invalid input
-            let allowed_origins = allowed_origins
-                .split(',')
-                .map(|x| HeaderValue::from_str(x.trim()))
-                .collect::<Result<Vec<_>, _>>()?
-                .into_iter()
-                .map(|x| Origin::decode(&mut [x].iter()))
-                .collect::<Result<Vec<_>, _>>()?
-                // parse worked. convert back to String and join them back together
-                .into_iter()
-                .map(|x| x.to_string());
-
-            let allowed_origins: String =
-                Itertools::intersperse(allowed_origins, ", ".to_string()).collect();
-
-            uk.allowed_origins = sea_orm::Set(Some(allowed_origins));
-        }
+        uk.allowed_origins = sea_orm::Set(parse_allowed_origins(&allowed_origins)?);
     }
 
-    // TODO: this should actually be bytes
     if let Some(allowed_referers) = payload.allowed_referers {
-        if allowed_referers.is_empty() {
-            uk.allowed_referers = sea_orm::Set(None);
-        } else {
-            // split allowed ips on ',' and try to parse them all. error on invalid input
-            let allowed_referers = allowed_referers
-                .split(',')
-                .map(|x| HeaderValue::from_str(x.trim()))
-                .collect::<Result<Vec<_>, _>>()?
-                .into_iter()
-                .map(|x| Referer::decode(&mut [x].iter()))
-                .collect::<Result<Vec<_>, _>>()?;
-
-            // parse worked. now we can put it back together.
-            // but we can't go directly to String.
-            // so we convert to HeaderValues first
-            let mut header_map = vec![];
-            for x in allowed_referers {
-                x.encode(&mut header_map);
-            }
-
-            // convert HeaderValues to Strings
-            // since we got these from strings, this should always work (unless we figure out using bytes)
-            let allowed_referers = header_map
-                .into_iter()
-                .map(|x| x.to_str().map(|x| x.to_string()))
-                .collect::<Result<Vec<_>, _>>()?;
-
-            // join strings together with commas
-            let allowed_referers: String =
-                Itertools::intersperse(allowed_referers.into_iter(), ", ".to_string()).collect();
-
-            uk.allowed_referers = sea_orm::Set(Some(allowed_referers));
-        }
+        uk.allowed_referers = sea_orm::Set(parse_allowed_referers(&allowed_referers)?);
     }
 
     if let Some(allowed_user_agents) = payload.allowed_user_agents {
@@ -243,6 +386,43 @@ pub async fn rpc_keys_management(
         }
     }
 
+    let mut new_shadow_secret = None;
+
+    if let Some(shadow_url) = payload.shadow_url {
+        if shadow_url.is_empty() {
+            uk.shadow_url = sea_orm::Set(None);
+            uk.shadow_secret = sea_orm::Set(None);
+        } else {
+            let shadowing = &app.config.request_shadowing;
+
+            let user_tier = user_tier::Entity::find_by_id(user.user_tier_id)
+                .one(db_replica.conn())
+                .await?
+                .web3_context("no related user tier")?;
+
+            if !shadowing.enabled || !shadowing.tiers.contains(&user_tier.title) {
+                return Err(Web3ProxyError::InvalidParam(
+                    "shadow_url",
+                    "request shadowing is not available on this tier".into(),
+                ));
+            }
+
+            let shadow_url = parse_shadow_url(&shadow_url)?;
+
+            // the secret is only shown in this response
+            let mut secret = [0u8; 32];
+            SystemRandom::new()
+                .fill(&mut secret)
+                .map_err(|_| anyhow::anyhow!("unable to make a shadow secret"))?;
+            let secret = hex::encode(secret);
+
+            uk.shadow_url = sea_orm::Set(Some(shadow_url));
+            uk.shadow_secret = sea_orm::Set(Some(app.seal_row(secret.clone())?));
+
+            new_shadow_secret = Some(secret);
+        }
+    }
+
     let uk = if uk.is_changed() {
         let db_conn = app.db_conn().web3_context("login requires a db")?;
 
@@ -255,5 +435,316 @@ pub async fn rpc_keys_management(
 
     let uk = uk.try_into_model()?;
 
-    Ok(Json(uk).into_response())
+    let mut response = serde_json::to_value(uk)?;
+
+    if let Some(secret_key) = new_secret_key {
+        response["secret_key"] = json!(secret_key.to_string());
+    }
+
+    if let Some(shadow_secret) = new_shadow_secret {
+        response["shadow_secret"] = json!(shadow_secret);
+    }
+
+    Ok(Json(response).into_response())
+}
+
+/// the JSON input to the `rpc_key_rotate_post` handler
+#[derive(Debug, Default, Deserialize)]
+pub struct RotateRpcKey {
+    /// how long the old secret keeps working. at most `rpc_key_rotation_grace_seconds`. 0 stops it right away
+    grace_seconds: Option<u64>,
+}
+
+/// `POST /user/keys/:id/rotate` -- Use a bearer token to give a key a new secret.
+/// The old secret keeps working for a grace period and then expires. Requests with either secret count as this key.
+#[debug_handler]
+pub async fn rpc_key_rotate_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+    payload: Option<Json<RotateRpcKey>>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = app.db_conn().web3_context("rotating keys needs a db")?;
+
+    // rows that only hold a rotated-away secret can't be rotated again
+    let uk = rpc_key::Entity::find()
+        .filter(rpc_key::Column::UserId.eq(user.id))
+        .filter(rpc_key::Column::Id.eq(rpc_key_id))
+        .filter(rpc_key::Column::SupersededBy.is_null())
+        .one(&db_conn)
+        .await
+        .web3_context("failed loading user's key")?
+        .ok_or(Web3ProxyError::RpcKeyNotFound)?;
+
+    let max_grace_seconds = app.config.rpc_key_rotation_grace_seconds;
+
+    let grace_seconds = payload
+        .and_then(|x| x.0.grace_seconds)
+        .unwrap_or(max_grace_seconds)
+        .min(max_grace_seconds);
+
+    let expires_at = Utc::now() + chrono::Duration::seconds(grace_seconds as i64);
+
+    let new_secret_key = RpcSecretKey::new();
+
+    let txn = db_conn.begin().await?;
+
+    // the key keeps its id (and so its stats and settings). only the secret changes
+    let mut new_uk = uk.clone().into_active_model();
+    new_uk.secret_key = sea_orm::Set(None);
+    new_uk.secret_key_hash = sea_orm::Set(Some(app.rpc_key_hasher.hash(new_secret_key)));
+
+    let new_uk = new_uk.update(&txn).await?;
+
+    if grace_seconds > 0 {
+        // the old secret moves to a row of its own. it is never deleted so that old stats and logs still make sense
+        let old_uk = rpc_key::ActiveModel {
+            user_id: sea_orm::Set(uk.user_id),
+            secret_key: sea_orm::Set(uk.secret_key),
+            secret_key_hash: sea_orm::Set(uk.secret_key_hash),
+            description: sea_orm::Set(Some(format!("rotated from key {}", uk.id))),
+            log_level: sea_orm::Set(uk.log_level),
+            org_id: sea_orm::Set(uk.org_id),
+            superseded_by: sea_orm::Set(Some(uk.id)),
+            expires_at: sea_orm::Set(Some(expires_at)),
+            ..Default::default()
+        };
+
+        old_uk.insert(&txn).await?;
+    }
+
+    txn.commit().await?;
+
+    // the old secret's cached checks don't know that it expires
+    app.publish_rpc_key_change(RpcKeyFrozen {
+        rpc_key_id,
+        frozen: false,
+    })
+    .await?;
+
+    let mut response = serde_json::to_value(new_uk)?;
+
+    response["secret_key"] = json!(new_secret_key.to_string());
+    response["previous_secret_expires_at"] = json!(expires_at);
+
+    Ok(Json(response).into_response())
+}
+
+/// `GET /user/keys/:id/rate_limit` -- Use a bearer token to see why a key is being throttled.
+/// Limits are shared by all of the user's keys, so this shows the user's usage and not just this key's.
+/// Subusers can see this for keys shared with them if they can see the key's stats.
+#[debug_handler]
+pub async fn rpc_key_rate_limit_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app
+        .db_replica()
+        .web3_context("db_replica is required to fetch a key's rate limits")?;
+
+    let rpc_key = rpc_key::Entity::find_by_id(rpc_key_id)
+        .one(db_replica.conn())
+        .await
+        .web3_context("failed loading rpc key")?
+        .ok_or(Web3ProxyError::NotFound)?;
+
+    if rpc_key.user_id != user.id {
+        let subuser = secondary_user::Entity::find()
+            .filter(secondary_user::Column::UserId.eq(user.id))
+            .filter(secondary_user::Column::RpcSecretKeyId.eq(rpc_key.id))
+            .one(db_replica.conn())
+            .await
+            .web3_context("failed loading subuser")?;
+
+        if !subuser.map_or(false, |x| subuser_can_view_stats(&x)) {
+            return Err(Web3ProxyError::AccessDenied);
+        }
+    }
+
+    let response_json = rate_limit_state(&app, rpc_key.id).await?;
+
+    Ok(Json(response_json).into_response())
+}
+
+/// The limits on a key and how much of them it is using right now. Shown on `GET /user/keys/:id/rate_limit`
+async fn rate_limit_state(
+    app: &Web3ProxyApp,
+    rpc_key_id: u64,
+) -> Web3ProxyResult<serde_json::Value> {
+    let authorization_checks = app
+        .authorization_checks_by_id(
+            ProxyMode::Best,
+            rpc_key_id.try_into().expect("db ids are never 0"),
+        )
+        .await?;
+
+    let user_id =
+        NonZeroU64::try_from(authorization_checks.user_id).or(Err(Web3ProxyError::UserIdZero))?;
+
+    // None means unlimited
+    let requests_remaining = app
+        .peek_token_buckets(&authorization_checks)
+        .await
+        .web3_context("failed peeking at the key's token buckets")?;
+
+    // the key's own bucket is the one that matters if it has one
+    let token_bucket = authorization_checks
+        .key_token_bucket
+        .or(authorization_checks.token_bucket);
+
+    let concurrent_requests = match authorization_checks.max_concurrent_requests {
+        Some(max_concurrent_requests) => app
+            .user_semaphores
+            .available_permits(&user_id)
+            .map(|x| (max_concurrent_requests as usize).saturating_sub(x))
+            .unwrap_or_default(),
+        None => 0,
+    };
+
+    let response_json = json!({
+        "rpc_key_id": rpc_key_id,
+        "max_requests_per_period": authorization_checks.max_requests_per_period,
+        "burst_capacity": token_bucket.map(|x| x.burst),
+        "sustained_requests_per_period": token_bucket.map(|x| x.sustained),
+        "period_seconds": RATE_LIMIT_PERIOD_SECONDS,
+        "requests_remaining": requests_remaining,
+        "max_concurrent_requests": authorization_checks.max_concurrent_requests,
+        "concurrent_requests": concurrent_requests,
+        "max_queue_millis": authorization_checks.max_queue_millis,
+        "max_response_bytes": authorization_checks.max_response_bytes,
+        "recent_rejections": app.recent_rate_limit_rejections(user_id),
+        "recent_rejections_seconds": RATE_LIMIT_REJECTIONS_PERIOD.as_secs(),
+    });
+
+    Ok(response_json)
+}
+
+/// `GET /user/keys/:id/canary_hits` -- Use a bearer token to see who used a canary key.
+/// Only the key's owner can see these. The newest 100 are shown.
+#[debug_handler]
+pub async fn rpc_key_canary_hits_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+) -> Web3ProxyResponse {
+    let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = app
+        .db_replica()
+        .web3_context("db_replica is required to fetch a key's canary hits")?;
+
+    let rpc_key = rpc_key::Entity::find_by_id(rpc_key_id)
+        .filter(rpc_key::Column::UserId.eq(user.id))
+        .one(db_replica.conn())
+        .await
+        .web3_context("failed loading rpc key")?
+        .ok_or(Web3ProxyError::NotFound)?;
+
+    let hits = canary_hit::Entity::find()
+        .filter(canary_hit::Column::RpcKeyId.eq(rpc_key.id))
+        .order_by_desc(canary_hit::Column::Timestamp)
+        .limit(100)
+        .all(db_replica.conn())
+        .await
+        .web3_context("failed loading canary hits")?;
+
+    let response_json = json!({
+        "rpc_key_id": rpc_key.id,
+        "canary": rpc_key.canary,
+        "canary_freeze_siblings": rpc_key.canary_freeze_siblings,
+        "hits": hits,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{AuthorizationChecks, TokenBucketLimit};
+    use crate::test_utils::spawn_test_app;
+
+    #[tokio::test]
+    async fn test_rate_limit_state() {
+        let app = spawn_test_app().await;
+
+        let rpc_key_id = NonZeroU64::new(5).unwrap();
+        let user_id = NonZeroU64::new(3).unwrap();
+
+        let user_bucket = TokenBucketLimit {
+            burst: 100,
+            sustained: 1_000,
+        };
+        let key_bucket = TokenBucketLimit {
+            burst: 10,
+            sustained: 60,
+        };
+
+        app.rpc_key_id_cache
+            .try_insert(
+                rpc_key_id,
+                AuthorizationChecks {
+                    user_id: user_id.get(),
+                    rpc_secret_key_id: Some(rpc_key_id),
+                    max_requests_per_period: Some(1_000),
+                    token_bucket: Some(user_bucket),
+                    key_token_bucket: Some(key_bucket),
+                    max_concurrent_requests: Some(4),
+                    max_queue_millis: Some(250),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let x = rate_limit_state(&app, rpc_key_id.get()).await.unwrap();
+
+        assert_eq!(x["rpc_key_id"], 5);
+        assert_eq!(x["max_requests_per_period"], 1_000);
+        // the key's own bucket is shown instead of the user's
+        assert_eq!(x["burst_capacity"], 10);
+        assert_eq!(x["sustained_requests_per_period"], 60);
+        assert_eq!(x["period_seconds"], RATE_LIMIT_PERIOD_SECONDS);
+        // no redis, so nothing to peek at
+        assert_eq!(x["requests_remaining"], serde_json::Value::Null);
+        assert_eq!(x["max_concurrent_requests"], 4);
+        assert_eq!(x["concurrent_requests"], 0);
+        assert_eq!(x["max_queue_millis"], 250);
+        assert_eq!(x["recent_rejections"], 0);
+
+        // requests in flight and rejections are counted for the user, so every one of their keys shows them
+        let _permit = app.user_semaphores.acquire(&user_id, 4).await.unwrap();
+        app.record_rate_limit_rejection(user_id.get()).await;
+        app.record_rate_limit_rejection(user_id.get()).await;
+
+        let x = rate_limit_state(&app, rpc_key_id.get()).await.unwrap();
+
+        assert_eq!(x["concurrent_requests"], 1);
+        assert_eq!(x["recent_rejections"], 2);
+        assert_eq!(
+            x["recent_rejections_seconds"],
+            RATE_LIMIT_REJECTIONS_PERIOD.as_secs()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_state_inactive_key() {
+        let app = spawn_test_app().await;
+
+        let rpc_key_id = NonZeroU64::new(6).unwrap();
+
+        // a key that was deleted or deactivated is cached without a user
+        app.rpc_key_id_cache
+            .try_insert(rpc_key_id, AuthorizationChecks::default())
+            .unwrap();
+
+        assert!(matches!(
+            rate_limit_state(&app, rpc_key_id.get()).await,
+            Err(Web3ProxyError::UserIdZero)
+        ));
+    }
 }
@@ -1,13 +1,11 @@
 //! Handle registration, logins, and managing account data.
 use crate::app::Web3ProxyApp;
 use crate::frontend::errors::{Web3ProxyErrorContext, Web3ProxyResponse};
-use crate::http_params::{
-    get_chain_id_from_params, get_page_from_params, get_query_start_from_params,
-};
+use crate::http_params::{Params, RevertLogsParams, StatsParams};
 use crate::stats::influxdb_queries::query_user_stats;
+use crate::stats::schema::{STAT_FIELDS, STAT_FILTERS, STAT_GROUPS, SUGGESTED_WINDOW_SECONDS};
 use crate::stats::StatType;
 use axum::{
-    extract::Query,
     headers::{authorization::Bearer, Authorization},
     response::IntoResponse,
     Extension, Json, TypedHeader,
@@ -25,13 +23,13 @@ use std::sync::Arc;
 pub async fn user_revert_logs_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
-    Query(params): Query<HashMap<String, String>>,
+    Params(params): Params<RevertLogsParams>,
 ) -> Web3ProxyResponse {
     let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
 
-    let chain_id = get_chain_id_from_params(app.as_ref(), &params)?;
-    let query_start = get_query_start_from_params(&params)?;
-    let page = get_page_from_params(&params)?;
+    let chain_id = params.chain_id.unwrap_or(app.config.chain_id);
+    let query_start = params.query_start;
+    let page = params.page;
 
     // TODO: page size from config
     let page_size = 1_000;
@@ -95,7 +93,7 @@ pub async fn user_revert_logs_get(
 pub async fn user_stats_aggregated_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
-    Query(params): Query<HashMap<String, String>>,
+    Params(params): Params<StatsParams>,
 ) -> Web3ProxyResponse {
     let response = query_user_stats(&app, bearer, &params, StatType::Aggregated).await?;
 
@@ -115,9 +113,31 @@ pub async fn user_stats_aggregated_get(
 pub async fn user_stats_detailed_get(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
-    Query(params): Query<HashMap<String, String>>,
+    Params(params): Params<StatsParams>,
 ) -> Web3ProxyResponse {
     let response = query_user_stats(&app, bearer, &params, StatType::Detailed).await?;
 
     Ok(response)
 }
+
+/// `GET /user/stats/schema` -- Public endpoint that describes the items in `/user/stats/aggregated` and `/user/stats/detailed`.
+/// Includes the fields and their units, what the items are grouped by, the query params, and the limits on windows.
+#[debug_handler]
+pub async fn user_stats_schema_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+) -> Web3ProxyResponse {
+    let response_json = json!({
+        "fields": STAT_FIELDS,
+        "groups": STAT_GROUPS,
+        "filters": STAT_FILTERS,
+        "windows": {
+            "default_seconds": 60,
+            "suggested_seconds": SUGGESTED_WINDOW_SECONDS,
+            "max_buckets": app.config.stats_max_buckets,
+            "public_max_range_seconds": app.config.public_stats_max_range_seconds,
+            "query_timeout_seconds": app.config.stats_query_timeout_seconds,
+        },
+    });
+
+    Ok(Json(response_json).into_response())
+}
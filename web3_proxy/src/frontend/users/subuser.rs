@@ -2,9 +2,9 @@
 use crate::app::Web3ProxyApp;
 use crate::frontend::authorization::RpcSecretKey;
 use crate::frontend::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse};
+use crate::http_params::{ModifySubuserParams, Params, SubuserStatus, SubusersParams};
 use anyhow::Context;
 use axum::{
-    extract::Query,
     headers::{authorization::Bearer, Authorization},
     response::IntoResponse,
     Extension, Json, TypedHeader,
@@ -28,12 +28,23 @@ use migration::sea_orm::TransactionTrait;
 use serde_json::json;
 use std::sync::Arc;
 use ulid::{self, Ulid};
-use uuid::Uuid;
+
+/// Owners and admins can always see a shared key's stats. Collaborators need the key's owner to grant `stats_access`
+pub fn subuser_can_view_stats(subuser: &secondary_user::Model) -> bool {
+    match subuser.role {
+        Role::Owner | Role::Admin => true,
+        Role::Collaborator => subuser.stats_access,
+    }
+}
+
+/// Only owners and admins can see what a shared key has spent
+pub fn subuser_can_view_balance(subuser: &secondary_user::Model) -> bool {
+    matches!(subuser.role, Role::Owner | Role::Admin)
+}
 
 pub async fn get_keys_as_subuser(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
-    Query(_params): Query<HashMap<String, String>>,
 ) -> Web3ProxyResponse {
     // First, authenticate
     let (subuser, _semaphore) = app.bearer_is_authorized(bearer).await?;
@@ -78,9 +89,14 @@ pub async fn get_keys_as_subuser(
                 match rpc_owner {
                     Some(inner_rpc_owner) => {
                         let mut tmp = HashMap::new();
-                        tmp.insert("rpc-key", serde_json::Value::String(Ulid::from(rpc_key.secret_key).to_string()));
+                        // keys saved only as a hash can't be shown. the id still identifies them
+                        tmp.insert("rpc-key", json!(rpc_key.secret_key.map(|x| Ulid::from(x).to_string())));
+                        tmp.insert("rpc-key-id", json!(rpc_key.id));
                         tmp.insert("rpc-owner", serde_json::Value::String(format!("{:?}", Address::from_slice(&inner_rpc_owner.address))));
-                        tmp.insert("role", serde_json::Value::String(format!("{:?}", secondary_user_entities.get(&rpc_key.id).unwrap().role))); // .to_string() returns ugly "'...'"
+                        let secondary_user = secondary_user_entities.get(&rpc_key.id).unwrap();
+                        tmp.insert("role", serde_json::Value::String(format!("{:?}", secondary_user.role))); // .to_string() returns ugly "'...'"
+                        tmp.insert("stats_access", serde_json::Value::Bool(subuser_can_view_stats(secondary_user)));
+                        tmp.insert("balance_access", serde_json::Value::Bool(subuser_can_view_balance(secondary_user)));
                         Some(tmp)
                     },
                     None => {
@@ -98,7 +114,7 @@ pub async fn get_keys_as_subuser(
 pub async fn get_subusers(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
-    Query(mut params): Query<HashMap<String, String>>,
+    Params(params): Params<SubusersParams>,
 ) -> Web3ProxyResponse {
     // First, authenticate
     let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
@@ -107,23 +123,16 @@ pub async fn get_subusers(
         .db_replica()
         .context("getting replica db for user's revert logs")?;
 
-    let rpc_key: Ulid = params
-        .remove("rpc_key")
-        // TODO: map_err so this becomes a 500. routing must be bad
-        .ok_or(Web3ProxyError::BadRequest(
-            "You have not provided the 'rpc_key' whose access to modify".to_string(),
-        ))?
-        .parse()
-        .context(format!("unable to parse rpc_key {:?}", params))?;
+    let rpc_key = params
+        .rpc_key
+        .ok_or(Web3ProxyError::MissingParam("rpc_key"))?;
 
     // Get the rpc key id
     let rpc_key = rpc_key::Entity::find()
-        .filter(rpc_key::Column::SecretKey.eq(Uuid::from(rpc_key)))
+        .filter(app.rpc_key_hasher.condition(rpc_key.into()))
         .one(db_replica.conn())
         .await?
-        .ok_or(Web3ProxyError::BadRequest(
-            "The provided RPC key cannot be found".to_string(),
-        ))?;
+        .ok_or(Web3ProxyError::RpcKeyNotFound)?;
 
     // Get all secondary users that have access to this rpc key
     let secondary_user_entities = secondary_user::Entity::find()
@@ -159,7 +168,9 @@ pub async fn get_subusers(
                 let mut tmp = HashMap::new();
                 // .encode_hex()
                 tmp.insert("address", serde_json::Value::String(format!("{:?}", Address::from_slice(&subuser.address))));
-                tmp.insert("role", serde_json::Value::String(format!("{:?}", secondary_user_entities.get(&subuser.id).unwrap().role)));
+                let secondary_user = secondary_user_entities.get(&subuser.id).unwrap();
+                tmp.insert("role", serde_json::Value::String(format!("{:?}", secondary_user.role)));
+                tmp.insert("stats_access", serde_json::Value::Bool(subuser_can_view_stats(secondary_user)));
                 json!(tmp)
             })
             .collect::<Vec::<_>>(),
@@ -172,7 +183,7 @@ pub async fn get_subusers(
 pub async fn modify_subuser(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
     TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
-    Query(mut params): Query<HashMap<String, String>>,
+    Params(params): Params<ModifySubuserParams>,
 ) -> Web3ProxyResponse {
     // First, authenticate
     let (user, _semaphore) = app.bearer_is_authorized(bearer).await?;
@@ -185,59 +196,29 @@ pub async fn modify_subuser(
 
     // Then, distinguish the endpoint to modify
     let rpc_key_to_modify: Ulid = params
-        .remove("rpc_key")
-        // TODO: map_err so this becomes a 500. routing must be bad
-        .ok_or(Web3ProxyError::BadRequest(
-            "You have not provided the 'rpc_key' whose access to modify".to_string(),
-        ))?
-        .parse::<Ulid>()
-        .context(format!("unable to parse rpc_key {:?}", params))?;
-    // let rpc_key_to_modify: Uuid = ulid::serde::ulid_as_uuid::deserialize(rpc_key_to_modify)?;
+        .rpc_key
+        .ok_or(Web3ProxyError::MissingParam("rpc_key"))?;
 
     let subuser_address: Address = params
-        .remove("subuser_address")
-        // TODO: map_err so this becomes a 500. routing must be bad
-        .ok_or(Web3ProxyError::BadRequest(
-            "You have not provided the 'user_address' whose access to modify".to_string(),
-        ))?
-        .parse()
-        .context(format!("unable to parse subuser_address {:?}", params))?;
+        .subuser_address
+        .ok_or(Web3ProxyError::MissingParam("subuser_address"))?;
 
     // TODO: Check subuser address for eip55 checksum
 
-    let keep_subuser: bool = match params
-        .remove("new_status")
-        // TODO: map_err so this becomes a 500. routing must be bad
-        .ok_or(Web3ProxyError::BadRequest(
-            "You have not provided the new_stats key in the request".to_string(),
-        ))?
-        .as_str()
-    {
-        "upsert" => Ok(true),
-        "remove" => Ok(false),
-        _ => Err(Web3ProxyError::BadRequest(
-            "'new_status' must be one of 'upsert' or 'remove'".to_string(),
-        )),
-    }?;
-
-    let new_role: Role = match params
-        .remove("new_role")
-        // TODO: map_err so this becomes a 500. routing must be bad
-        .ok_or(Web3ProxyError::BadRequest(
-            "You have not provided the new_role key in the request".to_string(),
-        ))?
-        .as_str()
-    {
-        // TODO: Technically, if this is the new owner, we should transpose the full table.
-        // For now, let's just not allow the primary owner to just delete his account
-        // (if there is even such a functionality)
-        "owner" => Ok(Role::Owner),
-        "admin" => Ok(Role::Admin),
-        "collaborator" => Ok(Role::Collaborator),
-        _ => Err(Web3ProxyError::BadRequest(
-            "'new_role' must be one of 'owner', 'admin', 'collaborator'".to_string(),
-        )),
-    }?;
+    let keep_subuser = params
+        .new_status
+        .ok_or(Web3ProxyError::MissingParam("new_status"))?
+        == SubuserStatus::Upsert;
+
+    // TODO: Technically, if this is the new owner, we should transpose the full table.
+    // For now, let's just not allow the primary owner to just delete his account
+    // (if there is even such a functionality)
+    let new_role: Role = params
+        .new_role
+        .ok_or(Web3ProxyError::MissingParam("new_role"))?
+        .into();
+
+    let stats_access = params.stats_access;
 
     // ---------------------------
     // First, check if the user exists as a user. If not, add them
@@ -249,18 +230,14 @@ pub async fn modify_subuser(
         .await?;
 
     let rpc_key_entity = rpc_key::Entity::find()
-        .filter(rpc_key::Column::SecretKey.eq(Uuid::from(rpc_key_to_modify)))
+        .filter(app.rpc_key_hasher.condition(rpc_key_to_modify.into()))
         .one(db_replica.conn())
         .await?
-        .ok_or(Web3ProxyError::BadRequest(
-            "Provided RPC key does not exist!".to_owned(),
-        ))?;
+        .ok_or(Web3ProxyError::RpcKeyNotFound)?;
 
     // Make sure that the user owns the rpc_key_entity
     if rpc_key_entity.user_id != user.id {
-        return Err(Web3ProxyError::BadRequest(
-            "you must own the RPC for which you are giving permissions out".to_string(),
-        ));
+        return Err(Web3ProxyError::NotRpcKeyOwner);
     }
 
     // TODO: There is a good chunk of duplicate logic as login-post. Consider refactoring ...
@@ -278,11 +255,13 @@ pub async fn modify_subuser(
             let subuser = subuser.insert(&txn).await?;
 
             // create the user's first api key
+            // only the hash is saved. the subuser can create a key of their own once they log in
             let rpc_secret_key = RpcSecretKey::new();
 
             let subuser_rpc_key = rpc_key::ActiveModel {
                 user_id: sea_orm::Set(subuser.id),
-                secret_key: sea_orm::Set(rpc_secret_key.into()),
+                secret_key: sea_orm::Set(None),
+                secret_key_hash: sea_orm::Set(Some(app.rpc_key_hasher.hash(rpc_secret_key))),
                 description: sea_orm::Set(None),
                 ..Default::default()
             };
@@ -307,9 +286,7 @@ pub async fn modify_subuser(
         }
         Some(subuser) => {
             if subuser.id == user.id {
-                return Err(Web3ProxyError::BadRequest(
-                    "you cannot make a subuser out of yourself".to_string(),
-                ));
+                return Err(Web3ProxyError::SubuserIsSelf);
             }
 
             // Let's say that a user that exists can actually also redeem a key in retrospect...
@@ -353,6 +330,9 @@ pub async fn modify_subuser(
             } else {
                 // Just change the role
                 active_subuser_entry_secondary_user.role = sea_orm::Set(new_role.clone());
+                if let Some(stats_access) = stats_access {
+                    active_subuser_entry_secondary_user.stats_access = sea_orm::Set(stats_access);
+                }
                 active_subuser_entry_secondary_user.save(&db_conn).await?;
                 action = "role modified";
             }
@@ -362,6 +342,7 @@ pub async fn modify_subuser(
                 user_id: sea_orm::Set(subuser.id),
                 rpc_secret_key_id: sea_orm::Set(rpc_key_entity.id),
                 role: sea_orm::Set(new_role.clone()),
+                stats_access: sea_orm::Set(stats_access.unwrap_or_default()),
                 ..Default::default()
             };
             active_subuser_entry_secondary_user.insert(&txn).await?;
@@ -385,6 +366,7 @@ pub async fn modify_subuser(
             "subuser_address": subuser_address,
             "keep_user": keep_subuser,
             "new_role": new_role,
+            "stats_access": stats_access,
             "action": action
         })),
     )
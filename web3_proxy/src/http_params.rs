@@ -1,17 +1,408 @@
+//! Query params for the http endpoints.
+//! Each endpoint deserializes its own struct with `Params`, so a new param is a new field.
 use crate::app::DatabaseReplica;
 use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
-use crate::{app::Web3ProxyApp, user_token::UserBearerToken};
+use crate::stats::influxdb_queries::StatsCursor;
+use crate::stats::schema::{is_stat_name, StatDimension};
+use crate::user_token::UserBearerToken;
 use anyhow::Context;
 use axum::{
+    async_trait,
+    extract::FromRequestParts,
     headers::{authorization::Bearer, Authorization},
+    http::request::Parts,
     TypedHeader,
 };
-use chrono::{NaiveDateTime, Utc};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Utc};
 use entities::login;
-use hashbrown::HashMap;
+use entities::sea_orm_active_enums::Role;
+use ethers::types::Address;
 use log::{debug, warn};
+use migration::sea_orm::prelude::Decimal;
 use migration::sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
 use redis_rate_limiter::{redis::AsyncCommands, RedisConnection};
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Deserializer};
+use ulid::Ulid;
+use url::form_urlencoded;
+
+/// Like axum's `Query`, but a bad param is a `Web3ProxyError` that names the param.
+/// Unknown params are ignored.
+#[derive(Debug)]
+pub struct Params<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Params<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Web3ProxyError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Self::from_query(parts.uri.query().unwrap_or_default())
+    }
+}
+
+impl<T: DeserializeOwned> Params<T> {
+    pub fn from_query(query: &str) -> Web3ProxyResult<Self> {
+        let deserializer =
+            serde_urlencoded::Deserializer::new(form_urlencoded::parse(query.as_bytes()));
+
+        serde_path_to_error::deserialize(deserializer)
+            .map(Self)
+            .map_err(|err| {
+                // the path is the name of the param that failed
+                Web3ProxyError::InvalidParam(
+                    "query",
+                    format!("{}: {}", err.path(), err.inner()).into(),
+                )
+            })
+    }
+}
+
+/// `GET /user/revert_logs`
+#[derive(Debug, Deserialize)]
+pub struct RevertLogsParams {
+    /// defaults to the proxy's chain. 0 is every chain
+    pub chain_id: Option<u64>,
+    #[serde(default = "thirty_days_ago", deserialize_with = "unix_timestamp")]
+    pub query_start: NaiveDateTime,
+    #[serde(default)]
+    pub page: u64,
+}
+
+/// `GET /user/stats/aggregate` and `GET /user/stats/detailed`
+#[derive(Debug, Deserialize)]
+pub struct StatsParams {
+    /// defaults to the proxy's chain. 0 is every chain
+    pub chain_id: Option<u64>,
+    #[serde(default = "thirty_days_ago", deserialize_with = "unix_timestamp")]
+    pub query_start: NaiveDateTime,
+    #[serde(default = "now", deserialize_with = "unix_timestamp")]
+    pub query_stop: NaiveDateTime,
+    #[serde(
+        default = "default_query_window_seconds",
+        deserialize_with = "window_seconds"
+    )]
+    pub query_window_seconds: u64,
+    /// Whether to floor the query's start and stop to the window so that buckets line up with the wall clock.
+    #[serde(default = "default_true")]
+    pub align: bool,
+    pub rpc_key_id: Option<u64>,
+    /// must match the bearer token's user
+    pub user_id: Option<u64>,
+    /// aggregate the org's keys and all of its members' keys instead of the user's. the user must be a member
+    pub org_id: Option<u64>,
+    #[serde(default)]
+    pub page: u64,
+    /// the most items to return. None returns every item
+    pub limit: Option<u64>,
+    /// `next_cursor` from the previous page. the other params must not change while paging
+    #[serde(default, deserialize_with = "stats_cursor")]
+    pub cursor: Option<StatsCursor>,
+    /// comma separated names of the fields to return on each item. None returns every field
+    #[serde(default, deserialize_with = "stat_fields")]
+    pub fields: Option<Vec<String>>,
+    /// comma separated tags to split items by. None keeps the default groups for the endpoint
+    #[serde(default, deserialize_with = "stat_dimensions")]
+    pub group_by: Option<Vec<StatDimension>>,
+    /// older clients send this. it must be empty or one of `STATS_COLUMNS`
+    #[serde(default, deserialize_with = "stats_column")]
+    pub query_stats_column: Option<String>,
+    #[serde(default)]
+    pub format: StatsFormat,
+}
+
+impl StatsParams {
+    /// only allow rpc_key_id to be set if user_id is also set.
+    /// this will keep people from reading someone else's keys.
+    pub fn rpc_key_id(&self, user_id: u64) -> Option<u64> {
+        self.rpc_key_id.filter(|_| user_id > 0)
+    }
+}
+
+pub const STATS_COLUMNS: [&str; 10] = [
+    "frontend_requests",
+    "backend_requests",
+    "cache_hits",
+    "cache_misses",
+    "no_servers",
+    "sum_request_bytes",
+    "sum_response_bytes",
+    "sum_response_millis",
+    "sum_credits_used",
+    "balance",
+];
+
+/// csv and parquet are only the items. the rest of the json response is not in the file
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsFormat {
+    #[default]
+    Json,
+    Csv,
+    Parquet,
+}
+
+/// `GET /user/orgs/:org_id/billing_export`
+#[derive(Debug, Deserialize)]
+pub struct OrgBillingExportParams {
+    /// "YYYY-MM" in UTC. defaults to the last full month
+    #[serde(default = "last_month", deserialize_with = "month")]
+    pub month: NaiveDate,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// `GET /admin/increase_balance`
+#[derive(Debug, Deserialize)]
+pub struct AdminIncreaseBalanceParams {
+    #[serde(default, deserialize_with = "address")]
+    pub user_address: Option<Address>,
+    pub note: Option<String>,
+    pub amount: Option<Decimal>,
+}
+
+/// `GET /admin/modify_role`
+#[derive(Debug, Deserialize)]
+pub struct AdminModifyRoleParams {
+    #[serde(default, deserialize_with = "address")]
+    pub user_address: Option<Address>,
+    pub user_tier_title: Option<String>,
+    /// must match the bearer token's user
+    pub user_id: Option<u64>,
+}
+
+/// `GET /admin/audit`
+#[derive(Debug, Deserialize)]
+pub struct AdminAuditParams {
+    /// only changes to this user's data
+    pub user_id: Option<u64>,
+    /// only changes made by this admin
+    pub actor_id: Option<u64>,
+    /// only this action. like "rpc_key_freeze"
+    pub action: Option<String>,
+    #[serde(default = "thirty_days_ago", deserialize_with = "unix_timestamp")]
+    pub query_start: NaiveDateTime,
+    #[serde(default = "now", deserialize_with = "unix_timestamp")]
+    pub query_stop: NaiveDateTime,
+    #[serde(default)]
+    pub page: u64,
+}
+
+/// `GET /admin/wallets/:address`
+#[derive(Debug, Deserialize)]
+pub struct AdminWalletParams {
+    /// how many days of stats to total. defaults to 30
+    #[serde(default = "default_admin_wallet_days")]
+    pub days: u64,
+    /// None totals every chain
+    pub chain_id: Option<u64>,
+}
+
+fn default_admin_wallet_days() -> u64 {
+    30
+}
+
+/// `GET /user/balance` and `GET /user/deposits`
+#[derive(Debug, Deserialize)]
+pub struct BalanceParams {
+    /// a key shared with the user. shows the key owner's balance instead of the user's own
+    pub rpc_key: Option<Ulid>,
+}
+
+/// `GET /user/subusers`
+#[derive(Debug, Deserialize)]
+pub struct SubusersParams {
+    pub rpc_key: Option<Ulid>,
+}
+
+/// `GET /user/subuser`
+#[derive(Debug, Deserialize)]
+pub struct ModifySubuserParams {
+    pub rpc_key: Option<Ulid>,
+    #[serde(default, deserialize_with = "address")]
+    pub subuser_address: Option<Address>,
+    pub new_status: Option<SubuserStatus>,
+    pub new_role: Option<SubuserRole>,
+    /// owners and admins can always see stats. this only matters for collaborators
+    pub stats_access: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubuserStatus {
+    Upsert,
+    Remove,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubuserRole {
+    Owner,
+    Admin,
+    Collaborator,
+}
+
+impl From<SubuserRole> for Role {
+    fn from(x: SubuserRole) -> Self {
+        match x {
+            SubuserRole::Owner => Role::Owner,
+            SubuserRole::Admin => Role::Admin,
+            SubuserRole::Collaborator => Role::Collaborator,
+        }
+    }
+}
+
+/// the websocket endpoints
+#[derive(Debug, Deserialize)]
+pub struct WebsocketParams {
+    /// a token from a dropped connection. its subscriptions are moved to the new connection
+    pub resume: Option<String>,
+}
+
+fn default_query_window_seconds() -> u64 {
+    60
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// TODO: return chrono::Utc instead?
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+fn thirty_days_ago() -> NaiveDateTime {
+    (Utc::now() - chrono::Duration::days(30)).naive_utc()
+}
+
+/// the first day of the previous month
+fn last_month() -> NaiveDate {
+    let this_month = Utc::now()
+        .date_naive()
+        .with_day(1)
+        .expect("every month has a first day");
+
+    this_month
+        .pred_opt()
+        .and_then(|x| x.with_day(1))
+        .expect("there is always a previous month")
+}
+
+/// unix epoch seconds
+fn unix_timestamp<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveDateTime, D::Error> {
+    let x = String::deserialize(deserializer)?;
+
+    let x: i64 = x
+        .parse()
+        .map_err(|_| D::Error::custom("not a unix timestamp"))?;
+
+    NaiveDateTime::from_timestamp_opt(x, 0)
+        .ok_or_else(|| D::Error::custom("unix timestamp out of range"))
+}
+
+/// "YYYY-MM". parsed as the first day of the month
+fn month<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveDate, D::Error> {
+    let x = String::deserialize(deserializer)?;
+
+    NaiveDate::parse_from_str(&format!("{}-01", x), "%Y-%m-%d")
+        .map_err(|_| D::Error::custom("not a month like 2023-06"))
+}
+
+fn window_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let x = String::deserialize(deserializer)?;
+
+    x.parse()
+        .map_err(|_| D::Error::custom("not a number of seconds"))
+}
+
+fn address<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Address>, D::Error> {
+    let x = String::deserialize(deserializer)?;
+
+    x.parse()
+        .map(Some)
+        .map_err(|_| D::Error::custom("not an address"))
+}
+
+fn stats_column<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let x = String::deserialize(deserializer)?;
+
+    if x.is_empty() {
+        Ok(None)
+    } else if STATS_COLUMNS.contains(&x.as_str()) {
+        Ok(Some(x))
+    } else {
+        Err(D::Error::custom(format!(
+            "must be empty, or one of: {}",
+            STATS_COLUMNS.join(", ")
+        )))
+    }
+}
+
+/// comma separated names from `/user/stats/schema`
+fn stat_fields<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    let x = String::deserialize(deserializer)?;
+
+    let fields: Vec<String> = x
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect();
+
+    if fields.is_empty() {
+        return Err(D::Error::custom("no fields"));
+    }
+
+    if let Some(unknown) = fields.iter().find(|x| !is_stat_name(x)) {
+        return Err(D::Error::custom(format!("unknown stat field {}", unknown)));
+    }
+
+    Ok(Some(fields))
+}
+
+fn stats_cursor<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<StatsCursor>, D::Error> {
+    let x = String::deserialize(deserializer)?;
+
+    StatsCursor::decode(&x)
+        .map(Some)
+        .ok_or_else(|| D::Error::custom("not a cursor from next_cursor"))
+}
+
+/// comma separated `StatDimension` names. Duplicates are ignored
+fn stat_dimensions<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<StatDimension>>, D::Error> {
+    let x = String::deserialize(deserializer)?;
+
+    let mut dimensions = vec![];
+
+    for name in x.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        let dimension = StatDimension::from_name(name)
+            .ok_or_else(|| D::Error::custom(format!("unknown group {}", name)))?;
+
+        if !dimensions.contains(&dimension) {
+            dimensions.push(dimension);
+        }
+    }
+
+    Ok(Some(dimensions))
+}
 
 /// get the attached address for the given bearer token.
 /// First checks redis. Then checks the database.
@@ -23,9 +414,9 @@ pub async fn get_user_id_from_params(
     db_replica: &DatabaseReplica,
     // this is a long type. should we strip it down?
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
-    params: &HashMap<String, String>,
+    user_id: Option<u64>,
 ) -> Web3ProxyResult<u64> {
-    match (bearer, params.get("user_id")) {
+    match (bearer, user_id) {
         (Some(TypedHeader(Authorization(bearer))), Some(user_id)) => {
             // check for the bearer cache key
             let user_bearer_token = UserBearerToken::try_from(bearer)?;
@@ -73,8 +464,6 @@ pub async fn get_user_id_from_params(
                 }
             };
 
-            let user_id: u64 = user_id.parse().context("Parsing user_id param")?;
-
             if bearer_user_id != user_id {
                 return Err(Web3ProxyError::AccessDenied);
             }
@@ -111,157 +500,99 @@ pub async fn get_user_id_from_params(
     }
 }
 
-/// only allow rpc_key to be set if user_id is also set.
-/// this will keep people from reading someone else's keys.
-/// 0 means none.
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-pub fn get_rpc_key_id_from_params(
-    user_id: u64,
-    params: &HashMap<String, String>,
-) -> anyhow::Result<u64> {
-    if user_id > 0 {
-        params.get("rpc_key_id").map_or_else(
-            || Ok(0),
-            |c| {
-                let c = c.parse()?;
+    fn stats_params(query: &str) -> Web3ProxyResult<StatsParams> {
+        Params::from_query(query).map(|Params(x)| x)
+    }
 
-                Ok(c)
-            },
-        )
-    } else {
-        Ok(0)
+    fn invalid_param(err: Web3ProxyError) -> String {
+        match err {
+            Web3ProxyError::InvalidParam(_, reason) => reason.to_string(),
+            err => panic!("{:?}", err),
+        }
     }
-}
 
-pub fn get_chain_id_from_params(
-    app: &Web3ProxyApp,
-    params: &HashMap<String, String>,
-) -> anyhow::Result<u64> {
-    params.get("chain_id").map_or_else(
-        || Ok(app.config.chain_id),
-        |c| {
-            let c = c.parse()?;
+    #[test]
+    fn test_unix_timestamp() {
+        let x = stats_params("query_start=1688169600&query_stop=1688256000").unwrap();
 
-            Ok(c)
-        },
-    )
-}
+        assert_eq!(x.query_start.timestamp(), 1_688_169_600);
+        assert_eq!(x.query_stop.timestamp(), 1_688_256_000);
 
-pub fn get_page_from_params(params: &HashMap<String, String>) -> anyhow::Result<u64> {
-    params.get("page").map_or_else::<anyhow::Result<u64>, _, _>(
-        || {
-            // no page in params. set default
-            Ok(0)
-        },
-        |x: &String| {
-            // parse the given timestamp
-            // TODO: error code 401
-            let x = x.parse().context("parsing page query from params")?;
+        let err = stats_params("query_start=yesterday").unwrap_err();
+        assert_eq!(invalid_param(err), "query_start: not a unix timestamp");
 
-            Ok(x)
-        },
-    )
-}
+        let err = stats_params(&format!("query_stop={}", i64::MAX)).unwrap_err();
+        assert_eq!(
+            invalid_param(err),
+            "query_stop: unix timestamp out of range"
+        );
+    }
 
-// TODO: return chrono::Utc instead?
-pub fn get_query_start_from_params(
-    params: &HashMap<String, String>,
-) -> anyhow::Result<chrono::NaiveDateTime> {
-    params.get("query_start").map_or_else(
-        || {
-            // no timestamp in params. set default
-            let x = chrono::Utc::now() - chrono::Duration::days(30);
-
-            Ok(x.naive_utc())
-        },
-        |x: &String| {
-            // parse the given timestamp
-            let x = x
-                .parse::<i64>()
-                .context("parsing start timestamp query param")?;
-
-            let x = NaiveDateTime::from_timestamp_opt(x, 0)
-                .context("parsing start timestamp query param")?;
-
-            Ok(x)
-        },
-    )
-}
+    #[test]
+    fn test_window_seconds() {
+        assert_eq!(stats_params("").unwrap().query_window_seconds, 60);
+        assert_eq!(
+            stats_params("query_window_seconds=3600")
+                .unwrap()
+                .query_window_seconds,
+            3600
+        );
+
+        for bad in ["-1", "1.5", "hour"] {
+            let err = stats_params(&format!("query_window_seconds={}", bad)).unwrap_err();
+            assert_eq!(
+                invalid_param(err),
+                "query_window_seconds: not a number of seconds"
+            );
+        }
+    }
 
-// TODO: return chrono::Utc instead?
-pub fn get_query_stop_from_params(
-    params: &HashMap<String, String>,
-) -> anyhow::Result<chrono::NaiveDateTime> {
-    params.get("query_stop").map_or_else(
-        || {
-            // no timestamp in params. set default
-            let x = chrono::Utc::now();
-
-            Ok(x.naive_utc())
-        },
-        |x: &String| {
-            // parse the given timestamp
-            let x = x
-                .parse::<i64>()
-                .context("parsing stop timestamp query param")?;
-
-            let x = NaiveDateTime::from_timestamp_opt(x, 0)
-                .context("parsing stop timestamp query param")?;
-
-            Ok(x)
-        },
-    )
-}
-
-pub fn get_query_window_seconds_from_params(
-    params: &HashMap<String, String>,
-) -> Web3ProxyResult<u64> {
-    params.get("query_window_seconds").map_or_else(
-        || {
-            // no query_window_seconds in params. set default
-            Ok(60)
-        },
-        |query_window_seconds: &String| {
-            // parse the given timestamp
-            query_window_seconds.parse::<u64>().map_err(|_| {
-                Web3ProxyError::BadRequest("Unable to parse query_window_seconds".to_string())
-            })
-        },
-    )
-}
-
-pub fn get_stats_column_from_params(params: &HashMap<String, String>) -> Web3ProxyResult<&str> {
-    params.get("query_stats_column").map_or_else(
-        || Ok(""),
-        |query_stats_column: &String| {
-            // Must be one of: Otherwise respond with an error ...
-            match query_stats_column.as_str() {
-                ""
-                | "frontend_requests"
-                | "backend_requests"
-                | "cache_hits"
-                | "cache_misses"
-                | "no_servers"
-                | "sum_request_bytes"
-                | "sum_response_bytes"
-                | "sum_response_millis"
-                | "sum_credits_used"
-                | "balance" => Ok(query_stats_column),
-                _ => Err(Web3ProxyError::BadRequest(
-                    "Unable to parse query_stats_column. It must be empty, or one of: \
-                    frontend_requests, \
-                    backend_requests, \
-                    cache_hits, \
-                    cache_misses, \
-                    no_servers, \
-                    sum_request_bytes, \
-                    sum_response_bytes, \
-                    sum_response_millis, \
-                    sum_credits_used, \
-                    balance"
-                        .to_string(),
-                )),
-            }
-        },
-    )
+    #[test]
+    fn test_address() {
+        let Params(x) = Params::<AdminModifyRoleParams>::from_query(
+            "user_address=0x0000000000000000000000000000000000000001",
+        )
+        .unwrap();
+        assert_eq!(x.user_address, Some(Address::from_low_u64_be(1)));
+
+        let Params(x) = Params::<AdminModifyRoleParams>::from_query("").unwrap();
+        assert_eq!(x.user_address, None);
+
+        let err = Params::<AdminModifyRoleParams>::from_query("user_address=0x1234").unwrap_err();
+        assert_eq!(invalid_param(err), "user_address: not an address");
+    }
+
+    #[test]
+    fn test_stats_column() {
+        assert_eq!(stats_params("").unwrap().query_stats_column, None);
+        assert_eq!(
+            stats_params("query_stats_column=")
+                .unwrap()
+                .query_stats_column,
+            None
+        );
+        assert_eq!(
+            stats_params("query_stats_column=cache_hits")
+                .unwrap()
+                .query_stats_column
+                .as_deref(),
+            Some("cache_hits")
+        );
+
+        let err = stats_params("query_stats_column=password").unwrap_err();
+        assert!(invalid_param(err).starts_with("query_stats_column: must be empty, or one of"));
+    }
+
+    #[test]
+    fn test_rpc_key_id_needs_user_id() {
+        let x = stats_params("rpc_key_id=5").unwrap();
+
+        // anonymous stats can't be filtered down to someone else's key
+        assert_eq!(x.rpc_key_id(0), None);
+        assert_eq!(x.rpc_key_id(1), Some(5));
+    }
 }
@@ -10,13 +10,16 @@ use std::borrow::Cow;
 use std::fmt;
 
 // TODO: &str here instead of String should save a lot of allocations
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Serialize)]
 pub struct JsonRpcRequest {
     pub jsonrpc: String,
     /// id could be a stricter type, but many rpcs do things against the spec
     pub id: Box<RawValue>,
     pub method: String,
     pub params: Option<serde_json::Value>,
+    /// requests without an "id" are notifications. the spec says we must not respond to them
+    #[serde(skip)]
+    pub notification: bool,
 }
 
 #[derive(From)]
@@ -52,6 +55,7 @@ impl JsonRpcRequest {
             id: id.to_raw_value(),
             method,
             params,
+            notification: false,
         };
 
         Ok(x)
@@ -66,18 +70,12 @@ impl fmt::Debug for JsonRpcRequest {
             .field("id", &self.id)
             .field("method", &self.method)
             .field("params", &self.params)
+            .field("notification", &self.notification)
             .finish()
     }
 }
 
-/// Requests can come in multiple formats
-#[derive(Debug, From)]
-pub enum JsonRpcRequestEnum {
-    Batch(Vec<JsonRpcRequest>),
-    Single(JsonRpcRequest),
-}
-
-impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
+impl<'de> Deserialize<'de> for JsonRpcRequest {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
@@ -89,31 +87,15 @@ impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
             Id,
             Method,
             Params,
-            // TODO: jsonrpc here, too?
         }
 
-        struct JsonRpcBatchVisitor;
+        struct JsonRpcRequestVisitor;
 
-        impl<'de> Visitor<'de> for JsonRpcBatchVisitor {
-            type Value = JsonRpcRequestEnum;
+        impl<'de> Visitor<'de> for JsonRpcRequestVisitor {
+            type Value = JsonRpcRequest;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("JsonRpcRequestEnum")
-            }
-
-            fn visit_seq<V>(self, mut seq: V) -> Result<JsonRpcRequestEnum, V::Error>
-            where
-                V: SeqAccess<'de>,
-            {
-                // TODO: what size should we use as the default?
-                let mut batch: Vec<JsonRpcRequest> =
-                    Vec::with_capacity(seq.size_hint().unwrap_or(10));
-
-                while let Ok(Some(s)) = seq.next_element::<JsonRpcRequest>() {
-                    batch.push(s);
-                }
-
-                Ok(JsonRpcRequestEnum::Batch(batch))
+                formatter.write_str("JsonRpcRequest")
             }
 
             fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
@@ -158,21 +140,86 @@ impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
                 // some providers don't follow the spec and dont include the jsonrpc key
                 // i think "2.0" should be a fine default to handle these incompatible clones
                 let jsonrpc = jsonrpc.unwrap_or_else(|| "2.0".to_string());
-                // TODO: Errors returned by the try operator get shown in an ugly way
-                let id = id.ok_or_else(|| de::Error::missing_field("id"))?;
                 let method = method.ok_or_else(|| de::Error::missing_field("method"))?;
 
+                // a request without an id is a notification. the client does not want a response
+                // a null id is NOT a notification. it gets a response with a null id
+                let (id, notification) = match id {
+                    Some(id) => (id, false),
+                    None => (JsonRpcId::None.to_raw_value(), true),
+                };
+
                 let params: Option<serde_json::Value> = match params {
                     None => Some(serde_json::Value::Array(vec![])),
                     Some(x) => Some(x),
                 };
 
-                let single = JsonRpcRequest {
+                Ok(JsonRpcRequest {
                     jsonrpc,
                     id,
                     method,
                     params,
-                };
+                    notification,
+                })
+            }
+        }
+
+        deserializer.deserialize_map(JsonRpcRequestVisitor)
+    }
+}
+
+/// Requests can come in multiple formats
+#[derive(Debug, From)]
+pub enum JsonRpcRequestEnum {
+    Batch(Vec<JsonRpcRequest>),
+    Single(JsonRpcRequest),
+}
+
+impl JsonRpcRequestEnum {
+    /// true if none of the requests expect a response
+    pub fn is_notification(&self) -> bool {
+        match self {
+            Self::Batch(x) => !x.is_empty() && x.iter().all(|x| x.notification),
+            Self::Single(x) => x.notification,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct JsonRpcBatchVisitor;
+
+        impl<'de> Visitor<'de> for JsonRpcBatchVisitor {
+            type Value = JsonRpcRequestEnum;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("JsonRpcRequestEnum")
+            }
+
+            fn visit_seq<V>(self, mut seq: V) -> Result<JsonRpcRequestEnum, V::Error>
+            where
+                V: SeqAccess<'de>,
+            {
+                // TODO: what size should we use as the default?
+                let mut batch: Vec<JsonRpcRequest> =
+                    Vec::with_capacity(seq.size_hint().unwrap_or(10));
+
+                while let Ok(Some(s)) = seq.next_element::<JsonRpcRequest>() {
+                    batch.push(s);
+                }
+
+                Ok(JsonRpcRequestEnum::Batch(batch))
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let single =
+                    JsonRpcRequest::deserialize(de::value::MapAccessDeserializer::new(map))?;
 
                 Ok(JsonRpcRequestEnum::Single(single))
             }
@@ -352,6 +399,24 @@ impl JsonRpcForwardedResponse {
     }
 }
 
+impl JsonRpcForwardedResponse {
+    /// Responses can come out of a shared cache or be coalesced with other requests.
+    /// Never trust the id or version already on them. Always echo what this client sent.
+    pub fn normalize(mut self, request: &JsonRpcRequest) -> Self {
+        self.id = request.id.clone();
+        self.jsonrpc = jsonrpc_version(&request.jsonrpc);
+        self
+    }
+}
+
+/// We only speak "2.0", but some old clients send "1.0" and expect to see it echoed back.
+pub fn jsonrpc_version(version: &str) -> &'static str {
+    match version {
+        "1.0" => "1.0",
+        _ => "2.0",
+    }
+}
+
 /// JSONRPC Responses can include one or many response objects.
 #[derive(Clone, Debug, From, Serialize)]
 #[serde(untagged)]
@@ -363,6 +428,7 @@ pub enum JsonRpcForwardedResponseEnum {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn this_deserialize_single() {
@@ -405,4 +471,172 @@ mod tests {
 
         assert!(matches!(output, JsonRpcRequestEnum::Batch(_)));
     }
+
+    #[test]
+    fn this_deserialize_notifications() {
+        let input = r#"[{"jsonrpc":"2.0","method":"eth_chainId","params":[]},{"jsonrpc":"2.0","method":"eth_chainId","params":[],"id":null},{"jsonrpc":"2.0","method":"eth_chainId","params":[],"id":1}]"#;
+
+        let output: JsonRpcRequestEnum = serde_json::from_str(input).unwrap();
+
+        let output = match output {
+            JsonRpcRequestEnum::Batch(x) => x,
+            _ => panic!("expected a batch"),
+        };
+
+        assert_eq!(output.len(), 3);
+
+        // a missing id is a notification
+        assert!(output[0].notification);
+        assert_eq!(output[0].id.to_string(), "null");
+
+        // a null id is not a notification
+        assert!(!output[1].notification);
+        assert_eq!(output[1].id.to_string(), "null");
+
+        assert!(!output[2].notification);
+        assert_eq!(output[2].id.to_string(), "1");
+
+        let input = r#"{"jsonrpc":"2.0","method":"eth_chainId"}"#;
+
+        let output: JsonRpcRequestEnum = serde_json::from_str(input).unwrap();
+
+        assert!(output.is_notification());
+    }
+
+    #[test]
+    fn this_normalize_response_ids() {
+        // the same cached data is shared by every client that asks the same question
+        let cached: JsonRpcResponseData =
+            serde_json::value::RawValue::from_string("\"0x1\"".to_string())
+                .unwrap()
+                .into();
+
+        let ids = [
+            "0",
+            "1",
+            "18446744073709551615",
+            "-1",
+            "1.5",
+            "null",
+            r#""""#,
+            r#""abc""#,
+            r#""a \"quoted\" id""#,
+            r#""01H0ABCDEFGHJKMNPQRSTVWXYZ""#,
+        ];
+
+        for (i, id) in ids.iter().enumerate() {
+            for version in ["2.0", "1.0", ""] {
+                let request = format!(
+                    r#"{{"jsonrpc":"{}","method":"eth_chainId","params":[],"id":{}}}"#,
+                    version, id
+                );
+
+                let request: JsonRpcRequest = serde_json::from_str(&request).unwrap();
+
+                // pretend this response was built for some other request
+                let other_id = ids[(i + 1) % ids.len()];
+                let other_id = RawValue::from_string(other_id.to_string()).unwrap();
+
+                let response =
+                    JsonRpcForwardedResponse::from_response_data(cached.clone(), other_id)
+                        .normalize(&request);
+
+                assert_eq!(response.id.get(), *id);
+                assert_eq!(
+                    response.jsonrpc,
+                    if version == "1.0" { "1.0" } else { "2.0" }
+                );
+
+                // the id must survive a round trip through serialization too
+                let response: serde_json::Value =
+                    serde_json::from_str(&serde_json::to_string(&response).unwrap()).unwrap();
+                let expected: serde_json::Value = serde_json::from_str(id).unwrap();
+
+                assert_eq!(response["id"], expected);
+                assert_eq!(response["result"], "0x1");
+            }
+        }
+    }
+
+    /// any id a client might send, as raw json
+    fn arb_id() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just("null".to_string()),
+            any::<u64>().prop_map(|x| x.to_string()),
+            any::<i64>().prop_map(|x| x.to_string()),
+            any::<f64>()
+                .prop_filter("json numbers are finite", |x| x.is_finite())
+                .prop_map(|x| serde_json::to_string(&x).unwrap()),
+            any::<String>().prop_map(|x| serde_json::to_string(&x).unwrap()),
+        ]
+    }
+
+    fn arb_version() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just("2.0".to_string()),
+            Just("1.0".to_string()),
+            any::<String>()
+        ]
+    }
+
+    fn arb_response_data() -> impl Strategy<Value = JsonRpcResponseData> {
+        prop_oneof![
+            any::<String>().prop_map(|x| json!(x).into()),
+            (any::<i64>(), any::<String>()).prop_map(|(code, message)| JsonRpcErrorData {
+                code,
+                message: message.into(),
+                data: None,
+            }
+            .into()),
+        ]
+    }
+
+    fn request_with(version: &str, id: &str) -> JsonRpcRequest {
+        let request = format!(
+            r#"{{"jsonrpc":{},"method":"eth_chainId","params":[],"id":{}}}"#,
+            serde_json::to_string(version).unwrap(),
+            id
+        );
+
+        serde_json::from_str(&request).unwrap()
+    }
+
+    proptest! {
+        #[test]
+        fn prop_normalize_echoes_id_and_version(
+            data in arb_response_data(),
+            id in arb_id(),
+            version in arb_version(),
+            other_id in arb_id(),
+            other_version in arb_version(),
+        ) {
+            let request = request_with(&version, &id);
+            let other_request = request_with(&other_version, &other_id);
+
+            let expected_version = jsonrpc_version(&version);
+            let expected_id: serde_json::Value = serde_json::from_str(&id).unwrap();
+
+            // a cached response is built with whatever id is at hand
+            let cached = JsonRpcForwardedResponse::from_response_data(
+                data.clone(),
+                other_request.id.clone(),
+            );
+
+            // a coalesced response was already normalized for a different client
+            let coalesced = cached.clone().normalize(&other_request);
+
+            for response in [cached, coalesced] {
+                let response = response.normalize(&request);
+
+                prop_assert_eq!(response.id.get(), id.as_str());
+                prop_assert_eq!(response.jsonrpc, expected_version);
+
+                let response: serde_json::Value =
+                    serde_json::from_str(&serde_json::to_string(&response).unwrap()).unwrap();
+
+                prop_assert_eq!(&response["id"], &expected_id);
+                prop_assert_eq!(&response["jsonrpc"], expected_version);
+            }
+        }
+    }
 }
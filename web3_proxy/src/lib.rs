@@ -9,9 +9,14 @@ pub mod pagerduty;
 pub mod prometheus;
 pub mod referral_code;
 pub mod response_cache;
+pub mod row_encryption;
 pub mod rpcs;
+pub mod semaphore_registry;
 pub mod stats;
+#[cfg(test)]
+pub mod test_utils;
 pub mod user_token;
+pub mod worker_pool;
 
 use serde::Deserialize;
 
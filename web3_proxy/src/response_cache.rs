@@ -1,14 +1,25 @@
 use crate::{
-    frontend::errors::Web3ProxyError, jsonrpc::JsonRpcErrorData, rpcs::blockchain::ArcBlock,
+    frontend::errors::{Web3ProxyError, Web3ProxyResult},
+    jsonrpc::JsonRpcErrorData,
+    rpcs::blockchain::{ArcBlock, Web3ProxyBlock},
 };
+use anyhow::Context;
+use axum::async_trait;
 use derive_more::From;
 use ethers::providers::ProviderError;
+use ethers::types::{H256, U64};
+use ethers::utils::keccak256;
+use futures::future::BoxFuture;
+use log::warn;
 use quick_cache_ttl::{CacheWithTTL, Weighter};
+use redis_rate_limiter::{redis, RedisPool};
+use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
 use std::{
     borrow::Cow,
     hash::{Hash, Hasher},
-    num::NonZeroU32,
+    num::{NonZeroU32, NonZeroU64},
+    sync::Arc,
 };
 
 #[derive(Clone, Debug, From, PartialEq, Eq)]
@@ -18,6 +29,8 @@ pub struct JsonRpcResponseCacheKey {
     pub method: String,
     pub params: Option<serde_json::Value>,
     pub cache_errors: bool,
+    /// tiers in `partitioned_cache_tiers` only see responses cached by their own requests
+    pub cache_partition: Option<NonZeroU64>,
 }
 
 impl Hash for JsonRpcResponseCacheKey {
@@ -29,7 +42,33 @@ impl Hash for JsonRpcResponseCacheKey {
         // make sure preserve_order feature is OFF
         self.params.as_ref().map(|x| x.to_string()).hash(state);
 
-        self.cache_errors.hash(state)
+        self.cache_errors.hash(state);
+
+        self.cache_partition.hash(state)
+    }
+}
+
+impl JsonRpcResponseCacheKey {
+    /// The same key on every replica. Blocks are identified by hash
+    pub fn shared_key(&self, chain_id: u64) -> String {
+        let key = format!(
+            "{:?}:{:?}:{}:{}:{}:{:?}",
+            self.from_block.as_ref().map(|x| x.hash),
+            self.to_block.as_ref().map(|x| x.hash),
+            self.method,
+            self.params
+                .as_ref()
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+            self.cache_errors,
+            self.cache_partition,
+        );
+
+        format!(
+            "web3_proxy:{}:response:{:?}",
+            chain_id,
+            H256::from(keccak256(key))
+        )
     }
 }
 
@@ -39,6 +78,18 @@ pub type JsonRpcResponseCache =
 #[derive(Clone)]
 pub struct JsonRpcResponseWeigher;
 
+/// A lookup that got "null" from the backends.
+/// Things can only start existing when the head changes, so these are only valid for the head block they were seen at
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct NegativeCacheKey {
+    pub head_block_hash: H256,
+    pub method: String,
+    /// params are stored as a string because `serde_json::Value` does not implement `Hash`
+    pub params: String,
+}
+
+pub type NegativeResponseCache = CacheWithTTL<NegativeCacheKey, ()>;
+
 #[derive(Clone, Debug)]
 pub enum JsonRpcResponseData {
     Result {
@@ -126,6 +177,375 @@ impl TryFrom<ProviderError> for JsonRpcErrorData {
     }
 }
 
+/// Fetches a response that was not in the cache. Returning `false` keeps the response out of the cache
+pub type CacheFetch<'a> = BoxFuture<'a, Web3ProxyResult<(JsonRpcResponseData, bool)>>;
+
+/// What an admin purge can select a cached response by. Redis keys are hashed, so this is saved next to each shared response
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct CachedResponseInfo {
+    pub method: String,
+    pub params: Option<serde_json::Value>,
+    pub from_block: Option<U64>,
+    pub to_block: Option<U64>,
+}
+
+impl From<&JsonRpcResponseCacheKey> for CachedResponseInfo {
+    fn from(key: &JsonRpcResponseCacheKey) -> Self {
+        Self {
+            method: key.method.clone(),
+            params: key.params.clone(),
+            from_block: key.from_block.as_ref().and_then(|x| x.number),
+            to_block: key.to_block.as_ref().and_then(|x| x.number),
+        }
+    }
+}
+
+/// Picks the responses to remove from a cache
+pub type CachePurgeFilter<'a> = &'a (dyn Fn(&CachedResponseInfo) -> bool + Send + Sync);
+
+/// Where cached responses are kept. Picked with `response_cache_backend`
+#[async_trait]
+pub trait CacheBackend: Send + Sync {
+    /// The cached response for this key, or the response from `fetch`
+    async fn get_or_fetch(
+        &self,
+        key: JsonRpcResponseCacheKey,
+        fetch: CacheFetch<'_>,
+    ) -> Web3ProxyResult<JsonRpcResponseData>;
+
+    /// Remove every response that `filter` matches from every tier. Returns how many were removed
+    async fn remove_matching(&self, filter: CachePurgeFilter<'_>) -> Web3ProxyResult<usize>;
+}
+
+/// Each replica has its own cache. Identical requests that arrive at the same time only fetch once
+pub struct MemoryCacheBackend(pub Arc<JsonRpcResponseCache>);
+
+#[async_trait]
+impl CacheBackend for MemoryCacheBackend {
+    async fn get_or_fetch(
+        &self,
+        key: JsonRpcResponseCacheKey,
+        fetch: CacheFetch<'_>,
+    ) -> Web3ProxyResult<JsonRpcResponseData> {
+        match self.0.get_value_or_guard_async(key).await {
+            Ok(x) => Ok(x),
+            Err(guard) => {
+                let (response_data, cacheable) = fetch.await?;
+
+                // dropping the guard keeps the response out of the cache
+                if cacheable {
+                    // TODO: convert the Box<RawValue> to an Arc<RawValue>
+                    guard.insert(response_data.clone());
+                }
+
+                Ok(response_data)
+            }
+        }
+    }
+
+    async fn remove_matching(&self, filter: CachePurgeFilter<'_>) -> Web3ProxyResult<usize> {
+        Ok(self.0.remove_matching(|key, _| filter(&key.into())))
+    }
+}
+
+/// How a response is saved in redis
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum SharedResponse {
+    Result(Box<RawValue>),
+    Error(JsonRpcErrorData),
+}
+
+/// A response and what it was for
+#[derive(Debug, Deserialize, Serialize)]
+struct SharedEntry {
+    info: CachedResponseInfo,
+    response: SharedResponse,
+}
+
+impl SharedEntry {
+    fn new(key: &JsonRpcResponseCacheKey, value: &JsonRpcResponseData) -> Self {
+        let response = match value {
+            JsonRpcResponseData::Result { value, .. } => SharedResponse::Result(value.clone()),
+            JsonRpcResponseData::Error { value, .. } => SharedResponse::Error(value.clone()),
+        };
+
+        Self {
+            info: key.into(),
+            response,
+        }
+    }
+}
+
+impl From<SharedEntry> for JsonRpcResponseData {
+    fn from(value: SharedEntry) -> Self {
+        match value.response {
+            SharedResponse::Result(x) => x.into(),
+            SharedResponse::Error(x) => x.into(),
+        }
+    }
+}
+
+/// Every replica shares one cache in redis. Redis errors are logged and treated as misses.
+/// Blocks by hash are shared here too. They never change, so any replica can use a block that another replica fetched
+#[derive(Clone)]
+pub struct RedisCacheBackend {
+    pub chain_id: u64,
+    pub redis_pool: RedisPool,
+    pub ttl_ms: u64,
+}
+
+impl RedisCacheBackend {
+    fn response_prefix(&self) -> String {
+        format!("web3_proxy:{}:response:", self.chain_id)
+    }
+
+    fn block_prefix(&self) -> String {
+        format!("web3_proxy:{}:block:", self.chain_id)
+    }
+
+    async fn get_string(&self, key: String) -> Option<String> {
+        let mut redis_conn = match self.redis_pool.get().await {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("no redis connection for the shared cache: {:?}", err);
+                return None;
+            }
+        };
+
+        match redis::cmd("GET")
+            .arg(key)
+            .query_async::<_, Option<String>>(&mut redis_conn)
+            .await
+        {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("redis error while reading the shared cache: {:?}", err);
+                None
+            }
+        }
+    }
+
+    async fn set_string(&self, key: String, value: String) {
+        let mut redis_conn = match self.redis_pool.get().await {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("no redis connection for the shared cache: {:?}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = redis::cmd("SET")
+            .arg(key)
+            .arg(value)
+            .arg("PX")
+            .arg(self.ttl_ms)
+            .query_async::<_, ()>(&mut redis_conn)
+            .await
+        {
+            warn!("redis error while saving to the shared cache: {:?}", err);
+        }
+    }
+
+    /// Delete the keys that start with `prefix` and have a value that `filter` matches.
+    /// Redis can't search values, so every key with the prefix is scanned. Only use this for admin purges
+    async fn remove_values(
+        &self,
+        prefix: String,
+        filter: impl Fn(&str) -> bool + Send,
+    ) -> Web3ProxyResult<usize> {
+        let mut redis_conn = self
+            .redis_pool
+            .get()
+            .await
+            .context("no redis connection for the shared cache")?;
+
+        let pattern = format!("{}*", prefix);
+
+        let mut cursor = 0u64;
+        let mut removed = 0;
+
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(1_000)
+                .query_async(&mut redis_conn)
+                .await?;
+
+            if !keys.is_empty() {
+                let values: Vec<Option<String>> = redis::cmd("MGET")
+                    .arg(&keys)
+                    .query_async(&mut redis_conn)
+                    .await?;
+
+                let matched: Vec<&String> = keys
+                    .iter()
+                    .zip(values)
+                    .filter(|(_, value)| value.as_deref().map_or(false, &filter))
+                    .map(|(key, _)| key)
+                    .collect();
+
+                if !matched.is_empty() {
+                    removed += redis::cmd("DEL")
+                        .arg(matched)
+                        .query_async::<_, usize>(&mut redis_conn)
+                        .await?;
+                }
+            }
+
+            if next == 0 {
+                break;
+            }
+
+            cursor = next;
+        }
+
+        Ok(removed)
+    }
+
+    async fn get(&self, key: &JsonRpcResponseCacheKey) -> Option<JsonRpcResponseData> {
+        let cached = self.get_string(key.shared_key(self.chain_id)).await?;
+
+        match serde_json::from_str::<SharedEntry>(&cached) {
+            Ok(x) => Some(x.into()),
+            Err(err) => {
+                warn!("bad response in the shared cache: {:?}", err);
+                None
+            }
+        }
+    }
+
+    async fn insert(&self, key: &JsonRpcResponseCacheKey, value: &JsonRpcResponseData) {
+        let value = serde_json::to_string(&SharedEntry::new(key, value))
+            .expect("responses should always serialize");
+
+        self.set_string(key.shared_key(self.chain_id), value).await
+    }
+
+    pub async fn get_block(&self, hash: &H256) -> Option<ArcBlock> {
+        let cached = self
+            .get_string(format!("{}{:?}", self.block_prefix(), hash))
+            .await?;
+
+        match serde_json::from_str(&cached) {
+            Ok(x) => Some(x),
+            Err(err) => {
+                warn!("bad block in the shared cache: {:?}", err);
+                None
+            }
+        }
+    }
+
+    pub async fn insert_block(&self, block: &Web3ProxyBlock) {
+        let value = serde_json::to_string(&block.block).expect("blocks should always serialize");
+
+        self.set_string(format!("{}{:?}", self.block_prefix(), block.hash()), value)
+            .await
+    }
+
+    /// Remove the shared blocks numbered `from_block..=to_block`. None on either end is unbounded
+    pub async fn remove_blocks(
+        &self,
+        from_block: Option<U64>,
+        to_block: Option<U64>,
+    ) -> Web3ProxyResult<usize> {
+        self.remove_values(self.block_prefix(), |value| {
+            match serde_json::from_str::<ArcBlock>(value)
+                .ok()
+                .and_then(|x| x.number)
+            {
+                Some(num) => {
+                    from_block.map_or(true, |x| num >= x) && to_block.map_or(true, |x| num <= x)
+                }
+                // nothing can use a block without a number
+                None => true,
+            }
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl CacheBackend for RedisCacheBackend {
+    async fn get_or_fetch(
+        &self,
+        key: JsonRpcResponseCacheKey,
+        fetch: CacheFetch<'_>,
+    ) -> Web3ProxyResult<JsonRpcResponseData> {
+        if let Some(x) = self.get(&key).await {
+            return Ok(x);
+        }
+
+        let (response_data, cacheable) = fetch.await?;
+
+        if cacheable {
+            self.insert(&key, &response_data).await;
+        }
+
+        Ok(response_data)
+    }
+
+    async fn remove_matching(&self, filter: CachePurgeFilter<'_>) -> Web3ProxyResult<usize> {
+        self.remove_values(self.response_prefix(), |value| {
+            match serde_json::from_str::<SharedEntry>(value) {
+                Ok(x) => filter(&x.info),
+                // saved by an older version without its info. it can't be checked, so it is removed
+                Err(_) => true,
+            }
+        })
+        .await
+    }
+}
+
+/// Memory first, then redis. Hits from redis are kept in memory too
+pub struct TieredCacheBackend {
+    pub memory: Arc<JsonRpcResponseCache>,
+    pub redis: RedisCacheBackend,
+}
+
+#[async_trait]
+impl CacheBackend for TieredCacheBackend {
+    async fn get_or_fetch(
+        &self,
+        key: JsonRpcResponseCacheKey,
+        fetch: CacheFetch<'_>,
+    ) -> Web3ProxyResult<JsonRpcResponseData> {
+        // the guard makes identical requests on this replica wait for the first one instead of all going to redis
+        let guard = match self.memory.get_value_or_guard_async(key.clone()).await {
+            Ok(x) => return Ok(x),
+            Err(x) => x,
+        };
+
+        if let Some(response_data) = self.redis.get(&key).await {
+            guard.insert(response_data.clone());
+
+            return Ok(response_data);
+        }
+
+        let (response_data, cacheable) = fetch.await?;
+
+        if cacheable {
+            self.redis.insert(&key, &response_data).await;
+
+            guard.insert(response_data.clone());
+        }
+
+        Ok(response_data)
+    }
+
+    async fn remove_matching(&self, filter: CachePurgeFilter<'_>) -> Web3ProxyResult<usize> {
+        // redis first. otherwise a request in between could copy a purged response back into memory
+        let shared = self.redis.remove_matching(filter).await?;
+
+        let local = self.memory.remove_matching(|key, _| filter(&key.into()));
+
+        Ok(shared + local)
+    }
+}
+
 impl<K, Q> Weighter<K, Q, JsonRpcResponseData> for JsonRpcResponseWeigher {
     fn weight(&self, _key: &K, _qey: &Q, value: &JsonRpcResponseData) -> NonZeroU32 {
         value.num_bytes()
@@ -134,9 +554,258 @@ impl<K, Q> Weighter<K, Q, JsonRpcResponseData> for JsonRpcResponseWeigher {
 
 #[cfg(test)]
 mod tests {
-    use super::{JsonRpcResponseData, JsonRpcResponseWeigher};
-    use quick_cache_ttl::CacheWithTTL;
-    use std::{num::NonZeroU32, time::Duration};
+    use super::*;
+    use ethers::types::Block;
+    use futures::FutureExt;
+    use redis_rate_limiter::{DeadpoolRuntime, RedisConfig};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn cache_key(method: &str, params: serde_json::Value) -> JsonRpcResponseCacheKey {
+        JsonRpcResponseCacheKey {
+            from_block: None,
+            to_block: None,
+            method: method.to_string(),
+            params: Some(params),
+            cache_errors: false,
+            cache_partition: None,
+        }
+    }
+
+    async fn memory_cache() -> Arc<JsonRpcResponseCache> {
+        let cache = JsonRpcResponseCache::new_with_weights(
+            "test",
+            100,
+            NonZeroU32::try_from(10_000).unwrap(),
+            100_000,
+            JsonRpcResponseWeigher,
+            Duration::from_secs(60),
+        )
+        .await
+        .purgeable();
+
+        Arc::new(cache)
+    }
+
+    /// nothing listens on port 1, so every redis call fails
+    fn unreachable_redis() -> RedisCacheBackend {
+        let redis_pool = RedisConfig::from_url("redis://127.0.0.1:1")
+            .builder()
+            .unwrap()
+            .runtime(DeadpoolRuntime::Tokio1)
+            .build()
+            .unwrap();
+
+        RedisCacheBackend {
+            chain_id: 1,
+            redis_pool,
+            ttl_ms: 60_000,
+        }
+    }
+
+    /// counts how many times the cache had to fetch
+    fn fetch(fetches: &AtomicUsize, value: serde_json::Value, cacheable: bool) -> CacheFetch<'_> {
+        async move {
+            fetches.fetch_add(1, Ordering::SeqCst);
+
+            Ok((value.into(), cacheable))
+        }
+        .boxed()
+    }
+
+    fn result_json(x: &JsonRpcResponseData) -> serde_json::Value {
+        match x {
+            JsonRpcResponseData::Result { value, .. } => serde_json::from_str(value.get()).unwrap(),
+            JsonRpcResponseData::Error { .. } => panic!("expected a result"),
+        }
+    }
+
+    #[test]
+    fn test_cached_response_info() {
+        let from_block: ArcBlock = Arc::new(Block {
+            number: Some(5.into()),
+            ..Default::default()
+        });
+
+        let key = JsonRpcResponseCacheKey {
+            from_block: Some(from_block),
+            ..cache_key("eth_getLogs", json!([{"address": "0x0"}]))
+        };
+
+        let info = CachedResponseInfo::from(&key);
+
+        assert_eq!(info.method, "eth_getLogs");
+        assert_eq!(info.params, Some(json!([{"address": "0x0"}])));
+        assert_eq!(info.from_block, Some(5.into()));
+        assert_eq!(info.to_block, None);
+    }
+
+    #[test]
+    fn test_shared_entry_round_trip() {
+        let key = cache_key("eth_chainId", json!([]));
+
+        let entry = SharedEntry::new(&key, &json!("0x1").into());
+        let entry = serde_json::to_string(&entry).unwrap();
+        let entry: SharedEntry = serde_json::from_str(&entry).unwrap();
+
+        assert_eq!(entry.info, CachedResponseInfo::from(&key));
+        assert_eq!(result_json(&entry.into()), json!("0x1"));
+
+        let error = JsonRpcErrorData {
+            code: -32000,
+            message: "execution reverted".into(),
+            data: None,
+        };
+
+        let entry = SharedEntry::new(&key, &error.clone().into());
+        let entry = serde_json::to_string(&entry).unwrap();
+        let entry: SharedEntry = serde_json::from_str(&entry).unwrap();
+
+        match JsonRpcResponseData::from(entry) {
+            JsonRpcResponseData::Error { value, .. } => assert_eq!(value.code, error.code),
+            JsonRpcResponseData::Result { .. } => panic!("expected an error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend() {
+        let backend = MemoryCacheBackend(memory_cache().await);
+        let fetches = AtomicUsize::new(0);
+
+        let key = cache_key("eth_chainId", json!([]));
+
+        let x = backend
+            .get_or_fetch(key.clone(), fetch(&fetches, json!("0x1"), true))
+            .await
+            .unwrap();
+        assert_eq!(result_json(&x), json!("0x1"));
+
+        // a hit does not fetch again
+        let x = backend
+            .get_or_fetch(key.clone(), fetch(&fetches, json!("0x2"), true))
+            .await
+            .unwrap();
+        assert_eq!(result_json(&x), json!("0x1"));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // uncacheable responses are returned but not saved
+        let other = cache_key("eth_blockNumber", json!([]));
+
+        for expected in 2..=3 {
+            backend
+                .get_or_fetch(other.clone(), fetch(&fetches, json!("0x10"), false))
+                .await
+                .unwrap();
+            assert_eq!(fetches.load(Ordering::SeqCst), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend_remove_matching() {
+        let backend = MemoryCacheBackend(memory_cache().await);
+        let fetches = AtomicUsize::new(0);
+
+        for method in ["eth_chainId", "eth_getBalance", "eth_getCode"] {
+            backend
+                .get_or_fetch(
+                    cache_key(method, json!([])),
+                    fetch(&fetches, json!("0x1"), true),
+                )
+                .await
+                .unwrap();
+        }
+
+        let removed = backend
+            .remove_matching(&|info| info.method.starts_with("eth_get"))
+            .await
+            .unwrap();
+        assert_eq!(removed, 2);
+
+        // the others are fetched again. eth_chainId is still cached
+        for method in ["eth_chainId", "eth_getBalance", "eth_getCode"] {
+            backend
+                .get_or_fetch(
+                    cache_key(method, json!([])),
+                    fetch(&fetches, json!("0x1"), true),
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_redis_backend_without_redis() {
+        let backend = unreachable_redis();
+        let fetches = AtomicUsize::new(0);
+
+        let key = cache_key("eth_chainId", json!([]));
+
+        // redis errors are misses, so requests are still served
+        for expected in 1..=2 {
+            let x = backend
+                .get_or_fetch(key.clone(), fetch(&fetches, json!("0x1"), true))
+                .await
+                .unwrap();
+            assert_eq!(result_json(&x), json!("0x1"));
+            assert_eq!(fetches.load(Ordering::SeqCst), expected);
+        }
+
+        assert!(backend.get_block(&H256::zero()).await.is_none());
+
+        // a purge that can't reach redis must not report success
+        backend.remove_matching(&|_| true).await.unwrap_err();
+        backend.remove_blocks(None, None).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_tiered_backend_without_redis() {
+        let backend = TieredCacheBackend {
+            memory: memory_cache().await,
+            redis: unreachable_redis(),
+        };
+        let fetches = AtomicUsize::new(0);
+
+        let key = cache_key("eth_chainId", json!([]));
+
+        // memory still caches when redis is down
+        for _ in 0..2 {
+            backend
+                .get_or_fetch(key.clone(), fetch(&fetches, json!("0x1"), true))
+                .await
+                .unwrap();
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // the purge fails instead of only clearing memory
+        backend.remove_matching(&|_| true).await.unwrap_err();
+    }
+
+    #[test]
+    fn test_shared_key() {
+        let key = JsonRpcResponseCacheKey {
+            from_block: None,
+            to_block: None,
+            method: "eth_chainId".to_string(),
+            params: Some(serde_json::json!([])),
+            cache_errors: false,
+            cache_partition: None,
+        };
+
+        let shared_key = key.shared_key(1);
+
+        assert!(shared_key.starts_with("web3_proxy:1:response:0x"));
+        assert_eq!(shared_key, key.clone().shared_key(1));
+        assert_ne!(shared_key, key.shared_key(137));
+
+        let other_params = JsonRpcResponseCacheKey {
+            params: Some(serde_json::json!(["latest"])),
+            ..key
+        };
+
+        assert_ne!(shared_key, other_params.shared_key(1));
+    }
 
     #[tokio::test(start_paused = true)]
     async fn test_json_rpc_query_weigher() {
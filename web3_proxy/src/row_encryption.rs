@@ -0,0 +1,200 @@
+//! Encrypt sensitive columns before they are saved so that a leaked database snapshot doesn't leak them too.
+//!
+//! Keys are loaded from a keyfile. Each line is `key_id:64_hex_chars`. The first key encrypts new values.
+//! The other keys are only used to decrypt values that haven't been rotated yet.
+//! A KMS can be used by having it write the keyfile before the proxy starts.
+//!
+//! Encrypted values look like `enc:v1:{key_id}:{hex of nonce + ciphertext + tag}`.
+//! Values without the prefix were saved before encryption was turned on and are returned as is.
+//!
+//! rpc secret keys are looked up by their value, so they are saved as a keyed hash instead. See `RpcKeyHasher`.
+use anyhow::Context;
+use ethers::utils::hex;
+use hashbrown::HashMap;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+use std::path::Path;
+
+const PREFIX: &str = "enc:v1:";
+
+pub struct RowCipher {
+    current_key_id: String,
+    keys: HashMap<String, LessSafeKey>,
+    rng: SystemRandom,
+}
+
+impl fmt::Debug for RowCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the keys
+        f.debug_struct("RowCipher")
+            .field("current_key_id", &self.current_key_id)
+            .field("num_keys", &self.keys.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl RowCipher {
+    pub fn from_keyfile(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("reading row encryption keyfile at {}", path.display()))?;
+
+        Self::from_keyfile_contents(&contents)
+    }
+
+    pub fn from_keyfile_contents(contents: &str) -> anyhow::Result<Self> {
+        let mut current_key_id = None;
+        let mut keys = HashMap::new();
+
+        for line in contents.lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key_id, key_hex) = line
+                .split_once(':')
+                .context("row encryption keys must look like key_id:hex")?;
+
+            if key_id.is_empty() {
+                anyhow::bail!("invalid row encryption key id: {:?}", key_id);
+            }
+
+            let key_bytes = hex::decode(key_hex).context("row encryption keys must be hex")?;
+
+            let key = UnboundKey::new(&AES_256_GCM, &key_bytes)
+                .ok()
+                .context("row encryption keys must be 32 bytes")?;
+
+            if keys
+                .insert(key_id.to_string(), LessSafeKey::new(key))
+                .is_some()
+            {
+                anyhow::bail!("duplicate row encryption key id: {}", key_id);
+            }
+
+            if current_key_id.is_none() {
+                current_key_id = Some(key_id.to_string());
+            }
+        }
+
+        let current_key_id = current_key_id.context("row encryption keyfile has no keys")?;
+
+        Ok(Self {
+            current_key_id,
+            keys,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> anyhow::Result<String> {
+        let key = &self.keys[&self.current_key_id];
+
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce_bytes)
+            .ok()
+            .context("generating nonce")?;
+
+        let mut in_out = plaintext.as_bytes().to_vec();
+
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce_bytes),
+            Aad::empty(),
+            &mut in_out,
+        )
+        .ok()
+        .context("encrypting row")?;
+
+        let mut sealed = nonce_bytes.to_vec();
+        sealed.extend(in_out);
+
+        Ok(format!(
+            "{}{}:{}",
+            PREFIX,
+            self.current_key_id,
+            hex::encode(sealed)
+        ))
+    }
+
+    pub fn decrypt(&self, stored: &str) -> anyhow::Result<String> {
+        let rest = match stored.strip_prefix(PREFIX) {
+            Some(x) => x,
+            // saved before encryption was turned on
+            None => return Ok(stored.to_string()),
+        };
+
+        let (key_id, sealed_hex) = rest
+            .split_once(':')
+            .context("encrypted row is missing its key id")?;
+
+        let key = self
+            .keys
+            .get(key_id)
+            .with_context(|| format!("unknown row encryption key: {}", key_id))?;
+
+        let mut sealed = hex::decode(sealed_hex).context("encrypted row is not hex")?;
+
+        if sealed.len() < NONCE_LEN {
+            anyhow::bail!("encrypted row is too short");
+        }
+
+        let mut in_out = sealed.split_off(NONCE_LEN);
+
+        let nonce = Nonce::try_assume_unique_for_key(&sealed)
+            .ok()
+            .context("bad nonce")?;
+
+        let plaintext = key
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .ok()
+            .context("decrypting row")?;
+
+        String::from_utf8(plaintext.to_vec()).context("decrypted row is not utf8")
+    }
+
+    /// True if the value is plaintext or was encrypted with an old key
+    pub fn needs_rotation(&self, stored: &str) -> bool {
+        match stored.strip_prefix(PREFIX).and_then(|x| x.split_once(':')) {
+            Some((key_id, _)) => key_id != self.current_key_id,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYFILE: &str = "
+# newest key first
+b:0202020202020202020202020202020202020202020202020202020202020202
+a:0101010101010101010101010101010101010101010101010101010101010101
+";
+
+    #[test]
+    fn test_round_trip_and_rotation() {
+        let old = RowCipher::from_keyfile_contents(
+            "a:0101010101010101010101010101010101010101010101010101010101010101",
+        )
+        .unwrap();
+        let new = RowCipher::from_keyfile_contents(KEYFILE).unwrap();
+
+        let sealed_old = old.encrypt("user@example.com").unwrap();
+        assert!(sealed_old.starts_with("enc:v1:a:"));
+        assert!(new.needs_rotation(&sealed_old));
+        assert_eq!(new.decrypt(&sealed_old).unwrap(), "user@example.com");
+
+        let sealed_new = new.encrypt("user@example.com").unwrap();
+        assert!(!new.needs_rotation(&sealed_new));
+        assert_ne!(sealed_new, new.encrypt("user@example.com").unwrap());
+
+        // plaintext from before encryption was turned on
+        assert!(new.needs_rotation("user@example.com"));
+        assert_eq!(new.decrypt("user@example.com").unwrap(), "user@example.com");
+
+        // the old keyfile can't read rows from the new key
+        assert!(old.decrypt(&sealed_new).is_err());
+    }
+}
@@ -0,0 +1,533 @@
+//! Send requests from many clients to a backend in one JSON-RPC batch.
+//! Clients pick their own ids, so every request gets a new upstream id and the response is matched back through that id.
+use super::provider::extract_auth;
+use crate::config::Web3RpcBatchConfig;
+use crate::jsonrpc::JsonRpcErrorData;
+use ethers::providers::{HttpClientError, JsonRpcError, ProviderError};
+use hashbrown::HashMap;
+use log::{trace, warn};
+use parking_lot::Mutex;
+use reqwest::header::AUTHORIZATION;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serde_json::value::RawValue;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio::time::{timeout_at, Duration, Instant};
+use url::Url;
+
+/// upstream ids are `partition << ID_SEQUENCE_BITS | sequence`.
+/// 16 bits of partition and 37 bits of sequence keep every id under 2^53 so backends written in javascript do not round them
+pub const ID_SEQUENCE_BITS: u32 = 37;
+const ID_SEQUENCE_MASK: u64 = (1 << ID_SEQUENCE_BITS) - 1;
+
+/// One response in an upstream batch
+#[derive(Debug, Deserialize)]
+pub struct UpstreamResponse {
+    pub id: Box<RawValue>,
+    pub result: Option<Box<RawValue>>,
+    pub error: Option<JsonRpcErrorData>,
+}
+
+/// Map the ids we send upstream back to whatever is waiting for the response.
+pub struct UpstreamIdTable<T> {
+    partition: u64,
+    next_sequence: AtomicU64,
+    pending: Mutex<HashMap<u64, T>>,
+}
+
+impl<T> UpstreamIdTable<T> {
+    pub fn new(partition: u16) -> Self {
+        Self {
+            partition: (partition as u64) << ID_SEQUENCE_BITS,
+            next_sequence: 0.into(),
+            pending: Default::default(),
+        }
+    }
+
+    /// Reserve a new upstream id for a request
+    pub fn insert(&self, value: T) -> u64 {
+        let sequence =
+            self.next_sequence.fetch_add(1, atomic::Ordering::Relaxed) & ID_SEQUENCE_MASK;
+
+        let id = self.partition | sequence;
+
+        // the sequence would need to wrap around while a request is still pending for this to replace anything
+        self.pending.lock().insert(id, value);
+
+        id
+    }
+
+    /// Stop waiting for an id. Use this when the upstream request fails without a response
+    pub fn remove(&self, id: u64) -> Option<T> {
+        self.pending.lock().remove(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.lock().is_empty()
+    }
+
+    /// Match every response in a batch to its waiter.
+    /// Responses with ids that we did not send (or that were already answered) are returned separately
+    pub fn demultiplex(
+        &self,
+        responses: Vec<UpstreamResponse>,
+    ) -> (Vec<(T, UpstreamResponse)>, Vec<UpstreamResponse>) {
+        let mut matched = Vec::with_capacity(responses.len());
+        let mut unmatched = vec![];
+
+        let mut pending = self.pending.lock();
+
+        for response in responses {
+            let waiter = serde_json::from_str::<u64>(response.id.get())
+                .ok()
+                .and_then(|id| pending.remove(&id));
+
+            match waiter {
+                Some(waiter) => matched.push((waiter, response)),
+                None => {
+                    warn!("unknown id in upstream batch response: {}", response.id);
+                    unmatched.push(response)
+                }
+            }
+        }
+
+        (matched, unmatched)
+    }
+}
+
+type BatchWaiter = oneshot::Sender<Result<UpstreamResponse, ProviderError>>;
+
+/// A request waiting for its batch to be sent
+struct QueuedRequest {
+    method: String,
+    params: serde_json::Value,
+    waiter: BatchWaiter,
+}
+
+/// Coalesce requests to an http backend that arrive within `max_wait_ms` of each other into one upstream batch.
+/// This helps a lot with providers that charge per http request instead of per method.
+pub struct Web3RpcBatcher {
+    sender: flume::Sender<QueuedRequest>,
+}
+
+impl Web3RpcBatcher {
+    /// The background task stops once the batcher is dropped.
+    /// Every batch gives up after `request_timeout`, even without the shared `http_client` and its timeout
+    pub fn spawn(
+        rpc_name: String,
+        mut url: Url,
+        http_client: Option<reqwest::Client>,
+        config: Web3RpcBatchConfig,
+        request_timeout: Duration,
+    ) -> Self {
+        let auth = extract_auth(&mut url).map(|x| x.to_string());
+
+        let http_client = http_client.unwrap_or_default();
+
+        let (sender, receiver) = flume::unbounded();
+
+        let id_table = Arc::new(UpstreamIdTable::new(config.id_partition));
+
+        tokio::spawn(async move {
+            let max_wait = Duration::from_millis(config.max_wait_ms);
+            let max_batch_size = config.max_batch_size.max(1);
+
+            while let Ok(first) = receiver.recv_async().await {
+                // the first request's latency budget decides when the batch is sent
+                let deadline = Instant::now() + max_wait;
+
+                let mut batch = vec![first];
+
+                while batch.len() < max_batch_size {
+                    match timeout_at(deadline, receiver.recv_async()).await {
+                        Ok(Ok(x)) => batch.push(x),
+                        // the batcher was dropped or the deadline passed
+                        Ok(Err(_)) | Err(_) => break,
+                    }
+                }
+
+                trace!("sending batch of {} to {}", batch.len(), rpc_name);
+
+                // send in the background so a slow batch does not hold up the next one
+                tokio::spawn(send_batch(
+                    rpc_name.clone(),
+                    http_client.clone(),
+                    url.clone(),
+                    auth.clone(),
+                    id_table.clone(),
+                    batch,
+                    request_timeout,
+                ));
+            }
+
+            trace!("batcher for {} exited", rpc_name);
+        });
+
+        Self { sender }
+    }
+
+    /// Queue a request and wait for its batch's response
+    pub async fn request<P, R>(&self, method: &str, params: &P) -> Result<R, ProviderError>
+    where
+        P: Serialize,
+        R: DeserializeOwned,
+    {
+        let params = serde_json::to_value(params)?;
+
+        let (waiter, response) = oneshot::channel();
+
+        self.sender
+            .send_async(QueuedRequest {
+                method: method.to_string(),
+                params,
+                waiter,
+            })
+            .await
+            .map_err(|_| ProviderError::CustomError("batcher is not running".to_string()))?;
+
+        let response = response.await.map_err(|_| {
+            ProviderError::CustomError("batch finished without a response".to_string())
+        })??;
+
+        match (response.result, response.error) {
+            (_, Some(err)) => Err(HttpClientError::JsonRpcError(JsonRpcError {
+                code: err.code,
+                message: err.message.to_string(),
+                data: err.data,
+            })
+            .into()),
+            (Some(result), None) => Ok(serde_json::from_str(result.get())?),
+            (None, None) => Ok(serde_json::from_str("null")?),
+        }
+    }
+}
+
+/// Why a whole batch failed
+enum BatchFailure {
+    RateLimited,
+    Other(String),
+}
+
+async fn send_batch(
+    rpc_name: String,
+    http_client: reqwest::Client,
+    url: Url,
+    auth: Option<String>,
+    id_table: Arc<UpstreamIdTable<BatchWaiter>>,
+    batch: Vec<QueuedRequest>,
+    request_timeout: Duration,
+) {
+    let mut ids = Vec::with_capacity(batch.len());
+    let mut requests = Vec::with_capacity(batch.len());
+
+    for queued in batch {
+        let id = id_table.insert(queued.waiter);
+
+        ids.push(id);
+        requests.push(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": queued.method,
+            "params": queued.params,
+        }));
+    }
+
+    // a batch of one is sent on its own so that it works the same as an unbatched request
+    let body = if requests.len() == 1 {
+        requests.pop().expect("one request")
+    } else {
+        serde_json::Value::Array(requests)
+    };
+
+    let mut request = http_client.post(url).json(&body).timeout(request_timeout);
+
+    if let Some(auth) = auth {
+        request = request.header(AUTHORIZATION, auth);
+    }
+
+    let failure = match request.send().await {
+        Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+            Some(BatchFailure::RateLimited)
+        }
+        Ok(response) => match response.bytes().await {
+            Ok(bytes) => {
+                let responses = if bytes.first() == Some(&b'[') {
+                    serde_json::from_slice::<Vec<UpstreamResponse>>(&bytes)
+                } else {
+                    serde_json::from_slice::<UpstreamResponse>(&bytes).map(|x| vec![x])
+                };
+
+                match responses {
+                    Ok(responses) => {
+                        let (matched, _) = id_table.demultiplex(responses);
+
+                        for (waiter, response) in matched {
+                            let _ = waiter.send(Ok(response));
+                        }
+
+                        None
+                    }
+                    Err(err) => Some(BatchFailure::Other(format!(
+                        "unable to parse batch response from {}: {}",
+                        rpc_name, err
+                    ))),
+                }
+            }
+            Err(err) => Some(BatchFailure::Other(format!(
+                "unable to read batch response from {}: {}",
+                rpc_name, err
+            ))),
+        },
+        Err(err) => Some(BatchFailure::Other(format!(
+            "unable to send batch to {}: {}",
+            rpc_name, err
+        ))),
+    };
+
+    // anything still waiting did not get a response
+    for id in ids {
+        if let Some(waiter) = id_table.remove(id) {
+            let err = match &failure {
+                // give every waiter an error that the rate limit handling recognizes
+                Some(BatchFailure::RateLimited) => HttpClientError::JsonRpcError(JsonRpcError {
+                    code: 429,
+                    message: "too many requests".to_string(),
+                    data: None,
+                })
+                .into(),
+                Some(BatchFailure::Other(msg)) => ProviderError::CustomError(msg.clone()),
+                None => ProviderError::CustomError(format!(
+                    "no response for id {} in batch from {}",
+                    id, rpc_name
+                )),
+            };
+
+            let _ = waiter.send(Err(err));
+        }
+    }
+}
+
+/// The partition that an upstream id was created in
+pub fn id_partition(id: u64) -> u16 {
+    (id >> ID_SEQUENCE_BITS) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::routing::post;
+    use axum::Json;
+
+    type Received = Arc<Mutex<Vec<serde_json::Value>>>;
+
+    /// a backend that answers every request with its method. `answer` picks which requests get a response
+    fn backend(answer: fn(&serde_json::Value) -> bool, delay: Duration) -> (Url, Received) {
+        let received = Received::default();
+
+        let handler = move |State(received): State<Received>,
+                            Json(body): Json<serde_json::Value>| async move {
+            received.lock().push(body.clone());
+
+            tokio::time::sleep(delay).await;
+
+            let respond = |x: &serde_json::Value| json!({"jsonrpc": "2.0", "id": x["id"], "result": x["method"]});
+
+            match body {
+                serde_json::Value::Array(requests) => Json(json!(requests
+                    .iter()
+                    .filter(|x| answer(x))
+                    .map(respond)
+                    .collect::<Vec<_>>())),
+                x => Json(respond(&x)),
+            }
+        };
+
+        let app = axum::Router::new()
+            .route("/", post(handler))
+            .with_state(received.clone());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        (format!("http://{}", addr).parse().unwrap(), received)
+    }
+
+    fn batcher(url: Url, max_batch_size: usize, request_timeout: Duration) -> Web3RpcBatcher {
+        let config = Web3RpcBatchConfig {
+            max_batch_size,
+            max_wait_ms: 50,
+            id_partition: 3,
+        };
+
+        Web3RpcBatcher::spawn("test".to_string(), url, None, config, request_timeout)
+    }
+
+    async fn request_all(
+        batcher: &Web3RpcBatcher,
+        methods: &[&str],
+    ) -> Vec<Result<String, ProviderError>> {
+        futures::future::join_all(
+            methods
+                .iter()
+                .map(|method| batcher.request::<_, String>(method, &())),
+        )
+        .await
+    }
+
+    fn response(id: u64) -> UpstreamResponse {
+        UpstreamResponse {
+            id: RawValue::from_string(id.to_string()).unwrap(),
+            result: Some(RawValue::from_string("\"0x1\"".to_string()).unwrap()),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_partitioned_ids() {
+        let a = UpstreamIdTable::new(1);
+        let b = UpstreamIdTable::new(2);
+
+        let a_id = a.insert("a");
+        let b_id = b.insert("b");
+
+        assert_ne!(a_id, b_id);
+        assert_eq!(id_partition(a_id), 1);
+        assert_eq!(id_partition(b_id), 2);
+
+        // javascript can represent every id exactly
+        let max_id = ((u16::MAX as u64) << ID_SEQUENCE_BITS) | ID_SEQUENCE_MASK;
+        assert!(max_id < 1 << 53);
+    }
+
+    #[test]
+    fn test_demultiplex() {
+        let table = UpstreamIdTable::new(0);
+
+        let first = table.insert("first");
+        let second = table.insert("second");
+
+        // backends may answer a batch in any order
+        let (matched, unmatched) =
+            table.demultiplex(vec![response(second), response(999), response(first)]);
+
+        assert_eq!(
+            matched.iter().map(|(x, _)| *x).collect::<Vec<_>>(),
+            vec!["second", "first"]
+        );
+        assert_eq!(unmatched.len(), 1);
+        assert!(table.is_empty());
+
+        // duplicate responses do not match twice
+        let (matched, unmatched) = table.demultiplex(vec![response(first)]);
+        assert!(matched.is_empty());
+        assert_eq!(unmatched.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_batcher_combines_requests() {
+        let (url, received) = backend(|_| true, Duration::ZERO);
+
+        let batcher = batcher(url, 10, Duration::from_secs(10));
+
+        let methods = ["eth_a", "eth_b", "eth_c"];
+
+        let responses = request_all(&batcher, &methods).await;
+
+        for (method, response) in methods.iter().zip(responses) {
+            assert_eq!(response.unwrap(), *method);
+        }
+
+        // one http request for all of them. the backend saw our ids, not the clients'
+        let received = received.lock();
+        assert_eq!(received.len(), 1);
+
+        let batch = received[0].as_array().unwrap();
+        assert_eq!(batch.len(), 3);
+
+        for x in batch {
+            assert_eq!(id_partition(x["id"].as_u64().unwrap()), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batcher_splits_batches() {
+        let (url, received) = backend(|_| true, Duration::ZERO);
+
+        let batcher = batcher(url, 2, Duration::from_secs(10));
+
+        let methods = ["eth_a", "eth_b", "eth_c", "eth_d", "eth_e"];
+
+        let responses = request_all(&batcher, &methods).await;
+
+        for (method, response) in methods.iter().zip(responses) {
+            assert_eq!(response.unwrap(), *method);
+        }
+
+        let received = received.lock();
+
+        let mut sizes: Vec<_> = received
+            .iter()
+            .map(|x| x.as_array().map_or(1, |x| x.len()))
+            .collect();
+        sizes.sort();
+
+        assert_eq!(sizes, vec![1, 2, 2]);
+
+        // the leftover request is sent on its own, not as a batch of one
+        assert!(received.iter().any(|x| x.is_object()));
+    }
+
+    #[tokio::test]
+    async fn test_batcher_missing_responses() {
+        // the backend drops "eth_b" from the batch
+        let (url, _) = backend(|x| x["method"] != "eth_b", Duration::ZERO);
+
+        let batcher = batcher(url, 10, Duration::from_secs(10));
+
+        let responses = request_all(&batcher, &["eth_a", "eth_b"]).await;
+
+        assert_eq!(responses[0].as_ref().unwrap(), "eth_a");
+
+        match &responses[1] {
+            Err(ProviderError::CustomError(x)) => assert!(x.contains("no response for id")),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batcher_timeout_without_shared_client() {
+        let (url, _) = backend(|_| true, Duration::from_secs(60));
+
+        let batcher = batcher(url, 10, Duration::from_millis(200));
+
+        let start = Instant::now();
+
+        let responses = request_all(&batcher, &["eth_a", "eth_b"]).await;
+
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        for response in responses {
+            match response {
+                Err(ProviderError::CustomError(x)) => {
+                    assert!(x.contains("unable to send batch"), "{}", x)
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+}
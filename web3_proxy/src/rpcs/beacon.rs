@@ -0,0 +1,283 @@
+//! Consensus layer (beacon node) REST apis.
+//! `/beacon/*` requests get the same authorization, rate limits, caching, and stats as json-rpc requests. Only the backends are different
+use super::one::Web3Rpc;
+use crate::config::BeaconRpcConfig;
+use crate::frontend::authorization::RequestMetadata;
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use axum::body::Bytes;
+use hashbrown::HashMap;
+use http::header::{ACCEPT, CONTENT_TYPE};
+use http::{Method, StatusCode};
+use itertools::Itertools;
+use log::{debug, info, warn};
+use quick_cache_ttl::CacheWithTTL;
+use std::num::NonZeroU64;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// status, content-type, and body of a beacon node's response
+pub type BeaconResponse = (StatusCode, Option<String>, Bytes);
+
+/// Cache GET responses by everything that can change them
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct BeaconCacheKey {
+    path_and_query: String,
+    /// beacon nodes return json or ssz depending on this
+    accept: Option<String>,
+    cache_partition: Option<NonZeroU64>,
+}
+
+/// One backend beacon node
+pub struct BeaconRpc {
+    /// beacon nodes are not json-rpc servers. this is only used for stats and the backend headers
+    pub rpc: Arc<Web3Rpc>,
+    url: String,
+}
+
+/// Send requests to any beacon node that answers. They are all expected to follow the same chain
+pub struct BeaconRpcs {
+    rpcs: Vec<BeaconRpc>,
+    http_client: reqwest::Client,
+    /// round robin between the rpcs
+    next: AtomicUsize,
+    /// None if caching is disabled
+    cache: Option<Arc<CacheWithTTL<BeaconCacheKey, BeaconResponse>>>,
+}
+
+impl BeaconRpcs {
+    /// None if every beacon rpc is disabled
+    pub async fn spawn(
+        configs: HashMap<String, BeaconRpcConfig>,
+        http_client: reqwest::Client,
+        cache_ttl_seconds: u64,
+    ) -> Option<Arc<Self>> {
+        let rpcs: Vec<_> = configs
+            .into_iter()
+            .filter(|(_, config)| !config.disabled)
+            .sorted_by(|a, b| a.0.cmp(&b.0))
+            .map(|(name, config)| {
+                let rpc = Web3Rpc {
+                    name,
+                    display_name: config.display_name,
+                    ..Default::default()
+                };
+
+                BeaconRpc {
+                    rpc: Arc::new(rpc),
+                    url: config.url.trim_end_matches('/').to_string(),
+                }
+            })
+            .collect();
+
+        if rpcs.is_empty() {
+            warn!("every beacon rpc is disabled");
+            return None;
+        }
+
+        info!("{} beacon rpcs", rpcs.len());
+
+        let cache = if cache_ttl_seconds == 0 {
+            None
+        } else {
+            // TODO: max_capacity from config
+            Some(
+                CacheWithTTL::arc_with_capacity(
+                    "beacon_response_cache",
+                    10_000,
+                    Duration::from_secs(cache_ttl_seconds),
+                )
+                .await,
+            )
+        };
+
+        let x = Self {
+            rpcs,
+            http_client,
+            next: AtomicUsize::new(0),
+            cache,
+        };
+
+        Some(Arc::new(x))
+    }
+
+    /// Send the request to the first beacon node that answers without a server error.
+    /// `path` is the part of the url after `/beacon/`
+    #[allow(clippy::too_many_arguments)]
+    pub async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        query: Option<&str>,
+        accept: Option<&str>,
+        body: Bytes,
+        bypass_cache: bool,
+        cache_partition: Option<NonZeroU64>,
+        request_metadata: &RequestMetadata,
+    ) -> Web3ProxyResult<BeaconResponse> {
+        let path = check_beacon_path(path)?;
+
+        let path_and_query = match query {
+            Some(query) => format!("/{}?{}", path, query),
+            None => format!("/{}", path),
+        };
+
+        let cache_key = if method == Method::GET && !bypass_cache && is_cacheable(path) {
+            self.cache.as_ref().map(|cache| {
+                (
+                    cache,
+                    BeaconCacheKey {
+                        path_and_query: path_and_query.clone(),
+                        accept: accept.map(|x| x.to_string()),
+                        cache_partition,
+                    },
+                )
+            })
+        } else {
+            None
+        };
+
+        if let Some((cache, key)) = cache_key.as_ref() {
+            if let Some(x) = cache.get(key) {
+                // backend_requests stays empty, so this counts as a cache hit
+                return Ok(x);
+            }
+        }
+
+        let first = self.next.fetch_add(1, atomic::Ordering::Relaxed);
+
+        let mut last_response = None;
+
+        for i in 0..self.rpcs.len() {
+            let beacon_rpc = &self.rpcs[(first + i) % self.rpcs.len()];
+
+            request_metadata
+                .backend_requests
+                .lock()
+                .push(beacon_rpc.rpc.clone());
+
+            let mut request = self
+                .http_client
+                .request(
+                    method.clone(),
+                    format!("{}{}", beacon_rpc.url, path_and_query),
+                )
+                .body(body.clone());
+
+            if let Some(accept) = accept {
+                request = request.header(ACCEPT, accept);
+            }
+
+            if !body.is_empty() {
+                request = request.header(CONTENT_TYPE, "application/json");
+            }
+
+            let response = match request.send().await {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!("beacon rpc {} failed: {:?}", beacon_rpc.rpc, err);
+                    continue;
+                }
+            };
+
+            let status = response.status();
+
+            let content_type = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.to_string());
+
+            let body = match response.bytes().await {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!("beacon rpc {} response failed: {:?}", beacon_rpc.rpc, err);
+                    continue;
+                }
+            };
+
+            let x = (status, content_type, body);
+
+            if status.is_server_error() {
+                debug!("beacon rpc {} returned {}", beacon_rpc.rpc, status);
+                last_response = Some(x);
+                continue;
+            }
+
+            if status == StatusCode::OK {
+                if let Some((cache, key)) = cache_key {
+                    let _ = cache.try_insert(key, x.clone());
+                }
+            }
+
+            return Ok(x);
+        }
+
+        // every server failed. pass the last server error along if there was one
+        last_response.ok_or(Web3ProxyError::NoHandleReady)
+    }
+}
+
+/// Only the standard `/eth/...` apis are proxied. Event streams are long lived and not supported
+fn check_beacon_path(path: &str) -> Web3ProxyResult<&str> {
+    let path = path.trim_start_matches('/');
+
+    if !path.starts_with("eth/") || path.split('/').any(|x| x == "..") {
+        return Err(Web3ProxyError::NotFound);
+    }
+
+    if path.ends_with("/events") {
+        return Err(Web3ProxyError::NotImplemented);
+    }
+
+    Ok(path)
+}
+
+/// `/eth/v1/node/*` describes the node that answered, not the chain. Every other GET is the same on every node
+fn is_cacheable(path: &str) -> bool {
+    !path.starts_with("eth/v1/node/")
+}
+
+/// The "method" that beacon requests are saved under in stats.
+/// Block roots, slots, and validator indexes are replaced so that every validator does not get its own method
+pub fn beacon_stat_label(method: &Method, path: &str) -> String {
+    let path = path.trim_start_matches('/');
+
+    let path = path
+        .split('/')
+        .map(|x| {
+            if x.starts_with("0x") || (!x.is_empty() && x.bytes().all(|b| b.is_ascii_digit())) {
+                "{id}"
+            } else {
+                x
+            }
+        })
+        .join("/");
+
+    format!("beacon:{} /{}", method, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_beacon_paths() {
+        assert_eq!(
+            beacon_stat_label(&Method::GET, "eth/v1/beacon/states/head/validators/0xabcd"),
+            "beacon:GET /eth/v1/beacon/states/head/validators/{id}"
+        );
+        assert_eq!(
+            beacon_stat_label(&Method::GET, "/eth/v2/beacon/blocks/123456"),
+            "beacon:GET /eth/v2/beacon/blocks/{id}"
+        );
+
+        assert!(check_beacon_path("eth/v1/beacon/genesis").is_ok());
+        assert!(check_beacon_path("eth/v1/../../admin").is_err());
+        assert!(check_beacon_path("lighthouse/health").is_err());
+        assert!(check_beacon_path("eth/v1/events").is_err());
+
+        assert!(is_cacheable("eth/v1/beacon/headers/head"));
+        assert!(!is_cacheable("eth/v1/node/syncing"));
+    }
+}
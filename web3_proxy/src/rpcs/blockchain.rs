@@ -1,5 +1,6 @@
 ///! Keep track of the blockchain as seen by a Web3Rpcs.
 use super::consensus::ConsensusFinder;
+use super::head_events::HeadEvent;
 use super::many::Web3Rpcs;
 use super::one::Web3Rpc;
 use super::transactions::TxStatus;
@@ -14,9 +15,12 @@ use quick_cache_ttl::CacheWithTTL;
 use serde::ser::SerializeStruct;
 use serde::Serialize;
 use serde_json::json;
+use std::collections::HashMap;
 use std::hash::Hash;
 use std::{cmp::Ordering, fmt::Display, sync::Arc};
 use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{Duration, Instant};
 
 // TODO: type for Hydrated Blocks with their full transactions?
 pub type ArcBlock = Arc<Block<TxHash>>;
@@ -24,6 +28,10 @@ pub type ArcBlock = Arc<Block<TxHash>>;
 pub type BlocksByHashCache = Arc<CacheWithTTL<H256, Web3ProxyBlock>>;
 pub type BlocksByNumberCache = Arc<CacheWithTTL<U64, H256>>;
 
+/// don't ask the backends for the finalized block on every new head
+/// TODO: put this in the config?
+const FINALIZED_CHECK_INTERVAL: Duration = Duration::from_secs(12);
+
 /// A block and its age.
 #[derive(Clone, Debug, Default, From)]
 pub struct Web3ProxyBlock {
@@ -197,6 +205,60 @@ impl Web3Rpcs {
         Ok(block)
     }
 
+    /// Forget the cached blocks numbered `from_block..=to_block`, here and in the shared cache. None is unbounded.
+    /// They are fetched again the next time they are needed. Returns how many blocks were removed
+    pub async fn purge_blocks(
+        &self,
+        from_block: Option<U64>,
+        to_block: Option<U64>,
+    ) -> Web3ProxyResult<usize> {
+        let in_range = |num: &U64| {
+            from_block.map_or(true, |x| *num >= x) && to_block.map_or(true, |x| *num <= x)
+        };
+
+        // shared first. otherwise a miss here could copy a purged block back out of redis
+        let shared = match self.shared_blocks.as_ref() {
+            Some(x) => x.remove_blocks(from_block, to_block).await?,
+            None => 0,
+        };
+
+        self.blocks_by_number
+            .remove_matching(|num, _| in_range(num));
+
+        let local = self
+            .blocks_by_hash
+            .remove_matching(|_, block| in_range(block.number()));
+
+        Ok(local + shared)
+    }
+
+    /// The heaviest chain's block at this height. Only checks the caches
+    pub fn cached_block_by_number(&self, num: &U64) -> Option<Web3ProxyBlock> {
+        let hash = self.blocks_by_number.get(num)?;
+
+        self.blocks_by_hash.get(&hash)
+    }
+
+    /// Hashes by number from `head` back through its cached parents.
+    /// Stops after `depth` blocks or at the first parent that isn't cached
+    pub fn cached_chain(&self, head: &Web3ProxyBlock, depth: usize) -> HashMap<U64, H256> {
+        let mut chain = HashMap::with_capacity(depth);
+
+        let mut block = Some(head.clone());
+
+        while let Some(x) = block {
+            if chain.len() >= depth {
+                break;
+            }
+
+            chain.insert(*x.number(), *x.hash());
+
+            block = self.blocks_by_hash.get(x.parent_hash());
+        }
+
+        chain
+    }
+
     /// Get a block from caches with fallback.
     /// Will query a specific node or the best available.
     /// TODO: return `Web3ProxyResult<Option<ArcBlock>>`?
@@ -213,6 +275,17 @@ impl Web3Rpcs {
             return Ok(block);
         }
 
+        // another proxy might have already fetched it
+        if let Some(shared) = self.shared_blocks.as_ref() {
+            if let Some(block) = shared
+                .get_block(hash)
+                .await
+                .and_then(|x| Web3ProxyBlock::try_from(x).ok())
+            {
+                return self.try_cache_block(block, false).await;
+            }
+        }
+
         // block not in cache. we need to ask an rpc for it
         let get_block_params = (*hash, false);
         // TODO: if error, retry?
@@ -265,6 +338,10 @@ impl Web3Rpcs {
         // TODO: fill in heaviest_chain! if the block is old enough, is this definitely true?
         let block = self.try_cache_block(block, false).await?;
 
+        if let Some(shared) = self.shared_blocks.as_ref() {
+            shared.insert_block(&block).await;
+        }
+
         Ok(block)
     }
 
@@ -396,6 +473,76 @@ impl Web3Rpcs {
         }
     }
 
+    /// Check the backends for a newer finalized block after new heads arrive and publish it on `head_events`.
+    /// Chains that do not support the "finalized" tag are only checked once.
+    pub(super) async fn watch_finalized_blocks(
+        self: Arc<Self>,
+        authorization: Arc<Authorization>,
+    ) -> Web3ProxyResult<()> {
+        let mut head_events = self.head_events.subscribe();
+
+        let mut last_checked: Option<Instant> = None;
+        let mut finalized: Option<Web3ProxyBlock> = None;
+
+        loop {
+            match head_events.recv().await {
+                Ok(HeadEvent::Finalized(_)) => continue,
+                Ok(_) => {}
+                // we only care that the head changed. missed events are fine
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Ok(()),
+            }
+
+            if last_checked.map_or(false, |x| x.elapsed() < FINALIZED_CHECK_INTERVAL) {
+                continue;
+            }
+            last_checked = Some(Instant::now());
+
+            let request = json!({ "jsonrpc": "2.0", "id": "1", "method": "eth_getBlockByNumber", "params": ("finalized", false) });
+            let request: JsonRpcRequest = serde_json::from_value(request)?;
+
+            let value = match self
+                .try_send_best_connection(&authorization, &request, None, None, None)
+                .await
+            {
+                Ok(JsonRpcResponseData::Result { value, .. }) => value,
+                Ok(JsonRpcResponseData::Error { value, .. }) => {
+                    debug!(
+                        "not watching finalized blocks on {}: {}",
+                        self.name, value.message
+                    );
+                    return Ok(());
+                }
+                Err(err) => {
+                    trace!("failed fetching finalized block: {:?}", err);
+                    continue;
+                }
+            };
+
+            let block: Option<ArcBlock> = match serde_json::from_str(value.get()) {
+                Ok(x) => x,
+                Err(err) => {
+                    debug!("unable to parse finalized block: {:?}", err);
+                    continue;
+                }
+            };
+
+            let block = match block.and_then(Web3ProxyBlock::try_new) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            if finalized
+                .as_ref()
+                .map_or(true, |x| block.number() > x.number())
+            {
+                finalized = Some(block.clone());
+
+                self.head_events.publish(HeadEvent::Finalized(block));
+            }
+        }
+    }
+
     /// `connection_heads` is a mapping of rpc_names to head block hashes.
     /// self.blockchain_map is a mapping of hashes to the complete ArcBlock.
     /// TODO: return something?
@@ -442,6 +589,17 @@ impl Web3Rpcs {
         let num_active_rpcs = consensus_finder.len();
         let total_rpcs = self.by_name.load().len();
 
+        {
+            let rpcs: Vec<_> = self.by_name.load().values().cloned().collect();
+
+            let min_synced_block = consensus_head_block
+                .number()
+                .saturating_sub(self.max_block_lag.unwrap_or_else(|| U64::from(5)));
+
+            self.failover.check(&rpcs, min_synced_block);
+            self.health.check(&rpcs, *consensus_head_block.number());
+        }
+
         let old_consensus_head_connections = self
             .watch_consensus_rpcs_sender
             .send_replace(Some(Arc::new(new_consensus_rpcs)));
@@ -470,6 +628,8 @@ impl Web3Rpcs {
                 // this should already be cached
                 let consensus_head_block = self.try_cache_block(consensus_head_block, true).await?;
 
+                self.head_events.publish_head(None, &consensus_head_block);
+
                 watch_consensus_head_sender
                     .send(Some(consensus_head_block))
                     .or(Err(Web3ProxyError::WatchSendError))
@@ -525,6 +685,9 @@ impl Web3Rpcs {
                                 .await
                                 .web3_context("save consensus_head_block as heaviest chain")?;
 
+                            self.head_events
+                                .publish_head(Some(old_head_block), &consensus_head_block);
+
                             watch_consensus_head_sender
                                 .send(Some(consensus_head_block))
                                 .or(Err(Web3ProxyError::WatchSendError))
@@ -561,6 +724,9 @@ impl Web3Rpcs {
                                 "save_block sending consensus_head_block as heaviest chain",
                             )?;
 
+                        self.head_events
+                            .publish_head(Some(old_head_block), &consensus_head_block);
+
                         watch_consensus_head_sender
                             .send(Some(consensus_head_block))
                             .or(Err(Web3ProxyError::WatchSendError))
@@ -588,6 +754,9 @@ impl Web3Rpcs {
                         let consensus_head_block =
                             self.try_cache_block(consensus_head_block, true).await?;
 
+                        self.head_events
+                            .publish_head(Some(old_head_block), &consensus_head_block);
+
                         watch_consensus_head_sender.send(Some(consensus_head_block))
                             .or(Err(Web3ProxyError::WatchSendError))
                             .web3_context("watch_consensus_head_sender failed sending new consensus_head_block")?;
@@ -1,4 +1,5 @@
 use super::blockchain::Web3ProxyBlock;
+use super::head_quorum::HeadQuorum;
 use super::many::Web3Rpcs;
 use super::one::Web3Rpc;
 use crate::frontend::authorization::Authorization;
@@ -114,6 +115,9 @@ pub struct ConsensusWeb3Rpcs {
     // TODO: make this work. the key needs to be a string. I think we need `serialize_with`
     #[serde(skip_serializing)]
     rpc_data: HashMap<Arc<Web3Rpc>, RpcData>,
+
+    /// servers on a different fork than the head block. only set if `head_quorum` is enabled. they get no requests
+    pub(crate) forked_rpcs: Vec<String>,
 }
 
 impl ConsensusWeb3Rpcs {
@@ -577,6 +581,16 @@ impl ConsensusFinder {
             )
         });
 
+        let quorum = HeadQuorum::new(
+            &web3_rpcs.head_quorum,
+            votes
+                .iter()
+                .flat_map(|(_, _, rpc_names)| rpc_names.iter())
+                .unique()
+                .filter_map(|x| self.rpc_heads.keys().find(|rpc| rpc.name == *x))
+                .map(|x| x.as_ref()),
+        );
+
         // return the first result that exceededs confgured minimums (if any)
         for (maybe_head_block, sum_soft_limit, rpc_names) in votes {
             if *sum_soft_limit < web3_rpcs.min_sum_soft_limit {
@@ -587,6 +601,13 @@ impl ConsensusFinder {
                 continue;
             }
 
+            if let Some(quorum) = quorum {
+                if !quorum.reached(rpc_names.len(), *sum_soft_limit) {
+                    trace!("no quorum on {}", maybe_head_block);
+                    continue;
+                }
+            }
+
             trace!("rpc_names: {:#?}", rpc_names);
 
             // consensus likely found! load the rpcs to make sure they all have active connections
@@ -609,6 +630,7 @@ impl ConsensusFinder {
             let backups_needed = consensus_rpcs.iter().any(|x| x.backup);
 
             let mut other_rpcs = BTreeMap::new();
+            let mut forked_rpcs = vec![];
 
             for (x, x_head) in self
                 .rpc_heads
@@ -617,6 +639,13 @@ impl ConsensusFinder {
             {
                 let x_head_num = *x_head.number();
 
+                // a server on the same chain would have voted for the head block
+                if quorum.is_some() && x_head_num >= *maybe_head_block.number() {
+                    debug!("{} is on a fork at {}", x, x_head);
+                    forked_rpcs.push(x.name.clone());
+                    continue;
+                }
+
                 let key: RpcRanking = RpcRanking::new(x.tier, x.backup, Some(x_head_num));
 
                 other_rpcs
@@ -641,6 +670,7 @@ impl ConsensusFinder {
                 other_rpcs,
                 backups_needed,
                 rpc_data,
+                forked_rpcs,
             };
 
             return Some(consensus);
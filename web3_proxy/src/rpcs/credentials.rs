@@ -0,0 +1,324 @@
+//! Rotate between multiple api keys for the same backend provider.
+use super::stream::Web3RpcStreamer;
+use crate::config::{CredentialRotation, Web3RpcCredentialConfig};
+use anyhow::Context;
+use log::{trace, warn};
+use parking_lot::RwLock;
+use redis_rate_limiter::{RedisPool, RedisRateLimitResult, RedisRateLimiter};
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::sync::atomic::{self, AtomicU64, AtomicUsize};
+use tokio::time::{Duration, Instant};
+use url::Url;
+
+/// One api key for a backend provider.
+pub struct Web3RpcCredential {
+    /// our own client instead of an ethers provider so that we can see the http status of every response
+    pub(super) streamer: Web3RpcStreamer,
+    /// quota for this key. stored in a central redis so that multiple proxies can share it
+    hard_limit: Option<RedisRateLimiter>,
+    /// set when we hit this key's quota or the provider rate limits it
+    limited_until: RwLock<Instant>,
+    total_requests: AtomicU64,
+    rate_limited_requests: AtomicU64,
+}
+
+/// Every api key for a backend provider.
+pub struct Web3RpcCredentials {
+    credentials: Vec<Web3RpcCredential>,
+    rotation: CredentialRotation,
+    /// index of the key to try first
+    next: AtomicUsize,
+}
+
+pub enum CredentialResult {
+    /// The index of the credential to use
+    Ready(usize),
+    /// Every credential is rate limited. Retry at the given time.
+    RetryAt(Instant),
+}
+
+impl Web3RpcCredentials {
+    /// The rpc's main http_url is always the first credential
+    pub fn try_new(
+        chain_id: u64,
+        name: &str,
+        http_url: Url,
+        configs: Vec<Web3RpcCredentialConfig>,
+        rotation: CredentialRotation,
+        http_client: Option<reqwest::Client>,
+        redis_pool: Option<RedisPool>,
+    ) -> anyhow::Result<Self> {
+        let now = Instant::now();
+
+        let mut credentials = Vec::with_capacity(configs.len() + 1);
+
+        credentials.push(Web3RpcCredential {
+            streamer: Web3RpcStreamer::new(http_url, http_client.clone()),
+            // the rpc's hard_limit already covers the first key
+            hard_limit: None,
+            limited_until: RwLock::new(now),
+            total_requests: 0.into(),
+            rate_limited_requests: 0.into(),
+        });
+
+        for (i, config) in configs.into_iter().enumerate() {
+            let http_url = config.http_url.parse::<Url>()?;
+
+            let hard_limit = match (config.hard_limit, redis_pool.as_ref()) {
+                (None, _) => None,
+                (Some(hard_limit), Some(redis_pool)) => Some(RedisRateLimiter::new(
+                    "web3_proxy",
+                    // the first key is 0
+                    &format!("{}:{}:credential:{}", chain_id, name, i + 1),
+                    hard_limit,
+                    60.0,
+                    redis_pool.clone(),
+                )),
+                (Some(_), None) => {
+                    return Err(anyhow::anyhow!(
+                        "no redis client pool! needed for credential hard limit"
+                    ))
+                }
+            };
+
+            credentials.push(Web3RpcCredential {
+                streamer: Web3RpcStreamer::new(http_url, http_client.clone()),
+                hard_limit,
+                limited_until: RwLock::new(now),
+                total_requests: 0.into(),
+                rate_limited_requests: 0.into(),
+            });
+        }
+
+        Ok(Self {
+            credentials,
+            rotation,
+            next: 0.into(),
+        })
+    }
+
+    pub fn get(&self, i: usize) -> Option<&Web3RpcCredential> {
+        self.credentials.get(i)
+    }
+
+    /// Find a key that is under its quota
+    pub async fn select(&self, rpc_name: &str) -> anyhow::Result<CredentialResult> {
+        let num_credentials = self.credentials.len();
+
+        let start = match self.rotation {
+            CredentialRotation::PerRequest => self.next.fetch_add(1, atomic::Ordering::Relaxed),
+            CredentialRotation::OnRateLimit => self.next.load(atomic::Ordering::Relaxed),
+        };
+
+        let now = Instant::now();
+
+        let mut earliest_retry_at: Option<Instant> = None;
+
+        for offset in 0..num_credentials {
+            let i = (start + offset) % num_credentials;
+
+            let credential = &self.credentials[i];
+
+            let limited_until = *credential.limited_until.read();
+            if limited_until > now {
+                earliest_retry_at =
+                    Some(earliest_retry_at.map_or(limited_until, |x| x.min(limited_until)));
+                continue;
+            }
+
+            if let Some(hard_limit) = credential.hard_limit.as_ref() {
+                match hard_limit.throttle().await.context(format!(
+                    "attempting to throttle {} credential {}",
+                    rpc_name, i
+                ))? {
+                    RedisRateLimitResult::Allowed(_) => {}
+                    RedisRateLimitResult::RetryAt(retry_at, _) => {
+                        trace!("{} credential {} is over quota", rpc_name, i);
+
+                        *credential.limited_until.write() = retry_at;
+
+                        earliest_retry_at =
+                            Some(earliest_retry_at.map_or(retry_at, |x| x.min(retry_at)));
+                        continue;
+                    }
+                    RedisRateLimitResult::RetryNever => {
+                        warn!(
+                            "how did retry never on {} credential {} happen?",
+                            rpc_name, i
+                        );
+                        continue;
+                    }
+                }
+            }
+
+            if offset > 0 && self.rotation == CredentialRotation::OnRateLimit {
+                // stick with this key until it is limited too
+                self.next.store(i, atomic::Ordering::Relaxed);
+            }
+
+            credential
+                .total_requests
+                .fetch_add(1, atomic::Ordering::Relaxed);
+
+            return Ok(CredentialResult::Ready(i));
+        }
+
+        // TODO: what should the default be?
+        let retry_at = earliest_retry_at.unwrap_or_else(|| now + Duration::from_secs(1));
+
+        Ok(CredentialResult::RetryAt(retry_at))
+    }
+
+    /// The provider told us that a key is rate limited. Stop using it for a little while
+    pub fn rate_limited(&self, i: usize) {
+        if let Some(credential) = self.credentials.get(i) {
+            credential
+                .rate_limited_requests
+                .fetch_add(1, atomic::Ordering::Relaxed);
+
+            // TODO: how long should we actually wait? different providers have different times
+            *credential.limited_until.write() = Instant::now() + Duration::from_secs(1);
+        }
+
+        if self.rotation == CredentialRotation::OnRateLimit {
+            self.next.store(i + 1, atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+impl Serialize for Web3RpcCredentials {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Web3RpcCredentials", 2)?;
+
+        state.serialize_field("rotation", &format!("{:?}", self.rotation))?;
+        state.serialize_field("credentials", &self.credentials)?;
+
+        state.end()
+    }
+}
+
+impl Serialize for Web3RpcCredential {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // the url is excluded because it includes the api key
+        let mut state = serializer.serialize_struct("Web3RpcCredential", 3)?;
+
+        state.serialize_field(
+            "total_requests",
+            &self.total_requests.load(atomic::Ordering::Relaxed),
+        )?;
+        state.serialize_field(
+            "rate_limited_requests",
+            &self.rate_limited_requests.load(atomic::Ordering::Relaxed),
+        )?;
+        state.serialize_field("limited", &(*self.limited_until.read() > Instant::now()))?;
+
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(num_extra: usize, rotation: CredentialRotation) -> Web3RpcCredentials {
+        let configs = (1..=num_extra)
+            .map(|i| Web3RpcCredentialConfig {
+                http_url: format!("http://127.0.0.1:1/key{}", i),
+                hard_limit: None,
+            })
+            .collect();
+
+        Web3RpcCredentials::try_new(
+            1,
+            "test",
+            "http://127.0.0.1:1/key0".parse().unwrap(),
+            configs,
+            rotation,
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    async fn select(x: &Web3RpcCredentials) -> usize {
+        match x.select("test").await.unwrap() {
+            CredentialResult::Ready(i) => i,
+            CredentialResult::RetryAt(_) => panic!("expected a ready credential"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_per_request_rotation() {
+        let x = credentials(2, CredentialRotation::PerRequest);
+
+        let picked = [
+            select(&x).await,
+            select(&x).await,
+            select(&x).await,
+            select(&x).await,
+        ];
+        assert_eq!(picked, [0, 1, 2, 0]);
+
+        // a limited key is skipped until its limit is over
+        x.rate_limited(1);
+        assert_eq!(select(&x).await, 2);
+        assert_eq!(select(&x).await, 2);
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(select(&x).await, 0);
+        assert_eq!(select(&x).await, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_on_rate_limit_rotation() {
+        let x = credentials(1, CredentialRotation::OnRateLimit);
+
+        assert_eq!(select(&x).await, 0);
+        assert_eq!(select(&x).await, 0);
+
+        // a request that started on the old key keeps it while the new key is rotated in
+        let in_flight = 0;
+        x.rate_limited(in_flight);
+        assert_eq!(select(&x).await, 1);
+        assert!(x.get(in_flight).is_some());
+
+        // once the old key's limit is over, the new key is still used until it is limited too
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(select(&x).await, 1);
+
+        x.rate_limited(1);
+        assert_eq!(select(&x).await, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_all_rate_limited() {
+        let x = credentials(1, CredentialRotation::OnRateLimit);
+
+        x.rate_limited(0);
+        tokio::time::advance(Duration::from_millis(500)).await;
+        x.rate_limited(1);
+
+        // the first key's limit ends first
+        match x.select("test").await.unwrap() {
+            CredentialResult::Ready(i) => panic!("credential {} should be limited", i),
+            CredentialResult::RetryAt(retry_at) => {
+                assert_eq!(retry_at, Instant::now() + Duration::from_millis(500))
+            }
+        }
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(select(&x).await, 0);
+
+        let stats = serde_json::to_value(&x).unwrap();
+        assert_eq!(stats["credentials"][0]["total_requests"], 1);
+        assert_eq!(stats["credentials"][0]["rate_limited_requests"], 1);
+        assert_eq!(stats["credentials"][1]["limited"], true);
+    }
+}
@@ -0,0 +1,270 @@
+//! Failover groups keep expensive backends on standby until the cheaper ones are unhealthy.
+//! Groups are checked in order. The first healthy group gets traffic and every other group waits.
+//! A group is demoted as soon as it is unhealthy, but it only takes traffic back after it has stayed healthy for `promote_after_seconds`.
+//! Servers that are not in a group always get traffic.
+use super::one::Web3Rpc;
+use crate::config::FailoverGroupConfig;
+use ethers::prelude::U64;
+use hashbrown::HashMap;
+use log::{info, warn};
+use parking_lot::Mutex;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+/// with fewer requests than this since the last check, a group's error rate is ignored
+const MIN_REQUESTS_FOR_ERROR_RATE: usize = 10;
+
+#[derive(Debug, Default)]
+struct FailoverState {
+    /// the index of the group that gets traffic. None sends traffic to every group
+    active: Option<usize>,
+    /// when each group became healthy. None while it is unhealthy
+    healthy_since: Vec<Option<Instant>>,
+    /// every server's total requests and errors at the last check
+    last_counts: HashMap<String, (usize, usize)>,
+}
+
+#[derive(Debug, Default)]
+pub struct FailoverGroups {
+    groups: Vec<FailoverGroupConfig>,
+    state: Mutex<FailoverState>,
+}
+
+impl FailoverGroups {
+    pub fn new(groups: Vec<FailoverGroupConfig>) -> Self {
+        let state = FailoverState {
+            healthy_since: vec![None; groups.len()],
+            ..Default::default()
+        };
+
+        Self {
+            groups,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// The name of the group that gets traffic. None if there are no groups or none of them are healthy
+    pub fn active_group(&self) -> Option<String> {
+        self.state
+            .lock()
+            .active
+            .map(|x| self.groups[x].name.clone())
+    }
+
+    /// True if this server is in a group that should not get traffic right now
+    pub fn on_standby(&self, rpc: &Web3Rpc) -> bool {
+        let group = match rpc.failover_group.as_deref() {
+            Some(x) => x,
+            None => return false,
+        };
+
+        let active = match self.state.lock().active {
+            Some(x) => x,
+            None => return false,
+        };
+
+        // servers in unknown groups are treated like servers without a group
+        self.groups
+            .iter()
+            .position(|x| x.name == group)
+            .map(|x| x != active)
+            .unwrap_or(false)
+    }
+
+    /// Check every group's health. Called whenever the consensus head changes.
+    /// A server is synced if its head is at least `min_synced_block`
+    pub fn check(&self, rpcs: &[Arc<Web3Rpc>], min_synced_block: U64) {
+        if self.groups.is_empty() {
+            return;
+        }
+
+        let mut state = self.state.lock();
+        let state = &mut *state;
+
+        let mut healthy = Vec::with_capacity(self.groups.len());
+
+        for group in self.groups.iter() {
+            let mut num_synced = 0;
+            let mut requests = 0;
+            let mut errors = 0;
+
+            for rpc in rpcs
+                .iter()
+                .filter(|x| x.failover_group.as_deref() == Some(group.name.as_str()))
+            {
+                if rpc.head_block_num() >= Some(min_synced_block) {
+                    num_synced += 1;
+                }
+
+                let total_requests = rpc.total_requests.load(Ordering::Acquire);
+                let total_errors = rpc.total_errors.load(Ordering::Acquire);
+
+                let (last_requests, last_errors) = state
+                    .last_counts
+                    .insert(rpc.name.clone(), (total_requests, total_errors))
+                    .unwrap_or_default();
+
+                requests += total_requests.saturating_sub(last_requests);
+                errors += total_errors.saturating_sub(last_errors);
+            }
+
+            healthy.push(is_healthy(group, num_synced, requests, errors));
+        }
+
+        let active = choose_active(
+            &self.groups,
+            &mut state.healthy_since,
+            &healthy,
+            state.active,
+            Instant::now(),
+        );
+
+        if active != state.active {
+            let name = |x: Option<usize>| x.map(|x| self.groups[x].name.as_str());
+
+            match active {
+                Some(_) => info!(
+                    "failover group changed from {:?} to {:?}",
+                    name(state.active),
+                    name(active)
+                ),
+                None => warn!(
+                    "no failover group is healthy. sending traffic to all of them instead of {:?}",
+                    name(state.active)
+                ),
+            }
+
+            state.active = active;
+        }
+    }
+}
+
+fn is_healthy(
+    group: &FailoverGroupConfig,
+    num_synced: usize,
+    requests: usize,
+    errors: usize,
+) -> bool {
+    if num_synced < group.min_synced_rpcs.max(1) {
+        return false;
+    }
+
+    requests < MIN_REQUESTS_FOR_ERROR_RATE
+        || (errors as u64) * 1000 <= group.max_error_permille * requests as u64
+}
+
+/// The first healthy group. Groups before the current one must also have been healthy for `promote_after_seconds`
+fn choose_active(
+    groups: &[FailoverGroupConfig],
+    healthy_since: &mut [Option<Instant>],
+    healthy: &[bool],
+    current: Option<usize>,
+    now: Instant,
+) -> Option<usize> {
+    for (since, is_healthy) in healthy_since.iter_mut().zip(healthy) {
+        if !is_healthy {
+            *since = None;
+        } else if since.is_none() {
+            *since = Some(now);
+        }
+    }
+
+    (0..groups.len()).find(|&i| match (healthy_since[i], current) {
+        (None, _) => false,
+        (Some(since), Some(current)) if i < current => {
+            now.duration_since(since) >= Duration::from_secs(groups[i].promote_after_seconds)
+        }
+        (Some(_), _) => true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose_active() {
+        let group = |name: &str| FailoverGroupConfig {
+            name: name.to_string(),
+            min_synced_rpcs: 1,
+            max_error_permille: 200,
+            promote_after_seconds: 60,
+        };
+
+        let groups = vec![
+            group("self_hosted"),
+            group("provider_a"),
+            group("provider_b"),
+        ];
+
+        let mut healthy_since = vec![None; 3];
+
+        let start = Instant::now();
+
+        // the first healthy group gets the traffic
+        let active = choose_active(
+            &groups,
+            &mut healthy_since,
+            &[true, true, true],
+            None,
+            start,
+        );
+        assert_eq!(active, Some(0));
+
+        // demotion is immediate
+        let active = choose_active(
+            &groups,
+            &mut healthy_since,
+            &[false, true, true],
+            active,
+            start + Duration::from_secs(1),
+        );
+        assert_eq!(active, Some(1));
+
+        // promotion waits until the group has been healthy for long enough
+        let active = choose_active(
+            &groups,
+            &mut healthy_since,
+            &[true, true, true],
+            active,
+            start + Duration::from_secs(2),
+        );
+        assert_eq!(active, Some(1));
+
+        let active = choose_active(
+            &groups,
+            &mut healthy_since,
+            &[true, true, true],
+            active,
+            start + Duration::from_secs(62),
+        );
+        assert_eq!(active, Some(0));
+
+        // with no healthy groups, every group gets traffic
+        let active = choose_active(
+            &groups,
+            &mut healthy_since,
+            &[false, false, false],
+            active,
+            start + Duration::from_secs(63),
+        );
+        assert_eq!(active, None);
+    }
+
+    #[test]
+    fn test_is_healthy() {
+        let group = FailoverGroupConfig {
+            name: "self_hosted".to_string(),
+            min_synced_rpcs: 2,
+            max_error_permille: 200,
+            promote_after_seconds: 60,
+        };
+
+        assert!(!is_healthy(&group, 1, 100, 0));
+        assert!(is_healthy(&group, 2, 100, 20));
+        assert!(!is_healthy(&group, 2, 100, 21));
+        // too few requests to judge
+        assert!(is_healthy(&group, 2, 5, 5));
+    }
+}
@@ -0,0 +1,168 @@
+//! Broadcast changes to the consensus head so that internal subsystems do not each need to poll for them.
+//!
+//! `watch_consensus_head_receiver` is still the way to get the *current* head. This bus is for reacting to every change.
+//! The response cache, the negative cache, websocket `newHeads`, and transaction tracking all subscribe to it.
+//! Filter emulation and a deposit watcher should subscribe here too when they are added. The filter methods are still unsupported and deposits are only credited through `POST /user/balance/:tx_hash`.
+use super::blockchain::Web3ProxyBlock;
+use tokio::sync::broadcast;
+
+/// how many events a slow subscriber can fall behind before it starts missing them
+/// TODO: put this in the config?
+const HEAD_EVENTS_CAPACITY: usize = 64;
+
+#[derive(Clone, Debug)]
+pub enum HeadEvent {
+    /// the consensus head moved forward on the same chain
+    NewHead(Web3ProxyBlock),
+    /// the consensus head moved to a different chain. `new` is the new head block
+    Reorg {
+        old: Web3ProxyBlock,
+        new: Web3ProxyBlock,
+    },
+    /// the backends agree on a newer finalized block
+    Finalized(Web3ProxyBlock),
+}
+
+impl HeadEvent {
+    /// The head block for `NewHead` and `Reorg`. Finalized blocks are not heads
+    pub fn head_block(&self) -> Option<&Web3ProxyBlock> {
+        match self {
+            Self::NewHead(x) => Some(x),
+            Self::Reorg { new, .. } => Some(new),
+            Self::Finalized(_) => None,
+        }
+    }
+
+    /// Decide what event (if any) a change in the consensus head is
+    pub fn classify(old: Option<&Web3ProxyBlock>, new: &Web3ProxyBlock) -> Option<Self> {
+        let old = match old {
+            None => return Some(Self::NewHead(new.clone())),
+            Some(x) => x,
+        };
+
+        if old.hash() == new.hash() {
+            return None;
+        }
+
+        // a head that does not build on the old head is a reorg
+        // blocks that skip ahead more than one can't be checked without more lookups. those are treated as new heads
+        let reorged = new.number() <= old.number()
+            || (*new.number() == *old.number() + 1 && new.parent_hash() != old.hash());
+
+        if reorged {
+            Some(Self::Reorg {
+                old: old.clone(),
+                new: new.clone(),
+            })
+        } else {
+            Some(Self::NewHead(new.clone()))
+        }
+    }
+}
+
+/// Fan out `HeadEvent`s to any number of subscribers.
+/// Adding a new consumer is just a call to `subscribe`.
+#[derive(Clone)]
+pub struct HeadEventBus {
+    sender: broadcast::Sender<HeadEvent>,
+}
+
+impl Default for HeadEventBus {
+    fn default() -> Self {
+        Self::new(HEAD_EVENTS_CAPACITY)
+    }
+}
+
+impl HeadEventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+
+        Self { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<HeadEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Send an event to every subscriber. Having no subscribers is fine
+    pub fn publish(&self, event: HeadEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Publish whatever event the change from `old` to `new` is
+    pub fn publish_head(&self, old: Option<&Web3ProxyBlock>, new: &Web3ProxyBlock) {
+        if let Some(event) = HeadEvent::classify(old, new) {
+            self.publish(event);
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Block, TxHash, H256, U64};
+    use std::sync::Arc;
+
+    fn block(num: u64, hash: u64, parent_hash: u64) -> Web3ProxyBlock {
+        let block: Block<TxHash> = Block {
+            number: Some(U64::from(num)),
+            hash: Some(H256::from_low_u64_be(hash)),
+            parent_hash: H256::from_low_u64_be(parent_hash),
+            ..Default::default()
+        };
+
+        Web3ProxyBlock::try_new(Arc::new(block)).unwrap()
+    }
+
+    #[test]
+    fn test_classify_head_events() {
+        let a = block(1, 1, 0);
+        let b = block(2, 2, 1);
+        let b_uncle = block(2, 3, 1);
+        let c_other_fork = block(3, 4, 3);
+
+        assert!(matches!(
+            HeadEvent::classify(None, &a),
+            Some(HeadEvent::NewHead(_))
+        ));
+        assert!(HeadEvent::classify(Some(&a), &a).is_none());
+        assert!(matches!(
+            HeadEvent::classify(Some(&a), &b),
+            Some(HeadEvent::NewHead(_))
+        ));
+        assert!(matches!(
+            HeadEvent::classify(Some(&b), &b_uncle),
+            Some(HeadEvent::Reorg { .. })
+        ));
+        assert!(matches!(
+            HeadEvent::classify(Some(&b), &a),
+            Some(HeadEvent::Reorg { .. })
+        ));
+        assert!(matches!(
+            HeadEvent::classify(Some(&b), &c_other_fork),
+            Some(HeadEvent::Reorg { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_head_event_bus() {
+        let bus = HeadEventBus::default();
+
+        // no subscribers is not an error
+        bus.publish_head(None, &block(1, 1, 0));
+
+        let mut first = bus.subscribe();
+        let mut second = bus.subscribe();
+
+        bus.publish_head(Some(&block(1, 1, 0)), &block(2, 2, 1));
+
+        for rx in [&mut first, &mut second] {
+            let event = rx.recv().await.unwrap();
+            assert_eq!(*event.head_block().unwrap().number(), U64::from(2));
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! A quorum on top of `min_synced_rpcs` and `min_sum_soft_limit` for picking the consensus head.
+//!
+//! Every server votes for its head block and that block's ancestors. Without a quorum, the highest block with enough votes wins,
+//! so one fast server on a minority fork can become the head. With a quorum, a block also needs a share of every server that voted.
+//! Servers that are at or past the consensus head but did not vote for it are on another fork. They get no requests until they rejoin.
+use super::one::Web3Rpc;
+use crate::config::HeadQuorumConfig;
+
+#[derive(Clone, Copy, Debug)]
+pub struct HeadQuorum {
+    min_rpcs_permille: u64,
+    min_soft_limit_permille: u64,
+    /// every server that voted for any block
+    total_rpcs: usize,
+    total_soft_limit: u64,
+}
+
+impl HeadQuorum {
+    /// None if the quorum is disabled
+    pub fn new<'a>(
+        config: &HeadQuorumConfig,
+        voters: impl IntoIterator<Item = &'a Web3Rpc>,
+    ) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+
+        let mut total_rpcs = 0;
+        let mut total_soft_limit = 0;
+
+        for rpc in voters {
+            total_rpcs += 1;
+            total_soft_limit += rpc.soft_limit as u64;
+        }
+
+        Some(Self {
+            min_rpcs_permille: config.min_rpcs_permille,
+            min_soft_limit_permille: config.min_soft_limit_permille,
+            total_rpcs,
+            total_soft_limit,
+        })
+    }
+
+    /// True if a block with these votes has enough of the servers and enough of their soft limits
+    pub fn reached(&self, rpcs: usize, soft_limit: u32) -> bool {
+        rpcs as u64 * 1000 >= self.min_rpcs_permille * self.total_rpcs as u64
+            && soft_limit as u64 * 1000 >= self.min_soft_limit_permille * self.total_soft_limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reached() {
+        let quorum = HeadQuorum {
+            min_rpcs_permille: 501,
+            min_soft_limit_permille: 0,
+            total_rpcs: 4,
+            total_soft_limit: 4_000,
+        };
+
+        // half is not a majority
+        assert!(!quorum.reached(2, 2_000));
+        assert!(quorum.reached(3, 100));
+
+        let quorum = HeadQuorum {
+            min_rpcs_permille: 0,
+            min_soft_limit_permille: 667,
+            ..quorum
+        };
+
+        assert!(!quorum.reached(3, 2_000));
+        assert!(quorum.reached(1, 3_000));
+    }
+}
@@ -0,0 +1,388 @@
+//! Health scores for every backend. Unhealthy backends are demoted out of the selection pool until probes succeed.
+//!
+//! Scores are updated when the consensus head changes, at most once every `check_seconds`.
+//! Error rate and p99 latency are over the last `window_seconds`. Head lag is how many blocks behind the consensus head the server is right now.
+//! A demoted server gets no traffic, so it is judged by the health check requests that idle servers already send.
+//! It is promoted after `promote_after_probes` checks in a row where those probes succeeded quickly and the server was caught up.
+//! Demoted servers are still tried if no other server can serve a request.
+use super::one::Web3Rpc;
+use crate::config::BackendHealthConfig;
+use ethers::prelude::U64;
+use hashbrown::HashMap;
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+/// with fewer requests than this in the window, a server's error rate is ignored
+const MIN_REQUESTS_FOR_ERROR_RATE: usize = 10;
+
+/// with fewer latencies than this in the window, a server's p99 is ignored
+const MIN_SAMPLES_FOR_P99: usize = 20;
+
+/// the most latencies that are kept between checks. later ones are dropped
+const MAX_LATENCY_SAMPLES: usize = 10_000;
+
+/// Latencies of successful requests since the last check
+#[derive(Debug, Default)]
+pub struct LatencySamples(Mutex<Vec<u32>>);
+
+impl LatencySamples {
+    pub fn record(&self, latency: Duration) {
+        let mut samples = self.0.lock();
+
+        if samples.len() < MAX_LATENCY_SAMPLES {
+            samples.push(latency.as_millis().min(u32::MAX as u128) as u32);
+        }
+    }
+
+    fn take(&self) -> Vec<u32> {
+        std::mem::take(&mut *self.0.lock())
+    }
+}
+
+/// What one server did between two checks
+#[derive(Debug)]
+struct HealthBucket {
+    at: Instant,
+    requests: usize,
+    errors: usize,
+    latencies_ms: Vec<u32>,
+}
+
+/// One server's current score. Returned by `GET /status/backends`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BackendScore {
+    pub name: String,
+    /// requests in the window
+    pub requests: usize,
+    /// errors in the window
+    pub errors: usize,
+    /// None with too few requests to judge
+    pub error_permille: Option<u64>,
+    /// None with too few successful requests to judge
+    pub p99_latency_ms: Option<u32>,
+    /// None if the server has no head block
+    pub head_lag: Option<u64>,
+    /// why the server is unhealthy. empty if it is healthy
+    pub unhealthy: Vec<&'static str>,
+    pub demoted: bool,
+    pub demoted_seconds: Option<u64>,
+    /// good probes in a row while demoted
+    pub good_probes: u32,
+}
+
+#[derive(Debug, Default)]
+struct RpcHealth {
+    buckets: VecDeque<HealthBucket>,
+    /// total requests and errors at the last check
+    last_counts: (usize, usize),
+    demoted_since: Option<Instant>,
+    good_probes: u32,
+    score: BackendScore,
+}
+
+impl RpcHealth {
+    /// Demote as soon as the window is unhealthy. While demoted, count the probes in the newest bucket.
+    /// A check without any probes does not count either way. Returns true if the server was demoted or promoted
+    fn update_demotion(
+        &mut self,
+        config: &BackendHealthConfig,
+        window_healthy: bool,
+        probe_healthy: Option<bool>,
+        now: Instant,
+    ) -> bool {
+        match self.demoted_since {
+            None if !window_healthy => {
+                self.demoted_since = Some(now);
+                self.good_probes = 0;
+                true
+            }
+            None => false,
+            Some(_) => {
+                match probe_healthy {
+                    Some(true) => self.good_probes += 1,
+                    Some(false) => self.good_probes = 0,
+                    None => {}
+                }
+
+                if self.good_probes >= config.promote_after_probes.max(1) {
+                    self.demoted_since = None;
+                    self.good_probes = 0;
+                    // the old requests are why it was demoted. start over with the probes
+                    self.buckets.clear();
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct HealthState {
+    last_check: Option<Instant>,
+    rpcs: HashMap<String, RpcHealth>,
+}
+
+#[derive(Debug, Default)]
+pub struct BackendHealth {
+    config: BackendHealthConfig,
+    state: Mutex<HealthState>,
+}
+
+impl BackendHealth {
+    pub fn new(config: BackendHealthConfig) -> Self {
+        Self {
+            config,
+            state: Default::default(),
+        }
+    }
+
+    /// True if this server should only be tried after every other server
+    pub fn is_demoted(&self, rpc: &Web3Rpc) -> bool {
+        if !self.config.enabled {
+            return false;
+        }
+
+        self.state
+            .lock()
+            .rpcs
+            .get(&rpc.name)
+            .map(|x| x.demoted_since.is_some())
+            .unwrap_or(false)
+    }
+
+    /// The server's p99 latency over the last window. None with too few requests
+    pub fn p99_latency_ms(&self, rpc: &Web3Rpc) -> Option<u32> {
+        self.state
+            .lock()
+            .rpcs
+            .get(&rpc.name)
+            .and_then(|x| x.score.p99_latency_ms)
+    }
+
+    /// Every server's current score, sorted by name
+    pub fn scores(&self) -> Vec<BackendScore> {
+        let now = Instant::now();
+
+        let mut scores: Vec<_> = self
+            .state
+            .lock()
+            .rpcs
+            .values()
+            .map(|x| {
+                let mut score = x.score.clone();
+                score.demoted_seconds = x.demoted_since.map(|x| now.duration_since(x).as_secs());
+                score
+            })
+            .collect();
+
+        scores.sort_by(|a, b| a.name.cmp(&b.name));
+
+        scores
+    }
+
+    /// Score every server. Called whenever the consensus head changes.
+    /// Scores are always kept, but servers are only demoted if `enabled`
+    pub fn check(&self, rpcs: &[Arc<Web3Rpc>], consensus_head_num: U64) {
+        let now = Instant::now();
+
+        let mut state = self.state.lock();
+
+        if let Some(last_check) = state.last_check {
+            if now.duration_since(last_check) < Duration::from_secs(self.config.check_seconds) {
+                return;
+            }
+        }
+        state.last_check = Some(now);
+
+        let window = Duration::from_secs(self.config.window_seconds.max(1));
+
+        // forget servers that were removed from the config
+        state
+            .rpcs
+            .retain(|name, _| rpcs.iter().any(|x| &x.name == name));
+
+        for rpc in rpcs.iter() {
+            let health = state.rpcs.entry(rpc.name.clone()).or_default();
+
+            let total_requests = rpc.total_requests.load(Ordering::Acquire);
+            let total_errors = rpc.total_errors.load(Ordering::Acquire);
+
+            let (last_requests, last_errors) = health.last_counts;
+            health.last_counts = (total_requests, total_errors);
+
+            health.buckets.push_back(HealthBucket {
+                at: now,
+                requests: total_requests.saturating_sub(last_requests),
+                errors: total_errors.saturating_sub(last_errors),
+                latencies_ms: rpc.latency_samples.take(),
+            });
+
+            while let Some(x) = health.buckets.front() {
+                if now.duration_since(x.at) > window {
+                    health.buckets.pop_front();
+                } else {
+                    break;
+                }
+            }
+
+            let head_lag = rpc
+                .head_block_num()
+                .map(|x| consensus_head_num.saturating_sub(x).as_u64());
+
+            let requests = health.buckets.iter().map(|x| x.requests).sum();
+            let errors = health.buckets.iter().map(|x| x.errors).sum();
+
+            let mut latencies_ms: Vec<u32> = health
+                .buckets
+                .iter()
+                .flat_map(|x| x.latencies_ms.iter().copied())
+                .collect();
+
+            let error_permille = (requests >= MIN_REQUESTS_FOR_ERROR_RATE)
+                .then(|| errors as u64 * 1000 / requests as u64);
+
+            let p99_latency_ms = p99(&mut latencies_ms);
+
+            let unhealthy =
+                unhealthy_reasons(&self.config, error_permille, p99_latency_ms, head_lag);
+
+            // probes are judged on their own. there are too few of them for a rate or a p99
+            let probe_healthy = health.buckets.back().and_then(|x| {
+                (x.requests > 0).then(|| {
+                    x.errors == 0
+                        && x.latencies_ms
+                            .iter()
+                            .all(|x| *x as u64 <= self.config.max_p99_latency_ms)
+                        && head_lag
+                            .map(|x| x <= self.config.max_head_lag)
+                            .unwrap_or(false)
+                })
+            });
+
+            if self.config.enabled
+                && health.update_demotion(&self.config, unhealthy.is_empty(), probe_healthy, now)
+            {
+                if health.demoted_since.is_some() {
+                    warn!("demoting {}: {:?}", rpc, unhealthy);
+                } else {
+                    info!("promoting {} after good probes", rpc);
+                }
+            }
+
+            health.score = BackendScore {
+                name: rpc.name.clone(),
+                requests,
+                errors,
+                error_permille,
+                p99_latency_ms,
+                head_lag,
+                unhealthy,
+                demoted: health.demoted_since.is_some(),
+                demoted_seconds: None,
+                good_probes: health.good_probes,
+            };
+        }
+    }
+}
+
+/// Nearest-rank 99th percentile. None with too few samples
+fn p99(latencies_ms: &mut [u32]) -> Option<u32> {
+    if latencies_ms.len() < MIN_SAMPLES_FOR_P99 {
+        return None;
+    }
+
+    latencies_ms.sort_unstable();
+
+    let rank = (latencies_ms.len() * 99 + 99) / 100;
+
+    Some(latencies_ms[rank - 1])
+}
+
+fn unhealthy_reasons(
+    config: &BackendHealthConfig,
+    error_permille: Option<u64>,
+    p99_latency_ms: Option<u32>,
+    head_lag: Option<u64>,
+) -> Vec<&'static str> {
+    let mut reasons = vec![];
+
+    if error_permille.map(|x| x > config.max_error_permille) == Some(true) {
+        reasons.push("error_rate");
+    }
+
+    if p99_latency_ms.map(|x| x as u64 > config.max_p99_latency_ms) == Some(true) {
+        reasons.push("p99_latency");
+    }
+
+    match head_lag {
+        None => reasons.push("no_head_block"),
+        Some(x) if x > config.max_head_lag => reasons.push("head_lag"),
+        Some(_) => {}
+    }
+
+    reasons
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_p99() {
+        assert_eq!(p99(&mut [1; 10]), None);
+
+        let mut latencies_ms: Vec<u32> = (1..=200).rev().collect();
+        assert_eq!(p99(&mut latencies_ms), Some(198));
+    }
+
+    #[test]
+    fn test_update_demotion() {
+        let config = BackendHealthConfig {
+            enabled: true,
+            promote_after_probes: 2,
+            ..Default::default()
+        };
+
+        let now = Instant::now();
+
+        let mut x = RpcHealth::default();
+
+        assert!(!x.update_demotion(&config, true, None, now));
+
+        // demotion is immediate
+        assert!(x.update_demotion(&config, false, None, now));
+        assert!(x.demoted_since.is_some());
+
+        // a bad probe starts the count over and checks without probes do not count
+        assert!(!x.update_demotion(&config, false, Some(true), now));
+        assert!(!x.update_demotion(&config, false, Some(false), now));
+        assert!(!x.update_demotion(&config, false, Some(true), now));
+        assert!(!x.update_demotion(&config, false, None, now));
+
+        // promotion only needs good probes
+        assert!(x.update_demotion(&config, false, Some(true), now));
+        assert!(x.demoted_since.is_none());
+    }
+
+    #[test]
+    fn test_unhealthy_reasons() {
+        let config = BackendHealthConfig::default();
+
+        assert!(unhealthy_reasons(&config, None, None, Some(0)).is_empty());
+        assert_eq!(
+            unhealthy_reasons(&config, Some(1000), Some(60_000), None),
+            vec!["error_rate", "p99_latency", "no_head_block"]
+        );
+        assert_eq!(
+            unhealthy_reasons(&config, Some(0), Some(1), Some(100)),
+            vec!["head_lag"]
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Hedged requests for slow backends.
+//!
+//! If the first server hasn't answered a method within its hedge delay, the same request is sent to one more idle server.
+//! Whichever answers first is returned and the other request is cancelled. An error only wins if both servers error.
+//! A delay of 0 uses the first server's p99 latency from the health scores, or `fallback_delay_ms` until it has one.
+//! Methods that change state are never hedged.
+use crate::app::STATE_CHANGING_METHODS;
+use crate::config::HedgingConfig;
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::time::Duration;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct HedgeStats {
+    /// requests that were sent to a second server
+    pub hedged: u64,
+    /// hedged requests where the second server answered first
+    pub hedge_wins: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Hedging {
+    config: HedgingConfig,
+    stats: Mutex<HashMap<String, HedgeStats>>,
+}
+
+impl Hedging {
+    pub fn new(config: HedgingConfig) -> Self {
+        Self {
+            config,
+            stats: Default::default(),
+        }
+    }
+
+    /// How long to wait for the first server before hedging. None if this method is never hedged
+    pub fn delay(&self, method: &str, p99_latency_ms: Option<u32>) -> Option<Duration> {
+        if !self.config.enabled || STATE_CHANGING_METHODS.contains(&method) {
+            return None;
+        }
+
+        let delay_ms = match *self.config.methods.get(method)? {
+            0 => p99_latency_ms
+                .map(u64::from)
+                .unwrap_or(self.config.fallback_delay_ms),
+            x => x,
+        };
+
+        Some(Duration::from_millis(delay_ms))
+    }
+
+    pub fn record(&self, method: &str, hedge_won: bool) {
+        let mut stats = self.stats.lock();
+
+        let x = stats.entry(method.to_string()).or_default();
+
+        x.hedged += 1;
+        if hedge_won {
+            x.hedge_wins += 1;
+        }
+    }
+
+    /// Totals since startup for every method that was hedged
+    pub fn stats(&self) -> HashMap<String, HedgeStats> {
+        self.stats.lock().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let hedging = Hedging::new(HedgingConfig {
+            enabled: true,
+            methods: [
+                ("eth_call".to_string(), 300),
+                ("eth_getLogs".to_string(), 0),
+                ("eth_sendRawTransaction".to_string(), 100),
+            ]
+            .into_iter()
+            .collect(),
+            fallback_delay_ms: 1_000,
+        });
+
+        assert_eq!(
+            hedging.delay("eth_call", Some(50)),
+            Some(Duration::from_millis(300))
+        );
+        assert_eq!(
+            hedging.delay("eth_getLogs", Some(50)),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(
+            hedging.delay("eth_getLogs", None),
+            Some(Duration::from_millis(1_000))
+        );
+        assert_eq!(hedging.delay("eth_blockNumber", Some(50)), None);
+        assert_eq!(hedging.delay("eth_sendRawTransaction", Some(50)), None);
+    }
+}
@@ -0,0 +1,276 @@
+//! IPFS gateways. `/ipfs/:cid` requests get the same authorization, rate limits, caching, and stats as json-rpc requests.
+//! Content is addressed by its hash, so cached responses never go stale. They only leave the cache when it is full
+use super::one::Web3Rpc;
+use crate::config::IpfsGatewayConfig;
+use crate::frontend::authorization::RequestMetadata;
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use axum::body::Bytes;
+use hashbrown::HashMap;
+use http::header::CONTENT_TYPE;
+use http::StatusCode;
+use itertools::Itertools;
+use log::{debug, info, warn};
+use quick_cache_ttl::{CacheWithTTL, Weighter};
+use std::num::{NonZeroU32, NonZeroU64};
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// the longest CID that we will look up. real ones are well under this
+const MAX_CID_LEN: usize = 128;
+
+/// status, content-type, and body of a gateway's response
+pub type IpfsResponse = (StatusCode, Option<String>, Bytes);
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct IpfsCacheKey {
+    cid: String,
+    path: String,
+    cache_partition: Option<NonZeroU64>,
+}
+
+#[derive(Clone)]
+pub struct IpfsResponseWeigher;
+
+impl<K, Q> Weighter<K, Q, IpfsResponse> for IpfsResponseWeigher {
+    fn weight(&self, _key: &K, _qey: &Q, value: &IpfsResponse) -> NonZeroU32 {
+        let num_bytes = value.2.len().max(1).min(u32::MAX as usize) as u32;
+
+        NonZeroU32::new(num_bytes).expect("num_bytes is always at least 1")
+    }
+}
+
+pub type IpfsResponseCache = CacheWithTTL<
+    IpfsCacheKey,
+    IpfsResponse,
+    IpfsResponseWeigher,
+    quick_cache_ttl::DefaultHashBuilder,
+>;
+
+/// One backend gateway
+pub struct IpfsGateway {
+    /// gateways are not json-rpc servers. this is only used for stats and the backend headers
+    pub rpc: Arc<Web3Rpc>,
+    url: String,
+}
+
+/// Send requests to any gateway that has the content
+pub struct IpfsGateways {
+    gateways: Vec<IpfsGateway>,
+    http_client: reqwest::Client,
+    /// round robin between the gateways
+    next: AtomicUsize,
+    /// larger responses are an error instead of being buffered
+    max_response_bytes: u64,
+    /// None if caching is disabled
+    cache: Option<IpfsResponseCache>,
+}
+
+impl IpfsGateways {
+    /// None if every gateway is disabled
+    pub async fn spawn(
+        configs: HashMap<String, IpfsGatewayConfig>,
+        http_client: reqwest::Client,
+        max_response_bytes: u64,
+        cache_max_bytes: u64,
+    ) -> Option<Arc<Self>> {
+        let gateways: Vec<_> = configs
+            .into_iter()
+            .filter(|(_, config)| !config.disabled)
+            .sorted_by(|a, b| a.0.cmp(&b.0))
+            .map(|(name, config)| {
+                let rpc = Web3Rpc {
+                    name,
+                    display_name: config.display_name,
+                    ..Default::default()
+                };
+
+                IpfsGateway {
+                    rpc: Arc::new(rpc),
+                    url: config.url.trim_end_matches('/').to_string(),
+                }
+            })
+            .collect();
+
+        if gateways.is_empty() {
+            warn!("every ipfs gateway is disabled");
+            return None;
+        }
+
+        info!("{} ipfs gateways", gateways.len());
+
+        let cache = if cache_max_bytes == 0 {
+            None
+        } else {
+            let max_item_weight = max_response_bytes.min(u32::MAX as u64).max(1) as u32;
+
+            // TODO: the ttl is only here because the cache requires one. content never changes
+            Some(
+                IpfsResponseCache::new_with_weights(
+                    "ipfs_response_cache",
+                    (cache_max_bytes / 16_384).max(1) as usize,
+                    NonZeroU32::new(max_item_weight).expect("max_item_weight is at least 1"),
+                    cache_max_bytes,
+                    IpfsResponseWeigher,
+                    Duration::from_secs(86_400),
+                )
+                .await,
+            )
+        };
+
+        let x = Self {
+            gateways,
+            http_client,
+            next: AtomicUsize::new(0),
+            max_response_bytes,
+            cache,
+        };
+
+        Some(Arc::new(x))
+    }
+
+    /// Get the content from the first gateway that has it.
+    /// `path` is anything after the cid. It is usually empty
+    pub async fn request(
+        &self,
+        cid: &str,
+        path: &str,
+        bypass_cache: bool,
+        cache_partition: Option<NonZeroU64>,
+        request_metadata: &RequestMetadata,
+    ) -> Web3ProxyResult<IpfsResponse> {
+        check_cid(cid)?;
+
+        let path = path.trim_start_matches('/');
+
+        if path.split('/').any(|x| x == "..") {
+            return Err(Web3ProxyError::NotFound);
+        }
+
+        let cache_key = IpfsCacheKey {
+            cid: cid.to_string(),
+            path: path.to_string(),
+            cache_partition,
+        };
+
+        let cache = self.cache.as_ref().filter(|_| !bypass_cache);
+
+        if let Some(x) = cache.and_then(|cache| cache.get(&cache_key)) {
+            // backend_requests stays empty, so this counts as a cache hit
+            return Ok(x);
+        }
+
+        let url_path = if path.is_empty() {
+            format!("/ipfs/{}", cid)
+        } else {
+            format!("/ipfs/{}/{}", cid, path)
+        };
+
+        let first = self.next.fetch_add(1, atomic::Ordering::Relaxed);
+
+        let mut last_response = None;
+
+        for i in 0..self.gateways.len() {
+            let gateway = &self.gateways[(first + i) % self.gateways.len()];
+
+            request_metadata
+                .backend_requests
+                .lock()
+                .push(gateway.rpc.clone());
+
+            let response = match self
+                .http_client
+                .get(format!("{}{}", gateway.url, url_path))
+                .send()
+                .await
+            {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!("ipfs gateway {} failed: {:?}", gateway.rpc, err);
+                    continue;
+                }
+            };
+
+            let status = response.status();
+
+            // gateways that don't have the content (yet) give a 404 or time out with a 5xx. another gateway might have it
+            if status.is_server_error() || status == StatusCode::NOT_FOUND {
+                debug!("ipfs gateway {} returned {}", gateway.rpc, status);
+                last_response = Some((status, None, Bytes::new()));
+                continue;
+            }
+
+            if let Some(num_bytes) = response.content_length() {
+                if num_bytes > self.max_response_bytes {
+                    return Err(Web3ProxyError::ResponseTooLarge {
+                        method: "ipfs".to_string(),
+                        num_bytes,
+                        max_bytes: self.max_response_bytes,
+                    });
+                }
+            }
+
+            let content_type = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.to_string());
+
+            let body = match response.bytes().await {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!("ipfs gateway {} response failed: {:?}", gateway.rpc, err);
+                    continue;
+                }
+            };
+
+            // content-length is optional. check again now that we know the size
+            if body.len() as u64 > self.max_response_bytes {
+                return Err(Web3ProxyError::ResponseTooLarge {
+                    method: "ipfs".to_string(),
+                    num_bytes: body.len() as u64,
+                    max_bytes: self.max_response_bytes,
+                });
+            }
+
+            let x = (status, content_type, body);
+
+            if status == StatusCode::OK {
+                if let Some(cache) = cache {
+                    let _ = cache.try_insert(cache_key, x.clone());
+                }
+            }
+
+            return Ok(x);
+        }
+
+        last_response.ok_or(Web3ProxyError::NoHandleReady)
+    }
+}
+
+/// CIDs are base32 (`bafy...`) or base58 (`Qm...`). Anything else is rejected before it gets to a gateway
+fn check_cid(cid: &str) -> Web3ProxyResult<()> {
+    if cid.is_empty() || cid.len() > MAX_CID_LEN || !cid.bytes().all(|x| x.is_ascii_alphanumeric())
+    {
+        return Err(Web3ProxyError::InvalidParam(
+            "cid",
+            "not a valid IPFS CID".into(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_cid() {
+        assert!(check_cid("QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG").is_ok());
+        assert!(check_cid("bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi").is_ok());
+        assert!(check_cid("").is_err());
+        assert!(check_cid("../admin").is_err());
+        assert!(check_cid(&"a".repeat(MAX_CID_LEN + 1)).is_err());
+    }
+}
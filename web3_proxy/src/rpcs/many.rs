@@ -1,16 +1,29 @@
 ///! Load balanced communication with a group of web3 rpc providers
 use super::blockchain::{BlocksByHashCache, BlocksByNumberCache, Web3ProxyBlock};
 use super::consensus::{ConsensusWeb3Rpcs, ShouldWaitForBlock};
+use super::failover::FailoverGroups;
+use super::head_events::{HeadEvent, HeadEventBus};
+use super::health::{BackendHealth, BackendScore};
+use super::hedging::Hedging;
 use super::one::Web3Rpc;
-use super::request::{OpenRequestHandle, OpenRequestResult, RequestErrorHandler};
+use super::request::{
+    CappedRequestError, OpenRequestHandle, OpenRequestResult, RequestErrorHandler,
+};
+use super::rollup::rollup_namespace;
+use super::routing::required_capabilities;
+use super::stream::StreamingResponse;
 use crate::app::{flatten_handle, Web3ProxyApp, Web3ProxyJoinHandle};
-use crate::config::{BlockAndRpc, TxHashAndRpc, Web3RpcConfig};
+use crate::config::{
+    BackendHealthConfig, BlockAndRpc, FailoverGroupConfig, HeadQuorumConfig, HedgingConfig,
+    RoutingRuleConfig, TxHashAndRpc, Web3RpcConfig,
+};
 use crate::frontend::authorization::{Authorization, RequestMetadata};
 use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
 use crate::frontend::rpc_proxy_ws::ProxyMode;
 use crate::jsonrpc::{JsonRpcErrorData, JsonRpcRequest};
-use crate::response_cache::JsonRpcResponseData;
+use crate::response_cache::{JsonRpcResponseData, RedisCacheBackend};
 use crate::rpcs::transactions::TxStatus;
+use crate::worker_pool::WorkerPool;
 use arc_swap::ArcSwap;
 use counter::Counter;
 use derive_more::From;
@@ -30,6 +43,7 @@ use serde_json::json;
 use serde_json::value::RawValue;
 use std::borrow::Cow;
 use std::cmp::{min_by_key, Reverse};
+use std::collections::BTreeSet;
 use std::fmt::{self, Display};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
@@ -53,6 +67,8 @@ pub struct Web3Rpcs {
     pub(crate) watch_consensus_rpcs_sender: watch::Sender<Option<Arc<ConsensusWeb3Rpcs>>>,
     /// this head receiver makes it easy to wait until there is a new block
     pub(super) watch_consensus_head_sender: Option<watch::Sender<Option<Web3ProxyBlock>>>,
+    /// new heads, reorgs, and finalized blocks for internal subsystems to react to
+    pub(crate) head_events: HeadEventBus,
     /// keep track of transactions that we have sent through subscriptions
     pub(super) pending_transaction_cache: Arc<CacheWithTTL<TxHash, TxStatus>>,
     pub(super) pending_tx_id_receiver: flume::Receiver<TxHashAndRpc>,
@@ -62,21 +78,40 @@ pub struct Web3Rpcs {
     pub(super) blocks_by_hash: BlocksByHashCache,
     /// blocks on the heaviest chain
     pub(super) blocks_by_number: BlocksByNumberCache,
+    /// blocks by hash that other proxies have already fetched
+    pub(super) shared_blocks: Option<RedisCacheBackend>,
     /// the number of rpcs required to agree on consensus for the head block (thundering herd protection)
     pub(super) min_head_rpcs: usize,
+    /// the share of servers that must agree on the head block
+    pub(super) head_quorum: HeadQuorumConfig,
     /// the soft limit required to agree on consensus for the head block. (thundering herd protection)
     pub(super) min_sum_soft_limit: u32,
     /// how far behind the highest known block height we can be before we stop serving requests
     pub(super) max_block_lag: Option<U64>,
     /// how old our consensus head block we can be before we stop serving requests
     pub(super) max_block_age: Option<u64>,
+    /// limits how many requests this group sends to its backends at once
+    pub(crate) backend_pool: WorkerPool,
+    /// methods that only go to servers with the right capabilities
+    pub(super) routing_rules: Vec<RoutingRuleConfig>,
+    /// which group of servers gets traffic while the others are on standby
+    pub(super) failover: FailoverGroups,
+    /// demotes servers with bad scores
+    pub(super) health: BackendHealth,
+    /// sends slow requests to a second server
+    pub(super) hedging: Hedging,
 }
 
 impl Web3Rpcs {
     /// Spawn durable connections to multiple Web3 providers.
     #[allow(clippy::too_many_arguments)]
     pub async fn spawn(
+        backend_pool: WorkerPool,
         db_conn: Option<DatabaseConnection>,
+        failover_groups: Vec<FailoverGroupConfig>,
+        head_quorum: HeadQuorumConfig,
+        health: BackendHealthConfig,
+        hedging: HedgingConfig,
         max_block_age: Option<u64>,
         max_block_lag: Option<U64>,
         min_head_rpcs: usize,
@@ -84,6 +119,8 @@ impl Web3Rpcs {
         name: String,
         pending_transaction_cache: Arc<CacheWithTTL<TxHash, TxStatus>>,
         pending_tx_sender: Option<broadcast::Sender<TxStatus>>,
+        routing_rules: Vec<RoutingRuleConfig>,
+        shared_blocks: Option<RedisCacheBackend>,
         watch_consensus_head_sender: Option<watch::Sender<Option<Web3ProxyBlock>>>,
     ) -> anyhow::Result<(
         Arc<Self>,
@@ -98,14 +135,18 @@ impl Web3Rpcs {
         // TODO: actual weighter on this
         // TODO: time_to_idle instead?
         let blocks_by_hash: BlocksByHashCache = Arc::new(
-            CacheWithTTL::new("blocks_by_hash", 10_000, Duration::from_secs(30 * 60)).await,
+            CacheWithTTL::new("blocks_by_hash", 10_000, Duration::from_secs(30 * 60))
+                .await
+                .purgeable(),
         );
 
         // all block numbers are the same size, so no need for weigher
         // TODO: limits from config
         // TODO: time_to_idle instead?
         let blocks_by_number = Arc::new(
-            CacheWithTTL::new("blocks_by_number", 10_000, Duration::from_secs(30 * 60)).await,
+            CacheWithTTL::new("blocks_by_number", 10_000, Duration::from_secs(30 * 60))
+                .await
+                .purgeable(),
         );
 
         let (watch_consensus_rpcs_sender, consensus_connections_watcher) =
@@ -115,10 +156,16 @@ impl Web3Rpcs {
         let by_name = Default::default();
 
         let connections = Arc::new(Self {
+            backend_pool,
             block_sender,
             blocks_by_hash,
             blocks_by_number,
             by_name,
+            failover: FailoverGroups::new(failover_groups),
+            head_events: HeadEventBus::default(),
+            head_quorum,
+            health: BackendHealth::new(health),
+            hedging: Hedging::new(hedging),
             max_block_age,
             max_block_lag,
             min_head_rpcs,
@@ -127,6 +174,8 @@ impl Web3Rpcs {
             pending_transaction_cache,
             pending_tx_id_receiver,
             pending_tx_id_sender,
+            routing_rules,
+            shared_blocks,
             watch_consensus_head_sender,
             watch_consensus_rpcs_sender,
         });
@@ -147,15 +196,32 @@ impl Web3Rpcs {
         &self,
         app: &Web3ProxyApp,
         rpc_configs: HashMap<String, Web3RpcConfig>,
+    ) -> Web3ProxyResult<()> {
+        self.apply_chain_server_configs(
+            app,
+            app.config.chain_id,
+            app.config.min_synced_rpcs,
+            rpc_configs,
+        )
+        .await
+    }
+
+    /// update the rpcs in this group. for groups that serve one of the `chains` instead of the app's chain
+    pub async fn apply_chain_server_configs(
+        &self,
+        app: &Web3ProxyApp,
+        chain_id: u64,
+        min_synced_rpcs: usize,
+        rpc_configs: HashMap<String, Web3RpcConfig>,
     ) -> Web3ProxyResult<()> {
         // safety checks
-        if rpc_configs.len() < app.config.min_synced_rpcs {
+        if rpc_configs.len() < min_synced_rpcs {
             // TODO: don't count disabled servers!
             // TODO: include if this is balanced, private, or 4337
             warn!(
                 "Only {}/{} rpcs! Add more rpcs or reduce min_synced_rpcs.",
                 rpc_configs.len(),
-                app.config.min_synced_rpcs
+                min_synced_rpcs
             );
             return Ok(());
         }
@@ -193,7 +259,6 @@ impl Web3Rpcs {
 
                 let pending_tx_id_sender = Some(self.pending_tx_id_sender.clone());
                 let blocks_by_hash_cache = self.blocks_by_hash.clone();
-                let chain_id = app.config.chain_id;
 
                 debug!("spawning {}", server_name);
 
@@ -280,6 +345,145 @@ impl Web3Rpcs {
         self.min_head_rpcs
     }
 
+    /// json-rpc namespaces that at least one server supports. eth, net, and web3 are always included
+    pub fn namespaces(&self) -> BTreeSet<String> {
+        let mut namespaces: BTreeSet<String> = ["eth", "net", "web3"]
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect();
+
+        for rpc in self.by_name.load().values() {
+            namespaces.extend(rpc.namespaces().iter().cloned());
+        }
+
+        namespaces
+    }
+
+    /// Servers that do not list the rollup namespace of this method. They would only answer "method not found".
+    /// Empty for non-rollup methods, and if no server lists the namespace (then every server is tried like before)
+    fn rpcs_without_rollup_namespace(&self, method: &str) -> Vec<Arc<Web3Rpc>> {
+        let namespace = match rollup_namespace(method) {
+            Some(x) => x,
+            None => return vec![],
+        };
+
+        let (with, without): (Vec<_>, Vec<_>) = self
+            .by_name
+            .load()
+            .values()
+            .cloned()
+            .partition(|x| x.namespaces().iter().any(|x| x == namespace));
+
+        if with.is_empty() {
+            vec![]
+        } else {
+            without
+        }
+    }
+
+    /// Servers in failover groups that are waiting for the active group to fail, and servers demoted for bad health
+    fn rpcs_on_standby(&self) -> Vec<Arc<Web3Rpc>> {
+        self.by_name
+            .load()
+            .values()
+            .filter(|x| self.failover.on_standby(x) || self.health.is_demoted(x))
+            .cloned()
+            .collect()
+    }
+
+    /// Every server that lists the capability
+    pub fn rpcs_with_capability(&self, capability: &str) -> Vec<Arc<Web3Rpc>> {
+        self.by_name
+            .load()
+            .values()
+            .filter(|x| x.has_capability(capability))
+            .cloned()
+            .collect()
+    }
+
+    /// Servers that are missing a capability that the routing rules (or the classifier script) require for this request.
+    /// Empty if no rule matches, and if no server has every capability (then every server is tried like before)
+    fn rpcs_without_capabilities(
+        &self,
+        method: &str,
+        request_metadata: Option<&Arc<RequestMetadata>>,
+    ) -> Vec<Arc<Web3Rpc>> {
+        let archive_needed = request_metadata
+            .map(|x| x.archive_request.load(Ordering::Acquire))
+            .unwrap_or(false);
+
+        let mut required = required_capabilities(&self.routing_rules, method, archive_needed);
+
+        // the classifier script can ask for more
+        if let Some(x) = request_metadata.and_then(|x| x.classification.get()) {
+            required.extend(x.require.iter().map(|x| x.as_str()));
+        }
+
+        if required.is_empty() {
+            return vec![];
+        }
+
+        let (with, without): (Vec<_>, Vec<_>) = self
+            .by_name
+            .load()
+            .values()
+            .cloned()
+            .partition(|rpc| required.iter().all(|x| rpc.has_capability(x)));
+
+        if with.is_empty() {
+            debug!(
+                "no servers in {} have {:?} for {}. trying all of them",
+                self, required, method
+            );
+            vec![]
+        } else {
+            without
+        }
+    }
+
+    /// True if at least one server has every block
+    pub fn has_archive(&self) -> bool {
+        self.by_name.load().values().any(|x| x.is_archive())
+    }
+
+    /// Every server's health, sorted by name so that snapshots of the same state are the same
+    pub fn health_snapshot(&self) -> Vec<serde_json::Value> {
+        self.by_name
+            .load()
+            .values()
+            .sorted_by(|a, b| a.name.cmp(&b.name))
+            .map(|x| x.health_snapshot())
+            .collect()
+    }
+
+    /// Every server's error rate, p99 latency, head lag, and whether it is demoted
+    pub fn backend_scores(&self) -> Vec<BackendScore> {
+        self.health.scores()
+    }
+
+    /// Every server's head block number and if it is syncing
+    pub fn sync_states(&self, max_head_age: u64) -> Vec<(Option<U64>, bool)> {
+        self.by_name
+            .load()
+            .values()
+            .map(|x| (x.head_block_num(), x.is_syncing(max_head_age)))
+            .collect()
+    }
+
+    /// What every server that answered `web3_clientVersion` is running
+    pub fn client_versions(&self) -> Vec<String> {
+        self.by_name
+            .load()
+            .values()
+            .filter_map(|x| x.client_version())
+            .collect()
+    }
+
+    /// new heads, reorgs, and finalized blocks. only sent if this has a `watch_consensus_head_sender`
+    pub fn subscribe_head_events(&self) -> broadcast::Receiver<HeadEvent> {
+        self.head_events.subscribe()
+    }
+
     /// subscribe to blocks and transactions from all the backend rpcs.
     /// blocks are processed by all the `Web3Rpc`s and then sent to the `block_receiver`
     /// transaction ids from all the `Web3Rpc`s are deduplicated and forwarded to `pending_tx_sender`
@@ -319,6 +523,19 @@ impl Web3Rpcs {
 
         // setup the block funnel
         if self.watch_consensus_head_sender.is_some() {
+            let connections = Arc::clone(&self);
+            let finalized_authorization = authorization.clone();
+
+            let handle = tokio::task::Builder::default()
+                .name("watch_finalized_blocks")
+                .spawn(async move {
+                    connections
+                        .watch_finalized_blocks(finalized_authorization)
+                        .await
+                })?;
+
+            futures.push(flatten_handle(handle));
+
             let connections = Arc::clone(&self);
             let pending_tx_sender = pending_tx_sender.clone();
 
@@ -816,9 +1033,22 @@ impl Web3Rpcs {
         min_block_needed: Option<&U64>,
         max_block_needed: Option<&U64>,
     ) -> Web3ProxyResult<JsonRpcResponseData> {
-        let mut skip_rpcs = vec![];
+        // held until the request is done
+        let _permit = self.backend_pool.acquire(authorization).await?;
+
+        let mut skip_rpcs = self.rpcs_without_rollup_namespace(&request.method);
+        skip_rpcs.extend(self.rpcs_without_capabilities(&request.method, request_metadata));
+
+        // standby servers are only tried if the active failover group can't serve this request
+        let mut standby_rpcs = self.rpcs_on_standby();
+        standby_rpcs.retain(|x| !skip_rpcs.contains(x));
+        skip_rpcs.extend(standby_rpcs.iter().cloned());
+
         let mut method_not_available_response = None;
 
+        // set once the request's method is final
+        let max_bytes = request_metadata.and_then(|x| x.max_response_bytes.get().copied());
+
         let mut watch_consensus_rpcs = self.watch_consensus_rpcs_sender.subscribe();
 
         let start = Instant::now();
@@ -848,16 +1078,36 @@ impl Web3Rpcs {
                         request_metadata.backend_requests.lock().push(rpc.clone());
                     }
 
-                    let is_backup_response = rpc.backup;
+                    let hedge_delay = self
+                        .hedging
+                        .delay(&request.method, self.health.p99_latency_ms(&rpc));
 
                     // TODO: get the log percent from the user data
-                    let response_result: Result<Box<RawValue>, _> = active_request_handle
-                        .request(
-                            &request.method,
-                            &json!(request.params),
-                            RequestErrorHandler::Save,
-                        )
-                        .await;
+                    let (response_result, rpc) = match hedge_delay {
+                        Some(hedge_delay) => {
+                            self.hedged_request(
+                                authorization,
+                                request,
+                                request_metadata,
+                                active_request_handle,
+                                hedge_delay,
+                                &mut skip_rpcs,
+                                min_block_needed,
+                                max_block_needed,
+                                max_bytes,
+                            )
+                            .await
+                        }
+                        None => {
+                            let response_result = active_request_handle
+                                .request_capped(request, max_bytes, RequestErrorHandler::Save)
+                                .await;
+
+                            (response_result, rpc)
+                        }
+                    };
+
+                    let is_backup_response = rpc.backup;
 
                     match response_result {
                         Ok(response) => {
@@ -870,7 +1120,21 @@ impl Web3Rpcs {
 
                             return Ok(response.into());
                         }
-                        Err(error) => {
+                        Err(CappedRequestError::TooLarge(num_bytes)) => {
+                            // another server would send the same response. don't bother retrying
+                            if let Some(request_metadata) = request_metadata {
+                                request_metadata
+                                    .response_too_large
+                                    .store(true, Ordering::Release);
+                            }
+
+                            return Err(Web3ProxyError::ResponseTooLarge {
+                                method: request.method.clone(),
+                                num_bytes,
+                                max_bytes: max_bytes.unwrap_or_default(),
+                            });
+                        }
+                        Err(CappedRequestError::Provider(error)) => {
                             // trace!(?response, "rpc error");
 
                             // TODO: separate jsonrpc error and web3 proxy error!
@@ -992,7 +1256,14 @@ impl Web3Rpcs {
                     }
                 }
                 OpenRequestResult::NotReady => {
-                    break;
+                    if standby_rpcs.is_empty() {
+                        break;
+                    }
+
+                    debug!("failing over to {} standby servers", standby_rpcs.len());
+
+                    skip_rpcs.retain(|x| !standby_rpcs.contains(x));
+                    standby_rpcs.clear();
                 }
             }
         }
@@ -1054,6 +1325,231 @@ impl Web3Rpcs {
         .into())
     }
 
+    /// Wait up to `hedge_delay` for the first server. Then send the request to one more idle server too.
+    /// Whichever answers first is returned with the server that answered and the other request is cancelled
+    #[allow(clippy::too_many_arguments)]
+    async fn hedged_request(
+        &self,
+        authorization: &Arc<Authorization>,
+        request: &JsonRpcRequest,
+        request_metadata: Option<&Arc<RequestMetadata>>,
+        active_request_handle: OpenRequestHandle,
+        hedge_delay: Duration,
+        skip_rpcs: &mut Vec<Arc<Web3Rpc>>,
+        min_block_needed: Option<&U64>,
+        max_block_needed: Option<&U64>,
+        max_bytes: Option<u64>,
+    ) -> (Result<Box<RawValue>, CappedRequestError>, Arc<Web3Rpc>) {
+        let first_rpc = active_request_handle.clone_connection();
+        let first =
+            active_request_handle.request_capped(request, max_bytes, RequestErrorHandler::Save);
+        tokio::pin!(first);
+
+        select! {
+            x = &mut first => return (x, first_rpc),
+            _ = sleep(hedge_delay) => {}
+        }
+
+        // the hedge never waits. if no other server is free right now, keep waiting on the first
+        let potential_rpcs: Vec<_> = match self.watch_consensus_rpcs_sender.borrow().as_ref() {
+            Some(consensus_rpcs) => consensus_rpcs
+                .head_rpcs
+                .iter()
+                .filter(|rpc| {
+                    consensus_rpcs.rpc_will_work_now(
+                        skip_rpcs,
+                        min_block_needed,
+                        max_block_needed,
+                        rpc,
+                    )
+                })
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+
+        if potential_rpcs.is_empty() {
+            return (first.await, first_rpc);
+        }
+
+        let second_handle = match self
+            ._best_available_rpc(authorization, &potential_rpcs, skip_rpcs)
+            .await
+        {
+            OpenRequestResult::Handle(x) => x,
+            _ => return (first.await, first_rpc),
+        };
+
+        let second_rpc = second_handle.clone_connection();
+
+        trace!(
+            "hedging {} on {} after {:?} on {}",
+            request.method,
+            second_rpc,
+            hedge_delay,
+            first_rpc
+        );
+
+        if let Some(request_metadata) = request_metadata {
+            request_metadata
+                .backend_requests
+                .lock()
+                .push(second_rpc.clone());
+        }
+
+        let second = second_handle.request_capped(request, max_bytes, RequestErrorHandler::Save);
+        tokio::pin!(second);
+
+        // an error only wins if the other server errors too
+        let (response_result, hedge_won) = select! {
+            x = &mut first => match x {
+                Ok(x) => (Ok(x), false),
+                Err(err) => match second.await {
+                    Ok(y) => (Ok(y), true),
+                    Err(_) => (Err(err), false),
+                },
+            },
+            y = &mut second => match y {
+                Ok(y) => (Ok(y), true),
+                Err(_) => (first.await, false),
+            },
+        };
+
+        self.hedging.record(&request.method, hedge_won);
+
+        if hedge_won {
+            (response_result, second_rpc)
+        } else {
+            (response_result, first_rpc)
+        }
+    }
+
+    /// true if any of the servers can stream responses
+    pub fn can_stream(&self) -> bool {
+        self.by_name
+            .load()
+            .values()
+            .any(|x| x.http_streamer.is_some())
+    }
+
+    /// Like `try_send_best_connection`, but the response body is streamed to the client instead of buffered.
+    /// Servers without an http url are skipped. Only the time until the response headers arrive is retried on other servers
+    /// be sure there is a timeout on this or it might loop forever
+    pub async fn try_stream_best_connection(
+        &self,
+        authorization: &Arc<Authorization>,
+        request: &JsonRpcRequest,
+        request_metadata: &Arc<RequestMetadata>,
+        min_block_needed: Option<&U64>,
+        max_block_needed: Option<&U64>,
+        max_bytes: Option<u64>,
+    ) -> Web3ProxyResult<StreamingResponse> {
+        // held until the body is done
+        let permit = self.backend_pool.acquire(authorization).await?;
+
+        let mut skip_rpcs = self.rpcs_without_rollup_namespace(&request.method);
+
+        skip_rpcs.extend(self.rpcs_without_capabilities(&request.method, Some(request_metadata)));
+
+        skip_rpcs.extend(
+            self.by_name
+                .load()
+                .values()
+                .filter(|x| x.http_streamer.is_none())
+                .cloned(),
+        );
+
+        // standby servers are only tried if the active failover group can't serve this request
+        let mut standby_rpcs = self.rpcs_on_standby();
+        standby_rpcs.retain(|x| !skip_rpcs.contains(x));
+        skip_rpcs.extend(standby_rpcs.iter().cloned());
+
+        let mut watch_consensus_rpcs = self.watch_consensus_rpcs_sender.subscribe();
+
+        let start = Instant::now();
+
+        // TODO: get from config
+        let max_wait = Duration::from_secs(10);
+
+        while start.elapsed() < max_wait {
+            match self
+                .wait_for_best_rpc(
+                    authorization,
+                    Some(request_metadata),
+                    &mut skip_rpcs,
+                    min_block_needed,
+                    max_block_needed,
+                    None,
+                )
+                .await?
+            {
+                OpenRequestResult::Handle(active_request_handle) => {
+                    let rpc = active_request_handle.clone_connection();
+
+                    request_metadata.backend_requests.lock().push(rpc.clone());
+
+                    match active_request_handle
+                        .stream(request, Some(request_metadata.clone()), max_bytes)
+                        .await
+                    {
+                        Ok(mut response) => {
+                            request_metadata
+                                .response_from_backup_rpc
+                                .store(rpc.backup, Ordering::Release);
+
+                            response.hold(permit);
+
+                            return Ok(response);
+                        }
+                        Err(CappedRequestError::TooLarge(num_bytes)) => {
+                            request_metadata
+                                .response_too_large
+                                .store(true, Ordering::Release);
+
+                            return Err(Web3ProxyError::ResponseTooLarge {
+                                method: request.method.clone(),
+                                num_bytes,
+                                max_bytes: max_bytes.unwrap_or_default(),
+                            });
+                        }
+                        Err(CappedRequestError::Provider(err)) => {
+                            // nothing has been sent to the client yet. try another server
+                            debug!("{:?}. retrying on another server", err);
+                        }
+                    }
+                }
+                OpenRequestResult::RetryAt(retry_at) => {
+                    request_metadata.no_servers.fetch_add(1, Ordering::AcqRel);
+
+                    tokio::select! {
+                        _ = sleep_until(retry_at) => {
+                            skip_rpcs.pop();
+                        }
+                        _ = watch_consensus_rpcs.changed() => {
+                            watch_consensus_rpcs.borrow_and_update();
+                        }
+                    }
+                }
+                OpenRequestResult::NotReady => {
+                    if standby_rpcs.is_empty() {
+                        break;
+                    }
+
+                    debug!("failing over to {} standby servers", standby_rpcs.len());
+
+                    skip_rpcs.retain(|x| !standby_rpcs.contains(x));
+                    standby_rpcs.clear();
+                }
+            }
+        }
+
+        request_metadata
+            .error_response
+            .store(true, Ordering::Release);
+
+        Err(Web3ProxyError::NoServersSynced)
+    }
+
     /// be sure there is a timeout on this or it might loop forever
     #[allow(clippy::too_many_arguments)]
     pub async fn try_send_all_synced_connections(
@@ -1067,6 +1563,9 @@ impl Web3Rpcs {
         max_count: Option<usize>,
         always_include_backups: bool,
     ) -> Web3ProxyResult<JsonRpcResponseData> {
+        // held until the request is done
+        let _permit = self.backend_pool.acquire(authorization).await?;
+
         let mut watch_consensus_rpcs = self.watch_consensus_rpcs_sender.subscribe();
 
         let start = Instant::now();
@@ -1222,6 +1721,10 @@ impl Serialize for Web3Rpcs {
             }
         }
 
+        state.serialize_field("failover_group", &self.failover.active_group())?;
+
+        state.serialize_field("hedging", &self.hedging.stats())?;
+
         // self.blocks_by_hash.sync();
         // self.blocks_by_number.sync();
         // state.serialize_field("block_hashes_count", &self.blocks_by_hash.entry_count())?;
@@ -1440,11 +1943,13 @@ mod tests {
 
         // TODO: make a Web3Rpcs::new
         let rpcs = Web3Rpcs {
+            backend_pool: Default::default(),
             block_sender: block_sender.clone(),
             by_name: ArcSwap::from_pointee(rpcs_by_name),
             name: "test".to_string(),
             watch_consensus_head_sender: Some(watch_consensus_head_sender),
             watch_consensus_rpcs_sender,
+            head_events: Default::default(),
             pending_transaction_cache: CacheWithTTL::arc_with_capacity(
                 "pending_transaction_cache",
                 100,
@@ -1471,6 +1976,12 @@ mod tests {
             max_block_lag: None,
             min_head_rpcs: 1,
             min_sum_soft_limit: 1,
+            routing_rules: vec![],
+            shared_blocks: None,
+            failover: Default::default(),
+            head_quorum: Default::default(),
+            health: Default::default(),
+            hedging: Default::default(),
         };
 
         let authorization = Arc::new(Authorization::internal(None).unwrap());
@@ -1729,11 +2240,13 @@ mod tests {
         let (watch_consensus_head_sender, _watch_consensus_head_receiver) = watch::channel(None);
 
         let rpcs = Web3Rpcs {
+            backend_pool: Default::default(),
             block_sender,
             by_name: ArcSwap::from_pointee(rpcs_by_name),
             name: "test".to_string(),
             watch_consensus_head_sender: Some(watch_consensus_head_sender),
             watch_consensus_rpcs_sender,
+            head_events: Default::default(),
             pending_transaction_cache: CacheWithTTL::arc_with_capacity(
                 "pending_transaction_cache",
                 100,
@@ -1758,6 +2271,12 @@ mod tests {
             min_sum_soft_limit: 4_000,
             max_block_age: None,
             max_block_lag: None,
+            routing_rules: vec![],
+            shared_blocks: None,
+            failover: Default::default(),
+            head_quorum: Default::default(),
+            health: Default::default(),
+            hedging: Default::default(),
         };
 
         let authorization = Arc::new(Authorization::internal(None).unwrap());
@@ -1924,11 +2443,13 @@ mod tests {
 
         // TODO: make a Web3Rpcs::new
         let rpcs = Web3Rpcs {
+            backend_pool: Default::default(),
             block_sender,
             by_name: ArcSwap::from_pointee(rpcs_by_name),
             name: "test".to_string(),
             watch_consensus_head_sender: Some(watch_consensus_head_sender),
             watch_consensus_rpcs_sender,
+            head_events: Default::default(),
             pending_transaction_cache: CacheWithTTL::arc_with_capacity(
                 "pending_transaction_cache",
                 10_000,
@@ -1953,6 +2474,12 @@ mod tests {
             min_sum_soft_limit: 1_000,
             max_block_age: None,
             max_block_lag: None,
+            routing_rules: vec![],
+            shared_blocks: None,
+            failover: Default::default(),
+            head_quorum: Default::default(),
+            health: Default::default(),
+            hedging: Default::default(),
         };
 
         let authorization = Arc::new(Authorization::internal(None).unwrap());
@@ -1,8 +1,20 @@
 // TODO: all pub, or export useful things here instead?
+pub mod batch;
+pub mod beacon;
 pub mod blockchain;
 pub mod consensus;
+pub mod credentials;
+pub mod failover;
+pub mod head_events;
+pub mod head_quorum;
+pub mod health;
+pub mod hedging;
+pub mod ipfs;
 pub mod many;
 pub mod one;
 pub mod provider;
 pub mod request;
+pub mod rollup;
+pub mod routing;
+pub mod stream;
 pub mod transactions;
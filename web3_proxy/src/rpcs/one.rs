@@ -1,9 +1,13 @@
 ///! Rate-limited communication with a web3 provider.
+use super::batch::Web3RpcBatcher;
 use super::blockchain::{ArcBlock, BlocksByHashCache, Web3ProxyBlock};
+use super::credentials::{CredentialResult, Web3RpcCredentials};
+use super::health::LatencySamples;
 use super::provider::{connect_http, connect_ws, EthersHttpProvider, EthersWsProvider};
 use super::request::{OpenRequestHandle, OpenRequestResult};
-use crate::app::{flatten_handle, Web3ProxyJoinHandle};
-use crate::config::{BlockAndRpc, Web3RpcConfig};
+use super::stream::Web3RpcStreamer;
+use crate::app::{flatten_handle, Web3ProxyJoinHandle, HTTP_REQUEST_TIMEOUT};
+use crate::config::{BlockAndRpc, Web3RpcBatchConfig, Web3RpcConfig};
 use crate::frontend::authorization::Authorization;
 use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
 use crate::rpcs::request::RequestErrorHandler;
@@ -25,6 +29,7 @@ use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::sync::atomic::{self, AtomicU64, AtomicUsize};
 use std::{cmp::Ordering, sync::Arc};
+use thread_fast_rng::rand::Rng;
 use tokio::sync::watch;
 use tokio::time::{sleep, sleep_until, timeout, Duration, Instant};
 use url::Url;
@@ -39,6 +44,8 @@ pub struct Web3Rpc {
     pub(super) http_provider: Option<EthersHttpProvider>,
     /// the websocket provider is only used for subscriptions
     pub(super) ws_provider: Option<EthersWsProvider>,
+    /// multiple api keys for the same http provider. requests rotate between them
+    pub(super) http_credentials: Option<Web3RpcCredentials>,
     /// keep track of hard limits
     /// this is only inside an Option so that the "Default" derive works. it will always be set.
     pub(super) hard_limit_until: Option<watch::Sender<Instant>>,
@@ -63,15 +70,41 @@ pub struct Web3Rpc {
     /// Track peak request latency
     /// This is only inside an Option so that the "Default" derive works. it will always be set.
     pub(super) peak_latency: Option<PeakEwmaLatency>,
+    /// Latencies of successful requests since the last health check
+    pub(super) latency_samples: LatencySamples,
     /// Track total requests served
     /// TODO: maybe move this to graphana
     pub(super) total_requests: AtomicUsize,
+    /// Track requests that failed for a reason other than a revert. Rate limits are included
+    pub(super) total_errors: AtomicUsize,
     pub(super) active_requests: AtomicUsize,
     /// this is only inside an Option so that the "Default" derive works. it will always be set.
     pub(super) disconnect_watch: Option<watch::Sender<bool>>,
     pub(super) created_at: Option<Instant>,
+    /// how much traffic is held back because this server sent us rate limit errors. 0 is no throttling.
+    /// this is per mille instead of the allowed rate so that the Default derive gives an unthrottled server
+    pub(super) throttled_permille: AtomicU64,
+    /// how to combine requests into upstream batches. None sends every request on its own
+    pub(super) batching: Option<Web3RpcBatchConfig>,
+    /// combines http requests into upstream batches. only set if batching is configured
+    pub(super) http_batcher: Option<Web3RpcBatcher>,
+    /// sends raw requests so that large responses can be streamed to the client. only set for http backends
+    pub(super) http_streamer: Option<Web3RpcStreamer>,
+    /// json-rpc namespaces besides eth, net, and web3 that this server supports
+    pub(super) namespaces: Vec<String>,
+    /// what this server can do for `routing_rules` (like "archive" or "trace")
+    pub(super) capabilities: Vec<String>,
+    /// the `failover_groups` entry this server is in. None always gets traffic
+    pub(super) failover_group: Option<String>,
+    /// from `web3_clientVersion` when the provider was checked. None if the server didn't answer
+    pub(super) client_version: RwLock<Option<String>>,
 }
 
+/// the throttle never drops below this many requests per thousand
+const MIN_ALLOWED_PERMILLE: u64 = 10;
+/// how much to speed back up after every successful request
+const THROTTLE_INCREASE_PERMILLE: u64 = 10;
+
 impl Web3Rpc {
     /// Connect to a web3 rpc
     // TODO: have this take a builder (which will have channels attached). or maybe just take the config and give the config public fields
@@ -91,7 +124,7 @@ impl Web3Rpc {
     ) -> anyhow::Result<(Arc<Web3Rpc>, Web3ProxyJoinHandle<()>)> {
         let created_at = Instant::now();
 
-        let hard_limit = match (config.hard_limit, redis_pool) {
+        let hard_limit = match (config.hard_limit, redis_pool.clone()) {
             (None, None) => None,
             (Some(hard_limit), Some(redis_pool)) => {
                 // TODO: in process rate limiter instead? or is deffered good enough?
@@ -159,15 +192,53 @@ impl Web3Rpc {
             Duration::from_secs(1),
         );
 
-        let http_provider = if let Some(http_url) = config.http_url {
-            let http_url = http_url.parse::<Url>()?;
+        let (http_provider, http_credentials, http_batcher, http_streamer) =
+            if let Some(http_url) = config.http_url {
+                let http_url = http_url.parse::<Url>()?;
+
+                let http_batcher = config.batching.map(|batching| {
+                    Web3RpcBatcher::spawn(
+                        name.clone(),
+                        http_url.clone(),
+                        http_client.clone(),
+                        batching,
+                        HTTP_REQUEST_TIMEOUT,
+                    )
+                });
 
-            Some(connect_http(http_url, http_client, block_interval)?)
+                let http_credentials = if config.http_credentials.is_empty() {
+                    None
+                } else {
+                    Some(Web3RpcCredentials::try_new(
+                        chain_id,
+                        &name,
+                        http_url.clone(),
+                        config.http_credentials,
+                        config.credential_rotation,
+                        http_client.clone(),
+                        redis_pool.clone(),
+                    )?)
+                };
 
-            // TODO: check the provider is on the right chain
-        } else {
-            None
-        };
+                // streaming always uses the main http_url. http_credentials are not rotated
+                let http_streamer = Web3RpcStreamer::new(http_url.clone(), http_client.clone());
+
+                let http_provider = connect_http(http_url, http_client, block_interval)?;
+
+                // TODO: check the provider is on the right chain
+                (
+                    Some(http_provider),
+                    http_credentials,
+                    http_batcher,
+                    Some(http_streamer),
+                )
+            } else if !config.http_credentials.is_empty() {
+                return Err(anyhow!("http_credentials require http_url"));
+            } else if config.batching.is_some() {
+                return Err(anyhow!("batching requires http_url"));
+            } else {
+                (None, None, None, None)
+            };
 
         let ws_provider = if let Some(ws_url) = config.ws_url {
             let ws_url = ws_url.parse::<Url>()?;
@@ -184,15 +255,22 @@ impl Web3Rpc {
         let new_rpc = Self {
             automatic_block_limit,
             backup,
+            batching: config.batching,
             block_data_limit,
+            capabilities: config.capabilities,
             created_at: Some(created_at),
             db_conn: db_conn.clone(),
             display_name: config.display_name,
+            failover_group: config.failover_group,
             hard_limit,
             hard_limit_until: Some(hard_limit_until),
             head_block: Some(head_block),
+            http_batcher,
+            http_credentials,
             http_provider,
+            http_streamer,
             name,
+            namespaces: config.namespaces,
             peak_latency: Some(peak_latency),
             soft_limit: config.soft_limit,
             tier: config.tier,
@@ -225,6 +303,42 @@ impl Web3Rpc {
         Ok((new_connection, handle))
     }
 
+    /// The fraction of requests that this server is currently allowed. 1.0 is unthrottled.
+    pub fn throttle_factor(&self) -> f64 {
+        let throttled = self.throttled_permille.load(atomic::Ordering::Relaxed);
+
+        (1000 - throttled) as f64 / 1000.0
+    }
+
+    /// Additive increase. A request succeeded so allow a little more traffic
+    pub(super) fn throttle_increase(&self) {
+        // the common case is no throttling. skip the write
+        if self.throttled_permille.load(atomic::Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        let _ = self.throttled_permille.fetch_update(
+            atomic::Ordering::AcqRel,
+            atomic::Ordering::Acquire,
+            |x| Some(x.saturating_sub(THROTTLE_INCREASE_PERMILLE)),
+        );
+    }
+
+    /// Multiplicative decrease. The server rate limited us so halve the traffic we send it
+    pub(super) fn throttle_decrease(&self) {
+        let _ = self.throttled_permille.fetch_update(
+            atomic::Ordering::AcqRel,
+            atomic::Ordering::Acquire,
+            |x| {
+                let allowed = ((1000 - x) / 2).max(MIN_ALLOWED_PERMILLE);
+
+                Some(1000 - allowed)
+            },
+        );
+
+        debug!("{} throttle factor is now {}", self, self.throttle_factor());
+    }
+
     pub fn peak_ewma(&self) -> OrderedFloat<f64> {
         let peak_latency = if let Some(peak_latency) = self.peak_latency.as_ref() {
             peak_latency.latency().as_secs_f64()
@@ -329,6 +443,69 @@ impl Web3Rpc {
         self.block_data_limit.load(atomic::Ordering::Acquire).into()
     }
 
+    /// True if this rpc has every block. Known after the block data limit is checked
+    pub fn is_archive(&self) -> bool {
+        self.block_data_limit.load(atomic::Ordering::Acquire) == u64::MAX
+    }
+
+    pub fn namespaces(&self) -> &[String] {
+        &self.namespaces
+    }
+
+    /// True if the config lists this capability. Servers that have every block are always "archive"
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|x| x == capability)
+            || (capability == "archive" && self.is_archive())
+    }
+
+    /// The number of the newest block this rpc has told us about
+    pub fn head_block_num(&self) -> Option<U64> {
+        self.head_block
+            .as_ref()
+            .and_then(|x| x.borrow().as_ref().map(|x| *x.number()))
+    }
+
+    /// True if this rpc has no head block or its head block is older than `max_head_age` seconds
+    pub fn is_syncing(&self, max_head_age: u64) -> bool {
+        self.head_block
+            .as_ref()
+            .and_then(|x| x.borrow().as_ref().map(|x| x.age() > max_head_age))
+            .unwrap_or(true)
+    }
+
+    /// What the server said it is running. Like "Geth/v1.12.0-stable/linux-amd64/go1.20.5"
+    pub fn client_version(&self) -> Option<String> {
+        self.client_version.read().clone()
+    }
+
+    /// The fraction of requests since startup that failed for a reason other than a revert
+    pub fn error_rate(&self) -> f64 {
+        let total_requests = self.total_requests.load(atomic::Ordering::Acquire);
+
+        if total_requests == 0 {
+            return 0.0;
+        }
+
+        self.total_errors.load(atomic::Ordering::Acquire) as f64 / total_requests as f64
+    }
+
+    /// A small summary of this server's health for status snapshots. Unlike `Serialize`, this doesn't include config
+    pub fn health_snapshot(&self) -> serde_json::Value {
+        json!({
+            "name": self.name,
+            "display_name": self.display_name,
+            "backup": self.backup,
+            "tier": self.tier,
+            "head_block_num": self.head_block_num(),
+            "total_requests": self.total_requests.load(atomic::Ordering::Acquire),
+            "total_errors": self.total_errors.load(atomic::Ordering::Acquire),
+            "error_rate": self.error_rate(),
+            "active_requests": self.active_requests.load(atomic::Ordering::Relaxed),
+            "head_latency_ms": self.head_latency.read().value(),
+            "throttle_factor": self.throttle_factor(),
+        })
+    }
+
     /// TODO: get rid of this now that consensus rpcs does it
     pub fn has_block_data(&self, needed_block_num: &U64) -> bool {
         let head_block_num = match self.head_block.as_ref().unwrap().borrow().as_ref() {
@@ -403,6 +580,20 @@ impl Web3Rpc {
             }
         }
 
+        // only used for the proxy's own web3_clientVersion. a server that doesn't answer still works
+        match self
+            .request::<_, String>(
+                "web3_clientVersion",
+                &json!(Vec::<()>::new()),
+                Level::Trace.into(),
+                authorization.clone(),
+            )
+            .await
+        {
+            Ok(x) => *self.client_version.write() = Some(x),
+            Err(err) => debug!("unable to get web3_clientVersion from {}: {:?}", self, err),
+        }
+
         self.check_block_data_limit(&authorization)
             .await
             .context(format!("unable to check_block_data_limit of {}", self))?;
@@ -882,7 +1073,36 @@ impl Web3Rpc {
             }
         };
 
-        let handle = OpenRequestHandle::new(authorization.clone(), self.clone()).await;
+        // if the server has been rate limiting us, skip some requests so they go to other servers
+        // internal requests are never skipped. user traffic is shed first so that chain watchers keep working
+        let throttled = self.throttled_permille.load(atomic::Ordering::Relaxed);
+        if throttled > 0
+            && !authorization.is_internal()
+            && thread_fast_rng::thread_fast_rng().gen_range(0..1000) < throttled
+        {
+            trace!("throttled request to {}", self);
+            // TODO: how long should this be?
+            return Ok(OpenRequestResult::RetryAt(
+                Instant::now() + Duration::from_millis(100),
+            ));
+        }
+
+        // if there are multiple api keys, pick one that is under its quota
+        let credential = if let Some(http_credentials) = self.http_credentials.as_ref() {
+            match http_credentials.select(&self.name).await? {
+                CredentialResult::Ready(i) => Some(i),
+                CredentialResult::RetryAt(retry_at) => {
+                    trace!("all credentials on {} are rate limited", self);
+                    return Ok(OpenRequestResult::RetryAt(retry_at));
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut handle = OpenRequestHandle::new(authorization.clone(), self.clone()).await;
+
+        handle.credential = credential;
 
         Ok(OpenRequestResult::Handle(handle))
     }
@@ -967,7 +1187,7 @@ impl Serialize for Web3Rpc {
         S: Serializer,
     {
         // 3 is the number of fields in the struct.
-        let mut state = serializer.serialize_struct("Web3Rpc", 12)?;
+        let mut state = serializer.serialize_struct("Web3Rpc", 18)?;
 
         // the url is excluded because it likely includes private information. just show the name that we use in keys
         state.serialize_field("name", &self.name)?;
@@ -1002,6 +1222,11 @@ impl Serialize for Web3Rpc {
             &self.total_requests.load(atomic::Ordering::Acquire),
         )?;
 
+        state.serialize_field(
+            "total_errors",
+            &self.total_errors.load(atomic::Ordering::Acquire),
+        )?;
+
         state.serialize_field(
             "active_requests",
             &self.active_requests.load(atomic::Ordering::Relaxed),
@@ -1016,6 +1241,16 @@ impl Serialize for Web3Rpc {
 
         state.serialize_field("peak_ewma_s", self.peak_ewma().as_ref())?;
 
+        state.serialize_field("credentials", &self.http_credentials)?;
+
+        state.serialize_field("throttle_factor", &self.throttle_factor())?;
+
+        state.serialize_field("batching", &self.batching)?;
+
+        state.serialize_field("capabilities", &self.capabilities)?;
+
+        state.serialize_field("failover_group", &self.failover_group)?;
+
         state.end()
     }
 }
@@ -1049,6 +1284,37 @@ mod tests {
     use super::*;
     use ethers::types::{Block, H256, U256};
 
+    #[test]
+    fn test_throttle_aimd() {
+        let x = Web3Rpc {
+            name: "name".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(x.throttle_factor(), 1.0);
+
+        x.throttle_decrease();
+        assert_eq!(x.throttle_factor(), 0.5);
+
+        x.throttle_decrease();
+        assert_eq!(x.throttle_factor(), 0.25);
+
+        x.throttle_increase();
+        assert_eq!(x.throttle_factor(), 0.26);
+
+        // never throttle down to nothing
+        for _ in 0..20 {
+            x.throttle_decrease();
+        }
+        assert_eq!(x.throttle_factor(), 0.01);
+
+        // never go above full speed
+        for _ in 0..200 {
+            x.throttle_increase();
+        }
+        assert_eq!(x.throttle_factor(), 1.0);
+    }
+
     #[test]
     fn test_archive_node_has_block_data() {
         let now = chrono::Utc::now().timestamp().into();
@@ -1,15 +1,19 @@
 use super::one::Web3Rpc;
-use crate::frontend::authorization::Authorization;
+use super::stream::{StreamingResponse, Web3RpcStreamer};
+use crate::frontend::authorization::{Authorization, RequestMetadata};
 use crate::frontend::errors::Web3ProxyResult;
+use crate::jsonrpc::JsonRpcRequest;
 use anyhow::Context;
 use chrono::Utc;
 use entities::revert_log;
 use entities::sea_orm_active_enums::Method;
-use ethers::providers::ProviderError;
+use ethers::providers::{HttpClientError, JsonRpcError, ProviderError};
 use ethers::types::{Address, Bytes};
+use futures::StreamExt;
 use log::{debug, error, trace, warn, Level};
 use migration::sea_orm::{self, ActiveEnum, ActiveModelTrait};
 use serde_json::json;
+use serde_json::value::RawValue;
 use std::fmt;
 use std::sync::atomic;
 use std::sync::Arc;
@@ -31,6 +35,162 @@ pub enum OpenRequestResult {
 pub struct OpenRequestHandle {
     authorization: Arc<Authorization>,
     rpc: Arc<Web3Rpc>,
+    /// which of the rpc's http_credentials to use. None uses the rpc's own http_url
+    pub(super) credential: Option<usize>,
+}
+
+/// Why a request with a response size limit failed
+#[derive(Debug)]
+pub enum CappedRequestError {
+    Provider(ProviderError),
+    /// The backend's body is at least this many bytes. Nothing more of it was read
+    TooLarge(u64),
+}
+
+impl From<ProviderError> for CappedRequestError {
+    fn from(err: ProviderError) -> Self {
+        Self::Provider(err)
+    }
+}
+
+/// A jsonrpc response body with the result left unparsed
+#[derive(serde::Deserialize)]
+struct RawJsonRpcResponse {
+    #[serde(default)]
+    result: Option<Box<RawValue>>,
+    #[serde(default)]
+    error: Option<JsonRpcError>,
+}
+
+/// Read a response body, but stop as soon as it is larger than `max_bytes`
+pub async fn read_capped_body(
+    response: reqwest::Response,
+    max_bytes: u64,
+) -> Result<Vec<u8>, CappedRequestError> {
+    if let Some(content_length) = response.content_length() {
+        if content_length > max_bytes {
+            return Err(CappedRequestError::TooLarge(content_length));
+        }
+    }
+
+    let mut body = Vec::with_capacity(response.content_length().unwrap_or_default() as usize);
+
+    let mut chunks = response.bytes_stream();
+
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(ProviderError::HTTPError)?;
+
+        let num_bytes = (body.len() + chunk.len()) as u64;
+
+        if num_bytes > max_bytes {
+            return Err(CappedRequestError::TooLarge(num_bytes));
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// Parse a jsonrpc response body the same way the ethers http provider does
+pub fn parse_raw_response(body: &[u8]) -> Result<Box<RawValue>, ProviderError> {
+    let response: RawJsonRpcResponse =
+        serde_json::from_slice(body).map_err(|err| HttpClientError::SerdeJson {
+            err,
+            text: String::from_utf8_lossy(body).to_string(),
+        })?;
+
+    if let Some(err) = response.error {
+        return Err(HttpClientError::JsonRpcError(err).into());
+    }
+
+    // a missing result is the same as a null result
+    Ok(response
+        .result
+        .unwrap_or_else(|| RawValue::from_string("null".to_string()).expect("null is valid json")))
+}
+
+/// Send a request to an http backend and parse the body.
+/// With a `max_bytes`, the read stops as soon as the body is too large
+async fn send_http(
+    streamer: &Web3RpcStreamer,
+    request: &JsonRpcRequest,
+    max_bytes: Option<u64>,
+) -> Result<Box<RawValue>, CappedRequestError> {
+    // non-2xx statuses are errors here. ProviderError::HTTPError keeps the status for `is_rate_limited`
+    let response = streamer
+        .request(request)
+        .await
+        .map_err(ProviderError::HTTPError)?;
+
+    let body = read_capped_body(response, max_bytes.unwrap_or(u64::MAX)).await?;
+
+    parse_raw_response(&body).map_err(Into::into)
+}
+
+/// Send a request that the proxy makes itself (not one from a client) to an http backend
+async fn send_internal<P, R>(
+    streamer: &Web3RpcStreamer,
+    method: &str,
+    params: &P,
+) -> Result<R, ProviderError>
+where
+    P: serde::Serialize,
+    R: serde::de::DeserializeOwned,
+{
+    // methods without params get an empty list instead of null. some servers reject null
+    let params = match serde_json::to_value(params)? {
+        serde_json::Value::Null => json!([]),
+        x => x,
+    };
+
+    let request = JsonRpcRequest::new(streamer.next_id().into(), method.to_string(), Some(params))
+        .map_err(|err| ProviderError::CustomError(err.to_string()))?;
+
+    let result = match send_http(streamer, &request, None).await {
+        Ok(x) => x,
+        Err(CappedRequestError::Provider(err)) => return Err(err),
+        Err(CappedRequestError::TooLarge(_)) => unreachable!("no limit was set"),
+    };
+
+    serde_json::from_str(result.get()).map_err(|err| {
+        HttpClientError::SerdeJson {
+            err,
+            text: result.get().to_string(),
+        }
+        .into()
+    })
+}
+
+/// Error codes that providers use for rate limits. -32005 is "limit exceeded" in EIP-1474. 429 is the http status that some providers copy
+const RATE_LIMIT_CODES: [i64; 2] = [-32005, 429];
+
+/// True if the backend is telling us to slow down.
+/// An http 429 is checked by its status. JSON-RPC errors are checked by their code and then by their message
+pub fn is_rate_limited(err: &ProviderError) -> bool {
+    match err {
+        ProviderError::HTTPError(err) => {
+            err.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS)
+        }
+        ProviderError::JsonRpcClientError(err) => match err.as_error_response() {
+            Some(err) => {
+                if RATE_LIMIT_CODES.contains(&err.code) {
+                    return true;
+                }
+
+                // different providers use different codes for this. some only say so in the message
+                let msg = err.message.to_lowercase();
+
+                !msg.starts_with("execution reverted")
+                    && (msg.contains("rate limit")
+                        || msg.contains("too many requests")
+                        || msg.contains("limit exceeded")
+                        || msg.contains("exceeded the quota"))
+            }
+            None => false,
+        },
+        _ => false,
+    }
 }
 
 /// Depending on the context, RPC errors require different handling.
@@ -137,18 +297,191 @@ impl OpenRequestHandle {
         rpc.active_requests
             .fetch_add(1, std::sync::atomic::Ordering::AcqRel);
 
-        Self { authorization, rpc }
+        Self {
+            authorization,
+            rpc,
+            credential: None,
+        }
     }
 
     pub fn connection_name(&self) -> String {
         self.rpc.name.clone()
     }
 
+    /// The http client for this handle's api key, or else the rpc's own.
+    /// None if requests should go through the batcher or the websocket instead
+    fn http_streamer(&self) -> Option<&Web3RpcStreamer> {
+        let credential = self
+            .credential
+            .and_then(|i| self.rpc.http_credentials.as_ref().and_then(|x| x.get(i)));
+
+        match credential {
+            Some(credential) => Some(&credential.streamer),
+            None if self.rpc.http_batcher.is_some() => None,
+            None => self.rpc.http_streamer.as_ref(),
+        }
+    }
+
+    /// The backend rate limited us. Send it less traffic whichever api key was used
+    fn rate_limited(&self) {
+        // multiplicative decrease. requests that succeed slowly raise this again
+        self.rpc.throttle_decrease();
+
+        if let (Some(i), Some(http_credentials)) =
+            (self.credential, self.rpc.http_credentials.as_ref())
+        {
+            // only this api key is limited. the other keys can keep serving requests
+            trace!("rate limit on {} credential {}", self.rpc, i);
+
+            http_credentials.rate_limited(i);
+        } else if let Some(hard_limit_until) = self.rpc.hard_limit_until.as_ref() {
+            // TODO: how long should we actually wait? different providers have different times
+            // TODO: if rate_limit_period_seconds is set, use that
+            // TODO: check response headers for rate limits too
+            if self.rpc.backup {
+                debug!("unexpected rate limit on {}!", self.rpc);
+            } else {
+                warn!("unexpected rate limit on {}!", self.rpc);
+            }
+
+            let retry_at = Instant::now() + Duration::from_secs(1);
+
+            trace!("retry {} at: {:?}", self.rpc, retry_at);
+
+            hard_limit_until.send_replace(retry_at);
+        }
+    }
+
     #[inline]
     pub fn clone_connection(&self) -> Arc<Web3Rpc> {
         self.rpc.clone()
     }
 
+    /// Send a request to an http backend without reading the response body.
+    /// The handle moves into the response and is dropped once the body is done.
+    /// Unlike `request`, errors inside a successful http response are passed through to the client as is
+    pub async fn stream(
+        self,
+        request: &JsonRpcRequest,
+        request_metadata: Option<Arc<RequestMetadata>>,
+        max_bytes: Option<u64>,
+    ) -> Result<StreamingResponse, CappedRequestError> {
+        let streamer = self.rpc.http_streamer.as_ref().ok_or_else(|| {
+            ProviderError::CustomError("streaming needs an http provider".to_string())
+        })?;
+
+        trace!("streaming from {}", self.rpc);
+
+        self.rpc
+            .total_requests
+            .fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+
+        match streamer.request(request).await {
+            Ok(response) => {
+                // once the status and headers are sent, a body that is too large can only be cut off. check now while we can still send an error
+                if let (Some(content_length), Some(max_bytes)) =
+                    (response.content_length(), max_bytes)
+                {
+                    if content_length > max_bytes {
+                        return Err(CappedRequestError::TooLarge(content_length));
+                    }
+                }
+
+                Ok(StreamingResponse::new(
+                    response,
+                    self,
+                    request_metadata,
+                    max_bytes,
+                ))
+            }
+            Err(err) => {
+                self.rpc
+                    .total_errors
+                    .fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+
+                if err.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS) {
+                    if let Some(hard_limit_until) = self.rpc.hard_limit_until.as_ref() {
+                        // send less traffic here until the backend stops complaining
+                        self.rpc.throttle_decrease();
+
+                        if self.rpc.backup {
+                            debug!("unexpected rate limit on {}!", self.rpc);
+                        } else {
+                            warn!("unexpected rate limit on {}!", self.rpc);
+                        }
+
+                        hard_limit_until.send_replace(Instant::now() + Duration::from_secs(1));
+                    }
+                }
+
+                Err(
+                    ProviderError::CustomError(format!("stream from {} failed: {}", self.rpc, err))
+                        .into(),
+                )
+            }
+        }
+    }
+
+    /// Send a client's request and return the result without parsing it.
+    /// With a `max_bytes`, http backends are read a chunk at a time and the read stops as soon as the body is too large.
+    /// Backends that can't be read that way (websockets and batchers) use `request` and the caller has to check the size after
+    pub async fn request_capped(
+        self,
+        request: &JsonRpcRequest,
+        max_bytes: Option<u64>,
+        error_handler: RequestErrorHandler,
+    ) -> Result<Box<RawValue>, CappedRequestError> {
+        let (max_bytes, streamer) = match (max_bytes, self.http_streamer()) {
+            (Some(max_bytes), Some(streamer)) => (max_bytes, streamer),
+            _ => {
+                return self
+                    .request(&request.method, &json!(request.params), error_handler)
+                    .await
+                    .map_err(Into::into)
+            }
+        };
+
+        trace!(
+            "requesting from {} with a {} byte limit",
+            self.rpc,
+            max_bytes
+        );
+
+        self.rpc
+            .total_requests
+            .fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+
+        let start = Instant::now();
+
+        let response = match send_http(streamer, request, Some(max_bytes)).await {
+            Ok(x) => Ok(x),
+            Err(CappedRequestError::TooLarge(num_bytes)) => {
+                // the backend did nothing wrong. the client asked for too much
+                trace!(
+                    "response from {} for {} is at least {} bytes",
+                    self.rpc,
+                    request.method,
+                    num_bytes
+                );
+
+                return Err(CappedRequestError::TooLarge(num_bytes));
+            }
+            Err(CappedRequestError::Provider(err)) => Err(err),
+        };
+
+        let latency = start.elapsed();
+
+        self.on_response(
+            &request.method,
+            &request.params,
+            &response,
+            latency,
+            error_handler,
+        );
+
+        response.map_err(Into::into)
+    }
+
     /// Send a web3 request
     /// By having the request method here, we ensure that the rate limiter was called and connection counts were properly incremented
     /// depending on how things are locked, you might need to pass the provider in
@@ -157,7 +490,7 @@ impl OpenRequestHandle {
         self,
         method: &str,
         params: &P,
-        mut error_handler: RequestErrorHandler,
+        error_handler: RequestErrorHandler,
     ) -> Result<R, ProviderError>
     where
         // TODO: not sure about this type. would be better to not need clones, but measure and spawns combine to need it
@@ -177,10 +510,11 @@ impl OpenRequestHandle {
 
         let start = Instant::now();
 
-        // TODO: replace ethers-rs providers with our own that supports streaming the responses
-        // TODO: replace ethers-rs providers with our own that handles "id" being null
-        let response: Result<R, _> = if let Some(ref p) = self.rpc.http_provider {
-            p.request(method, params).await
+        // TODO: replace the ethers-rs websocket provider with our own that handles "id" being null
+        let response: Result<R, _> = if let Some(streamer) = self.http_streamer() {
+            send_internal(streamer, method, params).await
+        } else if let Some(ref batcher) = self.rpc.http_batcher {
+            batcher.request(method, params).await
         } else if let Some(ref p) = self.rpc.ws_provider {
             p.request(method, params).await
         } else {
@@ -202,7 +536,24 @@ impl OpenRequestHandle {
             response,
         );
 
-        if let Err(err) = &response {
+        self.on_response(method, params, &response, latency, error_handler);
+
+        response
+    }
+
+    /// Count errors, back off rate limited backends, and maybe save reverts.
+    /// Successful responses record their latency
+    fn on_response<P, R>(
+        &self,
+        method: &str,
+        params: &P,
+        response: &Result<R, ProviderError>,
+        latency: Duration,
+        mut error_handler: RequestErrorHandler,
+    ) where
+        P: fmt::Debug + serde::Serialize,
+    {
+        if let Err(err) = response {
             // only save reverts for some types of calls
             // TODO: do something special for eth_sendRawTransaction too
             error_handler = if let RequestErrorHandler::Save = error_handler {
@@ -248,27 +599,23 @@ impl OpenRequestHandle {
 
             // check for "execution reverted" here
             // TODO: move this info a function on ResponseErrorType
-            let response_type = if let ProviderError::JsonRpcClientError(err) = err {
+            let response_type = if is_rate_limited(err) {
+                // TODO: too verbose
+                if self.rpc.backup {
+                    trace!("rate limit from {}", self.rpc);
+                } else {
+                    warn!("rate limit from {}", self.rpc);
+                }
+                ResponseTypes::RateLimit
+            } else if let ProviderError::JsonRpcClientError(err) = err {
                 // Http and Ws errors are very similar, but different types
                 let msg = err.as_error_response().map(|x| x.message.clone());
 
                 trace!("error message: {:?}", msg);
 
-                if let Some(msg) = msg {
-                    if msg.starts_with("execution reverted") {
-                        trace!("revert from {}", self.rpc);
-                        ResponseTypes::Revert
-                    } else if msg.contains("limit") || msg.contains("request") {
-                        // TODO: too verbose
-                        if self.rpc.backup {
-                            trace!("rate limit from {}", self.rpc);
-                        } else {
-                            warn!("rate limit from {}", self.rpc);
-                        }
-                        ResponseTypes::RateLimit
-                    } else {
-                        ResponseTypes::Error
-                    }
+                if msg.map_or(false, |x| x.starts_with("execution reverted")) {
+                    trace!("revert from {}", self.rpc);
+                    ResponseTypes::Revert
                 } else {
                     ResponseTypes::Error
                 }
@@ -276,24 +623,14 @@ impl OpenRequestHandle {
                 ResponseTypes::Error
             };
 
-            if matches!(response_type, ResponseTypes::RateLimit) {
-                if let Some(hard_limit_until) = self.rpc.hard_limit_until.as_ref() {
-                    // TODO: how long should we actually wait? different providers have different times
-                    // TODO: if rate_limit_period_seconds is set, use that
-                    // TODO: check response headers for rate limits too
-                    // TODO: warn if production, debug if backup
-                    if self.rpc.backup {
-                        debug!("unexpected rate limit on {}!", self.rpc);
-                    } else {
-                        warn!("unexpected rate limit on {}!", self.rpc);
-                    }
-
-                    let retry_at = Instant::now() + Duration::from_secs(1);
-
-                    trace!("retry {} at: {:?}", self.rpc, retry_at);
+            if !matches!(response_type, ResponseTypes::Revert) {
+                self.rpc
+                    .total_errors
+                    .fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+            }
 
-                    hard_limit_until.send_replace(retry_at);
-                }
+            if matches!(response_type, ResponseTypes::RateLimit) {
+                self.rate_limited();
             }
 
             // TODO: think more about the method and param logs. those can be sensitive information
@@ -360,10 +697,221 @@ impl OpenRequestHandle {
             }
         } else if let Some(peak_latency) = &self.rpc.peak_latency {
             peak_latency.report(latency);
+            self.rpc.latency_samples.record(latency);
+
+            self.rpc.throttle_increase();
         } else {
             unreachable!("peak_latency not initialized");
         }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CredentialRotation, Web3RpcCredentialConfig};
+    use crate::rpcs::credentials::{CredentialResult, Web3RpcCredentials};
+    use axum::routing::post;
+    use futures::stream;
+    use hyper::body::Bytes;
+    use reqwest::StatusCode;
+    use url::Url;
+
+    /// a backend that rate limits every request
+    fn rate_limited_backend() -> Url {
+        let app = axum::Router::new().route("/", post(|| async { StatusCode::TOO_MANY_REQUESTS }));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
 
-        response
+        format!("http://{}", addr).parse().unwrap()
+    }
+
+    fn json_rpc_error(code: i64, message: &str) -> ProviderError {
+        HttpClientError::JsonRpcError(JsonRpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        })
+        .into()
+    }
+
+    #[test]
+    fn test_is_rate_limited() {
+        let too_many = reqwest::Response::from(
+            http::Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .body("")
+                .unwrap(),
+        )
+        .error_for_status()
+        .unwrap_err();
+        assert!(is_rate_limited(&ProviderError::HTTPError(too_many)));
+
+        let bad_gateway = reqwest::Response::from(
+            http::Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body("")
+                .unwrap(),
+        )
+        .error_for_status()
+        .unwrap_err();
+        assert!(!is_rate_limited(&ProviderError::HTTPError(bad_gateway)));
+
+        // codes
+        assert!(is_rate_limited(&json_rpc_error(429, "whatever")));
+        assert!(is_rate_limited(&json_rpc_error(-32005, "whatever")));
+
+        // messages from providers that don't use a special code
+        assert!(is_rate_limited(&json_rpc_error(
+            -32000,
+            "Your app has exceeded its compute units per second capacity. Too many requests"
+        )));
+        assert!(is_rate_limited(&json_rpc_error(
+            -32000,
+            "daily request count exceeded, request rate limited"
+        )));
+
+        // these mention limits and requests, but the backend isn't asking us to slow down
+        assert!(!is_rate_limited(&json_rpc_error(
+            -32000,
+            "query exceeds max block range limit"
+        )));
+        assert!(!is_rate_limited(&json_rpc_error(-32600, "invalid request")));
+        assert!(!is_rate_limited(&json_rpc_error(
+            3,
+            "execution reverted: too many requests"
+        )));
+
+        assert!(!is_rate_limited(&ProviderError::CustomError(
+            "429".to_string()
+        )));
+    }
+
+    /// a rate limit on one api key has to slow the whole backend down too
+    #[tokio::test]
+    async fn test_credential_rate_limit_decreases_throttle() {
+        let url = rate_limited_backend();
+
+        let http_credentials = Web3RpcCredentials::try_new(
+            1,
+            "test",
+            url.clone(),
+            vec![Web3RpcCredentialConfig {
+                http_url: url.to_string(),
+                hard_limit: None,
+            }],
+            CredentialRotation::OnRateLimit,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let rpc = Arc::new(Web3Rpc {
+            name: "test".to_string(),
+            http_credentials: Some(http_credentials),
+            http_streamer: Some(Web3RpcStreamer::new(url, None)),
+            ..Default::default()
+        });
+
+        let authorization = Arc::new(Authorization::internal(None).unwrap());
+
+        let mut handle = OpenRequestHandle::new(authorization, rpc.clone()).await;
+        handle.credential = Some(0);
+
+        let err = handle
+            .request::<_, Box<RawValue>>("eth_blockNumber", &(), RequestErrorHandler::TraceLevel)
+            .await
+            .unwrap_err();
+
+        assert!(is_rate_limited(&err));
+        assert_eq!(rpc.throttle_factor(), 0.5);
+        assert_eq!(rpc.total_errors.load(atomic::Ordering::Acquire), 1);
+
+        // the limited key is skipped
+        match rpc
+            .http_credentials
+            .as_ref()
+            .unwrap()
+            .select("test")
+            .await
+            .unwrap()
+        {
+            CredentialResult::Ready(i) => assert_eq!(i, 1),
+            CredentialResult::RetryAt(_) => panic!("the second key is not limited"),
+        }
+    }
+
+    fn sized_response(body: &'static str) -> reqwest::Response {
+        http::Response::new(body).into()
+    }
+
+    /// no content-length. the size is only known by reading it
+    fn chunked_response(chunks: Vec<&'static str>) -> reqwest::Response {
+        let chunks = chunks
+            .into_iter()
+            .map(|x| Ok::<_, std::io::Error>(Bytes::from_static(x.as_bytes())));
+
+        http::Response::new(reqwest::Body::wrap_stream(stream::iter(chunks))).into()
+    }
+
+    #[tokio::test]
+    async fn test_read_capped_body() {
+        let body = r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#;
+
+        assert_eq!(
+            read_capped_body(sized_response(body), 1_000).await.unwrap(),
+            body.as_bytes()
+        );
+
+        // content-length is checked before reading anything
+        assert!(matches!(
+            read_capped_body(sized_response(body), 10).await,
+            Err(CappedRequestError::TooLarge(x)) if x == body.len() as u64
+        ));
+
+        assert_eq!(
+            read_capped_body(chunked_response(vec!["ab", "cd"]), 4)
+                .await
+                .unwrap(),
+            b"abcd"
+        );
+
+        // the read stops at the chunk that goes over
+        assert!(matches!(
+            read_capped_body(chunked_response(vec!["ab", "cd", "ef"]), 3).await,
+            Err(CappedRequestError::TooLarge(4))
+        ));
+    }
+
+    #[test]
+    fn test_parse_raw_response() {
+        let x = parse_raw_response(br#"{"jsonrpc":"2.0","id":1,"result":{"a":[1,2]}}"#).unwrap();
+        assert_eq!(x.get(), r#"{"a":[1,2]}"#);
+
+        let x = parse_raw_response(br#"{"jsonrpc":"2.0","id":1,"result":null}"#).unwrap();
+        assert_eq!(x.get(), "null");
+
+        let err = parse_raw_response(
+            br#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"execution reverted"}}"#,
+        )
+        .unwrap_err();
+
+        match err {
+            ProviderError::JsonRpcClientError(err) => {
+                let err = err.as_error_response().unwrap();
+
+                assert_eq!(err.code, -32000);
+                assert_eq!(err.message, "execution reverted");
+            }
+            err => panic!("{:?}", err),
+        }
+
+        assert!(parse_raw_response(b"<html>bad gateway</html>").is_err());
     }
 }
@@ -0,0 +1,78 @@
+//! Namespaces and helpers that only exist on L2 rollups.
+//! Backends list the rollup namespaces they serve in their `namespaces` config. Those methods are only sent to them.
+use ethers::types::Address;
+use serde::{Deserialize, Serialize};
+
+/// json-rpc namespaces that only rollup nodes serve
+pub const ROLLUP_NAMESPACES: &[&str] = &["arbtrace", "optimism", "zkevm"];
+
+/// The kind of rollup the chain is. Used to describe the chain to clients
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RollupKind {
+    /// Arbitrum Nitro
+    Arbitrum,
+    /// OP Stack chains (Optimism, Base, ...)
+    Optimism,
+    /// Polygon zkEVM
+    Zkevm,
+}
+
+impl RollupKind {
+    /// The contract to `eth_call` for estimating a transaction's L1 data fee.
+    /// On Optimism this is the `GasPriceOracle` predeploy (`getL1Fee(bytes)`).
+    /// On Arbitrum this is the virtual `NodeInterface` (`gasEstimateL1Component`).
+    /// The calls are plain `eth_call`s, so they are cached with the head block like any other call
+    pub fn l1_fee_oracle(&self) -> Option<Address> {
+        match self {
+            Self::Arbitrum => Some(Address::from_low_u64_be(0xc8)),
+            Self::Optimism => Some(
+                "0x420000000000000000000000000000000000000F"
+                    .parse()
+                    .expect("GasPriceOracle address should always parse"),
+            ),
+            // zkEVM fees are paid in one L2 gas price
+            Self::Zkevm => None,
+        }
+    }
+}
+
+/// The rollup namespace of a method. None for methods that any node can serve
+pub fn rollup_namespace(method: &str) -> Option<&'static str> {
+    let (namespace, _) = method.split_once('_')?;
+
+    ROLLUP_NAMESPACES.iter().find(|x| **x == namespace).copied()
+}
+
+/// Methods that read history from before the rollup's current node software.
+/// `arbtrace_*` is only served for blocks from before the Nitro upgrade, so it always needs archive data
+pub fn rollup_method_needs_archive(method: &str) -> bool {
+    method.starts_with("arbtrace_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rollup_namespace() {
+        assert_eq!(rollup_namespace("arbtrace_block"), Some("arbtrace"));
+        assert_eq!(rollup_namespace("optimism_outputAtBlock"), Some("optimism"));
+        assert_eq!(rollup_namespace("zkevm_batchNumber"), Some("zkevm"));
+        assert_eq!(rollup_namespace("eth_call"), None);
+        assert_eq!(rollup_namespace("trace_block"), None);
+        assert_eq!(rollup_namespace("optimism"), None);
+
+        assert!(rollup_method_needs_archive("arbtrace_transaction"));
+        assert!(!rollup_method_needs_archive("trace_transaction"));
+
+        assert_eq!(
+            RollupKind::Arbitrum.l1_fee_oracle(),
+            Some(
+                "0x00000000000000000000000000000000000000C8"
+                    .parse()
+                    .unwrap()
+            )
+        );
+    }
+}
@@ -0,0 +1,56 @@
+//! Routing rules send some methods only to backends that list the right capabilities.
+//! trace and debug methods can go to the tracing nodes, and old `eth_getLogs` can go to the archive nodes.
+//! Requests that no rule matches can go to any server.
+use crate::app::method_matches;
+use crate::config::RoutingRuleConfig;
+use std::collections::BTreeSet;
+
+/// Every capability that a server needs to be sent this request. Empty if no rule matches
+pub fn required_capabilities<'a>(
+    rules: &'a [RoutingRuleConfig],
+    method: &str,
+    archive_needed: bool,
+) -> BTreeSet<&'a str> {
+    rules
+        .iter()
+        .filter(|rule| archive_needed || !rule.archive_needed)
+        .filter(|rule| rule.methods.iter().any(|x| method_matches(x, method)))
+        .flat_map(|rule| rule.require.iter().map(|x| x.as_str()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_capabilities() {
+        let rules = vec![
+            RoutingRuleConfig {
+                methods: vec!["trace_*".to_string(), "debug_*".to_string()],
+                archive_needed: false,
+                require: vec!["trace".to_string()],
+            },
+            RoutingRuleConfig {
+                methods: vec!["eth_getLogs".to_string(), "trace_*".to_string()],
+                archive_needed: true,
+                require: vec!["archive".to_string()],
+            },
+        ];
+
+        assert!(required_capabilities(&rules, "eth_call", true).is_empty());
+        assert!(required_capabilities(&rules, "eth_getLogs", false).is_empty());
+        assert_eq!(
+            required_capabilities(&rules, "eth_getLogs", true),
+            BTreeSet::from(["archive"])
+        );
+        assert_eq!(
+            required_capabilities(&rules, "debug_traceTransaction", false),
+            BTreeSet::from(["trace"])
+        );
+        assert_eq!(
+            required_capabilities(&rules, "trace_block", true),
+            BTreeSet::from(["archive", "trace"])
+        );
+    }
+}
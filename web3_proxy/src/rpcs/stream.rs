@@ -0,0 +1,309 @@
+//! Forward large responses from http backends chunk by chunk instead of holding the whole body in memory.
+//! The client's request (including its id) is sent upstream as is, so the backend's body is already the response that the client needs.
+use super::provider::extract_auth;
+use super::request::OpenRequestHandle;
+use crate::frontend::authorization::RequestMetadata;
+use crate::jsonrpc::JsonRpcRequest;
+use futures::stream::{BoxStream, Stream, StreamExt};
+use hyper::body::Bytes;
+use log::{trace, warn};
+use reqwest::header::AUTHORIZATION;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{sleep, Instant, Sleep};
+use url::Url;
+
+/// Send raw requests to an http backend
+pub struct Web3RpcStreamer {
+    http_client: reqwest::Client,
+    url: Url,
+    auth: Option<String>,
+    /// ids for requests that the proxy makes itself. client requests keep their own id
+    next_id: AtomicU64,
+}
+
+impl Web3RpcStreamer {
+    pub fn new(mut url: Url, http_client: Option<reqwest::Client>) -> Self {
+        let auth = extract_auth(&mut url).map(|x| x.to_string());
+
+        Self {
+            http_client: http_client.unwrap_or_default(),
+            url,
+            auth,
+            next_id: 1.into(),
+        }
+    }
+
+    pub fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, atomic::Ordering::Relaxed)
+    }
+
+    /// Send the request and return as soon as the headers are back. The body is left for the caller to read
+    pub async fn request(
+        &self,
+        request: &JsonRpcRequest,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let mut builder = self.http_client.post(self.url.clone()).json(request);
+
+        if let Some(auth) = self.auth.as_ref() {
+            builder = builder.header(AUTHORIZATION, auth);
+        }
+
+        builder.send().await?.error_for_status()
+    }
+}
+
+/// A backend's response body on its way to the client.
+/// Keeps the backend's request handle (and any permits) until the body is done,
+/// so the request still counts against the backend and the pools while it streams
+pub struct StreamingResponse {
+    inner: BoxStream<'static, Result<Bytes, reqwest::Error>>,
+    handle: Option<OpenRequestHandle>,
+    request_metadata: Option<Arc<RequestMetadata>>,
+    /// worker pool and concurrent request permits of any kind
+    permits: Vec<Box<dyn Send>>,
+    num_bytes: u64,
+    max_bytes: Option<u64>,
+    /// reset every time a chunk arrives
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+    /// when the whole body must be done
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl StreamingResponse {
+    pub fn new(
+        response: reqwest::Response,
+        handle: OpenRequestHandle,
+        request_metadata: Option<Arc<RequestMetadata>>,
+        max_bytes: Option<u64>,
+    ) -> Self {
+        Self {
+            inner: response.bytes_stream().boxed(),
+            handle: Some(handle),
+            request_metadata,
+            permits: vec![],
+            num_bytes: 0,
+            max_bytes,
+            idle: None,
+            deadline: None,
+        }
+    }
+
+    /// Cut the body off if the backend sends nothing for `idle`, or if the whole body takes longer than `total`
+    pub fn set_timeouts(&mut self, idle: Duration, total: Duration) {
+        self.idle = Some((idle, Box::pin(sleep(idle))));
+        self.deadline = Some(Box::pin(sleep(total)));
+    }
+
+    /// Poll the timeouts so that they wake us. Returns the one that ran out
+    fn poll_timeouts(&mut self, cx: &mut Context<'_>) -> Option<&'static str> {
+        if let Some(deadline) = self.deadline.as_mut() {
+            if deadline.as_mut().poll(cx).is_ready() {
+                return Some("took too long");
+            }
+        }
+
+        if let Some((_, idle)) = self.idle.as_mut() {
+            if idle.as_mut().poll(cx).is_ready() {
+                return Some("went idle");
+            }
+        }
+
+        None
+    }
+
+    /// Hold the permit until the body is done
+    pub fn hold<P: Send + 'static>(&mut self, permit: Option<P>) {
+        if let Some(permit) = permit {
+            self.permits.push(Box::new(permit));
+        }
+    }
+
+    /// The body is done (or failed). Save the stat and release the backend
+    fn finish(&mut self) {
+        if let Some(request_metadata) = self.request_metadata.take() {
+            request_metadata.add_response(self.num_bytes);
+        }
+
+        self.handle.take();
+        self.permits.clear();
+    }
+}
+
+impl Stream for StreamingResponse {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.handle.is_none() {
+            return Poll::Ready(None);
+        }
+
+        match self.inner.poll_next_unpin(cx) {
+            Poll::Pending => match self.poll_timeouts(cx) {
+                None => Poll::Pending,
+                Some(reason) => {
+                    if let Some(handle) = self.handle.as_ref() {
+                        warn!(
+                            "stream from {} {} after {} bytes",
+                            handle.connection_name(),
+                            reason,
+                            self.num_bytes
+                        );
+                    }
+
+                    if let Some(request_metadata) = self.request_metadata.as_ref() {
+                        request_metadata
+                            .error_response
+                            .store(true, atomic::Ordering::Release);
+                    }
+
+                    // like a body that is too large, all we can do is cut it off
+                    self.finish();
+
+                    Poll::Ready(Some(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("stream {}", reason),
+                    ))))
+                }
+            },
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.num_bytes += chunk.len() as u64;
+
+                if let Some((idle_timeout, idle)) = self.idle.as_mut() {
+                    idle.as_mut().reset(Instant::now() + *idle_timeout);
+                }
+
+                if let Some(max_bytes) = self.max_bytes {
+                    if self.num_bytes > max_bytes {
+                        // the status and headers are already sent. all we can do is cut the body off
+                        if let Some(request_metadata) = self.request_metadata.as_ref() {
+                            request_metadata
+                                .response_too_large
+                                .store(true, atomic::Ordering::Release);
+                        }
+
+                        self.finish();
+
+                        return Poll::Ready(Some(Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("response is larger than {} bytes", max_bytes),
+                        ))));
+                    }
+                }
+
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Err(err))) => {
+                if let Some(handle) = self.handle.as_ref() {
+                    warn!("stream from {} failed: {:?}", handle.connection_name(), err);
+                }
+
+                if let Some(request_metadata) = self.request_metadata.as_ref() {
+                    request_metadata
+                        .error_response
+                        .store(true, atomic::Ordering::Release);
+                }
+
+                self.finish();
+
+                Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::Other, err))))
+            }
+            Poll::Ready(None) => {
+                trace!("streamed {} bytes", self.num_bytes);
+
+                self.finish();
+
+                Poll::Ready(None)
+            }
+        }
+    }
+}
+
+impl Drop for StreamingResponse {
+    fn drop(&mut self) {
+        // the client hung up before the body was done. still bill for what was sent
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::authorization::Authorization;
+    use crate::rpcs::one::Web3Rpc;
+
+    async fn streaming_response(
+        body: impl Stream<Item = Result<Bytes, io::Error>> + Send + 'static,
+    ) -> StreamingResponse {
+        let response: reqwest::Response =
+            http::Response::new(reqwest::Body::wrap_stream(body)).into();
+
+        let authorization = Arc::new(Authorization::internal(None).unwrap());
+
+        let handle = OpenRequestHandle::new(authorization, Arc::new(Web3Rpc::default())).await;
+
+        StreamingResponse::new(response, handle, None, None)
+    }
+
+    /// sends `n` chunks one second apart and then never finishes
+    fn slow_body(n: usize) -> impl Stream<Item = Result<Bytes, io::Error>> + Send + 'static {
+        futures::stream::iter(0..n)
+            .then(|_| async {
+                sleep(Duration::from_secs(1)).await;
+
+                Ok(Bytes::from_static(b"[]"))
+            })
+            .chain(futures::stream::pending())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_idle_timeout() {
+        let mut x = streaming_response(slow_body(3)).await;
+
+        x.set_timeouts(Duration::from_secs(2), Duration::from_secs(60));
+
+        let start = Instant::now();
+
+        // every chunk resets the idle timeout
+        for _ in 0..3 {
+            assert!(x.next().await.unwrap().is_ok());
+        }
+
+        let err = x.next().await.unwrap().unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+
+        // the body is done. the backend was released
+        assert!(x.next().await.is_none());
+        assert!(x.handle.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_total_timeout() {
+        let mut x = streaming_response(slow_body(100)).await;
+
+        x.set_timeouts(Duration::from_secs(2), Duration::from_secs(10));
+
+        let start = Instant::now();
+
+        let mut num_chunks = 0;
+
+        let err = loop {
+            match x.next().await.unwrap() {
+                Ok(_) => num_chunks += 1,
+                Err(err) => break err,
+            }
+        };
+
+        // a backend that never goes idle still can't stream forever
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        assert!(num_chunks < 100);
+    }
+}
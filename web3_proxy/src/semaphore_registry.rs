@@ -0,0 +1,274 @@
+//! Concurrent request limits for users, ips, and bearer tokens.
+//!
+//! Every permit that is handed out is tracked until it is dropped.
+//! A permit held for longer than `permit_leak_seconds` is assumed to be leaked. It is given back to its semaphore with a warning
+//! so that a bug somewhere in a request can't lock a user out until the semaphore falls out of the cache.
+//! If the leaked permit is dropped later, it is forgotten instead of being returned twice.
+use crate::frontend::errors::Web3ProxyResult;
+use hashbrown::HashMap;
+use log::warn;
+use parking_lot::Mutex;
+use quick_cache_ttl::Cache;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Held permits are spread over this many locks by id, so requests for different keys rarely wait on each other
+const HELD_SHARDS: usize = 16;
+
+/// One permit that has not been dropped yet
+struct Held<K> {
+    key: K,
+    since: Instant,
+    semaphore: Arc<Semaphore>,
+}
+
+struct Holders<K> {
+    held: [Mutex<HashMap<u64, Held<K>>>; HELD_SHARDS],
+    next_id: AtomicU64,
+    force_released: AtomicU64,
+}
+
+impl<K> Default for Holders<K> {
+    fn default() -> Self {
+        Self {
+            held: Default::default(),
+            next_id: Default::default(),
+            force_released: Default::default(),
+        }
+    }
+}
+
+impl<K> Holders<K> {
+    fn shard(&self, id: u64) -> &Mutex<HashMap<u64, Held<K>>> {
+        &self.held[id as usize % HELD_SHARDS]
+    }
+}
+
+/// Lets a permit give itself back without knowing its registry's key type
+trait ReleasePermit: Send + Sync {
+    /// false if the leak detector already released this permit
+    fn release(&self, id: u64) -> bool;
+}
+
+impl<K: Send> ReleasePermit for Holders<K> {
+    fn release(&self, id: u64) -> bool {
+        self.shard(id).lock().remove(&id).is_some()
+    }
+}
+
+/// A point in time look at a registry. Shown on /status and in the prometheus metrics
+#[derive(Debug, Default, Serialize)]
+pub struct SemaphoreRegistryStatus {
+    /// keys with a semaphore in the cache
+    pub semaphores: usize,
+    pub in_use: usize,
+    /// how long the oldest permit that is still in use has been held
+    pub oldest_held_seconds: u64,
+    /// permits that were held past the deadline and given back
+    pub force_released: u64,
+}
+
+pub struct SemaphoreRegistry<K> {
+    name: &'static str,
+    semaphores: Cache<K, Arc<Semaphore>>,
+    holders: Arc<Holders<K>>,
+}
+
+/// A permit from a `SemaphoreRegistry`. The request's slot is freed when this is dropped
+pub struct RegistryPermit {
+    id: u64,
+    permit: Option<OwnedSemaphorePermit>,
+    holders: Arc<dyn ReleasePermit>,
+}
+
+impl fmt::Debug for RegistryPermit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegistryPermit")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for RegistryPermit {
+    fn drop(&mut self) {
+        if !self.holders.release(self.id) {
+            // the leak detector already gave this permit back. returning it again would raise the limit
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl<K> SemaphoreRegistry<K>
+where
+    K: Clone + fmt::Debug + Hash + Eq + Send + Sync + 'static,
+{
+    pub fn new(name: &'static str, capacity: usize) -> Self {
+        Self {
+            name,
+            semaphores: Cache::new(capacity),
+            holders: Default::default(),
+        }
+    }
+
+    /// Wait for one of the key's `size` permits.
+    /// `size` is only used the first time the key is seen. It is the same for the life of the semaphore
+    pub async fn acquire(&self, key: &K, size: usize) -> Web3ProxyResult<RegistryPermit> {
+        let semaphore =
+            self.semaphores
+                .get_or_insert_async::<Infallible>(key, async move {
+                    Ok(Arc::new(Semaphore::new(size)))
+                })
+                .await
+                .expect("infallible");
+
+        let permit = semaphore.clone().acquire_owned().await?;
+
+        let id = self.holders.next_id.fetch_add(1, atomic::Ordering::Relaxed);
+
+        self.holders.shard(id).lock().insert(
+            id,
+            Held {
+                key: key.clone(),
+                since: Instant::now(),
+                semaphore,
+            },
+        );
+
+        Ok(RegistryPermit {
+            id,
+            permit: Some(permit),
+            holders: self.holders.clone(),
+        })
+    }
+
+    /// How many permits the key has left. None if the key has no semaphore
+    pub fn available_permits(&self, key: &K) -> Option<usize> {
+        self.semaphores.get(key).map(|x| x.available_permits())
+    }
+
+    /// Give back every permit that has been held longer than `max_held`. Returns how many were released
+    pub fn release_leaked(&self, max_held: Duration) -> usize {
+        let mut released = 0;
+
+        for shard in self.holders.held.iter() {
+            let mut held = shard.lock();
+
+            let leaked: Vec<u64> = held
+                .iter()
+                .filter(|(_, x)| x.since.elapsed() >= max_held)
+                .map(|(id, _)| *id)
+                .collect();
+
+            for id in leaked.iter() {
+                let x = held.remove(id).expect("id was just found");
+
+                warn!(
+                    "{} permit for {:?} held for {}s. releasing it",
+                    self.name,
+                    x.key,
+                    x.since.elapsed().as_secs()
+                );
+
+                x.semaphore.add_permits(1);
+            }
+
+            released += leaked.len();
+        }
+
+        self.holders
+            .force_released
+            .fetch_add(released as u64, atomic::Ordering::Relaxed);
+
+        released
+    }
+
+    pub fn status(&self) -> SemaphoreRegistryStatus {
+        let mut in_use = 0;
+        let mut oldest_held_seconds = 0;
+
+        for shard in self.holders.held.iter() {
+            let held = shard.lock();
+
+            in_use += held.len();
+
+            if let Some(x) = held.values().map(|x| x.since.elapsed().as_secs()).max() {
+                oldest_held_seconds = oldest_held_seconds.max(x);
+            }
+        }
+
+        SemaphoreRegistryStatus {
+            semaphores: self.semaphores.len(),
+            in_use,
+            oldest_held_seconds,
+            force_released: self.holders.force_released.load(atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_release_leaked() {
+        let registry = SemaphoreRegistry::new("test", 10);
+
+        let leaked = registry.acquire(&1u64, 1).await.unwrap();
+
+        assert_eq!(registry.available_permits(&1), Some(0));
+        assert_eq!(registry.status().in_use, 1);
+
+        assert_eq!(registry.release_leaked(Duration::ZERO), 1);
+        assert_eq!(registry.available_permits(&1), Some(1));
+
+        // the user can make requests again
+        let second = registry.acquire(&1, 1).await.unwrap();
+
+        // dropping the leaked permit doesn't raise the limit
+        drop(leaked);
+        assert_eq!(registry.available_permits(&1), Some(0));
+
+        drop(second);
+        assert_eq!(registry.available_permits(&1), Some(1));
+
+        let status = registry.status();
+        assert_eq!(status.in_use, 0);
+        assert_eq!(status.force_released, 1);
+    }
+
+    #[tokio::test]
+    async fn test_many_keys() {
+        let registry = SemaphoreRegistry::new("test", 100);
+
+        // more permits than shards, so every shard is used
+        let mut permits = vec![];
+        for key in 0..(HELD_SHARDS as u64 * 2) {
+            permits.push(registry.acquire(&key, 2).await.unwrap());
+        }
+
+        let status = registry.status();
+        assert_eq!(status.semaphores, HELD_SHARDS * 2);
+        assert_eq!(status.in_use, HELD_SHARDS * 2);
+
+        // dropping half of them frees exactly those keys
+        permits.truncate(HELD_SHARDS);
+        assert_eq!(registry.status().in_use, HELD_SHARDS);
+        assert_eq!(registry.available_permits(&0), Some(1));
+        assert_eq!(registry.available_permits(&(HELD_SHARDS as u64)), Some(2));
+
+        assert_eq!(registry.release_leaked(Duration::ZERO), HELD_SHARDS);
+        assert_eq!(registry.status().in_use, 0);
+        assert_eq!(registry.available_permits(&0), Some(2));
+
+        drop(permits);
+        assert_eq!(registry.available_permits(&0), Some(2));
+    }
+}
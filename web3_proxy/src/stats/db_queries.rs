@@ -1,10 +1,7 @@
 use super::StatType;
 use crate::app::Web3ProxyApp;
-use crate::frontend::errors::{Web3ProxyError, Web3ProxyResponse, Web3ProxyResult};
-use crate::http_params::{
-    get_chain_id_from_params, get_page_from_params, get_query_start_from_params,
-    get_query_window_seconds_from_params, get_user_id_from_params,
-};
+use crate::frontend::errors::{Web3ProxyResponse, Web3ProxyResult};
+use crate::http_params::{get_user_id_from_params, StatsParams};
 use anyhow::Context;
 use axum::response::IntoResponse;
 use axum::Json;
@@ -59,7 +56,7 @@ pub fn filter_query_window_seconds(
 pub async fn query_user_stats<'a>(
     app: &'a Web3ProxyApp,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
-    params: &'a HashMap<String, String>,
+    params: &'a StatsParams,
     stat_response_type: StatType,
 ) -> Web3ProxyResponse {
     let db_conn = app.db_conn().context("query_user_stats needs a db")?;
@@ -73,14 +70,20 @@ pub async fn query_user_stats<'a>(
         .context("query_user_stats needs a redis")?;
 
     // get the user id first. if it is 0, we should use a cache on the app
-    let user_id =
-        get_user_id_from_params(&mut redis_conn, &db_conn, &db_replica, bearer, params).await?;
+    let user_id = get_user_id_from_params(
+        &mut redis_conn,
+        &db_conn,
+        &db_replica,
+        bearer,
+        params.user_id,
+    )
+    .await?;
     // get the query window seconds now so that we can pick a cache with a good TTL
     // TODO: for now though, just do one cache. its easier
-    let query_window_seconds = get_query_window_seconds_from_params(params)?;
-    let query_start = get_query_start_from_params(params)?;
-    let chain_id = get_chain_id_from_params(app, params)?;
-    let page = get_page_from_params(params)?;
+    let query_window_seconds = params.query_window_seconds;
+    let query_start = params.query_start;
+    let chain_id = params.chain_id.unwrap_or(app.config.chain_id);
+    let page = params.page;
 
     let cache_key = if user_id == 0 {
         // TODO: cacheable query_window_seconds from config
@@ -205,12 +208,7 @@ pub async fn query_user_stats<'a>(
 
     // filter on rpc_key_id
     // if rpc_key_id, all the requests without a key will be loaded
-    // TODO: move getting the param and checking the bearer token into a helper function
-    if let Some(rpc_key_id) = params.get("rpc_key_id") {
-        let rpc_key_id = rpc_key_id.parse::<u64>().map_err(|e| {
-            Web3ProxyError::BadRequest(format!("Unable to parse rpc_key_id. {:?}", e))
-        })?;
-
+    if let Some(rpc_key_id) = params.rpc_key_id(user_id) {
         response_body.insert("rpc_key_id", serde_json::Value::Number(rpc_key_id.into()));
 
         condition = condition.add(rpc_accounting::Column::RpcKeyId.eq(rpc_key_id));
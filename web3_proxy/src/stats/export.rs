@@ -0,0 +1,267 @@
+//! Stats items as files for analytics tools instead of JSON.
+//!
+//! Every backend gets its columns from `schema`, so the files have the same names and types as `/user/stats/schema`.
+//! Missing values (like spend that is hidden from a subuser) are empty cells in csv and nulls in parquet.
+use super::schema::{StatField, StatValueKind, STAT_FIELDS, STAT_GROUPS};
+use super::StatType;
+use crate::http_params::StatsFormat;
+use anyhow::Context;
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use serde_json::Value;
+use std::sync::Arc;
+
+type StatsRow = serde_json::Map<String, Value>;
+
+pub trait StatsSerializer: Send {
+    fn content_type(&self) -> &'static str;
+
+    fn extension(&self) -> &'static str;
+
+    fn write_row(&mut self, row: &StatsRow) -> anyhow::Result<()>;
+
+    fn finish(self: Box<Self>) -> anyhow::Result<Vec<u8>>;
+}
+
+/// The serializer for a format. None for json, which is the normal response body
+pub fn stats_serializer(
+    format: StatsFormat,
+    columns: Vec<&'static StatField>,
+) -> anyhow::Result<Option<Box<dyn StatsSerializer>>> {
+    let x: Box<dyn StatsSerializer> = match format {
+        StatsFormat::Json => return Ok(None),
+        StatsFormat::Csv => Box::new(CsvStatsSerializer::new(columns)?),
+        StatsFormat::Parquet => Box::new(ParquetStatsSerializer::new(columns)),
+    };
+
+    Ok(Some(x))
+}
+
+/// Groups and then fields. `fields` limits the columns the same way it limits the keys on json items
+pub fn stats_columns(
+    stat_response_type: &StatType,
+    fields: Option<&[String]>,
+) -> Vec<&'static StatField> {
+    STAT_GROUPS
+        .iter()
+        .chain(STAT_FIELDS)
+        .filter(|x| !x.detailed_only || *stat_response_type == StatType::Detailed)
+        .filter(|x| match fields {
+            Some(fields) => fields.iter().any(|f| f == x.name),
+            None => true,
+        })
+        .collect()
+}
+
+pub struct CsvStatsSerializer {
+    columns: Vec<&'static StatField>,
+    writer: csv::Writer<Vec<u8>>,
+}
+
+impl CsvStatsSerializer {
+    pub fn new(columns: Vec<&'static StatField>) -> anyhow::Result<Self> {
+        let mut writer = csv::Writer::from_writer(vec![]);
+
+        writer.write_record(columns.iter().map(|x| x.name))?;
+
+        Ok(Self { columns, writer })
+    }
+}
+
+impl StatsSerializer for CsvStatsSerializer {
+    fn content_type(&self) -> &'static str {
+        "text/csv"
+    }
+
+    fn extension(&self) -> &'static str {
+        "csv"
+    }
+
+    fn write_row(&mut self, row: &StatsRow) -> anyhow::Result<()> {
+        let record = self.columns.iter().map(|x| match row.get(x.name) {
+            None | Some(Value::Null) => "".to_string(),
+            Some(Value::String(x)) => x.clone(),
+            Some(x) => x.to_string(),
+        });
+
+        self.writer.write_record(record)?;
+
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> anyhow::Result<Vec<u8>> {
+        self.writer
+            .into_inner()
+            .map_err(|err| anyhow::Error::from(err.into_error()))
+    }
+}
+
+/// Parquet is columnar, so rows are kept until `finish` writes them as one row group
+pub struct ParquetStatsSerializer {
+    columns: Vec<&'static StatField>,
+    rows: Vec<StatsRow>,
+}
+
+impl ParquetStatsSerializer {
+    pub fn new(columns: Vec<&'static StatField>) -> Self {
+        Self {
+            columns,
+            rows: vec![],
+        }
+    }
+
+    /// Every column is optional. Times are strings like they are in json
+    fn message_type(&self) -> String {
+        let columns: Vec<String> = self
+            .columns
+            .iter()
+            .map(|x| {
+                let physical_type = match x.kind {
+                    StatValueKind::Bool => "BOOLEAN",
+                    StatValueKind::Float => "DOUBLE",
+                    StatValueKind::Integer => "INT64",
+                    StatValueKind::String | StatValueKind::Time => "BYTE_ARRAY",
+                };
+
+                let logical_type = match x.kind {
+                    StatValueKind::String | StatValueKind::Time => " (UTF8)",
+                    _ => "",
+                };
+
+                format!("OPTIONAL {} {}{};", physical_type, x.name, logical_type)
+            })
+            .collect();
+
+        format!("message stats {{ {} }}", columns.join(" "))
+    }
+
+    /// The values that are set and the definition level of every row. 1 is set and 0 is null
+    fn column<T>(&self, name: &str, f: impl Fn(&Value) -> Option<T>) -> (Vec<T>, Vec<i16>) {
+        let mut values = vec![];
+        let mut def_levels = Vec::with_capacity(self.rows.len());
+
+        for row in self.rows.iter() {
+            match row.get(name).and_then(&f) {
+                Some(x) => {
+                    values.push(x);
+                    def_levels.push(1);
+                }
+                None => def_levels.push(0),
+            }
+        }
+
+        (values, def_levels)
+    }
+}
+
+impl StatsSerializer for ParquetStatsSerializer {
+    fn content_type(&self) -> &'static str {
+        "application/vnd.apache.parquet"
+    }
+
+    fn extension(&self) -> &'static str {
+        "parquet"
+    }
+
+    fn write_row(&mut self, row: &StatsRow) -> anyhow::Result<()> {
+        self.rows.push(row.clone());
+
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> anyhow::Result<Vec<u8>> {
+        let schema = Arc::new(parse_message_type(&self.message_type())?);
+        let props = Arc::new(WriterProperties::builder().build());
+
+        let mut writer = SerializedFileWriter::new(vec![], schema, props)?;
+
+        let mut row_group = writer.next_row_group()?;
+
+        for field in self.columns.iter() {
+            let mut column = row_group
+                .next_column()?
+                .context("parquet schema is missing a column")?;
+
+            match field.kind {
+                StatValueKind::Bool => {
+                    // influx tags that are not "true" or "false" are null
+                    let (values, def_levels) = self.column(field.name, |x| x.as_bool());
+                    column
+                        .typed::<BoolType>()
+                        .write_batch(&values, Some(&def_levels), None)?;
+                }
+                StatValueKind::Float => {
+                    let (values, def_levels) = self.column(field.name, |x| x.as_f64());
+                    column
+                        .typed::<DoubleType>()
+                        .write_batch(&values, Some(&def_levels), None)?;
+                }
+                StatValueKind::Integer => {
+                    let (values, def_levels) = self.column(field.name, |x| x.as_i64());
+                    column
+                        .typed::<Int64Type>()
+                        .write_batch(&values, Some(&def_levels), None)?;
+                }
+                StatValueKind::String | StatValueKind::Time => {
+                    let (values, def_levels) = self.column(field.name, |x| {
+                        x.as_str().map(|x| ByteArray::from(x.as_bytes().to_vec()))
+                    });
+                    column.typed::<ByteArrayType>().write_batch(
+                        &values,
+                        Some(&def_levels),
+                        None,
+                    )?;
+                }
+            }
+
+            column.close()?;
+        }
+
+        row_group.close()?;
+
+        Ok(writer.into_inner()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_csv_stats_serializer() {
+        let columns = stats_columns(
+            &StatType::Aggregated,
+            Some(&[
+                "time".to_string(),
+                "method".to_string(),
+                "archive_needed".to_string(),
+                "total_frontend_requests".to_string(),
+                "total_credits_used".to_string(),
+            ]),
+        );
+
+        // method is only on detailed stats
+        assert_eq!(columns.len(), 4);
+
+        let mut x = Box::new(CsvStatsSerializer::new(columns).unwrap());
+
+        let row = json!({
+            "time": "2023-05-01 00:00:00 +00:00",
+            "archive_needed": false,
+            "total_frontend_requests": 10,
+            "rpc_key_id": "1",
+        });
+
+        x.write_row(row.as_object().unwrap()).unwrap();
+
+        let csv = String::from_utf8(x.finish().unwrap()).unwrap();
+
+        assert_eq!(
+            csv,
+            "time,archive_needed,total_frontend_requests,total_credits_used\n2023-05-01 00:00:00 +00:00,false,10,\n"
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! How far behind the stats in influx are.
+//!
+//! A stat goes through three steps before a dashboard can see it:
+//! it waits in the stat channel, it waits in the buffer until the next tsdb save, and then it is written to influx.
+//! The stat buffer times each step and keeps the latest numbers here.
+use chrono::Utc;
+use serde_json::json;
+use std::sync::atomic::{self, AtomicI64, AtomicU64};
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+pub struct StatFreshness {
+    /// the longest a stat waited in the channel before the buffer got it. from the last save
+    channel_wait_ms: AtomicU64,
+    /// how long the buffer held stats before the last save started
+    aggregation_ms: AtomicU64,
+    /// how long the last write to influx took
+    write_ms: AtomicU64,
+    /// from the oldest request in the last save completing to that save finishing
+    end_to_end_ms: AtomicU64,
+    /// unix epoch milliseconds. requests that completed before this are in influx. 0 until the first save
+    as_of_ms: AtomicI64,
+}
+
+/// The timings of one tsdb save
+#[derive(Debug, Default)]
+pub struct StatSaveTimings {
+    pub channel_wait: Duration,
+    pub aggregation: Duration,
+    pub write: Duration,
+    pub end_to_end: Duration,
+}
+
+impl StatFreshness {
+    /// Save the timings of a tsdb save that started at `started_ms` (unix epoch milliseconds).
+    /// `as_of` only moves forward if every write worked. Otherwise the stats look older and older until one does
+    pub fn record_save(&self, started_ms: i64, timings: StatSaveTimings, all_written: bool) {
+        self.channel_wait_ms.store(
+            timings.channel_wait.as_millis() as u64,
+            atomic::Ordering::Relaxed,
+        );
+        self.aggregation_ms.store(
+            timings.aggregation.as_millis() as u64,
+            atomic::Ordering::Relaxed,
+        );
+        self.write_ms
+            .store(timings.write.as_millis() as u64, atomic::Ordering::Relaxed);
+        self.end_to_end_ms.store(
+            timings.end_to_end.as_millis() as u64,
+            atomic::Ordering::Relaxed,
+        );
+
+        if all_written {
+            // a request that completed right before the save started could still have been in the channel
+            let as_of_ms = started_ms - timings.channel_wait.as_millis() as i64;
+
+            self.as_of_ms.fetch_max(as_of_ms, atomic::Ordering::AcqRel);
+        }
+    }
+
+    /// Seconds between now and the newest time that every stat is in influx. None before the first save
+    pub fn lag_seconds(&self) -> Option<f64> {
+        match self.as_of_ms.load(atomic::Ordering::Acquire) {
+            0 => None,
+            x => Some((Utc::now().timestamp_millis() - x).max(0) as f64 / 1000.0),
+        }
+    }
+
+    /// Milliseconds spent in each step by the last save. For the prometheus metrics
+    pub fn steps_ms(&self) -> [(&'static str, u64); 4] {
+        [
+            (
+                "channel_wait",
+                self.channel_wait_ms.load(atomic::Ordering::Relaxed),
+            ),
+            (
+                "aggregation",
+                self.aggregation_ms.load(atomic::Ordering::Relaxed),
+            ),
+            ("write", self.write_ms.load(atomic::Ordering::Relaxed)),
+            (
+                "end_to_end",
+                self.end_to_end_ms.load(atomic::Ordering::Relaxed),
+            ),
+        ]
+    }
+
+    /// The `freshness` field of stats responses
+    pub fn snapshot(&self) -> serde_json::Value {
+        let as_of_ms = self.as_of_ms.load(atomic::Ordering::Acquire);
+
+        json!({
+            "as_of": (as_of_ms > 0).then_some(as_of_ms / 1000),
+            "lag_seconds": self.lag_seconds(),
+            "channel_wait_ms": self.channel_wait_ms.load(atomic::Ordering::Relaxed),
+            "aggregation_ms": self.aggregation_ms.load(atomic::Ordering::Relaxed),
+            "write_ms": self.write_ms.load(atomic::Ordering::Relaxed),
+            "end_to_end_ms": self.end_to_end_ms.load(atomic::Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failed_save_keeps_as_of() {
+        let freshness = StatFreshness::default();
+
+        assert_eq!(freshness.lag_seconds(), None);
+
+        let timings = || StatSaveTimings {
+            channel_wait: Duration::from_millis(500),
+            ..Default::default()
+        };
+
+        freshness.record_save(10_000, timings(), true);
+        assert_eq!(freshness.as_of_ms.load(atomic::Ordering::Acquire), 9_500);
+
+        freshness.record_save(20_000, timings(), false);
+        assert_eq!(freshness.as_of_ms.load(atomic::Ordering::Acquire), 9_500);
+
+        assert_eq!(freshness.snapshot()["as_of"], json!(9));
+    }
+}
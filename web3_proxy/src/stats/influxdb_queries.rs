@@ -1,12 +1,13 @@
+use super::export::{stats_columns, stats_serializer};
+use super::schema::{stat_field_for_influx, StatDimension, StatValueKind, STAT_FIELDS};
 use super::StatType;
 use crate::frontend::errors::Web3ProxyErrorContext;
+use crate::frontend::users::org::{org_member_role, org_role_can_view_balance};
+use crate::frontend::users::subuser::{subuser_can_view_balance, subuser_can_view_stats};
 use crate::{
     app::Web3ProxyApp,
-    frontend::errors::{Web3ProxyError, Web3ProxyResponse},
-    http_params::{
-        get_chain_id_from_params, get_query_start_from_params, get_query_stop_from_params,
-        get_query_window_seconds_from_params,
-    },
+    frontend::errors::{Web3ProxyError, Web3ProxyResponse, Web3ProxyResult},
+    http_params::StatsParams,
 };
 use anyhow::Context;
 use axum::{
@@ -14,38 +15,199 @@ use axum::{
     response::IntoResponse,
     Json, TypedHeader,
 };
-use entities::sea_orm_active_enums::Role;
-use entities::{rpc_key, secondary_user};
+use entities::{org_member, rpc_key, secondary_user, user_tier};
+use ethers::utils::hex;
 use fstrings::{f, format_args_f};
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
+use http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
 use influxdb2::api::query::FluxRecord;
 use influxdb2::models::Query;
 use log::{error, info, warn};
 use migration::sea_orm::ColumnTrait;
+use migration::sea_orm::Condition;
 use migration::sea_orm::EntityTrait;
 use migration::sea_orm::QueryFilter;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::{timeout, Instant};
 use ulid::Ulid;
 
+/// The tags that items are grouped by. A cursor includes all of them so that every item has a unique place in the order
+const CURSOR_GROUPS: [&str; 7] = [
+    "_measurement",
+    "archive_needed",
+    "chain_id",
+    "error_response",
+    "method",
+    "origin",
+    "rpc_secret_key_id",
+];
+
+/// Where a page of stats stopped. Items are sorted newest first and then by their groups.
+/// The next page starts after this item, so items saved while paging do not shift the pages
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct StatsCursor {
+    time: i64,
+    groups: Vec<String>,
+}
+
+impl Ord for StatsCursor {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .time
+            .cmp(&self.time)
+            .then_with(|| self.groups.cmp(&other.groups))
+    }
+}
+
+impl PartialOrd for StatsCursor {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl StatsCursor {
+    fn from_values(values: &BTreeMap<String, influxdb2_structmap::value::Value>) -> Self {
+        let time = match values.get("_time") {
+            Some(influxdb2_structmap::value::Value::TimeRFC(x)) => x.timestamp(),
+            _ => 0,
+        };
+
+        let groups = CURSOR_GROUPS
+            .iter()
+            .map(|x| match values.get(*x) {
+                Some(influxdb2_structmap::value::Value::String(x)) => x.clone(),
+                _ => String::new(),
+            })
+            .collect();
+
+        Self { time, groups }
+    }
+
+    /// Opaque to clients. They only pass it back as `cursor`
+    pub fn encode(&self) -> String {
+        hex::encode(serde_json::to_vec(self).expect("cursors should always serialize"))
+    }
+
+    pub fn decode(x: &str) -> Option<Self> {
+        let x = hex::decode(x).ok()?;
+
+        serde_json::from_slice(&x).ok()
+    }
+}
+
+/// Sort the items and cut out the page after `cursor`. Returns the page and the cursor for the next page
+pub fn paginate_stats<T>(
+    mut items: Vec<(StatsCursor, T)>,
+    cursor: Option<&StatsCursor>,
+    limit: Option<u64>,
+) -> (Vec<T>, Option<StatsCursor>) {
+    items.sort_by(|a, b| a.0.cmp(&b.0));
+
+    if let Some(cursor) = cursor {
+        items.retain(|x| x.0 > *cursor);
+    }
+
+    let mut next_cursor = None;
+
+    if let Some(limit) = limit {
+        let limit = limit as usize;
+
+        if items.len() > limit {
+            items.truncate(limit);
+
+            next_cursor = items.last().map(|x| x.0.clone());
+        }
+    }
+
+    (items.into_iter().map(|x| x.1).collect(), next_cursor)
+}
+
 pub async fn query_user_stats<'a>(
     app: &'a Web3ProxyApp,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
-    params: &'a HashMap<String, String>,
+    params: &'a StatsParams,
     stat_response_type: StatType,
 ) -> Web3ProxyResponse {
-    let user_id = match bearer {
+    let columns = stats_columns(&stat_response_type, params.fields.as_deref());
+
+    let serializer = stats_serializer(params.format, columns)
+        .web3_context("failed starting the stats export")?;
+
+    let response_json = query_user_stats_json(app, bearer, params, stat_response_type).await?;
+
+    let mut serializer = match serializer {
+        None => return Ok(Json(response_json).into_response()),
+        Some(x) => x,
+    };
+
+    let items = response_json["result"].as_array().into_iter().flatten();
+
+    for item in items.filter_map(|x| x.as_object()) {
+        serializer
+            .write_row(item)
+            .web3_context("failed writing the stats export")?;
+    }
+
+    let content_type = serializer.content_type();
+
+    let content_disposition = format!(
+        "attachment; filename=\"stats-{}-{}-{}.{}\"",
+        response_json["chain_id"],
+        response_json["query_start"],
+        response_json["query_stop"],
+        serializer.extension()
+    );
+
+    let body = serializer
+        .finish()
+        .web3_context("failed writing the stats export")?;
+
+    let mut response = (
+        [
+            (CONTENT_TYPE, content_type.to_string()),
+            (CONTENT_DISPOSITION, content_disposition),
+        ],
+        body,
+    )
+        .into_response();
+
+    // the file only has the items. paging still works with this header
+    if let Some(next_cursor) = response_json["next_cursor"].as_str() {
+        response.headers_mut().insert(
+            "X-Next-Cursor",
+            next_cursor
+                .parse()
+                .expect("hex cursors should always be valid headers"),
+        );
+    }
+
+    Ok(response)
+}
+
+/// The body of `query_user_stats`. The GraphQL endpoint reads the same stats
+pub async fn query_user_stats_json<'a>(
+    app: &'a Web3ProxyApp,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    params: &'a StatsParams,
+    stat_response_type: StatType,
+) -> Web3ProxyResult<serde_json::Value> {
+    let user = match bearer {
         Some(inner_bearer) => {
             let (user, _semaphore) = app.bearer_is_authorized(inner_bearer.0 .0).await?;
-            user.id
+            Some(user)
         }
-        None => 0,
+        None => None,
     };
 
+    let user_id = user.as_ref().map(|x| x.id).unwrap_or_default();
+
     // Return an error if the bearer is set, but the StatType is Detailed
     if stat_response_type == StatType::Detailed && user_id == 0 {
-        return Err(Web3ProxyError::BadRequest(
-            "Detailed Stats Response requires you to authorize with a bearer token".to_owned(),
-        ));
+        return Err(Web3ProxyError::StatsRequireBearer);
     }
 
     let db_replica = app
@@ -58,16 +220,61 @@ pub async fn query_user_stats<'a>(
         .as_ref()
         .context("query_user_stats needs an influxdb client")?;
 
-    let query_window_seconds = get_query_window_seconds_from_params(params)?;
-    let query_start = get_query_start_from_params(params)?.timestamp();
-    let query_stop = get_query_stop_from_params(params)?.timestamp();
-    let chain_id = get_chain_id_from_params(app, params)?;
+    let query_window_seconds = params.query_window_seconds;
+    let query_start = params.query_start.timestamp();
+    let query_stop = params.query_stop.timestamp();
+    let chain_id = params.chain_id.unwrap_or(app.config.chain_id);
+    let align = params.align;
+
+    if query_window_seconds == 0 {
+        return Err(Web3ProxyError::InvalidQueryWindow);
+    }
+
+    if params.limit == Some(0) {
+        return Err(Web3ProxyError::InvalidParam(
+            "limit",
+            "must be at least 1".into(),
+        ));
+    }
 
     // Return a bad request if query_start == query_stop, because then the query is empty basically
     if query_start == query_stop {
-        return Err(Web3ProxyError::BadRequest(
-            "Start and Stop date cannot be equal. Please specify a (different) start date."
-                .to_owned(),
+        return Err(Web3ProxyError::QueryRangeEmpty);
+    }
+
+    // without alignment, the buckets start at query_start and the same hour can land in different buckets
+    let (query_start, query_stop) = if align {
+        align_query_range(query_start, query_stop, query_window_seconds)
+    } else {
+        (query_start, query_stop)
+    };
+
+    let max_range_seconds = match user.as_ref() {
+        Some(user) => user_tier::Entity::find_by_id(user.user_tier_id)
+            .one(db_replica.conn())
+            .await
+            .web3_context("failed loading user's tier")?
+            .and_then(|x| x.max_stats_range_seconds),
+        None => app.config.public_stats_max_range_seconds,
+    };
+
+    check_query_limits(
+        query_start,
+        query_stop,
+        query_window_seconds,
+        max_range_seconds,
+        app.config.stats_max_buckets,
+    )?;
+
+    if let Some(dimension) = params
+        .group_by
+        .iter()
+        .flatten()
+        .find(|x| x.detailed_only() && stat_response_type != StatType::Detailed)
+    {
+        return Err(Web3ProxyError::InvalidParam(
+            "group_by",
+            format!("{} is only on detailed stats", dimension.name()).into(),
         ));
     }
 
@@ -78,10 +285,51 @@ pub async fn query_user_stats<'a>(
     };
 
     // Include a hashmap to go from rpc_secret_key_id to the rpc_secret_key
+    // keys that are only saved as a hash map to None
     let mut rpc_key_id_to_key = HashMap::new();
+    // shared keys whose owner only gave us read access to the stats. their spend is removed from the response
+    let mut balance_hidden_rpc_key_ids = HashSet::new();
 
-    let rpc_key_filter = if user_id == 0 {
-        "".to_string()
+    let rpc_key_ids = if user_id == 0 {
+        vec![]
+    } else if let Some(org_id) = params.org_id {
+        let role = org_member_role(db_replica.conn(), org_id, user_id).await?;
+
+        let member_ids: Vec<u64> = org_member::Entity::find()
+            .filter(org_member::Column::OrgId.eq(org_id))
+            .all(db_replica.conn())
+            .await
+            .web3_context("failed loading org members")?
+            .into_iter()
+            .map(|x| x.user_id)
+            .collect();
+
+        let org_rpc_keys = rpc_key::Entity::find()
+            .filter(
+                Condition::any()
+                    .add(rpc_key::Column::OrgId.eq(org_id))
+                    .add(rpc_key::Column::UserId.is_in(member_ids)),
+            )
+            .all(db_replica.conn())
+            .await
+            .web3_context("failed loading org keys")?
+            .into_iter()
+            .map(|x| {
+                let key = x.id.to_string();
+                let val = x.secret_key.map(Ulid::from);
+                rpc_key_id_to_key.insert(key.clone(), val);
+                if !org_role_can_view_balance(&role) {
+                    balance_hidden_rpc_key_ids.insert(key.clone());
+                }
+                key
+            })
+            .collect::<Vec<_>>();
+
+        if org_rpc_keys.is_empty() {
+            return Err(Web3ProxyError::NoRpcKeys);
+        }
+
+        org_rpc_keys
     } else {
         // Fetch all rpc_secret_key_ids, and filter for these
         let mut user_rpc_keys = rpc_key::Entity::find()
@@ -92,7 +340,7 @@ pub async fn query_user_stats<'a>(
             .into_iter()
             .map(|x| {
                 let key = x.id.to_string();
-                let val = Ulid::from(x.secret_key);
+                let val = x.secret_key.map(Ulid::from);
                 rpc_key_id_to_key.insert(key.clone(), val);
                 key
             })
@@ -110,10 +358,13 @@ pub async fn query_user_stats<'a>(
             .flat_map(
                 |(subuser, wrapped_shared_rpc_key)| match wrapped_shared_rpc_key {
                     Some(shared_rpc_key) => {
-                        if subuser.role == Role::Admin || subuser.role == Role::Owner {
+                        if subuser_can_view_stats(&subuser) {
                             let key = shared_rpc_key.id.to_string();
-                            let val = Ulid::from(shared_rpc_key.secret_key);
+                            let val = shared_rpc_key.secret_key.map(Ulid::from);
                             rpc_key_id_to_key.insert(key.clone(), val);
+                            if !subuser_can_view_balance(&subuser) {
+                                balance_hidden_rpc_key_ids.insert(key.clone());
+                            }
                             Some(key)
                         } else {
                             None
@@ -127,16 +378,10 @@ pub async fn query_user_stats<'a>(
         user_rpc_keys.append(&mut subuser_rpc_keys);
 
         if user_rpc_keys.is_empty() {
-            return Err(Web3ProxyError::BadRequest(
-                "User has no secret RPC keys yet".to_string(),
-            ));
+            return Err(Web3ProxyError::NoRpcKeys);
         }
 
-        // Iterate, pop and add to string
-        f!(
-            r#"|> filter(fn: (r) => contains(value: r["rpc_secret_key_id"], set: {:?}))"#,
-            user_rpc_keys
-        )
+        user_rpc_keys
     };
 
     // TODO: Turn into a 500 error if bucket is not found ..
@@ -148,12 +393,6 @@ pub async fn query_user_stats<'a>(
         .context("No influxdb bucket was provided")?; // "web3_proxy";
 
     info!("Bucket is {:?}", bucket);
-    let mut filter_chain_id = "".to_string();
-    if chain_id != 0 {
-        filter_chain_id = f!(r#"|> filter(fn: (r) => r["chain_id"] == "{chain_id}")"#);
-    }
-
-    // Fetch and request for balance
 
     info!(
         "Query start and stop are: {:?} {:?}",
@@ -161,45 +400,42 @@ pub async fn query_user_stats<'a>(
     );
     // info!("Query column parameters are: {:?}", stats_column);
     info!("Query measurement is: {:?}", measurement);
-    info!("Filters are: {:?}", filter_chain_id); // filter_field
     info!("window seconds are: {:?}", query_window_seconds);
 
-    let drop_method = match stat_response_type {
-        StatType::Aggregated => f!(r#"|> drop(columns: ["method"])"#),
-        StatType::Detailed => "".to_string(),
-    };
-
-    let query = f!(r#"
-    base = from(bucket: "{bucket}")
-        |> range(start: {query_start}, stop: {query_stop})
-        {rpc_key_filter}
-        |> filter(fn: (r) => r["_measurement"] == "{measurement}")
-        {filter_chain_id}
-        {drop_method}
-
-    base
-        |> aggregateWindow(every: {query_window_seconds}s, fn: sum, createEmpty: false)
-        |> pivot(rowKey: ["_time"], columnKey: ["_field"], valueColumn: "_value")
-        |> drop(columns: ["balance"])
-        |> group(columns: ["_time", "_measurement", "archive_needed", "chain_id", "error_response", "method", "rpc_secret_key_id"])
-        |> sort(columns: ["frontend_requests"])
-        |> map(fn:(r) => ({{ r with "sum_credits_used": float(v: r["sum_credits_used"]) }}))
-        |> cumulativeSum(columns: ["backend_requests", "cache_hits", "cache_misses", "frontend_requests", "sum_credits_used", "sum_request_bytes", "sum_response_bytes", "sum_response_millis"])
-        |> sort(columns: ["frontend_requests"], desc: true)
-        |> limit(n: 1)
-        |> group()
-        |> sort(columns: ["_time", "_measurement", "archive_needed", "chain_id", "error_response", "method", "rpc_secret_key_id"], desc: true)
-    "#);
+    let query = build_flux_query(
+        bucket,
+        measurement,
+        query_start,
+        query_stop,
+        query_window_seconds,
+        chain_id,
+        &rpc_key_ids,
+        &stat_response_type,
+        params.group_by.as_deref(),
+    );
 
     info!("Raw query to db is: {:?}", query);
     let query = Query::new(query.to_string());
     info!("Query to db is: {:?}", query);
 
     // Make the query and collect all data
-    let raw_influx_responses: Vec<FluxRecord> = influxdb_client
-        .query_raw(Some(query.clone()))
-        .await
-        .context("failed parsing query result into a FluxRecord")?;
+    let query_timeout = Duration::from_secs(app.config.stats_query_timeout_seconds);
+    let query_started = Instant::now();
+
+    let raw_influx_responses: Vec<FluxRecord> = timeout(
+        query_timeout,
+        influxdb_client.query_raw(Some(query.clone())),
+    )
+    .await
+    .map_err(|_| Web3ProxyError::StatsQueryTimeout)?
+    .context("failed parsing query result into a FluxRecord")?;
+
+    info!(
+        "stats query for user {} returned {} records in {:?}",
+        user_id,
+        raw_influx_responses.len(),
+        query_started.elapsed()
+    );
 
     // Basically rename all items to be "total",
     // calculate number of "archive_needed" and "error_responses" through their boolean representations ...
@@ -211,11 +447,43 @@ pub async fn query_user_stats<'a>(
         // .into_values()
         .map(|x| x.values)
         .map(|value_map| {
+            // before any fields are removed
+            let cursor = StatsCursor::from_values(&value_map);
+
+            // without rpc_key in group_by, an item can include hidden keys
+            let hide_balance = match value_map.get("rpc_secret_key_id") {
+                Some(influxdb2_structmap::value::Value::String(inner)) => {
+                    balance_hidden_rpc_key_ids.contains(inner)
+                }
+                _ => !balance_hidden_rpc_key_ids.is_empty(),
+            };
+
             // Unwrap all relevant numbers
             // BTreeMap<String, value::Value>
             let mut out: HashMap<String, serde_json::Value> = HashMap::new();
             value_map.into_iter().for_each(|(key, value)| {
-                if key == "_measurement" {
+                if let Some(field) = stat_field_for_influx(&key) {
+                    match (field.kind, value) {
+                        (
+                            StatValueKind::Integer,
+                            influxdb2_structmap::value::Value::Long(inner),
+                        ) => {
+                            out.insert(
+                                field.name.to_owned(),
+                                serde_json::Value::Number(inner.into()),
+                            );
+                        }
+                        (
+                            StatValueKind::Float,
+                            influxdb2_structmap::value::Value::Double(inner),
+                        ) => {
+                            out.insert(field.name.to_owned(), json!(f64::from(inner)));
+                        }
+                        _ => {
+                            error!("{} should always be a {:?}!", key, field.kind);
+                        }
+                    }
+                } else if key == "_measurement" {
                     match value {
                         influxdb2_structmap::value::Value::String(inner) => {
                             if inner == "opt_in_proxy" {
@@ -264,134 +532,21 @@ pub async fn query_user_stats<'a>(
                             error!("_stop should always be a TimeRFC!");
                         }
                     }
-                } else if key == "backend_requests" {
-                    match value {
-                        influxdb2_structmap::value::Value::Long(inner) => {
-                            out.insert(
-                                "total_backend_requests".to_owned(),
-                                serde_json::Value::Number(inner.into()),
-                            );
-                        }
-                        _ => {
-                            error!("backend_requests should always be a Long!");
-                        }
-                    }
-                } else if key == "balance" {
-                    match value {
-                        influxdb2_structmap::value::Value::Double(inner) => {
-                            out.insert("balance".to_owned(), json!(f64::from(inner)));
-                        }
-                        _ => {
-                            error!("balance should always be a Double!");
-                        }
-                    }
-                } else if key == "cache_hits" {
-                    match value {
-                        influxdb2_structmap::value::Value::Long(inner) => {
-                            out.insert(
-                                "total_cache_hits".to_owned(),
-                                serde_json::Value::Number(inner.into()),
-                            );
-                        }
-                        _ => {
-                            error!("cache_hits should always be a Long!");
-                        }
-                    }
-                } else if key == "cache_misses" {
-                    match value {
-                        influxdb2_structmap::value::Value::Long(inner) => {
-                            out.insert(
-                                "total_cache_misses".to_owned(),
-                                serde_json::Value::Number(inner.into()),
-                            );
-                        }
-                        _ => {
-                            error!("cache_misses should always be a Long!");
-                        }
-                    }
-                } else if key == "frontend_requests" {
-                    match value {
-                        influxdb2_structmap::value::Value::Long(inner) => {
-                            out.insert(
-                                "total_frontend_requests".to_owned(),
-                                serde_json::Value::Number(inner.into()),
-                            );
-                        }
-                        _ => {
-                            error!("frontend_requests should always be a Long!");
-                        }
-                    }
-                } else if key == "no_servers" {
-                    match value {
-                        influxdb2_structmap::value::Value::Long(inner) => {
-                            out.insert(
-                                "no_servers".to_owned(),
-                                serde_json::Value::Number(inner.into()),
-                            );
-                        }
-                        _ => {
-                            error!("no_servers should always be a Long!");
-                        }
-                    }
-                } else if key == "sum_credits_used" {
-                    match value {
-                        influxdb2_structmap::value::Value::Double(inner) => {
-                            out.insert("total_credits_used".to_owned(), json!(f64::from(inner)));
-                        }
-                        _ => {
-                            error!("sum_credits_used should always be a Double!");
-                        }
-                    }
-                } else if key == "sum_request_bytes" {
-                    match value {
-                        influxdb2_structmap::value::Value::Long(inner) => {
-                            out.insert(
-                                "total_request_bytes".to_owned(),
-                                serde_json::Value::Number(inner.into()),
-                            );
-                        }
-                        _ => {
-                            error!("sum_request_bytes should always be a Long!");
-                        }
-                    }
-                } else if key == "sum_response_bytes" {
-                    match value {
-                        influxdb2_structmap::value::Value::Long(inner) => {
-                            out.insert(
-                                "total_response_bytes".to_owned(),
-                                serde_json::Value::Number(inner.into()),
-                            );
-                        }
-                        _ => {
-                            error!("sum_response_bytes should always be a Long!");
-                        }
-                    }
                 } else if key == "rpc_secret_key_id" {
                     match value {
                         influxdb2_structmap::value::Value::String(inner) => {
-                            out.insert(
-                                "rpc_key".to_owned(),
-                                serde_json::Value::String(
-                                    rpc_key_id_to_key.get(&inner).unwrap().to_string(),
-                                ),
-                            );
+                            if let Some(Some(rpc_key)) = rpc_key_id_to_key.get(&inner) {
+                                out.insert(
+                                    "rpc_key".to_owned(),
+                                    serde_json::Value::String(rpc_key.to_string()),
+                                );
+                            }
+                            out.insert("rpc_key_id".to_owned(), serde_json::Value::String(inner));
                         }
                         _ => {
                             error!("rpc_secret_key_id should always be a String!");
                         }
                     }
-                } else if key == "sum_response_millis" {
-                    match value {
-                        influxdb2_structmap::value::Value::Long(inner) => {
-                            out.insert(
-                                "total_response_millis".to_owned(),
-                                serde_json::Value::Number(inner.into()),
-                            );
-                        }
-                        _ => {
-                            error!("sum_response_millis should always be a Long!");
-                        }
-                    }
                 }
                 // Make this if detailed ...
                 else if stat_response_type == StatType::Detailed && key == "method" {
@@ -403,6 +558,15 @@ pub async fn query_user_stats<'a>(
                             error!("method should always be a String!");
                         }
                     }
+                } else if stat_response_type == StatType::Detailed && key == "origin" {
+                    match value {
+                        influxdb2_structmap::value::Value::String(inner) => {
+                            out.insert("origin".to_owned(), serde_json::Value::String(inner));
+                        }
+                        _ => {
+                            error!("origin should always be a String!");
+                        }
+                    }
                 } else if key == "chain_id" {
                     match value {
                         influxdb2_structmap::value::Value::String(inner) => {
@@ -451,11 +615,24 @@ pub async fn query_user_stats<'a>(
                 }
             });
 
+            if hide_balance {
+                for field in STAT_FIELDS.iter().filter(|x| x.balance_sensitive) {
+                    out.remove(field.name);
+                }
+            }
+
+            if let Some(fields) = params.fields.as_ref() {
+                out.retain(|k, _| fields.contains(k));
+            }
+
             // datapoints.insert(out.get("time"), out);
-            json!(out)
+            (cursor, json!(out))
         })
         .collect::<Vec<_>>();
 
+    let (datapoints, next_cursor) =
+        paginate_stats(datapoints, params.cursor.as_ref(), params.limit);
+
     // I suppose archive requests could be either gathered by default (then summed up), or retrieved on a second go.
     // Same with error responses ..
     let mut response_body = HashMap::new();
@@ -464,11 +641,16 @@ pub async fn query_user_stats<'a>(
         serde_json::Value::Number(datapoints.len().into()),
     );
     response_body.insert("result", serde_json::Value::Array(datapoints));
+    response_body.insert("limit", json!(params.limit));
+    // null on the last page
+    response_body.insert("next_cursor", json!(next_cursor.map(|x| x.encode())));
     response_body.insert(
         "query_window_seconds",
         serde_json::Value::Number(query_window_seconds.into()),
     );
     response_body.insert("query_start", serde_json::Value::Number(query_start.into()));
+    response_body.insert("query_stop", serde_json::Value::Number(query_stop.into()));
+    response_body.insert("align", serde_json::Value::Bool(align));
     response_body.insert("chain_id", serde_json::Value::Number(chain_id.into()));
 
     if user_id == 0 {
@@ -478,17 +660,341 @@ pub async fn query_user_stats<'a>(
     }
 
     // Also optionally add the rpc_key_id:
-    if let Some(rpc_key_id) = params.get("rpc_key_id") {
-        let rpc_key_id = rpc_key_id
-            .parse::<u64>()
-            .map_err(|_| Web3ProxyError::BadRequest("Unable to parse rpc_key_id".to_string()))?;
+    if let Some(rpc_key_id) = params.rpc_key_id(user_id) {
         response_body.insert("rpc_key_id", serde_json::Value::Number(rpc_key_id.into()));
     }
 
-    let response = Json(json!(response_body)).into_response();
-    // Add the requests back into out
+    if let Some(org_id) = params.org_id {
+        response_body.insert("org_id", serde_json::Value::Number(org_id.into()));
+    }
 
-    // TODO: Now impplement the proper response type
+    // how stale these numbers might be. null if this server isn't saving stats
+    response_body.insert(
+        "freshness",
+        app.stat_freshness
+            .as_ref()
+            .map(|x| x.snapshot())
+            .unwrap_or_default(),
+    );
 
-    Ok(response)
+    Ok(json!(response_body))
+}
+
+/// Floor the start and stop to multiples of the window so that every query sees the same buckets.
+/// Stop is pushed forward one window if flooring would leave an empty range.
+pub fn align_query_range(
+    query_start: i64,
+    query_stop: i64,
+    query_window_seconds: u64,
+) -> (i64, i64) {
+    let window = query_window_seconds as i64;
+
+    let query_start = query_start.div_euclid(window) * window;
+    let mut query_stop = query_stop.div_euclid(window) * window;
+
+    if query_stop <= query_start {
+        query_stop = query_start + window;
+    }
+
+    (query_start, query_stop)
+}
+
+/// Reject queries that would be expensive for influx before they are sent.
+/// `max_buckets` of 0 allows any number of buckets
+pub fn check_query_limits(
+    query_start: i64,
+    query_stop: i64,
+    query_window_seconds: u64,
+    max_range_seconds: Option<u64>,
+    max_buckets: u64,
+) -> Web3ProxyResult<()> {
+    let range_seconds = query_start.abs_diff(query_stop);
+
+    if let Some(max_range_seconds) = max_range_seconds {
+        if range_seconds > max_range_seconds {
+            return Err(Web3ProxyError::StatsRangeTooLarge {
+                range_seconds,
+                max_range_seconds,
+            });
+        }
+    }
+
+    // round up. a partial window is still a bucket
+    let num_buckets = (range_seconds + query_window_seconds - 1) / query_window_seconds;
+
+    if max_buckets > 0 && num_buckets > max_buckets {
+        return Err(Web3ProxyError::StatsTooManyBuckets {
+            num_buckets,
+            max_buckets,
+            min_window_seconds: (range_seconds + max_buckets - 1) / max_buckets,
+        });
+    }
+
+    Ok(())
+}
+
+/// The influx tags that are dropped so that their series are summed together.
+/// Without `group_by`, aggregated stats drop the method and origin and detailed stats drop the origin
+pub fn dropped_tags(
+    stat_response_type: &StatType,
+    group_by: Option<&[StatDimension]>,
+) -> Vec<&'static str> {
+    StatDimension::ALL
+        .iter()
+        .filter(|x| match group_by {
+            Some(group_by) => !group_by.contains(x),
+            None => {
+                !x.grouped_by_default()
+                    || (x.detailed_only() && *stat_response_type != StatType::Detailed)
+            }
+        })
+        .map(|x| x.influx_tag())
+        .collect()
+}
+
+/// Build the Flux query for `query_user_stats`.
+/// An empty `rpc_secret_key_ids` means every key. A `chain_id` of 0 means every chain.
+/// `group_by` of None keeps the default groups for the stat type.
+#[allow(clippy::too_many_arguments)]
+pub fn build_flux_query(
+    bucket: &str,
+    measurement: &str,
+    query_start: i64,
+    query_stop: i64,
+    query_window_seconds: u64,
+    chain_id: u64,
+    rpc_secret_key_ids: &[String],
+    stat_response_type: &StatType,
+    group_by: Option<&[StatDimension]>,
+) -> String {
+    let rpc_key_filter = if rpc_secret_key_ids.is_empty() {
+        "".to_string()
+    } else {
+        f!(
+            r#"|> filter(fn: (r) => contains(value: r["rpc_secret_key_id"], set: {:?}))"#,
+            rpc_secret_key_ids
+        )
+    };
+
+    let filter_chain_id = if chain_id == 0 {
+        "".to_string()
+    } else {
+        f!(r#"|> filter(fn: (r) => r["chain_id"] == "{chain_id}")"#)
+    };
+
+    let dropped_tags = dropped_tags(stat_response_type, group_by);
+
+    let drop_tags = if dropped_tags.is_empty() {
+        "".to_string()
+    } else {
+        f!(r#"|> drop(columns: {:?})"#, dropped_tags)
+    };
+
+    f!(r#"
+    base = from(bucket: "{bucket}")
+        |> range(start: {query_start}, stop: {query_stop})
+        {rpc_key_filter}
+        |> filter(fn: (r) => r["_measurement"] == "{measurement}")
+        {filter_chain_id}
+        {drop_tags}
+
+    base
+        |> aggregateWindow(every: {query_window_seconds}s, fn: sum, createEmpty: false)
+        |> pivot(rowKey: ["_time"], columnKey: ["_field"], valueColumn: "_value")
+        |> drop(columns: ["balance"])
+        |> group(columns: ["_time", "_measurement", "archive_needed", "chain_id", "error_response", "method", "origin", "rpc_secret_key_id"])
+        |> sort(columns: ["frontend_requests"])
+        |> map(fn:(r) => ({{ r with "sum_credits_used": float(v: r["sum_credits_used"]) }}))
+        |> cumulativeSum(columns: ["backend_requests", "cache_hits", "cache_misses", "frontend_requests", "sum_credits_used", "sum_request_bytes", "sum_response_bytes", "sum_response_millis"])
+        |> sort(columns: ["frontend_requests"], desc: true)
+        |> limit(n: 1)
+        |> group()
+        |> sort(columns: ["_time", "_measurement", "archive_needed", "chain_id", "error_response", "method", "origin", "rpc_secret_key_id"], desc: true)
+    "#)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_align_query_range() {
+        // 2023-05-01 00:00:00
+        let hour = 1682899200;
+
+        assert_eq!(
+            align_query_range(hour, hour + 7200, 3600),
+            (hour, hour + 7200)
+        );
+        assert_eq!(
+            align_query_range(hour + 59, hour + 7259, 3600),
+            (hour, hour + 7200)
+        );
+        assert_eq!(
+            align_query_range(hour + 1800, hour + 3000, 3600),
+            (hour, hour + 3600)
+        );
+        assert_eq!(
+            align_query_range(hour + 61, hour + 119, 60),
+            (hour + 60, hour + 120)
+        );
+    }
+
+    #[test]
+    fn test_check_query_limits() {
+        let day: i64 = 86_400;
+        let max_range = Some(day as u64);
+
+        assert!(check_query_limits(0, day, 60, None, 10_000).is_ok());
+        assert!(check_query_limits(0, day, 60, max_range, 10_000).is_ok());
+
+        assert!(matches!(
+            check_query_limits(0, 2 * day, 60, max_range, 10_000),
+            Err(Web3ProxyError::StatsRangeTooLarge {
+                range_seconds: 172_800,
+                max_range_seconds: 86_400,
+            })
+        ));
+
+        // a year of 1 second buckets
+        assert!(matches!(
+            check_query_limits(0, 365 * day, 1, None, 10_000),
+            Err(Web3ProxyError::StatsTooManyBuckets {
+                num_buckets: 31_536_000,
+                max_buckets: 10_000,
+                min_window_seconds: 3154,
+            })
+        ));
+
+        assert!(check_query_limits(0, 365 * day, 3154, None, 10_000).is_ok());
+        assert!(check_query_limits(0, 365 * day, 1, None, 0).is_ok());
+    }
+
+    #[test]
+    fn test_paginate_stats() {
+        let item = |time: i64, method: &str| {
+            let cursor = StatsCursor {
+                time,
+                groups: vec![method.to_string()],
+            };
+
+            (cursor, format!("{}_{}", time, method))
+        };
+
+        let items = vec![
+            item(60, "eth_call"),
+            item(120, "eth_getBalance"),
+            item(60, "eth_blockNumber"),
+            item(120, "eth_call"),
+        ];
+
+        // newest first. then by group
+        let (page, next_cursor) = paginate_stats(items.clone(), None, Some(3));
+        assert_eq!(
+            page,
+            vec!["120_eth_call", "120_eth_getBalance", "60_eth_blockNumber"]
+        );
+
+        let next_cursor = StatsCursor::decode(&next_cursor.unwrap().encode()).unwrap();
+
+        let (page, next_cursor) = paginate_stats(items.clone(), Some(&next_cursor), Some(3));
+        assert_eq!(page, vec!["60_eth_call"]);
+        assert_eq!(next_cursor, None);
+
+        // without a limit, everything is one page
+        let (page, next_cursor) = paginate_stats(items, None, None);
+        assert_eq!(page.len(), 4);
+        assert_eq!(next_cursor, None);
+
+        assert_eq!(StatsCursor::decode("not a cursor"), None);
+    }
+
+    #[test]
+    fn test_dropped_tags() {
+        assert_eq!(
+            dropped_tags(&StatType::Aggregated, None),
+            vec!["method", "origin"]
+        );
+        assert_eq!(dropped_tags(&StatType::Detailed, None), vec!["origin"]);
+        assert_eq!(
+            dropped_tags(
+                &StatType::Detailed,
+                Some(&[StatDimension::Method, StatDimension::ChainId])
+            ),
+            vec![
+                "archive_needed",
+                "error_response",
+                "origin",
+                "rpc_secret_key_id"
+            ]
+        );
+
+        // origins are only split up when asked for
+        assert_eq!(
+            dropped_tags(
+                &StatType::Detailed,
+                Some(&[StatDimension::Origin, StatDimension::RpcKey])
+            ),
+            vec!["archive_needed", "chain_id", "error_response", "method"]
+        );
+    }
+
+    /// Compare every combination of query parameters against the files in `tests/golden/flux`.
+    /// Run with `UPDATE_GOLDEN=1` to rewrite the files after an intentional change to the query.
+    #[test]
+    fn test_flux_query_golden_files() {
+        let golden_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden/flux");
+
+        let update = std::env::var("UPDATE_GOLDEN").is_ok();
+
+        let keys = ["1".to_string(), "2".to_string()];
+
+        for (stat_type_name, stat_type) in [
+            ("aggregated", StatType::Aggregated),
+            ("detailed", StatType::Detailed),
+        ] {
+            for chain_id in [0, 1] {
+                for (keys_name, rpc_secret_key_ids) in [("all_keys", &[][..]), ("keys", &keys[..])]
+                {
+                    for query_window_seconds in [60, 3600] {
+                        let query = build_flux_query(
+                            "dev_web3_proxy",
+                            "opt_in_proxy",
+                            1682899200,
+                            1682985600,
+                            query_window_seconds,
+                            chain_id,
+                            rpc_secret_key_ids,
+                            &stat_type,
+                            None,
+                        );
+
+                        let name = format!(
+                            "{}_chain_{}_{}_window_{}.flux",
+                            stat_type_name, chain_id, keys_name, query_window_seconds
+                        );
+
+                        let path = golden_dir.join(&name);
+
+                        if update {
+                            fs::create_dir_all(&golden_dir).unwrap();
+                            fs::write(&path, &query).unwrap();
+                            continue;
+                        }
+
+                        let expected = fs::read_to_string(&path)
+                            .unwrap_or_else(|err| panic!("reading {}: {}", name, err));
+
+                        assert_eq!(
+                            query, expected,
+                            "{} changed. run with UPDATE_GOLDEN=1 if this is intentional",
+                            name
+                        );
+                    }
+                }
+            }
+        }
+    }
 }
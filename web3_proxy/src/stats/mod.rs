@@ -1,12 +1,18 @@
 //! Store "stats" in a database for billing and a different database for graphing
 //! TODO: move some of these structs/functions into their own file?
 pub mod db_queries;
+pub mod export;
+mod freshness;
 pub mod influxdb_queries;
+pub mod payload_sample;
+pub mod request_shadow;
+pub mod schema;
 mod stat_buffer;
 
+pub use freshness::StatFreshness;
 pub use stat_buffer::{SpawnedStatBuffer, StatBuffer};
 
-use crate::app::RpcSecretKeyCache;
+use crate::app::{RpcSecretKeyCache, UserBalance};
 use crate::frontend::authorization::{Authorization, RequestMetadata};
 use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
 use crate::rpcs::one::Web3Rpc;
@@ -15,13 +21,17 @@ use axum::headers::Origin;
 use chrono::{DateTime, Months, TimeZone, Utc};
 use derive_more::From;
 use entities::sea_orm_active_enums::TrackingLevel;
-use entities::{balance, referee, referrer, rpc_accounting_v2, rpc_key, user, user_tier};
+use entities::{
+    balance, org, referee, referrer, rpc_accounting_v2, rpc_key, stat_flush, user, user_tier,
+};
+use ethers::types::H256;
+use ethers::utils::keccak256;
 use influxdb2::models::DataPoint;
 use log::{error, trace, warn};
 use migration::sea_orm::prelude::Decimal;
 use migration::sea_orm::{
-    self, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
-    QueryFilter,
+    self, ActiveModelTrait, ColumnTrait, DatabaseConnection, DatabaseTransaction, EntityTrait,
+    IntoActiveModel, QueryFilter, TransactionTrait, UpdateMany,
 };
 use migration::{Expr, OnConflict};
 use num_traits::ToPrimitive;
@@ -30,6 +40,7 @@ use std::cmp::max;
 use std::num::NonZeroU64;
 use std::sync::atomic::{self, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
 use self::stat_buffer::BufferedRpcQueryStats;
 
@@ -41,6 +52,10 @@ pub enum StatType {
 
 pub type BackendRequests = Mutex<Vec<Arc<Web3Rpc>>>;
 
+/// The method on the stat for a batch itself. Only saved if the user's tier has a per-batch cost.
+/// The requests inside the batch get their own stats
+pub const BATCH_STAT_METHOD: &str = "batch";
+
 /// TODO: better name? RpcQueryStatBuilder?
 #[derive(Clone, Debug)]
 pub struct RpcQueryStats {
@@ -48,6 +63,12 @@ pub struct RpcQueryStats {
     pub method: Option<String>,
     pub archive_request: bool,
     pub error_response: bool,
+    /// notifications are requests that did not want a response
+    pub notification: bool,
+    /// the response was larger than the method's max_response_bytes
+    pub response_too_large: bool,
+    /// the key or ip is tagged as a probable bot
+    pub probable_bot: bool,
     pub request_bytes: u64,
     /// if backend_requests is 0, there was a cache_hit
     /// no need to track frontend_request on this. a RpcQueryStats always represents one frontend request
@@ -55,8 +76,16 @@ pub struct RpcQueryStats {
     pub response_bytes: u64,
     pub response_millis: u64,
     pub response_timestamp: i64,
+    /// how long the request waited for the rate limit to reset
+    pub queue_millis: u64,
     /// Credits used signifies how how much money was used up
     pub credits_used: Decimal,
+    /// when the stat was built. used to time how long it takes to reach the databases
+    pub completed_at: Instant,
+    /// None for the stat buffer's own chain. Some for requests to one of the `chains`
+    pub chain_id: Option<u64>,
+    /// Some if the key is cut off when its balance runs out. `credits_used` was already taken from it
+    pub balance: Option<Arc<UserBalance>>,
 }
 
 #[derive(Clone, Debug, From, Hash, PartialEq, Eq)]
@@ -69,12 +98,40 @@ pub struct RpcQueryKey {
     archive_needed: bool,
     /// true if the response was some sort of JSONRPC error
     error_response: bool,
+    /// only in the timeseries db
+    probable_bot: bool,
     /// method tracking is opt-in
     method: Option<String>,
-    /// origin tracking is opt-in
-    origin: Option<Origin>,
+    /// origin tracking is opt-in. see `origin_tag`
+    origin: Option<String>,
     /// None if the public url was used
     rpc_secret_key_id: Option<NonZeroU64>,
+    /// None if the key was used directly instead of through a key pool
+    rpc_key_pool_id: Option<NonZeroU64>,
+    /// None for the stat buffer's own chain
+    chain_id: Option<u64>,
+}
+
+impl RpcQueryKey {
+    /// A deterministic id for one flush of this key's stats.
+    /// Retries of the flush reuse it, so the relational database can tell that it was already saved
+    fn flush_id(&self, chain_id: u64, writer_id: &str, flushed_at_ms: i64) -> String {
+        let x = format!(
+            "{}:{}:{}:{}:{}:{}:{:?}:{:?}:{:?}:{:?}",
+            chain_id,
+            writer_id,
+            flushed_at_ms,
+            self.response_timestamp,
+            self.archive_needed,
+            self.error_response,
+            self.method,
+            self.origin,
+            self.rpc_secret_key_id,
+            self.rpc_key_pool_id,
+        );
+
+        format!("{:?}", H256::from(keccak256(x.as_bytes())))
+    }
 }
 
 /// round the unix epoch time to the start of a period
@@ -82,7 +139,43 @@ fn round_timestamp(timestamp: i64, period_seconds: i64) -> i64 {
     timestamp / period_seconds * period_seconds
 }
 
+/// Lowercase and without a default port so that one site is always one tag value
+fn normalize_origin(origin: &Origin) -> String {
+    let scheme = origin.scheme().to_ascii_lowercase();
+    let hostname = origin.hostname().to_ascii_lowercase();
+
+    match origin.port() {
+        Some(port) if !matches!((scheme.as_str(), port), ("http", 80) | ("https", 443)) => {
+            format!("{}://{}:{}", scheme, hostname, port)
+        }
+        _ => format!("{}://{}", scheme, hostname),
+    }
+}
+
+/// Only origins in the key's `allowed_origins` are saved. Any other origin is "other".
+/// The Origin header is set by the caller, so saving it as-is would let anyone make unlimited tag values
+fn origin_tag(origin: Option<&Origin>, allowed_origins: Option<&Vec<Origin>>) -> Option<String> {
+    let origin = normalize_origin(origin?);
+
+    let allowed = allowed_origins
+        .map(|x| x.iter().any(|x| normalize_origin(x) == origin))
+        .unwrap_or(false);
+
+    if allowed {
+        Some(origin)
+    } else {
+        Some("other".to_string())
+    }
+}
+
 impl RpcQueryStats {
+    fn origin_tag(&self) -> Option<String> {
+        origin_tag(
+            self.authorization.origin.as_ref(),
+            self.authorization.checks.allowed_origins.as_ref(),
+        )
+    }
+
     /// rpc keys can opt into multiple levels of tracking.
     /// we always need enough to handle billing, so even the "none" level still has some minimal tracking.
     /// This "accounting_key" is used in the relational database.
@@ -110,7 +203,7 @@ impl RpcQueryStats {
                 // detailed tracking keeps track of the method and origin
                 // depending on the request, the origin might still be None
                 let method = self.method.clone();
-                let origin = self.authorization.origin.clone();
+                let origin = self.origin_tag();
 
                 (method, origin)
             }
@@ -122,9 +215,13 @@ impl RpcQueryStats {
             response_timestamp,
             archive_needed: self.archive_request,
             error_response: self.error_response,
+            probable_bot: false,
             method,
             rpc_secret_key_id,
+            // the relational database only tracks keys. pools are summed from their keys
+            rpc_key_pool_id: None,
             origin,
+            chain_id: self.chain_id,
         }
     }
 
@@ -133,7 +230,7 @@ impl RpcQueryStats {
     fn global_timeseries_key(&self) -> RpcQueryKey {
         // we include the method because that can be helpful for predicting load
         let method = self.method.clone();
-        // we don't store origin in the timeseries db. its only used for optional accounting
+        // the global stats are not split up by origin. only a key's own detailed stats are
         let origin = None;
         // everyone gets grouped together
         let rpc_secret_key_id = None;
@@ -142,30 +239,30 @@ impl RpcQueryStats {
             response_timestamp: self.response_timestamp,
             archive_needed: self.archive_request,
             error_response: self.error_response,
+            probable_bot: self.probable_bot,
             method,
             rpc_secret_key_id,
+            rpc_key_pool_id: None,
             origin,
+            chain_id: self.chain_id,
         }
     }
 
     /// rpc keys can opt into more detailed tracking
     fn opt_in_timeseries_key(&self) -> Option<RpcQueryKey> {
-        // we don't store origin in the timeseries db. its only optionaly used for accounting
-        let origin = None;
-
-        // depending on tracking level, we either skip opt-in stats, track without method, or track with method
-        let method = match self.authorization.checks.tracking_level {
+        // depending on tracking level, we either skip opt-in stats, track without method, or track with method and origin
+        let (method, origin) = match self.authorization.checks.tracking_level {
             TrackingLevel::None => {
                 // this RPC key requested no tracking. this is the default.
                 return None;
             }
             TrackingLevel::Aggregated => {
                 // this RPC key requested tracking aggregated across all methods
-                None
+                (None, None)
             }
             TrackingLevel::Detailed => {
-                // detailed tracking keeps track of the method
-                self.method.clone()
+                // detailed tracking keeps track of the method and origin so users can see which of their sites the requests are from
+                (self.method.clone(), self.origin_tag())
             }
         };
 
@@ -173,9 +270,12 @@ impl RpcQueryStats {
             response_timestamp: self.response_timestamp,
             archive_needed: self.archive_request,
             error_response: self.error_response,
+            probable_bot: self.probable_bot,
             method,
             rpc_secret_key_id: self.authorization.checks.rpc_secret_key_id,
+            rpc_key_pool_id: self.authorization.checks.rpc_key_pool_id,
             origin,
+            chain_id: self.chain_id,
         };
 
         Some(key)
@@ -195,6 +295,14 @@ impl BufferedRpcQueryStats {
         // a stat always come from just 1 frontend request
         self.frontend_requests += 1;
 
+        if stat.notification {
+            self.notifications += 1;
+        }
+
+        if stat.response_too_large {
+            self.response_too_large += 1;
+        }
+
         // TODO: is this always okay? is it true that each backend rpc will only be queried once per request? i think so
         let num_backend_rpcs_used = stat.backend_rpcs_used.len() as u64;
 
@@ -212,6 +320,7 @@ impl BufferedRpcQueryStats {
         self.sum_request_bytes += stat.request_bytes;
         self.sum_response_bytes += stat.response_bytes;
         self.sum_response_millis += stat.response_millis;
+        self.sum_queue_millis += stat.queue_millis;
         self.sum_credits_used += stat.credits_used;
 
         // Also record the latest balance for this user ..
@@ -220,14 +329,64 @@ impl BufferedRpcQueryStats {
             .checks
             .balance
             .unwrap_or(Decimal::from(0));
+
+        if stat.balance.is_some() {
+            self.balance = stat.balance;
+        }
     }
 
-    // TODO: take a db transaction instead so that we can batch?
+    /// The database has these credits now (or never will). They stop being held against the in-memory balance
+    pub fn settle_balance(&self) {
+        if let Some(balance) = self.balance.as_ref() {
+            balance.settle(self.sum_credits_used);
+        }
+    }
+
+    /// Add the stat to the accounting tables and balances in one transaction.
+    /// Returns false without changing anything if `flush_id` was already saved
     async fn save_db(
-        self,
+        &self,
         chain_id: u64,
         db_conn: &DatabaseConnection,
-        key: RpcQueryKey,
+        key: &RpcQueryKey,
+        flush_id: &str,
+        rpc_secret_key_cache: Option<&RpcSecretKeyCache>,
+    ) -> Web3ProxyResult<bool> {
+        let txn = db_conn.begin().await?;
+
+        // the last attempt might have committed even though it looked like it failed
+        if stat_flush::Entity::find()
+            .filter(stat_flush::Column::FlushId.eq(flush_id))
+            .one(&txn)
+            .await?
+            .is_some()
+        {
+            return Ok(false);
+        }
+
+        // the unique index stops two attempts that race each other from both committing
+        let flush = stat_flush::ActiveModel {
+            id: sea_orm::NotSet,
+            flush_id: sea_orm::Set(flush_id.to_string()),
+            chain_id: sea_orm::Set(chain_id),
+            created_at: sea_orm::NotSet,
+        };
+
+        stat_flush::Entity::insert(flush).exec(&txn).await?;
+
+        self._save_db(chain_id, &txn, key, rpc_secret_key_cache)
+            .await?;
+
+        txn.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn _save_db(
+        &self,
+        chain_id: u64,
+        db_conn: &DatabaseTransaction,
+        key: &RpcQueryKey,
         rpc_secret_key_cache: Option<&RpcSecretKeyCache>,
     ) -> Web3ProxyResult<()> {
         if key.response_timestamp == 0 {
@@ -335,8 +494,8 @@ impl BufferedRpcQueryStats {
             .await?;
 
         // Technicall there should always be a user ... still let's return "Ok(())" for now
-        let sender_user_id: u64 = match sender_rpc_key {
-            Some(x) => x.user_id,
+        let (sender_user_id, sender_org_id) = match sender_rpc_key {
+            Some(x) => (x.user_id, x.org_id),
             // Return early if the User is not found, because then it is an anonymous user
             // Let's also issue a warning because obviously the RPC key should correspond to a user
             None => {
@@ -354,6 +513,20 @@ impl BufferedRpcQueryStats {
             return Ok(());
         }
 
+        // org keys are billed to the org. there are no referral credits or downgrades for orgs
+        if let Some(org_id) = sender_org_id {
+            let debited = org_debit(org_id, self.sum_credits_used)
+                .exec(db_conn)
+                .await?
+                .rows_affected;
+
+            if debited == 0 {
+                warn!("No org was found for the following id: {:?}", org_id);
+            }
+
+            return Ok(());
+        }
+
         let sender_balance = match balance::Entity::find()
             .filter(balance::Column::UserId.eq(sender_user_id))
             .one(db_conn)
@@ -538,10 +711,10 @@ impl BufferedRpcQueryStats {
     }
 
     async fn build_timeseries_point(
-        self,
+        &self,
         measurement: &str,
         chain_id: u64,
-        key: RpcQueryKey,
+        key: &RpcQueryKey,
     ) -> anyhow::Result<DataPoint> {
         let mut builder = DataPoint::builder(measurement);
 
@@ -551,21 +724,33 @@ impl BufferedRpcQueryStats {
             builder = builder.tag("rpc_secret_key_id", rpc_secret_key_id.to_string());
         }
 
-        if let Some(method) = key.method {
+        if let Some(rpc_key_pool_id) = key.rpc_key_pool_id {
+            builder = builder.tag("rpc_key_pool_id", rpc_key_pool_id.to_string());
+        }
+
+        if let Some(method) = key.method.as_ref() {
             builder = builder.tag("method", method);
         }
 
+        if let Some(origin) = key.origin.as_ref() {
+            builder = builder.tag("origin", origin);
+        }
+
         builder = builder
             .tag("archive_needed", key.archive_needed.to_string())
             .tag("error_response", key.error_response.to_string())
+            .tag("probable_bot", key.probable_bot.to_string())
             .field("frontend_requests", self.frontend_requests as i64)
             .field("backend_requests", self.backend_requests as i64)
             .field("no_servers", self.no_servers as i64)
+            .field("notifications", self.notifications as i64)
+            .field("response_too_large", self.response_too_large as i64)
             .field("cache_misses", self.cache_misses as i64)
             .field("cache_hits", self.cache_hits as i64)
             .field("sum_request_bytes", self.sum_request_bytes as i64)
             .field("sum_response_millis", self.sum_response_millis as i64)
             .field("sum_response_bytes", self.sum_response_bytes as i64)
+            .field("sum_queue_millis", self.sum_queue_millis as i64)
             // TODO: will this be enough of a range
             // I guess Decimal can be a f64
             // TODO: This should prob be a float, i should change the query if we want float-precision for this (which would be important...)
@@ -641,24 +826,54 @@ impl TryFrom<RequestMetadata> for RpcQueryStats {
 
         let method = metadata.method.take();
 
-        let credits_used = Self::compute_cost(
+        let notification = metadata.notification;
+
+        let response_too_large = metadata.response_too_large.load(Ordering::Acquire);
+
+        let probable_bot = metadata.probable_bot;
+
+        let queue_millis = authorization.rate_limit_queue_millis;
+
+        let mut credits_used = Self::compute_cost(
             request_bytes,
             response_bytes,
             backend_rpcs_used.is_empty(),
             method.as_deref(),
         );
 
+        if method.as_deref() == Some(BATCH_STAT_METHOD) {
+            credits_used += authorization.checks.batch_overhead_cost.unwrap_or_default();
+        }
+
+        // the classifier script's price for this request
+        if let Some(x) = metadata.classification.get().and_then(|x| x.credits) {
+            credits_used += x;
+        }
+
+        let balance = metadata.balance.take();
+
+        if let Some(balance) = balance.as_ref() {
+            balance.spend(credits_used);
+        }
+
         let x = Self {
             authorization,
             archive_request,
             method,
             backend_rpcs_used,
+            notification,
             request_bytes,
+            response_too_large,
+            probable_bot,
             error_response,
             response_bytes,
             response_millis,
             response_timestamp,
+            queue_millis,
             credits_used,
+            completed_at: Instant::now(),
+            chain_id: metadata.chain_id,
+            balance,
         };
 
         Ok(x)
@@ -714,3 +929,95 @@ impl RpcQueryStats {
         */
     }
 }
+
+/// Charge an org for credits it used. The math is done in mysql so that concurrent flushes can't overwrite each other.
+/// The available balance stops at 0
+fn org_debit(org_id: u64, credits_used: Decimal) -> UpdateMany<org::Entity> {
+    org::Entity::update_many()
+        .col_expr(
+            org::Column::UsedBalance,
+            Expr::col(org::Column::UsedBalance).add(credits_used),
+        )
+        .col_expr(
+            org::Column::AvailableBalance,
+            Expr::cust_with_values("GREATEST(`available_balance` - ?, 0)", [credits_used]),
+        )
+        .filter(org::Column::Id.eq(org_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{org_debit, origin_tag, RpcQueryKey};
+    use axum::headers::Origin;
+    use migration::sea_orm::{prelude::Decimal, DbBackend, QueryTrait};
+    use std::num::NonZeroU64;
+
+    #[test]
+    fn test_flush_id() {
+        let key = RpcQueryKey {
+            response_timestamp: 1_687_392_000,
+            archive_needed: false,
+            error_response: false,
+            probable_bot: false,
+            method: None,
+            origin: None,
+            rpc_secret_key_id: NonZeroU64::new(1),
+            rpc_key_pool_id: None,
+            chain_id: None,
+        };
+
+        let a = key.flush_id(1, "writer", 1_687_392_060_000);
+
+        // retries get the same id
+        assert_eq!(a, key.flush_id(1, "writer", 1_687_392_060_000));
+        assert_eq!(a.len(), 66);
+
+        // the next flush (or another proxy) gets a new one
+        assert_ne!(a, key.flush_id(1, "writer", 1_687_392_120_000));
+        assert_ne!(a, key.flush_id(1, "other_writer", 1_687_392_060_000));
+    }
+
+    #[test]
+    fn test_origin_tag() {
+        let allowed = vec![
+            Origin::try_from_parts("https", "app.example.com", None).unwrap(),
+            Origin::try_from_parts("http", "localhost", 3000).unwrap(),
+        ];
+
+        let example = Origin::try_from_parts("HTTPS", "App.Example.com", 443).unwrap();
+        let localhost = Origin::try_from_parts("http", "localhost", 3000).unwrap();
+        let evil = Origin::try_from_parts("https", "evil.example.com", None).unwrap();
+
+        // default ports and case don't make new values
+        assert_eq!(
+            origin_tag(Some(&example), Some(&allowed)).as_deref(),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            origin_tag(Some(&localhost), Some(&allowed)).as_deref(),
+            Some("http://localhost:3000")
+        );
+
+        assert_eq!(
+            origin_tag(Some(&evil), Some(&allowed)).as_deref(),
+            Some("other")
+        );
+
+        // keys that allow every origin can't have unlimited tag values either
+        assert_eq!(origin_tag(Some(&example), None).as_deref(), Some("other"));
+
+        assert_eq!(origin_tag(None, Some(&allowed)), None);
+    }
+
+    #[test]
+    fn test_org_debit() {
+        let sql = org_debit(7, Decimal::new(125, 2))
+            .build(DbBackend::MySql)
+            .to_string();
+
+        // both balances are changed relative to what is in the row instead of being overwritten
+        assert!(sql.contains("`used_balance` = `used_balance` + 1.25"));
+        assert!(sql.contains("`available_balance` = GREATEST(`available_balance` - 1.25, 0)"));
+        assert!(sql.ends_with("WHERE `org`.`id` = 7"));
+    }
+}
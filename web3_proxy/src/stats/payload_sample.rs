@@ -0,0 +1,197 @@
+//! Save a small random sample of full requests to kafka for offline analysis of real-world query shapes.
+//! Keys can opt out. Anything that looks like a secret is redacted before it leaves the proxy.
+use crate::app::Web3ProxyApp;
+use crate::frontend::authorization::{Authorization, RequestMetadata};
+use crate::jsonrpc::JsonRpcRequest;
+use ethers::types::U64;
+use log::error;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::util::Timeout as KafkaTimeout;
+use serde_json::json;
+use std::fmt;
+use std::sync::atomic;
+use std::time::Duration;
+use thread_fast_rng::rand::Rng;
+
+pub const REDACTED: &str = "[redacted]";
+
+/// these methods take passwords or private keys as params. their params are never saved
+const SECRET_METHODS: &[&str] = &[
+    "personal_importRawKey",
+    "personal_newAccount",
+    "personal_sendTransaction",
+    "personal_sign",
+    "personal_signTransaction",
+    "personal_unlockAccount",
+];
+
+/// object keys that probably hold a secret. matched case-insensitively anywhere in the params
+const SECRET_FIELDS: &[&str] = &[
+    "accesstoken",
+    "apikey",
+    "mnemonic",
+    "passphrase",
+    "password",
+    "privatekey",
+    "secret",
+];
+
+/// A request that was picked for sampling. It is sent once the response is done
+pub struct PayloadSample {
+    producer: FutureProducer,
+    topic: String,
+    chain_id: u64,
+    head_block_num: Option<U64>,
+    method: String,
+    params: serde_json::Value,
+}
+
+impl fmt::Debug for PayloadSample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PayloadSample")
+            .field("topic", &self.topic)
+            .field("method", &self.method)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PayloadSample {
+    /// Roll the dice for this request. None if it was not picked or sampling is disabled
+    pub fn try_new(
+        app: &Web3ProxyApp,
+        authorization: &Authorization,
+        request: &JsonRpcRequest,
+        head_block_num: Option<&U64>,
+    ) -> Option<Self> {
+        let per_million = app.config.payload_samples_per_million;
+
+        if per_million == 0
+            || authorization.is_internal()
+            || authorization.checks.payload_sampling_opt_out
+        {
+            return None;
+        }
+
+        if thread_fast_rng::thread_fast_rng().gen_range(0..1_000_000) >= per_million {
+            return None;
+        }
+
+        let producer = app.kafka_producer.clone()?;
+
+        let params = if SECRET_METHODS.contains(&request.method.as_str()) {
+            json!(REDACTED)
+        } else {
+            request.params.as_ref().map(redact).unwrap_or_default()
+        };
+
+        let head_block_num = head_block_num
+            .copied()
+            .or_else(|| app.balanced_rpcs.head_block_num());
+
+        Some(Self {
+            producer,
+            topic: app.config.payload_sample_topic.clone(),
+            chain_id: app.config.chain_id,
+            head_block_num,
+            method: request.method.clone(),
+            params,
+        })
+    }
+
+    /// Add the response's metadata and send the sample in the background.
+    /// The response body is not saved. Only its size and timing
+    pub fn send(self, request_metadata: &RequestMetadata) {
+        let rpc_secret_key_id = request_metadata
+            .authorization
+            .as_ref()
+            .and_then(|x| x.checks.rpc_secret_key_id)
+            .map(|x| x.get());
+
+        let backend_rpcs: Vec<_> = request_metadata
+            .backend_rpcs_used()
+            .iter()
+            .map(|x| x.name.clone())
+            .collect();
+
+        let sample = json!({
+            "request_ulid": request_metadata.request_ulid.to_string(),
+            "chain_id": self.chain_id,
+            "head_block_num": self.head_block_num,
+            "rpc_secret_key_id": rpc_secret_key_id,
+            "method": self.method,
+            "params": self.params,
+            "request_bytes": request_metadata.request_bytes,
+            "response_bytes": request_metadata.response_bytes.load(atomic::Ordering::Acquire),
+            "response_millis": request_metadata.response_millis.load(atomic::Ordering::Acquire),
+            "response_too_large": request_metadata.response_too_large.load(atomic::Ordering::Acquire),
+            "error_response": request_metadata.error_response.load(atomic::Ordering::Acquire),
+            "archive_request": request_metadata.archive_request.load(atomic::Ordering::Acquire),
+            "cache_hit": backend_rpcs.is_empty(),
+            "backend_rpcs": backend_rpcs,
+        });
+
+        let payload = sample.to_string();
+        let key = self.method;
+
+        let f = async move {
+            let record = FutureRecord::to(&self.topic).key(&key).payload(&payload);
+
+            if let Err((err, _)) = self
+                .producer
+                .send(record, KafkaTimeout::After(Duration::from_secs(60)))
+                .await
+            {
+                // samples are best effort. no need to retry
+                error!("produce payload sample: {}", err);
+            }
+        };
+
+        tokio::spawn(f);
+    }
+}
+
+/// Replace anything that looks like a secret
+fn redact(value: &serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Array(x) => x.iter().map(redact).collect(),
+        serde_json::Value::Object(x) => x
+            .iter()
+            .map(|(k, v)| {
+                let k_lower = k.to_ascii_lowercase();
+
+                let v = if SECRET_FIELDS.iter().any(|x| k_lower.contains(x)) {
+                    json!(REDACTED)
+                } else {
+                    redact(v)
+                };
+
+                (k.clone(), v)
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        x => x.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let params = json!([
+            {"to": "0x00000000000000000000000000000000deadbeef", "data": "0x"},
+            {"apiKey": "abc", "nested": [{"PrivateKey": "0x01", "blockHash": "0x02"}]},
+            "latest"
+        ]);
+
+        assert_eq!(
+            redact(&params),
+            json!([
+                {"to": "0x00000000000000000000000000000000deadbeef", "data": "0x"},
+                {"apiKey": REDACTED, "nested": [{"PrivateKey": REDACTED, "blockHash": "0x02"}]},
+                "latest"
+            ])
+        );
+    }
+}
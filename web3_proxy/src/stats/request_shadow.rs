@@ -0,0 +1,275 @@
+//! Signed webhooks with the metadata of every request made with a key. A personal audit feed without access to influx.
+//!
+//! Keys on the tiers in `request_shadowing.tiers` can set a `shadow_url`. Requests are queued when their stat is sent
+//! and each key's queued requests are POSTed as one JSON array every `flush_millis`.
+//! The body is signed with HMAC-SHA256 of "{timestamp}.{body}" using the key's shadow secret.
+//! The timestamp is in `X-Web3-Proxy-Timestamp` and the hex signature is in `X-Web3-Proxy-Signature`.
+//! Deliveries are not retried. Params and responses are never sent.
+use crate::app::Web3ProxyApp;
+use crate::frontend::authorization::{Authorization, RequestMetadata};
+use crate::frontend::errors::Web3ProxyResult;
+use chrono::Utc;
+use ethers::utils::hex;
+use hashbrown::HashMap;
+use log::{trace, warn};
+use ring::hmac;
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic;
+use std::sync::Arc;
+use tokio::time::{timeout_at, Duration, Instant};
+
+pub const SHADOW_TIMESTAMP_HEADER: &str = "X-Web3-Proxy-Timestamp";
+pub const SHADOW_SIGNATURE_HEADER: &str = "X-Web3-Proxy-Signature";
+
+/// Where one key's requests are sent
+pub struct ShadowTarget {
+    pub rpc_key_id: u64,
+    pub url: String,
+    key: hmac::Key,
+}
+
+impl fmt::Debug for ShadowTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the key is a secret
+        f.debug_struct("ShadowTarget")
+            .field("rpc_key_id", &self.rpc_key_id)
+            .field("url", &self.url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ShadowTarget {
+    pub fn new(rpc_key_id: u64, url: String, secret: &str) -> Self {
+        Self {
+            rpc_key_id,
+            url,
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+        }
+    }
+
+    /// hex HMAC-SHA256 of "{timestamp}.{body}"
+    pub fn sign(&self, timestamp: i64, body: &str) -> String {
+        let tag = hmac::sign(&self.key, format!("{}.{}", timestamp, body).as_bytes());
+
+        hex::encode(tag.as_ref())
+    }
+}
+
+/// One request's metadata. This is all that is sent
+#[derive(Debug, Serialize)]
+pub struct ShadowEvent {
+    #[serde(skip)]
+    target: Arc<ShadowTarget>,
+    request_ulid: String,
+    rpc_key_id: u64,
+    method: Option<String>,
+    /// unix timestamp of the response
+    timestamp: i64,
+    request_bytes: usize,
+    response_bytes: u64,
+    response_millis: u64,
+    error_response: bool,
+    cache_hit: bool,
+    archive_request: bool,
+}
+
+/// A request with a key that has a shadow url. It is queued once the response is done
+#[derive(Debug)]
+pub struct RequestShadow {
+    sender: flume::Sender<ShadowEvent>,
+    target: Arc<ShadowTarget>,
+}
+
+impl RequestShadow {
+    /// None if shadowing is off or the key has no shadow url
+    pub fn try_new(app: &Web3ProxyApp, authorization: &Authorization) -> Option<Self> {
+        let target = authorization.checks.request_shadow.clone()?;
+        let sender = app.request_shadow_sender.clone()?;
+
+        Some(Self { sender, target })
+    }
+
+    /// Queue the request's metadata. If the queue is full, it is dropped
+    pub fn send(self, request_metadata: &RequestMetadata) {
+        let event = ShadowEvent {
+            rpc_key_id: self.target.rpc_key_id,
+            request_ulid: request_metadata.request_ulid.to_string(),
+            method: request_metadata.method.clone(),
+            timestamp: request_metadata
+                .response_timestamp
+                .load(atomic::Ordering::Acquire),
+            request_bytes: request_metadata.request_bytes,
+            response_bytes: request_metadata
+                .response_bytes
+                .load(atomic::Ordering::Acquire),
+            response_millis: request_metadata
+                .response_millis
+                .load(atomic::Ordering::Acquire),
+            error_response: request_metadata
+                .error_response
+                .load(atomic::Ordering::Acquire),
+            cache_hit: request_metadata.backend_requests.lock().is_empty(),
+            archive_request: request_metadata
+                .archive_request
+                .load(atomic::Ordering::Acquire),
+            target: self.target,
+        };
+
+        if let Err(err) = self.sender.try_send(event) {
+            trace!("dropping request shadow: {:?}", err);
+        }
+    }
+}
+
+/// Split a batch up by key. Keys keep the order that their requests were queued in
+fn group_by_key(events: Vec<ShadowEvent>) -> Vec<(Arc<ShadowTarget>, Vec<ShadowEvent>)> {
+    let mut groups: Vec<(Arc<ShadowTarget>, Vec<ShadowEvent>)> = vec![];
+    let mut index: HashMap<_, usize> = HashMap::new();
+
+    for event in events {
+        match index.get(&event.rpc_key_id) {
+            Some(&i) => groups[i].1.push(event),
+            None => {
+                index.insert(event.rpc_key_id, groups.len());
+                groups.push((event.target.clone(), vec![event]));
+            }
+        }
+    }
+
+    groups
+}
+
+impl Web3ProxyApp {
+    /// Batch queued requests and send them to their keys' shadow urls
+    pub(crate) async fn watch_request_shadows(
+        self: Arc<Self>,
+        receiver: flume::Receiver<ShadowEvent>,
+    ) -> Web3ProxyResult<()> {
+        let config = &self.config.request_shadowing;
+
+        let http_client = match self.http_client.clone() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let flush = Duration::from_millis(config.flush_millis.max(1));
+        let max_batch = config.max_batch.max(1);
+        let request_timeout = Duration::from_secs(config.timeout_seconds.max(1));
+
+        while let Ok(first) = receiver.recv_async().await {
+            let deadline = Instant::now() + flush;
+
+            let mut events = vec![first];
+
+            while events.len() < max_batch {
+                match timeout_at(deadline, receiver.recv_async()).await {
+                    Ok(Ok(x)) => events.push(x),
+                    _ => break,
+                }
+            }
+
+            for (target, events) in group_by_key(events) {
+                let http_client = http_client.clone();
+
+                // a slow endpoint only delays its own key
+                tokio::spawn(async move {
+                    let body = match serde_json::to_string(&events) {
+                        Ok(x) => x,
+                        Err(err) => {
+                            warn!("unable to serialize request shadows: {:?}", err);
+                            return;
+                        }
+                    };
+
+                    let timestamp = Utc::now().timestamp();
+                    let signature = target.sign(timestamp, &body);
+
+                    if let Err(err) = http_client
+                        .post(&target.url)
+                        .timeout(request_timeout)
+                        .header("content-type", "application/json")
+                        .header(SHADOW_TIMESTAMP_HEADER, timestamp)
+                        .header(SHADOW_SIGNATURE_HEADER, signature)
+                        .body(body)
+                        .send()
+                        .await
+                        .and_then(|x| x.error_for_status())
+                    {
+                        // the url is user provided. failures are expected and are not our problem
+                        trace!(
+                            "request shadows for key {} not delivered: {:?}",
+                            target.rpc_key_id,
+                            err
+                        );
+                    }
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(target: &Arc<ShadowTarget>, request_ulid: &str) -> ShadowEvent {
+        ShadowEvent {
+            target: target.clone(),
+            request_ulid: request_ulid.to_string(),
+            rpc_key_id: target.rpc_key_id,
+            method: Some("eth_blockNumber".to_string()),
+            timestamp: 0,
+            request_bytes: 0,
+            response_bytes: 0,
+            response_millis: 0,
+            error_response: false,
+            cache_hit: true,
+            archive_request: false,
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        let target = ShadowTarget::new(1, "https://example.com".to_string(), "secret");
+
+        let signature = target.sign(1_700_000_000, "[]");
+
+        assert_eq!(signature.len(), 64);
+        assert_eq!(signature, target.sign(1_700_000_000, "[]"));
+        assert_ne!(signature, target.sign(1_700_000_001, "[]"));
+
+        let other = ShadowTarget::new(1, "https://example.com".to_string(), "other");
+        assert_ne!(signature, other.sign(1_700_000_000, "[]"));
+    }
+
+    #[test]
+    fn test_group_by_key() {
+        let a = Arc::new(ShadowTarget::new(
+            1,
+            "https://a.example.com".to_string(),
+            "a",
+        ));
+        let b = Arc::new(ShadowTarget::new(
+            2,
+            "https://b.example.com".to_string(),
+            "b",
+        ));
+
+        let groups = group_by_key(vec![event(&a, "1"), event(&b, "2"), event(&a, "3")]);
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].0.rpc_key_id, 1);
+        assert_eq!(
+            groups[0]
+                .1
+                .iter()
+                .map(|x| x.request_ulid.as_str())
+                .collect::<Vec<_>>(),
+            vec!["1", "3"]
+        );
+        assert_eq!(groups[1].1.len(), 1);
+    }
+}
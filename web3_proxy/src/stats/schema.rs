@@ -0,0 +1,390 @@
+//! What `/user/stats/aggregated` and `/user/stats/detailed` return.
+//! `query_user_stats` renames influx fields with this, so `/user/stats/schema` can not drift from the real responses.
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatValueKind {
+    Bool,
+    Float,
+    Integer,
+    String,
+    /// RFC 3339
+    Time,
+}
+
+/// One key on each item in a stats response's "result"
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct StatField {
+    /// key in the response
+    pub name: &'static str,
+    /// key in influx. None if the proxy computes it
+    #[serde(skip)]
+    pub influx_field: Option<&'static str>,
+    pub kind: StatValueKind,
+    pub unit: Option<&'static str>,
+    /// summed over the window
+    pub summed: bool,
+    /// only on `/user/stats/detailed`
+    pub detailed_only: bool,
+    /// removed for subusers that can not view the key's balance
+    pub balance_sensitive: bool,
+    pub description: &'static str,
+}
+
+impl StatField {
+    const fn summed(
+        name: &'static str,
+        influx_field: &'static str,
+        kind: StatValueKind,
+        unit: &'static str,
+        description: &'static str,
+    ) -> Self {
+        Self {
+            name,
+            influx_field: Some(influx_field),
+            kind,
+            unit: Some(unit),
+            summed: true,
+            detailed_only: false,
+            balance_sensitive: false,
+            description,
+        }
+    }
+
+    const fn group(
+        name: &'static str,
+        kind: StatValueKind,
+        detailed_only: bool,
+        description: &'static str,
+    ) -> Self {
+        Self {
+            name,
+            influx_field: None,
+            kind,
+            unit: None,
+            summed: false,
+            detailed_only,
+            balance_sensitive: false,
+            description,
+        }
+    }
+}
+
+/// Every numeric field. Each is summed over the query window
+pub const STAT_FIELDS: &[StatField] = &[
+    StatField::summed(
+        "total_frontend_requests",
+        "frontend_requests",
+        StatValueKind::Integer,
+        "requests",
+        "requests sent to the proxy",
+    ),
+    StatField::summed(
+        "total_backend_requests",
+        "backend_requests",
+        StatValueKind::Integer,
+        "requests",
+        "requests the proxy sent to backend rpcs. retries are included",
+    ),
+    StatField::summed(
+        "total_cache_hits",
+        "cache_hits",
+        StatValueKind::Integer,
+        "requests",
+        "requests answered from the proxy's cache",
+    ),
+    StatField::summed(
+        "total_cache_misses",
+        "cache_misses",
+        StatValueKind::Integer,
+        "requests",
+        "requests that needed a backend rpc",
+    ),
+    StatField::summed(
+        "no_servers",
+        "no_servers",
+        StatValueKind::Integer,
+        "requests",
+        "requests that failed because no backend rpc could serve them",
+    ),
+    StatField::summed(
+        "total_request_bytes",
+        "sum_request_bytes",
+        StatValueKind::Integer,
+        "bytes",
+        "size of the requests",
+    ),
+    StatField::summed(
+        "total_response_bytes",
+        "sum_response_bytes",
+        StatValueKind::Integer,
+        "bytes",
+        "size of the responses",
+    ),
+    StatField::summed(
+        "total_response_millis",
+        "sum_response_millis",
+        StatValueKind::Integer,
+        "milliseconds",
+        "time spent responding. divide by total_frontend_requests for the average",
+    ),
+    StatField {
+        balance_sensitive: true,
+        ..StatField::summed(
+            "total_credits_used",
+            "sum_credits_used",
+            StatValueKind::Float,
+            "credits",
+            "credits spent on the requests",
+        )
+    },
+    StatField {
+        summed: false,
+        balance_sensitive: true,
+        ..StatField::summed(
+            "balance",
+            "balance",
+            StatValueKind::Float,
+            "credits",
+            "the user's balance at the end of the window",
+        )
+    },
+];
+
+/// Every key that a stats item is grouped by. Items with the same groups are in one bucket
+pub const STAT_GROUPS: &[StatField] = &[
+    StatField::group(
+        "time",
+        StatValueKind::Time,
+        false,
+        "end of the window that the item covers",
+    ),
+    StatField::group(
+        "stop_time",
+        StatValueKind::Time,
+        false,
+        "end of the query range",
+    ),
+    StatField::group(
+        "collection",
+        StatValueKind::String,
+        false,
+        "\"global\" for anonymous queries. \"opt-in\" for a user's own keys",
+    ),
+    StatField::group("chain_id", StatValueKind::String, false, "the chain served"),
+    StatField::group(
+        "rpc_key",
+        StatValueKind::String,
+        false,
+        "the key that made the requests. only on a user's own stats",
+    ),
+    StatField::group(
+        "rpc_key_id",
+        StatValueKind::String,
+        false,
+        "the id of the key that made the requests. only on a user's own stats",
+    ),
+    StatField::group(
+        "archive_needed",
+        StatValueKind::Bool,
+        false,
+        "if the requests needed an archive node",
+    ),
+    StatField::group(
+        "error_response",
+        StatValueKind::Bool,
+        false,
+        "if the requests got an error response",
+    ),
+    StatField::group(
+        "method",
+        StatValueKind::String,
+        true,
+        "the json-rpc method. keys that do not track methods are not split up",
+    ),
+    StatField::group(
+        "origin",
+        StatValueKind::String,
+        true,
+        "the Origin header of the requests, like a dapp's domain. origins that are not in the key's allowed_origins are \"other\". only with group_by=origin on keys that track methods",
+    ),
+];
+
+/// A tag that `group_by` can split stats items by. Tags that are not selected are summed together
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatDimension {
+    ArchiveNeeded,
+    ChainId,
+    ErrorResponse,
+    Method,
+    Origin,
+    RpcKey,
+}
+
+impl StatDimension {
+    pub const ALL: &'static [Self] = &[
+        Self::ArchiveNeeded,
+        Self::ChainId,
+        Self::ErrorResponse,
+        Self::Method,
+        Self::Origin,
+        Self::RpcKey,
+    ];
+
+    /// the name in `group_by`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ArchiveNeeded => "archive_needed",
+            Self::ChainId => "chain_id",
+            Self::ErrorResponse => "error_response",
+            Self::Method => "method",
+            Self::Origin => "origin",
+            Self::RpcKey => "rpc_key",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().find(|x| x.name() == name).copied()
+    }
+
+    /// the tag in influx
+    pub fn influx_tag(&self) -> &'static str {
+        match self {
+            Self::RpcKey => "rpc_secret_key_id",
+            x => x.name(),
+        }
+    }
+
+    /// methods and origins are only split up on `/user/stats/detailed`
+    pub fn detailed_only(&self) -> bool {
+        matches!(self, Self::Method | Self::Origin)
+    }
+
+    /// origins are only split up if `group_by` asks for them
+    pub fn grouped_by_default(&self) -> bool {
+        !matches!(self, Self::Origin)
+    }
+}
+
+/// A query param that filters or shapes a stats query
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct StatFilter {
+    pub name: &'static str,
+    pub kind: StatValueKind,
+    pub default: &'static str,
+    pub description: &'static str,
+}
+
+pub const STAT_FILTERS: &[StatFilter] = &[
+    StatFilter {
+        name: "chain_id",
+        kind: StatValueKind::Integer,
+        default: "this proxy's chain",
+        description: "0 for every chain",
+    },
+    StatFilter {
+        name: "query_start",
+        kind: StatValueKind::Integer,
+        default: "30 days ago",
+        description: "unix epoch seconds",
+    },
+    StatFilter {
+        name: "query_stop",
+        kind: StatValueKind::Integer,
+        default: "now",
+        description: "unix epoch seconds",
+    },
+    StatFilter {
+        name: "query_window_seconds",
+        kind: StatValueKind::Integer,
+        default: "60",
+        description: "how many seconds each item sums over",
+    },
+    StatFilter {
+        name: "align",
+        kind: StatValueKind::Bool,
+        default: "true",
+        description: "floor query_start and query_stop to the window so repeated queries get the same buckets",
+    },
+    StatFilter {
+        name: "org_id",
+        kind: StatValueKind::Integer,
+        default: "none",
+        description: "sum the org's keys and every member's keys. spend is only shown to owners and billing admins",
+    },
+    StatFilter {
+        name: "fields",
+        kind: StatValueKind::String,
+        default: "every field",
+        description: "comma separated names of the fields to return on each item. fewer fields make high resolution charts smaller",
+    },
+    StatFilter {
+        name: "group_by",
+        kind: StatValueKind::String,
+        default: "every group",
+        description: "comma separated archive_needed, chain_id, error_response, method, origin, and rpc_key. groups that are not listed are summed together. method and origin are only on detailed stats. origin is never a default group",
+    },
+    StatFilter {
+        name: "limit",
+        kind: StatValueKind::Integer,
+        default: "every item",
+        description: "the most items to return",
+    },
+    StatFilter {
+        name: "cursor",
+        kind: StatValueKind::String,
+        default: "the first page",
+        description: "next_cursor from the previous page",
+    },
+    StatFilter {
+        name: "format",
+        kind: StatValueKind::String,
+        default: "json",
+        description: "json, csv, or parquet. files only have the items with these fields as columns",
+    },
+];
+
+/// Windows that dashboards should offer. Any whole number of seconds works if it stays under `stats_max_buckets`
+pub const SUGGESTED_WINDOW_SECONDS: &[u64] = &[60, 300, 3_600, 86_400, 604_800];
+
+/// The numeric field with this influx name
+pub fn stat_field_for_influx(influx_field: &str) -> Option<&'static StatField> {
+    STAT_FIELDS
+        .iter()
+        .find(|x| x.influx_field == Some(influx_field))
+}
+
+/// True if items in a stats response can have a key with this name
+pub fn is_stat_name(name: &str) -> bool {
+    STAT_FIELDS
+        .iter()
+        .chain(STAT_GROUPS)
+        .any(|x| x.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashbrown::HashSet;
+
+    #[test]
+    fn test_stat_names_are_unique() {
+        let mut names = HashSet::new();
+
+        for field in STAT_FIELDS.iter().chain(STAT_GROUPS) {
+            assert!(names.insert(field.name), "duplicate {}", field.name);
+        }
+
+        assert_eq!(
+            stat_field_for_influx("sum_credits_used").map(|x| x.name),
+            Some("total_credits_used")
+        );
+        assert!(stat_field_for_influx("method").is_none());
+
+        assert!(is_stat_name("total_frontend_requests"));
+        assert!(is_stat_name("time"));
+        assert!(!is_stat_name("frontend_requests"));
+    }
+}
@@ -1,16 +1,20 @@
-use super::{AppStat, RpcQueryKey};
-use crate::app::{RpcSecretKeyCache, Web3ProxyJoinHandle};
+use super::freshness::{StatFreshness, StatSaveTimings};
+use super::{AppStat, RpcQueryKey, RpcQueryStats};
+use crate::app::{RpcSecretKeyCache, UserBalance, Web3ProxyJoinHandle};
 use crate::frontend::errors::Web3ProxyResult;
+use chrono::Utc;
 use derive_more::From;
 use futures::stream;
 use hashbrown::HashMap;
 use influxdb2::api::write::TimestampPrecision;
-use log::{error, info, trace};
+use log::{error, info, trace, warn};
 use migration::sea_orm::prelude::Decimal;
 use migration::sea_orm::DatabaseConnection;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast;
 use tokio::time::interval;
+use ulid::Ulid;
 
 #[derive(Debug, Default)]
 pub struct BufferedRpcQueryStats {
@@ -18,21 +22,65 @@ pub struct BufferedRpcQueryStats {
     pub backend_requests: u64,
     pub backend_retries: u64,
     pub no_servers: u64,
+    /// requests that did not get a response because they were notifications
+    pub notifications: u64,
+    /// responses that were replaced with an error because they were larger than max_response_bytes
+    pub response_too_large: u64,
     pub cache_misses: u64,
     pub cache_hits: u64,
     pub sum_request_bytes: u64,
     pub sum_response_bytes: u64,
     pub sum_response_millis: u64,
+    /// time spent waiting in the rate limit queue
+    pub sum_queue_millis: u64,
     pub sum_credits_used: Decimal,
     /// Balance tells us the user's balance at this point in time
     pub latest_balance: Decimal,
+    /// the in-memory balance that `sum_credits_used` was taken from. see `balances`
+    pub balance: Option<Arc<UserBalance>>,
 }
 
+/// An accounting entry that has not been saved yet. Every attempt uses the same `flush_id`
+struct PendingDbSave {
+    flush_id: String,
+    key: RpcQueryKey,
+    stat: BufferedRpcQueryStats,
+    attempts: u32,
+}
+
+/// A timeseries point is identified by its measurement, tags, and timestamp. Writing it again replaces it.
+/// So entries stay in the buffer for a while after they are written and every write has the full total for its point.
+/// Stats that arrive late and failed writes are fixed by writing the point again
+#[derive(Debug, Default)]
+struct TsdbEntry {
+    stat: BufferedRpcQueryStats,
+    /// changed since the point was last written
+    dirty: bool,
+}
+
+impl TsdbEntry {
+    fn add(&mut self, stat: RpcQueryStats) {
+        self.stat.add(stat);
+        self.dirty = true;
+    }
+}
+
+/// accounting entries that keep failing are dropped after this many tries
+const MAX_DB_SAVE_ATTEMPTS: u32 = 10;
+
+/// how long a timeseries entry is kept after it is written. late stats for it are added to the old total
+const TSDB_REWRITE_SECONDS: i64 = 60;
+
+/// entries that still have not been written after this long are dropped
+const TSDB_MAX_RETRY_SECONDS: i64 = 3_600;
+
 #[derive(From)]
 pub struct SpawnedStatBuffer {
     pub stat_sender: flume::Sender<AppStat>,
     /// these handles are important and must be allowed to finish
     pub background_handle: Web3ProxyJoinHandle<()>,
+    /// how far behind the stats in influx are
+    pub freshness: Arc<StatFreshness>,
 }
 pub struct StatBuffer {
     accounting_db_buffer: HashMap<RpcQueryKey, BufferedRpcQueryStats>,
@@ -40,12 +88,22 @@ pub struct StatBuffer {
     chain_id: u64,
     db_conn: Option<DatabaseConnection>,
     db_save_interval_seconds: u32,
-    global_timeseries_buffer: HashMap<RpcQueryKey, BufferedRpcQueryStats>,
+    freshness: Arc<StatFreshness>,
+    global_timeseries_buffer: HashMap<RpcQueryKey, TsdbEntry>,
     influxdb_client: Option<influxdb2::Client>,
-    opt_in_timeseries_buffer: HashMap<RpcQueryKey, BufferedRpcQueryStats>,
+    opt_in_timeseries_buffer: HashMap<RpcQueryKey, TsdbEntry>,
+    pending_db_saves: Vec<PendingDbSave>,
     rpc_secret_key_cache: Option<RpcSecretKeyCache>,
     timestamp_precision: TimestampPrecision,
     tsdb_save_interval_seconds: u32,
+    /// when the first stat since the last tsdb save was received
+    tsdb_first_received: Option<Instant>,
+    /// when the oldest request since the last tsdb save completed
+    tsdb_oldest_completed: Option<Instant>,
+    /// the longest a stat since the last tsdb save waited in the channel
+    tsdb_max_channel_wait: Duration,
+    /// part of every flush id. unique to this stat buffer
+    writer_id: String,
 }
 
 impl StatBuffer {
@@ -59,13 +117,20 @@ impl StatBuffer {
         influxdb_client: Option<influxdb2::Client>,
         rpc_secret_key_cache: Option<RpcSecretKeyCache>,
         shutdown_receiver: broadcast::Receiver<()>,
+        stat_buffer_capacity: Option<usize>,
         tsdb_save_interval_seconds: u32,
     ) -> anyhow::Result<Option<SpawnedStatBuffer>> {
         if db_conn.is_none() && influxdb_client.is_none() {
             return Ok(None);
         }
 
-        let (stat_sender, stat_receiver) = flume::unbounded();
+        // bounded so that a chain with slow stat saving can't use all the memory
+        let (stat_sender, stat_receiver) = match stat_buffer_capacity {
+            Some(x) => flume::bounded(x),
+            None => flume::unbounded(),
+        };
+
+        let freshness = Arc::new(StatFreshness::default());
 
         let timestamp_precision = TimestampPrecision::Seconds;
         let mut new = Self {
@@ -74,12 +139,18 @@ impl StatBuffer {
             chain_id,
             db_conn,
             db_save_interval_seconds,
+            freshness: freshness.clone(),
             global_timeseries_buffer: Default::default(),
             influxdb_client,
             opt_in_timeseries_buffer: Default::default(),
+            pending_db_saves: vec![],
             rpc_secret_key_cache,
             timestamp_precision,
             tsdb_save_interval_seconds,
+            tsdb_first_received: None,
+            tsdb_oldest_completed: None,
+            tsdb_max_channel_wait: Duration::ZERO,
+            writer_id: Ulid::new().to_string(),
         };
 
         // any errors inside this task will cause the application to exit
@@ -88,7 +159,38 @@ impl StatBuffer {
                 .await
         });
 
-        Ok(Some((stat_sender, handle).into()))
+        Ok(Some((stat_sender, handle, freshness).into()))
+    }
+
+    fn buffer_stat(&mut self, stat: AppStat) {
+        let AppStat::RpcQuery(stat) = stat;
+
+        if self.influxdb_client.is_some() {
+            // TODO: round the timestamp at all?
+
+            self.track_tsdb_lag(stat.completed_at);
+
+            let global_timeseries_key = stat.global_timeseries_key();
+
+            self.global_timeseries_buffer
+                .entry(global_timeseries_key)
+                .or_default()
+                .add(stat.clone());
+
+            if let Some(opt_in_timeseries_key) = stat.opt_in_timeseries_key() {
+                self.opt_in_timeseries_buffer
+                    .entry(opt_in_timeseries_key)
+                    .or_default()
+                    .add(stat.clone());
+            }
+        }
+
+        if self.db_conn.is_some() {
+            self.accounting_db_buffer
+                .entry(stat.accounting_key(self.billing_period_seconds))
+                .or_default()
+                .add(stat);
+        }
     }
 
     async fn aggregate_and_save_loop(
@@ -111,23 +213,7 @@ impl StatBuffer {
                     // trace!("Received stat");
                     // save the stat to a buffer
                     match stat {
-                        Ok(AppStat::RpcQuery(stat)) => {
-                            if self.influxdb_client.is_some() {
-                                // TODO: round the timestamp at all?
-
-                                let global_timeseries_key = stat.global_timeseries_key();
-
-                                self.global_timeseries_buffer.entry(global_timeseries_key).or_default().add(stat.clone());
-
-                                if let Some(opt_in_timeseries_key) = stat.opt_in_timeseries_key() {
-                                    self.opt_in_timeseries_buffer.entry(opt_in_timeseries_key).or_default().add(stat.clone());
-                                }
-                            }
-
-                            if self.db_conn.is_some() {
-                                self.accounting_db_buffer.entry(stat.accounting_key(self.billing_period_seconds)).or_default().add(stat);
-                            }
-                        }
+                        Ok(stat) => self.buffer_stat(stat),
                         Err(err) => {
                             info!("error receiving stat: {}", err);
                             break;
@@ -160,10 +246,26 @@ impl StatBuffer {
             }
         }
 
+        // the frontend is done, so these are the last of its requests' stats
+        let mut drained = 0;
+        for stat in stat_receiver.drain() {
+            self.buffer_stat(stat);
+            drained += 1;
+        }
+
+        info!("buffered {} stat(s) left in the channel", drained);
+
         let saved_relational = self.save_relational_stats().await;
 
         info!("saved {} pending relational stat(s)", saved_relational);
 
+        if !self.pending_db_saves.is_empty() {
+            error!(
+                "unable to save {} accounting entries before exiting!",
+                self.pending_db_saves.len()
+            );
+        }
+
         let saved_tsdb = self.save_tsdb_stats(&bucket).await;
 
         info!("saved {} pending tsdb stat(s)", saved_tsdb);
@@ -177,64 +279,124 @@ impl StatBuffer {
         let mut count = 0;
 
         if let Some(db_conn) = self.db_conn.as_ref() {
-            count = self.accounting_db_buffer.len();
+            let flushed_at_ms = Utc::now().timestamp_millis();
+
             for (key, stat) in self.accounting_db_buffer.drain() {
+                let flush_id = key.flush_id(
+                    key.chain_id.unwrap_or(self.chain_id),
+                    &self.writer_id,
+                    flushed_at_ms,
+                );
+
+                self.pending_db_saves.push(PendingDbSave {
+                    flush_id,
+                    key,
+                    stat,
+                    attempts: 0,
+                });
+            }
+
+            count = self.pending_db_saves.len();
+
+            for mut pending in std::mem::take(&mut self.pending_db_saves) {
                 // TODO: batch saves
-                // TODO: i don't like passing key (which came from the stat) to the function on the stat. but it works for now
-                if let Err(err) = stat
+                match pending
+                    .stat
                     .save_db(
-                        self.chain_id,
+                        pending.key.chain_id.unwrap_or(self.chain_id),
                         db_conn,
-                        key,
+                        &pending.key,
+                        &pending.flush_id,
                         self.rpc_secret_key_cache.as_ref(),
                     )
                     .await
                 {
-                    error!("unable to save accounting entry! err={:?}", err);
-                };
+                    Ok(true) => pending.stat.settle_balance(),
+                    Ok(false) => {
+                        info!("accounting entry {} was already saved", pending.flush_id);
+
+                        pending.stat.settle_balance();
+                    }
+                    Err(err) => {
+                        pending.attempts += 1;
+
+                        if pending.attempts >= MAX_DB_SAVE_ATTEMPTS {
+                            error!(
+                                "unable to save accounting entry {}! giving up after {} attempts. err={:?} {:?} {:?}",
+                                pending.flush_id, pending.attempts, err, pending.key, pending.stat
+                            );
+
+                            pending.stat.settle_balance();
+                        } else {
+                            warn!(
+                                "unable to save accounting entry {}! trying again next time. err={:?}",
+                                pending.flush_id, err
+                            );
+
+                            self.pending_db_saves.push(pending);
+                        }
+                    }
+                }
             }
         }
 
         count
     }
 
+    fn track_tsdb_lag(&mut self, completed_at: Instant) {
+        let now = Instant::now();
+
+        self.tsdb_max_channel_wait = self
+            .tsdb_max_channel_wait
+            .max(now.saturating_duration_since(completed_at));
+
+        self.tsdb_first_received.get_or_insert(now);
+
+        if self
+            .tsdb_oldest_completed
+            .map(|x| completed_at < x)
+            .unwrap_or(true)
+        {
+            self.tsdb_oldest_completed = Some(completed_at);
+        }
+    }
+
     // TODO: bucket should be an enum so that we don't risk typos
     async fn save_tsdb_stats(&mut self, bucket: &str) -> usize {
         let mut count = 0;
 
         if let Some(influxdb_client) = self.influxdb_client.as_ref() {
+            let started = Instant::now();
+            let started_ms = Utc::now().timestamp_millis();
+
+            let mut all_written = true;
+
             // TODO: use stream::iter properly to avoid allocating this Vec
             let mut points = vec![];
 
-            for (key, stat) in self.global_timeseries_buffer.drain() {
-                // TODO: i don't like passing key (which came from the stat) to the function on the stat. but it works for now
-                match stat
-                    .build_timeseries_point("global_proxy", self.chain_id, key)
-                    .await
-                {
-                    Ok(point) => {
-                        points.push(point);
-                    }
-                    Err(err) => {
-                        error!("unable to build global stat! err={:?}", err);
-                    }
-                };
-            }
-
-            for (key, stat) in self.opt_in_timeseries_buffer.drain() {
-                // TODO: i don't like passing key (which came from the stat) to the function on the stat. but it works for now
-                match stat
-                    .build_timeseries_point("opt_in_proxy", self.chain_id, key)
-                    .await
-                {
-                    Ok(point) => {
-                        points.push(point);
-                    }
-                    Err(err) => {
-                        // TODO: if this errors, we throw away some of the pending stats! we should probably buffer them somewhere to be tried again
-                        error!("unable to build opt-in stat! err={:?}", err);
-                    }
-                };
+            for (measurement, buffer) in [
+                ("global_proxy", &self.global_timeseries_buffer),
+                ("opt_in_proxy", &self.opt_in_timeseries_buffer),
+            ] {
+                for (key, entry) in buffer.iter().filter(|(_, x)| x.dirty) {
+                    match entry
+                        .stat
+                        .build_timeseries_point(
+                            measurement,
+                            key.chain_id.unwrap_or(self.chain_id),
+                            key,
+                        )
+                        .await
+                    {
+                        Ok(point) => {
+                            points.push(point);
+                        }
+                        Err(err) => {
+                            error!("unable to build {} stat! err={:?}", measurement, err);
+                            all_written = false;
+                        }
+                    };
+                }
             }
 
             count = points.len();
@@ -262,13 +424,66 @@ impl StatBuffer {
                         )
                         .await
                     {
-                        // TODO: if this errors, we throw away some of the pending stats! we should probably buffer them somewhere to be tried again
+                        // the entries stay dirty and are written again next time
                         error!("unable to save {} tsdb stats! err={:?}", batch_size, err);
+                        all_written = false;
                     }
 
                     points = p;
                 }
             }
+
+            let now = Utc::now().timestamp();
+
+            for buffer in [
+                &mut self.global_timeseries_buffer,
+                &mut self.opt_in_timeseries_buffer,
+            ] {
+                if all_written {
+                    buffer.values_mut().for_each(|x| x.dirty = false);
+                }
+
+                let mut dropped = 0;
+
+                buffer.retain(|key, entry| {
+                    let age = now - key.response_timestamp;
+
+                    if !entry.dirty {
+                        age < TSDB_REWRITE_SECONDS
+                    } else if age < TSDB_MAX_RETRY_SECONDS {
+                        true
+                    } else {
+                        dropped += 1;
+                        false
+                    }
+                });
+
+                if dropped > 0 {
+                    error!(
+                        "giving up on {} tsdb stats that could not be saved",
+                        dropped
+                    );
+                }
+            }
+
+            let timings = StatSaveTimings {
+                channel_wait: self.tsdb_max_channel_wait,
+                aggregation: self
+                    .tsdb_first_received
+                    .map(|x| started.saturating_duration_since(x))
+                    .unwrap_or_default(),
+                write: started.elapsed(),
+                end_to_end: self
+                    .tsdb_oldest_completed
+                    .map(|x| x.elapsed())
+                    .unwrap_or_default(),
+            };
+
+            self.freshness.record_save(started_ms, timings, all_written);
+
+            self.tsdb_first_received = None;
+            self.tsdb_oldest_completed = None;
+            self.tsdb_max_channel_wait = Duration::ZERO;
         }
 
         count
@@ -0,0 +1,31 @@
+//! Fixtures shared by the unit tests.
+use crate::app::Web3ProxyApp;
+use crate::config::TopConfig;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// An app on chain 1 without any backends, database, or redis
+pub async fn spawn_test_app() -> Arc<Web3ProxyApp> {
+    spawn_test_app_with_config(
+        r#"
+        [app]
+        chain_id = 1
+
+        [balanced_rpcs]
+        "#,
+    )
+    .await
+}
+
+/// Spawn an app from a toml config.
+/// `TopConfig::from_toml` is skipped so that `WEB3_PROXY_PROFILE` in the environment can't change the tests
+pub async fn spawn_test_app_with_config(config: &str) -> Arc<Web3ProxyApp> {
+    let top_config: TopConfig = toml::from_str(config).unwrap();
+
+    let (shutdown_sender, _) = broadcast::channel(1);
+
+    Web3ProxyApp::spawn(top_config, 2, shutdown_sender)
+        .await
+        .unwrap()
+        .app
+}
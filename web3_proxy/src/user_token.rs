@@ -1,8 +1,12 @@
+use std::fmt;
 use std::str::FromStr;
 
 use axum::headers::authorization::Bearer;
+use ethers::utils::hex;
 use migration::sea_orm::prelude::Uuid;
-use serde::Serialize;
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
 use ulid::Ulid;
 
 /// Key used for caching the user's login
@@ -10,6 +14,13 @@ use ulid::Ulid;
 #[serde(transparent)]
 pub struct UserBearerToken(pub Ulid);
 
+impl fmt::Debug for UserBearerToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the token is a secret
+        f.write_str("UserBearerToken(..)")
+    }
+}
+
 impl UserBearerToken {
     pub fn redis_key(&self) -> String {
         format!("bearer:{}", self.0)
@@ -57,3 +68,33 @@ impl TryFrom<Bearer> for UserBearerToken {
         Ok(UserBearerToken(u))
     }
 }
+
+/// Long lived secret that trades for a new bearer token without signing another message.
+/// Only its hash is saved. Each one only works once
+#[derive(Clone, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct UserRefreshToken(pub String);
+
+impl fmt::Debug for UserRefreshToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the token is a secret
+        f.write_str("UserRefreshToken(..)")
+    }
+}
+
+impl UserRefreshToken {
+    pub fn new() -> anyhow::Result<Self> {
+        let mut x = [0u8; 32];
+
+        SystemRandom::new()
+            .fill(&mut x)
+            .map_err(|_| anyhow::anyhow!("unable to make a refresh token"))?;
+
+        Ok(Self(hex::encode(x)))
+    }
+
+    /// hex sha256. the tokens are random, so they do not need a slow or keyed hash
+    pub fn hash(&self) -> String {
+        hex::encode(digest(&SHA256, self.0.as_bytes()))
+    }
+}
@@ -0,0 +1,143 @@
+//! Bounded pools of work for the chain this proxy serves.
+//! A slow or failing chain fills up its own pools and sheds load instead of tying up the whole runtime.
+use crate::frontend::authorization::Authorization;
+use crate::frontend::errors::{Web3ProxyError, Web3ProxyResult};
+use serde::Serialize;
+use std::sync::atomic::{self, AtomicU64, AtomicUsize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+
+/// The default pool is unbounded
+#[derive(Debug, Default)]
+pub struct WorkerPool {
+    name: &'static str,
+    /// None if the pool is unbounded
+    semaphore: Option<Arc<Semaphore>>,
+    size: usize,
+    /// how long to wait for a permit before shedding the work
+    max_wait: Duration,
+    waiting: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+/// A point in time look at a pool. Shown on /status and in the prometheus metrics
+#[derive(Debug, Default, Serialize)]
+pub struct WorkerPoolStatus {
+    pub size: usize,
+    pub in_use: usize,
+    pub waiting: usize,
+    pub rejected: u64,
+    /// 0.0 is idle. 1.0 means every permit is in use and new work has to wait
+    pub saturation: f64,
+}
+
+impl WorkerPool {
+    pub fn new(name: &'static str, size: Option<usize>, max_wait: Duration) -> Self {
+        let semaphore = size.map(|x| Arc::new(Semaphore::new(x)));
+
+        Self {
+            name,
+            semaphore,
+            size: size.unwrap_or_default(),
+            max_wait,
+            waiting: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Wait for room in the pool. The permit must be held until the work is done.
+    /// Internal requests never wait here. They have their own lane
+    pub async fn acquire(
+        &self,
+        authorization: &Authorization,
+    ) -> Web3ProxyResult<Option<OwnedSemaphorePermit>> {
+        let semaphore = match self.semaphore.as_ref() {
+            Some(x) if !authorization.is_internal() => x.clone(),
+            _ => return Ok(None),
+        };
+
+        // skip the counters in the common case
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+
+        self.waiting.fetch_add(1, atomic::Ordering::AcqRel);
+
+        let permit = timeout(self.max_wait, semaphore.acquire_owned()).await;
+
+        self.waiting.fetch_sub(1, atomic::Ordering::AcqRel);
+
+        match permit {
+            Ok(permit) => Ok(Some(permit?)),
+            Err(_) => {
+                self.rejected.fetch_add(1, atomic::Ordering::Relaxed);
+
+                Err(Web3ProxyError::PoolSaturated(self.name))
+            }
+        }
+    }
+
+    pub fn status(&self) -> WorkerPoolStatus {
+        let semaphore = match self.semaphore.as_ref() {
+            Some(x) => x,
+            None => return WorkerPoolStatus::default(),
+        };
+
+        let in_use = self.size - semaphore.available_permits();
+
+        let saturation = if self.size == 0 {
+            1.0
+        } else {
+            in_use as f64 / self.size as f64
+        };
+
+        WorkerPoolStatus {
+            size: self.size,
+            in_use,
+            waiting: self.waiting.load(atomic::Ordering::Acquire),
+            rejected: self.rejected.load(atomic::Ordering::Relaxed),
+            saturation,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::rpc_proxy_ws::ProxyMode;
+
+    #[tokio::test]
+    async fn test_worker_pool_sheds_when_full() {
+        let pool = WorkerPool::new("test", Some(1), Duration::from_millis(10));
+
+        let user = Authorization::external(
+            &Default::default(),
+            None,
+            "127.0.0.1".parse().unwrap(),
+            None,
+            ProxyMode::Best,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let permit = pool.acquire(&user).await.unwrap();
+        assert!(permit.is_some());
+        assert_eq!(pool.status().saturation, 1.0);
+
+        assert!(matches!(
+            pool.acquire(&user).await,
+            Err(Web3ProxyError::PoolSaturated("test"))
+        ));
+        assert_eq!(pool.status().rejected, 1);
+
+        // the internal lane is never blocked by user traffic
+        let internal = Authorization::internal(None).unwrap();
+        assert!(pool.acquire(&internal).await.unwrap().is_none());
+
+        drop(permit);
+        assert_eq!(pool.status().in_use, 0);
+    }
+}